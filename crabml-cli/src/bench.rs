@@ -0,0 +1,302 @@
+use std::time::Instant;
+
+use clap::Args;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::CpuLlama2Model;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::ActivationType;
+use crate::CacheType;
+use crate::LoadArgs;
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// The GGUF model file to benchmark
+    #[arg(short, long)]
+    model: String,
+
+    /// The number of the prompt tokens forwarded on the prefill, 0 skips the prefill
+    #[arg(short, long, default_value_t = 512)]
+    prompt_tokens: usize,
+
+    /// The number of the tokens generated one by one after the prompt, 0 skips the decoding
+    #[arg(short, long, default_value_t = 128)]
+    gen_tokens: usize,
+
+    /// The number of the runs, the throughputs are averaged over the runs
+    #[arg(short, long, default_value_t = 3)]
+    repetitions: usize,
+
+    /// The max number of the prompt tokens forwarded together
+    #[arg(long, default_value_t = 512)]
+    batch_size: usize,
+
+    /// The type of the kv cache
+    #[arg(long, value_enum, default_value_t = CacheType::F32)]
+    cache_type: CacheType,
+
+    /// The type of the inputs of the matmuls
+    #[arg(long, value_enum, default_value_t = ActivationType::F32)]
+    activation_type: ActivationType,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Take the attention in the fused kernel of the flash attention
+    #[arg(long = "flash-attn", default_value_t = false)]
+    flash_attn: bool,
+
+    /// The number of threads, the number of the cpus if 0
+    #[arg(short, long, default_value_t = 0)]
+    threads: usize,
+
+    /// Print the result in json for tracking the performance on CI
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+/// the tokens per second of the runs on a stage, like the prefill or the decoding.
+#[derive(Debug, Clone, Default)]
+struct BenchStage {
+    tokens: usize,
+    secs: Vec<f64>,
+}
+
+impl BenchStage {
+    fn tokens_per_sec(&self) -> Vec<f64> {
+        self.secs
+            .iter()
+            .map(|secs| self.tokens as f64 / secs.max(1e-9))
+            .collect()
+    }
+
+    fn mean(&self) -> f64 {
+        let tps = self.tokens_per_sec();
+        tps.iter().sum::<f64>() / tps.len().max(1) as f64
+    }
+
+    fn stddev(&self) -> f64 {
+        let tps = self.tokens_per_sec();
+        if tps.len() < 2 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let var = tps.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (tps.len() - 1) as f64;
+        var.sqrt()
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "tokens": self.tokens,
+            "tokens_per_sec": self.mean(),
+            "stddev": self.stddev(),
+            "secs": self.secs,
+        })
+    }
+}
+
+/// benchmark the prefill and the decoding like llama-bench, the prompt of the BOS tokens is
+/// forwarded in the batches of `batch_size`, then the tokens are generated greedily one by
+/// one, so the runs take the same work on each repetition.
+pub fn bench(args: &BenchArgs) -> Result<()> {
+    let v = bench_json(args)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&v).unwrap());
+        return Ok(());
+    }
+
+    println!(
+        "model: {}, threads: {}, batch size: {}, cache type: {}, activation type: {}",
+        v["model"].as_str().unwrap(),
+        v["threads"],
+        v["batch_size"],
+        v["cache_type"].as_str().unwrap(),
+        v["activation_type"].as_str().unwrap(),
+    );
+    println!(
+        "{:<10} {:>8} {:>14} {:>10}",
+        "stage", "tokens", "tokens/s", "stddev"
+    );
+    for stage in ["prefill", "decode"] {
+        if v[stage]["tokens"] == 0 {
+            continue;
+        }
+        println!(
+            "{:<10} {:>8} {:>14.2} {:>10.2}",
+            stage,
+            v[stage]["tokens"].as_u64().unwrap(),
+            v[stage]["tokens_per_sec"].as_f64().unwrap(),
+            v[stage]["stddev"].as_f64().unwrap(),
+        );
+    }
+    let memory = &v["memory"];
+    print!(
+        "memory: weights {}, kv cache {}",
+        format_mib(memory["weights_bytes"].as_u64().unwrap()),
+        format_mib(memory["kv_cache_bytes"].as_u64().unwrap()),
+    );
+    match memory["peak_rss_bytes"].as_u64() {
+        Some(rss) => println!(", peak rss {}", format_mib(rss)),
+        None => println!(),
+    }
+    Ok(())
+}
+
+fn bench_json(args: &BenchArgs) -> Result<Value> {
+    if args.prompt_tokens + args.gen_tokens == 0 {
+        return Err((ErrorKind::BadInput, "no tokens to benchmark").into());
+    }
+    let gl = GGUFFileLoader::new_with_options(&args.model, args.load.options())?;
+    let gf = gl.open()?;
+    let device = CpuTensorDevice::new().with_threads(args.threads)?;
+    let threads = device.threads();
+    let model = CpuLlama2Model::load(&gf, device)?;
+    let conf = *model.conf();
+    let n_tokens = args.prompt_tokens + args.gen_tokens;
+    if n_tokens > conf.seq_len {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "the {} tokens to benchmark are beyond the context of {} tokens",
+                n_tokens, conf.seq_len
+            ),
+        )
+            .into());
+    }
+    let mut runner = Llama2Runner::try_from(&model)?
+        .with_kv_cache_dtype(args.cache_type.dtype())?
+        .with_activation_dtype(args.activation_type.dtype())?
+        .with_flash_attention(args.flash_attn)
+        .with_batch_size(args.batch_size);
+
+    let bos = model.tokenizer().bos_token();
+    let prompt = vec![bos; args.prompt_tokens];
+    let mut prefill = BenchStage {
+        tokens: args.prompt_tokens,
+        ..Default::default()
+    };
+    let mut decode = BenchStage {
+        tokens: args.gen_tokens,
+        ..Default::default()
+    };
+    for _ in 0..args.repetitions.max(1) {
+        // the forwarding from the position 0 restarts the sequence in the kv cache
+        let start_time = Instant::now();
+        let mut logits = vec![];
+        for (i, batch) in prompt.chunks(args.batch_size.max(1)).enumerate() {
+            logits = runner
+                .forward_batch(batch, i * args.batch_size.max(1))?
+                .to_vec();
+        }
+        if args.prompt_tokens > 0 {
+            prefill.secs.push(start_time.elapsed().as_secs_f64());
+        }
+
+        let start_time = Instant::now();
+        let mut token = match logits.is_empty() {
+            true => bos,
+            false => argmax(&logits),
+        };
+        for pos in args.prompt_tokens..n_tokens {
+            token = argmax(runner.forward(token, pos)?);
+        }
+        if args.gen_tokens > 0 {
+            decode.secs.push(start_time.elapsed().as_secs_f64());
+        }
+    }
+
+    let weights_bytes = gf
+        .tensor_infos()
+        .iter()
+        .map(|info| info.data().len())
+        .sum::<usize>();
+    let kv_cache_bytes = args
+        .cache_type
+        .dtype()
+        .bytes_of(conf.kv_cache_len() * conf.kv_dim())?
+        * conf.n_kv_cache_layers()
+        * 2;
+    Ok(json!({
+        "model": args.model,
+        "threads": threads,
+        "batch_size": args.batch_size,
+        "cache_type": format!("{}", args.cache_type.dtype()),
+        "activation_type": format!("{}", args.activation_type.dtype()),
+        "flash_attn": args.flash_attn,
+        "repetitions": args.repetitions.max(1),
+        "prefill": prefill.to_json(),
+        "decode": decode.to_json(),
+        "memory": {
+            "weights_bytes": weights_bytes,
+            "kv_cache_bytes": kv_cache_bytes,
+            "peak_rss_bytes": peak_rss_bytes(),
+        },
+    }))
+}
+
+fn argmax(logits: &[f32]) -> usize {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// the peak resident memory of the process, which is only read from procfs on linux.
+fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+fn format_mib(n: u64) -> String {
+    format!("{:.2} MiB", n as f64 / 1024.0 / 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_json() -> Result<()> {
+        let args = BenchArgs {
+            model: "../testdata/tinyllamas-stories-260k-f32.gguf".to_string(),
+            prompt_tokens: 16,
+            gen_tokens: 8,
+            repetitions: 2,
+            batch_size: 5,
+            cache_type: CacheType::Q8_0,
+            activation_type: ActivationType::F16,
+            load: LoadArgs::default(),
+            flash_attn: false,
+            threads: 1,
+            json: true,
+        };
+        let v = bench_json(&args)?;
+        assert_eq!(v["threads"], 1);
+        assert_eq!(v["cache_type"], "Q8_0");
+        assert_eq!(v["activation_type"], "F16");
+        assert_eq!(v["prefill"]["tokens"], 16);
+        assert_eq!(v["prefill"]["secs"].as_array().unwrap().len(), 2);
+        assert_eq!(v["decode"]["tokens"], 8);
+        assert!(v["decode"]["tokens_per_sec"].as_f64().unwrap() > 0.0);
+        // 5 layers of the keys and the values, 512 rows of 32 kv dims in a block of q8_0
+        assert_eq!(v["memory"]["kv_cache_bytes"], 5 * 2 * 512 * 34);
+        assert!(v["memory"]["weights_bytes"].as_u64().unwrap() > 0);
+
+        let args = BenchArgs {
+            prompt_tokens: 500,
+            gen_tokens: 100,
+            ..args
+        };
+        assert!(bench_json(&args).is_err());
+        Ok(())
+    }
+}
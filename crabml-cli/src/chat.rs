@@ -0,0 +1,471 @@
+use std::io::BufRead;
+use std::io::Write;
+use std::rc::Rc;
+
+use clap::ArgMatches;
+use clap::Args;
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::BpeTokenizer;
+use crabml_llama2::chat_template::ChatMessage;
+use crabml_llama2::chat_template::ChatTemplate;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::sampler::Llama2Sampler;
+use crabml_llama2::CpuLlama2Model;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::config::merge;
+use crate::config::GenerationConfig;
+use crate::ActivationType;
+use crate::CacheType;
+use crate::LoadArgs;
+
+const CHAT_HELP: &str = "\
+/system <text>  set the system message and start a new conversation
+/reset          start a new conversation, the system message is kept
+/save <path>    save the conversation into a json file
+/load <path>    load the conversation from a json file
+/history        print the conversation
+/help           print this help
+/exit           exit the chat";
+
+#[derive(Args, Debug)]
+pub struct ChatArgs {
+    /// The GGUF model file to chat with, required unless it's in the config file
+    #[arg(short, long, default_value = "", hide_default_value = true)]
+    model: String,
+
+    /// The system message of the chat
+    #[arg(long)]
+    system: Option<String>,
+
+    /// Use a built-in chat template instead of the one of the model
+    #[arg(long, value_parser = ["llama2", "chatml", "mistral"])]
+    chat_template: Option<String>,
+
+    /// The max number of the tokens generated on each reply
+    #[arg(short = 'n', long, default_value_t = 512)]
+    max_tokens: usize,
+
+    #[arg(short, long, default_value_t = 0.8)]
+    temperature: f32,
+
+    // The probability of sampling from the top-p.
+    #[arg(short, long, default_value_t = 0.9)]
+    probability: f32,
+
+    /// The seed of the random number generator for sampling, random if not set
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// The max number of the prompt tokens forwarded together
+    #[arg(long, default_value_t = 512)]
+    batch_size: usize,
+
+    /// The type of the kv cache
+    #[arg(long, value_enum, default_value_t = CacheType::F32)]
+    cache_type: CacheType,
+
+    /// The type of the inputs of the matmuls, f16 halves their memory and bandwidth with the
+    /// products still accumulated in f32. It's taken on the cpu and wgpu.
+    #[arg(long, value_enum, default_value_t = ActivationType::F32)]
+    activation_type: ActivationType,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Take the attention in the fused kernel of the flash attention
+    #[arg(long = "flash-attn", default_value_t = false)]
+    flash_attn: bool,
+
+    /// The number of threads, the number of the cpus if 0
+    #[arg(short = 'T', long, default_value_t = 0)]
+    threads: usize,
+
+    /// The size of the context in tokens, capped at the context length of the model
+    #[arg(long)]
+    ctx_size: Option<usize>,
+
+    /// Print the prompt tokens reused from the kv cache on each reply
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+}
+
+/// the reply of a turn, with the prompt tokens of the turn and the ones kept in the kv cache
+/// from the last turns.
+#[derive(Debug, Clone, PartialEq)]
+struct ChatReply {
+    text: String,
+    n_prompt: usize,
+    n_reused: usize,
+}
+
+/// a conversation on a runner. the whole history is rendered by the chat template on each
+/// turn, and the prefix of it already forwarded on the last turns is kept in the kv cache, so
+/// only the new message and the reply are forwarded. the oldest turns are dropped once the
+/// history does not fit into the context.
+struct ChatSession<'a> {
+    runner: Llama2Runner<CpuTensor<'a>>,
+    tokenizer: Rc<BpeTokenizer>,
+    template: ChatTemplate,
+    sampler: Llama2Sampler,
+    seq_len: usize,
+    max_tokens: usize,
+    system: Option<String>,
+    history: Vec<ChatMessage>,
+}
+
+impl<'a> ChatSession<'a> {
+    /// the messages of the conversation, starting with the system message if any.
+    fn messages(&self) -> Vec<ChatMessage> {
+        self.system
+            .iter()
+            .map(|system| ChatMessage::new("system", system))
+            .chain(self.history.iter().cloned())
+            .collect()
+    }
+
+    fn render(&self) -> Result<(String, Vec<usize>)> {
+        let prompt = self
+            .template
+            .render(&self.messages(), true, &self.tokenizer)?;
+        let tokens = self.tokenizer.encode_special(
+            &prompt,
+            self.tokenizer.add_bos_token(),
+            self.tokenizer.add_eos_token(),
+        )?;
+        Ok((prompt, tokens))
+    }
+
+    /// reply to the user message, the text of the reply is written to `out` as it's
+    /// generated.
+    fn reply(&mut self, content: &str, out: &mut impl Write) -> Result<ChatReply> {
+        self.history.push(ChatMessage::new("user", content));
+
+        // drop the oldest turns until the prompt leaves the room for the reply
+        let max_tokens = self.max_tokens.min(self.seq_len / 2);
+        let (prompt, tokens) = loop {
+            let (prompt, tokens) = self.render()?;
+            if tokens.len() + max_tokens <= self.seq_len {
+                break (prompt, tokens);
+            }
+            if self.history.len() <= 1 {
+                self.history.pop();
+                return Err((
+                    ErrorKind::BadInput,
+                    format!(
+                        "the message of {} tokens does not fit into the context of {} tokens",
+                        tokens.len(),
+                        self.seq_len
+                    ),
+                )
+                    .into());
+            }
+            self.history.drain(..2.min(self.history.len() - 1));
+        };
+        // the past turns are delimited by the eos and bos tokens, on which `generate` stops
+        let n_reused = self.runner.prefill(&tokens)?;
+
+        let mut text = String::new();
+        let mut generator = self
+            .runner
+            .generate(&prompt, max_tokens, &mut self.sampler)?
+            .with_max_tokens(max_tokens);
+        while let Some(token) = generator.next_token()? {
+            text.push_str(&token.text);
+            write!(out, "{}", token.text).map_err(io_error)?;
+            out.flush().map_err(io_error)?;
+        }
+        writeln!(out).map_err(io_error)?;
+
+        let text = text.trim().to_string();
+        self.history.push(ChatMessage::new("assistant", &text));
+        Ok(ChatReply {
+            text,
+            n_prompt: tokens.len(),
+            n_reused,
+        })
+    }
+
+    /// run the slash command, returns false on exit.
+    fn command(&mut self, line: &str, out: &mut impl Write) -> Result<bool> {
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        match name {
+            "/exit" | "/quit" => return Ok(false),
+            "/help" => writeln!(out, "{}", CHAT_HELP).map_err(io_error)?,
+            "/reset" => self.history.clear(),
+            "/system" => {
+                self.system = Some(arg.to_string()).filter(|s| !s.is_empty());
+                self.history.clear();
+            }
+            "/history" => {
+                for m in self.messages() {
+                    writeln!(out, "[{}] {}", m.role, m.content).map_err(io_error)?;
+                }
+            }
+            "/save" if !arg.is_empty() => {
+                let messages = self
+                    .messages()
+                    .iter()
+                    .map(|m| json!({"role": m.role, "content": m.content}))
+                    .collect::<Vec<_>>();
+                let json = serde_json::to_string_pretty(&messages).unwrap();
+                std::fs::write(arg, json).map_err(|err| Error {
+                    kind: ErrorKind::IOError,
+                    message: format!("failed to save the conversation into {}", arg),
+                    cause: Some(Box::new(err)),
+                })?;
+            }
+            "/load" if !arg.is_empty() => {
+                let json = std::fs::read_to_string(arg).map_err(|err| Error {
+                    kind: ErrorKind::IOError,
+                    message: format!("failed to read the conversation from {}", arg),
+                    cause: Some(Box::new(err)),
+                })?;
+                self.load(&json)?;
+            }
+            _ => {
+                return Err((
+                    ErrorKind::BadInput,
+                    format!("unknown command {}, try /help", line),
+                )
+                    .into());
+            }
+        }
+        Ok(true)
+    }
+
+    /// take the conversation in the messages of `/save`.
+    fn load(&mut self, json: &str) -> Result<()> {
+        let messages: Value = serde_json::from_str(json).map_err(|err| Error {
+            kind: ErrorKind::BadInput,
+            message: "failed to parse the conversation".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+        let messages = messages
+            .as_array()
+            .ok_or_else(|| Error::from((ErrorKind::BadInput, "expected an array of messages")))?
+            .iter()
+            .map(|m| match (m["role"].as_str(), m["content"].as_str()) {
+                (Some(role), Some(content)) => Ok(ChatMessage::new(role, content)),
+                _ => Err((ErrorKind::BadInput, "expected the role and the content").into()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.system = None;
+        self.history = messages;
+        if self.history.first().map(|m| m.role.as_str()) == Some("system") {
+            self.system = Some(self.history.remove(0).content);
+        }
+        Ok(())
+    }
+}
+
+/// chat with the model in a read-eval-print loop on the terminal.
+impl ChatArgs {
+    /// fill the flags not given on the command line from the config file.
+    pub fn apply_config(&mut self, config: &GenerationConfig, matches: &ArgMatches) -> Result<()> {
+        merge(matches, "model", &mut self.model, config.model.clone());
+        merge(
+            matches,
+            "system",
+            &mut self.system,
+            config.system.clone().map(Some),
+        );
+        merge(
+            matches,
+            "chat_template",
+            &mut self.chat_template,
+            config.chat_template.clone().map(Some),
+        );
+        merge(
+            matches,
+            "max_tokens",
+            &mut self.max_tokens,
+            config.max_tokens,
+        );
+        merge(
+            matches,
+            "temperature",
+            &mut self.temperature,
+            config.temperature,
+        );
+        merge(matches, "probability", &mut self.probability, config.top_p);
+        merge(matches, "seed", &mut self.seed, config.seed.map(Some));
+        merge(matches, "threads", &mut self.threads, config.threads);
+        merge(
+            matches,
+            "ctx_size",
+            &mut self.ctx_size,
+            config.ctx_size.map(Some),
+        );
+        if self.model.is_empty() {
+            return Err((
+                ErrorKind::BadInput,
+                "the model is required by --model or the model of the config file",
+            )
+                .into());
+        }
+        Ok(())
+    }
+}
+
+pub fn chat(args: &ChatArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new_with_options(&args.model, args.load.options())?;
+    let gf = gl.open()?;
+    let device = CpuTensorDevice::new().with_threads(args.threads)?;
+    let mut model = CpuLlama2Model::load(&gf, device)?;
+    if let Some(n_ctx) = args.ctx_size {
+        model = model.with_context_size(n_ctx);
+    }
+    let template = match &args.chat_template {
+        Some(name) => ChatTemplate::builtin(name).unwrap(),
+        None => ChatTemplate::from_gguf(&gf),
+    };
+    let runner = Llama2Runner::try_from(&model)?
+        .with_kv_cache_dtype(args.cache_type.dtype())?
+        .with_activation_dtype(args.activation_type.dtype())?
+        .with_flash_attention(args.flash_attn)
+        .with_batch_size(args.batch_size);
+    let mut sampler =
+        Llama2Sampler::new(model.conf().vocab_size, args.temperature, args.probability);
+    if let Some(seed) = args.seed {
+        sampler = sampler.with_seed(seed);
+    }
+    let mut session = ChatSession {
+        runner,
+        tokenizer: model.tokenizer(),
+        template,
+        sampler,
+        seq_len: model.conf().seq_len,
+        max_tokens: args.max_tokens,
+        system: args.system.clone(),
+        history: vec![],
+    };
+
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    println!("chatting with {}, type /help for the commands", args.model);
+    loop {
+        print!("> ");
+        stdout.flush().map_err(io_error)?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(io_error)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('/') {
+            match session.command(line, &mut stdout) {
+                Ok(true) => continue,
+                Ok(false) => return Ok(()),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    continue;
+                }
+            }
+        }
+        match session.reply(line, &mut stdout) {
+            Ok(reply) if args.verbose => eprintln!(
+                "[{} of {} prompt tokens reused from the kv cache]",
+                reply.n_reused, reply.n_prompt
+            ),
+            Ok(_) => {}
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error {
+        kind: ErrorKind::IOError,
+        message: "failed to read or write the terminal".to_string(),
+        cause: Some(Box::new(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_session() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let model = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut session = ChatSession {
+            runner: Llama2Runner::try_from(&model)?.with_batch_size(4),
+            tokenizer: model.tokenizer(),
+            template: ChatTemplate::Llama2,
+            sampler: Llama2Sampler::new(model.conf().vocab_size, 0.0, 0.9),
+            seq_len: model.conf().seq_len,
+            max_tokens: 16,
+            system: Some("Tell a story".to_string()),
+            history: vec![],
+        };
+
+        let mut out = vec![];
+        let reply = session.reply("Lily", &mut out)?;
+        assert_eq!(reply.n_reused, 0);
+        assert_eq!(String::from_utf8(out).unwrap().trim(), reply.text);
+
+        // the first turn is kept in the kv cache
+        let n_prompt = reply.n_prompt;
+        let reply = session.reply("Tom", &mut vec![])?;
+        assert!(reply.n_reused >= n_prompt);
+        assert!(!reply.text.is_empty());
+        assert_eq!(session.history.len(), 4);
+
+        // the conversation is saved and loaded with the system message
+        let path = std::env::temp_dir().join(format!("crabml-chat-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(session.command(&format!("/save {}", path), &mut vec![])?);
+        assert!(session.command("/reset", &mut vec![])?);
+        assert!(session.history.is_empty());
+        assert!(session.command(&format!("/load {}", path), &mut vec![])?);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(session.history.len(), 4);
+        assert_eq!(session.system.as_deref(), Some("Tell a story"));
+
+        assert!(session.command("/system Be brief", &mut vec![])?);
+        assert_eq!(session.messages(), vec![ChatMessage::new(
+            "system", "Be brief"
+        )]);
+        assert!(session.command("/unknown", &mut vec![]).is_err());
+        assert!(!session.command("/exit", &mut vec![])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_drop_turns() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let model = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut session = ChatSession {
+            runner: Llama2Runner::try_from(&model)?,
+            tokenizer: model.tokenizer(),
+            template: ChatTemplate::Llama2,
+            sampler: Llama2Sampler::new(model.conf().vocab_size, 0.0, 0.9),
+            seq_len: 64,
+            max_tokens: 8,
+            system: None,
+            history: vec![],
+        };
+        for _ in 0..6 {
+            let reply = session.reply("Lily and Tom went to the park", &mut vec![])?;
+            assert!(reply.n_prompt + 8 <= 64);
+        }
+        // the oldest turns are dropped to fit into the context
+        assert!(session.history.len() < 12);
+        assert_eq!(session.history.last().unwrap().role, "assistant");
+        Ok(())
+    }
+}
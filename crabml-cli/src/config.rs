@@ -0,0 +1,234 @@
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use toml_edit::Document;
+use toml_edit::Item;
+
+/// the config file taken from the working directory if `--config` is not given.
+pub const DEFAULT_CONFIG_FILE: &str = "crabml.toml";
+
+/// the generation config of `crabml.toml`, like:
+///
+/// ```toml
+/// model = "./models/llama-2-7b-chat.Q4_K_M.gguf"
+/// temperature = 0.7
+/// top_p = 0.9
+/// ctx_size = 2048
+/// threads = 8
+/// system = "You are a helpful assistant."
+/// ```
+///
+/// all the keys are optional, and the flags given on the command line take precedence.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GenerationConfig {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub seed: Option<u64>,
+    pub max_tokens: Option<usize>,
+    pub ctx_size: Option<usize>,
+    pub threads: Option<usize>,
+    pub system: Option<String>,
+    pub chat_template: Option<String>,
+}
+
+impl GenerationConfig {
+    pub fn parse(s: &str) -> Result<Self> {
+        let doc = s.parse::<Document>().map_err(|err| Error {
+            kind: ErrorKind::BadInput,
+            message: "failed to parse the config".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+
+        let mut config = Self::default();
+        for (key, item) in doc.iter() {
+            match key {
+                "model" => config.model = Some(string_value(key, item)?),
+                "temperature" => config.temperature = Some(f32_value(key, item)?),
+                "top_p" => config.top_p = Some(f32_value(key, item)?),
+                "min_p" => config.min_p = Some(f32_value(key, item)?),
+                "seed" => config.seed = Some(usize_value(key, item)? as u64),
+                "max_tokens" => config.max_tokens = Some(usize_value(key, item)?),
+                "ctx_size" => config.ctx_size = Some(usize_value(key, item)?),
+                "threads" => config.threads = Some(usize_value(key, item)?),
+                "system" => config.system = Some(string_value(key, item)?),
+                "chat_template" => {
+                    let name = string_value(key, item)?;
+                    if !["llama2", "chatml", "mistral"].contains(&name.as_str()) {
+                        return Err((
+                            ErrorKind::BadInput,
+                            format!(
+                                "unknown chat template {} in the config, expected llama2, chatml or mistral",
+                                name
+                            ),
+                        )
+                            .into());
+                    }
+                    config.chat_template = Some(name);
+                }
+                _ => {
+                    return Err((
+                        ErrorKind::BadInput,
+                        format!("unknown key {} in the config", key),
+                    )
+                        .into());
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the config file {}", path.display()),
+            cause: Some(Box::new(err)),
+        })?;
+        Self::parse(&s).map_err(|err| Error {
+            kind: err.kind,
+            message: format!("{}: {}", path.display(), err.message),
+            cause: err.cause,
+        })
+    }
+
+    /// load the config file of `--config`, or `crabml.toml` in the working directory if it
+    /// exists, an empty config otherwise.
+    pub fn discover(path: Option<&str>) -> Result<Self> {
+        match path {
+            Some(path) => Self::load(path),
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Self::load(DEFAULT_CONFIG_FILE),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// take the value of the config unless the flag of `id` is given on the command line.
+pub fn merge<T>(matches: &ArgMatches, id: &str, field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            *field = value;
+        }
+    }
+}
+
+fn type_error(key: &str, expected: &str) -> Error {
+    (
+        ErrorKind::BadInput,
+        format!("expected {} in the config to be {}", key, expected),
+    )
+        .into()
+}
+
+fn string_value(key: &str, item: &Item) -> Result<String> {
+    item.as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| type_error(key, "a string"))
+}
+
+fn f32_value(key: &str, item: &Item) -> Result<f32> {
+    item.as_float()
+        .or_else(|| item.as_integer().map(|v| v as f64))
+        .map(|v| v as f32)
+        .ok_or_else(|| type_error(key, "a number"))
+}
+
+fn usize_value(key: &str, item: &Item) -> Result<usize> {
+    item.as_integer()
+        .and_then(|v| usize::try_from(v).ok())
+        .ok_or_else(|| type_error(key, "a non-negative integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Args;
+    use clap::Command;
+    use clap::FromArgMatches;
+
+    use super::*;
+
+    #[derive(Args, Debug)]
+    struct TestArgs {
+        #[arg(short, long, default_value_t = 1.0)]
+        temperature: f32,
+
+        #[arg(long)]
+        seed: Option<u64>,
+
+        #[arg(long)]
+        system: Option<String>,
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config = GenerationConfig::parse(
+            r#"
+# the defaults of the generation
+model = "./model.gguf"
+temperature = 0.7
+top_p = 1
+seed = 42
+ctx_size = 2048
+system = "You are a cat."
+chat_template = "chatml"
+"#,
+        )?;
+        assert_eq!(config, GenerationConfig {
+            model: Some("./model.gguf".to_string()),
+            temperature: Some(0.7),
+            top_p: Some(1.0),
+            seed: Some(42),
+            ctx_size: Some(2048),
+            system: Some("You are a cat.".to_string()),
+            chat_template: Some("chatml".to_string()),
+            ..Default::default()
+        });
+
+        let err = GenerationConfig::parse("temprature = 0.7").unwrap_err();
+        assert_eq!(err.message, "unknown key temprature in the config");
+        let err = GenerationConfig::parse("threads = -1").unwrap_err();
+        assert_eq!(
+            err.message,
+            "expected threads in the config to be a non-negative integer"
+        );
+        assert!(GenerationConfig::parse("model = ").is_err());
+        assert_eq!(GenerationConfig::parse("")?, GenerationConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let config = GenerationConfig {
+            temperature: Some(0.5),
+            seed: Some(7),
+            system: Some("You are a cat.".to_string()),
+            ..Default::default()
+        };
+        let cmd = TestArgs::augment_args(Command::new("test"));
+        let matches = cmd.get_matches_from(["test", "--seed", "1"]);
+        let mut args = TestArgs::from_arg_matches(&matches).unwrap();
+        merge(
+            &matches,
+            "temperature",
+            &mut args.temperature,
+            config.temperature,
+        );
+        merge(&matches, "seed", &mut args.seed, config.seed.map(Some));
+        merge(
+            &matches,
+            "system",
+            &mut args.system,
+            config.system.clone().map(Some),
+        );
+
+        // the default is replaced by the config, but the flag is kept
+        assert_eq!(args.temperature, 0.5);
+        assert_eq!(args.seed, Some(1));
+        assert_eq!(args.system.as_deref(), Some("You are a cat."));
+    }
+}
@@ -0,0 +1,633 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use clap::Args;
+use clap::ValueEnum;
+use crabml::backends::cpu::buf::CpuTensorBuf;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFWriter;
+use crabml::gguf::ModelTensor;
+use crabml::gguf::KEY_ATTENTION_HEAD_COUNT;
+use crabml::gguf::KEY_ATTENTION_HEAD_COUNT_KV;
+use crabml::gguf::KEY_ATTENTION_LAYERNORM_RMS_EPS;
+use crabml::gguf::KEY_ATTENTION_SLIDING_WINDOW;
+use crabml::gguf::KEY_BLOCK_COUNT;
+use crabml::gguf::KEY_CONTEXT_LENGTH;
+use crabml::gguf::KEY_EMBEDDING_LENGTH;
+use crabml::gguf::KEY_FEED_FORWARD_LENGTH;
+use crabml::gguf::KEY_GENERAL_ARCHITECTURE;
+use crabml::gguf::KEY_GENERAL_FILE_TYPE;
+use crabml::gguf::KEY_GENERAL_NAME;
+use crabml::gguf::KEY_ROPE_DIMENSION_COUNT;
+use crabml::gguf::KEY_ROPE_FREQ_BASE;
+use crabml::gguf::KEY_ROPE_SCALING_FACTOR;
+use crabml::gguf::KEY_ROPE_SCALING_ORIG_CTX_LEN;
+use crabml::gguf::KEY_ROPE_SCALING_TYPE;
+use crabml::gguf::KEY_TOKENIZER_ADD_BOS;
+use crabml::gguf::KEY_TOKENIZER_BOS_ID;
+use crabml::gguf::KEY_TOKENIZER_CHAT_TEMPLATE;
+use crabml::gguf::KEY_TOKENIZER_EOS_ID;
+use crabml::gguf::KEY_TOKENIZER_HF_JSON;
+use crabml::gguf::KEY_TOKENIZER_LIST;
+use crabml::gguf::KEY_TOKENIZER_MERGES;
+use crabml::gguf::KEY_TOKENIZER_MODEL;
+use crabml::gguf::KEY_TOKENIZER_SCORES;
+use crabml::gguf::KEY_TOKENIZER_TOKEN_TYPE;
+use crabml::gguf::KEY_TOKENIZER_UNK_ID;
+use crabml::safetensors::SafetensorsFile;
+use crabml::safetensors::SafetensorsFileLoader;
+use crabml::safetensors::SafetensorsTensorInfo;
+use crabml::tensor::RopeMode;
+use crabml::tensor::RopeScaling;
+use crabml::tokenizer::BpeTokenType;
+use crabml::tokenizer::BpeTokenizer;
+use crabml_llama2::model::permute_hf_rows;
+use crabml_llama2::model::Llama2Config;
+use serde_json::Value;
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// The directory of the HuggingFace checkpoint, with config.json, tokenizer.json and the
+    /// weights in safetensors
+    input: String,
+
+    /// The path to write the GGUF file
+    output: String,
+
+    /// The type of the weights, the norms and the biases are kept in f32
+    #[arg(long = "outtype", value_enum, default_value_t = ConvertType::F16)]
+    out_type: ConvertType,
+
+    /// The name of the model in the metadata, the name of the directory by default
+    #[arg(long)]
+    name: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertType {
+    #[value(name = "f32")]
+    F32,
+    #[value(name = "f16")]
+    F16,
+}
+
+impl ConvertType {
+    fn typ(&self) -> GGMLType {
+        match self {
+            ConvertType::F32 => GGMLType::F32,
+            ConvertType::F16 => GGMLType::F16,
+        }
+    }
+
+    /// the `general.file_type` of llama.cpp.
+    fn file_type(&self) -> u32 {
+        match self {
+            ConvertType::F32 => 0,
+            ConvertType::F16 => 1,
+        }
+    }
+}
+
+/// the vocabulary of the tokenizer.json in the form of the `tokenizer.ggml.*` metadata.
+struct ConvertVocab {
+    model: &'static str,
+    tokens: Vec<String>,
+    scores: Vec<f32>,
+    token_types: Vec<i32>,
+    merges: Vec<String>,
+    bos_token: usize,
+    eos_token: usize,
+    unk_token: Option<usize>,
+    add_bos_token: bool,
+}
+
+/// convert a HuggingFace checkpoint of llama, mistral or qwen2 into a GGUF file, so the
+/// models can be converted without the python scripts of llama.cpp.
+pub fn convert(args: &ConvertArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let dir = Path::new(&args.input);
+    let config_json = read_file(&dir.join("config.json"))?;
+    let conf = Llama2Config::from_hf_config(&config_json)?;
+    let config: Value = serde_json::from_str(&config_json).unwrap();
+    if conf.n_experts > 0 {
+        return Err((
+            ErrorKind::NotImplemented,
+            "converting the mixture of experts is not supported yet",
+        )
+            .into());
+    }
+
+    let vocab = convert_vocab(dir, &config, conf.vocab_size)?;
+    let tokenizer_json = read_file(&dir.join("tokenizer.json"))?;
+    let chat_template = read_tokenizer_config(dir)?
+        .and_then(|v| v["chat_template"].as_str().map(|s| s.to_string()));
+
+    let loaders = safetensors_paths(dir)?
+        .iter()
+        .map(|path| SafetensorsFileLoader::new(path.to_str().unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    let files = loaders
+        .iter()
+        .map(|l| l.open())
+        .collect::<Result<Vec<_>>>()?;
+    let tensors = convert_tensors(&files, &conf, args.out_type)?;
+
+    let arch = conf.arch.name();
+    let key = |k: &str| k.replace("{arch}", arch);
+    let name = args.name.clone().unwrap_or_else(|| {
+        dir.canonicalize()
+            .ok()
+            .and_then(|dir| Some(dir.file_name()?.to_str()?.to_string()))
+            .unwrap_or_else(|| arch.to_string())
+    });
+
+    let mut w = GGUFWriter::new();
+    w.add_metadata(KEY_GENERAL_ARCHITECTURE, GGUFMetadataValue::String(arch));
+    w.add_metadata(KEY_GENERAL_NAME, GGUFMetadataValue::String(&name));
+    w.add_metadata(
+        KEY_GENERAL_FILE_TYPE,
+        GGUFMetadataValue::U32(args.out_type.file_type()),
+    );
+    let u32_value = |v: usize| GGUFMetadataValue::U32(v as u32);
+    w.add_metadata(&key(KEY_CONTEXT_LENGTH), u32_value(conf.seq_len));
+    w.add_metadata(&key(KEY_EMBEDDING_LENGTH), u32_value(conf.embedding_dim));
+    w.add_metadata(&key(KEY_BLOCK_COUNT), u32_value(conf.n_layers));
+    w.add_metadata(&key(KEY_FEED_FORWARD_LENGTH), u32_value(conf.hidden_dim));
+    w.add_metadata(&key(KEY_ATTENTION_HEAD_COUNT), u32_value(conf.n_heads));
+    w.add_metadata(
+        &key(KEY_ATTENTION_HEAD_COUNT_KV),
+        u32_value(conf.n_kv_heads),
+    );
+    w.add_metadata(
+        &key(KEY_ATTENTION_LAYERNORM_RMS_EPS),
+        GGUFMetadataValue::F32(conf.norm_eps),
+    );
+    w.add_metadata(&key(KEY_ROPE_DIMENSION_COUNT), u32_value(conf.rope_dim));
+    w.add_metadata(
+        &key(KEY_ROPE_FREQ_BASE),
+        GGUFMetadataValue::F32(conf.rope_freq_base),
+    );
+    if conf.rope_freq_scale != 1.0 {
+        let typ = match conf.rope_scaling {
+            RopeScaling::Yarn { orig_ctx_len } => {
+                w.add_metadata(&key(KEY_ROPE_SCALING_ORIG_CTX_LEN), u32_value(orig_ctx_len));
+                "yarn"
+            }
+            _ => "linear",
+        };
+        w.add_metadata(&key(KEY_ROPE_SCALING_TYPE), GGUFMetadataValue::String(typ));
+        w.add_metadata(
+            &key(KEY_ROPE_SCALING_FACTOR),
+            GGUFMetadataValue::F32(1.0 / conf.rope_freq_scale),
+        );
+    }
+    if let Some(window) = conf.sliding_window {
+        w.add_metadata(&key(KEY_ATTENTION_SLIDING_WINDOW), u32_value(window));
+    }
+
+    w.add_metadata(KEY_TOKENIZER_MODEL, GGUFMetadataValue::String(vocab.model));
+    w.add_metadata(
+        KEY_TOKENIZER_LIST,
+        GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+            vocab.tokens.iter().map(|s| s.as_str()).collect(),
+        )),
+    );
+    w.add_metadata(
+        KEY_TOKENIZER_SCORES,
+        GGUFMetadataValue::Array(GGUFMetadataArray::F32Array(&vocab.scores)),
+    );
+    w.add_metadata(
+        KEY_TOKENIZER_TOKEN_TYPE,
+        GGUFMetadataValue::Array(GGUFMetadataArray::I32Array(&vocab.token_types)),
+    );
+    if !vocab.merges.is_empty() {
+        w.add_metadata(
+            KEY_TOKENIZER_MERGES,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                vocab.merges.iter().map(|s| s.as_str()).collect(),
+            )),
+        );
+    }
+    w.add_metadata(KEY_TOKENIZER_BOS_ID, u32_value(vocab.bos_token));
+    w.add_metadata(KEY_TOKENIZER_EOS_ID, u32_value(vocab.eos_token));
+    if let Some(unk_token) = vocab.unk_token {
+        w.add_metadata(KEY_TOKENIZER_UNK_ID, u32_value(unk_token));
+    }
+    w.add_metadata(
+        KEY_TOKENIZER_ADD_BOS,
+        GGUFMetadataValue::Bool(vocab.add_bos_token as u8),
+    );
+    if let Some(chat_template) = &chat_template {
+        w.add_metadata(
+            KEY_TOKENIZER_CHAT_TEMPLATE,
+            GGUFMetadataValue::String(chat_template),
+        );
+    }
+    // the tokenizer.json is kept as it is, which is preferred on loading over the vocabulary
+    // above, as the pipeline of the tokenizer is not expressible in the GGML metadata
+    w.add_metadata(
+        KEY_TOKENIZER_HF_JSON,
+        GGUFMetadataValue::String(&tokenizer_json),
+    );
+
+    let mut n_bytes = 0;
+    for (name, dims, typ, data) in tensors {
+        n_bytes += data.len();
+        w.add_tensor(&name, &dims, typ, data)?;
+    }
+    w.write_to_file(&args.output)?;
+
+    println!(
+        "converted {} into {}: {} {} layers, {:.2} MiB in {}, {}ms",
+        args.input,
+        args.output,
+        arch,
+        conf.n_layers,
+        n_bytes as f64 / 1024.0 / 1024.0,
+        args.out_type.typ(),
+        start_time.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read {}", path.display()),
+        cause: Some(Box::new(err)),
+    })
+}
+
+fn read_tokenizer_config(dir: &Path) -> Result<Option<Value>> {
+    let path = dir.join("tokenizer_config.json");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let v = serde_json::from_str(&read_file(&path)?).map_err(|err| Error {
+        kind: ErrorKind::FormatError,
+        message: format!("failed to parse {}", path.display()),
+        cause: Some(Box::new(err)),
+    })?;
+    Ok(Some(v))
+}
+
+/// the safetensors files of the checkpoint, which may be sharded like
+/// `model-00001-of-00002.safetensors`.
+fn safetensors_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read the directory {}", dir.display()),
+        cause: Some(Box::new(err)),
+    })?;
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "safetensors"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    if paths.is_empty() {
+        return Err((
+            ErrorKind::BadInput,
+            format!("no safetensors file is found in {}", dir.display()),
+        )
+            .into());
+    }
+    Ok(paths)
+}
+
+/// take the vocabulary of the tokenizer.json, with the special tokens of the
+/// tokenizer_config.json or the config.json.
+fn convert_vocab(dir: &Path, config: &Value, vocab_size: usize) -> Result<ConvertVocab> {
+    let json = read_file(&dir.join("tokenizer.json"))?;
+    let root: Value = serde_json::from_str(&json).map_err(|err| Error {
+        kind: ErrorKind::FormatError,
+        message: "failed to parse tokenizer.json".to_string(),
+        cause: Some(Box::new(err)),
+    })?;
+    let tokenizer = BpeTokenizer::from_hf_json(&json, 0, 0)?;
+    let mut tokens = tokenizer.vocab().to_vec();
+    let mut token_types = (0..tokens.len())
+        .map(|id| tokenizer.token_type(id).to_gguf())
+        .collect::<Vec<_>>();
+    // the embedding table may have more rows than the tokens
+    for id in tokens.len()..vocab_size {
+        tokens.push(format!("[PAD{}]", id));
+        token_types.push(BpeTokenType::Unused.to_gguf());
+    }
+    let token_ids = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.as_str(), id))
+        .collect::<HashMap<_, _>>();
+
+    let merges = root["model"]["merges"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|merge| match merge {
+            Value::String(merge) => Some(merge.clone()),
+            Value::Array(pair) => Some(format!(
+                "{} {}",
+                pair.first()?.as_str()?,
+                pair.get(1)?.as_str()?
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // the sentencepiece vocabularies fall back to the bytes like llama, the others are the
+    // byte level BPE of gpt2. the sentencepiece BPE merges the pair of the highest score, so
+    // the scores are taken from the ranks of the merges.
+    let byte_fallback = root["model"]["byte_fallback"].as_bool().unwrap_or(false);
+    let (model, scores, merges) = if byte_fallback {
+        let mut scores = vec![0.0; tokens.len()];
+        for (rank, merge) in merges.iter().enumerate() {
+            if let Some(id) = token_ids.get(merge.replace(' ', "").as_str()) {
+                if scores[*id] == 0.0 {
+                    scores[*id] = -(rank as f32) - 1.0;
+                }
+            }
+        }
+        for (id, token) in tokens.iter().enumerate() {
+            if token.len() == 6 && token.starts_with("<0x") && token.ends_with('>') {
+                token_types[id] = BpeTokenType::Byte.to_gguf();
+            }
+        }
+        ("llama", scores, vec![])
+    } else {
+        ("gpt2", vec![0.0; tokens.len()], merges)
+    };
+
+    let tokenizer_config = read_tokenizer_config(dir)?;
+    let special_token = |name: &str| -> Option<usize> {
+        let v = &tokenizer_config.as_ref()?[name];
+        let content = v.as_str().or_else(|| v["content"].as_str())?;
+        token_ids.get(content).copied()
+    };
+    let config_token = |name: &str| -> Option<usize> {
+        let v = &config[name];
+        v.as_u64().or_else(|| v[0].as_u64()).map(|id| id as usize)
+    };
+    let eos_token = special_token("eos_token")
+        .or_else(|| config_token("eos_token_id"))
+        .ok_or_else(|| {
+            Error::from((
+                ErrorKind::FormatError,
+                "the eos token is not found in the tokenizer_config.json or the config.json",
+            ))
+        })?;
+    // some vocabularies like qwen2 have no bos token
+    let bos_token = special_token("bos_token")
+        .or_else(|| config_token("bos_token_id"))
+        .unwrap_or(eos_token);
+    for token in [bos_token, eos_token] {
+        token_types[token] = BpeTokenType::Control.to_gguf();
+    }
+    let add_bos_token = tokenizer_config
+        .as_ref()
+        .and_then(|v| v["add_bos_token"].as_bool())
+        .unwrap_or(tokenizer.add_bos_token());
+
+    Ok(ConvertVocab {
+        model,
+        tokens,
+        scores,
+        token_types,
+        merges,
+        bos_token,
+        eos_token,
+        unk_token: tokenizer.unk_token(),
+        add_bos_token,
+    })
+}
+
+/// convert the tensors into the names and the layouts of GGUF, the q, k projections are
+/// permuted for the rope of llama.
+#[allow(clippy::type_complexity)]
+fn convert_tensors(
+    files: &[SafetensorsFile],
+    conf: &Llama2Config,
+    out_type: ConvertType,
+) -> Result<Vec<(String, Vec<usize>, GGMLType, Vec<u8>)>> {
+    let find = |name: &str| -> Option<&SafetensorsTensorInfo> {
+        files.iter().find_map(|f| f.get_tensor_info(name))
+    };
+    let mut tensors = vec![];
+    let mut convert = |tensor: ModelTensor, layer: usize| -> Result<()> {
+        let hf_name = tensor
+            .hf_name(layer)
+            .unwrap_or_else(|| tensor.gguf_name(layer));
+        let Some(info) = find(&hf_name) else {
+            return match tensor {
+                // the output weights may be tied with the embedding table
+                ModelTensor::Output
+                | ModelTensor::AttnQBias
+                | ModelTensor::AttnKBias
+                | ModelTensor::AttnVBias => Ok(()),
+                _ => Err((
+                    ErrorKind::FormatError,
+                    format!("failed to find tensor {}", hf_name),
+                )
+                    .into()),
+            };
+        };
+        let shape = info.shape();
+        let buf = info.to_f32_vec()?;
+        let rows = |n_heads: usize| [shape[0], shape.get(1).copied().unwrap_or(1), n_heads];
+        let buf = match (tensor, conf.rope_mode) {
+            (ModelTensor::AttnQ | ModelTensor::AttnQBias, RopeMode::Normal) => {
+                let [n_rows, n_cols, n_heads] = rows(conf.n_heads);
+                permute_hf_rows(buf, &[n_rows, n_cols], n_heads)
+            }
+            (ModelTensor::AttnK | ModelTensor::AttnKBias, RopeMode::Normal) => {
+                let [n_rows, n_cols, n_heads] = rows(conf.n_kv_heads);
+                permute_hf_rows(buf, &[n_rows, n_cols], n_heads)
+            }
+            _ => buf,
+        };
+        let typ = match shape.len() {
+            2 => out_type.typ(),
+            _ => GGMLType::F32,
+        };
+        let data = CpuTensorBuf::F32(Cow::Owned(buf))
+            .quantize(typ)?
+            .as_bytes()
+            .to_vec();
+        let dims = shape.iter().rev().copied().collect::<Vec<_>>();
+        tensors.push((tensor.gguf_name(layer), dims, typ, data));
+        Ok(())
+    };
+
+    for tensor in [
+        ModelTensor::TokenEmbd,
+        ModelTensor::OutputNorm,
+        ModelTensor::Output,
+    ] {
+        convert(tensor, 0)?;
+    }
+    for layer in 0..conf.n_layers {
+        // the tensors in the layout of llama, which have a huggingface name
+        for tensor in ModelTensor::all().filter(|t| t.is_block() && t.hf_name(0).is_some()) {
+            convert(tensor, layer)?;
+        }
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml_llama2::CpuLlama2Model;
+    use serde_json::json;
+
+    use super::*;
+
+    /// write the GGUF model as a HuggingFace checkpoint, with the q, k rows unpermuted.
+    fn write_hf_checkpoint(gguf_path: &str, dir: &Path) -> Result<()> {
+        let gl = GGUFFileLoader::new(gguf_path)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let conf = lm.conf;
+        let unpermute = |buf: Vec<f32>, shape: &[usize], n_heads: usize| {
+            let (n_rows, n_cols) = (shape[0], shape[1]);
+            let half = n_rows / n_heads / 2;
+            let mut out = vec![0.0; buf.len()];
+            for h in 0..n_heads {
+                for i in 0..half {
+                    for j in 0..2 {
+                        let src = (h * half * 2 + i * 2 + j) * n_cols;
+                        let dst = (h * half * 2 + j * half + i) * n_cols;
+                        out[dst..dst + n_cols].copy_from_slice(&buf[src..src + n_cols]);
+                    }
+                }
+            }
+            out
+        };
+
+        let mut header = serde_json::Map::new();
+        let mut data = vec![];
+        for info in gf.tensor_infos() {
+            let (tensor, layer) = ModelTensor::from_gguf_name(info.name()).unwrap();
+            let shape = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+            let buf = info
+                .data()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect::<Vec<_>>();
+            let buf = match tensor {
+                ModelTensor::AttnQ => unpermute(buf, &shape, conf.n_heads),
+                ModelTensor::AttnK => unpermute(buf, &shape, conf.n_kv_heads),
+                _ => buf,
+            };
+            let begin = data.len();
+            data.extend(buf.iter().flat_map(|v| v.to_le_bytes()));
+            header.insert(
+                tensor.hf_name(layer.unwrap_or(0)).unwrap(),
+                json!({"dtype": "F32", "shape": shape, "data_offsets": [begin, data.len()]}),
+            );
+        }
+        let header = Value::Object(header).to_string();
+        let mut buf = (header.len() as u64).to_le_bytes().to_vec();
+        buf.extend_from_slice(header.as_bytes());
+        buf.extend_from_slice(&data);
+        std::fs::write(dir.join("model.safetensors"), buf).unwrap();
+
+        let config = json!({
+            "model_type": "llama",
+            "hidden_size": conf.embedding_dim,
+            "intermediate_size": conf.hidden_dim,
+            "num_hidden_layers": conf.n_layers,
+            "num_attention_heads": conf.n_heads,
+            "num_key_value_heads": conf.n_kv_heads,
+            "vocab_size": conf.vocab_size,
+            "max_position_embeddings": conf.seq_len,
+            "rms_norm_eps": 1e-5,
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+        });
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+        let tokens = gf.metadata().get_string_array(KEY_TOKENIZER_LIST).unwrap();
+        let vocab = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), json!(id)))
+            .collect::<serde_json::Map<_, _>>();
+        let tokenizer = json!({
+            "added_tokens": [
+                {"id": 1, "content": "<s>", "special": true},
+                {"id": 2, "content": "</s>", "special": true},
+            ],
+            "model": {
+                "type": "BPE",
+                "vocab": vocab,
+                "merges": [],
+                "byte_fallback": true,
+                "unk_token": "<unk>",
+            },
+        });
+        std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+        let tokenizer_config =
+            json!({"bos_token": "<s>", "eos_token": "</s>", "chat_template": "{{ messages }}"});
+        std::fs::write(
+            dir.join("tokenizer_config.json"),
+            tokenizer_config.to_string(),
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert() -> Result<()> {
+        let gguf_path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let dir = std::env::temp_dir().join(format!("crabml-convert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_hf_checkpoint(gguf_path, &dir)?;
+
+        let output = dir.join("model.gguf").to_str().unwrap().to_string();
+        for out_type in [ConvertType::F32, ConvertType::F16] {
+            convert(&ConvertArgs {
+                input: dir.to_str().unwrap().to_string(),
+                output: output.clone(),
+                out_type,
+                name: Some("tiny".to_string()),
+            })?;
+
+            let gl = GGUFFileLoader::new(&output)?;
+            let gf = gl.open()?;
+            let metadata = gf.metadata();
+            assert_eq!(metadata.get_string(KEY_GENERAL_NAME), Some("tiny"));
+            assert_eq!(metadata.get_string(KEY_TOKENIZER_MODEL), Some("llama"));
+            assert_eq!(metadata.get_u32(KEY_TOKENIZER_EOS_ID), Some(2));
+            assert_eq!(
+                metadata.get_string(KEY_TOKENIZER_CHAT_TEMPLATE),
+                Some("{{ messages }}")
+            );
+            let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+            assert_eq!(lm.conf.n_layers, 5);
+            assert_eq!(lm.tokenizer.vocab()[1], "<s>");
+
+            let src_gl = GGUFFileLoader::new(gguf_path)?;
+            let src_gf = src_gl.open()?;
+            assert_eq!(gf.tensor_infos().len(), src_gf.tensor_infos().len());
+            for src in src_gf.tensor_infos() {
+                let info = gf.get_tensor_info(src.name()).unwrap();
+                assert_eq!(info.dimensions(), src.dimensions(), "{}", src.name());
+                match (out_type, info.dimensions().len()) {
+                    (ConvertType::F16, 2) => assert_eq!(info.typ(), GGMLType::F16),
+                    _ => assert_eq!(info.data(), src.data(), "{}", src.name()),
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+}
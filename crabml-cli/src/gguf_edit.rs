@@ -0,0 +1,191 @@
+use clap::Args;
+use clap::ValueEnum;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::edit_metadata;
+use crabml::gguf::GGUFEditMode;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFMetadataValueType;
+
+#[derive(Args, Debug)]
+pub struct GGUFSetArgs {
+    /// The GGUF file to edit
+    model: String,
+
+    /// The metadata key, like llama.rope.freq_base
+    key: String,
+
+    /// The new value of the key
+    value: String,
+
+    /// The type of the value, the type of the existing key by default
+    #[arg(long = "type", value_enum)]
+    typ: Option<MetadataType>,
+}
+
+#[derive(Args, Debug)]
+pub struct GGUFRmArgs {
+    /// The GGUF file to edit
+    model: String,
+
+    /// The metadata keys to remove
+    #[arg(required = true)]
+    keys: Vec<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    Bool,
+    String,
+}
+
+impl MetadataType {
+    fn from_value_type(typ: GGUFMetadataValueType) -> Option<Self> {
+        let typ = match typ {
+            GGUFMetadataValueType::U8 => Self::U8,
+            GGUFMetadataValueType::I8 => Self::I8,
+            GGUFMetadataValueType::U16 => Self::U16,
+            GGUFMetadataValueType::I16 => Self::I16,
+            GGUFMetadataValueType::U32 => Self::U32,
+            GGUFMetadataValueType::I32 => Self::I32,
+            GGUFMetadataValueType::U64 => Self::U64,
+            GGUFMetadataValueType::I64 => Self::I64,
+            GGUFMetadataValueType::F32 => Self::F32,
+            GGUFMetadataValueType::F64 => Self::F64,
+            GGUFMetadataValueType::Bool => Self::Bool,
+            GGUFMetadataValueType::String => Self::String,
+            GGUFMetadataValueType::Array => return None,
+        };
+        Some(typ)
+    }
+
+    fn parse<'a>(&self, s: &'a str) -> Result<GGUFMetadataValue<'a>> {
+        fn num<T: std::str::FromStr>(s: &str, typ: MetadataType) -> Result<T> {
+            s.trim().parse::<T>().map_err(|_| {
+                Error::from((
+                    ErrorKind::BadInput,
+                    format!("failed to parse {} as {:?}", s, typ),
+                ))
+            })
+        }
+        let value = match self {
+            Self::U8 => GGUFMetadataValue::U8(num(s, *self)?),
+            Self::I8 => GGUFMetadataValue::I8(num(s, *self)?),
+            Self::U16 => GGUFMetadataValue::U16(num(s, *self)?),
+            Self::I16 => GGUFMetadataValue::I16(num(s, *self)?),
+            Self::U32 => GGUFMetadataValue::U32(num(s, *self)?),
+            Self::I32 => GGUFMetadataValue::I32(num(s, *self)?),
+            Self::U64 => GGUFMetadataValue::U64(num(s, *self)?),
+            Self::I64 => GGUFMetadataValue::I64(num(s, *self)?),
+            Self::F32 => GGUFMetadataValue::F32(num(s, *self)?),
+            Self::F64 => GGUFMetadataValue::F64(num(s, *self)?),
+            Self::Bool => GGUFMetadataValue::Bool(num::<bool>(s, *self)? as u8),
+            Self::String => GGUFMetadataValue::String(s),
+        };
+        Ok(value)
+    }
+}
+
+pub fn gguf_set(args: &GGUFSetArgs) -> Result<()> {
+    let typ = match args.typ {
+        Some(typ) => typ,
+        None => {
+            let gl = GGUFFileLoader::new(&args.model)?;
+            let gf = gl.open()?;
+            let existing = gf.metadata().get(&args.key).ok_or_else(|| {
+                Error::from((
+                    ErrorKind::BadInput,
+                    format!(
+                        "the key {} is not found in {}, pass --type to add it",
+                        args.key, args.model
+                    ),
+                ))
+            })?;
+            MetadataType::from_value_type(existing.typ()).ok_or_else(|| {
+                Error::from((
+                    ErrorKind::NotImplemented,
+                    format!("editing the array of {} is not supported", args.key),
+                ))
+            })?
+        }
+    };
+    let value = typ.parse(&args.value)?;
+    let mode = edit_metadata(&args.model, &[(&args.key, value)], &[])?;
+    print_mode(&args.model, mode);
+    Ok(())
+}
+
+pub fn gguf_rm(args: &GGUFRmArgs) -> Result<()> {
+    let keys = args.keys.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+    let mode = edit_metadata(&args.model, &[], &keys)?;
+    print_mode(&args.model, mode);
+    Ok(())
+}
+
+fn print_mode(path: &str, mode: GGUFEditMode) {
+    match mode {
+        GGUFEditMode::InPlace => println!("rewrote the metadata of {} in place", path),
+        GGUFEditMode::CopyOnWrite => println!("rewrote {} with the new metadata", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gguf_set_and_rm() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("crabml-gguf-set-{}.gguf", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::copy("../testdata/tinyllamas-stories-260k-f32.gguf", &path).unwrap();
+
+        // the type is taken from the existing key
+        gguf_set(&GGUFSetArgs {
+            model: path.clone(),
+            key: "llama.context_length".to_string(),
+            value: "256".to_string(),
+            typ: None,
+        })?;
+        gguf_set(&GGUFSetArgs {
+            model: path.clone(),
+            key: "llama.rope.freq_base".to_string(),
+            value: "10000".to_string(),
+            typ: Some(MetadataType::F32),
+        })?;
+        let err = gguf_set(&GGUFSetArgs {
+            model: path.clone(),
+            key: "llama.block_count".to_string(),
+            value: "five".to_string(),
+            typ: None,
+        })
+        .unwrap_err();
+        assert_eq!(err.message, "failed to parse five as U32");
+        gguf_rm(&GGUFRmArgs {
+            model: path.clone(),
+            keys: vec!["llama.tensor_data_layout".to_string()],
+        })?;
+
+        let gl = GGUFFileLoader::new(&path)?;
+        let gf = gl.open()?;
+        let metadata = gf.metadata();
+        assert_eq!(metadata.get_u32("llama.context_length"), Some(256));
+        assert_eq!(metadata.get_f32("llama.rope.freq_base"), Some(10000.0));
+        assert_eq!(metadata.get("llama.tensor_data_layout"), None);
+        drop(gl);
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+}
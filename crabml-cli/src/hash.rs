@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFWriter;
+use crabml::gguf::KEY_CHECKSUM_SHA256;
+use crabml::gguf::KEY_CHECKSUM_TENSORS_SHA256;
+use crabml::sha256::sha256_hex_reader;
+use serde_json::json;
+
+#[derive(Args, Debug)]
+pub struct HashArgs {
+    /// The GGUF file to hash
+    model: String,
+
+    /// Print the sha256 of each tensor too
+    #[arg(long, default_value_t = false)]
+    tensors: bool,
+
+    /// Verify the tensor data against the checksums stored in the metadata
+    #[arg(long, default_value_t = false)]
+    check: bool,
+
+    /// Write a copy of the GGUF file with the checksums of the tensor data stored in the
+    /// metadata, which are verified on load by --verify-checksums
+    #[arg(long)]
+    write: Option<String>,
+
+    /// Print the result in json for scripting
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+pub fn hash(args: &HashArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let gl = GGUFFileLoader::new(&args.model)?;
+    let gf = gl.open()?;
+
+    // the sha256 of the whole file is the oid of git lfs, which is shown on the HuggingFace Hub
+    let mut f = File::open(&args.model).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to open {}", args.model),
+        cause: Some(Box::new(err)),
+    })?;
+    let file_sha256 = sha256_hex_reader(&mut f)?;
+    let data_sha256 = gf.tensor_data_sha256();
+    let tensor_sha256s = if args.tensors || args.write.is_some() {
+        gf.tensor_sha256s()
+    } else {
+        vec![]
+    };
+    let verified = if args.check {
+        Some(gf.verify_checksums()?)
+    } else {
+        None
+    };
+
+    if let Some(output) = &args.write {
+        let mut w = GGUFWriter::new();
+        for (key, value) in gf.metadata().as_hashmap() {
+            w.add_metadata(key, value.clone());
+        }
+        w.add_metadata(KEY_CHECKSUM_SHA256, GGUFMetadataValue::String(&data_sha256));
+        w.add_metadata(
+            KEY_CHECKSUM_TENSORS_SHA256,
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                tensor_sha256s.iter().map(|s| s.as_str()).collect(),
+            )),
+        );
+        for info in gf.tensor_infos() {
+            w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?;
+        }
+        w.write_to_file(output)?;
+    }
+
+    if args.json {
+        let mut v = json!({
+            "file": args.model,
+            "sha256": file_sha256,
+            "tensor_data_sha256": data_sha256,
+        });
+        if args.tensors {
+            v["tensors"] = gf
+                .tensor_infos()
+                .iter()
+                .zip(tensor_sha256s.iter())
+                .map(|(info, sha256)| json!({"name": info.name(), "sha256": sha256}))
+                .collect();
+        }
+        if let Some(verified) = verified {
+            v["verified"] = json!(verified);
+        }
+        println!("{}", serde_json::to_string_pretty(&v).unwrap());
+        return Ok(());
+    }
+
+    println!("{}  {}", file_sha256, args.model);
+    println!("tensor data: {}", data_sha256);
+    if args.tensors {
+        for (info, sha256) in gf.tensor_infos().iter().zip(tensor_sha256s.iter()) {
+            println!("  {}  {}", sha256, info.name());
+        }
+    }
+    match verified {
+        Some(true) => println!("checksums: ok"),
+        Some(false) => println!("checksums: not stored, add them by --write"),
+        None => {}
+    }
+    if let Some(output) = &args.write {
+        println!("wrote the checksums into {}", output);
+    }
+    println!("hashed in {}ms", start_time.elapsed().as_millis());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_write() -> Result<()> {
+        let output = std::env::temp_dir().join(format!("crabml-hash-{}.gguf", std::process::id()));
+        let args = HashArgs {
+            model: "../testdata/tinyllamas-stories-260k-f32.gguf".to_string(),
+            tensors: true,
+            check: true,
+            write: Some(output.to_str().unwrap().to_string()),
+            json: false,
+        };
+        hash(&args)?;
+
+        let src = GGUFFileLoader::new(&args.model)?;
+        let src = src.open()?;
+        let gl = GGUFFileLoader::new(args.write.as_ref().unwrap())?;
+        let gf = gl.open()?;
+        std::fs::remove_file(&output).unwrap();
+
+        assert!(!src.has_checksums());
+        assert!(gf.verify_checksums()?);
+        assert_eq!(
+            gf.metadata().get_string(KEY_CHECKSUM_SHA256),
+            Some(src.tensor_data_sha256().as_str())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use clap::Args;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use serde_json::json;
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// The GGUF file to inspect
+    model: String,
+
+    /// Print the result in json for scripting
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// The max number of items to print in an array, only used in the table output
+    #[arg(long, default_value_t = 8)]
+    max_array_items: usize,
+}
+
+pub fn inspect(args: &InspectArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model)?;
+    let gf = gl.open()?;
+    let problems = gl
+        .validate()
+        .iter()
+        .map(|err| {
+            // the decoding errors explain where the read failed in their sources
+            let mut message = err.to_string();
+            let mut source = std::error::Error::source(err);
+            while let Some(err) = source {
+                message.push_str(&format!(": {}", err));
+                source = err.source();
+            }
+            message
+        })
+        .collect::<Vec<_>>();
+
+    if args.json {
+        let mut v = inspect_json(&gf);
+        v["problems"] = json!(problems);
+        println!("{}", serde_json::to_string_pretty(&v).unwrap());
+        return Ok(());
+    }
+
+    println!("version: {}", gf.version());
+    println!("architecture: {}", gf.architecture());
+
+    let mut metadata = gf.metadata().as_hashmap().iter().collect::<Vec<_>>();
+    metadata.sort_by_key(|(k, _)| k.to_string());
+    println!("\nmetadata ({}):", metadata.len());
+    let key_width = metadata.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+    for (key, value) in metadata {
+        println!(
+            "  {:key_width$}  {:10}  {}",
+            key,
+            value_type_name(value),
+            format_value(value, args.max_array_items),
+        );
+    }
+
+    let tensor_infos = gf.tensor_infos();
+    let total_bytes = tensor_infos.iter().map(|t| t.data().len()).sum::<usize>();
+    println!(
+        "\ntensors ({}, {}):",
+        tensor_infos.len(),
+        format_bytes(total_bytes)
+    );
+    let name_width = tensor_infos
+        .iter()
+        .map(|t| t.name().len())
+        .max()
+        .unwrap_or(0);
+    for tensor in tensor_infos {
+        println!(
+            "  {:name_width$}  {:6}  {:20}  {}",
+            tensor.name(),
+            tensor.typ().to_string(),
+            format!("{:?}", tensor.dimensions()),
+            format_bytes(tensor.data().len()),
+        );
+    }
+
+    if !problems.is_empty() {
+        println!("\nproblems ({}):", problems.len());
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
+    Ok(())
+}
+
+fn inspect_json(gf: &GGUFFile) -> serde_json::Value {
+    let metadata = gf
+        .metadata()
+        .as_hashmap()
+        .iter()
+        .map(|(k, v)| (k.clone(), value_to_json(v)))
+        .collect::<serde_json::Map<_, _>>();
+    let tensors = gf
+        .tensor_infos()
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name(),
+                "type": t.typ().to_string(),
+                "shape": t.dimensions(),
+                "size": t.data().len(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": gf.version().to_string().parse::<u32>().unwrap(),
+        "architecture": gf.architecture(),
+        "metadata": metadata,
+        "tensors": tensors,
+    })
+}
+
+fn value_type_name(value: &GGUFMetadataValue) -> String {
+    match value {
+        GGUFMetadataValue::Array(arr) => format!("[{:?}]", arr.typ()).to_lowercase(),
+        _ => format!("{:?}", value.typ()).to_lowercase(),
+    }
+}
+
+fn format_value(value: &GGUFMetadataValue, max_items: usize) -> String {
+    match value {
+        GGUFMetadataValue::U8(v) => v.to_string(),
+        GGUFMetadataValue::I8(v) => v.to_string(),
+        GGUFMetadataValue::U16(v) => v.to_string(),
+        GGUFMetadataValue::I16(v) => v.to_string(),
+        GGUFMetadataValue::U32(v) => v.to_string(),
+        GGUFMetadataValue::I32(v) => v.to_string(),
+        GGUFMetadataValue::U64(v) => v.to_string(),
+        GGUFMetadataValue::I64(v) => v.to_string(),
+        GGUFMetadataValue::F32(v) => v.to_string(),
+        GGUFMetadataValue::F64(v) => v.to_string(),
+        GGUFMetadataValue::Bool(v) => (*v != 0).to_string(),
+        GGUFMetadataValue::String(v) => format!("{:?}", v),
+        GGUFMetadataValue::Array(arr) => {
+            let items = array_to_json(arr);
+            let items = items.as_array().unwrap();
+            let mut s = items
+                .iter()
+                .take(max_items)
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if items.len() > max_items {
+                s.push_str(&format!(", ... ({} items)", items.len()));
+            }
+            format!("[{}]", s)
+        }
+    }
+}
+
+fn value_to_json(value: &GGUFMetadataValue) -> serde_json::Value {
+    match value {
+        GGUFMetadataValue::U8(v) => json!(v),
+        GGUFMetadataValue::I8(v) => json!(v),
+        GGUFMetadataValue::U16(v) => json!(v),
+        GGUFMetadataValue::I16(v) => json!(v),
+        GGUFMetadataValue::U32(v) => json!(v),
+        GGUFMetadataValue::I32(v) => json!(v),
+        GGUFMetadataValue::U64(v) => json!(v),
+        GGUFMetadataValue::I64(v) => json!(v),
+        GGUFMetadataValue::F32(v) => json!(v),
+        GGUFMetadataValue::F64(v) => json!(v),
+        GGUFMetadataValue::Bool(v) => json!(*v != 0),
+        GGUFMetadataValue::String(v) => json!(v),
+        GGUFMetadataValue::Array(arr) => array_to_json(arr),
+    }
+}
+
+fn array_to_json(arr: &GGUFMetadataArray) -> serde_json::Value {
+    match arr {
+        GGUFMetadataArray::U8Array(v) => json!(v),
+        GGUFMetadataArray::I8Array(v) => json!(v),
+        GGUFMetadataArray::U16Array(v) => json!(v),
+        GGUFMetadataArray::I16Array(v) => json!(v),
+        GGUFMetadataArray::U32Array(v) => json!(v),
+        GGUFMetadataArray::I32Array(v) => json!(v),
+        GGUFMetadataArray::U64Array(v) => json!(v),
+        GGUFMetadataArray::I64Array(v) => json!(v),
+        GGUFMetadataArray::F32Array(v) => json!(v),
+        GGUFMetadataArray::F64Array(v) => json!(v),
+        GGUFMetadataArray::BoolArray(v) => json!(v.iter().map(|v| *v != 0).collect::<Vec<_>>()),
+        GGUFMetadataArray::StringArray(v) => json!(v),
+        GGUFMetadataArray::NestedArray(v) => {
+            serde_json::Value::Array(v.iter().map(array_to_json).collect())
+        }
+    }
+}
+
+fn format_bytes(n: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = n as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{} B", n);
+    }
+    format!("{:.2} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspect_json() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let v = inspect_json(&gf);
+        assert_eq!(v["version"], 1);
+        assert_eq!(v["architecture"], "llama");
+        assert_eq!(v["metadata"]["llama.block_count"], 5);
+        assert_eq!(v["metadata"]["tokenizer.ggml.tokens"][1], "<s>");
+        assert_eq!(v["tensors"][0]["name"], "token_embd.weight");
+        assert_eq!(v["tensors"][0]["type"], "F32");
+        assert_eq!(v["tensors"][0]["shape"], json!([64, 512]));
+        assert_eq!(v["tensors"][0]["size"], 64 * 512 * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_value() {
+        let arr = GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(vec!["a", "b", "c"]));
+        assert_eq!(format_value(&arr, 2), "[\"a\", \"b\", ... (3 items)]");
+        assert_eq!(format_value(&arr, 3), "[\"a\", \"b\", \"c\"]");
+        assert_eq!(value_type_name(&arr), "[string]");
+        assert_eq!(format_value(&GGUFMetadataValue::Bool(1), 2), "true");
+        assert_eq!(format_bytes(1000), "1000 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.00 MiB");
+    }
+}
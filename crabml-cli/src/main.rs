@@ -1,21 +1,211 @@
 use std::io::Write;
 use std::time::Instant;
 
+use clap::ArgMatches;
+use clap::CommandFactory;
+use clap::FromArgMatches;
 use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use config::merge;
+use config::GenerationConfig;
 use crabml::backends::cpu::CpuTensorDevice;
+#[cfg(feature = "cuda")]
+use crabml::backends::cuda::CudaTensorDevice;
+#[cfg(feature = "cuda")]
+use crabml::backends::cuda::CudaTensorDeviceOptions;
+#[cfg(target_os = "macos")]
+use crabml::backends::metal::MetalTensorDevice;
+#[cfg(target_os = "macos")]
+use crabml::backends::metal::MetalTensorDeviceOptions;
+use crabml::backends::wgpu::WgpuTensorDevice;
+use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::file_buf::FileBufOptions;
+use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFileLoader;
+use crabml::tensor::Tensor;
+use crabml::tensor::TensorBackend;
 use crabml::tensor::TensorDeviceMetrics;
+use crabml::tensor::TensorProfiler;
+use crabml::tokenizer::BpeSpecialMode;
+use crabml::tokenizer::BpeTokenizer;
+use crabml_llama2::chat_template::ChatMessage;
+use crabml_llama2::chat_template::ChatTemplate;
+use crabml_llama2::control_vector::Llama2ControlVector;
+use crabml_llama2::grammar::Grammar;
+use crabml_llama2::llama2::Llama2BeamSearchOptions;
 use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::lora::Llama2LoraAdapter;
+use crabml_llama2::model::Llama2Model;
 use crabml_llama2::sampler::Llama2Sampler;
+use crabml_llama2::sampler::Llama2SamplerDry;
+use crabml_llama2::sampler::Llama2SamplerPenalties;
+use crabml_llama2::sampler::Llama2SamplerStage;
+use crabml_llama2::session::Llama2Session;
 use crabml_llama2::CpuLlama2Model;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+mod bench;
+mod chat;
+mod config;
+mod convert;
+mod gguf_edit;
+mod hash;
+mod inspect;
+mod metrics;
+mod perplexity;
+mod pull;
+mod quantize;
+mod serve;
+mod tokenize;
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The config file of the model and the generation, `crabml.toml` in the working
+    /// directory is taken if it exists. The flags given on the command line take precedence
+    #[arg(long, global = true)]
+    config: Option<String>,
+
+    #[command(flatten)]
+    run: CommandArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the metadata and tensors of a GGUF file
+    Inspect(inspect::InspectArgs),
+    /// Quantize the tensors of a f32/f16 GGUF file into a new GGUF file
+    Quantize(quantize::QuantizeArgs),
+    /// Print the tokens of a text by the tokenizer of a GGUF file
+    Tokenize(tokenize::TokenizeArgs),
+    /// Compute the perplexity of a model on the chunks of a text file
+    Perplexity(perplexity::PerplexityArgs),
+    /// Benchmark the prefill and the decoding throughputs of a model on the cpu
+    Bench(bench::BenchArgs),
+    /// Serve the model on the OpenAI compatible http api
+    Serve(serve::ServeArgs),
+    /// Chat with the model on the terminal, with the history kept across the turns
+    Chat(chat::ChatArgs),
+    /// Download a GGUF model from the HuggingFace Hub into the cache
+    Pull(pull::PullArgs),
+    /// Print the sha256 of a GGUF file and its tensors, and verify or store the checksums
+    Hash(hash::HashArgs),
+    /// Convert a HuggingFace checkpoint in safetensors into a GGUF file
+    Convert(convert::ConvertArgs),
+    /// Set a metadata key of a GGUF file, in place if the header keeps its size
+    #[command(name = "gguf-set")]
+    GGUFSet(gguf_edit::GGUFSetArgs),
+    /// Remove the metadata keys of a GGUF file
+    #[command(name = "gguf-rm")]
+    GGUFRm(gguf_edit::GGUFRmArgs),
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Cpu,
+    Wgpu,
+    #[cfg(target_os = "macos")]
+    Metal,
+    #[cfg(feature = "cuda")]
+    Cuda,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SamplerStage {
+    #[value(name = "tfs_z")]
+    TfsZ,
+    #[value(name = "typical_p")]
+    TypicalP,
+    #[value(name = "top_p")]
+    TopP,
+    #[value(name = "min_p")]
+    MinP,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheType {
+    #[value(name = "f32")]
+    F32,
+    #[value(name = "q8_0")]
+    Q8_0,
+    #[value(name = "q4_0")]
+    Q4_0,
+}
+
+impl CacheType {
+    fn dtype(&self) -> GGMLType {
+        match self {
+            CacheType::F32 => GGMLType::F32,
+            CacheType::Q8_0 => GGMLType::Q8_0,
+            CacheType::Q4_0 => GGMLType::Q4_0,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ActivationType {
+    #[value(name = "f32")]
+    F32,
+    #[value(name = "f16")]
+    F16,
+}
+
+impl ActivationType {
+    fn dtype(&self) -> GGMLType {
+        match self {
+            ActivationType::F32 => GGMLType::F32,
+            ActivationType::F16 => GGMLType::F16,
+        }
+    }
+}
+
+/// The options of loading the model file, shared by the subcommands which load a model.
+#[derive(clap::Args, Debug, Clone, Default)]
+struct LoadArgs {
+    /// Lock the weights in the RAM, so they're not swapped out or evicted under the memory
+    /// pressure. It's limited by `ulimit -l`.
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+
+    /// Read the whole model file into the memory on loading instead of mmaping it, which
+    /// takes longer to start, but has no stalls on the page faults in the first generation.
+    #[arg(long, default_value_t = false)]
+    no_mmap: bool,
+
+    /// Read ahead the mmaped model file in the background on loading, so the first
+    /// generation takes less page faults.
+    #[arg(long, default_value_t = false, conflicts_with = "no_mmap")]
+    prefetch: bool,
+}
+
+impl LoadArgs {
+    fn options(&self) -> FileBufOptions {
+        FileBufOptions {
+            mmap: !self.no_mmap,
+            mlock: self.mlock,
+            prefetch: self.prefetch,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
 struct CommandArgs {
     /// The checkpoint file to load
     #[arg(short, long, default_value_t = format!("./testdata/tinyllamas-stories-15m-f32.gguf"))]
     model: String,
 
+    /// The tokenizer.json of HuggingFace to replace the tokenizer of the model
+    #[arg(long)]
+    tokenizer: Option<String>,
+
     // The number of tokens to generate
     #[arg(short, long, default_value_t = 300)]
     steps: usize,
@@ -27,46 +217,444 @@ struct CommandArgs {
     #[arg(short, long, default_value_t = 1.0)]
     temperature: f32,
 
+    /// The min-p sampling threshold, it replaces the top-p sampling if set.
+    #[arg(long, default_value_t = 0.0)]
+    min_p: f32,
+
+    /// The locally typical sampling threshold, 1.0 means disabled.
+    #[arg(long, default_value_t = 1.0)]
+    typical_p: f32,
+
+    /// The tail free sampling threshold, 1.0 means disabled.
+    #[arg(long, default_value_t = 1.0)]
+    tfs_z: f32,
+
+    /// The order of the enabled truncation stages, used when the typical or the tail free
+    /// sampling is enabled.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "tfs_z,typical_p,top_p,min_p"
+    )]
+    sampler_order: Vec<SamplerStage>,
+
+    /// Penalize the tokens repeated in the last N tokens, 0 disables the penalties.
+    #[arg(long, default_value_t = 64)]
+    penalty_last_n: usize,
+
+    /// The repetition penalty, 1.0 means disabled.
+    #[arg(long, default_value_t = 1.0)]
+    repeat_penalty: f32,
+
+    /// The frequency penalty, 0.0 means disabled.
+    #[arg(long, default_value_t = 0.0)]
+    frequency_penalty: f32,
+
+    /// The presence penalty, 0.0 means disabled.
+    #[arg(long, default_value_t = 0.0)]
+    presence_penalty: f32,
+
+    /// The multiplier of the DRY penalty on the repeated sequences, 0.0 means disabled.
+    #[arg(long, default_value_t = 0.0)]
+    dry_multiplier: f32,
+
+    /// The base of the DRY penalty, which grows exponentially with the repeated length.
+    #[arg(long, default_value_t = 1.75)]
+    dry_base: f32,
+
+    /// The repeated sequences not longer than it are not penalized by DRY.
+    #[arg(long, default_value_t = 2)]
+    dry_allowed_length: usize,
+
+    /// Search the repetitions of DRY in the last N tokens.
+    #[arg(long, default_value_t = 1024)]
+    dry_penalty_last_n: usize,
+
+    /// The sequence breakers of DRY, replaces the default breakers if given.
+    #[arg(long = "dry-sequence-breaker")]
+    dry_sequence_breakers: Vec<String>,
+
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 
-    #[arg(short = 'T', long, default_value_t = 2)]
+    /// Verify the tensor data against the checksums stored by `hash --write` before running,
+    /// so a corrupted file fails instead of generating garbage
+    #[arg(long, default_value_t = false)]
+    verify_checksums: bool,
+
+    /// Profile the ops on the cpu, and print the walltime, the flops and the bytes moved of
+    /// each op per token after the generation
+    #[arg(long, default_value_t = false)]
+    profile: bool,
+
+    /// Write the profiled ops on the cpu into the file in the chrome trace format, which is
+    /// opened by chrome://tracing or perfetto
+    #[arg(long)]
+    profile_trace: Option<String>,
+
+    /// The number of the threads running the matmuls on the cpu, the number of the cpus if 0
+    #[arg(short = 'T', long, default_value_t = 0)]
     threads: usize,
 
-    /// The prompt
-    prompt: String,
+    /// The size of the context in tokens, capped at the context length of the model, which
+    /// is taken by default
+    #[arg(long)]
+    ctx_size: Option<usize>,
+
+    /// The device to run the model on, the weights are dequantized into f32 on wgpu, which
+    /// runs on any gpu of vulkan, metal or dx12. metal runs the Q8_0 and Q4_0 weights as they
+    /// are on apple silicon. cuda runs the matmuls on cublas, which is built with the cuda
+    /// feature.
+    #[arg(long, value_enum, default_value_t = Device::Cpu)]
+    device: Device,
+
+    /// The number of the layers offloaded to the gpu device, all the layers are offloaded by
+    /// default. the first layers run on the gpu and the rest on the cpu, and 0 runs the model
+    /// on the cpu.
+    #[arg(long)]
+    n_gpu_layers: Option<usize>,
+
+    /// The type of the kv cache, the quantized types save the memory on the long contexts.
+    #[arg(long, value_enum, default_value_t = CacheType::F32)]
+    cache_type: CacheType,
+
+    /// The type of the inputs of the matmuls, f16 halves their memory and bandwidth with the
+    /// products still accumulated in f32. It's taken on the cpu and wgpu.
+    #[arg(long, value_enum, default_value_t = ActivationType::F32)]
+    activation_type: ActivationType,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Generate with the tokens drafted by the smaller model of the same vocab, which are
+    /// verified by the model in one pass.
+    #[arg(long)]
+    draft_model: Option<String>,
+
+    /// Generate with the tokens drafted from the matches of the last n-grams of up to N
+    /// tokens in the context, which takes no draft model.
+    #[arg(long, conflicts_with = "draft_model")]
+    lookup_ngram: Option<usize>,
+
+    /// The max number of the tokens drafted on each step of the speculative decoding.
+    #[arg(long, default_value_t = 5)]
+    draft: usize,
+
+    /// The max number of the prompt tokens forwarded together.
+    #[arg(long, default_value_t = 512)]
+    batch_size: usize,
+
+    /// Take the attention in a fused kernel with the online softmax over the tiles of the kv
+    /// cache, which does not keep the scores of the whole context.
+    #[arg(long = "flash-attn", default_value_t = false)]
+    flash_attn: bool,
+
+    /// Shift the context once it's full instead of stopping, the first N tokens are kept and
+    /// the half of the rest are dropped.
+    #[arg(long)]
+    keep: Option<usize>,
+
+    /// Restore the kv cache from the session file if it exists, and save it into the file
+    /// after the generation. Only the rest of the prompt after the tokens of the session is
+    /// forwarded.
+    #[arg(long)]
+    session: Option<String>,
+
+    /// Add a bias to the logit of a token, like `13=-inf` to ban the token 13. Can be
+    /// repeated.
+    #[arg(long, value_parser = parse_logit_bias)]
+    logit_bias: Vec<(usize, f32)>,
+
+    /// Apply a LoRA adapter in GGUF on the model, like `adapter.gguf=0.5` to apply it at
+    /// the half scale. Can be repeated to stack the adapters.
+    #[arg(long, value_parser = parse_scaled_path)]
+    lora: Vec<(String, f32)>,
+
+    /// Steer the output by a control vector in GGUF, like `happy.gguf=0.8` to add it at the
+    /// strength 0.8. Can be repeated to add up the vectors.
+    #[arg(long, value_parser = parse_scaled_path)]
+    control_vector: Vec<(String, f32)>,
+
+    /// Guide the generation away from a negative prompt by the classifier-free guidance,
+    /// which takes a second pass on each token
+    #[arg(long)]
+    negative_prompt: Option<String>,
+
+    /// The scale of the classifier-free guidance, 1.0 takes no guidance
+    #[arg(long, default_value_t = 1.5, requires = "negative_prompt")]
+    cfg_scale: f32,
+
+    /// Constrain the output to a GBNF grammar
+    #[arg(long, conflicts_with = "grammar_file")]
+    grammar: Option<String>,
+
+    /// Constrain the output to the GBNF grammar in the file
+    #[arg(long)]
+    grammar_file: Option<String>,
+
+    /// Constrain the output to a JSON value of the JSON schema
+    #[arg(long, conflicts_with_all = ["grammar", "grammar_file"])]
+    json_schema: Option<String>,
+
+    /// Generate with the beam search of the width instead of sampling, 0 disables it.
+    #[arg(long, default_value_t = 0)]
+    beam_width: usize,
+
+    /// The length penalty of the beam search, the larger prefers the longer outputs.
+    #[arg(long, default_value_t = 1.0)]
+    length_penalty: f32,
+
+    /// The number of the best outputs to print on the beam search.
+    #[arg(long, default_value_t = 1)]
+    n_best: usize,
+
+    /// Stop the generation on the sequence, can be given multiple times.
+    #[arg(long = "stop")]
+    stop_sequences: Vec<String>,
+
+    /// Render the generated special tokens like `<|im_end|>` instead of skipping them.
+    #[arg(long)]
+    special: bool,
+
+    /// Print the log probabilities of the generated tokens with the N most likely tokens on
+    /// each position, 0 disables it.
+    #[arg(long, default_value_t = 0)]
+    logprobs: usize,
+
+    /// Format the prompt as a user message by the chat template of the model.
+    #[arg(long)]
+    chat: bool,
+
+    /// The system message of the chat.
+    #[arg(long, requires = "chat")]
+    system: Option<String>,
+
+    /// Use a built-in chat template instead of the one of the model.
+    #[arg(long, requires = "chat", value_parser = ["llama2", "chatml", "mistral"])]
+    chat_template: Option<String>,
+
+    /// The seed of the random number generator for sampling, random if not set.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// The prompt, required unless a subcommand is given
+    prompt: Option<String>,
+}
+
+impl CommandArgs {
+    /// fill the flags not given on the command line from the config file.
+    fn apply_config(&mut self, config: &GenerationConfig, matches: &ArgMatches) {
+        merge(matches, "model", &mut self.model, config.model.clone());
+        merge(matches, "steps", &mut self.steps, config.max_tokens);
+        merge(
+            matches,
+            "temperature",
+            &mut self.temperature,
+            config.temperature,
+        );
+        merge(matches, "probability", &mut self.probability, config.top_p);
+        merge(matches, "min_p", &mut self.min_p, config.min_p);
+        merge(matches, "seed", &mut self.seed, config.seed.map(Some));
+        merge(matches, "threads", &mut self.threads, config.threads);
+        merge(
+            matches,
+            "ctx_size",
+            &mut self.ctx_size,
+            config.ctx_size.map(Some),
+        );
+        merge(
+            matches,
+            "system",
+            &mut self.system,
+            config.system.clone().map(Some),
+        );
+        merge(
+            matches,
+            "chat_template",
+            &mut self.chat_template,
+            config.chat_template.clone().map(Some),
+        );
+    }
 }
 
 fn main() -> Result<()> {
-    let args = CommandArgs::parse();
-    let start_time = Instant::now();
+    // the spans of the inference are logged on their close with the durations, filtered by
+    // RUST_LOG like RUST_LOG=crabml=debug. the logs go to stderr, stdout is for the output.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let config = GenerationConfig::discover(cli.config.as_deref())?;
+    match &mut cli.command {
+        Some(Command::Serve(args)) => {
+            args.apply_config(&config, matches.subcommand_matches("serve").unwrap())?;
+            serve::serve(args)
+        }
+        Some(Command::Chat(args)) => {
+            args.apply_config(&config, matches.subcommand_matches("chat").unwrap())?;
+            chat::chat(args)
+        }
+        Some(Command::Inspect(args)) => inspect::inspect(args),
+        Some(Command::Quantize(args)) => quantize::quantize(args),
+        Some(Command::Tokenize(args)) => tokenize::tokenize(args),
+        Some(Command::Perplexity(args)) => perplexity::perplexity(args),
+        Some(Command::Bench(args)) => bench::bench(args),
+        Some(Command::Pull(args)) => pull::pull(args),
+        Some(Command::Hash(args)) => hash::hash(args),
+        Some(Command::Convert(args)) => convert::convert(args),
+        Some(Command::GGUFSet(args)) => gguf_edit::gguf_set(args),
+        Some(Command::GGUFRm(args)) => gguf_edit::gguf_rm(args),
+        None => {
+            if cli.run.prompt.is_none() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::MissingRequiredArgument,
+                        "the following required argument was not provided: <PROMPT>",
+                    )
+                    .exit();
+            }
+            cli.run.apply_config(&config, &matches);
+            run(&cli.run)
+        }
+    }
+}
+
+fn parse_logit_bias(s: &str) -> std::result::Result<(usize, f32), String> {
+    let (token, bias) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TOKEN=BIAS, got {}", s))?;
+    let token = token
+        .parse()
+        .map_err(|err| format!("invalid token {}: {}", token, err))?;
+    let bias = bias
+        .parse()
+        .map_err(|err| format!("invalid bias {}: {}", bias, err))?;
+    Ok((token, bias))
+}
 
-    // configure rayon
-    let mut threads = args.threads;
-    if threads == 0 {
-        threads = num_cpus::get();
+fn parse_scaled_path(s: &str) -> std::result::Result<(String, f32), String> {
+    match s.rsplit_once('=') {
+        Some((path, scale)) => {
+            let scale = scale
+                .parse()
+                .map_err(|err| format!("invalid scale {}: {}", scale, err))?;
+            Ok((path.to_string(), scale))
+        }
+        None => Ok((s.to_string(), 1.0)),
     }
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(threads)
-        .build_global()
-        .unwrap();
+}
+
+fn run(args: &CommandArgs) -> Result<()> {
+    let start_time = Instant::now();
 
-    let gl = GGUFFileLoader::new(&args.model)?;
+    let gl = GGUFFileLoader::new_with_options(&args.model, args.load.options())?;
     let gf = gl.open()?;
+    if args.verify_checksums && !gf.verify_checksums()? {
+        eprintln!("{} has no checksums to verify", args.model);
+    }
 
     let metrics = TensorDeviceMetrics::default();
-    let device_cpu = CpuTensorDevice::new().with_metrics(metrics.clone());
-    let model_cpu = CpuLlama2Model::load(&gf, device_cpu)?;
+    let mut device_cpu = CpuTensorDevice::new()
+        .with_metrics(metrics.clone())
+        .with_threads(args.threads)?;
+    if args.profile || args.profile_trace.is_some() {
+        device_cpu = device_cpu.with_profiler(TensorProfiler::new());
+    }
+    let threads = device_cpu.threads();
+    let mut model_cpu = CpuLlama2Model::load(&gf, device_cpu)?;
+    if let Some(path) = &args.tokenizer {
+        let json = std::fs::read_to_string(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the tokenizer file {}", path),
+            cause: Some(Box::new(err)),
+        })?;
+        let tokenizer = model_cpu.tokenizer();
+        let tokenizer =
+            BpeTokenizer::from_hf_json(&json, tokenizer.bos_token(), tokenizer.eos_token())?;
+        model_cpu = model_cpu.with_tokenizer(tokenizer);
+    }
+    if let Some(n_ctx) = args.ctx_size {
+        model_cpu = model_cpu.with_context_size(n_ctx);
+    }
     let conf = model_cpu.conf();
 
-    // let device_wgpu = WgpuTensorDevice::new(
-    //     WgpuTensorDeviceOptions::new().with_staging_buf_bytes(conf.vocab_size * 4),
-    // );
-    // let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
-
-    let mut sampler = Llama2Sampler::new(conf.vocab_size, args.temperature, args.probability);
-    let mut runner = Llama2Runner::try_from(&model_cpu)?;
-
+    let mut sampler = Llama2Sampler::new(conf.vocab_size, args.temperature, args.probability)
+        .with_minp(args.min_p)
+        .with_penalties(Llama2SamplerPenalties {
+            last_n: args.penalty_last_n,
+            repeat: args.repeat_penalty,
+            frequency: args.frequency_penalty,
+            presence: args.presence_penalty,
+        });
+    if args.dry_multiplier > 0.0 {
+        let mut dry = Llama2SamplerDry {
+            multiplier: args.dry_multiplier,
+            base: args.dry_base,
+            allowed_length: args.dry_allowed_length,
+            last_n: args.dry_penalty_last_n,
+            ..Default::default()
+        };
+        if !args.dry_sequence_breakers.is_empty() {
+            dry.sequence_breakers = args.dry_sequence_breakers.clone();
+        }
+        sampler = sampler.with_dry(dry, &model_cpu.tokenizer());
+    }
+    if args.typical_p < 1.0 || args.tfs_z < 1.0 {
+        let enabled = |p: f32| p > 0.0 && p < 1.0;
+        let stages = args
+            .sampler_order
+            .iter()
+            .filter_map(|stage| match stage {
+                SamplerStage::TfsZ if enabled(args.tfs_z) => {
+                    Some(Llama2SamplerStage::TailFree(args.tfs_z))
+                }
+                SamplerStage::TypicalP if enabled(args.typical_p) => {
+                    Some(Llama2SamplerStage::Typical(args.typical_p))
+                }
+                SamplerStage::TopP if enabled(args.probability) => {
+                    Some(Llama2SamplerStage::TopP(args.probability))
+                }
+                SamplerStage::MinP if enabled(args.min_p) => {
+                    Some(Llama2SamplerStage::MinP(args.min_p))
+                }
+                _ => None,
+            })
+            .collect();
+        sampler = sampler.with_stages(stages);
+    }
+    if let Some(seed) = args.seed {
+        sampler = sampler.with_seed(seed);
+    }
+    if args.logprobs > 0 {
+        sampler = sampler.with_logprobs(args.logprobs);
+    }
+    if !args.logit_bias.is_empty() {
+        sampler = sampler.with_logit_bias(args.logit_bias.iter().copied().collect());
+    }
+    let grammar = match (&args.grammar, &args.grammar_file) {
+        (Some(grammar), _) => Some(grammar.clone()),
+        (None, Some(path)) => Some(std::fs::read_to_string(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the grammar file {}", path),
+            cause: Some(Box::new(err)),
+        })?),
+        (None, None) => None,
+    };
+    if let Some(grammar) = grammar {
+        sampler = sampler.with_grammar(Grammar::parse(&grammar)?, &model_cpu.tokenizer());
+    }
+    if let Some(schema) = &args.json_schema {
+        let schema = serde_json::from_str(schema).map_err(|err| Error {
+            kind: ErrorKind::BadInput,
+            message: "failed to parse the json schema".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+        sampler = sampler.with_json_schema(&schema, &model_cpu.tokenizer())?;
+    }
     if args.verbose {
         for tensor in gf.tensor_infos() {
             println!(
@@ -79,19 +667,289 @@ fn main() -> Result<()> {
         println!("loaded model: {}ms", start_time.elapsed().as_millis());
     }
 
-    let mut output = runner.generate(&args.prompt, args.steps, &mut sampler)?;
-    print!("{}", &args.prompt);
+    let mut prompt = args.prompt.clone().unwrap_or_default();
+    if args.chat {
+        let template = match &args.chat_template {
+            Some(name) => ChatTemplate::builtin(name).unwrap(),
+            None => ChatTemplate::from_gguf(&gf),
+        };
+        let mut messages = vec![];
+        if let Some(system) = &args.system {
+            messages.push(ChatMessage::new("system", system));
+        }
+        messages.push(ChatMessage::new("user", prompt));
+        prompt = template.render(&messages, true, &model_cpu.tokenizer())?;
+    }
+    let prompt = prompt.as_str();
+
+    let device = match args.n_gpu_layers {
+        Some(0) => Device::Cpu,
+        _ => args.device,
+    };
+    let n_gpu_layers = args
+        .n_gpu_layers
+        .unwrap_or(conf.n_layers)
+        .min(conf.n_layers);
+    match device {
+        Device::Cpu => {
+            let mut runner = Llama2Runner::try_from(&model_cpu)?
+                .with_kv_cache_dtype(args.cache_type.dtype())?
+                .with_activation_dtype(args.activation_type.dtype())?
+                .with_flash_attention(args.flash_attn)
+                .with_batch_size(args.batch_size);
+            let gl_loras = args
+                .lora
+                .iter()
+                .map(|(path, _)| GGUFFileLoader::new(path))
+                .collect::<Result<Vec<_>>>()?;
+            let gf_loras = gl_loras
+                .iter()
+                .map(|gl| gl.open())
+                .collect::<Result<Vec<_>>>()?;
+            for ((path, _), gf) in args.lora.iter().zip(gf_loras.iter()) {
+                let adapter = Llama2LoraAdapter::load(gf, model_cpu.device.clone())?;
+                runner.add_lora(path, adapter)?;
+            }
+            let loras = args
+                .lora
+                .iter()
+                .map(|(path, scale)| (path.as_str(), *scale))
+                .collect::<Vec<_>>();
+            runner.set_loras(&loras)?;
+
+            let gl_draft = args
+                .draft_model
+                .as_deref()
+                .map(GGUFFileLoader::new)
+                .transpose()?;
+            let gf_draft = gl_draft.as_ref().map(|gl| gl.open()).transpose()?;
+            let model_draft = gf_draft
+                .as_ref()
+                .map(|gf| CpuLlama2Model::load(gf, CpuTensorDevice::new().with_threads(threads)?))
+                .transpose()?;
+            let mut draft = model_draft
+                .as_ref()
+                .map(Llama2Runner::try_from)
+                .transpose()?
+                .map(|draft| draft.with_flash_attention(args.flash_attn));
+            let draft = draft.as_mut();
+            generate(
+                args,
+                &mut runner,
+                draft,
+                &mut sampler,
+                prompt,
+                &metrics,
+                model_cpu.device.profiler(),
+                threads,
+            )
+        }
+        Device::Wgpu => {
+            let device_wgpu = WgpuTensorDevice::try_new(
+                WgpuTensorDeviceOptions::new().with_staging_buf_bytes(conf.vocab_size * 4),
+            )?;
+            generate_on_gpu(
+                args,
+                &model_cpu,
+                device_wgpu,
+                n_gpu_layers,
+                &mut sampler,
+                prompt,
+                &metrics,
+                threads,
+            )
+        }
+        #[cfg(target_os = "macos")]
+        Device::Metal => {
+            let device_metal = MetalTensorDevice::try_new(MetalTensorDeviceOptions::new())?;
+            generate_on_gpu(
+                args,
+                &model_cpu,
+                device_metal,
+                n_gpu_layers,
+                &mut sampler,
+                prompt,
+                &metrics,
+                threads,
+            )
+        }
+        #[cfg(feature = "cuda")]
+        Device::Cuda => {
+            let device_cuda = CudaTensorDevice::try_new(CudaTensorDeviceOptions::new())?;
+            generate_on_gpu(
+                args,
+                &model_cpu,
+                device_cuda,
+                n_gpu_layers,
+                &mut sampler,
+                prompt,
+                &metrics,
+                threads,
+            )
+        }
+    }
+}
+
+/// upload the model onto the gpu backend and generate on it, the first `n_gpu_layers` layers
+/// only if they're fewer than the layers of the model, and the rest run on the cpu.
+#[allow(clippy::too_many_arguments)]
+fn generate_on_gpu<B: TensorBackend>(
+    args: &CommandArgs,
+    model_cpu: &CpuLlama2Model,
+    device: B,
+    n_gpu_layers: usize,
+    sampler: &mut Llama2Sampler,
+    prompt: &str,
+    metrics: &TensorDeviceMetrics,
+    threads: usize,
+) -> Result<()>
+where
+    B::Tensor: 'static,
+{
+    if !args.lora.is_empty() || args.draft_model.is_some() {
+        return Err(Error {
+            kind: ErrorKind::NotImplemented,
+            message: format!(
+                "the lora adapters and the draft model are not supported on {} yet",
+                device.name()
+            ),
+            cause: None,
+        });
+    }
+    if n_gpu_layers < model_cpu.conf.n_layers {
+        let model_gpu = Llama2Model::from_cpu_layers(model_cpu, device, n_gpu_layers)?;
+        let offload = Llama2Runner::try_from(&model_gpu)?
+            .with_kv_cache_dtype(args.cache_type.dtype())?
+            .with_activation_dtype(args.activation_type.dtype())?
+            .with_flash_attention(args.flash_attn);
+        let mut runner = Llama2Runner::try_from(model_cpu)?
+            .with_offloaded_layers(offload, n_gpu_layers)?
+            .with_kv_cache_dtype(args.cache_type.dtype())?
+            .with_activation_dtype(args.activation_type.dtype())?
+            .with_flash_attention(args.flash_attn)
+            .with_batch_size(args.batch_size);
+        let profiler = model_cpu.device.profiler();
+        return generate(
+            args,
+            &mut runner,
+            None,
+            sampler,
+            prompt,
+            metrics,
+            profiler,
+            threads,
+        );
+    }
+    let model_gpu = Llama2Model::from_cpu(model_cpu, device)?;
+    let mut runner = Llama2Runner::try_from(&model_gpu)?
+        .with_kv_cache_dtype(args.cache_type.dtype())?
+        .with_activation_dtype(args.activation_type.dtype())?
+        .with_flash_attention(args.flash_attn)
+        .with_batch_size(args.batch_size);
+    let profiler = model_cpu.device.profiler();
+    generate(
+        args,
+        &mut runner,
+        None,
+        sampler,
+        prompt,
+        metrics,
+        profiler,
+        threads,
+    )
+}
+
+/// generate from the prompt on the runner of either device, the profiler takes the ops on
+/// the cpu of each token as a pass.
+#[allow(clippy::too_many_arguments)]
+fn generate<T: Tensor>(
+    args: &CommandArgs,
+    runner: &mut Llama2Runner<T>,
+    draft: Option<&mut Llama2Runner<T>>,
+    sampler: &mut Llama2Sampler,
+    prompt: &str,
+    metrics: &TensorDeviceMetrics,
+    profiler: Option<&TensorProfiler>,
+    threads: usize,
+) -> Result<()> {
+    let vectors = args
+        .control_vector
+        .iter()
+        .map(|(path, strength)| Ok((Llama2ControlVector::load(path)?, *strength)))
+        .collect::<Result<Vec<_>>>()?;
+    let vectors = vectors.iter().map(|(v, s)| (v, *s)).collect::<Vec<_>>();
+    runner.set_control_vectors(&vectors)?;
+    if let Some(path) = &args.session {
+        if std::path::Path::new(path).exists() {
+            runner.restore_session(&Llama2Session::load(path)?, sampler)?;
+        }
+    }
+
+    if args.beam_width > 0 {
+        let options = Llama2BeamSearchOptions {
+            beam_width: args.beam_width,
+            length_penalty: args.length_penalty,
+            n_best: args.n_best,
+        };
+        let outputs = runner.beam_search(prompt, args.steps, &options)?;
+        for output in outputs.iter() {
+            println!("[score: {:.4}] {}{}", output.score, prompt, output.text);
+        }
+        return Ok(());
+    }
+    if draft.is_some() || args.lookup_ngram.is_some() {
+        let mut output = match (draft, args.lookup_ngram) {
+            (Some(draft), _) => {
+                runner.generate_speculative(draft, prompt, args.steps, args.draft, sampler)?
+            }
+            (None, max_ngram) => runner.generate_prompt_lookup(
+                prompt,
+                args.steps,
+                args.draft,
+                max_ngram.unwrap_or(3),
+                sampler,
+            )?,
+        };
+        print!("{}", prompt);
+        for token in output.by_ref() {
+            print!("{}", token?.text);
+            std::io::stdout().flush().unwrap();
+        }
+        println!();
+        println!(
+            "{:.2}% of the draft tokens accepted",
+            output.acceptance_rate() * 100.0
+        );
+        return Ok(());
+    }
+    let mut output = runner
+        .generate(prompt, args.steps, sampler)?
+        .with_stop_sequences(args.stop_sequences.clone())
+        .with_special_mode(if args.special {
+            BpeSpecialMode::Render
+        } else {
+            BpeSpecialMode::Skip
+        });
+    if let Some(n_keep) = args.keep {
+        output = output.with_context_shift(n_keep);
+    }
+    if let Some(negative_prompt) = &args.negative_prompt {
+        output = output.with_guidance(negative_prompt, args.cfg_scale)?;
+    }
+    print!("{}", prompt);
 
+    let mut logprobs = vec![];
     loop {
         let token = {
             let _t = metrics.total_walltime.track();
-            match output.next() {
-                Some(token) => token?,
+            match output.next_token()? {
+                Some(token) => token,
                 None => break,
             }
         };
 
-        print!("{}", token);
+        print!("{}", token.text);
+        logprobs.extend(token.logprobs);
 
         if args.verbose {
             print!("\nmetrics per token: ");
@@ -101,6 +959,9 @@ fn main() -> Result<()> {
         }
 
         metrics.reset();
+        if let Some(profiler) = profiler {
+            profiler.next_pass();
+        }
         std::io::stdout().flush().unwrap();
     }
 
@@ -110,6 +971,40 @@ fn main() -> Result<()> {
         output.average_tokens_per_seconds(),
         threads
     );
+    if let Some(profiler) = profiler {
+        if args.profile {
+            println!("\n{}", profiler.summary_table());
+        }
+        if let Some(path) = &args.profile_trace {
+            std::fs::write(path, profiler.chrome_trace()).map_err(|err| Error {
+                kind: ErrorKind::IOError,
+                message: format!("failed to write the profile trace {}", path),
+                cause: Some(Box::new(err)),
+            })?;
+        }
+    }
+
+    if let Some(path) = &args.session {
+        runner.session(sampler)?.save(path)?;
+    }
+
+    if !logprobs.is_empty() {
+        println!();
+        for logprobs in logprobs.iter() {
+            let top = logprobs
+                .top
+                .iter()
+                .map(|t| format!("{:?}: {:.4}", t.text, t.logprob))
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!(
+                "{:>16} {:.4} [{}]",
+                format!("{:?}", logprobs.token.text),
+                logprobs.token.logprob,
+                top
+            );
+        }
+    }
 
     Ok(())
 }
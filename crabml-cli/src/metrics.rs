@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// the seconds from the request to the first token, which forwards the prompt.
+const PREFILL_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// the seconds of each generated token after the first one.
+const DECODE_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// the endpoints counted by their paths, the others are counted as "other" to keep the
+/// number of the series bounded.
+const ENDPOINTS: &[&str] = &[
+    "/v1/models",
+    "/v1/completions",
+    "/v1/chat/completions",
+    "/v1/embeddings",
+    "/metrics",
+];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, v: f64) {
+        if let Some(i) = self.buckets.iter().position(|le| v <= *le) {
+            self.counts[i] += 1;
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+        writeln!(out, "{}_count {}", name, self.count).unwrap();
+    }
+}
+
+/// the metrics of the server, rendered in the text format of prometheus on `/metrics`.
+#[derive(Debug, Clone)]
+pub struct ServerMetrics {
+    /// the requests by the endpoint and the status code
+    requests: BTreeMap<(&'static str, u16), u64>,
+    queue_depth: usize,
+    prompt_tokens: u64,
+    generated_tokens: u64,
+    prefill_seconds: Histogram,
+    decode_seconds: Histogram,
+    kv_cache_tokens: usize,
+    kv_cache_size: usize,
+}
+
+impl ServerMetrics {
+    pub fn new(kv_cache_size: usize) -> Self {
+        Self {
+            requests: BTreeMap::new(),
+            queue_depth: 0,
+            prompt_tokens: 0,
+            generated_tokens: 0,
+            prefill_seconds: Histogram::new(PREFILL_BUCKETS),
+            decode_seconds: Histogram::new(DECODE_BUCKETS),
+            kv_cache_tokens: 0,
+            kv_cache_size,
+        }
+    }
+
+    pub fn record_request(&mut self, path: &str, status: u16) {
+        let endpoint = ENDPOINTS
+            .iter()
+            .find(|endpoint| **endpoint == path)
+            .copied()
+            .unwrap_or("other");
+        *self.requests.entry((endpoint, status)).or_default() += 1;
+    }
+
+    pub fn inc_queue_depth(&mut self) {
+        self.queue_depth += 1;
+    }
+
+    pub fn dec_queue_depth(&mut self) {
+        self.queue_depth = self.queue_depth.saturating_sub(1);
+    }
+
+    pub fn record_prompt(&mut self, n_tokens: usize) {
+        self.prompt_tokens += n_tokens as u64;
+    }
+
+    pub fn record_prefill(&mut self, elapsed: Duration) {
+        self.prefill_seconds.observe(elapsed.as_secs_f64());
+        self.generated_tokens += 1;
+    }
+
+    pub fn record_decode(&mut self, elapsed: Duration) {
+        self.decode_seconds.observe(elapsed.as_secs_f64());
+        self.generated_tokens += 1;
+    }
+
+    pub fn set_kv_cache_tokens(&mut self, n_tokens: usize) {
+        self.kv_cache_tokens = n_tokens;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP crabml_requests_total The number of the requests by the endpoint and the status code."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE crabml_requests_total counter").unwrap();
+        for ((endpoint, code), n) in self.requests.iter() {
+            writeln!(
+                out,
+                "crabml_requests_total{{endpoint=\"{}\",code=\"{}\"}} {}",
+                endpoint, code, n
+            )
+            .unwrap();
+        }
+
+        let metric = |out: &mut String, name: &str, typ: &str, help: &str, v: f64| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, typ).unwrap();
+            writeln!(out, "{} {}", name, v).unwrap();
+        };
+        metric(
+            &mut out,
+            "crabml_queue_depth",
+            "gauge",
+            "The number of the requests waiting for the model.",
+            self.queue_depth as f64,
+        );
+        metric(
+            &mut out,
+            "crabml_prompt_tokens_total",
+            "counter",
+            "The number of the prompt tokens.",
+            self.prompt_tokens as f64,
+        );
+        metric(
+            &mut out,
+            "crabml_generated_tokens_total",
+            "counter",
+            "The number of the generated tokens.",
+            self.generated_tokens as f64,
+        );
+        self.prefill_seconds.render(
+            &mut out,
+            "crabml_prefill_seconds",
+            "The seconds from the request to the first token, which forwards the prompt.",
+        );
+        self.decode_seconds.render(
+            &mut out,
+            "crabml_decode_seconds",
+            "The seconds of each generated token after the first one.",
+        );
+        metric(
+            &mut out,
+            "crabml_kv_cache_tokens",
+            "gauge",
+            "The number of the tokens in the kv cache.",
+            self.kv_cache_tokens as f64,
+        );
+        metric(
+            &mut out,
+            "crabml_kv_cache_utilization",
+            "gauge",
+            "The ratio of the kv cache taken by the tokens.",
+            self.kv_cache_tokens as f64 / self.kv_cache_size.max(1) as f64,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let mut metrics = ServerMetrics::new(512);
+        metrics.record_request("/v1/completions", 200);
+        metrics.record_request("/v1/completions", 200);
+        metrics.record_request("/v1/unknown", 404);
+        metrics.inc_queue_depth();
+        metrics.inc_queue_depth();
+        metrics.dec_queue_depth();
+        metrics.record_prompt(10);
+        metrics.record_prefill(Duration::from_millis(40));
+        metrics.record_decode(Duration::from_millis(3));
+        metrics.record_decode(Duration::from_millis(7));
+        metrics.set_kv_cache_tokens(128);
+
+        let out = metrics.render();
+        let lines = out.lines().collect::<Vec<_>>();
+        for line in [
+            "crabml_requests_total{endpoint=\"/v1/completions\",code=\"200\"} 2",
+            "crabml_requests_total{endpoint=\"other\",code=\"404\"} 1",
+            "crabml_queue_depth 1",
+            "crabml_prompt_tokens_total 10",
+            "crabml_generated_tokens_total 3",
+            "crabml_prefill_seconds_bucket{le=\"0.025\"} 0",
+            "crabml_prefill_seconds_bucket{le=\"0.05\"} 1",
+            "crabml_prefill_seconds_count 1",
+            "crabml_decode_seconds_bucket{le=\"0.005\"} 1",
+            "crabml_decode_seconds_bucket{le=\"0.01\"} 2",
+            "crabml_decode_seconds_bucket{le=\"+Inf\"} 2",
+            "crabml_decode_seconds_count 2",
+            "crabml_kv_cache_tokens 128",
+            "crabml_kv_cache_utilization 0.25",
+        ] {
+            assert!(lines.contains(&line), "{} not in:\n{}", line, out);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::Write;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::CpuLlama2Model;
+
+#[derive(Args, Debug)]
+pub struct PerplexityArgs {
+    /// The GGUF model file to evaluate
+    #[arg(short, long)]
+    model: String,
+
+    /// The text file to evaluate on, like wiki.test.raw of wikitext-2
+    #[arg(short, long)]
+    file: String,
+
+    /// The tokens of each chunk, the model's context length if 0
+    #[arg(short, long, default_value_t = 512)]
+    ctx_size: usize,
+
+    /// The max chunks to evaluate, all the chunks if 0
+    #[arg(long, default_value_t = 0)]
+    chunks: usize,
+
+    /// The max number of the tokens forwarded together
+    #[arg(long, default_value_t = 512)]
+    batch_size: usize,
+
+    /// The number of threads, the number of the cpus if 0
+    #[arg(short, long, default_value_t = 0)]
+    threads: usize,
+}
+
+/// compute the perplexity on the chunks of the text like llama.cpp, each chunk starts from
+/// the BOS token, and only the tokens in the second half of the chunk are scored, so every
+/// scored token takes at least half of the context.
+pub fn perplexity(args: &PerplexityArgs) -> Result<()> {
+    let text = std::fs::read_to_string(&args.file).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read the file {}", args.file),
+        cause: Some(Box::new(err)),
+    })?;
+    let gl = GGUFFileLoader::new(&args.model)?;
+    let gf = gl.open()?;
+    let model = CpuLlama2Model::load(&gf, CpuTensorDevice::new().with_threads(args.threads)?)?;
+    let mut runner = Llama2Runner::try_from(&model)?.with_batch_size(args.batch_size);
+
+    let seq_len = model.conf().seq_len;
+    let n_ctx = match args.ctx_size {
+        0 => seq_len,
+        n => n.min(seq_len),
+    };
+    if n_ctx < 2 {
+        return Err((
+            ErrorKind::BadInput,
+            "the context should take at least 2 tokens",
+        )
+            .into());
+    }
+    let tokenizer = model.tokenizer();
+    let tokens = tokenizer.encode(&text, false, false)?;
+    let mut n_chunks = tokens.len() / n_ctx;
+    if args.chunks > 0 {
+        n_chunks = n_chunks.min(args.chunks);
+    }
+    if n_chunks == 0 {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "the text of {} tokens is shorter than the context of {} tokens",
+                tokens.len(),
+                n_ctx
+            ),
+        )
+            .into());
+    }
+    eprintln!(
+        "evaluating {} chunks of {} tokens, {} tokens in total",
+        n_chunks,
+        n_ctx,
+        tokens.len()
+    );
+
+    let start_time = Instant::now();
+    let mut nll_sum = 0.0;
+    let mut nll2_sum = 0.0;
+    let mut count = 0;
+    for (i, chunk) in tokens.chunks_exact(n_ctx).take(n_chunks).enumerate() {
+        let mut chunk = chunk.to_vec();
+        if tokenizer.add_bos_token() {
+            chunk[0] = tokenizer.bos_token();
+        }
+        for nll in runner.token_nlls(&chunk, n_ctx / 2)? {
+            nll_sum += nll as f64;
+            nll2_sum += (nll as f64).powi(2);
+            count += 1;
+        }
+        if i == 0 {
+            let secs = start_time.elapsed().as_secs_f64();
+            eprintln!(
+                "{:.2} seconds per chunk, ETA {:.1} minutes",
+                secs,
+                secs * n_chunks as f64 / 60.0
+            );
+        }
+        print!("[{}]{:.4},", i + 1, (nll_sum / count as f64).exp());
+        std::io::stdout().flush().unwrap();
+    }
+    println!();
+
+    // the standard error of the mean nll, which is carried to the perplexity by its derivative
+    let mean = nll_sum / count as f64;
+    let var = (nll2_sum / count as f64 - mean * mean).max(0.0);
+    let ppl = mean.exp();
+    let err = ppl * (var / (count - 1).max(1) as f64).sqrt();
+    println!("perplexity: {:.4} +/- {:.4}", ppl, err);
+    Ok(())
+}
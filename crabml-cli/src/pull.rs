@@ -0,0 +1,481 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+
+use clap::Args;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::split_prefix;
+use crabml::sha256::sha256_hex_reader;
+use serde_json::Value;
+
+#[derive(Args, Debug)]
+pub struct PullArgs {
+    /// The model to pull like `TheBloke/Llama-2-7B-GGUF:Q4_K_M`, the quantization after the
+    /// colon picks the GGUF file if the repo has many
+    model: String,
+
+    /// The directory to cache the models, `$CRABML_CACHE` or `~/.cache/crabml` by default
+    #[arg(long)]
+    cache_dir: Option<String>,
+
+    /// The branch, tag or commit of the repo
+    #[arg(long, default_value = "main")]
+    revision: String,
+
+    /// The endpoint of the hub, `$HF_ENDPOINT` or https://huggingface.co by default
+    #[arg(long)]
+    endpoint: Option<String>,
+}
+
+/// a GGUF file in the repo, the sha256 is the oid of git lfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HubFile {
+    path: String,
+    size: u64,
+    sha256: Option<String>,
+}
+
+/// download the GGUF file of the repo on the hub into the cache, and print its path. the
+/// files are downloaded by `curl`, which resumes the partial downloads and shows the
+/// progress, and the `$HF_TOKEN` is sent for the gated repos.
+pub fn pull(args: &PullArgs) -> Result<()> {
+    let (repo, quant) = parse_model(&args.model)?;
+    let endpoint = args
+        .endpoint
+        .clone()
+        .or_else(|| std::env::var("HF_ENDPOINT").ok())
+        .unwrap_or_else(|| "https://huggingface.co".to_string());
+    let endpoint = endpoint.trim_end_matches('/');
+    let token = std::env::var("HF_TOKEN").ok();
+    let cache_dir = match &args.cache_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => default_cache_dir()?,
+    };
+
+    let tree_url = format!(
+        "{}/api/models/{}/tree/{}?recursive=true",
+        endpoint, repo, args.revision
+    );
+    let tree = curl_json(&tree_url, token.as_deref())?;
+    let files = select_files(&tree, quant)?;
+
+    let dir = cache_dir.join(repo.replace('/', "--"));
+    let mut paths = vec![];
+    for file in files.iter() {
+        let url = format!(
+            "{}/{}/resolve/{}/{}",
+            endpoint, repo, args.revision, file.path
+        );
+        let dest = cache_path(&dir, &file.path)?;
+        download(&url, &dest, file, token.as_deref())?;
+        paths.push(dest);
+    }
+    // the first shard of a split model loads all the shards
+    println!("{}", paths[0].display());
+    Ok(())
+}
+
+/// split `<repo>[:<quant>]`, the repo is `<owner>/<name>`.
+fn parse_model(model: &str) -> Result<(&str, Option<&str>)> {
+    let (repo, quant) = match model.split_once(':') {
+        Some((repo, quant)) => (repo, Some(quant)),
+        None => (model, None),
+    };
+    let valid = |s: &str| !s.is_empty() && !s.contains(['/', ':', '?', '#']);
+    match repo.split_once('/') {
+        Some((owner, name)) if valid(owner) && valid(name) && quant.map_or(true, valid) => {
+            Ok((repo, quant))
+        }
+        _ => Err((
+            ErrorKind::BadInput,
+            format!(
+                "expected the model like <owner>/<name>[:<quant>], got {}",
+                model
+            ),
+        )
+            .into()),
+    }
+}
+
+/// pick the GGUF files of the quantization, or the only GGUF file of the repo. the shards
+/// of a split model are all picked.
+fn select_files(tree: &Value, quant: Option<&str>) -> Result<Vec<HubFile>> {
+    let entries = tree.as_array().ok_or_else(|| {
+        Error::from((
+            ErrorKind::FormatError,
+            "expected the files of the repo in a json array",
+        ))
+    })?;
+    let mut ggufs = entries
+        .iter()
+        .filter(|e| e["type"] == "file")
+        .filter_map(|e| {
+            let path = e["path"].as_str()?;
+            path.ends_with(".gguf").then(|| HubFile {
+                path: path.to_string(),
+                size: e["lfs"]["size"]
+                    .as_u64()
+                    .or(e["size"].as_u64())
+                    .unwrap_or(0),
+                sha256: e["lfs"]["oid"].as_str().map(|s| s.to_string()),
+            })
+        })
+        .collect::<Vec<_>>();
+    ggufs.sort_by(|a, b| a.path.cmp(&b.path));
+    if ggufs.is_empty() {
+        return Err((ErrorKind::BadInput, "no GGUF file in the repo").into());
+    }
+    let available = || {
+        ggufs
+            .iter()
+            .map(|f| f.path.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let selected = match quant {
+        Some(quant) => ggufs
+            .iter()
+            .filter(|f| has_quant(&f.path, quant))
+            .cloned()
+            .collect::<Vec<_>>(),
+        None => ggufs.clone(),
+    };
+    if selected.is_empty() {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "no GGUF file of {} in the repo, available: {}",
+                quant.unwrap_or_default(),
+                available()
+            ),
+        )
+            .into());
+    }
+
+    // many files are only fine as the shards of a single split model
+    let prefix = split_prefix(&selected[0].path);
+    let is_one_model = selected.len() == 1
+        || (prefix.is_some() && selected.iter().all(|f| split_prefix(&f.path) == prefix));
+    if !is_one_model {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "many GGUF files in the repo, pick one by <repo>:<quant>, available: {}",
+                available()
+            ),
+        )
+            .into());
+    }
+    Ok(selected)
+}
+
+/// whether the quantization is in the file name like `llama-2-7b.Q4_K_M.gguf`, but not
+/// like `Q4_K` in `Q4_K_M`.
+fn has_quant(path: &str, quant: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path).to_uppercase();
+    let quant = quant.to_uppercase();
+    name.match_indices(&quant).any(|(i, _)| {
+        let before = name[..i].chars().last();
+        let after = name[i + quant.len()..].chars().next();
+        matches!(before, None | Some('.' | '-' | '_')) && matches!(after, Some('.' | '-'))
+    })
+}
+
+/// the path to save the file of the repo, the paths from the hub like `../x` or `/x` are
+/// rejected to not write outside the cache directory.
+fn cache_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let is_normal = Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if path.is_empty() || !is_normal {
+        return Err((
+            ErrorKind::FormatError,
+            format!("invalid file path {:?} from the hub", path),
+        )
+            .into());
+    }
+    Ok(dir.join(path))
+}
+
+fn default_cache_dir() -> Result<PathBuf> {
+    if let Ok(dir) = std::env::var("CRABML_CACHE") {
+        return Ok(PathBuf::from(dir));
+    }
+    let home = std::env::var("HOME")
+        .or_else(|_| std::env::var("USERPROFILE"))
+        .map_err(|_| {
+            Error::from((
+                ErrorKind::BadInput,
+                "no home directory, set the cache directory by --cache-dir",
+            ))
+        })?;
+    Ok(Path::new(&home).join(".cache").join("crabml"))
+}
+
+fn io_error(err: std::io::Error, message: String) -> Error {
+    Error {
+        kind: ErrorKind::IOError,
+        message,
+        cause: Some(Box::new(err)),
+    }
+}
+
+/// the authorization header of the token is read by curl from the stdin, to not be seen in
+/// the command line by the other users, it's written by `spawn_curl`.
+fn curl(url: &str, token: Option<&str>) -> Command {
+    let mut cmd = Command::new("curl");
+    cmd.args(["-fL", "--retry", "3"]);
+    if token.is_some() {
+        cmd.args(["-H", "@-"]).stdin(Stdio::piped());
+    }
+    cmd.arg(url);
+    cmd
+}
+
+fn spawn_curl(cmd: &mut Command, token: Option<&str>) -> Result<Child> {
+    let mut child = cmd
+        .spawn()
+        .map_err(|err| io_error(err, "failed to run curl".to_string()))?;
+    // the stdin is closed when dropped, so curl stops reading the headers
+    if let (Some(token), Some(mut stdin)) = (token, child.stdin.take()) {
+        writeln!(stdin, "Authorization: Bearer {}", token)
+            .map_err(|err| io_error(err, "failed to pass the token to curl".to_string()))?;
+    }
+    Ok(child)
+}
+
+fn curl_json(url: &str, token: Option<&str>) -> Result<Value> {
+    let mut cmd = curl(url, token);
+    cmd.args(["-sS"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    let output = spawn_curl(&mut cmd, token)?
+        .wait_with_output()
+        .map_err(|err| io_error(err, "failed to run curl".to_string()))?;
+    if !output.status.success() {
+        return Err((ErrorKind::IOError, format!("failed to fetch {}", url)).into());
+    }
+    serde_json::from_slice(&output.stdout).map_err(|err| Error {
+        kind: ErrorKind::FormatError,
+        message: format!("failed to parse the json of {}", url),
+        cause: Some(Box::new(err)),
+    })
+}
+
+/// download the file into `<dest>.part`, which is resumed if it's there, and move it to
+/// `dest` after the checksum is verified. the file already downloaded is kept.
+fn download(url: &str, dest: &Path, file: &HubFile, token: Option<&str>) -> Result<()> {
+    let file_size = |path: &Path| std::fs::metadata(path).map(|m| m.len()).ok();
+    if file_size(dest).is_some_and(|size| file.size == 0 || size == file.size) {
+        eprintln!("{} is already downloaded", file.path);
+        return Ok(());
+    }
+    let dir = dest.parent().unwrap();
+    std::fs::create_dir_all(dir)
+        .map_err(|err| io_error(err, format!("failed to create {}", dir.display())))?;
+
+    let part = dest.with_file_name(format!(
+        "{}.part",
+        dest.file_name().unwrap().to_string_lossy()
+    ));
+    if file.size == 0 || file_size(&part) != Some(file.size) {
+        eprintln!("downloading {}", file.path);
+        let mut cmd = curl(url, token);
+        cmd.args(["-C", "-", "--progress-bar", "-o"]).arg(&part);
+        let status = spawn_curl(&mut cmd, token)?
+            .wait()
+            .map_err(|err| io_error(err, "failed to run curl".to_string()))?;
+        if !status.success() {
+            return Err((
+                ErrorKind::IOError,
+                format!(
+                    "failed to download {}, run again to resume the download",
+                    url
+                ),
+            )
+                .into());
+        }
+    }
+
+    if let Some(expected) = &file.sha256 {
+        let mut f = File::open(&part)
+            .map_err(|err| io_error(err, format!("failed to open {}", part.display())))?;
+        let actual = sha256_hex_reader(&mut f)?;
+        if &actual != expected {
+            let _ = std::fs::remove_file(&part);
+            return Err((
+                ErrorKind::FormatError,
+                format!(
+                    "the checksum of {} is {}, but expected {}, the download is removed",
+                    file.path, actual, expected
+                ),
+            )
+                .into());
+        }
+    }
+    std::fs::rename(&part, dest)
+        .map_err(|err| io_error(err, format!("failed to move {}", part.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tree(paths: &[&str]) -> Value {
+        let mut entries = vec![json!({"type": "directory", "path": "docs"})];
+        entries.extend(paths.iter().map(|path| {
+            json!({"type": "file", "path": path, "size": 100, "lfs": {"oid": "abc", "size": 100}})
+        }));
+        Value::Array(entries)
+    }
+
+    fn paths(files: &[HubFile]) -> Vec<&str> {
+        files.iter().map(|f| f.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select_files() -> Result<()> {
+        assert_eq!(
+            parse_model("TheBloke/Llama-2-7B-GGUF:Q4_K_M")?,
+            ("TheBloke/Llama-2-7B-GGUF", Some("Q4_K_M"))
+        );
+        assert_eq!(parse_model("a/b")?, ("a/b", None));
+        assert!(parse_model("llama").is_err());
+        assert!(parse_model("a/b/c").is_err());
+        assert!(parse_model("a/b:").is_err());
+
+        let t = tree(&[
+            "README.md",
+            "llama-2-7b.Q4_K.gguf",
+            "llama-2-7b.Q4_K_M.gguf",
+            "llama-2-7b.Q8_0.gguf",
+            "q6_k/llama-2-7b-q6_k-00002-of-00002.gguf",
+            "q6_k/llama-2-7b-q6_k-00001-of-00002.gguf",
+        ]);
+        let files = select_files(&t, Some("q4_k_m"))?;
+        assert_eq!(paths(&files), vec!["llama-2-7b.Q4_K_M.gguf"]);
+        assert_eq!(files[0].sha256.as_deref(), Some("abc"));
+        assert_eq!(paths(&select_files(&t, Some("Q4_K"))?), vec![
+            "llama-2-7b.Q4_K.gguf"
+        ]);
+        assert_eq!(paths(&select_files(&t, Some("Q6_K"))?), vec![
+            "q6_k/llama-2-7b-q6_k-00001-of-00002.gguf",
+            "q6_k/llama-2-7b-q6_k-00002-of-00002.gguf",
+        ]);
+
+        let err = select_files(&t, None).unwrap_err();
+        assert!(err.message.contains("pick one"), "{}", err);
+        let err = select_files(&t, Some("Q5_0")).unwrap_err();
+        assert!(err.message.contains("llama-2-7b.Q8_0.gguf"), "{}", err);
+        assert_eq!(paths(&select_files(&tree(&["model.gguf"]), None)?), vec![
+            "model.gguf"
+        ]);
+        assert!(select_files(&tree(&["README.md"]), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_path() -> Result<()> {
+        let dir = Path::new("/cache/karpathy--stories");
+        assert_eq!(
+            cache_path(dir, "q6_k/model.gguf")?,
+            dir.join("q6_k").join("model.gguf")
+        );
+        for path in [
+            "../model.gguf",
+            "q6_k/../../model.gguf",
+            "/etc/model.gguf",
+            "./model.gguf",
+            "",
+        ] {
+            let err = cache_path(dir, path).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::FormatError, "{}", path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_curl_token() -> Result<()> {
+        let cmd = curl("https://huggingface.co", Some("hf_secret"));
+        assert!(
+            cmd.get_args()
+                .all(|arg| !arg.to_string_lossy().contains("hf_secret"))
+        );
+
+        let path = std::env::temp_dir().join(format!("crabml-curl-test-{}", std::process::id()));
+        std::fs::write(&path, "{\"ok\": true}").unwrap();
+        let url = format!("file://{}", path.display());
+        let value = curl_json(&url, Some("hf_secret"));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(value?, json!({"ok": true}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pull_from_file_endpoint() -> Result<()> {
+        let model = std::fs::read("../testdata/tinyllamas-stories-260k-f32.gguf").unwrap();
+        let hub = std::env::temp_dir().join(format!("crabml-pull-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&hub);
+        let write = |path: PathBuf, data: &[u8]| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        };
+        let sha256 = crabml::sha256::sha256_hex(&model);
+        let tree = json!([{
+            "type": "file",
+            "path": "stories-260k.F32.gguf",
+            "size": model.len(),
+            "lfs": {"oid": sha256, "size": model.len()},
+        }]);
+        write(
+            hub.join("endpoint/api/models/karpathy/stories/tree/main"),
+            tree.to_string().as_bytes(),
+        );
+        write(
+            hub.join("endpoint/karpathy/stories/resolve/main/stories-260k.F32.gguf"),
+            &model,
+        );
+        // a partial download is resumed
+        write(
+            hub.join("cache/karpathy--stories/stories-260k.F32.gguf.part"),
+            &model[..1000],
+        );
+
+        let args = PullArgs {
+            model: "karpathy/stories:f32".to_string(),
+            cache_dir: Some(hub.join("cache").to_string_lossy().to_string()),
+            revision: "main".to_string(),
+            endpoint: Some(format!("file://{}", hub.join("endpoint").display())),
+        };
+        pull(&args)?;
+        let dest = hub.join("cache/karpathy--stories/stories-260k.F32.gguf");
+        assert_eq!(std::fs::read(&dest).unwrap(), model);
+        assert!(
+            !hub.join("cache/karpathy--stories/stories-260k.F32.gguf.part")
+                .exists()
+        );
+
+        // the corrupted download is removed
+        std::fs::remove_file(&dest).unwrap();
+        write(
+            hub.join("endpoint/karpathy/stories/resolve/main/stories-260k.F32.gguf"),
+            &vec![0; model.len()],
+        );
+        let err = pull(&args).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert!(err.message.contains("checksum"), "{}", err);
+        assert!(!dest.exists());
+        std::fs::remove_dir_all(&hub).unwrap();
+        Ok(())
+    }
+}
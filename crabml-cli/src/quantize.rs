@@ -0,0 +1,287 @@
+use std::time::Instant;
+
+use clap::Args;
+use clap::ValueEnum;
+use crabml::backends::cpu::buf::CpuTensorBuf;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::gguf::GGUFWriter;
+use crabml::gguf::ModelTensor;
+use crabml::gguf::KEY_GENERAL_FILE_TYPE;
+use crabml::gguf::KEY_GENERAL_QUANTIZATION_VERSION;
+
+#[derive(Args, Debug)]
+pub struct QuantizeArgs {
+    /// The f32/f16 GGUF file to quantize
+    input: String,
+
+    /// The path to write the quantized GGUF file
+    output: String,
+
+    /// The quantization scheme
+    #[arg(long = "type", value_enum, default_value_t = QuantizeType::Q8_0)]
+    typ: QuantizeType,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizeType {
+    #[value(name = "f16")]
+    F16,
+    #[value(name = "q8_0")]
+    Q8_0,
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q4_1")]
+    Q4_1,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q5_1")]
+    Q5_1,
+    #[value(name = "q4_k_s")]
+    Q4KS,
+    /// Q4_K, but the output, half of the attn_v and ffn_down are in Q6_K
+    #[value(name = "q4_k_m")]
+    Q4KM,
+    #[value(name = "q6_k")]
+    Q6K,
+}
+
+impl QuantizeType {
+    /// the `general.file_type` of llama.cpp.
+    fn file_type(&self) -> u32 {
+        match self {
+            QuantizeType::F16 => 1,
+            QuantizeType::Q4_0 => 2,
+            QuantizeType::Q4_1 => 3,
+            QuantizeType::Q8_0 => 7,
+            QuantizeType::Q5_0 => 8,
+            QuantizeType::Q5_1 => 9,
+            QuantizeType::Q4KS => 14,
+            QuantizeType::Q4KM => 15,
+            QuantizeType::Q6K => 18,
+        }
+    }
+
+    /// the type to quantize the tensor into, returns None if the tensor should be kept as
+    /// it is. the norms, the biases and the token embedding are never quantized.
+    fn tensor_type(
+        &self,
+        tensor: ModelTensor,
+        layer: Option<usize>,
+        n_layers: usize,
+    ) -> Option<GGMLType> {
+        // only the weights of the matmuls are quantized, the embeddings, the norms and the
+        // biases are kept
+        match tensor {
+            ModelTensor::Output
+            | ModelTensor::AttnQ
+            | ModelTensor::AttnK
+            | ModelTensor::AttnV
+            | ModelTensor::AttnOutput
+            | ModelTensor::FfnGate
+            | ModelTensor::FfnDown
+            | ModelTensor::FfnUp => {}
+            _ => return None,
+        }
+
+        let typ = match self {
+            QuantizeType::F16 => GGMLType::F16,
+            QuantizeType::Q8_0 => GGMLType::Q8_0,
+            QuantizeType::Q4_0 => GGMLType::Q4_0,
+            QuantizeType::Q4_1 => GGMLType::Q4_1,
+            QuantizeType::Q5_0 => GGMLType::Q5_0,
+            QuantizeType::Q5_1 => GGMLType::Q5_1,
+            QuantizeType::Q4KS => GGMLType::Q4K,
+            QuantizeType::Q6K => GGMLType::Q6K,
+            QuantizeType::Q4KM => match (tensor, layer) {
+                (ModelTensor::Output, _) => GGMLType::Q6K,
+                (ModelTensor::AttnV | ModelTensor::FfnDown, Some(layer))
+                    if use_more_bits(layer, n_layers) =>
+                {
+                    GGMLType::Q6K
+                }
+                _ => GGMLType::Q4K,
+            },
+        };
+        Some(typ)
+    }
+}
+
+/// the same layers as llama.cpp to keep more bits: the first and last 1/8 of the layers,
+/// and every third layer in the middle.
+fn use_more_bits(layer: usize, n_layers: usize) -> bool {
+    layer < n_layers / 8 || layer >= 7 * n_layers / 8 || (layer - n_layers / 8) % 3 == 2
+}
+
+/// the k-quants need the rows to be a multiple of 256 elements, fall back to the legacy
+/// quants with a similar size on the small rows like llama.cpp does. returns None if the
+/// row can not be quantized at all.
+fn fallback_type(typ: GGMLType, row_len: usize) -> Option<GGMLType> {
+    let typ = match typ {
+        GGMLType::Q4K if row_len % 256 != 0 => GGMLType::Q5_0,
+        GGMLType::Q6K if row_len % 256 != 0 => GGMLType::Q8_0,
+        typ => typ,
+    };
+    (row_len % typ.block_size() == 0).then_some(typ)
+}
+
+/// the f32 tensors are borrowed from the mmaped file, the f16 tensors are converted to f32.
+fn tensor_f32_buf<'a>(info: &GGUFTensorInfo<'a>) -> Result<CpuTensorBuf<'a>> {
+    match info.typ() {
+        GGMLType::F32 | GGMLType::F16 => {
+            CpuTensorBuf::from_raw_bytes(info.data(), info.typ())?.dequantize(GGMLType::F32)
+        }
+        typ => Err(Error {
+            kind: ErrorKind::BadInput,
+            message: format!(
+                "tensor {} is already in {}, only f32/f16 tensors can be quantized",
+                info.name(),
+                typ
+            ),
+            cause: None,
+        }),
+    }
+}
+
+pub fn quantize(args: &QuantizeArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let gl = GGUFFileLoader::new(&args.input)?;
+    let gf = gl.open()?;
+
+    let n_layers = gf
+        .tensor_infos()
+        .iter()
+        .filter_map(|info| ModelTensor::from_gguf_name(info.name())?.1)
+        .max()
+        .map_or(0, |layer| layer + 1);
+
+    let mut w = GGUFWriter::new();
+    for (key, value) in gf.metadata().as_hashmap() {
+        w.add_metadata(key, value.clone());
+    }
+    w.add_metadata(
+        KEY_GENERAL_FILE_TYPE,
+        GGUFMetadataValue::U32(args.typ.file_type()),
+    );
+    w.add_metadata(KEY_GENERAL_QUANTIZATION_VERSION, GGUFMetadataValue::U32(2));
+
+    let tensor_infos = gf.tensor_infos();
+    let (mut src_bytes, mut dst_bytes) = (0, 0);
+    for (i, info) in tensor_infos.iter().enumerate() {
+        let dims = info.dimensions();
+        let typ = match ModelTensor::from_gguf_name(info.name()) {
+            Some((tensor, layer)) if dims.len() == 2 => args
+                .typ
+                .tensor_type(tensor, layer, n_layers)
+                .and_then(|typ| fallback_type(typ, dims[0])),
+            _ => None,
+        };
+
+        src_bytes += info.data().len();
+        let typ = match typ {
+            Some(typ) if typ != info.typ() => typ,
+            _ => {
+                dst_bytes += info.data().len();
+                w.add_tensor(info.name(), dims, info.typ(), info.data())?;
+                continue;
+            }
+        };
+
+        let buf = tensor_f32_buf(info)?.quantize(typ)?;
+        let data = buf.as_bytes().to_vec();
+        println!(
+            "[{:3}/{:3}] {:32} {:20} {} -> {}",
+            i + 1,
+            tensor_infos.len(),
+            info.name(),
+            format!("{:?}", dims),
+            info.typ(),
+            typ
+        );
+        dst_bytes += data.len();
+        w.add_tensor(info.name(), dims, typ, data)?;
+    }
+    w.write_to_file(&args.output)?;
+
+    println!(
+        "quantized {} into {}: {:.2} MiB -> {:.2} MiB, {}ms",
+        args.input,
+        args.output,
+        src_bytes as f64 / 1024.0 / 1024.0,
+        dst_bytes as f64 / 1024.0 / 1024.0,
+        start_time.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tensor_type() {
+        let typ = QuantizeType::Q4KM;
+        assert_eq!(typ.tensor_type(ModelTensor::TokenEmbd, None, 32), None);
+        assert_eq!(typ.tensor_type(ModelTensor::FfnNorm, Some(0), 32), None);
+        assert_eq!(
+            typ.tensor_type(ModelTensor::Output, None, 32),
+            Some(GGMLType::Q6K)
+        );
+        assert_eq!(
+            typ.tensor_type(ModelTensor::AttnV, Some(0), 32),
+            Some(GGMLType::Q6K)
+        );
+        assert_eq!(
+            typ.tensor_type(ModelTensor::AttnV, Some(4), 32),
+            Some(GGMLType::Q4K)
+        );
+        assert_eq!(
+            typ.tensor_type(ModelTensor::FfnDown, Some(6), 32),
+            Some(GGMLType::Q6K)
+        );
+        assert_eq!(
+            typ.tensor_type(ModelTensor::AttnQ, Some(0), 32),
+            Some(GGMLType::Q4K)
+        );
+
+        assert_eq!(fallback_type(GGMLType::Q4K, 4096), Some(GGMLType::Q4K));
+        assert_eq!(fallback_type(GGMLType::Q4K, 288), Some(GGMLType::Q5_0));
+        assert_eq!(fallback_type(GGMLType::Q6K, 64), Some(GGMLType::Q8_0));
+        assert_eq!(fallback_type(GGMLType::Q8_0, 48), None);
+    }
+
+    #[test]
+    fn test_quantize() -> Result<()> {
+        let output =
+            std::env::temp_dir().join(format!("crabml-quantize-{}.gguf", std::process::id()));
+        let args = QuantizeArgs {
+            input: "../testdata/tinyllamas-stories-260k-f32.gguf".to_string(),
+            output: output.to_str().unwrap().to_string(),
+            typ: QuantizeType::Q4KM,
+        };
+        quantize(&args)?;
+
+        let gl = GGUFFileLoader::new(&args.output)?;
+        let gf = gl.open()?;
+        std::fs::remove_file(&args.output).unwrap();
+
+        assert_eq!(
+            gf.metadata().get_u32(KEY_GENERAL_FILE_TYPE),
+            Some(QuantizeType::Q4KM.file_type())
+        );
+        let typ_of = |name: &str| gf.get_tensor_info(name).unwrap().typ();
+        assert_eq!(typ_of("token_embd.weight"), GGMLType::F32);
+        assert_eq!(typ_of("blk.0.attn_norm.weight"), GGMLType::F32);
+        // the rows of the tiny model are too short for the k-quants
+        assert_eq!(typ_of("blk.0.attn_q.weight"), GGMLType::Q5_0);
+        assert_eq!(typ_of("blk.0.attn_v.weight"), GGMLType::Q5_0);
+        assert_eq!(typ_of("blk.4.attn_v.weight"), GGMLType::Q8_0);
+        assert_eq!(typ_of("output.weight"), GGMLType::Q8_0);
+        Ok(())
+    }
+}
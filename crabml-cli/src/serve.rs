@@ -0,0 +1,1076 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use clap::ArgMatches;
+use clap::Args;
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::BpeTokenizer;
+use crabml_llama2::abort::Llama2AbortHandle;
+use crabml_llama2::chat_template::ChatMessage;
+use crabml_llama2::chat_template::ChatTemplate;
+use crabml_llama2::llama2::Llama2EmbeddingOptions;
+use crabml_llama2::llama2::Llama2FinishReason;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::Llama2Config;
+use crabml_llama2::sampler::Llama2Sampler;
+use crabml_llama2::sampler::Llama2SamplerPenalties;
+use crabml_llama2::CpuLlama2Model;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::config::merge;
+use crate::config::GenerationConfig;
+use crate::metrics::ServerMetrics;
+use crate::ActivationType;
+use crate::CacheType;
+use crate::LoadArgs;
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// The GGUF model file to serve, required unless it's in the config file
+    #[arg(short, long, default_value = "", hide_default_value = true)]
+    model: String,
+
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// The port to listen on
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+
+    /// Use a built-in chat template instead of the one of the model
+    #[arg(long, value_parser = ["llama2", "chatml", "mistral"])]
+    chat_template: Option<String>,
+
+    /// The max number of the prompt tokens forwarded together
+    #[arg(long, default_value_t = 512)]
+    batch_size: usize,
+
+    /// The type of the kv cache
+    #[arg(long, value_enum, default_value_t = CacheType::F32)]
+    cache_type: CacheType,
+
+    /// The type of the inputs of the matmuls, f16 halves their memory and bandwidth with the
+    /// products still accumulated in f32. It's taken on the cpu and wgpu.
+    #[arg(long, value_enum, default_value_t = ActivationType::F32)]
+    activation_type: ActivationType,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Take the attention in the fused kernel of the flash attention
+    #[arg(long = "flash-attn", default_value_t = false)]
+    flash_attn: bool,
+
+    /// The number of threads, the number of the cpus if 0
+    #[arg(short, long, default_value_t = 0)]
+    threads: usize,
+
+    /// The size of the context in tokens, capped at the context length of the model
+    #[arg(long)]
+    ctx_size: Option<usize>,
+
+    /// The temperature of the requests without one
+    #[arg(long, default_value_t = 1.0)]
+    temperature: f32,
+
+    /// The top-p of the requests without one
+    #[arg(long, default_value_t = 1.0)]
+    top_p: f32,
+
+    /// The system message prepended to the chat completions without one
+    #[arg(long)]
+    system: Option<String>,
+
+    /// The wall-clock timeout of each request in seconds, the generation stops with the
+    /// finish reason of "timeout" once it's reached
+    #[arg(long)]
+    timeout: Option<u64>,
+}
+
+impl ServeArgs {
+    /// fill the flags not given on the command line from the config file.
+    pub fn apply_config(&mut self, config: &GenerationConfig, matches: &ArgMatches) -> Result<()> {
+        merge(matches, "model", &mut self.model, config.model.clone());
+        merge(
+            matches,
+            "chat_template",
+            &mut self.chat_template,
+            config.chat_template.clone().map(Some),
+        );
+        merge(matches, "threads", &mut self.threads, config.threads);
+        merge(
+            matches,
+            "ctx_size",
+            &mut self.ctx_size,
+            config.ctx_size.map(Some),
+        );
+        merge(
+            matches,
+            "temperature",
+            &mut self.temperature,
+            config.temperature,
+        );
+        merge(matches, "top_p", &mut self.top_p, config.top_p);
+        merge(
+            matches,
+            "system",
+            &mut self.system,
+            config.system.clone().map(Some),
+        );
+        if self.model.is_empty() {
+            return Err((
+                ErrorKind::BadInput,
+                "the model is required by --model or the model of the config file",
+            )
+                .into());
+        }
+        Ok(())
+    }
+}
+
+/// serve the model on the OpenAI compatible api, the requests are taken one by one on a
+/// single runner, so the prompt shared with the last request is not forwarded again. the
+/// requests are read on a thread of its own, which answers `/metrics` right away and queues
+/// the others for the model.
+pub fn serve(args: &ServeArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new_with_options(&args.model, args.load.options())?;
+    let gf = gl.open()?;
+    let device = CpuTensorDevice::new().with_threads(args.threads)?;
+    let mut model = CpuLlama2Model::load(&gf, device)?;
+    if let Some(n_ctx) = args.ctx_size {
+        model = model.with_context_size(n_ctx);
+    }
+    let template = match &args.chat_template {
+        Some(name) => ChatTemplate::builtin(name).unwrap(),
+        None => ChatTemplate::from_gguf(&gf),
+    };
+    let runner = Llama2Runner::try_from(&model)?
+        .with_kv_cache_dtype(args.cache_type.dtype())?
+        .with_activation_dtype(args.activation_type.dtype())?
+        .with_flash_attention(args.flash_attn)
+        .with_batch_size(args.batch_size);
+    let mut server = OpenAIServer::new(model_id(&args.model), &model, runner, template)
+        .with_defaults(args.temperature, args.top_p, args.system.clone())
+        .with_timeout(args.timeout.map(Duration::from_secs));
+
+    let addr = format!("{}:{}", args.host, args.port);
+    let listener = TcpListener::bind(&addr).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to listen on {}", addr),
+        cause: Some(Box::new(err)),
+    })?;
+    eprintln!("serving {} on http://{}/v1", server.model_id, addr);
+    let (queue_tx, queue_rx) = mpsc::channel();
+    let metrics = server.metrics.clone();
+    std::thread::spawn(move || accept(listener, metrics, queue_tx));
+    for (req, mut stream) in queue_rx {
+        server.metrics.lock().unwrap().dec_queue_depth();
+        server.abort = watch_disconnect(&stream);
+        // the errors after the response is started are the clients gone away
+        if let Err(err) = server.handle(&req, &mut stream) {
+            eprintln!("{} {}: {}", req.method, req.path, err);
+        }
+        // wakes up the watcher of the connection
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    Ok(())
+}
+
+/// the max bytes of a request body, the larger ones are rejected with 413.
+const MAX_BODY_SIZE: usize = 16 << 20;
+
+/// the max time to wait for a client to send its request.
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the requests are read on a thread of each connection, so a slow or idle client does not
+/// block the others.
+fn accept(
+    listener: TcpListener,
+    metrics: Arc<Mutex<ServerMetrics>>,
+    queue: mpsc::Sender<(HttpRequest, TcpStream)>,
+) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let (metrics, queue) = (metrics.clone(), queue.clone());
+        std::thread::spawn(move || read_request(stream, &metrics, &queue));
+    }
+}
+
+fn read_request(
+    mut stream: TcpStream,
+    metrics: &Mutex<ServerMetrics>,
+    queue: &mpsc::Sender<(HttpRequest, TcpStream)>,
+) {
+    let _ = stream.set_read_timeout(Some(REQUEST_READ_TIMEOUT));
+    let req = match HttpRequest::read(&mut BufReader::new(&mut stream)) {
+        Ok(req) => req,
+        Err(err) => {
+            let _ = write_error(&mut stream, &err);
+            return;
+        }
+    };
+    if req.method == "GET" && req.path == "/metrics" {
+        metrics.lock().unwrap().record_request(&req.path, 200);
+        let _ = write_metrics(&mut stream, metrics);
+        return;
+    }
+    // the watcher of the disconnection blocks on reading until the client closes
+    let _ = stream.set_read_timeout(None);
+    metrics.lock().unwrap().inc_queue_depth();
+    let _ = queue.send((req, stream));
+}
+
+/// returns a handle aborted once the client closes the connection, so the generation of a
+/// client gone away stops without waiting for the next token to fail on writing. the request
+/// is read in whole already, so the client sends nothing more until it closes.
+fn watch_disconnect(stream: &TcpStream) -> Llama2AbortHandle {
+    let abort = Llama2AbortHandle::new();
+    let Ok(mut stream) = stream.try_clone() else {
+        return abort;
+    };
+    let handle = abort.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        handle.abort();
+    });
+    abort
+}
+
+/// the name of the model file without the extension, which is the model id on the api.
+fn model_id(path: &str) -> String {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    name.strip_suffix(".gguf").unwrap_or(name).to_string()
+}
+
+#[derive(Debug, Clone, Default)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// read a request of HTTP/1.1, only the body of `Content-Length` is supported.
+    fn read(r: &mut impl BufRead) -> Result<Self> {
+        let mut line = String::new();
+        r.read_line(&mut line).map_err(io_error)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err((ErrorKind::BadInput, "invalid http request line").into());
+        };
+        let mut req = HttpRequest {
+            method: method.to_string(),
+            path: target.split('?').next().unwrap_or(target).to_string(),
+            body: vec![],
+        };
+
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            if r.read_line(&mut line).map_err(io_error)? == 0 {
+                break;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().map_err(|_| {
+                        Error::from((ErrorKind::BadInput, "invalid content-length"))
+                    })?;
+                }
+            }
+        }
+        if content_length > MAX_BODY_SIZE {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "the request body of {} bytes exceeds the limit of {} bytes",
+                    content_length, MAX_BODY_SIZE
+                ),
+                cause: Some(Box::new(BodyTooLarge)),
+            });
+        }
+        req.body = vec![0; content_length];
+        r.read_exact(&mut req.body).map_err(io_error)?;
+        Ok(req)
+    }
+
+    fn json(&self) -> Result<Value> {
+        serde_json::from_slice(&self.body).map_err(|err| Error {
+            kind: ErrorKind::BadInput,
+            message: "failed to parse the request body as json".to_string(),
+            cause: Some(Box::new(err)),
+        })
+    }
+}
+
+/// the parameters of the generation shared by the completions and the chat completions.
+struct GenerateParams {
+    max_tokens: usize,
+    stop: Vec<String>,
+    stream: bool,
+    sampler: Llama2Sampler,
+}
+
+struct OpenAIServer<'a> {
+    model_id: String,
+    conf: Llama2Config,
+    tokenizer: Rc<BpeTokenizer>,
+    runner: Llama2Runner<CpuTensor<'a>>,
+    template: ChatTemplate,
+    /// the temperature and the top-p of the requests without them
+    temperature: f32,
+    top_p: f32,
+    /// the system message of the chat completions without one
+    system: Option<String>,
+    n_requests: usize,
+    metrics: Arc<Mutex<ServerMetrics>>,
+    /// aborted once the client of the current request closes the connection
+    abort: Llama2AbortHandle,
+    /// the wall-clock timeout of each request
+    timeout: Option<Duration>,
+    /// whether the headers of the event stream are sent for the current request
+    sse_started: bool,
+}
+
+impl<'a> OpenAIServer<'a> {
+    fn new(
+        model_id: String,
+        model: &CpuLlama2Model<'a>,
+        runner: Llama2Runner<CpuTensor<'a>>,
+        template: ChatTemplate,
+    ) -> Self {
+        Self {
+            model_id,
+            conf: *model.conf(),
+            tokenizer: model.tokenizer(),
+            runner,
+            template,
+            temperature: 1.0,
+            top_p: 1.0,
+            system: None,
+            n_requests: 0,
+            metrics: Arc::new(Mutex::new(ServerMetrics::new(model.conf().seq_len))),
+            abort: Llama2AbortHandle::new(),
+            timeout: None,
+            sse_started: false,
+        }
+    }
+
+    fn with_defaults(mut self, temperature: f32, top_p: f32, system: Option<String>) -> Self {
+        self.temperature = temperature;
+        self.top_p = top_p;
+        self.system = system;
+        self
+    }
+
+    fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// write the response of the request, the errors before the response is started are
+    /// written as the errors of the api, and the ones after the event stream is started are
+    /// written as an error event.
+    fn handle(&mut self, req: &HttpRequest, w: &mut impl Write) -> Result<()> {
+        self.n_requests += 1;
+        self.sse_started = false;
+        let result = match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/v1/models") => {
+                let v = json!({
+                    "object": "list",
+                    "data": [{
+                        "id": self.model_id,
+                        "object": "model",
+                        "created": unix_time(),
+                        "owned_by": "crabml",
+                    }],
+                });
+                write_json(w, "200 OK", &v)
+            }
+            ("POST", "/v1/completions") => self.completions(req, w),
+            ("POST", "/v1/chat/completions") => self.chat_completions(req, w),
+            ("POST", "/v1/embeddings") => self.embeddings(req, w),
+            ("GET", "/metrics") => write_metrics(w, &self.metrics),
+            ("OPTIONS", _) => write_response(w, "204 No Content", "text/plain", b""),
+            _ => Err((
+                ErrorKind::NotImplemented,
+                format!("no route of {} {}", req.method, req.path),
+            )
+                .into()),
+        };
+        let code = match &result {
+            Ok(()) if req.method == "OPTIONS" => 204,
+            Ok(()) => 200,
+            Err(err) => error_status(err).0,
+        };
+        self.metrics.lock().unwrap().record_request(&req.path, code);
+        match result {
+            Err(err) if err.kind == ErrorKind::IOError => Err(err),
+            Err(err) if self.sse_started => write_sse_error(w, &err),
+            Err(err) => write_error(w, &err),
+            result => result,
+        }
+    }
+
+    fn completions(&mut self, req: &HttpRequest, w: &mut impl Write) -> Result<()> {
+        let body = req.json()?;
+        let prompt = match &body["prompt"] {
+            Value::String(prompt) => prompt.clone(),
+            Value::Array(prompts) if prompts.len() == 1 && prompts[0].is_string() => {
+                prompts[0].as_str().unwrap().to_string()
+            }
+            _ => {
+                return Err((ErrorKind::BadInput, "expected the prompt to be a string").into());
+            }
+        };
+        let params = self.generate_params(&body, 16)?;
+        let id = format!("cmpl-{}", self.n_requests);
+        let model_id = self.model_id.clone();
+        let chunk = |text: &str, finish_reason: Option<&str>| {
+            json!({
+                "id": id,
+                "object": "text_completion",
+                "created": unix_time(),
+                "model": model_id,
+                "choices": [{
+                    "index": 0,
+                    "text": text,
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }],
+            })
+        };
+
+        if params.stream {
+            self.sse_started = true;
+            write_sse_headers(w)?;
+            let (_, finish_reason) =
+                self.generate(&prompt, params, |text| write_sse(w, &chunk(text, None)))?;
+            write_sse(w, &chunk("", Some(finish_reason)))?;
+            return write_sse_done(w);
+        }
+        let mut output = String::new();
+        let (usage, finish_reason) = self.generate(&prompt, params, |text| {
+            output.push_str(text);
+            Ok(())
+        })?;
+        let mut v = chunk(&output, Some(finish_reason));
+        v["usage"] = usage;
+        write_json(w, "200 OK", &v)
+    }
+
+    fn chat_completions(&mut self, req: &HttpRequest, w: &mut impl Write) -> Result<()> {
+        let body = req.json()?;
+        let mut messages = body["messages"]
+            .as_array()
+            .filter(|messages| !messages.is_empty())
+            .ok_or_else(|| Error::from((ErrorKind::BadInput, "expected the messages")))?
+            .iter()
+            .map(|m| match (m["role"].as_str(), m["content"].as_str()) {
+                (Some(role), Some(content)) => Ok(ChatMessage::new(role, content)),
+                _ => Err((
+                    ErrorKind::BadInput,
+                    "expected the role and the content of the message as strings",
+                )
+                    .into()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(system) = &self.system {
+            if messages.iter().all(|m| m.role != "system") {
+                messages.insert(0, ChatMessage::new("system", system));
+            }
+        }
+        let prompt = self.template.render(&messages, true, &self.tokenizer)?;
+        let params = self.generate_params(&body, usize::MAX)?;
+        let id = format!("chatcmpl-{}", self.n_requests);
+        let model_id = self.model_id.clone();
+        let chunk = |delta: Value, finish_reason: Option<&str>| {
+            json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": unix_time(),
+                "model": model_id,
+                "choices": [{
+                    "index": 0,
+                    "delta": delta,
+                    "finish_reason": finish_reason,
+                }],
+            })
+        };
+
+        if params.stream {
+            self.sse_started = true;
+            write_sse_headers(w)?;
+            write_sse(w, &chunk(json!({"role": "assistant", "content": ""}), None))?;
+            let (_, finish_reason) = self.generate(&prompt, params, |text| {
+                write_sse(w, &chunk(json!({ "content": text }), None))
+            })?;
+            write_sse(w, &chunk(json!({}), Some(finish_reason)))?;
+            return write_sse_done(w);
+        }
+        let mut output = String::new();
+        let (usage, finish_reason) = self.generate(&prompt, params, |text| {
+            output.push_str(text);
+            Ok(())
+        })?;
+        let v = json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": unix_time(),
+            "model": self.model_id,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": output},
+                "finish_reason": finish_reason,
+            }],
+            "usage": usage,
+        });
+        write_json(w, "200 OK", &v)
+    }
+
+    fn embeddings(&mut self, req: &HttpRequest, w: &mut impl Write) -> Result<()> {
+        let body = req.json()?;
+        let inputs = match &body["input"] {
+            Value::String(input) => vec![input.clone()],
+            Value::Array(inputs) if inputs.iter().all(|input| input.is_string()) => inputs
+                .iter()
+                .map(|input| input.as_str().unwrap().to_string())
+                .collect(),
+            _ => {
+                return Err((
+                    ErrorKind::BadInput,
+                    "expected the input to be a string or an array of strings",
+                )
+                    .into());
+            }
+        };
+        let tokenizer = self.tokenizer.clone();
+        let mut data = vec![];
+        let mut n_tokens = 0;
+        for (i, input) in inputs.iter().enumerate() {
+            let embedding = self
+                .runner
+                .embeddings(input, &Llama2EmbeddingOptions::default())?;
+            let n_input_tokens = tokenizer
+                .encode_special(input, tokenizer.add_bos_token(), tokenizer.add_eos_token())?
+                .len();
+            self.metrics.lock().unwrap().record_prompt(n_input_tokens);
+            n_tokens += n_input_tokens;
+            data.push(json!({"object": "embedding", "index": i, "embedding": embedding}));
+        }
+        let v = json!({
+            "object": "list",
+            "data": data,
+            "model": self.model_id,
+            "usage": {"prompt_tokens": n_tokens, "total_tokens": n_tokens},
+        });
+        write_json(w, "200 OK", &v)
+    }
+
+    /// map the sampling parameters of the api to the sampler, the defaults follow the api.
+    fn generate_params(&self, body: &Value, default_max_tokens: usize) -> Result<GenerateParams> {
+        let f32_param = |name: &str, default: f32| match &body[name] {
+            Value::Null => Ok(default),
+            v => v.as_f64().map(|v| v as f32).ok_or_else(|| {
+                Error::from((
+                    ErrorKind::BadInput,
+                    format!("expected {} to be a number", name),
+                ))
+            }),
+        };
+        if body["n"].as_u64().unwrap_or(1) != 1 {
+            return Err((ErrorKind::NotImplemented, "only n=1 is supported").into());
+        }
+
+        let conf = &self.conf;
+        let mut sampler = Llama2Sampler::new(
+            conf.vocab_size,
+            f32_param("temperature", self.temperature)?,
+            f32_param("top_p", self.top_p)?,
+        )
+        .with_penalties(Llama2SamplerPenalties {
+            frequency: f32_param("frequency_penalty", 0.0)?,
+            presence: f32_param("presence_penalty", 0.0)?,
+            ..Default::default()
+        });
+        if let Some(seed) = body["seed"].as_u64() {
+            sampler = sampler.with_seed(seed);
+        }
+        if let Some(logit_bias) = body["logit_bias"].as_object() {
+            // the bias of -100 bans the token on the api
+            let logit_bias = logit_bias
+                .iter()
+                .map(
+                    |(token, bias)| match (token.parse::<usize>(), bias.as_f64()) {
+                        (Ok(token), Some(bias)) if token < conf.vocab_size => {
+                            let bias = if bias <= -100.0 {
+                                f32::NEG_INFINITY
+                            } else {
+                                bias as f32
+                            };
+                            Ok((token, bias))
+                        }
+                        _ => Err(Error::from((
+                            ErrorKind::BadInput,
+                            format!("invalid logit bias of the token {}", token),
+                        ))),
+                    },
+                )
+                .collect::<Result<HashMap<_, _>>>()?;
+            sampler = sampler.with_logit_bias(logit_bias);
+        }
+        let stop = match &body["stop"] {
+            Value::Null => vec![],
+            Value::String(stop) => vec![stop.clone()],
+            Value::Array(stops) if stops.iter().all(|stop| stop.is_string()) => stops
+                .iter()
+                .map(|stop| stop.as_str().unwrap().to_string())
+                .collect(),
+            _ => {
+                return Err((
+                    ErrorKind::BadInput,
+                    "expected stop to be a string or an array of strings",
+                )
+                    .into());
+            }
+        };
+        let max_tokens = body["max_completion_tokens"]
+            .as_u64()
+            .or(body["max_tokens"].as_u64())
+            .map_or(default_max_tokens, |n| n as usize)
+            .min(conf.seq_len);
+        Ok(GenerateParams {
+            max_tokens,
+            stop,
+            stream: body["stream"].as_bool().unwrap_or(false),
+            sampler,
+        })
+    }
+
+    /// generate on the prompt and pass the texts of the tokens to `on_text`, returns the
+    /// usage and the finish reason.
+    fn generate(
+        &mut self,
+        prompt: &str,
+        params: GenerateParams,
+        mut on_text: impl FnMut(&str) -> Result<()>,
+    ) -> Result<(Value, &'static str)> {
+        let tokenizer = &self.tokenizer;
+        let tokens = tokenizer.encode_special(
+            prompt,
+            tokenizer.add_bos_token(),
+            tokenizer.add_eos_token(),
+        )?;
+        let n_prompt = tokens.len();
+        let metrics = self.metrics.clone();
+        metrics.lock().unwrap().record_prompt(n_prompt);
+        let start_time = Instant::now();
+        let mut step_time = Instant::now();
+        // the past turns of the chat are delimited by the eos and bos tokens, on which
+        // `generate` stops, so the prompt is forwarded here
+        let n_cached = self.runner.prefill(&tokens)?;
+        let mut sampler = params.sampler;
+        let abort = self.abort.clone();
+        let mut generator = self
+            .runner
+            .generate(prompt, params.max_tokens, &mut sampler)?
+            .with_max_tokens(params.max_tokens)
+            .with_stop_sequences(params.stop)
+            .with_abort_handle(abort.clone());
+        if let Some(timeout) = self.timeout {
+            // the timeout takes the time of the prefill too
+            generator = generator.with_timeout(timeout.saturating_sub(start_time.elapsed()));
+        }
+        let mut n_tokens = 0;
+        let mut written = Ok(());
+        while let Some(token) = generator.next_token()? {
+            if n_tokens == 0 {
+                metrics.lock().unwrap().record_prefill(step_time.elapsed());
+            } else {
+                metrics.lock().unwrap().record_decode(step_time.elapsed());
+            }
+            n_tokens += 1;
+            if !token.text.is_empty() {
+                // abort on a failed write, so the generator releases the kv cache
+                written = on_text(&token.text);
+                if written.is_err() {
+                    abort.abort();
+                }
+            }
+            step_time = Instant::now();
+        }
+        let finish_reason = generator
+            .finish_reason()
+            .unwrap_or(Llama2FinishReason::Stop);
+        let stats = generator.stats();
+        drop(generator);
+        metrics
+            .lock()
+            .unwrap()
+            .set_kv_cache_tokens(self.runner.tokens().len());
+        written?;
+        if finish_reason == Llama2FinishReason::Cancelled {
+            return Err((ErrorKind::IOError, "the client closed the connection").into());
+        }
+        let usage = json!({
+            "prompt_tokens": stats.n_prompt_tokens,
+            "completion_tokens": stats.n_eval_tokens,
+            "total_tokens": stats.n_prompt_tokens + stats.n_eval_tokens,
+            "prompt_tokens_details": {"cached_tokens": n_cached},
+        });
+        Ok((usage, finish_reason.as_str()))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error {
+        kind: ErrorKind::IOError,
+        message: "failed to read or write the http connection".to_string(),
+        cause: Some(Box::new(err)),
+    }
+}
+
+fn write_response(w: &mut impl Write, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Headers: *\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )
+    .map_err(io_error)?;
+    w.write_all(body).map_err(io_error)?;
+    w.flush().map_err(io_error)
+}
+
+fn write_json(w: &mut impl Write, status: &str, v: &Value) -> Result<()> {
+    write_response(w, status, "application/json", v.to_string().as_bytes())
+}
+
+/// the cause of the request rejected for its body size.
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the request body is too large")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// the status code, the status line and the type of the error on the api, the bad inputs are
+/// the errors of the client.
+fn error_status(err: &Error) -> (u16, &'static str, &'static str) {
+    if err
+        .cause
+        .as_deref()
+        .is_some_and(|cause| cause.is::<BodyTooLarge>())
+    {
+        return (413, "413 Payload Too Large", "invalid_request_error");
+    }
+    match err.kind {
+        ErrorKind::BadInput => (400, "400 Bad Request", "invalid_request_error"),
+        ErrorKind::NotImplemented => (404, "404 Not Found", "invalid_request_error"),
+        _ => (500, "500 Internal Server Error", "server_error"),
+    }
+}
+
+/// write the error in the format of the api.
+fn write_error(w: &mut impl Write, err: &Error) -> Result<()> {
+    let (_, status, typ) = error_status(err);
+    let v = json!({"error": {"message": err.message, "type": typ, "code": null}});
+    write_json(w, status, &v)
+}
+
+/// write the error as the last event of a started event stream, no [DONE] follows it.
+fn write_sse_error(w: &mut impl Write, err: &Error) -> Result<()> {
+    let (_, _, typ) = error_status(err);
+    write_sse(
+        w,
+        &json!({"error": {"message": err.message, "type": typ, "code": null}}),
+    )
+}
+
+/// write the metrics in the text format of prometheus.
+fn write_metrics(w: &mut impl Write, metrics: &Mutex<ServerMetrics>) -> Result<()> {
+    let body = metrics.lock().unwrap().render();
+    write_response(w, "200 OK", "text/plain; version=0.0.4", body.as_bytes())
+}
+
+/// the events are written until the connection is closed, so there's no content length.
+fn write_sse_headers(w: &mut impl Write) -> Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n"
+    )
+    .map_err(io_error)?;
+    w.flush().map_err(io_error)
+}
+
+fn write_sse(w: &mut impl Write, v: &Value) -> Result<()> {
+    write!(w, "data: {}\n\n", v).map_err(io_error)?;
+    w.flush().map_err(io_error)
+}
+
+fn write_sse_done(w: &mut impl Write) -> Result<()> {
+    write!(w, "data: [DONE]\n\n").map_err(io_error)?;
+    w.flush().map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &Value) -> HttpRequest {
+        let body = body.to_string();
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        HttpRequest::read(&mut raw.as_bytes()).unwrap()
+    }
+
+    /// split the response into the status line and the body.
+    fn response(out: &[u8]) -> (String, String) {
+        let out = String::from_utf8(out.to_vec()).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[test]
+    fn test_read_request_limits() -> Result<()> {
+        let raw = format!(
+            "POST /v1/completions HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let err = HttpRequest::read(&mut raw.as_bytes()).unwrap_err();
+        let mut out = vec![];
+        write_error(&mut out, &err)?;
+        assert_eq!(response(&out).0, "HTTP/1.1 413 Payload Too Large");
+
+        // an idle client does not block the requests of the others
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Mutex::new(ServerMetrics::new(512)));
+        let (queue_tx, _queue_rx) = mpsc::channel();
+        std::thread::spawn(move || accept(listener, metrics, queue_tx));
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(client, "GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut out = vec![];
+        client.read_to_end(&mut out).unwrap();
+        assert_eq!(response(&out).0, "HTTP/1.1 200 OK");
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_openai() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let model = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let runner = Llama2Runner::try_from(&model)?;
+        let mut server = OpenAIServer::new(
+            model_id("../testdata/tinyllamas-stories-260k-f32.gguf"),
+            &model,
+            runner,
+            ChatTemplate::Llama2,
+        );
+
+        let mut out = vec![];
+        server.handle(&request("GET", "/v1/models", &json!({})), &mut out)?;
+        let (status, body) = response(&out);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["data"][0]["id"], "tinyllamas-stories-260k-f32");
+
+        // the greedy completion stops on the max tokens
+        let req = json!({"prompt": "Lily", "max_tokens": 8, "temperature": 0.0});
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/completions", &req), &mut out)?;
+        let (status, body) = response(&out);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["object"], "text_completion");
+        assert_eq!(v["choices"][0]["finish_reason"], "length");
+        assert_eq!(v["usage"]["completion_tokens"], 8);
+        assert_eq!(v["usage"]["prompt_tokens_details"]["cached_tokens"], 0);
+        let text = v["choices"][0]["text"].as_str().unwrap().to_string();
+        assert!(!text.is_empty());
+
+        // the stream takes the same text in the chunks
+        let req = json!({"prompt": "Lily", "max_tokens": 8, "temperature": 0.0, "stream": true});
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/completions", &req), &mut out)?;
+        let (status, body) = response(&out);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let events = body
+            .split("\n\n")
+            .filter_map(|e| e.strip_prefix("data: "))
+            .collect::<Vec<_>>();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let chunks = events[..events.len() - 1]
+            .iter()
+            .map(|e| serde_json::from_str::<Value>(e).unwrap())
+            .collect::<Vec<_>>();
+        let streamed = chunks
+            .iter()
+            .map(|c| c["choices"][0]["text"].as_str().unwrap())
+            .collect::<String>();
+        assert_eq!(streamed, text);
+        assert_eq!(
+            chunks.last().unwrap()["choices"][0]["finish_reason"],
+            "length"
+        );
+
+        // the chat completion
+        let req = json!({
+            "messages": [{"role": "user", "content": "Tell me a story"}],
+            "max_tokens": 4,
+            "temperature": 0.0,
+        });
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/chat/completions", &req), &mut out)?;
+        let v: Value = serde_json::from_str(&response(&out).1).unwrap();
+        assert_eq!(v["object"], "chat.completion");
+        assert_eq!(v["choices"][0]["message"]["role"], "assistant");
+        assert_eq!(v["usage"]["completion_tokens"], 4);
+
+        // the past turns delimited by the eos token are forwarded too
+        let req = json!({
+            "messages": [
+                {"role": "user", "content": "Tell me a story"},
+                {"role": "assistant", "content": "Lily went to the park."},
+                {"role": "user", "content": "And then?"},
+            ],
+            "max_tokens": 4,
+            "temperature": 0.0,
+        });
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/chat/completions", &req), &mut out)?;
+        let v: Value = serde_json::from_str(&response(&out).1).unwrap();
+        assert_eq!(v["usage"]["completion_tokens"], 4);
+
+        // the embeddings of the inputs are normalized
+        let req = json!({"input": ["Lily", "Tom"]});
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/embeddings", &req), &mut out)?;
+        let v: Value = serde_json::from_str(&response(&out).1).unwrap();
+        assert_eq!(v["data"].as_array().unwrap().len(), 2);
+        let embedding = v["data"][1]["embedding"].as_array().unwrap();
+        assert_eq!(embedding.len(), model.conf().embedding_dim);
+        let norm = embedding
+            .iter()
+            .map(|x| x.as_f64().unwrap().powi(2))
+            .sum::<f64>();
+        assert!((norm - 1.0).abs() < 1e-3);
+
+        // the errors of the api
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/completions", &json!({})), &mut out)?;
+        let (status, body) = response(&out);
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["error"]["type"], "invalid_request_error");
+        let mut out = vec![];
+        server.handle(&request("GET", "/v1/unknown", &json!({})), &mut out)?;
+        assert_eq!(response(&out).0, "HTTP/1.1 404 Not Found");
+
+        // the metrics of the requests above
+        let mut out = vec![];
+        server.handle(&request("GET", "/metrics", &json!({})), &mut out)?;
+        let (status, body) = response(&out);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        for line in [
+            "crabml_requests_total{endpoint=\"/v1/completions\",code=\"200\"} 2",
+            "crabml_requests_total{endpoint=\"/v1/completions\",code=\"400\"} 1",
+            "crabml_requests_total{endpoint=\"other\",code=\"404\"} 1",
+            "crabml_generated_tokens_total 24",
+            "crabml_prefill_seconds_count 4",
+            "crabml_queue_depth 0",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{} not in:\n{}",
+                line,
+                body
+            );
+        }
+
+        // the error after the event stream is started is sent as an error event
+        let prompt = "Lily ".repeat(model.conf().seq_len);
+        let req = json!({"prompt": prompt, "max_tokens": 8, "stream": true});
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/completions", &req), &mut out)?;
+        let (status, body) = response(&out);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(!body.contains("HTTP/1.1"), "{}", body);
+        let event = body.strip_prefix("data: ").unwrap().trim_end();
+        let v: Value = serde_json::from_str(event).unwrap();
+        assert!(v["error"]["message"].is_string(), "{}", v);
+
+        // the aborted generation releases the kv cache without writing a response
+        server.abort.abort();
+        let req = json!({"prompt": "Lily", "max_tokens": 8, "stream": true});
+        let mut out = vec![];
+        let err = server
+            .handle(&request("POST", "/v1/completions", &req), &mut out)
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        assert!(response(&out).1.is_empty());
+        assert!(server.runner.tokens().is_empty());
+
+        // the timeout takes the time of the prefill
+        server.abort = Llama2AbortHandle::new();
+        server.timeout = Some(Duration::ZERO);
+        let req = json!({"prompt": "Lily", "max_tokens": 8});
+        let mut out = vec![];
+        server.handle(&request("POST", "/v1/completions", &req), &mut out)?;
+        let v: Value = serde_json::from_str(&response(&out).1).unwrap();
+        assert_eq!(v["choices"][0]["finish_reason"], "timeout");
+        assert_eq!(v["usage"]["completion_tokens"], 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use clap::Args;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::BpeSpecialMode;
+use crabml::tokenizer::BpeTokenizer;
+use serde_json::json;
+
+#[derive(Args, Debug)]
+pub struct TokenizeArgs {
+    /// The GGUF file to load the tokenizer from, the tensors are not loaded
+    #[arg(short, long)]
+    model: String,
+
+    /// The text to tokenize
+    text: String,
+
+    /// Do not prepend the bos token, which is added by default if the tokenizer asks so
+    #[arg(long, default_value_t = false)]
+    no_bos: bool,
+
+    /// Encode the literals of the special tokens like `</s>` as plain text
+    #[arg(long, default_value_t = false)]
+    no_special: bool,
+
+    /// Print the token ids only
+    #[arg(long, default_value_t = false)]
+    ids: bool,
+
+    /// Print the result in json for scripting
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+pub fn tokenize(args: &TokenizeArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model)?;
+    let gf = gl.open()?;
+    let tokenizer = BpeTokenizer::from_gguf(&gf)?;
+
+    let bos = tokenizer.add_bos_token() && !args.no_bos;
+    let eos = tokenizer.add_eos_token();
+    let tokens = if args.no_special {
+        tokenizer.encode(&args.text, bos, eos)?
+    } else {
+        tokenizer.encode_special(&args.text, bos, eos)?
+    };
+
+    if args.json {
+        let pieces = tokens
+            .iter()
+            .map(|token| json!({ "id": token, "piece": tokenizer.token(*token) }))
+            .collect::<Vec<_>>();
+        let text = tokenizer.decode_tokens(&tokens, BpeSpecialMode::Render)?;
+        let v = json!({ "tokens": pieces, "count": tokens.len(), "decoded": text });
+        println!("{}", serde_json::to_string_pretty(&v).unwrap());
+        return Ok(());
+    }
+    if args.ids {
+        let ids = tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        println!("{}", ids.join(" "));
+        return Ok(());
+    }
+
+    let id_width = tokens
+        .iter()
+        .map(|t| t.to_string().len())
+        .max()
+        .unwrap_or(0);
+    for token in tokens.iter() {
+        let text = match tokenizer.token_text(*token) {
+            Some(text) => format!("{:?}", text),
+            None => String::new(),
+        };
+        let line = format!(
+            "{:>id_width$}  {:20}  {}",
+            token,
+            tokenizer.token(*token),
+            text
+        );
+        println!("{}", line.trim_end());
+    }
+    println!("\ntokens: {}", tokens.len());
+    Ok(())
+}
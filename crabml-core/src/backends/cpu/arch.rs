@@ -0,0 +1,56 @@
+//! the simd instructions of the cpu detected on the runtime. the kernels of all the
+//! instructions are built for any cpu of the target arch, and the best one is picked on the
+//! runtime, so the same binary runs on the old cpus and runs fast on the new ones.
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::sync::LazyLock;
+
+/// the simd instructions taken by the kernels on x86_64, ordered from the slowest.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum X86Simd {
+    Fallback,
+    Avx2,   // avx2 and fma
+    Avx512, // avx512f and avx512bw, besides avx2
+}
+
+#[cfg(target_arch = "x86_64")]
+impl X86Simd {
+    /// the detection is taken only once, the kernels check it on every call.
+    pub fn detect() -> Self {
+        static SIMD: LazyLock<X86Simd> = LazyLock::new(|| {
+            let avx2 = is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma");
+            let avx512 =
+                is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw");
+            match (avx2, avx512) {
+                (true, true) => X86Simd::Avx512,
+                (true, false) => X86Simd::Avx2,
+                _ => X86Simd::Fallback,
+            }
+        });
+        *SIMD
+    }
+}
+
+/// the simd instructions taken by the kernels on aarch64, neon is always there on aarch64.
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ArmSimd {
+    Neon,
+    Dotprod, /* the sdot and udot of armv8.2, like apple silicon or the cortex-a76 of raspberry pi 5 */
+}
+
+#[cfg(target_arch = "aarch64")]
+impl ArmSimd {
+    /// the detection is taken only once, the kernels check it on every call.
+    pub fn detect() -> Self {
+        static SIMD: LazyLock<ArmSimd> = LazyLock::new(|| {
+            if std::arch::is_aarch64_feature_detected!("dotprod") {
+                ArmSimd::Dotprod
+            } else {
+                ArmSimd::Neon
+            }
+        });
+        *SIMD
+    }
+}
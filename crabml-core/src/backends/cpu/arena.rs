@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+/// the bytes of the free buffers kept by the arena of a device by default.
+pub const DEFAULT_ARENA_BYTES: usize = 64 * 1024 * 1024;
+
+/// the scratch buffers of the activations on a device. the f32 buffers of the dropped tensors,
+/// like the outputs of the matmuls on each layer, are kept by their length, and taken again
+/// on allocating the tensors of the same length, so each forward pass reuses the buffers of
+/// the last one instead of allocating them again. the free buffers are kept up to
+/// `max_bytes`, the ones beyond are freed, so the memory taken by the arena is bounded.
+#[derive(Debug, Clone, Default)]
+pub struct CpuTensorArena {
+    free: HashMap<usize, Vec<Vec<f32>>>, // the free buffers by the length
+    free_bytes: usize,
+    max_bytes: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl CpuTensorArena {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// take a zeroed buffer of `len` from the free ones, or allocate it if there's none.
+    pub fn alloc(&mut self, len: usize) -> Vec<f32> {
+        if len == 0 {
+            return vec![];
+        }
+        match self.free.get_mut(&len).and_then(|bufs| bufs.pop()) {
+            Some(mut buf) => {
+                self.free_bytes -= buf.capacity() * 4;
+                self.hits += 1;
+                buf.fill(0.0);
+                buf
+            }
+            None => {
+                self.misses += 1;
+                vec![0.0; len]
+            }
+        }
+    }
+
+    /// keep the buffer of a dropped tensor for the next allocations, or free it if the arena
+    /// is full.
+    pub fn recycle(&mut self, buf: Vec<f32>) {
+        let bytes = buf.capacity() * 4;
+        if buf.is_empty() || self.free_bytes + bytes > self.max_bytes {
+            return;
+        }
+        self.free_bytes += bytes;
+        self.free.entry(buf.len()).or_default().push(buf);
+    }
+
+    /// the bytes of the free buffers kept by the arena.
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// the allocations taken from the free buffers.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// the allocations which found no free buffer of their length.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena_reuse() {
+        let mut arena = CpuTensorArena::new(1024);
+        let mut buf = arena.alloc(64);
+        buf.fill(1.0);
+        arena.recycle(buf);
+        assert_eq!(arena.free_bytes(), 256);
+
+        // the buffer of the same length is taken again, and zeroed
+        let buf = arena.alloc(64);
+        assert_eq!(buf, vec![0.0; 64]);
+        assert_eq!((arena.hits(), arena.misses()), (1, 1));
+        assert_eq!(arena.free_bytes(), 0);
+
+        // the other lengths are allocated
+        arena.recycle(buf);
+        assert_eq!(arena.alloc(32).len(), 32);
+        assert_eq!((arena.hits(), arena.misses()), (1, 2));
+
+        // the buffers beyond the max bytes are freed
+        arena.recycle(vec![0.0; 256]);
+        assert_eq!(arena.free_bytes(), 256);
+        arena.recycle(vec![0.0; 192]);
+        assert_eq!(arena.free_bytes(), 1024);
+    }
+}
@@ -1,8 +1,33 @@
 use std::borrow::Cow;
 
+use half::f16;
+
+use super::buf_f16::dequantize_f16_f32;
+use super::buf_f16::f16_buf_from_bytes;
+use super::buf_f16::quantize_f32_f16;
+use super::buf_f16::vec_dot_f16_f16;
 use super::buf_f32::f32_buf_from_bytes;
 use super::buf_f32::vec_dot_f32_f32;
+use super::buf_q4_0::BlockQ4_0;
+use super::buf_q8_0::BlockQ8_0;
+use super::QuantBuf;
+use crate::backends::cpu::buf::QuantBufIQ2XS;
+use crate::backends::cpu::buf::QuantBufIQ2XXS;
+use crate::backends::cpu::buf::QuantBufIQ3XXS;
+use crate::backends::cpu::buf::QuantBufIQ4NL;
+use crate::backends::cpu::buf::QuantBufQ2K;
+use crate::backends::cpu::buf::QuantBufQ3K;
+use crate::backends::cpu::buf::QuantBufQ4K;
+use crate::backends::cpu::buf::QuantBufQ4_0;
+use crate::backends::cpu::buf::QuantBufQ4_1;
+use crate::backends::cpu::buf::QuantBufQ5K;
+use crate::backends::cpu::buf::QuantBufQ5_0;
+use crate::backends::cpu::buf::QuantBufQ5_1;
+use crate::backends::cpu::buf::QuantBufQ6K;
+use crate::backends::cpu::buf::QuantBufQ8K;
 use crate::backends::cpu::buf::QuantBufQ8_0;
+use crate::backends::cpu::buf::QuantBufQ8_1;
+use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
@@ -12,20 +37,64 @@ use crate::gguf::GGMLType;
 #[non_exhaustive]
 pub enum CpuTensorBuf<'a> {
     F32(Cow<'a, [f32]>),
+    F16(Cow<'a, [f16]>),
     Q8_0(QuantBufQ8_0<'a>),
+    Q4_0(QuantBufQ4_0<'a>),
+    Q4_1(QuantBufQ4_1<'a>),
+    Q5_0(QuantBufQ5_0<'a>),
+    Q5_1(QuantBufQ5_1<'a>),
+    Q8_1(QuantBufQ8_1<'a>),
+    Q2K(QuantBufQ2K<'a>),
+    Q3K(QuantBufQ3K<'a>),
+    Q4K(QuantBufQ4K<'a>),
+    Q5K(QuantBufQ5K<'a>),
+    Q6K(QuantBufQ6K<'a>),
+    Q8K(QuantBufQ8K<'a>),
+    IQ4NL(QuantBufIQ4NL<'a>),
+    IQ2XXS(QuantBufIQ2XXS<'a>),
+    IQ2XS(QuantBufIQ2XS<'a>),
+    IQ3XXS(QuantBufIQ3XXS<'a>),
 }
 
 impl<'a> CpuTensorBuf<'a> {
     pub fn from_raw_bytes(buf: &'a [u8], typ: GGMLType) -> Result<Self> {
         match typ {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(f32_buf_from_bytes(buf))),
+            GGMLType::F16 => Ok(CpuTensorBuf::F16(f16_buf_from_bytes(buf))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::from_bytes(buf))),
-            _ => unimplemented!(),
+            GGMLType::Q4_0 => Ok(CpuTensorBuf::Q4_0(QuantBufQ4_0::from_bytes(buf))),
+            GGMLType::Q4_1 => Ok(CpuTensorBuf::Q4_1(QuantBufQ4_1::from_bytes(buf))),
+            GGMLType::Q5_0 => Ok(CpuTensorBuf::Q5_0(QuantBufQ5_0::from_bytes(buf))),
+            GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::from_bytes(buf))),
+            GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::from_bytes(buf))),
+            GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::from_bytes(buf))),
+            GGMLType::Q4K => Ok(CpuTensorBuf::Q4K(QuantBufQ4K::from_bytes(buf))),
+            GGMLType::Q5K => Ok(CpuTensorBuf::Q5K(QuantBufQ5K::from_bytes(buf))),
+            GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::from_bytes(buf))),
+            GGMLType::IQ4NL => Ok(CpuTensorBuf::IQ4NL(QuantBufIQ4NL::from_bytes(buf))),
+            GGMLType::IQ2XXS => Ok(CpuTensorBuf::IQ2XXS(QuantBufIQ2XXS::from_bytes(buf))),
+            GGMLType::IQ2XS => Ok(CpuTensorBuf::IQ2XS(QuantBufIQ2XS::from_bytes(buf))),
+            GGMLType::IQ3XXS => Ok(CpuTensorBuf::IQ3XXS(QuantBufIQ3XXS::from_bytes(buf))),
+            _ => Err((
+                ErrorKind::TensorError,
+                format!("tensor type {} is not supported yet", typ),
+            )
+                .into()),
         }
     }
 
     pub fn is_owned(&self) -> bool {
-        matches!(self, CpuTensorBuf::F32(Cow::Owned(_)))
+        matches!(
+            self,
+            CpuTensorBuf::F32(Cow::Owned(_))
+                | CpuTensorBuf::F16(Cow::Owned(_))
+                | CpuTensorBuf::Q8_0(QuantBuf {
+                    blocks: Cow::Owned(_)
+                })
+                | CpuTensorBuf::Q4_0(QuantBuf {
+                    blocks: Cow::Owned(_)
+                })
+        )
     }
 
     pub fn is_quantized(&self) -> bool {
@@ -35,7 +104,23 @@ impl<'a> CpuTensorBuf<'a> {
     pub fn len(&self) -> usize {
         match self {
             CpuTensorBuf::F32(buf) => buf.len(),
+            CpuTensorBuf::F16(buf) => buf.len(),
             CpuTensorBuf::Q8_0(buf) => buf.len(),
+            CpuTensorBuf::Q4_0(buf) => buf.len(),
+            CpuTensorBuf::Q4_1(buf) => buf.len(),
+            CpuTensorBuf::Q5_0(buf) => buf.len(),
+            CpuTensorBuf::Q5_1(buf) => buf.len(),
+            CpuTensorBuf::Q8_1(buf) => buf.len(),
+            CpuTensorBuf::Q2K(buf) => buf.len(),
+            CpuTensorBuf::Q3K(buf) => buf.len(),
+            CpuTensorBuf::Q4K(buf) => buf.len(),
+            CpuTensorBuf::Q5K(buf) => buf.len(),
+            CpuTensorBuf::Q6K(buf) => buf.len(),
+            CpuTensorBuf::Q8K(buf) => buf.len(),
+            CpuTensorBuf::IQ4NL(buf) => buf.len(),
+            CpuTensorBuf::IQ2XXS(buf) => buf.len(),
+            CpuTensorBuf::IQ2XS(buf) => buf.len(),
+            CpuTensorBuf::IQ3XXS(buf) => buf.len(),
         }
     }
 
@@ -46,7 +131,49 @@ impl<'a> CpuTensorBuf<'a> {
     pub fn dtype(&self) -> GGMLType {
         match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
+            CpuTensorBuf::F16(_) => GGMLType::F16,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
+            CpuTensorBuf::Q4_0(_) => GGMLType::Q4_0,
+            CpuTensorBuf::Q4_1(_) => GGMLType::Q4_1,
+            CpuTensorBuf::Q5_0(_) => GGMLType::Q5_0,
+            CpuTensorBuf::Q5_1(_) => GGMLType::Q5_1,
+            CpuTensorBuf::Q8_1(_) => GGMLType::Q8_1,
+            CpuTensorBuf::Q2K(_) => GGMLType::Q2K,
+            CpuTensorBuf::Q3K(_) => GGMLType::Q3K,
+            CpuTensorBuf::Q4K(_) => GGMLType::Q4K,
+            CpuTensorBuf::Q5K(_) => GGMLType::Q5K,
+            CpuTensorBuf::Q6K(_) => GGMLType::Q6K,
+            CpuTensorBuf::Q8K(_) => GGMLType::Q8K,
+            CpuTensorBuf::IQ4NL(_) => GGMLType::IQ4NL,
+            CpuTensorBuf::IQ2XXS(_) => GGMLType::IQ2XXS,
+            CpuTensorBuf::IQ2XS(_) => GGMLType::IQ2XS,
+            CpuTensorBuf::IQ3XXS(_) => GGMLType::IQ3XXS,
+        }
+    }
+
+    /// the dtype of the other operand in `vec_dot`. the activation is quantized into this
+    /// dtype before doing matmul with this buffer.
+    pub fn vec_dot_dtype(&self) -> GGMLType {
+        match self {
+            CpuTensorBuf::F32(_) => GGMLType::F32,
+            CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::Q8_0(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q4_0(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q4_1(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q5_0(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q5_1(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q2K(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q3K(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q4K(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q5K(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q6K(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::IQ4NL(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::IQ2XXS(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::IQ2XS(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::IQ3XXS(buf) => buf.vec_dot_dtype(),
+            CpuTensorBuf::Q8_1(_) | CpuTensorBuf::Q8K(_) => {
+                unreachable!("{} is only used as the activation", self.dtype())
+            }
         }
     }
 
@@ -62,21 +189,123 @@ impl<'a> CpuTensorBuf<'a> {
                 .into());
         }
 
+        let typ = self.dtype();
+        let not_implemented = || {
+            Err(Error::from((
+                ErrorKind::NotImplemented,
+                format!("dequantize {} to {} is not implemented yet", typ, dtype),
+            )))
+        };
         match self {
-            CpuTensorBuf::F32(buf) => Ok(CpuTensorBuf::F32(buf)),
+            CpuTensorBuf::F32(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf)),
+                _ => Ok(CpuTensorBuf::F16(quantize_f32_f16(&buf))),
+            },
+            CpuTensorBuf::F16(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(dequantize_f16_f32(&buf))),
+                _ => Ok(CpuTensorBuf::F16(buf)),
+            },
             CpuTensorBuf::Q8_0(buf) => match dtype {
                 GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
-                _ => unimplemented!(),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q4_0(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q4_1(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q5_0(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q5_1(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q8_1(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q2K(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q3K(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q4K(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q5K(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q6K(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::Q8K(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::IQ4NL(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::IQ2XXS(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::IQ2XS(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
+            },
+            CpuTensorBuf::IQ3XXS(buf) => match dtype {
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(buf.dequantize(0).collect())),
+                _ => not_implemented(),
             },
         }
     }
 
     pub fn quantize(&self, dtype: GGMLType) -> Result<Self> {
+        // the f16 activations are taken as they are by the f16 weights, and widened to f32
+        // before quantized into the vec dot types of the others
+        if let CpuTensorBuf::F16(buf) = self {
+            return match dtype {
+                GGMLType::F16 => Ok(self.clone()),
+                GGMLType::F32 => Ok(CpuTensorBuf::F32(dequantize_f16_f32(buf))),
+                _ => CpuTensorBuf::F32(dequantize_f16_f32(buf)).quantize(dtype),
+            };
+        }
         match dtype {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(self.as_f32_ref().to_vec().into())),
+            GGMLType::F16 => Ok(CpuTensorBuf::F16(quantize_f32_f16(self.as_f32_ref()))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::quantize(
                 self.as_f32_ref(),
             ))),
+            GGMLType::Q4_0 => Ok(CpuTensorBuf::Q4_0(QuantBufQ4_0::quantize(
+                self.as_f32_ref(),
+            ))),
+            GGMLType::Q4_1 => Ok(CpuTensorBuf::Q4_1(QuantBufQ4_1::quantize(
+                self.as_f32_ref(),
+            ))),
+            GGMLType::Q5_0 => Ok(CpuTensorBuf::Q5_0(QuantBufQ5_0::quantize(
+                self.as_f32_ref(),
+            ))),
+            GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::quantize(
+                self.as_f32_ref(),
+            ))),
+            GGMLType::Q8_1 => Ok(CpuTensorBuf::Q8_1(QuantBufQ8_1::quantize(
+                self.as_f32_ref(),
+            ))),
+            GGMLType::Q4K => Ok(CpuTensorBuf::Q4K(QuantBufQ4K::quantize(self.as_f32_ref()))),
+            GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::quantize(self.as_f32_ref()))),
+            GGMLType::Q8K => Ok(CpuTensorBuf::Q8K(QuantBufQ8K::quantize(self.as_f32_ref()))),
             _ => Err((
                 ErrorKind::TensorError,
                 format!("quantize to {:?} is not supported", dtype),
@@ -87,34 +316,91 @@ impl<'a> CpuTensorBuf<'a> {
 
     pub fn vec_dot(&self, a_offset: usize, b: &Self, b_offset: usize, len: usize) -> f32 {
         assert!(
-            self.dtype() == b.dtype(),
-            "only same dtype can be dotted, but got {:?} and {:?}",
+            self.vec_dot_dtype() == b.dtype(),
+            "{:?} can only be dotted with {:?}, but got {:?}",
             self.dtype(),
+            self.vec_dot_dtype(),
             b.dtype()
         );
 
         use CpuTensorBuf::*;
         match (self, b) {
             (F32(a), F32(b)) => vec_dot_f32_f32(a, a_offset, b, b_offset, len),
+            (F16(a), F16(b)) => vec_dot_f16_f16(a, a_offset, b, b_offset, len),
             (Q8_0(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q4_0(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q4_1(a), Q8_1(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q5_0(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q5_1(a), Q8_1(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q2K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q3K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q4K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q5K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q6K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (IQ4NL(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (IQ2XXS(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (IQ2XS(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (IQ3XXS(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             _ => unreachable!(),
         }
     }
 
+    /// append the values, which are quantized on the owned Q8_0 and Q4_0 buffers of the kv
+    /// caches, the number of the values must be a multiple of the block size then.
     pub fn extend(&mut self, iter: impl Iterator<Item = f32>) {
         match self {
             CpuTensorBuf::F32(Cow::Owned(buf)) => buf.extend(iter),
+            CpuTensorBuf::Q8_0(QuantBuf {
+                blocks: Cow::Owned(blocks),
+            }) => blocks.extend(quantize_blocks_q8_0(iter)),
+            CpuTensorBuf::Q4_0(QuantBuf {
+                blocks: Cow::Owned(blocks),
+            }) => blocks.extend(quantize_blocks_q4_0(iter)),
             _ => unreachable!("only owned buffers can be extended"),
         }
     }
 
+    /// overwrite the values from the offset, like `extend`, the offset and the number of the
+    /// values must be multiples of the block size on the quantized buffers.
+    pub fn overwrite(&mut self, offset: usize, iter: impl Iterator<Item = f32>) {
+        fn overwrite_blocks<B: Clone>(blocks: &mut [B], offset: usize, src: Vec<B>) {
+            blocks[offset..offset + src.len()].clone_from_slice(&src);
+        }
+
+        match self {
+            CpuTensorBuf::F32(Cow::Owned(buf)) => buf
+                .iter_mut()
+                .skip(offset)
+                .zip(iter)
+                .for_each(|(dst, src)| *dst = src),
+            CpuTensorBuf::Q8_0(QuantBuf {
+                blocks: Cow::Owned(blocks),
+            }) => {
+                assert_eq!(offset % 32, 0);
+                let src = quantize_blocks_q8_0(iter);
+                overwrite_blocks(blocks, offset / 32, src)
+            }
+            CpuTensorBuf::Q4_0(QuantBuf {
+                blocks: Cow::Owned(blocks),
+            }) => {
+                assert_eq!(offset % 32, 0);
+                let src = quantize_blocks_q4_0(iter);
+                overwrite_blocks(blocks, offset / 32, src)
+            }
+            _ => unreachable!("only owned buffers can be overwritten"),
+        }
+    }
+
     pub fn copy_from(&mut self, src: &Self, offset: usize, len: usize) -> Result<()> {
         assert!(self.is_owned(), "only owned buffers can be copied to");
         assert!(
             self.dtype() == GGMLType::F32 || self.dtype() == GGMLType::F16,
             "only f32/f16 can be copied to"
         );
-        assert!(self.dtype() == src.dtype(), "only same dtype can be copied");
+        assert!(
+            self.dtype() == src.dtype() || (self.dtype() == GGMLType::F32 && src.is_owned()),
+            "only same dtype or the quantized kv caches can be copied"
+        );
 
         match src {
             CpuTensorBuf::F32(buf) => {
@@ -123,6 +409,19 @@ impl<'a> CpuTensorBuf<'a> {
                     *dst = *src;
                 });
             }
+            // the rows of the quantized kv caches are dequantized on copying
+            CpuTensorBuf::Q8_0(buf) => {
+                let src_iter = buf.dequantize(offset).take(len);
+                self.iter_f32_mut().zip(src_iter).for_each(|(dst, src)| {
+                    *dst = src;
+                });
+            }
+            CpuTensorBuf::Q4_0(buf) => {
+                let src_iter = buf.dequantize(offset).take(len);
+                self.iter_f32_mut().zip(src_iter).for_each(|(dst, src)| {
+                    *dst = src;
+                });
+            }
             // TODO: add f16 support
             _ => unreachable!("only f32/f16 buffers can be copied"),
         };
@@ -144,6 +443,64 @@ impl<'a> CpuTensorBuf<'a> {
         }
     }
 
+    /// the raw bytes of the buffer, which is the same layout as the tensor data in GGUF files.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            CpuTensorBuf::F32(buf) => unsafe {
+                std::slice::from_raw_parts(
+                    buf.as_ptr() as *const u8,
+                    std::mem::size_of_val(&buf[..]),
+                )
+            },
+            CpuTensorBuf::F16(buf) => unsafe {
+                std::slice::from_raw_parts(
+                    buf.as_ptr() as *const u8,
+                    std::mem::size_of_val(&buf[..]),
+                )
+            },
+            CpuTensorBuf::Q8_0(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q4_0(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q4_1(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q5_0(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q5_1(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q8_1(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q2K(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q3K(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q4K(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q5K(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q6K(buf) => buf.as_bytes(),
+            CpuTensorBuf::Q8K(buf) => buf.as_bytes(),
+            CpuTensorBuf::IQ4NL(buf) => buf.as_bytes(),
+            CpuTensorBuf::IQ2XXS(buf) => buf.as_bytes(),
+            CpuTensorBuf::IQ2XS(buf) => buf.as_bytes(),
+            CpuTensorBuf::IQ3XXS(buf) => buf.as_bytes(),
+        }
+    }
+
+    /// copy the borrowed buffer, like the one on the mmaped GGUF file, into an owned one.
+    pub fn into_owned(self) -> CpuTensorBuf<'static> {
+        match self {
+            CpuTensorBuf::F32(buf) => CpuTensorBuf::F32(Cow::Owned(buf.into_owned())),
+            CpuTensorBuf::F16(buf) => CpuTensorBuf::F16(Cow::Owned(buf.into_owned())),
+            CpuTensorBuf::Q8_0(buf) => CpuTensorBuf::Q8_0(buf.into_owned()),
+            CpuTensorBuf::Q4_0(buf) => CpuTensorBuf::Q4_0(buf.into_owned()),
+            CpuTensorBuf::Q4_1(buf) => CpuTensorBuf::Q4_1(buf.into_owned()),
+            CpuTensorBuf::Q5_0(buf) => CpuTensorBuf::Q5_0(buf.into_owned()),
+            CpuTensorBuf::Q5_1(buf) => CpuTensorBuf::Q5_1(buf.into_owned()),
+            CpuTensorBuf::Q8_1(buf) => CpuTensorBuf::Q8_1(buf.into_owned()),
+            CpuTensorBuf::Q2K(buf) => CpuTensorBuf::Q2K(buf.into_owned()),
+            CpuTensorBuf::Q3K(buf) => CpuTensorBuf::Q3K(buf.into_owned()),
+            CpuTensorBuf::Q4K(buf) => CpuTensorBuf::Q4K(buf.into_owned()),
+            CpuTensorBuf::Q5K(buf) => CpuTensorBuf::Q5K(buf.into_owned()),
+            CpuTensorBuf::Q6K(buf) => CpuTensorBuf::Q6K(buf.into_owned()),
+            CpuTensorBuf::Q8K(buf) => CpuTensorBuf::Q8K(buf.into_owned()),
+            CpuTensorBuf::IQ4NL(buf) => CpuTensorBuf::IQ4NL(buf.into_owned()),
+            CpuTensorBuf::IQ2XXS(buf) => CpuTensorBuf::IQ2XXS(buf.into_owned()),
+            CpuTensorBuf::IQ2XS(buf) => CpuTensorBuf::IQ2XS(buf.into_owned()),
+            CpuTensorBuf::IQ3XXS(buf) => CpuTensorBuf::IQ3XXS(buf.into_owned()),
+        }
+    }
+
     /// the quantized tensor can not be iterated directly. to iterate the quantized tensor,
     /// use `dequantize` to convert it to f32/f16 tensor first.
     pub fn iter_f32(&self) -> impl Iterator<Item = f32> + '_ {
@@ -156,11 +513,39 @@ impl<'a> CpuTensorBuf<'a> {
     }
 }
 
+fn quantize_blocks_q8_0(iter: impl Iterator<Item = f32>) -> Vec<BlockQ8_0> {
+    QuantBufQ8_0::quantize(&iter.collect::<Vec<_>>())
+        .blocks
+        .into_owned()
+}
+
+fn quantize_blocks_q4_0(iter: impl Iterator<Item = f32>) -> Vec<BlockQ4_0> {
+    QuantBufQ4_0::quantize(&iter.collect::<Vec<_>>())
+        .blocks
+        .into_owned()
+}
+
 impl Clone for CpuTensorBuf<'_> {
     fn clone(&self) -> Self {
         match self {
             CpuTensorBuf::F32(buf) => Self::F32(buf.clone()),
+            CpuTensorBuf::F16(buf) => Self::F16(buf.clone()),
             CpuTensorBuf::Q8_0(buf) => Self::Q8_0(buf.clone()),
+            CpuTensorBuf::Q4_0(buf) => Self::Q4_0(buf.clone()),
+            CpuTensorBuf::Q4_1(buf) => Self::Q4_1(buf.clone()),
+            CpuTensorBuf::Q5_0(buf) => Self::Q5_0(buf.clone()),
+            CpuTensorBuf::Q5_1(buf) => Self::Q5_1(buf.clone()),
+            CpuTensorBuf::Q8_1(buf) => Self::Q8_1(buf.clone()),
+            CpuTensorBuf::Q2K(buf) => Self::Q2K(buf.clone()),
+            CpuTensorBuf::Q3K(buf) => Self::Q3K(buf.clone()),
+            CpuTensorBuf::Q4K(buf) => Self::Q4K(buf.clone()),
+            CpuTensorBuf::Q5K(buf) => Self::Q5K(buf.clone()),
+            CpuTensorBuf::Q6K(buf) => Self::Q6K(buf.clone()),
+            CpuTensorBuf::Q8K(buf) => Self::Q8K(buf.clone()),
+            CpuTensorBuf::IQ4NL(buf) => Self::IQ4NL(buf.clone()),
+            CpuTensorBuf::IQ2XXS(buf) => Self::IQ2XXS(buf.clone()),
+            CpuTensorBuf::IQ2XS(buf) => Self::IQ2XS(buf.clone()),
+            CpuTensorBuf::IQ3XXS(buf) => Self::IQ3XXS(buf.clone()),
         }
     }
 }
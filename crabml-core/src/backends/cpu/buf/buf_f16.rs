@@ -0,0 +1,80 @@
+use std::borrow::Cow;
+use std::slice;
+
+use half::f16;
+
+/// the f16 weights are kept as is without upconverting to f32, which halves the memory of
+/// the fp16 models. the data must be aligned to 2 bytes, which is always true on the tensors
+/// of GGUF files.
+pub fn f16_buf_from_bytes(buf: &[u8]) -> Cow<'_, [f16]> {
+    let len = buf.len();
+    assert_eq!(
+        len % std::mem::size_of::<f16>(),
+        0,
+        "Length of slice must be multiple of f16 size"
+    );
+    assert_eq!(
+        buf.as_ptr() as usize % std::mem::align_of::<f16>(),
+        0,
+        "data must be aligned to f16"
+    );
+    let new_len = len / std::mem::size_of::<f16>();
+    let ptr = buf.as_ptr() as *const f16;
+    let f16_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
+    f16_buf.into()
+}
+
+pub fn quantize_f32_f16<'a>(buf: &[f32]) -> Cow<'a, [f16]> {
+    buf.iter()
+        .map(|v| f16::from_f32(*v))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+pub fn dequantize_f16_f32<'a>(buf: &[f16]) -> Cow<'a, [f32]> {
+    buf.iter().map(|v| v.to_f32()).collect::<Vec<_>>().into()
+}
+
+/// the products are accumulated in f32 to avoid the overflow and the precision loss of f16.
+pub fn vec_dot_f16_f16(a: &[f16], a_offset: usize, b: &[f16], b_offset: usize, len: usize) -> f32 {
+    let ac = &a[a_offset..a_offset + len];
+    let bc = &b[b_offset..b_offset + len];
+
+    // accumulate in 8 lanes to let the compiler vectorize the loop
+    let mut sums = [0.0_f32; 8];
+    let mut ac_chunks = ac.chunks_exact(8);
+    let mut bc_chunks = bc.chunks_exact(8);
+    for (ca, cb) in (&mut ac_chunks).zip(&mut bc_chunks) {
+        for i in 0..8 {
+            sums[i] += ca[i].to_f32() * cb[i].to_f32();
+        }
+    }
+    let mut sum = sums.iter().sum::<f32>();
+    for (va, vb) in ac_chunks.remainder().iter().zip(bc_chunks.remainder()) {
+        sum += va.to_f32() * vb.to_f32();
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vec_dot_f16_f16() {
+        let a = (0..70).map(|i| (i as f32 - 35.0) / 8.0).collect::<Vec<_>>();
+        let b = (0..70).map(|i| (i % 9) as f32 / 4.0).collect::<Vec<_>>();
+        let ha = quantize_f32_f16(&a);
+        let hb = quantize_f32_f16(&b);
+        assert_eq!(dequantize_f16_f32(&ha).as_ref(), &a[..]);
+
+        let bytes = ha.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        assert_eq!(f16_buf_from_bytes(&bytes).as_ref(), ha.as_ref());
+
+        for (offset, len) in [(0, 70), (3, 64), (60, 10)] {
+            let want = (offset..offset + len).map(|i| a[i] * b[i]).sum::<f32>();
+            let got = vec_dot_f16_f16(&ha, offset, &hb, offset, len);
+            assert!((got - want).abs() < 1e-4, "got {} want {}", got, want);
+        }
+    }
+}
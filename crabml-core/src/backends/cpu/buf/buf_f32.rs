@@ -3,6 +3,9 @@ use std::slice;
 
 use half::f16;
 
+#[cfg(target_arch = "x86_64")]
+use crate::backends::cpu::arch::X86Simd;
+
 pub fn f32_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f32]> {
     let len = buf.len();
     assert_eq!(
@@ -19,6 +22,14 @@ pub fn f32_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f32]> {
 pub fn vec_dot_f32_f32(a: &[f32], a_offset: usize, b: &[f32], b_offset: usize, len: usize) -> f32 {
     let ac = &a[a_offset..a_offset + len];
     let bc = &b[b_offset..b_offset + len];
+
+    #[cfg(target_arch = "x86_64")]
+    match X86Simd::detect() {
+        X86Simd::Avx512 => return unsafe { impl_x86_64::vec_dot_f32_f32_avx512(ac, bc) },
+        X86Simd::Avx2 => return unsafe { impl_x86_64::vec_dot_f32_f32_avx2(ac, bc) },
+        X86Simd::Fallback => {}
+    }
+
     let mut sum = 0.0;
     for i in 0..len {
         sum += ac[i] * bc[i];
@@ -26,6 +37,50 @@ pub fn vec_dot_f32_f32(a: &[f32], a_offset: usize, b: &[f32], b_offset: usize, l
     sum
 }
 
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64 {
+    use std::arch::x86_64::*;
+
+    use super::super::buf_q8_0::impl_x86_64_avx2::hsum_float_8;
+
+    /// accumulate in 4 vectors to hide the latency of fma.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn vec_dot_f32_f32_avx2(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len() - a.len() % 32;
+        let mut acc = [_mm256_setzero_ps(); 4];
+        for i in (0..n).step_by(32) {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let av = _mm256_loadu_ps(a.as_ptr().add(i + j * 8));
+                let bv = _mm256_loadu_ps(b.as_ptr().add(i + j * 8));
+                *acc = _mm256_fmadd_ps(av, bv, *acc);
+            }
+        }
+        let acc = _mm256_add_ps(_mm256_add_ps(acc[0], acc[1]), _mm256_add_ps(acc[2], acc[3]));
+
+        let mut sum = hsum_float_8(acc);
+        for i in n..a.len() {
+            sum += a[i] * b[i];
+        }
+        sum
+    }
+
+    #[target_feature(enable = "avx512f,avx512bw,avx2,fma")]
+    pub unsafe fn vec_dot_f32_f32_avx512(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len() - a.len() % 64;
+        let mut acc = [_mm512_setzero_ps(); 4];
+        for i in (0..n).step_by(64) {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                let av = _mm512_loadu_ps(a.as_ptr().add(i + j * 16));
+                let bv = _mm512_loadu_ps(b.as_ptr().add(i + j * 16));
+                *acc = _mm512_fmadd_ps(av, bv, *acc);
+            }
+        }
+        let acc = _mm512_add_ps(_mm512_add_ps(acc[0], acc[1]), _mm512_add_ps(acc[2], acc[3]));
+
+        _mm512_reduce_add_ps(acc) + vec_dot_f32_f32_avx2(&a[n..], &b[n..])
+    }
+}
+
 pub fn exp_f32_cached(x: f32, cache: &[f16]) -> f32 {
     let cache_ptr = cache.as_ptr();
     let x16 = f16::from_f32(x);
@@ -33,3 +88,30 @@ pub fn exp_f32_cached(x: f32, cache: &[f16]) -> f32 {
 
     unsafe { (*cache_ptr.add(x16n as usize)).to_f32() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vec_dot_f32_f32() {
+        let a = (0..200)
+            .map(|i| (i as f32 - 100.0) / 16.0)
+            .collect::<Vec<_>>();
+        let b = (0..200).map(|i| (i % 13) as f32 / 4.0).collect::<Vec<_>>();
+
+        // cover the tails which are not taken by the simd vectors
+        for (offset, len) in [(0, 200), (3, 128), (7, 71), (190, 10)] {
+            let want = (offset..offset + len).map(|i| a[i] * b[i]).sum::<f32>();
+            let got = vec_dot_f32_f32(&a, offset, &b, offset, len);
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if X86Simd::detect() >= X86Simd::Avx2 {
+            let want = vec_dot_f32_f32(&a, 7, &b, 7, 71);
+            let got = unsafe { impl_x86_64::vec_dot_f32_f32_avx2(&a[7..78], &b[7..78]) };
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+    }
+}
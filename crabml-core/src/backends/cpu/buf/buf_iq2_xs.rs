@@ -0,0 +1,120 @@
+use half::f16;
+
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::iq_grids::IQ2XS_GRID;
+use super::iq_grids::KSIGNS_IQ2XS;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// IQ2_XS quantizes every 8 elements as an u16: 9 bits of index into `IQ2XS_GRID` and 7 bits
+/// of signs. every 16 elements have a 4-bit scale: `x = d * (2 * scale + 1) / 8 * grid * sign`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockIQ2XS {
+    pub d: f16,                  // super-block scale
+    pub qs: [u16; QK_K / 8],     // grid indices and signs
+    pub scales: [u8; QK_K / 32], // scales of the blocks of 16 elements, in nibbles
+}
+
+impl BlockIQ2XS {
+    /// unpack the signed grid values and the scale `2 * scale + 1` of every 16 elements.
+    fn quants(&self) -> ([i8; QK_K], [u8; QK_K / 16]) {
+        let mut qs = [0_i8; QK_K];
+        let raw = self.qs;
+        for (l, q) in raw.iter().enumerate() {
+            let grid = IQ2XS_GRID[(q & 511) as usize].to_le_bytes();
+            let signs = KSIGNS_IQ2XS[(q >> 9) as usize];
+            for j in 0..8 {
+                let q = grid[j] as i8;
+                qs[l * 8 + j] = if signs & (1 << j) != 0 { -q } else { q };
+            }
+        }
+        let mut scales = [0_u8; QK_K / 16];
+        for (ib, sc) in self.scales.iter().enumerate() {
+            scales[ib * 2] = 2 * (sc & 0xF) + 1;
+            scales[ib * 2 + 1] = 2 * (sc >> 4) + 1;
+        }
+        (qs, scales)
+    }
+}
+
+impl BlockQuant for BlockIQ2XS {
+    const DTYPE: GGMLType = GGMLType::IQ2XS;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let (qs, scales) = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = d * scales[i / 16] as f32 * 0.125 * qs[i] as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_iq2_xs_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufIQ2XS<'a> = QuantBuf<'a, BlockIQ2XS>;
+
+pub fn vec_dot_iq2_xs_q8_k(abs: &[BlockIQ2XS], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let (qs, scales) = a.quants();
+        let mut sumi = 0;
+        for (ib, sc) in scales.iter().enumerate() {
+            let range = ib * 16..(ib + 1) * 16;
+            sumi += *sc as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+        }
+        sumf += a.d.to_f32() * b.d * sumi as f32;
+    }
+    0.125 * sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+    use crate::backends::cpu::buf::buf_q8_k::tests::reference_q8_k_blocks;
+    use crate::backends::cpu::buf::QuantBufQ8K;
+
+    #[test]
+    fn test_iq2_xs_block() {
+        // the reference values are computed by dequantize_row_iq2_xs and
+        // ggml_vec_dot_iq2_xs_q8_K of ggml on the same blocks
+        let mut buf = pseudo_random_bytes(2 * 74);
+        for blk in buf.chunks_mut(74) {
+            blk[0..2].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+        }
+
+        let bf = QuantBufIQ2XS::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        assert_eq!(bf.as_bytes(), &buf);
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        for (i, want) in [
+            (0, 35.9375),
+            (1, -61.8125),
+            (37, 14.0625),
+            (100, -40.3125),
+            (255, -7.8125),
+            (300, -9.5),
+            (511, 23.4375),
+        ] {
+            assert_eq!(values[i], want, "element {}", i);
+        }
+
+        let qb = QuantBufQ8K::from_blocks(reference_q8_k_blocks(2));
+        let got = bf.vec_dot(0, &qb, 0, 512);
+        assert!((got - -8501.641).abs() < 1e-2, "got {}", got);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+}
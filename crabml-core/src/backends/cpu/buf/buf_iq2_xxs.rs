@@ -0,0 +1,121 @@
+use half::f16;
+
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::iq_grids::IQ2XXS_GRID;
+use super::iq_grids::KSIGNS_IQ2XS;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// IQ2_XXS splits a super block of 256 elements into 8 blocks of 32 elements. each block
+/// takes 2 u32: the first one keeps 4 indices of 8 bits into `IQ2XXS_GRID`, the second one
+/// keeps 4 signs of 7 bits and a 4-bit scale: `x = d * (2 * scale + 1) / 8 * grid * sign`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockIQ2XXS {
+    pub d: f16,              // super-block scale
+    pub qs: [u16; QK_K / 8], // grid indices, signs and scales
+}
+
+impl BlockIQ2XXS {
+    /// unpack the signed grid values and the scale `2 * scale + 1` of every 32 elements.
+    fn quants(&self) -> ([i8; QK_K], [u8; QK_K / 32]) {
+        let mut qs = [0_i8; QK_K];
+        let mut scales = [0_u8; QK_K / 32];
+        let raw = self.qs;
+        for (ib, chunk) in raw.chunks(4).enumerate() {
+            let aux0 = chunk[0] as u32 | (chunk[1] as u32) << 16;
+            let aux1 = chunk[2] as u32 | (chunk[3] as u32) << 16;
+            scales[ib] = 2 * (aux1 >> 28) as u8 + 1;
+            for l in 0..4 {
+                let grid = IQ2XXS_GRID[(aux0 >> (8 * l) & 0xFF) as usize].to_le_bytes();
+                let signs = KSIGNS_IQ2XS[(aux1 >> (7 * l) & 127) as usize];
+                for j in 0..8 {
+                    let q = grid[j] as i8;
+                    qs[ib * 32 + l * 8 + j] = if signs & (1 << j) != 0 { -q } else { q };
+                }
+            }
+        }
+        (qs, scales)
+    }
+}
+
+impl BlockQuant for BlockIQ2XXS {
+    const DTYPE: GGMLType = GGMLType::IQ2XXS;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let (qs, scales) = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = d * scales[i / 32] as f32 * 0.125 * qs[i] as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_iq2_xxs_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufIQ2XXS<'a> = QuantBuf<'a, BlockIQ2XXS>;
+
+pub fn vec_dot_iq2_xxs_q8_k(abs: &[BlockIQ2XXS], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let (qs, scales) = a.quants();
+        let mut sumi = 0;
+        for (ib, sc) in scales.iter().enumerate() {
+            let range = ib * 32..(ib + 1) * 32;
+            sumi += *sc as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+        }
+        sumf += a.d.to_f32() * b.d * sumi as f32;
+    }
+    0.125 * sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+    use crate::backends::cpu::buf::buf_q8_k::tests::reference_q8_k_blocks;
+    use crate::backends::cpu::buf::QuantBufQ8K;
+
+    #[test]
+    fn test_iq2_xxs_block() {
+        // the reference values are computed by dequantize_row_iq2_xxs and
+        // ggml_vec_dot_iq2_xxs_q8_K of ggml on the same blocks
+        let mut buf = pseudo_random_bytes(2 * 66);
+        for blk in buf.chunks_mut(66) {
+            blk[0..2].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+        }
+
+        let bf = QuantBufIQ2XXS::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        assert_eq!(bf.as_bytes(), &buf);
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        for (i, want) in [
+            (0, -8.5),
+            (1, 8.5),
+            (37, -0.5),
+            (100, -14.5),
+            (255, 14.5),
+            (300, 11.5),
+            (511, 0.5),
+        ] {
+            assert_eq!(values[i], want, "element {}", i);
+        }
+
+        let qb = QuantBufQ8K::from_blocks(reference_q8_k_blocks(2));
+        let got = bf.vec_dot(0, &qb, 0, 512);
+        assert!((got - -12399.984).abs() < 1e-2, "got {}", got);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+}
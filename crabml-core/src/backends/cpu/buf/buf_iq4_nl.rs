@@ -0,0 +1,121 @@
+use half::f16;
+
+use super::buf_q8_0::BlockQ8_0;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// the non-linear codebook of IQ4_NL, which is fitted on the weights distribution to
+/// keep more precision around zero than the linear Q4_0.
+pub const KVALUES_IQ4NL: [i8; 16] = [
+    -127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113,
+];
+
+/// IQ4_NL has the same layout as Q4_0, but each nibble is an index into `KVALUES_IQ4NL`
+/// instead of a linear value: `x = d * KVALUES_IQ4NL[q]`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockIQ4NL {
+    pub d: f16,       // delta
+    pub qs: [u8; 16], // nibbles / indices of the codebook
+}
+
+impl BlockIQ4NL {
+    fn quants(&self) -> [i8; 32] {
+        let mut qs = [0_i8; 32];
+        for (j, q) in self.qs.iter().enumerate() {
+            qs[j] = KVALUES_IQ4NL[(q & 0x0F) as usize];
+            qs[j + 16] = KVALUES_IQ4NL[(q >> 4) as usize];
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockIQ4NL {
+    const DTYPE: GGMLType = GGMLType::IQ4NL;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_0;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (v, q) in buf.iter_mut().zip(self.quants()) {
+            *v = q as f32 * d;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_iq4_nl_q8_0(abs, bbs)
+    }
+}
+
+pub type QuantBufIQ4NL<'a> = QuantBuf<'a, BlockIQ4NL>;
+
+pub fn vec_dot_iq4_nl_q8_0(abs: &[BlockIQ4NL], bbs: &[BlockQ8_0]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf: f32 = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let sumi = a
+            .quants()
+            .iter()
+            .zip(b.qs.iter())
+            .map(|(x, y)| *x as i32 * *y as i32)
+            .sum::<i32>();
+        sumf += sumi as f32 * a.d.to_f32() * b.d.to_f32();
+    }
+
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::QuantBufQ8_0;
+
+    #[test]
+    fn test_iq4_nl_block() {
+        let mut buf: [u8; 36] = [0x88; 36];
+        let d = f16::from_f32(0.5).to_le_bytes();
+        buf[0..2].copy_from_slice(&d);
+        buf[18..20].copy_from_slice(&d);
+        buf[2] = 0x0F; // elements 0 and 16
+        buf[35] = 0xA3; // elements 47 and 63
+
+        let bf = QuantBufIQ4NL::from_bytes(&buf);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.as_bytes(), &buf);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 64);
+        assert_eq!(values[0], 0.5 * 113.0);
+        assert_eq!(values[1], 0.5);
+        assert_eq!(values[16], 0.5 * -127.0);
+        assert_eq!(values[47], 0.5 * -65.0);
+        assert_eq!(values[63], 0.5 * 25.0);
+        assert_eq!(bf.dequantize(32).count(), 32);
+    }
+
+    #[test]
+    fn test_vec_dot_iq4_nl_q8_0() {
+        let bytes = (0..4 * 18)
+            .map(|i| match i % 18 {
+                0 => 0x00,
+                1 => 0x20, // d = 2^-7
+                _ => (i * 37 % 256) as u8,
+            })
+            .collect::<Vec<_>>();
+        let qa = QuantBufIQ4NL::from_bytes(&bytes);
+        let b = (0..128)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+        let qb = QuantBufQ8_0::quantize(&b);
+
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        for (offset, len) in [(0, 128), (32, 64), (96, 32)] {
+            let want = (offset..offset + len).map(|i| da[i] * db[i]).sum::<f32>();
+            let got = qa.vec_dot(offset, &qb, offset, len);
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use half::f16;
+
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q2_K splits a super block of 256 elements into 16 blocks of 16 elements, each element
+/// is quantized with 2 bits. the scale and min of each block are quantized with 4 bits,
+/// and scaled by `d` and `dmin`: `x = d * scale * q - dmin * min`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ2K {
+    pub scales: [u8; QK_K / 16], // scales and mins, quantized with 4 bits
+    pub qs: [u8; QK_K / 4],      // quants
+    pub d: f16,                  // super-block scale for quantized scales
+    pub dmin: f16,               // super-block scale for quantized mins
+}
+
+impl BlockQ2K {
+    /// unpack the 2-bit quants. the quants are stored in 2 chunks of 32 bytes, each byte
+    /// keeps 4 quants with the distance of 32 elements.
+    fn quants(&self) -> [i8; QK_K] {
+        let mut qs = [0_i8; QK_K];
+        for (n, q) in self.qs.chunks(32).enumerate() {
+            for (j, shift) in [0, 2, 4, 6].into_iter().enumerate() {
+                for l in 0..32 {
+                    qs[n * 128 + j * 32 + l] = ((q[l] >> shift) & 3) as i8;
+                }
+            }
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ2K {
+    const DTYPE: GGMLType = GGMLType::Q2K;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let dmin = self.dmin.to_f32();
+        let qs = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            let sc = self.scales[i / 16];
+            *v = d * (sc & 0xF) as f32 * qs[i] as f32 - dmin * (sc >> 4) as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q2_k_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufQ2K<'a> = QuantBuf<'a, BlockQ2K>;
+
+pub fn vec_dot_q2_k_q8_k(abs: &[BlockQ2K], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let qs = a.quants();
+
+        let mut sumi = 0;
+        let mut summs = 0;
+        for j in 0..QK_K / 16 {
+            let sc = a.scales[j];
+            let range = j * 16..(j + 1) * 16;
+            sumi += (sc & 0xF) as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+            summs += (sc >> 4) as i32 * b.bsums[j] as i32;
+        }
+
+        sumf += b.d * (a.d.to_f32() * sumi as f32 - a.dmin.to_f32() * summs as f32);
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+
+    #[test]
+    fn test_q2_k_block() {
+        let mut buf = pseudo_random_bytes(2 * 84);
+        for blk in buf.chunks_mut(84) {
+            blk[80..82].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+            blk[82..84].copy_from_slice(&f16::from_f32(0.25).to_le_bytes());
+        }
+        buf[0] = 0x23; // scale 3, min 2 of the elements 0..16
+        buf[2] = 0x14; // scale 4, min 1 of the elements 32..48
+        buf[4] = 0x05; // scale 5, min 0 of the elements 64..80
+        buf[16] = 0xE4; // the quants 0, 1, 2, 3 of the elements 0, 32, 64, 96
+
+        let bf = QuantBufQ2K::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        assert_eq!(values[0], -0.5);
+        assert_eq!(values[32], 1.75);
+        assert_eq!(values[64], 5.0);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+}
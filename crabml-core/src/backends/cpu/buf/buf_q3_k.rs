@@ -0,0 +1,137 @@
+use half::f16;
+
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q3_K splits a super block of 256 elements into 16 blocks of 16 elements, each element
+/// is quantized with 3 bits: the low 2 bits are kept in `qs`, and the high bit is kept in
+/// `hmask`. the scales of the blocks are quantized with 6 bits: `x = d * (scale - 32) * q`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ3K {
+    pub hmask: [u8; QK_K / 8], // quants, high bit
+    pub qs: [u8; QK_K / 4],    // quants, low 2 bits
+    pub scales: [u8; 12],      // scales, quantized with 6 bits
+    pub d: f16,                // super-block scale
+}
+
+impl BlockQ3K {
+    /// unpack the 16 scales in 6 bits. the low 4 bits of the scales are kept in the first
+    /// 8 bytes, and the high 2 bits are kept in the last 4 bytes.
+    fn scales(&self) -> [i8; 16] {
+        const KMASK1: u32 = 0x03030303;
+        const KMASK2: u32 = 0x0f0f0f0f;
+
+        let mut aux = [0_u32; 4];
+        for (i, v) in aux.iter_mut().take(3).enumerate() {
+            *v = u32::from_le_bytes(self.scales[i * 4..(i + 1) * 4].try_into().unwrap());
+        }
+        let tmp = aux[2];
+        aux[2] = ((aux[0] >> 4) & KMASK2) | (((tmp >> 4) & KMASK1) << 4);
+        aux[3] = ((aux[1] >> 4) & KMASK2) | (((tmp >> 6) & KMASK1) << 4);
+        aux[0] = (aux[0] & KMASK2) | ((tmp & KMASK1) << 4);
+        aux[1] = (aux[1] & KMASK2) | (((tmp >> 2) & KMASK1) << 4);
+
+        let mut scales = [0_i8; 16];
+        for (i, v) in aux.iter().enumerate() {
+            for (j, b) in v.to_le_bytes().into_iter().enumerate() {
+                scales[i * 4 + j] = b as i8 - 32;
+            }
+        }
+        scales
+    }
+
+    /// unpack the 3-bit quants into [-4, 3]. the quants are stored in the same way as Q2_K,
+    /// the high bit of the element `i` is kept in the bit `i / 32` of `hmask[i % 32]`.
+    fn quants(&self) -> [i8; QK_K] {
+        let mut qs = [0_i8; QK_K];
+        for (n, q) in self.qs.chunks(32).enumerate() {
+            for (j, shift) in [0, 2, 4, 6].into_iter().enumerate() {
+                let m = 1 << (n * 4 + j);
+                for l in 0..32 {
+                    let h = if self.hmask[l] & m != 0 { 0 } else { 4 };
+                    qs[n * 128 + j * 32 + l] = ((q[l] >> shift) & 3) as i8 - h;
+                }
+            }
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ3K {
+    const DTYPE: GGMLType = GGMLType::Q3K;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let scales = self.scales();
+        let qs = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = d * scales[i / 16] as f32 * qs[i] as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q3_k_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufQ3K<'a> = QuantBuf<'a, BlockQ3K>;
+
+pub fn vec_dot_q3_k_q8_k(abs: &[BlockQ3K], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let scales = a.scales();
+        let qs = a.quants();
+
+        let mut sumi = 0;
+        for (j, sc) in scales.iter().enumerate() {
+            let range = j * 16..(j + 1) * 16;
+            sumi += *sc as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+        }
+
+        sumf += a.d.to_f32() * b.d * sumi as f32;
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+
+    #[test]
+    fn test_q3_k_block() {
+        let mut buf = pseudo_random_bytes(2 * 110);
+        for blk in buf.chunks_mut(110) {
+            blk[108..110].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+        }
+        buf[0] = 0x01; // the high bit of the element 0
+        buf[32] = 0xE4; // the low bits 0, 1, 2, 3 of the elements 0, 32, 64, 96
+        buf[96] = 0x05; // the low 4 bits of the scales 0 and 8
+        buf[104] = 0x02; // the high 2 bits of the scales 0, 4, 8, 12
+
+        let bf = QuantBufQ3K::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        assert_eq!(bf.blocks[0].scales()[0], 5);
+        assert_eq!(bf.blocks[0].scales()[8], -32);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        assert_eq!(values[0], 0.0);
+        assert_eq!(values[1], 0.5 * 5.0 * bf.blocks[0].quants()[1] as f32);
+        assert_eq!(bf.blocks[0].quants()[0], 0);
+        assert_eq!(bf.blocks[0].quants()[32], 1 - 4);
+        assert_eq!(bf.blocks[0].quants()[96], 3 - 4);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+}
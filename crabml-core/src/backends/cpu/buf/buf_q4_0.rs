@@ -0,0 +1,479 @@
+use half::f16;
+
+use super::buf_q8_0::BlockQ8_0;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q4_0 stores 32 elements in 16 bytes, the low nibbles keep the first 16 elements
+/// and the high nibbles keep the last 16 elements. each element is stored as an unsigned
+/// 4-bit value with an offset of 8.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ4_0 {
+    pub d: f16,       // delta
+    pub qs: [u8; 16], // nibbles / quants
+}
+
+impl BlockQuant for BlockQ4_0 {
+    const DTYPE: GGMLType = GGMLType::Q4_0;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_0;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (i, q) in self.qs.iter().enumerate() {
+            buf[i] = ((q & 0x0F) as i32 - 8) as f32 * d;
+            buf[i + 16] = ((q >> 4) as i32 - 8) as f32 * d;
+        }
+    }
+
+    /// the activation is quantized into Q8_0 on matmul, just like ggml does, so the dot
+    /// product is always taken between Q4_0 blocks and Q8_0 blocks.
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q4_0_q8_0(abs, bbs)
+    }
+}
+
+pub type QuantBufQ4_0<'a> = QuantBuf<'a, BlockQ4_0>;
+
+impl QuantBufQ4_0<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        Self::from_blocks(quantize_f32_q4_0(data))
+    }
+}
+
+pub fn quantize_f32_q4_0(data: &[f32]) -> Vec<BlockQ4_0> {
+    let mut bs = Vec::with_capacity(data.len() / 32);
+
+    for chunk in data.chunks(32) {
+        // find the value with the max absolute value, the sign is kept so that the
+        // extreme value is mapped to -8 exactly
+        let mut max_abs_value = 0.0;
+        let mut max_value = 0.0;
+        for &value in chunk {
+            if value.abs() > max_abs_value {
+                max_abs_value = value.abs();
+                max_value = value;
+            }
+        }
+
+        let d = max_value / -8.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+
+        let mut qs = [0_u8; 16];
+        for (i, q) in qs.iter_mut().enumerate() {
+            let x0 = chunk[i] * id;
+            let x1 = chunk[i + 16] * id;
+            let xi0 = ((x0 + 8.5) as u8).min(15);
+            let xi1 = ((x1 + 8.5) as u8).min(15);
+            *q = xi0 | (xi1 << 4);
+        }
+
+        bs.push(BlockQ4_0 {
+            d: f16::from_f32(d),
+            qs,
+        });
+    }
+
+    bs
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+mod impl_aarch64_neon {
+    use std::arch::aarch64;
+
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+
+    pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
+
+        unsafe {
+            let mut sumv0 = aarch64::vdupq_n_f32(0.0);
+            let m4b = aarch64::vdupq_n_u8(0x0F);
+            let s8b = aarch64::vdupq_n_s8(0x8);
+
+            for i in 0..bbs.len() {
+                let ab0 = abs.get_unchecked(i);
+                let bb0 = bbs.get_unchecked(i);
+
+                let av0 = aarch64::vld1q_u8(ab0.qs.as_ptr());
+
+                // 4-bit -> 8-bit, and subtract the offset
+                let av0l = aarch64::vsubq_s8(
+                    aarch64::vreinterpretq_s8_u8(aarch64::vandq_u8(av0, m4b)),
+                    s8b,
+                );
+                let av0h = aarch64::vsubq_s8(
+                    aarch64::vreinterpretq_s8_u8(aarch64::vshrq_n_u8(av0, 4)),
+                    s8b,
+                );
+
+                let bv0l = aarch64::vld1q_s8(bb0.qs.as_ptr());
+                let bv0h = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
+
+                let pl0 = aarch64::vmull_s8(aarch64::vget_low_s8(av0l), aarch64::vget_low_s8(bv0l));
+                let pl1 = aarch64::vmull_high_s8(av0l, bv0l);
+                let ph0 = aarch64::vmull_s8(aarch64::vget_low_s8(av0h), aarch64::vget_low_s8(bv0h));
+                let ph1 = aarch64::vmull_high_s8(av0h, bv0h);
+
+                let mut p = aarch64::vpaddlq_s16(pl0);
+                p = aarch64::vpadalq_s16(p, pl1);
+                p = aarch64::vpadalq_s16(p, ph0);
+                p = aarch64::vpadalq_s16(p, ph1);
+
+                sumv0 = aarch64::vmlaq_n_f32(
+                    sumv0,
+                    aarch64::vcvtq_f32_s32(p),
+                    ab0.d.to_f32() * bb0.d.to_f32(),
+                );
+            }
+
+            aarch64::vaddvq_f32(sumv0)
+        }
+    }
+}
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+mod impl_aarch64_dotprod {
+    use std::arch::aarch64;
+
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+
+    /// the same as the neon kernel, but the products are summed by sdot in one instruction.
+    #[target_feature(enable = "neon,dotprod")]
+    pub unsafe fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        let mut sumv0 = aarch64::vdupq_n_f32(0.0);
+        let zerov = aarch64::vdupq_n_s32(0);
+        let m4b = aarch64::vdupq_n_u8(0x0F);
+        let s8b = aarch64::vdupq_n_s8(0x8);
+
+        for i in 0..bbs.len() {
+            let ab0 = abs.get_unchecked(i);
+            let bb0 = bbs.get_unchecked(i);
+
+            let av0 = aarch64::vld1q_u8(ab0.qs.as_ptr());
+
+            // 4-bit -> 8-bit, and subtract the offset
+            let av0l = aarch64::vsubq_s8(
+                aarch64::vreinterpretq_s8_u8(aarch64::vandq_u8(av0, m4b)),
+                s8b,
+            );
+            let av0h = aarch64::vsubq_s8(
+                aarch64::vreinterpretq_s8_u8(aarch64::vshrq_n_u8(av0, 4)),
+                s8b,
+            );
+
+            let bv0l = aarch64::vld1q_s8(bb0.qs.as_ptr());
+            let bv0h = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
+
+            let p = aarch64::vaddq_s32(
+                aarch64::vdotq_s32(zerov, av0l, bv0l),
+                aarch64::vdotq_s32(zerov, av0h, bv0h),
+            );
+
+            sumv0 = aarch64::vmlaq_n_f32(
+                sumv0,
+                aarch64::vcvtq_f32_s32(p),
+                ab0.d.to_f32() * bb0.d.to_f32(),
+            );
+        }
+
+        aarch64::vaddvq_f32(sumv0)
+    }
+}
+
+/// pick the kernel by the simd instructions of the cpu on the runtime.
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+mod impl_aarch64 {
+    use super::impl_aarch64_dotprod;
+    use super::impl_aarch64_neon;
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+    use crate::backends::cpu::arch::ArmSimd;
+
+    pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
+
+        match ArmSimd::detect() {
+            ArmSimd::Dotprod => unsafe { impl_aarch64_dotprod::vec_dot_q4_0_q8_0(abs, bbs) },
+            ArmSimd::Neon => impl_aarch64_neon::vec_dot_q4_0_q8_0(abs, bbs),
+        }
+    }
+}
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+use impl_aarch64::*;
+
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64_avx2 {
+    use std::arch::x86_64::*;
+
+    use super::super::buf_q8_0::impl_x86_64_avx2::hsum_float_8;
+    use super::super::buf_q8_0::impl_x86_64_avx2::mul_sum_i8_pairs_float;
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        let mut acc = _mm256_setzero_ps();
+
+        for (abs, bbs) in abs.iter().zip(bbs) {
+            let d = _mm256_set1_ps(abs.d.to_f32() * bbs.d.to_f32());
+
+            // 4-bit -> 8-bit, and convert the range from [0, 15] to [-8, 7]
+            let qa = bytes_from_nibbles_32(abs.qs.as_ptr());
+            let qa = _mm256_sub_epi8(qa, _mm256_set1_epi8(8));
+            let qb = _mm256_loadu_si256(bbs.qs.as_ptr() as *const __m256i);
+
+            let q = mul_sum_i8_pairs_float(qa, qb);
+
+            acc = _mm256_fmadd_ps(d, q, acc);
+        }
+
+        hsum_float_8(acc)
+    }
+
+    /// unpack 32 4-bit fields into 32 bytes, the low nibbles are placed in the first
+    /// 16 bytes, and the high nibbles are placed in the last 16 bytes.
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn bytes_from_nibbles_32(rsi: *const u8) -> __m256i {
+        let tmp = _mm_loadu_si128(rsi as *const __m128i);
+        let bytes = _mm256_set_m128i(_mm_srli_epi16(tmp, 4), tmp);
+        let low_mask = _mm256_set1_epi8(0x0F);
+        _mm256_and_si256(low_mask, bytes)
+    }
+}
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64_avx512 {
+    use std::arch::x86_64::*;
+
+    use super::super::buf_q8_0::impl_x86_64_avx512::load_2_blocks;
+    use super::super::buf_q8_0::impl_x86_64_avx512::mul_sum_i8_pairs_float;
+    use super::impl_x86_64_avx2;
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+
+    /// take 2 blocks in a vector like q8_0, the odd block at the end is taken by avx2.
+    #[target_feature(enable = "avx512f,avx512bw,avx2,fma")]
+    pub unsafe fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        let mut acc = _mm512_setzero_ps();
+
+        let n = abs.len() - abs.len() % 2;
+        for i in (0..n).step_by(2) {
+            let (ab0, ab1) = (abs.get_unchecked(i), abs.get_unchecked(i + 1));
+            let (bb0, bb1) = (bbs.get_unchecked(i), bbs.get_unchecked(i + 1));
+            let d = _mm512_mask_blend_ps(
+                0xFF00,
+                _mm512_set1_ps(ab0.d.to_f32() * bb0.d.to_f32()),
+                _mm512_set1_ps(ab1.d.to_f32() * bb1.d.to_f32()),
+            );
+
+            // 4-bit -> 8-bit, and convert the range from [0, 15] to [-8, 7]
+            let qa0 = impl_x86_64_avx2::bytes_from_nibbles_32(ab0.qs.as_ptr());
+            let qa1 = impl_x86_64_avx2::bytes_from_nibbles_32(ab1.qs.as_ptr());
+            let qa = _mm512_inserti64x4(_mm512_castsi256_si512(qa0), qa1, 1);
+            let qa = _mm512_sub_epi8(qa, _mm512_set1_epi8(8));
+            let qb = load_2_blocks(bb0.qs.as_ptr(), bb1.qs.as_ptr());
+
+            let q = mul_sum_i8_pairs_float(qa, qb);
+
+            acc = _mm512_fmadd_ps(d, q, acc);
+        }
+
+        let sum = _mm512_reduce_add_ps(acc);
+        if n < abs.len() {
+            return sum + impl_x86_64_avx2::vec_dot_q4_0_q8_0(&abs[n..], &bbs[n..]);
+        }
+        sum
+    }
+}
+
+/// pick the kernel by the simd instructions of the cpu on the runtime.
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64 {
+    use super::impl_fallback;
+    use super::impl_x86_64_avx2;
+    use super::impl_x86_64_avx512;
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+    use crate::backends::cpu::arch::X86Simd;
+
+    pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
+
+        match X86Simd::detect() {
+            X86Simd::Avx512 => unsafe { impl_x86_64_avx512::vec_dot_q4_0_q8_0(abs, bbs) },
+            X86Simd::Avx2 => unsafe { impl_x86_64_avx2::vec_dot_q4_0_q8_0(abs, bbs) },
+            X86Simd::Fallback => impl_fallback::vec_dot_q4_0_q8_0(abs, bbs),
+        }
+    }
+}
+#[cfg(target_arch = "x86_64")]
+use impl_x86_64::*;
+
+#[cfg(not(all(target_arch = "aarch64", target_feature = "neon")))]
+mod impl_fallback {
+    use super::BlockQ4_0;
+    use super::BlockQ8_0;
+
+    pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
+
+        let mut sumf: f32 = 0.0;
+        for i in 0..bbs.len() {
+            let mut sumi: i32 = 0;
+            for j in 0..16 {
+                let v0 = (abs[i].qs[j] & 0x0F) as i32 - 8;
+                let v1 = (abs[i].qs[j] >> 4) as i32 - 8;
+                sumi += v0 * bbs[i].qs[j] as i32 + v1 * bbs[i].qs[j + 16] as i32;
+            }
+            sumf += sumi as f32 * abs[i].d.to_f32() * bbs[i].d.to_f32();
+        }
+
+        sumf
+    }
+}
+#[cfg(not(any(
+    all(target_arch = "aarch64", target_feature = "neon"),
+    target_arch = "x86_64"
+)))]
+use impl_fallback::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::QuantBufQ8_0;
+
+    #[test]
+    fn test_q4_0_block() {
+        let mut buf: [u8; 36] = [0x88; 36];
+        let d = f16::from_f32(3.0).to_bits().to_le_bytes();
+        buf[0] = d[0];
+        buf[1] = d[1];
+        buf[2] = 0x19; // elements 0 and 16
+        buf[3] = 0xF0; // elements 1 and 17
+        buf[18] = d[0];
+        buf[19] = d[1];
+        buf[35] = 0x7A; // elements 47 and 63
+
+        let bf = QuantBufQ4_0::from_bytes(&buf);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.as_bytes(), &buf);
+        assert_eq!(bf.blocks[0].d.to_f32(), 3.0);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 64);
+        assert_eq!(values[0], 3.0);
+        assert_eq!(values[1], -24.0);
+        assert_eq!(values[2], 0.0);
+        assert_eq!(values[16], -21.0);
+        assert_eq!(values[17], 21.0);
+        assert_eq!(values[47], 6.0);
+        assert_eq!(values[63], -3.0);
+        assert_eq!(bf.dequantize(32).count(), 32);
+    }
+
+    #[test]
+    fn test_quantize_q4_0() {
+        let data = (0..64).map(|i| (i as f32 - 30.0) / 4.0).collect::<Vec<_>>();
+        let bf = QuantBufQ4_0::quantize(&data);
+        assert_eq!(bf.len(), 64);
+
+        // the max absolute value is mapped to -8 exactly
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values[63], 8.25);
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.6, "got {} want {}", got, want);
+        }
+
+        let bf = QuantBufQ4_0::quantize(&[0.0; 32]);
+        assert!(bf.dequantize(0).all(|v| v == 0.0));
+    }
+
+    #[test]
+    fn test_vec_dot_q4_0_q8_0() {
+        let a = (0..128)
+            .map(|i| ((i * 7 % 31) as f32 - 15.0) / 8.0)
+            .collect::<Vec<_>>();
+        let b = (0..128)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+
+        let qa = QuantBufQ4_0::quantize(&a);
+        let qb = QuantBufQ8_0::quantize(&b);
+
+        // compare with the dot product of the dequantized values, which should be nearly
+        // the same except the rounding errors of the accumulation
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        for (offset, len) in [(0, 128), (32, 64), (96, 32)] {
+            let want = (offset..offset + len).map(|i| da[i] * db[i]).sum::<f32>();
+            let got = qa.vec_dot(offset, &qb, offset, len);
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+
+        // the quantization error is bounded by the magnitude of the products
+        let want = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>();
+        let scale = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a * b).abs())
+            .sum::<f32>();
+        let got = qa.vec_dot(0, &qb, 0, 128);
+        assert!(
+            (got - want).abs() < scale * 0.05,
+            "got {} want {}",
+            got,
+            want
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_simd_x86_64() {
+        use crate::backends::cpu::arch::X86Simd;
+
+        let a = (0..160)
+            .map(|i| ((i * 7 % 31) as f32 - 15.0) / 8.0)
+            .collect::<Vec<_>>();
+        let b = (0..160)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+        let qa = quantize_f32_q4_0(&a);
+        let qb = QuantBufQ8_0::quantize(&b).blocks.to_vec();
+        let want = impl_fallback::vec_dot_q4_0_q8_0(&qa, &qb);
+
+        let simd = X86Simd::detect();
+        if simd >= X86Simd::Avx2 {
+            let got = unsafe { impl_x86_64_avx2::vec_dot_q4_0_q8_0(&qa, &qb) };
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+        if simd >= X86Simd::Avx512 {
+            let got = unsafe { impl_x86_64_avx512::vec_dot_q4_0_q8_0(&qa, &qb) };
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    #[test]
+    fn test_simd_aarch64() {
+        use crate::backends::cpu::arch::ArmSimd;
+
+        let a = (0..160)
+            .map(|i| ((i * 7 % 31) as f32 - 15.0) / 8.0)
+            .collect::<Vec<_>>();
+        let b = (0..160)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+        let qa = quantize_f32_q4_0(&a);
+        let qb = QuantBufQ8_0::quantize(&b).blocks.to_vec();
+        let want = impl_aarch64_neon::vec_dot_q4_0_q8_0(&qa, &qb);
+
+        if ArmSimd::detect() >= ArmSimd::Dotprod {
+            let got = unsafe { impl_aarch64_dotprod::vec_dot_q4_0_q8_0(&qa, &qb) };
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+    }
+}
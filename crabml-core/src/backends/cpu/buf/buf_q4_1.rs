@@ -0,0 +1,179 @@
+use half::f16;
+
+use super::buf_q8_1::BlockQ8_1;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q4_1 stores 32 elements in 16 bytes with the same nibble layout as Q4_0, but each
+/// element is an unsigned 4-bit value scaled by `d` and shifted by the min `m`:
+/// `x = d * q + m`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ4_1 {
+    pub d: f16,       // delta
+    pub m: f16,       // min
+    pub qs: [u8; 16], // nibbles / quants
+}
+
+impl BlockQuant for BlockQ4_1 {
+    const DTYPE: GGMLType = GGMLType::Q4_1;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_1;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let m = self.m.to_f32();
+        for (i, q) in self.qs.iter().enumerate() {
+            buf[i] = (q & 0x0F) as f32 * d + m;
+            buf[i + 16] = (q >> 4) as f32 * d + m;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q4_1_q8_1(abs, bbs)
+    }
+}
+
+pub type QuantBufQ4_1<'a> = QuantBuf<'a, BlockQ4_1>;
+
+impl QuantBufQ4_1<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        Self::from_blocks(quantize_f32_q4_1(data))
+    }
+}
+
+pub fn quantize_f32_q4_1(data: &[f32]) -> Vec<BlockQ4_1> {
+    let mut bs = Vec::with_capacity(data.len() / 32);
+
+    for chunk in data.chunks(32) {
+        let min = chunk.iter().fold(f32::MAX, |m, v| m.min(*v));
+        let max = chunk.iter().fold(f32::MIN, |m, v| m.max(*v));
+
+        let d = (max - min) / 15.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+
+        let mut qs = [0_u8; 16];
+        for (i, q) in qs.iter_mut().enumerate() {
+            let x0 = (chunk[i] - min) * id;
+            let x1 = (chunk[i + 16] - min) * id;
+            let xi0 = ((x0 + 0.5) as u8).min(15);
+            let xi1 = ((x1 + 0.5) as u8).min(15);
+            *q = xi0 | (xi1 << 4);
+        }
+
+        bs.push(BlockQ4_1 {
+            d: f16::from_f32(d),
+            m: f16::from_f32(min),
+            qs,
+        });
+    }
+
+    bs
+}
+
+/// `sum(x * y) = sum((d_x * q_x + m_x) * d_y * q_y) = d_x * d_y * sum(q_x * q_y) + m_x * s_y`,
+/// the `s_y` is precomputed on quantizing the activation into Q8_1.
+pub fn vec_dot_q4_1_q8_1(abs: &[BlockQ4_1], bbs: &[BlockQ8_1]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf: f32 = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let mut sumi: i32 = 0;
+        for j in 0..16 {
+            let v0 = (a.qs[j] & 0x0F) as i32;
+            let v1 = (a.qs[j] >> 4) as i32;
+            sumi += v0 * b.qs[j] as i32 + v1 * b.qs[j + 16] as i32;
+        }
+        sumf += sumi as f32 * a.d.to_f32() * b.d.to_f32() + a.m.to_f32() * b.s.to_f32();
+    }
+
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::QuantBufQ8_1;
+
+    #[test]
+    fn test_q4_1_block() {
+        let mut buf: [u8; 40] = [0x00; 40];
+        let d = f16::from_f32(0.5).to_le_bytes();
+        let m = f16::from_f32(-2.0).to_le_bytes();
+        for blk in buf.chunks_mut(20) {
+            blk[0..2].copy_from_slice(&d);
+            blk[2..4].copy_from_slice(&m);
+        }
+        buf[4] = 0x19; // elements 0 and 16
+        buf[39] = 0xF0; // elements 47 and 63
+
+        let bf = QuantBufQ4_1::from_bytes(&buf);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.as_bytes(), &buf);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 64);
+        assert_eq!(values[0], 2.5);
+        assert_eq!(values[1], -2.0);
+        assert_eq!(values[16], -1.5);
+        assert_eq!(values[47], -2.0);
+        assert_eq!(values[63], 5.5);
+        assert_eq!(bf.dequantize(32).count(), 32);
+    }
+
+    #[test]
+    fn test_quantize_q4_1() {
+        let data = (0..64).map(|i| (i as f32 - 30.0) / 4.0).collect::<Vec<_>>();
+        let bf = QuantBufQ4_1::quantize(&data);
+        assert_eq!(bf.len(), 64);
+
+        // the min and max values are kept (nearly) exactly
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values[0], -7.5);
+        assert!((values[63] - 8.25).abs() < 1e-2);
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.3, "got {} want {}", got, want);
+        }
+
+        let bf = QuantBufQ4_1::quantize(&[1.0; 32]);
+        assert!(bf.dequantize(0).all(|v| v == 1.0));
+    }
+
+    #[test]
+    fn test_vec_dot_q4_1_q8_1() {
+        let a = (0..128)
+            .map(|i| (i * 7 % 31) as f32 / 8.0 - 1.0)
+            .collect::<Vec<_>>();
+        let b = (0..128)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+
+        let qa = QuantBufQ4_1::quantize(&a);
+        let qb = QuantBufQ8_1::quantize(&b);
+
+        // the min of Q4_1 is applied with the `s` of Q8_1, which is rounded into f16, so
+        // the result is a bit different from the dot product of the dequantized values
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        for (offset, len) in [(0, 128), (32, 64), (96, 32)] {
+            let want = (offset..offset + len).map(|i| da[i] * db[i]).sum::<f32>();
+            let got = qa.vec_dot(offset, &qb, offset, len);
+            assert!((got - want).abs() < 0.05, "got {} want {}", got, want);
+        }
+
+        let want = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>();
+        let scale = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a * b).abs())
+            .sum::<f32>();
+        let got = qa.vec_dot(0, &qb, 0, 128);
+        assert!(
+            (got - want).abs() < scale * 0.05,
+            "got {} want {}",
+            got,
+            want
+        );
+    }
+}
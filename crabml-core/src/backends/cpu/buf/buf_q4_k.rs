@@ -0,0 +1,241 @@
+use half::f16;
+
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q4_K splits a super block of 256 elements into 8 blocks of 32 elements, each element
+/// is quantized with 4 bits. the scale and min of each block are quantized with 6 bits,
+/// and scaled by `d` and `dmin`: `x = d * scale * q - dmin * min`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ4K {
+    pub d: f16,             // super-block scale for quantized scales
+    pub dmin: f16,          // super-block scale for quantized mins
+    pub scales: [u8; 12],   // scales and mins, quantized with 6 bits
+    pub qs: [u8; QK_K / 2], // quants, 4 bits
+}
+
+/// unpack the scales and mins in 6 bits, which is shared by Q4_K and Q5_K. the first 4
+/// scales and mins are kept in the low 6 bits of the first 8 bytes, the last 4 are kept in
+/// the last 4 bytes plus the high 2 bits of the first 8 bytes.
+pub fn unpack_scales_mins_k4(scales: &[u8; 12]) -> ([u8; 8], [u8; 8]) {
+    let mut sc = [0_u8; 8];
+    let mut m = [0_u8; 8];
+    for j in 0..8 {
+        if j < 4 {
+            sc[j] = scales[j] & 63;
+            m[j] = scales[j + 4] & 63;
+        } else {
+            sc[j] = (scales[j + 4] & 0xF) | ((scales[j - 4] >> 6) << 4);
+            m[j] = (scales[j + 4] >> 4) | ((scales[j] >> 6) << 4);
+        }
+    }
+    (sc, m)
+}
+
+impl BlockQ4K {
+    /// unpack the 4-bit quants. every 32 bytes keeps 64 elements, the low nibbles are the
+    /// first 32 elements, and the high nibbles are the last 32 elements.
+    fn quants(&self) -> [i8; QK_K] {
+        let mut qs = [0_i8; QK_K];
+        for (j, q) in self.qs.chunks(32).enumerate() {
+            for l in 0..32 {
+                qs[j * 64 + l] = (q[l] & 0xF) as i8;
+                qs[j * 64 + l + 32] = (q[l] >> 4) as i8;
+            }
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ4K {
+    const DTYPE: GGMLType = GGMLType::Q4K;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let dmin = self.dmin.to_f32();
+        let (sc, m) = unpack_scales_mins_k4(&self.scales);
+        let qs = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = d * sc[i / 32] as f32 * qs[i] as f32 - dmin * m[i / 32] as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q4_k_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufQ4K<'a> = QuantBuf<'a, BlockQ4K>;
+
+impl QuantBufQ4K<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        assert_eq!(
+            data.len() % QK_K,
+            0,
+            "data length must be a multiple of {}, got: {}",
+            QK_K,
+            data.len()
+        );
+        Self::from_blocks(quantize_f32_q4_k(data))
+    }
+}
+
+/// quantize each block of 32 elements with its own min and max, then quantize the scales
+/// and mins into 6 bits with the max of them in the super block. unlike ggml, the scales
+/// are not refined by searching, which loses a bit precision but is much faster.
+pub fn quantize_f32_q4_k(data: &[f32]) -> Vec<BlockQ4K> {
+    let mut bs = Vec::with_capacity(data.len() / QK_K);
+
+    for chunk in data.chunks(QK_K) {
+        let mut scales = [0.0_f32; 8];
+        let mut mins = [0.0_f32; 8];
+        for (j, sub) in chunk.chunks(32).enumerate() {
+            // the min is always kept <= 0, so it can be applied by subtraction
+            let min = sub.iter().fold(0.0_f32, |m, v| m.min(*v));
+            let max = sub.iter().fold(f32::MIN, |m, v| m.max(*v));
+            scales[j] = (max - min) / 15.0;
+            mins[j] = -min;
+        }
+
+        let max_scale = scales.iter().fold(0.0_f32, |m, v| m.max(*v));
+        let max_min = mins.iter().fold(0.0_f32, |m, v| m.max(*v));
+        let inv_scale = if max_scale > 0.0 {
+            63.0 / max_scale
+        } else {
+            0.0
+        };
+        let inv_min = if max_min > 0.0 { 63.0 / max_min } else { 0.0 };
+
+        let mut ls = [0_u8; 8];
+        let mut lm = [0_u8; 8];
+        for j in 0..8 {
+            ls[j] = ((inv_scale * scales[j]).round() as u8).min(63);
+            lm[j] = ((inv_min * mins[j]).round() as u8).min(63);
+        }
+
+        let mut packed = [0_u8; 12];
+        for j in 0..8 {
+            if j < 4 {
+                packed[j] = ls[j];
+                packed[j + 4] = lm[j];
+            } else {
+                packed[j + 4] = (ls[j] & 0xF) | ((lm[j] & 0xF) << 4);
+                packed[j - 4] |= (ls[j] >> 4) << 6;
+                packed[j] |= (lm[j] >> 4) << 6;
+            }
+        }
+
+        // quantize the elements with the rounded scales and mins
+        let d = f16::from_f32(max_scale / 63.0);
+        let dmin = f16::from_f32(max_min / 63.0);
+        let mut l = [0_u8; QK_K];
+        for (j, sub) in chunk.chunks(32).enumerate() {
+            let sc = d.to_f32() * ls[j] as f32;
+            if sc == 0.0 {
+                continue;
+            }
+            let m = dmin.to_f32() * lm[j] as f32;
+            for (i, v) in sub.iter().enumerate() {
+                l[j * 32 + i] = ((v + m) / sc).round().clamp(0.0, 15.0) as u8;
+            }
+        }
+
+        let mut qs = [0_u8; QK_K / 2];
+        for (n, q) in qs.chunks_mut(32).enumerate() {
+            for i in 0..32 {
+                q[i] = l[n * 64 + i] | (l[n * 64 + i + 32] << 4);
+            }
+        }
+
+        bs.push(BlockQ4K {
+            d,
+            dmin,
+            scales: packed,
+            qs,
+        });
+    }
+
+    bs
+}
+
+pub fn vec_dot_q4_k_q8_k(abs: &[BlockQ4K], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let (sc, m) = unpack_scales_mins_k4(&a.scales);
+        let qs = a.quants();
+
+        let mut sumi = 0;
+        let mut summs = 0;
+        for j in 0..QK_K / 32 {
+            let range = j * 32..(j + 1) * 32;
+            sumi += sc[j] as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+            summs += m[j] as i32 * (b.bsums[j * 2] as i32 + b.bsums[j * 2 + 1] as i32);
+        }
+
+        sumf += b.d * (a.d.to_f32() * sumi as f32 - a.dmin.to_f32() * summs as f32);
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+
+    #[test]
+    fn test_unpack_scales_mins_k4() {
+        let scales = [1, 2, 3, 0x44, 5, 6, 7, 0xC8, 0x9A, 0xBC, 0xDE, 0xF0];
+        let (sc, m) = unpack_scales_mins_k4(&scales);
+        assert_eq!(sc, [1, 2, 3, 4, 0xA, 0xC, 0xE, 0x10]);
+        assert_eq!(m, [5, 6, 7, 8, 0x9, 0xB, 0xD, 0x3F]);
+    }
+
+    #[test]
+    fn test_q4_k_block() {
+        let mut buf = pseudo_random_bytes(2 * 144);
+        for blk in buf.chunks_mut(144) {
+            blk[0..2].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+            blk[2..4].copy_from_slice(&f16::from_f32(0.25).to_le_bytes());
+        }
+        buf[4] = 3; // scale 3 of the elements 0..32
+        buf[8] = 2; // min 2 of the elements 0..32
+        buf[16] = 0x7A; // the quants of the elements 0 and 32
+
+        let bf = QuantBufQ4K::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        assert_eq!(values[0], 0.5 * 3.0 * 10.0 - 0.25 * 2.0);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+
+    #[test]
+    fn test_quantize_q4_k() {
+        let data = (0..512)
+            .map(|i| ((i * 37 % 101) as f32 - 40.0) / 16.0)
+            .collect::<Vec<_>>();
+        let bf = QuantBufQ4K::quantize(&data);
+        assert_eq!(bf.len(), 512);
+
+        // the error is bounded by a half step of the scale, plus the error of the 6-bit
+        // scales and mins
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.25, "got {} want {}", got, want);
+        }
+
+        let bf = QuantBufQ4K::quantize(&[0.0; 256]);
+        assert!(bf.dequantize(0).all(|v| v == 0.0));
+    }
+}
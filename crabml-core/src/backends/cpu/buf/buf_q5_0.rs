@@ -0,0 +1,199 @@
+use half::f16;
+
+use super::buf_q8_0::BlockQ8_0;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q5_0 is the same as Q4_0, except that each element is quantized with 5 bits with an
+/// offset of 16. the low 4 bits are kept in `qs`, and the high bit of the element `i` is
+/// kept in the bit `i` of `qh`, which is a little endian u32.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ5_0 {
+    pub d: f16,       // delta
+    pub qh: [u8; 4],  // 5-th bit of quants
+    pub qs: [u8; 16], // nibbles / quants
+}
+
+impl BlockQ5_0 {
+    /// unpack the 5-bit quants into [-16, 15].
+    fn quants(&self) -> [i8; 32] {
+        let qh = u32::from_le_bytes(self.qh);
+        let mut qs = [0_i8; 32];
+        for (j, q) in self.qs.iter().enumerate() {
+            let xh0 = ((qh >> j) << 4) & 0x10;
+            let xh1 = (qh >> (j + 12)) & 0x10;
+            qs[j] = ((*q as u32 & 0x0F) | xh0) as i8 - 16;
+            qs[j + 16] = ((*q as u32 >> 4) | xh1) as i8 - 16;
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ5_0 {
+    const DTYPE: GGMLType = GGMLType::Q5_0;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_0;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (v, q) in buf.iter_mut().zip(self.quants()) {
+            *v = q as f32 * d;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q5_0_q8_0(abs, bbs)
+    }
+}
+
+pub type QuantBufQ5_0<'a> = QuantBuf<'a, BlockQ5_0>;
+
+impl QuantBufQ5_0<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        Self::from_blocks(quantize_f32_q5_0(data))
+    }
+}
+
+pub fn quantize_f32_q5_0(data: &[f32]) -> Vec<BlockQ5_0> {
+    let mut bs = Vec::with_capacity(data.len() / 32);
+
+    for chunk in data.chunks(32) {
+        // like Q4_0, the extreme value is mapped to -16 exactly
+        let mut max_abs_value = 0.0;
+        let mut max_value = 0.0;
+        for &value in chunk {
+            if value.abs() > max_abs_value {
+                max_abs_value = value.abs();
+                max_value = value;
+            }
+        }
+
+        let d = max_value / -16.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+
+        let mut qs = [0_u8; 16];
+        let mut qh = 0_u32;
+        for (i, q) in qs.iter_mut().enumerate() {
+            let x0 = chunk[i] * id;
+            let x1 = chunk[i + 16] * id;
+            let xi0 = ((x0 + 16.5) as u8).min(31);
+            let xi1 = ((x1 + 16.5) as u8).min(31);
+            *q = (xi0 & 0x0F) | ((xi1 & 0x0F) << 4);
+            qh |= ((xi0 as u32 & 0x10) >> 4) << i;
+            qh |= ((xi1 as u32 & 0x10) >> 4) << (i + 16);
+        }
+
+        bs.push(BlockQ5_0 {
+            d: f16::from_f32(d),
+            qh: qh.to_le_bytes(),
+            qs,
+        });
+    }
+
+    bs
+}
+
+pub fn vec_dot_q5_0_q8_0(abs: &[BlockQ5_0], bbs: &[BlockQ8_0]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf: f32 = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let sumi = a
+            .quants()
+            .iter()
+            .zip(b.qs.iter())
+            .map(|(x, y)| *x as i32 * *y as i32)
+            .sum::<i32>();
+        sumf += sumi as f32 * a.d.to_f32() * b.d.to_f32();
+    }
+
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::QuantBufQ8_0;
+
+    #[test]
+    fn test_q5_0_block() {
+        let mut buf: [u8; 44] = [0x00; 44];
+        let d = f16::from_f32(0.5).to_le_bytes();
+        for blk in buf.chunks_mut(22) {
+            blk[0..2].copy_from_slice(&d);
+        }
+        buf[2] = 0x01; // the high bit of the element 0
+        buf[5] = 0x80; // the high bit of the element 31
+        buf[6] = 0x19; // elements 0 and 16
+        buf[21] = 0xF0; // elements 15 and 31
+
+        let bf = QuantBufQ5_0::from_bytes(&buf);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.as_bytes(), &buf);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 64);
+        assert_eq!(values[0], 0.5 * (0x19 - 16) as f32);
+        assert_eq!(values[1], -8.0);
+        assert_eq!(values[15], -8.0);
+        assert_eq!(values[16], 0.5 * (1 - 16) as f32);
+        assert_eq!(values[31], 0.5 * (0x1F - 16) as f32);
+        assert_eq!(values[32], -8.0);
+        assert_eq!(bf.dequantize(32).count(), 32);
+    }
+
+    #[test]
+    fn test_quantize_q5_0() {
+        let data = (0..64).map(|i| (i as f32 - 30.0) / 4.0).collect::<Vec<_>>();
+        let bf = QuantBufQ5_0::quantize(&data);
+        assert_eq!(bf.len(), 64);
+
+        // the max absolute value is mapped to -16 exactly
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values[0], -7.5);
+        assert_eq!(values[63], 8.25);
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.3, "got {} want {}", got, want);
+        }
+
+        let bf = QuantBufQ5_0::quantize(&[0.0; 32]);
+        assert!(bf.dequantize(0).all(|v| v == 0.0));
+    }
+
+    #[test]
+    fn test_vec_dot_q5_0_q8_0() {
+        let a = (0..128)
+            .map(|i| ((i * 7 % 31) as f32 - 15.0) / 8.0)
+            .collect::<Vec<_>>();
+        let b = (0..128)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+
+        let qa = QuantBufQ5_0::quantize(&a);
+        let qb = QuantBufQ8_0::quantize(&b);
+
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        for (offset, len) in [(0, 128), (32, 64), (96, 32)] {
+            let want = (offset..offset + len).map(|i| da[i] * db[i]).sum::<f32>();
+            let got = qa.vec_dot(offset, &qb, offset, len);
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        }
+
+        let want = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>();
+        let scale = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a * b).abs())
+            .sum::<f32>();
+        let got = qa.vec_dot(0, &qb, 0, 128);
+        assert!(
+            (got - want).abs() < scale * 0.03,
+            "got {} want {}",
+            got,
+            want
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use half::f16;
+
+use super::buf_q8_1::BlockQ8_1;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q5_1 is the same as Q4_1, except that each element is quantized with 5 bits. the high
+/// bits are kept in `qh` in the same way as Q5_0: `x = d * q + m`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ5_1 {
+    pub d: f16,       // delta
+    pub m: f16,       // min
+    pub qh: [u8; 4],  // 5-th bit of quants
+    pub qs: [u8; 16], // nibbles / quants
+}
+
+impl BlockQ5_1 {
+    /// unpack the 5-bit quants into [0, 31].
+    fn quants(&self) -> [i8; 32] {
+        let qh = u32::from_le_bytes(self.qh);
+        let mut qs = [0_i8; 32];
+        for (j, q) in self.qs.iter().enumerate() {
+            let xh0 = ((qh >> j) << 4) & 0x10;
+            let xh1 = (qh >> (j + 12)) & 0x10;
+            qs[j] = ((*q as u32 & 0x0F) | xh0) as i8;
+            qs[j + 16] = ((*q as u32 >> 4) | xh1) as i8;
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ5_1 {
+    const DTYPE: GGMLType = GGMLType::Q5_1;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_1;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let m = self.m.to_f32();
+        for (v, q) in buf.iter_mut().zip(self.quants()) {
+            *v = q as f32 * d + m;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q5_1_q8_1(abs, bbs)
+    }
+}
+
+pub type QuantBufQ5_1<'a> = QuantBuf<'a, BlockQ5_1>;
+
+impl QuantBufQ5_1<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        Self::from_blocks(quantize_f32_q5_1(data))
+    }
+}
+
+pub fn quantize_f32_q5_1(data: &[f32]) -> Vec<BlockQ5_1> {
+    let mut bs = Vec::with_capacity(data.len() / 32);
+
+    for chunk in data.chunks(32) {
+        let min = chunk.iter().fold(f32::MAX, |m, v| m.min(*v));
+        let max = chunk.iter().fold(f32::MIN, |m, v| m.max(*v));
+
+        let d = (max - min) / 31.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+
+        let mut qs = [0_u8; 16];
+        let mut qh = 0_u32;
+        for (i, q) in qs.iter_mut().enumerate() {
+            let x0 = (chunk[i] - min) * id;
+            let x1 = (chunk[i + 16] - min) * id;
+            let xi0 = ((x0 + 0.5) as u8).min(31);
+            let xi1 = ((x1 + 0.5) as u8).min(31);
+            *q = (xi0 & 0x0F) | ((xi1 & 0x0F) << 4);
+            qh |= ((xi0 as u32 & 0x10) >> 4) << i;
+            qh |= ((xi1 as u32 & 0x10) >> 4) << (i + 16);
+        }
+
+        bs.push(BlockQ5_1 {
+            d: f16::from_f32(d),
+            m: f16::from_f32(min),
+            qh: qh.to_le_bytes(),
+            qs,
+        });
+    }
+
+    bs
+}
+
+/// the same as `vec_dot_q4_1_q8_1`, the min is applied with the precomputed `s` of Q8_1.
+pub fn vec_dot_q5_1_q8_1(abs: &[BlockQ5_1], bbs: &[BlockQ8_1]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf: f32 = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let sumi = a
+            .quants()
+            .iter()
+            .zip(b.qs.iter())
+            .map(|(x, y)| *x as i32 * *y as i32)
+            .sum::<i32>();
+        sumf += sumi as f32 * a.d.to_f32() * b.d.to_f32() + a.m.to_f32() * b.s.to_f32();
+    }
+
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::QuantBufQ8_1;
+
+    #[test]
+    fn test_q5_1_block() {
+        let mut buf: [u8; 48] = [0x00; 48];
+        let d = f16::from_f32(0.5).to_le_bytes();
+        let m = f16::from_f32(-2.0).to_le_bytes();
+        for blk in buf.chunks_mut(24) {
+            blk[0..2].copy_from_slice(&d);
+            blk[2..4].copy_from_slice(&m);
+        }
+        buf[4] = 0x01; // the high bit of the element 0
+        buf[7] = 0x80; // the high bit of the element 31
+        buf[8] = 0x19; // elements 0 and 16
+        buf[23] = 0xF0; // elements 15 and 31
+
+        let bf = QuantBufQ5_1::from_bytes(&buf);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.as_bytes(), &buf);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 64);
+        assert_eq!(values[0], 0.5 * 25.0 - 2.0);
+        assert_eq!(values[1], -2.0);
+        assert_eq!(values[16], 0.5 - 2.0);
+        assert_eq!(values[31], 0.5 * 31.0 - 2.0);
+        assert_eq!(values[32], -2.0);
+        assert_eq!(bf.dequantize(32).count(), 32);
+    }
+
+    #[test]
+    fn test_quantize_q5_1() {
+        let data = (0..64).map(|i| (i as f32 - 30.0) / 4.0).collect::<Vec<_>>();
+        let bf = QuantBufQ5_1::quantize(&data);
+        assert_eq!(bf.len(), 64);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values[0], -7.5);
+        assert!((values[63] - 8.25).abs() < 1e-2);
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.15, "got {} want {}", got, want);
+        }
+
+        let bf = QuantBufQ5_1::quantize(&[1.0; 32]);
+        assert!(bf.dequantize(0).all(|v| v == 1.0));
+    }
+
+    #[test]
+    fn test_vec_dot_q5_1_q8_1() {
+        let a = (0..128)
+            .map(|i| (i * 7 % 31) as f32 / 8.0 - 1.0)
+            .collect::<Vec<_>>();
+        let b = (0..128)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+
+        let qa = QuantBufQ5_1::quantize(&a);
+        let qb = QuantBufQ8_1::quantize(&b);
+
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        for (offset, len) in [(0, 128), (32, 64), (96, 32)] {
+            let want = (offset..offset + len).map(|i| da[i] * db[i]).sum::<f32>();
+            let got = qa.vec_dot(offset, &qb, offset, len);
+            assert!((got - want).abs() < 0.05, "got {} want {}", got, want);
+        }
+
+        let want = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>();
+        let scale = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a * b).abs())
+            .sum::<f32>();
+        let got = qa.vec_dot(0, &qb, 0, 128);
+        assert!(
+            (got - want).abs() < scale * 0.03,
+            "got {} want {}",
+            got,
+            want
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use half::f16;
+
+use super::buf_q4_k::unpack_scales_mins_k4;
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q5_K is the same as Q4_K, except that each element is quantized with 5 bits, the
+/// low 4 bits are kept in `qs`, and the high bit is kept in `qh`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ5K {
+    pub d: f16,             // super-block scale for quantized scales
+    pub dmin: f16,          // super-block scale for quantized mins
+    pub scales: [u8; 12],   // scales and mins, quantized with 6 bits
+    pub qh: [u8; QK_K / 8], // quants, high bit
+    pub qs: [u8; QK_K / 2], // quants, low 4 bits
+}
+
+impl BlockQ5K {
+    /// unpack the 5-bit quants. the low 4 bits are stored in the same way as Q4_K, the
+    /// high bit of the element `i` is kept in the bit `i / 32` of `qh[i % 32]`.
+    fn quants(&self) -> [i8; QK_K] {
+        let mut qs = [0_i8; QK_K];
+        for (j, q) in self.qs.chunks(32).enumerate() {
+            let u1 = 1 << (j * 2);
+            let u2 = 2 << (j * 2);
+            for l in 0..32 {
+                let h1 = if self.qh[l] & u1 != 0 { 16 } else { 0 };
+                let h2 = if self.qh[l] & u2 != 0 { 16 } else { 0 };
+                qs[j * 64 + l] = ((q[l] & 0xF) + h1) as i8;
+                qs[j * 64 + l + 32] = ((q[l] >> 4) + h2) as i8;
+            }
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ5K {
+    const DTYPE: GGMLType = GGMLType::Q5K;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let dmin = self.dmin.to_f32();
+        let (sc, m) = unpack_scales_mins_k4(&self.scales);
+        let qs = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = d * sc[i / 32] as f32 * qs[i] as f32 - dmin * m[i / 32] as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q5_k_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufQ5K<'a> = QuantBuf<'a, BlockQ5K>;
+
+pub fn vec_dot_q5_k_q8_k(abs: &[BlockQ5K], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let (sc, m) = unpack_scales_mins_k4(&a.scales);
+        let qs = a.quants();
+
+        let mut sumi = 0;
+        let mut summs = 0;
+        for j in 0..QK_K / 32 {
+            let range = j * 32..(j + 1) * 32;
+            sumi += sc[j] as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+            summs += m[j] as i32 * (b.bsums[j * 2] as i32 + b.bsums[j * 2 + 1] as i32);
+        }
+
+        sumf += b.d * (a.d.to_f32() * sumi as f32 - a.dmin.to_f32() * summs as f32);
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+
+    #[test]
+    fn test_q5_k_block() {
+        let mut buf = pseudo_random_bytes(2 * 176);
+        for blk in buf.chunks_mut(176) {
+            blk[0..2].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+            blk[2..4].copy_from_slice(&f16::from_f32(0.25).to_le_bytes());
+        }
+        buf[4] = 3; // scale 3 of the elements 0..32
+        buf[8] = 2; // min 2 of the elements 0..32
+        buf[16] = 0x05; // the high bits of the elements 0 and 64
+        buf[48] = 0x7A; // the low bits of the elements 0 and 32
+
+        let bf = QuantBufQ5K::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        assert_eq!(bf.blocks[0].quants()[0], 26);
+        assert_eq!(bf.blocks[0].quants()[32], 7);
+        assert_eq!(bf.blocks[0].quants()[64] & 0x10, 0x10);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        assert_eq!(values[0], 0.5 * 3.0 * 26.0 - 0.25 * 2.0);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+}
@@ -0,0 +1,197 @@
+use half::f16;
+
+use super::buf_q8_k::vec_dot_i8_i8;
+use super::buf_q8_k::BlockQ8K;
+use super::buf_q8_k::QK_K;
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q6_K splits a super block of 256 elements into 16 blocks of 16 elements, each element
+/// is quantized with 6 bits: the low 4 bits are kept in `ql`, and the high 2 bits are kept
+/// in `qh`. the scales of the blocks are quantized with 8 bits: `x = d * scale * (q - 32)`.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ6K {
+    pub ql: [u8; QK_K / 2],      // quants, low 4 bits
+    pub qh: [u8; QK_K / 4],      // quants, high 2 bits
+    pub scales: [i8; QK_K / 16], // scales, quantized with 8 bits
+    pub d: f16,                  // super-block scale
+}
+
+impl BlockQ6K {
+    /// unpack the 6-bit quants into [-32, 31]. every 64 bytes of `ql` and 32 bytes of `qh`
+    /// keep 128 elements.
+    fn quants(&self) -> [i8; QK_K] {
+        let mut qs = [0_i8; QK_K];
+        for (n, (ql, qh)) in self.ql.chunks(64).zip(self.qh.chunks(32)).enumerate() {
+            let qs = &mut qs[n * 128..(n + 1) * 128];
+            for l in 0..32 {
+                qs[l] = ((ql[l] & 0xF) | ((qh[l] & 3) << 4)) as i8 - 32;
+                qs[l + 32] = ((ql[l + 32] & 0xF) | (((qh[l] >> 2) & 3) << 4)) as i8 - 32;
+                qs[l + 64] = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i8 - 32;
+                qs[l + 96] = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i8 - 32;
+            }
+        }
+        qs
+    }
+}
+
+impl BlockQuant for BlockQ6K {
+    const DTYPE: GGMLType = GGMLType::Q6K;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        let qs = self.quants();
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = d * self.scales[i / 16] as f32 * qs[i] as f32;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q6_k_q8_k(abs, bbs)
+    }
+}
+
+pub type QuantBufQ6K<'a> = QuantBuf<'a, BlockQ6K>;
+
+impl QuantBufQ6K<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        assert_eq!(
+            data.len() % QK_K,
+            0,
+            "data length must be a multiple of {}, got: {}",
+            QK_K,
+            data.len()
+        );
+        Self::from_blocks(quantize_f32_q6_k(data))
+    }
+}
+
+/// quantize each block of 16 elements like Q8_0 does but in 6 bits, the extreme value of a
+/// block is mapped to -32. the scales are quantized into 8 bits in the same way, by the
+/// extreme scale in the super block.
+pub fn quantize_f32_q6_k(data: &[f32]) -> Vec<BlockQ6K> {
+    let mut bs = Vec::with_capacity(data.len() / QK_K);
+
+    for chunk in data.chunks(QK_K) {
+        let mut scales = [0.0_f32; QK_K / 16];
+        for (j, sub) in chunk.chunks(16).enumerate() {
+            let max = sub
+                .iter()
+                .fold(0.0_f32, |m, v| if v.abs() > m.abs() { *v } else { m });
+            scales[j] = max / -32.0;
+        }
+
+        let max_scale = scales
+            .iter()
+            .fold(0.0_f32, |m, v| if v.abs() > m.abs() { *v } else { m });
+        let iscale = if max_scale != 0.0 {
+            -128.0 / max_scale
+        } else {
+            0.0
+        };
+        let d = f16::from_f32(if iscale != 0.0 { 1.0 / iscale } else { 0.0 });
+
+        let mut sc = [0_i8; QK_K / 16];
+        let mut l = [32_u8; QK_K];
+        for (j, sub) in chunk.chunks(16).enumerate() {
+            sc[j] = (iscale * scales[j]).round().clamp(-128.0, 127.0) as i8;
+            let d = d.to_f32() * sc[j] as f32;
+            if d == 0.0 {
+                continue;
+            }
+            for (i, v) in sub.iter().enumerate() {
+                l[j * 16 + i] = ((v / d).round().clamp(-32.0, 31.0) + 32.0) as u8;
+            }
+        }
+
+        let mut ql = [0_u8; QK_K / 2];
+        let mut qh = [0_u8; QK_K / 4];
+        for n in 0..QK_K / 128 {
+            let l = &l[n * 128..(n + 1) * 128];
+            for i in 0..32 {
+                let (q1, q2, q3, q4) = (l[i], l[i + 32], l[i + 64], l[i + 96]);
+                ql[n * 64 + i] = (q1 & 0xF) | ((q3 & 0xF) << 4);
+                ql[n * 64 + i + 32] = (q2 & 0xF) | ((q4 & 0xF) << 4);
+                qh[n * 32 + i] = (q1 >> 4) | ((q2 >> 4) << 2) | ((q3 >> 4) << 4) | ((q4 >> 4) << 6);
+            }
+        }
+
+        bs.push(BlockQ6K {
+            ql,
+            qh,
+            scales: sc,
+            d,
+        });
+    }
+
+    bs
+}
+
+pub fn vec_dot_q6_k_q8_k(abs: &[BlockQ6K], bbs: &[BlockQ8K]) -> f32 {
+    assert!(abs.len() == bbs.len());
+
+    let mut sumf = 0.0;
+    for (a, b) in abs.iter().zip(bbs.iter()) {
+        let qs = a.quants();
+
+        let mut sumi = 0;
+        for j in 0..QK_K / 16 {
+            let range = j * 16..(j + 1) * 16;
+            sumi += a.scales[j] as i32 * vec_dot_i8_i8(&qs[range.clone()], &b.qs[range]);
+        }
+
+        sumf += a.d.to_f32() * b.d * sumi as f32;
+    }
+    sumf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::assert_vec_dot_k;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
+
+    #[test]
+    fn test_q6_k_block() {
+        let mut buf = pseudo_random_bytes(2 * 210);
+        for blk in buf.chunks_mut(210) {
+            blk[208..210].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+        }
+        buf[0] = 0x7A; // the low bits of the elements 0 and 64
+        buf[128] = 0xE4; // the high bits 0, 1, 2, 3 of the elements 0, 32, 64, 96
+        buf[192] = -3_i8 as u8; // scale of the elements 0..16
+
+        let bf = QuantBufQ6K::from_bytes(&buf);
+        assert_eq!(bf.len(), 512);
+        assert_eq!(bf.blocks[0].quants()[0], 0x0A - 32);
+        assert_eq!(bf.blocks[0].quants()[64], 0x27 - 32);
+        assert_eq!(bf.blocks[0].quants()[96] >> 4, 1);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(values.len(), 512);
+        assert_eq!(values[0], 0.5 * -3.0 * -22.0);
+
+        assert_vec_dot_k(&values, |b| bf.vec_dot(0, b, 0, 512));
+    }
+
+    #[test]
+    fn test_quantize_q6_k() {
+        let data = (0..512)
+            .map(|i| ((i * 37 % 101) as f32 - 40.0) / 16.0)
+            .collect::<Vec<_>>();
+        let bf = QuantBufQ6K::quantize(&data);
+        assert_eq!(bf.len(), 512);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.06, "got {} want {}", got, want);
+        }
+
+        let bf = QuantBufQ6K::quantize(&[0.0; 256]);
+        assert!(bf.dequantize(0).all(|v| v == 0.0));
+    }
+}
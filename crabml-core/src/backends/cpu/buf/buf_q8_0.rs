@@ -1,7 +1,9 @@
-use std::borrow::Cow;
-
 use half::f16;
 
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
 pub struct BlockQ8_0 {
@@ -9,75 +11,38 @@ pub struct BlockQ8_0 {
     pub qs: [i8; 32], // quants
 }
 
-impl BlockQ8_0 {
-    pub const BLOCK_ELEMS: usize = 32;
+impl BlockQuant for BlockQ8_0 {
+    const DTYPE: GGMLType = GGMLType::Q8_0;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_0;
 
-    pub fn dequantize(&self, buf: &mut [f32]) {
+    fn dequantize(&self, buf: &mut [f32]) {
         let d = self.d.to_f32();
         for (i, v) in buf.iter_mut().enumerate().take(32) {
             *v = self.qs[i] as f32 * d;
         }
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct QuantBufQ8_0<'a> {
-    pub blocks: Cow<'a, [BlockQ8_0]>,
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        vec_dot_q8_0_q8_0(abs, bbs)
+    }
 }
 
-impl<'a> QuantBufQ8_0<'a> {
-    pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ8_0>();
+pub type QuantBufQ8_0<'a> = QuantBuf<'a, BlockQ8_0>;
+
+impl QuantBufQ8_0<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
         assert_eq!(
-            data.len() % blk_size,
+            data.len() % 32,
             0,
-            "data length must be a multiple of QuantBlockQ8_0 size"
+            "data length must be a multiple of 32, got: {}",
+            data.len()
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_0, data.len() / blk_size)
-        };
-        Self {
-            blocks: blocks.into(),
-        }
-    }
-
-    pub fn quantize(data: &[f32]) -> Self {
-        let bs = quantize_f32_q8_0(data);
-        Self { blocks: bs.into() }
-    }
-
-    fn blocks(&self) -> &[BlockQ8_0] {
-        &self.blocks
-    }
-
-    pub fn len(&self) -> usize {
-        self.blocks.len() * 32
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.blocks.is_empty()
-    }
-
-    pub fn dequantize(&'a self, start: usize) -> impl Iterator<Item = f32> + 'a {
-        assert_eq!(start % 32, 0);
-
-        let block_start = start / 32;
-        self.blocks()[block_start..].iter().flat_map(|blk| {
-            let mut buf = [0.0; 32];
-            blk.dequantize(&mut buf);
-            buf.into_iter()
-        })
-    }
-
-    pub fn vec_dot(&self, a_offset: usize, b: &Self, b_offset: usize, len: usize) -> f32 {
-        let abs = &self.blocks[a_offset / 32..(a_offset + len) / 32];
-        let bbs = &b.blocks()[b_offset / 32..(b_offset + len) / 32];
-
-        vec_dot_q8_0_q8_0(abs, bbs)
+        Self::from_blocks(quantize_f32_q8_0(data))
     }
 }
 
-#[cfg(all(target_arch = "aaarch64", target_feature = "neon"))]
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
 mod impl_aarch64_neon {
     use std::arch::aarch64;
 
@@ -132,102 +97,161 @@ mod impl_aarch64_neon {
         bs
     }
 
+    /// the neon of armv8.0 has no sdot, like the cortex-a72 of raspberry pi 4, the products
+    /// are widened to i16 and added pairwise instead.
     pub fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
-        assert!(abs.len() == bbs.len());
-
-        if bbs.len() % 2 == 0 {
-            return vec_dot_q8_0_q8_0_unrolled(abs, bbs);
-        }
-        vec_dot_q8_0_q8_0_rolled(abs, bbs)
-    }
-
-    fn vec_dot_q8_0_q8_0_rolled(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
         unsafe {
-            use std::arch::aarch64;
             let mut sumv0 = aarch64::vdupq_n_f32(0.0);
-            let zerov = aarch64::vdupq_n_s32(0);
 
             for i in 0..bbs.len() {
                 let ab0 = abs.get_unchecked(i);
                 let bb0 = bbs.get_unchecked(i);
 
-                let av00 = aarch64::vld1q_s8(ab0.qs.as_ptr());
-                let av01 = aarch64::vld1q_s8(ab0.qs.as_ptr().add(16));
+                let av0 = aarch64::vld1q_s8(ab0.qs.as_ptr());
+                let av1 = aarch64::vld1q_s8(ab0.qs.as_ptr().add(16));
+                let bv0 = aarch64::vld1q_s8(bb0.qs.as_ptr());
+                let bv1 = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
+
+                let p0 = aarch64::vmull_s8(aarch64::vget_low_s8(av0), aarch64::vget_low_s8(bv0));
+                let p1 = aarch64::vmull_high_s8(av0, bv0);
+                let p2 = aarch64::vmull_s8(aarch64::vget_low_s8(av1), aarch64::vget_low_s8(bv1));
+                let p3 = aarch64::vmull_high_s8(av1, bv1);
 
-                let bv00 = aarch64::vld1q_s8(bb0.qs.as_ptr());
-                let bv01 = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
+                let mut p = aarch64::vpaddlq_s16(p0);
+                p = aarch64::vpadalq_s16(p, p1);
+                p = aarch64::vpadalq_s16(p, p2);
+                p = aarch64::vpadalq_s16(p, p3);
 
                 sumv0 = aarch64::vmlaq_n_f32(
                     sumv0,
-                    aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
-                        aarch64::vdotq_s32(zerov, av00, bv00),
-                        aarch64::vdotq_s32(zerov, av01, bv01),
-                    )),
-                    f16::to_f32(ab0.d) * f16::to_f32(bb0.d),
+                    aarch64::vcvtq_f32_s32(p),
+                    ab0.d.to_f32() * bb0.d.to_f32(),
                 );
             }
 
             aarch64::vaddvq_f32(sumv0)
         }
     }
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+mod impl_aarch64_dotprod {
+    use std::arch::aarch64;
+
+    use half::f16;
+
+    use super::BlockQ8_0;
+
+    #[target_feature(enable = "neon,dotprod")]
+    pub unsafe fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        if bbs.len() % 2 == 0 {
+            return vec_dot_q8_0_q8_0_unrolled(abs, bbs);
+        }
+        vec_dot_q8_0_q8_0_rolled(abs, bbs)
+    }
+
+    #[target_feature(enable = "neon,dotprod")]
+    unsafe fn vec_dot_q8_0_q8_0_rolled(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        let mut sumv0 = aarch64::vdupq_n_f32(0.0);
+        let zerov = aarch64::vdupq_n_s32(0);
+
+        for i in 0..bbs.len() {
+            let ab0 = abs.get_unchecked(i);
+            let bb0 = bbs.get_unchecked(i);
+
+            let av00 = aarch64::vld1q_s8(ab0.qs.as_ptr());
+            let av01 = aarch64::vld1q_s8(ab0.qs.as_ptr().add(16));
+
+            let bv00 = aarch64::vld1q_s8(bb0.qs.as_ptr());
+            let bv01 = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
+
+            sumv0 = aarch64::vmlaq_n_f32(
+                sumv0,
+                aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
+                    aarch64::vdotq_s32(zerov, av00, bv00),
+                    aarch64::vdotq_s32(zerov, av01, bv01),
+                )),
+                f16::to_f32(ab0.d) * f16::to_f32(bb0.d),
+            );
+        }
+
+        aarch64::vaddvq_f32(sumv0)
+    }
 
-    fn vec_dot_q8_0_q8_0_unrolled(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+    #[target_feature(enable = "neon,dotprod")]
+    unsafe fn vec_dot_q8_0_q8_0_unrolled(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
         assert!(
             bbs.len() % 2 == 0,
             "bbs.len() must be a multiple of 64, got: {}",
             bbs.len()
         );
 
-        unsafe {
-            use std::arch::aarch64;
-            let mut sumv0 = aarch64::vdupq_n_f32(0.0);
-            let mut sumv1 = aarch64::vdupq_n_f32(0.0);
-            let zerov = aarch64::vdupq_n_s32(0);
-
-            for i in (0..bbs.len()).step_by(2) {
-                let ab0 = abs.get_unchecked(i);
-                let ab1 = abs.get_unchecked(i + 1);
-                let bb0 = bbs.get_unchecked(i);
-                let bb1 = bbs.get_unchecked(i + 1);
-
-                let av00 = aarch64::vld1q_s8(ab0.qs.as_ptr());
-                let av01 = aarch64::vld1q_s8(ab0.qs.as_ptr().add(16));
-                let av10 = aarch64::vld1q_s8(ab1.qs.as_ptr());
-                let av11 = aarch64::vld1q_s8(ab1.qs.as_ptr().add(16));
+        let mut sumv0 = aarch64::vdupq_n_f32(0.0);
+        let mut sumv1 = aarch64::vdupq_n_f32(0.0);
+        let zerov = aarch64::vdupq_n_s32(0);
+
+        for i in (0..bbs.len()).step_by(2) {
+            let ab0 = abs.get_unchecked(i);
+            let ab1 = abs.get_unchecked(i + 1);
+            let bb0 = bbs.get_unchecked(i);
+            let bb1 = bbs.get_unchecked(i + 1);
+
+            let av00 = aarch64::vld1q_s8(ab0.qs.as_ptr());
+            let av01 = aarch64::vld1q_s8(ab0.qs.as_ptr().add(16));
+            let av10 = aarch64::vld1q_s8(ab1.qs.as_ptr());
+            let av11 = aarch64::vld1q_s8(ab1.qs.as_ptr().add(16));
+
+            let bv00 = aarch64::vld1q_s8(bb0.qs.as_ptr());
+            let bv01 = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
+            let bv10 = aarch64::vld1q_s8(bb1.qs.as_ptr());
+            let bv11 = aarch64::vld1q_s8(bb1.qs.as_ptr().add(16));
+
+            sumv0 = aarch64::vmlaq_n_f32(
+                sumv0,
+                aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
+                    aarch64::vdotq_s32(zerov, av00, bv00),
+                    aarch64::vdotq_s32(zerov, av01, bv01),
+                )),
+                f16::to_f32(ab0.d) * f16::to_f32(bb0.d),
+            );
+
+            sumv1 = aarch64::vmlaq_n_f32(
+                sumv1,
+                aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
+                    aarch64::vdotq_s32(zerov, av10, bv10),
+                    aarch64::vdotq_s32(zerov, av11, bv11),
+                )),
+                f16::to_f32(ab1.d) * f16::to_f32(bb1.d),
+            );
+        }
 
-                let bv00 = aarch64::vld1q_s8(bb0.qs.as_ptr());
-                let bv01 = aarch64::vld1q_s8(bb0.qs.as_ptr().add(16));
-                let bv10 = aarch64::vld1q_s8(bb1.qs.as_ptr());
-                let bv11 = aarch64::vld1q_s8(bb1.qs.as_ptr().add(16));
+        aarch64::vaddvq_f32(sumv0) + aarch64::vaddvq_f32(sumv1)
+    }
+}
 
-                sumv0 = aarch64::vmlaq_n_f32(
-                    sumv0,
-                    aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
-                        aarch64::vdotq_s32(zerov, av00, bv00),
-                        aarch64::vdotq_s32(zerov, av01, bv01),
-                    )),
-                    f16::to_f32(ab0.d) * f16::to_f32(bb0.d),
-                );
+/// pick the kernels by the simd instructions of the cpu on the runtime.
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+mod impl_aarch64 {
+    use super::impl_aarch64_dotprod;
+    use super::impl_aarch64_neon;
+    pub use super::impl_aarch64_neon::quantize_f32_q8_0;
+    use super::BlockQ8_0;
+    use crate::backends::cpu::arch::ArmSimd;
 
-                sumv1 = aarch64::vmlaq_n_f32(
-                    sumv1,
-                    aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
-                        aarch64::vdotq_s32(zerov, av10, bv10),
-                        aarch64::vdotq_s32(zerov, av11, bv11),
-                    )),
-                    f16::to_f32(ab1.d) * f16::to_f32(bb1.d),
-                );
-            }
+    pub fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
 
-            aarch64::vaddvq_f32(sumv0) + aarch64::vaddvq_f32(sumv1)
+        match ArmSimd::detect() {
+            ArmSimd::Dotprod => unsafe { impl_aarch64_dotprod::vec_dot_q8_0_q8_0(abs, bbs) },
+            ArmSimd::Neon => impl_aarch64_neon::vec_dot_q8_0_q8_0(abs, bbs),
         }
     }
 }
-#[cfg(all(target_arch = "aaarch64", target_feature = "neon"))]
-use impl_aarch64_neon::*;
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+use impl_aarch64::*;
 
-#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
-mod impl_x86_64_avx2 {
+#[cfg(target_arch = "x86_64")]
+pub(super) mod impl_x86_64_avx2 {
     //! Inspired a lot by [ggml](https://github.com/ggerganov/ggml/blob/master/src/ggml-quants.c)
 
     use std::arch::x86_64::*;
@@ -236,82 +260,73 @@ mod impl_x86_64_avx2 {
 
     use super::BlockQ8_0;
 
-    pub fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
         let mut bs = Vec::with_capacity(data.len() / 32);
 
-        unsafe {
-            for chunk in data.chunks(32) {
-                let mut max_abs_values = _mm256_setzero_ps();
-
-                for &value in chunk {
-                    let val_vec = _mm256_set1_ps(value);
-                    max_abs_values = _mm256_max_ps(
-                        max_abs_values,
-                        _mm256_andnot_ps(_mm256_set1_ps(-0.0), val_vec),
-                    );
-                }
+        for chunk in data.chunks(32) {
+            // take the absolute values by clearing the sign bits, and find the max of
+            // the 32 values in 4 vectors
+            let sign_bit = _mm256_set1_ps(-0.0);
+            let mut max_abs_values = _mm256_setzero_ps();
+            for values in chunk.chunks_exact(8) {
+                let values_vec = _mm256_loadu_ps(values.as_ptr());
+                max_abs_values =
+                    _mm256_max_ps(max_abs_values, _mm256_andnot_ps(sign_bit, values_vec));
+            }
+            let max_abs_value = hmax_float_8(max_abs_values);
+
+            let d = max_abs_value / 127.0;
+            let d_vec = _mm256_set1_ps(d);
+            let mut qs = [0_i8; 32];
+            let mut temp = [0i32; 8]; // Temporary array to hold intermediate results
+
+            for (chunk_index, values) in chunk.chunks(8).enumerate() {
+                let values_vec = _mm256_loadu_ps(values.as_ptr());
+                let scaled_vec = _mm256_div_ps(values_vec, d_vec);
+                let clamped_vec = _mm256_max_ps(
+                    _mm256_set1_ps(i8::MIN as f32),
+                    _mm256_min_ps(_mm256_set1_ps(i8::MAX as f32), scaled_vec),
+                );
+                let converted_vec = _mm256_cvtps_epi32(clamped_vec);
+                _mm256_storeu_si256(temp.as_mut_ptr() as *mut __m256i, converted_vec);
 
-                let max_abs_value = {
-                    let mut max_vals = [0.0; 8];
-                    _mm256_storeu_ps(max_vals.as_mut_ptr(), max_abs_values);
-                    *max_vals
-                        .iter()
-                        .max_by(|x, y| x.partial_cmp(y).unwrap())
-                        .unwrap()
-                };
-
-                let d = max_abs_value / 127.0;
-                let d_vec = _mm256_set1_ps(d);
-                let mut qs = [0_i8; 32];
-                let mut temp = [0i32; 8]; // Temporary array to hold intermediate results
-
-                for (chunk_index, values) in chunk.chunks(8).enumerate() {
-                    let values_vec = _mm256_loadu_ps(values.as_ptr());
-                    let scaled_vec = _mm256_div_ps(values_vec, d_vec);
-                    let clamped_vec = _mm256_max_ps(
-                        _mm256_set1_ps(i8::MIN as f32),
-                        _mm256_min_ps(_mm256_set1_ps(i8::MAX as f32), scaled_vec),
-                    );
-                    let converted_vec = _mm256_cvtps_epi32(clamped_vec);
-                    _mm256_storeu_si256(temp.as_mut_ptr() as *mut __m256i, converted_vec);
-
-                    for (i, &value) in temp.iter().enumerate() {
-                        qs[chunk_index * 8 + i] = value as i8;
-                    }
+                for (i, &value) in temp.iter().enumerate() {
+                    qs[chunk_index * 8 + i] = value as i8;
                 }
-
-                bs.push(BlockQ8_0 {
-                    d: f16::from_f32(d),
-                    qs,
-                });
             }
+
+            bs.push(BlockQ8_0 {
+                d: f16::from_f32(d),
+                qs,
+            });
         }
 
         bs
     }
 
-    pub fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
-        unsafe {
-            let mut acc = _mm256_setzero_ps();
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        let mut acc = _mm256_setzero_ps();
 
-            for (abs, bbs) in abs.iter().zip(bbs) {
-                let d = _mm256_set1_ps(abs.d.to_f32() * bbs.d.to_f32());
+        for (abs, bbs) in abs.iter().zip(bbs) {
+            let d = _mm256_set1_ps(abs.d.to_f32() * bbs.d.to_f32());
 
-                let qa = _mm256_loadu_si256(abs.qs.as_ptr() as *const __m256i);
-                let qb = _mm256_loadu_si256(bbs.qs.as_ptr() as *const __m256i);
+            let qa = _mm256_loadu_si256(abs.qs.as_ptr() as *const __m256i);
+            let qb = _mm256_loadu_si256(bbs.qs.as_ptr() as *const __m256i);
 
-                let q = mul_sum_i8_pairs_float(qa, qb);
+            let q = mul_sum_i8_pairs_float(qa, qb);
 
-                acc = _mm256_fmadd_ps(d, q, acc);
-            }
-
-            hsum_float_8(acc)
+            acc = _mm256_fmadd_ps(d, q, acc);
         }
+
+        hsum_float_8(acc)
     }
 
     /// TODO: Adding AVX-VNNI support so that we can use `_mm256_dpbssd_epi32`
     #[inline]
-    unsafe fn mul_sum_i8_pairs_float(x: __m256i, y: __m256i) -> __m256 {
+    #[target_feature(enable = "avx2,fma")]
+    pub(crate) unsafe fn mul_sum_i8_pairs_float(x: __m256i, y: __m256i) -> __m256 {
         // Get absolute values of x vectors
         let ax = _mm256_sign_epi8(x, x);
         // Sign the values of the y vectors
@@ -320,6 +335,7 @@ mod impl_x86_64_avx2 {
     }
 
     #[inline]
+    #[target_feature(enable = "avx2,fma")]
     unsafe fn mul_sum_us8_pairs_float(ax: __m256i, sy: __m256i) -> __m256 {
         let axl = _mm256_castsi256_si128(ax);
         let axh = _mm256_extractf128_si256(ax, 1);
@@ -332,6 +348,7 @@ mod impl_x86_64_avx2 {
     }
 
     #[inline]
+    #[target_feature(enable = "avx2,fma")]
     unsafe fn sum_i16_pairs_float(xh: __m128i, xl: __m128i) -> __m256 {
         let ones = _mm_set1_epi16(1);
         let summed_pairsl = _mm_madd_epi16(ones, xl);
@@ -340,9 +357,21 @@ mod impl_x86_64_avx2 {
         _mm256_cvtepi32_ps(summed_pairs)
     }
 
+    /// horizontally find the max of 8 floats
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn hmax_float_8(x: __m256) -> f32 {
+        let res = _mm256_extractf128_ps(x, 1);
+        let res = _mm_max_ps(res, _mm256_castps256_ps128(x));
+        let res = _mm_max_ps(res, _mm_movehl_ps(res, res));
+        let res = _mm_max_ss(res, _mm_movehdup_ps(res));
+        _mm_cvtss_f32(res)
+    }
+
     /// horizontally add 8 floats
     #[inline]
-    unsafe fn hsum_float_8(x: __m256) -> f32 {
+    #[target_feature(enable = "avx2,fma")]
+    pub(crate) unsafe fn hsum_float_8(x: __m256) -> f32 {
         let res = _mm256_extractf128_ps(x, 1);
         let res = _mm_add_ps(res, _mm256_castps256_ps128(x));
         let res = _mm_add_ps(res, _mm_movehl_ps(res, res));
@@ -350,13 +379,125 @@ mod impl_x86_64_avx2 {
         _mm_cvtss_f32(res)
     }
 }
-#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
-use impl_x86_64_avx2::*;
 
-#[cfg(not(any(
-    all(target_arch = "aarch64", target_feature = "neon"),
-    all(target_arch = "x86_64", target_feature = "avx2")
-)))]
+#[cfg(target_arch = "x86_64")]
+pub(super) mod impl_x86_64_avx512 {
+    use std::arch::x86_64::*;
+
+    use half::f16;
+
+    use super::impl_x86_64_avx2;
+    use super::BlockQ8_0;
+
+    /// quantize 32 values in 2 vectors, the values are rounded to the nearest like avx2.
+    #[target_feature(enable = "avx512f,avx512bw,avx2,fma")]
+    pub unsafe fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
+        let mut bs = Vec::with_capacity(data.len() / 32);
+
+        for chunk in data.chunks_exact(32) {
+            let v0 = _mm512_loadu_ps(chunk.as_ptr());
+            let v1 = _mm512_loadu_ps(chunk.as_ptr().add(16));
+            let max = _mm512_reduce_max_ps(_mm512_max_ps(_mm512_abs_ps(v0), _mm512_abs_ps(v1)));
+
+            let d = max / 127.0;
+            let mut qs = [0_i8; 32];
+            if d != 0.0 {
+                let dv = _mm512_set1_ps(d);
+                let q0 = _mm512_cvtsepi32_epi8(_mm512_cvtps_epi32(_mm512_div_ps(v0, dv)));
+                let q1 = _mm512_cvtsepi32_epi8(_mm512_cvtps_epi32(_mm512_div_ps(v1, dv)));
+                _mm_storeu_si128(qs.as_mut_ptr() as *mut __m128i, q0);
+                _mm_storeu_si128(qs.as_mut_ptr().add(16) as *mut __m128i, q1);
+            }
+
+            bs.push(BlockQ8_0 {
+                d: f16::from_f32(d),
+                qs,
+            });
+        }
+
+        bs
+    }
+
+    /// take 2 blocks in a vector, the lower 8 lanes of the sums belong to the first block.
+    #[target_feature(enable = "avx512f,avx512bw,avx2,fma")]
+    pub unsafe fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        let mut acc = _mm512_setzero_ps();
+
+        let n = abs.len() - abs.len() % 2;
+        for i in (0..n).step_by(2) {
+            let (ab0, ab1) = (abs.get_unchecked(i), abs.get_unchecked(i + 1));
+            let (bb0, bb1) = (bbs.get_unchecked(i), bbs.get_unchecked(i + 1));
+            let d = _mm512_mask_blend_ps(
+                0xFF00,
+                _mm512_set1_ps(ab0.d.to_f32() * bb0.d.to_f32()),
+                _mm512_set1_ps(ab1.d.to_f32() * bb1.d.to_f32()),
+            );
+
+            let qa = load_2_blocks(ab0.qs.as_ptr(), ab1.qs.as_ptr());
+            let qb = load_2_blocks(bb0.qs.as_ptr(), bb1.qs.as_ptr());
+            let q = mul_sum_i8_pairs_float(qa, qb);
+
+            acc = _mm512_fmadd_ps(d, q, acc);
+        }
+
+        let sum = _mm512_reduce_add_ps(acc);
+        if n < abs.len() {
+            return sum + impl_x86_64_avx2::vec_dot_q8_0_q8_0(&abs[n..], &bbs[n..]);
+        }
+        sum
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx512f,avx512bw,avx2,fma")]
+    pub(crate) unsafe fn load_2_blocks(q0: *const i8, q1: *const i8) -> __m512i {
+        let q0 = _mm256_loadu_si256(q0 as *const __m256i);
+        let q1 = _mm256_loadu_si256(q1 as *const __m256i);
+        _mm512_inserti64x4(_mm512_castsi256_si512(q0), q1, 1)
+    }
+
+    /// there's no sign_epi8 on avx512, so the y values are negated on the negative x values.
+    #[inline]
+    #[target_feature(enable = "avx512f,avx512bw,avx2,fma")]
+    pub(crate) unsafe fn mul_sum_i8_pairs_float(x: __m512i, y: __m512i) -> __m512 {
+        let ax = _mm512_abs_epi8(x);
+        let sy = _mm512_mask_sub_epi8(y, _mm512_movepi8_mask(x), _mm512_setzero_si512(), y);
+        let dot = _mm512_maddubs_epi16(ax, sy);
+        let summed_pairs = _mm512_madd_epi16(_mm512_set1_epi16(1), dot);
+        _mm512_cvtepi32_ps(summed_pairs)
+    }
+}
+
+/// pick the kernels by the simd instructions of the cpu on the runtime.
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64 {
+    use super::impl_fallback;
+    use super::impl_x86_64_avx2;
+    use super::impl_x86_64_avx512;
+    use super::BlockQ8_0;
+    use crate::backends::cpu::arch::X86Simd;
+
+    pub fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
+        match X86Simd::detect() {
+            X86Simd::Avx512 => unsafe { impl_x86_64_avx512::quantize_f32_q8_0(data) },
+            X86Simd::Avx2 => unsafe { impl_x86_64_avx2::quantize_f32_q8_0(data) },
+            X86Simd::Fallback => impl_fallback::quantize_f32_q8_0(data),
+        }
+    }
+
+    pub fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
+
+        match X86Simd::detect() {
+            X86Simd::Avx512 => unsafe { impl_x86_64_avx512::vec_dot_q8_0_q8_0(abs, bbs) },
+            X86Simd::Avx2 => unsafe { impl_x86_64_avx2::vec_dot_q8_0_q8_0(abs, bbs) },
+            X86Simd::Fallback => impl_fallback::vec_dot_q8_0_q8_0(abs, bbs),
+        }
+    }
+}
+#[cfg(target_arch = "x86_64")]
+use impl_x86_64::*;
+
+#[cfg(not(all(target_arch = "aarch64", target_feature = "neon")))]
 mod impl_fallback {
     use half::f16;
 
@@ -411,7 +552,7 @@ mod impl_fallback {
 }
 #[cfg(not(any(
     all(target_arch = "aarch64", target_feature = "neon"),
-    all(target_arch = "x86_64", target_feature = "avx2")
+    target_arch = "x86_64"
 )))]
 use impl_fallback::*;
 
@@ -451,6 +592,23 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_quantize_q8_0() {
+        let data = (0..64).map(|i| (i as f32 - 30.0) / 4.0).collect::<Vec<_>>();
+        let bf = QuantBufQ8_0::quantize(&data);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.blocks[0].d.to_f32(), f16::from_f32(7.5 / 127.0).to_f32());
+        assert_eq!(
+            bf.blocks[1].d.to_f32(),
+            f16::from_f32(8.25 / 127.0).to_f32()
+        );
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.07, "got {} want {}", got, want);
+        }
+    }
+
     #[test]
     fn test_vec_dot_q8_0_q8_0() {
         let tests = vec![
@@ -517,4 +675,87 @@ mod tests {
             assert_eq!(result, expect, "test: {}", name);
         }
     }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_simd_x86_64() {
+        use crate::backends::cpu::arch::X86Simd;
+
+        let a = (0..160)
+            .map(|i| ((i * 7 % 31) as f32 - 15.0) / 8.0)
+            .collect::<Vec<_>>();
+        let b = (0..160)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+        let qa = impl_fallback::quantize_f32_q8_0(&a);
+        let qb = impl_fallback::quantize_f32_q8_0(&b);
+        let want = impl_fallback::vec_dot_q8_0_q8_0(&qa, &qb);
+
+        // the simd kernels round the quants to the nearest, while the fallback truncates
+        let simd = X86Simd::detect();
+        if simd >= X86Simd::Avx2 {
+            let got = unsafe { impl_x86_64_avx2::vec_dot_q8_0_q8_0(&qa, &qb) };
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+            let got = unsafe { impl_x86_64_avx2::quantize_f32_q8_0(&a) };
+            for (got, want) in got.iter().zip(qa.iter()) {
+                assert_eq!({ got.d }, { want.d });
+                for (got, want) in { got.qs }.iter().zip({ want.qs }.iter()) {
+                    assert!((got - want).abs() <= 1, "got {} want {}", got, want);
+                }
+            }
+        }
+        if simd >= X86Simd::Avx512 {
+            let got = unsafe { impl_x86_64_avx512::vec_dot_q8_0_q8_0(&qa, &qb) };
+            assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+            let got = unsafe { impl_x86_64_avx512::quantize_f32_q8_0(&a) };
+            let want = unsafe { impl_x86_64_avx2::quantize_f32_q8_0(&a) };
+            for (got, want) in got.iter().zip(want.iter()) {
+                assert_eq!({ got.d }, { want.d });
+                assert_eq!({ got.qs }, { want.qs });
+            }
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    #[test]
+    fn test_simd_aarch64() {
+        use crate::backends::cpu::arch::ArmSimd;
+
+        let a = (0..160)
+            .map(|i| ((i * 7 % 31) as f32 - 15.0) / 8.0)
+            .collect::<Vec<_>>();
+        let b = (0..160)
+            .map(|i| ((i * 5 % 17) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+        let qa = quantize_f32_q8_0(&a);
+        let qb = quantize_f32_q8_0(&b);
+        let want = vec_dot_scalar(&qa, &qb);
+
+        let got = impl_aarch64_neon::vec_dot_q8_0_q8_0(&qa, &qb);
+        assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+        if ArmSimd::detect() >= ArmSimd::Dotprod {
+            // both of the rolled and the unrolled loops
+            for n in [5, 4] {
+                let got = unsafe { impl_aarch64_dotprod::vec_dot_q8_0_q8_0(&qa[..n], &qb[..n]) };
+                let want = vec_dot_scalar(&qa[..n], &qb[..n]);
+                assert!((got - want).abs() < 1e-3, "got {} want {}", got, want);
+            }
+        }
+    }
+
+    /// the scalar dot product, the fallback kernel is not built on aarch64.
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    fn vec_dot_scalar(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        abs.iter()
+            .zip(bbs.iter())
+            .map(|(a, b)| {
+                let sumi =
+                    a.qs.iter()
+                        .zip(b.qs.iter())
+                        .map(|(x, y)| *x as i32 * *y as i32)
+                        .sum::<i32>();
+                sumi as f32 * a.d.to_f32() * b.d.to_f32()
+            })
+            .sum()
+    }
 }
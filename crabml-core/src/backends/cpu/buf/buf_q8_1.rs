@@ -0,0 +1,114 @@
+use half::f16;
+
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// Q8_1 is the same as Q8_0, except that it also keeps `s = d * sum(qs)`. it's used to
+/// quantize the activation before doing dot product with Q4_1 and Q5_1, the `s` is used to
+/// apply the min of the other operand in one multiplication.
+#[repr(C, packed)]
+#[derive(Debug, Clone)]
+pub struct BlockQ8_1 {
+    pub d: f16,       // delta
+    pub s: f16,       // d * sum(qs[i])
+    pub qs: [i8; 32], // quants
+}
+
+impl BlockQuant for BlockQ8_1 {
+    const DTYPE: GGMLType = GGMLType::Q8_1;
+    const BLOCK_ELEMS: usize = 32;
+    type VecDotBlock = BlockQ8_1;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (i, v) in buf.iter_mut().enumerate().take(32) {
+            *v = self.qs[i] as f32 * d;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        abs.iter()
+            .zip(bbs.iter())
+            .map(|(a, b)| {
+                let sumi =
+                    a.qs.iter()
+                        .zip(b.qs.iter())
+                        .map(|(x, y)| *x as i32 * *y as i32)
+                        .sum::<i32>();
+                sumi as f32 * a.d.to_f32() * b.d.to_f32()
+            })
+            .sum()
+    }
+}
+
+pub type QuantBufQ8_1<'a> = QuantBuf<'a, BlockQ8_1>;
+
+impl QuantBufQ8_1<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        assert_eq!(
+            data.len() % 32,
+            0,
+            "data length must be a multiple of 32, got: {}",
+            data.len()
+        );
+        Self::from_blocks(quantize_f32_q8_1(data))
+    }
+}
+
+pub fn quantize_f32_q8_1(data: &[f32]) -> Vec<BlockQ8_1> {
+    let mut bs = Vec::with_capacity(data.len() / 32);
+
+    for chunk in data.chunks(32) {
+        let amax = chunk.iter().fold(0.0_f32, |m, v| m.max(v.abs()));
+        let d = amax / 127.0;
+        let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+
+        let mut qs = [0_i8; 32];
+        let mut sum = 0;
+        for (q, v) in qs.iter_mut().zip(chunk.iter()) {
+            *q = (v * id).round() as i8;
+            sum += *q as i32;
+        }
+
+        bs.push(BlockQ8_1 {
+            d: f16::from_f32(d),
+            s: f16::from_f32(sum as f32 * d),
+            qs,
+        });
+    }
+
+    bs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantize_q8_1() {
+        let data = (0..64).map(|i| (i as f32 - 30.0) / 4.0).collect::<Vec<_>>();
+        let bf = QuantBufQ8_1::quantize(&data);
+        assert_eq!(bf.len(), 64);
+        assert_eq!(bf.as_bytes().len(), 72);
+
+        // the max absolute value is mapped to 127 exactly
+        assert_eq!(bf.blocks[0].qs[0], -127);
+        assert_eq!(bf.blocks[1].qs[31], 127);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.04, "got {} want {}", got, want);
+        }
+        for (blk, chunk) in bf.blocks.iter().zip(values.chunks(32)) {
+            let want = chunk.iter().sum::<f32>();
+            let got = blk.s.to_f32();
+            assert!(
+                (got - want).abs() < want.abs() * 1e-3,
+                "got {} want {}",
+                got,
+                want
+            );
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use super::BlockQuant;
+use super::QuantBuf;
+use crate::gguf::GGMLType;
+
+/// the number of elements in a super block of the k-quants.
+pub const QK_K: usize = 256;
+
+/// Q8_K is used to quantize the activation before doing dot product with the k-quants,
+/// it's never stored in a GGUF file. besides the quants, it also keeps the sum of every
+/// 16 quants to speed up the dot product with the blocks which have mins.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BlockQ8K {
+    pub d: f32,           // delta
+    pub qs: [i8; QK_K],   // quants
+    pub bsums: [i16; 16], // sum of the quants in groups of 16
+}
+
+impl BlockQuant for BlockQ8K {
+    const DTYPE: GGMLType = GGMLType::Q8K;
+    const BLOCK_ELEMS: usize = QK_K;
+    type VecDotBlock = BlockQ8K;
+
+    fn dequantize(&self, buf: &mut [f32]) {
+        for (i, v) in buf.iter_mut().enumerate().take(QK_K) {
+            *v = self.qs[i] as f32 * self.d;
+        }
+    }
+
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32 {
+        abs.iter()
+            .zip(bbs.iter())
+            .map(|(a, b)| a.d * b.d * vec_dot_i8_i8(&a.qs, &b.qs) as f32)
+            .sum()
+    }
+}
+
+pub type QuantBufQ8K<'a> = QuantBuf<'a, BlockQ8K>;
+
+impl QuantBufQ8K<'_> {
+    pub fn quantize(data: &[f32]) -> Self {
+        assert_eq!(
+            data.len() % QK_K,
+            0,
+            "data length must be a multiple of {}, got: {}",
+            QK_K,
+            data.len()
+        );
+        Self::from_blocks(quantize_f32_q8_k(data))
+    }
+}
+
+pub fn quantize_f32_q8_k(data: &[f32]) -> Vec<BlockQ8K> {
+    let mut bs = Vec::with_capacity(data.len() / QK_K);
+
+    for chunk in data.chunks(QK_K) {
+        // find the value with the max absolute value, the sign is kept so that the
+        // extreme value is mapped to -128 exactly
+        let mut max_abs_value = 0.0;
+        let mut max_value = 0.0;
+        for &value in chunk {
+            if value.abs() > max_abs_value {
+                max_abs_value = value.abs();
+                max_value = value;
+            }
+        }
+
+        let mut blk = BlockQ8K {
+            d: 0.0,
+            qs: [0; QK_K],
+            bsums: [0; 16],
+        };
+        if max_abs_value == 0.0 {
+            bs.push(blk);
+            continue;
+        }
+
+        let iscale = -128.0 / max_value;
+        for (q, &value) in blk.qs.iter_mut().zip(chunk.iter()) {
+            *q = ((iscale * value).round() as i32).min(127) as i8;
+        }
+        for (sum, qs) in blk.bsums.iter_mut().zip(blk.qs.chunks(16)) {
+            *sum = qs.iter().map(|&q| q as i16).sum();
+        }
+        blk.d = 1.0 / iscale;
+        bs.push(blk);
+    }
+
+    bs
+}
+
+/// the integer dot product shared by the k-quants kernels. the quants of the k-quants are
+/// unpacked into i8 before calling this, the loop is simple enough to be vectorized by
+/// the compiler.
+#[inline]
+pub fn vec_dot_i8_i8(a: &[i8], b: &[i8]) -> i32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(&a, &b)| a as i32 * b as i32)
+        .sum()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// generate the bytes of the quantized blocks for testing, the scales in f16 should be
+    /// overwritten to avoid NaN or Inf.
+    pub fn pseudo_random_bytes(n: usize) -> Vec<u8> {
+        let mut seed: u32 = 42;
+        (0..n)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// the Q8_K blocks to check the dot products against the reference values computed by ggml,
+    /// the quants are `(i * 37 % 255) - 127` of the element i, with the scale 0.25.
+    pub fn reference_q8_k_blocks(n: usize) -> Vec<BlockQ8K> {
+        (0..n)
+            .map(|b| {
+                let mut qs = [0_i8; QK_K];
+                for (i, q) in qs.iter_mut().enumerate() {
+                    *q = (((b * QK_K + i) * 37 % 255) as i32 - 127) as i8;
+                }
+                BlockQ8K {
+                    d: 0.25,
+                    qs,
+                    bsums: [0; 16],
+                }
+            })
+            .collect()
+    }
+
+    /// check the dot product of a k-quants buffer with a Q8_K buffer against the dot product
+    /// on the dequantized values.
+    pub fn assert_vec_dot_k(a: &[f32], vec_dot: impl Fn(&QuantBufQ8K) -> f32) {
+        let b = (0..a.len())
+            .map(|i| ((i * 13 % 29) as f32 - 14.0) / 7.0)
+            .collect::<Vec<_>>();
+        let qb = QuantBufQ8K::quantize(&b);
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+
+        let want = a.iter().zip(db.iter()).map(|(a, b)| a * b).sum::<f32>();
+        let scale = a
+            .iter()
+            .zip(db.iter())
+            .map(|(a, b)| (a * b).abs())
+            .sum::<f32>();
+        let got = vec_dot(&qb);
+        assert!(
+            (got - want).abs() <= scale * 1e-5,
+            "got {} want {}",
+            got,
+            want
+        );
+    }
+
+    #[test]
+    fn test_quantize_q8_k() {
+        let data = (0..512)
+            .map(|i| (i as f32 - 200.0) / 16.0)
+            .collect::<Vec<_>>();
+        let bf = QuantBufQ8K::quantize(&data);
+        assert_eq!(bf.len(), 512);
+        assert_eq!(bf.blocks[0].qs[0], -128);
+        assert!((bf.blocks[0].d - 12.5 / 128.0).abs() < 1e-6);
+
+        let values = bf.dequantize(0).collect::<Vec<_>>();
+        for (got, want) in values.iter().zip(data.iter()) {
+            assert!((got - want).abs() <= 0.1, "got {} want {}", got, want);
+        }
+        for blk in bf.blocks.iter() {
+            for (j, sum) in blk.bsums.iter().enumerate() {
+                let want = blk.qs[j * 16..(j + 1) * 16]
+                    .iter()
+                    .map(|&q| q as i16)
+                    .sum::<i16>();
+                assert_eq!(*sum, want);
+            }
+        }
+
+        let bf = QuantBufQ8K::quantize(&[0.0; 256]);
+        assert!(bf.dequantize(0).all(|v| v == 0.0));
+        assert_eq!(vec_dot_i8_i8(&[1, -2, 3], &[4, 5, -6]), -24);
+    }
+}
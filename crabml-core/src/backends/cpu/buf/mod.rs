@@ -1,7 +1,59 @@
 pub mod api;
 pub use api::CpuTensorBuf;
 
+pub mod buf_f16;
 pub mod buf_f32;
 
+pub mod quant;
+pub use quant::BlockQuant;
+pub use quant::QuantBuf;
+
+pub mod buf_q4_0;
+pub use buf_q4_0::QuantBufQ4_0;
+
+pub mod buf_q4_1;
+pub use buf_q4_1::QuantBufQ4_1;
+
+pub mod buf_q5_0;
+pub use buf_q5_0::QuantBufQ5_0;
+
+pub mod buf_q5_1;
+pub use buf_q5_1::QuantBufQ5_1;
+
 pub mod buf_q8_0;
 pub use buf_q8_0::QuantBufQ8_0;
+
+pub mod buf_q8_1;
+pub use buf_q8_1::QuantBufQ8_1;
+
+pub mod buf_q2_k;
+pub use buf_q2_k::QuantBufQ2K;
+
+pub mod buf_q3_k;
+pub use buf_q3_k::QuantBufQ3K;
+
+pub mod buf_q4_k;
+pub use buf_q4_k::QuantBufQ4K;
+
+pub mod buf_q5_k;
+pub use buf_q5_k::QuantBufQ5K;
+
+pub mod buf_q6_k;
+pub use buf_q6_k::QuantBufQ6K;
+
+pub mod buf_q8_k;
+pub use buf_q8_k::QuantBufQ8K;
+
+pub mod buf_iq4_nl;
+pub use buf_iq4_nl::QuantBufIQ4NL;
+
+pub mod iq_grids;
+
+pub mod buf_iq2_xxs;
+pub use buf_iq2_xxs::QuantBufIQ2XXS;
+
+pub mod buf_iq2_xs;
+pub use buf_iq2_xs::QuantBufIQ2XS;
+
+pub mod buf_iq3_xxs;
+pub use buf_iq3_xxs::QuantBufIQ3XXS;
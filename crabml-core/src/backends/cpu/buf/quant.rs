@@ -0,0 +1,127 @@
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+use crate::gguf::GGMLType;
+
+/// the max number of elements in a block among all the quantized types.
+const MAX_BLOCK_ELEMS: usize = 256;
+
+/// BlockQuant is implemented by the blocks of all the quantized types. the layout of the
+/// block must be the same as ggml, so that the tensor data in GGUF files can be used
+/// without copying.
+///
+/// the dot product of a quantized buffer is always taken with the activation which is
+/// quantized into `VecDotBlock`, to add a new quantized type, it only needs to implement
+/// this trait and register a variant in `CpuTensorBuf`.
+pub trait BlockQuant: Debug + Clone + Send + Sync {
+    /// the GGML type of the block.
+    const DTYPE: GGMLType;
+
+    /// the number of elements in a block.
+    const BLOCK_ELEMS: usize;
+
+    /// the block type of the other operand in `vec_dot`.
+    type VecDotBlock: BlockQuant;
+
+    /// dequantize the block into `buf`, which has at least `BLOCK_ELEMS` elements.
+    fn dequantize(&self, buf: &mut [f32]);
+
+    /// the dot product of two slices of blocks, both slices have the same number of elements.
+    fn vec_dot(abs: &[Self], bbs: &[Self::VecDotBlock]) -> f32;
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantBuf<'a, B: BlockQuant> {
+    pub blocks: Cow<'a, [B]>,
+}
+
+impl<'a, B: BlockQuant> QuantBuf<'a, B> {
+    pub fn from_bytes(data: &'a [u8]) -> Self {
+        let blk_size = std::mem::size_of::<B>();
+        assert_eq!(
+            data.len() % blk_size,
+            0,
+            "data length must be a multiple of {} block size {}",
+            B::DTYPE,
+            blk_size
+        );
+        assert_eq!(
+            data.as_ptr() as usize % std::mem::align_of::<B>(),
+            0,
+            "data must be aligned to {} blocks",
+            B::DTYPE
+        );
+        let blocks =
+            unsafe { std::slice::from_raw_parts(data.as_ptr() as *const B, data.len() / blk_size) };
+        Self {
+            blocks: blocks.into(),
+        }
+    }
+
+    pub fn from_blocks(blocks: Vec<B>) -> Self {
+        Self {
+            blocks: blocks.into(),
+        }
+    }
+
+    pub fn blocks(&self) -> &[B] {
+        &self.blocks
+    }
+
+    /// copy the borrowed blocks, like the ones on the mmaped GGUF file.
+    pub fn into_owned(self) -> QuantBuf<'static, B>
+    where B: 'static {
+        QuantBuf {
+            blocks: Cow::Owned(self.blocks.into_owned()),
+        }
+    }
+
+    /// the raw bytes of the blocks, which is the same layout as the tensor data in GGUF files.
+    pub fn as_bytes(&self) -> &[u8] {
+        let blocks = self.blocks();
+        unsafe {
+            std::slice::from_raw_parts(blocks.as_ptr() as *const u8, std::mem::size_of_val(blocks))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len() * B::BLOCK_ELEMS
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn dtype(&self) -> GGMLType {
+        B::DTYPE
+    }
+
+    pub fn vec_dot_dtype(&self) -> GGMLType {
+        B::VecDotBlock::DTYPE
+    }
+
+    pub fn dequantize(&'a self, start: usize) -> impl Iterator<Item = f32> + 'a {
+        assert_eq!(start % B::BLOCK_ELEMS, 0);
+
+        let block_start = start / B::BLOCK_ELEMS;
+        self.blocks()[block_start..].iter().flat_map(|blk| {
+            let mut buf = [0.0; MAX_BLOCK_ELEMS];
+            blk.dequantize(&mut buf);
+            buf.into_iter().take(B::BLOCK_ELEMS)
+        })
+    }
+
+    pub fn vec_dot(
+        &self,
+        a_offset: usize,
+        b: &QuantBuf<B::VecDotBlock>,
+        b_offset: usize,
+        len: usize,
+    ) -> f32 {
+        let abs = &self.blocks[a_offset / B::BLOCK_ELEMS..(a_offset + len) / B::BLOCK_ELEMS];
+        let bbs = &b.blocks()[b_offset / B::VecDotBlock::BLOCK_ELEMS
+            ..(b_offset + len) / B::VecDotBlock::BLOCK_ELEMS];
+
+        B::vec_dot(abs, bbs)
+    }
+}
@@ -1,26 +1,50 @@
+use std::cell::Ref;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use half::f16;
 
+use super::arena::CpuTensorArena;
+use super::arena::DEFAULT_ARENA_BYTES;
 use super::CpuTensor;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::profiler::TensorProfileGuard;
 use crate::tensor::TensorDeviceMetrics;
+use crate::tensor::TensorProfiler;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct CpuTensorDeviceOptions {
     /// when enabled, whenever tensor called with `with_name`, the name and the
     /// tensor will be recorded in the device. only used in test.
     pub debug_named_tensors: bool,
+
+    /// the bytes of the free activation buffers kept for reusing, 0 to disable the arena.
+    pub arena_bytes: usize,
+}
+
+impl Default for CpuTensorDeviceOptions {
+    fn default() -> Self {
+        Self {
+            debug_named_tensors: false,
+            arena_bytes: DEFAULT_ARENA_BYTES,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct CpuTensorDevice<'a> {
     pub(crate) opts: CpuTensorDeviceOptions,
     pub(crate) metrics: TensorDeviceMetrics,
+    pub(crate) profiler: Option<TensorProfiler>,
     pub(crate) debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
     pub(crate) wbuf: RefCell<Option<Vec<f32>>>,
+    pub(crate) arena: RefCell<CpuTensorArena>,
     pub(crate) exp_cache: Vec<f16>,
+    pub(crate) thread_pool: Option<Arc<rayon::ThreadPool>>, // the global pool of rayon if none
     _phantom: std::marker::PhantomData<&'a ()>,
 }
 
@@ -28,12 +52,16 @@ pub type CpuTensorDeviceRef<'a> = Rc<CpuTensorDevice<'a>>;
 
 impl<'a> CpuTensorDevice<'a> {
     pub fn new() -> CpuTensorDeviceRef<'a> {
+        let opts = CpuTensorDeviceOptions::default();
         let device = Self {
-            opts: CpuTensorDeviceOptions::default(),
+            arena: RefCell::new(CpuTensorArena::new(opts.arena_bytes)),
+            opts,
             debug_tensors: RefCell::new(HashMap::new()),
             metrics: TensorDeviceMetrics::default(),
+            profiler: None,
             wbuf: RefCell::new(Some(vec![0.0; 32000])),
             exp_cache: Self::init_exp_cache(),
+            thread_pool: None,
             _phantom: std::marker::PhantomData,
         };
         Rc::new(device)
@@ -41,11 +69,14 @@ impl<'a> CpuTensorDevice<'a> {
 
     pub fn with_options(opts: CpuTensorDeviceOptions) -> CpuTensorDeviceRef<'a> {
         let device = Self {
+            arena: RefCell::new(CpuTensorArena::new(opts.arena_bytes)),
             opts,
             debug_tensors: RefCell::new(HashMap::new()),
             metrics: TensorDeviceMetrics::default(),
+            profiler: None,
             wbuf: RefCell::new(Some(vec![0.0; 32000])),
             exp_cache: Self::init_exp_cache(),
+            thread_pool: None,
             _phantom: std::marker::PhantomData,
         };
         Rc::new(device)
@@ -56,17 +87,103 @@ impl<'a> CpuTensorDevice<'a> {
             opts: self.opts.clone(),
             debug_tensors: self.debug_tensors.clone(),
             wbuf: self.wbuf.clone(),
+            arena: RefCell::new(CpuTensorArena::new(self.opts.arena_bytes)),
             exp_cache: self.exp_cache.clone(),
+            thread_pool: self.thread_pool.clone(),
             metrics,
+            profiler: self.profiler.clone(),
+            _phantom: std::marker::PhantomData,
+        };
+        Rc::new(device)
+    }
+
+    /// run the matmuls on a pool of `threads` threads instead of the global pool of rayon,
+    /// the number of the cpus is taken if `threads` is 0.
+    ///
+    /// the threads can't be spawned on wasm32, where the global pool of rayon runs the jobs
+    /// on the calling thread, so `threads` is ignored there.
+    pub fn with_threads(self: Rc<Self>, threads: usize) -> Result<CpuTensorDeviceRef<'a>> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map(|pool| Some(Arc::new(pool)))
+            .map_err(|err| Error {
+                kind: ErrorKind::Unexpected,
+                message: format!("failed to build the thread pool of {} threads", threads),
+                cause: Some(Box::new(err)),
+            })?;
+        #[cfg(target_arch = "wasm32")]
+        let thread_pool = None;
+        let device = Self {
+            opts: self.opts.clone(),
+            debug_tensors: self.debug_tensors.clone(),
+            wbuf: self.wbuf.clone(),
+            arena: RefCell::new(CpuTensorArena::new(self.opts.arena_bytes)),
+            exp_cache: self.exp_cache.clone(),
+            thread_pool,
+            metrics: self.metrics.clone(),
+            profiler: self.profiler.clone(),
+            _phantom: std::marker::PhantomData,
+        };
+        Ok(Rc::new(device))
+    }
+
+    /// record the walltime, the flops and the bytes moved of each op into the profiler.
+    pub fn with_profiler(self: Rc<Self>, profiler: TensorProfiler) -> CpuTensorDeviceRef<'a> {
+        let device = Self {
+            opts: self.opts.clone(),
+            debug_tensors: self.debug_tensors.clone(),
+            wbuf: self.wbuf.clone(),
+            arena: RefCell::new(CpuTensorArena::new(self.opts.arena_bytes)),
+            exp_cache: self.exp_cache.clone(),
+            thread_pool: self.thread_pool.clone(),
+            metrics: self.metrics.clone(),
+            profiler: Some(profiler),
             _phantom: std::marker::PhantomData,
         };
         Rc::new(device)
     }
 
+    /// the number of the threads taken by the matmuls.
+    pub fn threads(&self) -> usize {
+        match &self.thread_pool {
+            Some(pool) => pool.current_num_threads(),
+            None => rayon::current_num_threads(),
+        }
+    }
+
+    /// run the parallel iterators in `op` on the thread pool of the device.
+    pub(crate) fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.thread_pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
     pub fn metrics(&self) -> &TensorDeviceMetrics {
         &self.metrics
     }
 
+    pub fn profiler(&self) -> Option<&TensorProfiler> {
+        self.profiler.as_ref()
+    }
+
+    /// track the op on the profiler if there's one, until the guard is dropped.
+    pub(crate) fn profile(
+        &self,
+        op: &'static str,
+        flops: usize,
+        bytes: usize,
+    ) -> Option<TensorProfileGuard> {
+        self.profiler.as_ref().map(|p| p.track(op, flops, bytes))
+    }
+
+    /// the arena of the activation buffers, like its hits and misses.
+    pub fn arena(&self) -> Ref<'_, CpuTensorArena> {
+        self.arena.borrow()
+    }
+
     pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
         self.debug_tensors.borrow().get(name).cloned()
     }
@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use super::CpuTensor;
+use super::CpuTensorBuf;
+use super::CpuTensorDeviceRef;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::gguf::GGUFFile;
+use crate::gguf::GGUFTensorInfo;
+
+/// the elements dequantized in a job of `preload_f32`, the large tensors like the embedding
+/// table are split into the chunks of rows to keep all the threads busy.
+const PRELOAD_CHUNK_ELEMS: usize = 1 << 18;
+
+/// CpuTensorLoader hands out the tensors of a GGUF file to the model builder by name.
+///
+/// Nothing is read on creation besides the tensor infos: the data of a tensor is only
+/// touched when it's requested, as a zero-copy view on the mmaped file, and it's only
+/// dequantized when requested with `load_as`. This allows loading a part of the model,
+/// like the embedding table only, without paging in the full weights. The tensors to be
+/// dequantized can be requested ahead with `preload_f32`, which dequantizes them in parallel.
+pub struct CpuTensorLoader<'a> {
+    gf: &'a GGUFFile<'a>,
+    device: CpuTensorDeviceRef<'a>,
+    tensor_infos: HashMap<&'a str, &'a GGUFTensorInfo<'a>>,
+    loaded_tensors: RefCell<Vec<String>>,
+    preloaded: RefCell<HashMap<String, CpuTensor<'a>>>,
+}
+
+impl<'a> CpuTensorLoader<'a> {
+    pub fn new(gf: &'a GGUFFile<'a>, device: CpuTensorDeviceRef<'a>) -> Self {
+        let tensor_infos = gf
+            .tensor_infos()
+            .iter()
+            .map(|info| (info.name(), info))
+            .collect();
+        Self {
+            gf,
+            device,
+            tensor_infos,
+            loaded_tensors: RefCell::new(vec![]),
+            preloaded: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn gguf_file(&self) -> &'a GGUFFile<'a> {
+        self.gf
+    }
+
+    pub fn device(&self) -> CpuTensorDeviceRef<'a> {
+        self.device.clone()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tensor_infos.contains_key(name)
+    }
+
+    /// the names of the tensors which have been requested so far, in the order of the requests.
+    pub fn loaded_tensors(&self) -> Vec<String> {
+        self.loaded_tensors.borrow().clone()
+    }
+
+    /// load the tensor in the type stored in the file without copying.
+    pub fn load(&self, name: &str) -> Result<CpuTensor<'a>> {
+        let info = match self.tensor_infos.get(name) {
+            Some(info) => info,
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::IOError,
+                    message: format!("failed to find tensor {}", name),
+                    cause: None,
+                });
+            }
+        };
+
+        // the dimensions stored in GGUF seems in a reverse order of numpy's shape
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        let tensor = CpuTensor::from_bytes(info.data(), info.typ(), &dims, self.device.clone())?;
+        self.mark_loaded(name);
+        Ok(tensor)
+    }
+
+    fn mark_loaded(&self, name: &str) {
+        let mut loaded_tensors = self.loaded_tensors.borrow_mut();
+        if !loaded_tensors.iter().any(|n| n == name) {
+            loaded_tensors.push(name.to_string());
+        }
+    }
+
+    /// load the stacked tensor of the experts like `blk.0.ffn_gate_exps.weight`, whose first
+    /// dimension is the count of the experts, as the tensors of each expert without copying.
+    pub fn load_experts(&self, name: &str) -> Result<Vec<CpuTensor<'a>>> {
+        let tensor = self.load(name)?;
+        let info = self.tensor_infos[name];
+        let shape = tensor.shape();
+        if shape.len() != 3 || info.data().len() % shape[0] != 0 {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!("unexpected shape of the experts {}: {:?}", name, shape),
+                cause: None,
+            });
+        }
+
+        let n_experts = shape[0];
+        let expert_shape = shape[1..].to_vec();
+        info.data()
+            .chunks_exact(info.data().len() / n_experts)
+            .map(|buf| CpuTensor::from_bytes(buf, info.typ(), &expert_shape, self.device.clone()))
+            .collect()
+    }
+
+    /// load the fused tensor like `blk.0.attn_qkv.weight` as the tensors of the given rows on
+    /// the first dimension without copying, like the q, k, v projections of phi.
+    pub fn load_split(&self, name: &str, rows: &[usize]) -> Result<Vec<CpuTensor<'a>>> {
+        let tensor = self.load(name)?;
+        let info = self.tensor_infos[name];
+        let shape = tensor.shape();
+        if rows.iter().sum::<usize>() != shape[0] || info.data().len() % shape[0] != 0 {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!(
+                    "failed to split {} of shape {:?} by {:?}",
+                    name, shape, rows
+                ),
+                cause: None,
+            });
+        }
+
+        let row_bytes = info.data().len() / shape[0];
+        let mut offset = 0;
+        rows.iter()
+            .map(|n_rows| {
+                let buf = &info.data()[offset * row_bytes..(offset + n_rows) * row_bytes];
+                offset += n_rows;
+                let shape = [&[*n_rows], &shape[1..]].concat();
+                CpuTensor::from_bytes(buf, info.typ(), &shape, self.device.clone())
+            })
+            .collect()
+    }
+
+    /// load the tensor and dequantize it into the given type, the dequantization happens
+    /// on each call, so the caller is expected to keep the returned tensor. the tensor
+    /// dequantized by `preload_f32` is taken instead if there's one.
+    pub fn load_as(&self, name: &str, typ: GGMLType) -> Result<CpuTensor<'a>> {
+        if typ == GGMLType::F32 {
+            if let Some(tensor) = self.preloaded.borrow_mut().remove(name) {
+                self.mark_loaded(name);
+                return Ok(tensor);
+            }
+        }
+        self.load(name)?.dequantize(typ)
+    }
+
+    /// dequantize the tensors into f32 on the thread pool of the device ahead of `load_as`,
+    /// which takes most of the startup besides paging in the weights, like the embedding
+    /// table of a quantized model. the chunks of the tensors are dequantized in parallel,
+    /// then assembled in the order of the names, so the first error in the order is
+    /// returned. the tensors already in f32 are skipped, as they're loaded without copying.
+    pub fn preload_f32(&self, names: &[&str]) -> Result<()> {
+        let mut infos = vec![];
+        for name in names {
+            match self.tensor_infos.get(name) {
+                Some(info) if info.typ() != GGMLType::F32 => infos.push(*info),
+                Some(_) => {}
+                None => {
+                    return Err(Error {
+                        kind: ErrorKind::IOError,
+                        message: format!("failed to find tensor {}", name),
+                        cause: None,
+                    });
+                }
+            }
+        }
+        let _span = tracing::debug_span!("preload_tensors", n_tensors = infos.len()).entered();
+
+        // (tensor index, the bytes of the chunk)
+        let mut chunks = vec![];
+        for (i, info) in infos.iter().enumerate() {
+            let row_len = info.dimensions().first().copied().unwrap_or(1).max(1);
+            let n_rows = info.dimensions().iter().skip(1).product::<usize>().max(1);
+            let row_bytes = info.data().len() / n_rows;
+            let chunk_rows = (PRELOAD_CHUNK_ELEMS / row_len).max(1);
+            for chunk in info.data().chunks(chunk_rows * row_bytes) {
+                chunks.push((i, chunk, info.typ()));
+            }
+        }
+        let dequantize = |chunk: &[u8], typ: GGMLType| -> Result<Vec<f32>> {
+            match CpuTensorBuf::from_raw_bytes(chunk, typ)?.dequantize(GGMLType::F32)? {
+                CpuTensorBuf::F32(buf) => Ok(buf.into_owned()),
+                _ => unreachable!(),
+            }
+        };
+        let bufs = self.device.install(|| {
+            chunks
+                .par_iter()
+                .map(|(_, chunk, typ)| dequantize(chunk, *typ))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut tensor_bufs = vec![vec![]; infos.len()];
+        for ((i, _, _), buf) in chunks.iter().zip(bufs) {
+            tensor_bufs[*i].extend(buf);
+        }
+        let mut preloaded = self.preloaded.borrow_mut();
+        for (info, buf) in infos.iter().zip(tensor_bufs) {
+            let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+            let tensor = CpuTensor::new(buf, &dims, self.device.clone())?;
+            preloaded.insert(info.name().to_string(), tensor);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::CpuTensorDevice;
+    use crate::gguf::GGUFFileLoader;
+    use crate::gguf::GGUFMetadataValue;
+    use crate::gguf::GGUFWriter;
+    use crate::tensor::Tensor;
+
+    #[test]
+    fn test_load_experts() -> Result<()> {
+        let data = (0..12)
+            .flat_map(|v| (v as f32).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut w = GGUFWriter::new();
+        w.add_metadata("general.architecture", GGUFMetadataValue::String("llama"));
+        // 2 experts of (3, 2)
+        w.add_tensor(
+            "blk.0.ffn_up_exps.weight",
+            &[2, 3, 2],
+            GGMLType::F32,
+            &data[..],
+        )?;
+        w.add_tensor("blk.0.ffn_norm.weight", &[12], GGMLType::F32, &data[..])?;
+        let path = std::env::temp_dir().join(format!("crabml-experts-{}.gguf", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        w.write_to_file(&path)?;
+        let gl = GGUFFileLoader::new(&path)?;
+        let gf = gl.open()?;
+        std::fs::remove_file(&path).unwrap();
+        let loader = CpuTensorLoader::new(&gf, CpuTensorDevice::new());
+
+        let experts = loader.load_experts("blk.0.ffn_up_exps.weight")?;
+        assert_eq!(experts.len(), 2);
+        assert_eq!(experts[1].shape(), &[3, 2]);
+        let mut buf = vec![0.0; 6];
+        experts[1].export(&mut buf)?;
+        assert_eq!(buf, &[6.0, 7.0, 8.0, 9.0, 10.0, 11.0]);
+        assert!(loader.load_experts("blk.0.ffn_norm.weight").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_split() -> Result<()> {
+        let data = (0..12)
+            .flat_map(|v| (v as f32).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut w = GGUFWriter::new();
+        w.add_metadata("general.architecture", GGUFMetadataValue::String("phi3"));
+        // the fused rows of (1, 2) + (2, 2) + (3, 2)
+        w.add_tensor("blk.0.attn_qkv.weight", &[2, 6], GGMLType::F32, &data[..])?;
+        w.add_tensor("blk.0.attn_qkv.bias", &[12], GGMLType::F32, &data[..])?;
+        let path = std::env::temp_dir().join(format!("crabml-split-{}.gguf", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        w.write_to_file(&path)?;
+        let gl = GGUFFileLoader::new(&path)?;
+        let gf = gl.open()?;
+        std::fs::remove_file(&path).unwrap();
+        let loader = CpuTensorLoader::new(&gf, CpuTensorDevice::new());
+
+        let qkv = loader.load_split("blk.0.attn_qkv.weight", &[1, 2, 3])?;
+        assert_eq!(qkv[2].shape(), &[3, 2]);
+        let mut buf = vec![0.0; 6];
+        qkv[2].export(&mut buf)?;
+        assert_eq!(buf, &[6.0, 7.0, 8.0, 9.0, 10.0, 11.0]);
+
+        let bias = loader.load_split("blk.0.attn_qkv.bias", &[4, 8])?;
+        assert_eq!(bias[1].shape(), &[8]);
+        assert!(loader.load_split("blk.0.attn_qkv.weight", &[1, 2]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_partial_tensors() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+        let loader = CpuTensorLoader::new(&gf, device);
+
+        assert!(loader.contains("token_embd.weight"));
+        assert!(!loader.contains("not_exists.weight"));
+        assert!(loader.load("not_exists.weight").is_err());
+        assert!(loader.loaded_tensors().is_empty());
+
+        let wq = loader.load("blk.0.attn_q.weight")?;
+        assert_eq!(wq.typ(), GGMLType::Q8_0);
+        assert_eq!(wq.shape(), &[288, 288]);
+        assert!(!wq.is_owned());
+
+        let embd = loader.load_as("token_embd.weight", GGMLType::F32)?;
+        assert_eq!(embd.typ(), GGMLType::F32);
+        assert_eq!(embd.shape(), &[32000, 288]);
+        assert!(embd.is_owned());
+
+        assert_eq!(loader.loaded_tensors(), vec![
+            "blk.0.attn_q.weight",
+            "token_embd.weight"
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preload_f32() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new().with_threads(4)?;
+        let loader = CpuTensorLoader::new(&gf, device);
+
+        let names = ["token_embd.weight", "output_norm.weight"];
+        loader.preload_f32(&names)?;
+        assert!(loader.loaded_tensors().is_empty());
+        assert!(loader.preload_f32(&["not_exists.weight"]).is_err());
+
+        // the embedding table is dequantized in the chunks of rows, the norm is already f32
+        let embd = loader.load_as("token_embd.weight", GGMLType::F32)?;
+        assert_eq!(embd.shape(), &[32000, 288]);
+        let mut got = vec![0.0; 32000 * 288];
+        embd.export(&mut got)?;
+        let mut want = vec![0.0; 32000 * 288];
+        loader
+            .load("token_embd.weight")?
+            .dequantize(GGMLType::F32)?
+            .export(&mut want)?;
+        assert_eq!(got, want);
+
+        let norm = loader.load_as("output_norm.weight", GGMLType::F32)?;
+        assert!(!norm.is_owned());
+        assert_eq!(loader.loaded_tensors(), names);
+        Ok(())
+    }
+}
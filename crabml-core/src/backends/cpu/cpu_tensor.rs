@@ -1,11 +1,16 @@
+use std::borrow::Cow;
+
 use super::CpuTensorDeviceRef;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::buf::QuantBuf;
 use crate::backends::cpu::primitives;
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::tensor::RopeOptions;
 use crate::tensor::Tensor;
+use crate::tensor::TensorBackend;
 use crate::tensor::TensorStrider;
 
 #[derive(Debug, Clone)]
@@ -23,7 +28,7 @@ pub struct CpuTensor<'a> {
 // copying the owned buffer. Feel free to clone() the tensor.
 impl<'a> CpuTensor<'a> {
     pub fn new(buf: Vec<f32>, shape: &[usize], device: CpuTensorDeviceRef<'a>) -> Result<Self> {
-        if buf.len() != shape.iter().product() {
+        if buf.len() != shape.iter().product::<usize>() {
             return Err(Error {
                 kind: ErrorKind::TensorError,
                 message: format!("invalid shape {:?} for data of length {}", shape, buf.len()),
@@ -56,17 +61,19 @@ impl<'a> CpuTensor<'a> {
         })
     }
 
-    pub fn dequantize(self, dtype: GGMLType) -> Result<Self> {
-        let strider = self.strider.clone();
-        let device = self.device.clone();
-        let name = self.name.clone();
-        let buf = self.buf.dequantize(dtype)?;
-        Ok(Self {
-            buf,
-            strider,
-            device,
-            name,
-        })
+    pub fn dequantize(mut self, dtype: GGMLType) -> Result<Self> {
+        // the buffer is moved out, the empty one left is not taken by the arena on dropping
+        let buf = std::mem::replace(&mut self.buf, CpuTensorBuf::F32(Cow::Owned(vec![])));
+        self.buf = buf.dequantize(dtype)?;
+        Ok(self)
+    }
+
+    /// the buffer in f32, the quantized kv cache is dequantized on reading.
+    fn f32_buf(&self) -> Result<Cow<'_, CpuTensorBuf<'a>>> {
+        match self.dtype() {
+            GGMLType::F32 => Ok(Cow::Borrowed(self.buf())),
+            _ => Ok(Cow::Owned(self.buf.clone().dequantize(GGMLType::F32)?)),
+        }
     }
 
     pub fn typ(&self) -> GGMLType {
@@ -117,7 +124,54 @@ impl<'a> Tensor for CpuTensor<'a> {
     type Device = CpuTensorDeviceRef<'a>;
 
     fn alloc(shape: &[usize], _capacity: Option<usize>, device: Self::Device) -> Result<Self> {
-        let buf = vec![0.0; shape.iter().product()];
+        let buf = device.arena.borrow_mut().alloc(shape.iter().product());
+        Self::new(buf, shape, device)
+    }
+
+    fn alloc_cache(
+        shape: &[usize],
+        capacity: Option<usize>,
+        dtype: GGMLType,
+        device: Self::Device,
+    ) -> Result<Self> {
+        let n_blocks = capacity.unwrap_or(0) / 32;
+        let buf = match dtype {
+            GGMLType::F32 => return Self::alloc(shape, capacity, device),
+            GGMLType::Q8_0 => {
+                CpuTensorBuf::Q8_0(QuantBuf::from_blocks(Vec::with_capacity(n_blocks)))
+            }
+            GGMLType::Q4_0 => {
+                CpuTensorBuf::Q4_0(QuantBuf::from_blocks(Vec::with_capacity(n_blocks)))
+            }
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("the kv cache of {} is not supported", dtype),
+                )
+                    .into());
+            }
+        };
+        // the rows are quantized one by one, each row takes the whole blocks
+        let row_len = shape[1..].iter().product::<usize>();
+        if shape[0] != 0 || row_len % 32 != 0 {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "the quantized kv cache should be empty and in rows of 32x, got {:?}",
+                    shape
+                ),
+            )
+                .into());
+        }
+        Ok(Self {
+            buf,
+            strider: TensorStrider::new(shape.to_vec()),
+            device,
+            name: None,
+        })
+    }
+
+    fn from_vec(buf: Vec<f32>, shape: &[usize], device: Self::Device) -> Result<Self> {
         Self::new(buf, shape, device)
     }
 
@@ -125,33 +179,28 @@ impl<'a> Tensor for CpuTensor<'a> {
         self.buf.dtype()
     }
 
-    fn reshape(self, shape: &[usize]) -> Result<Self> {
-        let strider = self.strider.reshape(shape.to_vec())?;
-        Ok(Self {
-            buf: self.buf,
-            strider,
-            device: self.device.clone(),
-            name: None,
-        })
+    fn reshape(mut self, shape: &[usize]) -> Result<Self> {
+        self.strider = self.strider.reshape(shape.to_vec())?;
+        self.name = None;
+        Ok(self)
     }
 
-    fn transpose(self, dims: &[usize]) -> Result<Self> {
-        let strider = self.strider.transpose(dims)?;
-        Ok(Self {
-            buf: self.buf,
-            strider,
-            device: self.device.clone(),
-            name: None,
-        })
+    fn transpose(mut self, dims: &[usize]) -> Result<Self> {
+        self.strider = self.strider.transpose(dims)?;
+        self.name = None;
+        Ok(self)
     }
 
-    fn with_strider(self, strider: TensorStrider) -> Result<Self> {
-        Ok(Self {
-            buf: self.buf,
-            strider,
-            device: self.device.clone(),
-            name: None,
-        })
+    fn slice(mut self, axis: usize, start: usize, len: usize) -> Result<Self> {
+        self.strider = self.strider.slice(axis, start, len)?;
+        self.name = None;
+        Ok(self)
+    }
+
+    fn with_strider(mut self, strider: TensorStrider) -> Result<Self> {
+        self.strider = strider;
+        self.name = None;
+        Ok(self)
     }
 
     fn with_name(mut self, name: String) -> Self {
@@ -197,6 +246,31 @@ impl<'a> Tensor for CpuTensor<'a> {
         Ok(())
     }
 
+    fn set_row(&mut self, row: usize, t: &CpuTensor<'a>) -> Result<()> {
+        if !self.is_owned() {
+            return Err((ErrorKind::TensorError, "not owned").into());
+        }
+        if !self.is_contiguous() {
+            return Err((ErrorKind::TensorError, "not contiguous").into());
+        }
+        if !t.shape().eq(&self.shape()[1..]) || row >= self.shape()[0] {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "shape mismatch on set_row {}, want {:?} but got {:?}",
+                    row,
+                    &self.shape(),
+                    &t.shape()
+                ),
+            )
+                .into());
+        }
+
+        let row_len = t.len();
+        self.buf.overwrite(row * row_len, t.buf.iter_f32());
+        Ok(())
+    }
+
     fn repeat_n(self, n: usize) -> Result<Self> {
         assert!(self.is_owned());
         assert!(self.is_contiguous());
@@ -222,7 +296,9 @@ impl<'a> Tensor for CpuTensor<'a> {
         if !src.is_contiguous() {
             return Err((ErrorKind::TensorError, "src tensor is not contiguous").into());
         }
-        if self.dtype() != src.dtype() {
+        // the quantized kv caches are dequantized on copying
+        let is_cache = matches!(src.dtype(), GGMLType::Q8_0 | GGMLType::Q4_0) && src.is_owned();
+        if self.dtype() != src.dtype() && !(self.dtype() == GGMLType::F32 && is_cache) {
             return Err((
                 ErrorKind::TensorError,
                 format!(
@@ -239,34 +315,132 @@ impl<'a> Tensor for CpuTensor<'a> {
     }
 
     fn dup(&self) -> Result<Self> {
+        // the quantized kv caches are duplicated as they are
+        if self.dtype() != GGMLType::F32 && self.is_owned() {
+            return Ok(Self {
+                buf: self.buf.clone(),
+                strider: TensorStrider::new(self.shape().to_vec()),
+                device: self.device.clone(),
+                name: None,
+            });
+        }
         let buf = self.buf.iter_f32().collect::<Vec<_>>();
         Self::new(buf, self.shape(), self.device.clone())
     }
 
     fn export(&self, dst: &mut [f32]) -> Result<()> {
-        assert!(self.is_contiguous());
+        // the views like the slices are gathered by the strides
+        if !self.is_contiguous() {
+            let buf = self.f32_buf()?;
+            let buf = buf.as_f32_ref();
+            dst.iter_mut()
+                .zip(self.strider.iter())
+                .for_each(|(dst, i)| *dst = buf[i]);
+            return Ok(());
+        }
 
         dst.iter_mut()
-            .zip(self.buf.iter_f32())
+            .zip(self.f32_buf()?.iter_f32())
             .for_each(|(dst, src)| {
                 *dst = src;
             });
         Ok(())
     }
 
+    fn to_dtype(&self, dtype: GGMLType) -> Result<Self> {
+        if !self.is_contiguous() {
+            return Err((ErrorKind::TensorError, "not contiguous").into());
+        }
+        let buf = match (self.dtype(), dtype) {
+            (from, to) if from == to => return self.dup(),
+            (GGMLType::F32, GGMLType::F16) | (GGMLType::F16, GGMLType::F32) => {
+                self.buf.quantize(dtype)?
+            }
+            (from, to) => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("converting {} to {} is not supported", from, to),
+                )
+                    .into());
+            }
+        };
+        Ok(Self {
+            buf,
+            strider: TensorStrider::new(self.shape().to_vec()),
+            device: self.device.clone(),
+            name: None,
+        })
+    }
+
     fn batch_matmul_vec(&self, b: &CpuTensor<'a>) -> Result<Self> {
-        // (b, m, k) @ (b, k, ) -> (b, m, )
-        let bufa = self.buf();
+        // (b, m, k) @ (b * g, k, ) -> (b * g, m, )
+        // the quantized kv cache is dequantized on reading
+        let bufa = self.f32_buf()?;
+        let bufa = bufa.as_ref();
         let bufb = b.buf();
         let _t = self.device.metrics.batch_matmul_walltime.track();
-        let mut c = CpuTensor::alloc(&[self.shape()[0], self.shape()[1]], None, self.device())?;
+        let mut c = CpuTensor::alloc(&[b.shape()[0], self.shape()[1]], None, self.device())?;
+        let (n, k) = (c.len(), self.shape()[2]);
+        let _p = self.device.profile(
+            "batch_matmul_vec",
+            2 * n * k,
+            (self.len() + b.len() + n) * 4,
+        );
         let bufc = c.buf_mut();
         let strider1 = self.strider();
         let strider2 = b.strider();
-        primitives::batch_matmul_vec(bufa, bufb, bufc, strider1, strider2)?;
+        primitives::batch_matmul_vec(self.device.clone(), bufa, bufb, bufc, strider1, strider2)?;
         Ok(c)
     }
 
+    fn flash_attention(
+        &self,
+        k_pages: &[&CpuTensor<'a>],
+        v_pages: &[&CpuTensor<'a>],
+        scale: f32,
+        alibi: Option<(usize, f32)>,
+    ) -> Result<Self> {
+        // (n_heads, head_size) on the pages of (page_len, n_kv_heads, head_size)
+        assert!(self.shape().len() == 2);
+        assert!(self.is_contiguous());
+        let (n_kv_heads, head_size) = match k_pages.first() {
+            Some(k) => (k.shape()[1], k.shape()[2]),
+            None => return Err((ErrorKind::TensorError, "the kv cache is empty").into()),
+        };
+        assert!(k_pages.iter().chain(v_pages).all(|t| t.is_contiguous()));
+        let k_bufs = k_pages
+            .iter()
+            .map(|k| k.f32_buf())
+            .collect::<Result<Vec<_>>>()?;
+        let v_bufs = v_pages
+            .iter()
+            .map(|v| v.f32_buf())
+            .collect::<Result<Vec<_>>>()?;
+        let k_bufs = k_bufs.iter().map(|b| b.as_ref()).collect::<Vec<_>>();
+        let v_bufs = v_bufs.iter().map(|b| b.as_ref()).collect::<Vec<_>>();
+
+        let _t = self.device.metrics.flash_attention_walltime.track();
+        let n_seq = k_pages.iter().map(|k| k.shape()[0]).sum::<usize>();
+        let _p = self.device.profile(
+            "flash_attention",
+            4 * self.len() * n_seq,
+            (2 * n_seq * n_kv_heads * head_size + 2 * self.len()) * 4,
+        );
+        let mut out = CpuTensor::alloc(self.shape(), None, self.device())?;
+        primitives::flash_attention(
+            self.device.clone(),
+            self.buf(),
+            &k_bufs,
+            &v_bufs,
+            out.buf_mut(),
+            n_kv_heads,
+            head_size,
+            scale,
+            alibi,
+        )?;
+        Ok(out)
+    }
+
     // gemv
     // (m, k) @ (k, ) => (m, )
     fn matmul_vec(&self, x: &CpuTensor<'a>) -> Result<Self> {
@@ -277,14 +451,55 @@ impl<'a> Tensor for CpuTensor<'a> {
         let strider1 = self.strider();
         let strider2 = x.strider();
         let _t = self.device.metrics.matmul_walltime.track();
+        let _p = self.device.profile(
+            "matmul_vec",
+            2 * self.len(),
+            self.buf.as_bytes().len() + x.buf.as_bytes().len() + self.shape()[0] * 4,
+        );
         primitives::matmul_vec(self.device.clone(), bufa, bufb, bufc, strider1, strider2)?;
         Ok(c)
     }
 
+    // batched gemv
+    // (m, k) @ (b, k) => (b, m)
+    fn matmul(&self, x: &CpuTensor<'a>) -> Result<Self> {
+        let (m, b) = (self.shape()[0], x.shape()[0]);
+        // fall back to the gemv on each row if self can not be taken in simd
+        if !self.is_contiguous() || self.len() % 32 != 0 {
+            let x = x.to_dtype(GGMLType::F32)?;
+            let mut c = CpuTensor::alloc(&[0, m], Some(b * m), self.device())?;
+            for bi in 0..b {
+                let mut row = CpuTensor::alloc(&[x.shape()[1]], None, self.device())?;
+                row.copy_from(&x, &[bi, 0], x.shape()[1])?;
+                c.extend(&self.matmul_vec(&row)?)?;
+            }
+            return Ok(c);
+        }
+
+        let mut c = CpuTensor::alloc(&[b, m], None, x.device())?;
+        let _p = self.device.profile(
+            "matmul",
+            2 * self.len() * b,
+            self.buf.as_bytes().len() + x.buf.as_bytes().len() + c.len() * 4,
+        );
+        let bufc = c.buf_mut();
+        let _t = self.device.metrics.matmul_walltime.track();
+        primitives::matmul(
+            self.device.clone(),
+            self.buf(),
+            x.buf(),
+            bufc,
+            self.strider(),
+            x.strider(),
+        )?;
+        Ok(c)
+    }
+
     fn mul_inplace(mut self, rhs: &CpuTensor<'a>) -> Result<Self> {
         let strider1 = self.strider().clone();
         let strider2 = rhs.strider();
         let _t = self.device.metrics.mul_walltime.track();
+        let _p = self.device.profile("mul", self.len(), self.len() * 12);
         primitives::mul_inplace(self.buf_mut(), rhs.buf(), &strider1, strider2)?;
         Ok(self)
     }
@@ -292,6 +507,7 @@ impl<'a> Tensor for CpuTensor<'a> {
     fn add_inplace(mut self, b: &Self) -> Result<Self> {
         let strider1 = self.strider().clone();
         let strider2 = b.strider();
+        let _p = self.device.profile("add", self.len(), self.len() * 12);
         primitives::add_inplace(self.buf_mut(), b.buf(), &strider1, strider2)?;
         Ok(self)
     }
@@ -300,46 +516,137 @@ impl<'a> Tensor for CpuTensor<'a> {
         let rhs = CpuTensor::new(vec![b], &[1], self.device())?;
         let strider1 = self.strider().clone();
         let strider2 = rhs.strider();
+        let _p = self.device.profile("div", self.len(), self.len() * 8);
         primitives::div_inplace(self.buf_mut(), rhs.buf(), &strider1, strider2)?;
         Ok(self)
     }
 
     fn silu_inplace(mut self) -> Result<Self> {
         let _t = self.device.metrics.silu_walltime.track();
+        let _p = self.device.profile("silu", self.len() * 4, self.len() * 8);
         primitives::silu_inplace(self.device(), self.buf_mut())?;
         Ok(self)
     }
 
+    fn gelu_inplace(mut self) -> Result<Self> {
+        let _t = self.device.metrics.gelu_walltime.track();
+        let _p = self.device.profile("gelu", self.len() * 8, self.len() * 8);
+        primitives::gelu_inplace(self.buf_mut())?;
+        Ok(self)
+    }
+
     fn softmax_inplace(mut self, axis: usize) -> Result<Self> {
         let _t = self.device.metrics.softmax_walltime.track();
+        let _p = self
+            .device
+            .profile("softmax", self.len() * 3, self.len() * 8);
         let strider1 = self.strider().clone();
         primitives::softmax_inplace(self.device(), self.buf_mut(), strider1, axis)?;
         Ok(self)
     }
 
-    fn rope_inplace(mut self, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn rope_inplace(mut self, pos: usize, rope: &RopeOptions) -> Result<Self> {
         let _t = self.device.metrics.rope_walltime.track();
+        let _p = self.device.profile("rope", self.len() * 3, self.len() * 8);
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::rope_inplace(buf1, &strider1, pos, rope)?;
+        Ok(self)
+    }
+
+    fn alibi_inplace(mut self, pos: usize, max_bias: f32) -> Result<Self> {
+        let _t = self.device.metrics.alibi_walltime.track();
+        let _p = self.device.profile("alibi", self.len(), self.len() * 8);
         let strider1 = self.strider().clone();
         let buf1 = self.buf_mut();
-        primitives::rope_inplace(buf1, &strider1, pos, rope_dims)?;
+        primitives::alibi_inplace(buf1, &strider1, pos, max_bias)?;
         Ok(self)
     }
 
     fn rms_norm_inplace(mut self, eps: f32) -> Result<Self> {
         let _t = self.device.metrics.rms_norm_walltime.track();
+        let _p = self
+            .device
+            .profile("rms_norm", self.len() * 3, self.len() * 8);
         let strider1 = self.strider().clone();
         let buf1 = self.buf_mut();
         primitives::rms_norm_inplace(buf1, &strider1, eps)?;
         Ok(self)
     }
+
+    fn rms_norm_mul_inplace(mut self, eps: f32, weight: &Self) -> Result<Self> {
+        let _t = self.device.metrics.rms_norm_walltime.track();
+        let _p = self
+            .device
+            .profile("rms_norm_mul", self.len() * 4, self.len() * 12);
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::rms_norm_mul_inplace(buf1, weight.buf(), &strider1, eps)?;
+        Ok(self)
+    }
+
+    fn silu_mul_inplace(mut self, rhs: &Self) -> Result<Self> {
+        let _t = self.device.metrics.silu_walltime.track();
+        let _p = self
+            .device
+            .profile("silu_mul", self.len() * 5, self.len() * 12);
+        assert!(self.strider().shape() == rhs.strider().shape());
+        primitives::silu_mul_inplace(self.device(), self.buf_mut(), rhs.buf())?;
+        Ok(self)
+    }
+
+    fn layer_norm_inplace(mut self, eps: f32) -> Result<Self> {
+        let _t = self.device.metrics.layer_norm_walltime.track();
+        let _p = self
+            .device
+            .profile("layer_norm", self.len() * 5, self.len() * 8);
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::layer_norm_inplace(buf1, &strider1, eps)?;
+        Ok(self)
+    }
+}
+
+// the owned f32 buffers are taken back by the arena of the device, which are mostly the
+// activations, the weights are borrowed from the mmap of the GGUF file.
+// SAFETY: only the owned buffer and the device are touched on dropping, never the data
+// borrowed for 'a, so the tensors may be dropped after the GGUF file like before.
+unsafe impl<#[may_dangle] 'a> Drop for CpuTensor<'a> {
+    fn drop(&mut self) {
+        if let CpuTensorBuf::F32(Cow::Owned(buf)) = &mut self.buf {
+            self.device.arena.borrow_mut().recycle(std::mem::take(buf));
+        }
+    }
+}
+
+impl<'a> TensorBackend for CpuTensorDeviceRef<'a> {
+    type Tensor = CpuTensor<'a>;
+
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn upload(&self, tensor: &CpuTensor) -> Result<CpuTensor<'a>> {
+        // all the dtypes are taken on the cpu, the borrowed buffers are copied
+        Ok(CpuTensor {
+            buf: tensor.buf.clone().into_owned(),
+            strider: tensor.strider.clone(),
+            device: self.clone(),
+            name: tensor.name.clone(),
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
+    use half::f16;
 
     use super::*;
+    use crate::backends::cpu::buf::buf_q8_k::tests::pseudo_random_bytes;
     use crate::backends::cpu::CpuTensorDevice;
+    use crate::tensor::RopeMode;
+    use crate::tensor::TensorProfiler;
 
     #[test]
     fn test_tensor_view() -> Result<()> {
@@ -352,6 +659,99 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tensor_slice() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t = CpuTensor::new((0..12).map(|i| i as f32).collect(), &[3, 4], device.clone())?;
+
+        // the columns 1..3 of each row, without copying the buffer
+        let s = t.clone().slice(1, 1, 2)?;
+        assert_eq!(s.shape(), &[3, 2]);
+        assert!(!s.is_contiguous());
+        let mut buf = vec![0.0; 6];
+        s.export(&mut buf)?;
+        assert_eq!(buf, vec![1.0, 2.0, 5.0, 6.0, 9.0, 10.0]);
+
+        // the rows 1..3, transposed
+        let s = t.clone().slice(0, 1, 2)?.transpose(&[1, 0])?;
+        assert_eq!(s.shape(), &[4, 2]);
+        let mut buf = vec![0.0; 8];
+        s.export(&mut buf)?;
+        assert_eq!(buf, vec![4.0, 8.0, 5.0, 9.0, 6.0, 10.0, 7.0, 11.0]);
+
+        assert!(t.slice(1, 3, 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let x = CpuTensor::new((0..6).map(|i| i as f32).collect(), &[2, 3], device.clone())?;
+
+        // the bias of (3, ) on each of the rows
+        let bias = CpuTensor::new(vec![1.0, 2.0, 3.0], &[3], device.clone())?;
+        let y = x.dup()?.add_inplace(&bias)?;
+        assert_eq!(y.to_vec(), vec![1.0, 3.0, 5.0, 4.0, 6.0, 8.0]);
+
+        // the scale of (2, 1) on each of the heads
+        let scale = CpuTensor::new(vec![2.0, -1.0], &[2, 1], device.clone())?;
+        let y = x.dup()?.mul_inplace(&scale)?;
+        assert_eq!(y.to_vec(), vec![0.0, 2.0, 4.0, -3.0, -4.0, -5.0]);
+
+        // the rhs may be a slice
+        let rhs = CpuTensor::new((0..6).map(|i| i as f32).collect(), &[6], device.clone())?;
+        let y = x.dup()?.add_inplace(&rhs.slice(0, 3, 3)?)?;
+        assert_eq!(y.to_vec(), vec![3.0, 5.0, 7.0, 6.0, 8.0, 10.0]);
+
+        assert!(x.dup()?.add_inplace(&scale.reshape(&[2])?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_alloc_arena() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![1.0; 64], &[2, 32], device.clone())?;
+        drop(t1.reshape(&[64])?);
+
+        // the buffer of the dropped tensor is taken again, and zeroed
+        let t2 = CpuTensor::alloc(&[64], None, device.clone())?;
+        assert_eq!(t2.to_vec(), vec![0.0; 64]);
+        assert_eq!((device.arena().hits(), device.arena().misses()), (1, 0));
+
+        // the borrowed buffers are not taken by the arena
+        let bytes = vec![0u8; 64 * 4];
+        drop(CpuTensor::from_bytes(
+            &bytes,
+            GGMLType::F32,
+            &[64],
+            device.clone(),
+        )?);
+        drop(t2.dequantize(GGMLType::F32)?);
+        assert_eq!(device.arena().free_bytes(), 64 * 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_ops() -> Result<()> {
+        let profiler = TensorProfiler::new();
+        let device = CpuTensorDevice::new().with_profiler(profiler.clone());
+        let w = CpuTensor::new(vec![1.0; 64 * 4], &[4, 64], device.clone())?;
+        let x = CpuTensor::new(vec![1.0; 64], &[64], device.clone())?;
+        let y = w.matmul_vec(&x)?;
+        profiler.next_pass();
+        drop(y.silu_inplace()?);
+
+        let events = profiler.events();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.op, e.flops, e.bytes, e.pass))
+                .collect::<Vec<_>>(),
+            vec![("matmul_vec", 512, 1296, 0), ("silu", 16, 32, 1)]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_copy_from() -> Result<()> {
         // 1 2
@@ -387,6 +787,64 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_set_row() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let mut t1 = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0], &[2, 2], device.clone())?;
+        let t2 = CpuTensor::new(vec![5.0, 6.0], &[2], device)?;
+        t1.set_row(1, &t2)?;
+        assert_eq!(t1.to_vec(), &[1.0, 2.0, 5.0, 6.0]);
+        assert!(t1.set_row(2, &t2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_cache() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let rows = (0..3)
+            .map(|r| {
+                (0..64)
+                    .map(|i| ((i * 7 + r) % 16) as f32 - 8.0)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let q = CpuTensor::new(
+            (0..64).map(|i| i as f32 / 64.0).collect(),
+            &[2, 32],
+            device.clone(),
+        )?;
+
+        for typ in [GGMLType::Q8_0, GGMLType::Q4_0] {
+            let mut cache = CpuTensor::alloc_cache(&[0, 2, 32], Some(3 * 64), typ, device.clone())?;
+            let mut want = CpuTensor::alloc(&[0, 2, 32], None, device.clone())?;
+            for row in rows[..2].iter() {
+                let row = CpuTensor::new(row.clone(), &[2, 32], device.clone())?;
+                cache.extend(&row)?;
+                want.extend(&row)?;
+            }
+            let row = CpuTensor::new(rows[2].clone(), &[2, 32], device.clone())?;
+            cache.set_row(0, &row)?;
+            want.set_row(0, &row)?;
+            assert_eq!(cache.dtype(), typ);
+            assert_eq!(cache.shape(), &[2, 2, 32]);
+
+            // the rows are dequantized on copying
+            let mut copied = CpuTensor::alloc(&[2, 32], None, device.clone())?;
+            copied.copy_from(&cache, &[1, 0, 0], 64)?;
+            assert_relative_eq!(&copied.to_vec()[..], &rows[1][..], epsilon = 1.0);
+
+            // the keys are dequantized on the attention
+            let got = cache.dup()?.transpose(&[1, 0, 2])?.batch_matmul_vec(&q)?;
+            let want = want.transpose(&[1, 0, 2])?.batch_matmul_vec(&q)?;
+            assert_relative_eq!(&got.to_vec()[..], &want.to_vec()[..], max_relative = 0.1);
+        }
+
+        // the rows of the quantized cache take the whole blocks
+        assert!(CpuTensor::alloc_cache(&[0, 2, 8], None, GGMLType::Q8_0, device.clone()).is_err());
+        assert!(CpuTensor::alloc_cache(&[0, 2, 32], None, GGMLType::Q4K, device).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_repeat() -> Result<()> {
         let device = CpuTensorDevice::new();
@@ -437,7 +895,7 @@ mod tests {
         let v1 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
         let t1 = CpuTensor::new(v1, &[2, 16], device.clone())?;
 
-        let r1 = t1.rope_inplace(1, 2)?;
+        let r1 = t1.rope_inplace(1, &RopeOptions::new(RopeMode::Normal, 2))?;
         let out = r1.to_vec();
         assert_relative_eq!(
             &out[..],
@@ -452,6 +910,103 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_rope_neox() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let v1 = (0..4).map(|v| v as f32).collect::<Vec<_>>();
+        let t1 = CpuTensor::new(v1, &[1, 4], device.clone())?;
+
+        // the pairs are (0, 2) and (1, 3)
+        let r1 = t1.rope_inplace(
+            1,
+            &RopeOptions::new(RopeMode::Neox, 4).with_freq_base(100.0),
+        )?;
+        let (cos0, sin0) = (1.0_f32.cos(), 1.0_f32.sin());
+        let (cos1, sin1) = (0.1_f32.cos(), 0.1_f32.sin());
+        assert_relative_eq!(
+            &r1.to_vec()[..],
+            &[
+                -2.0 * sin0,
+                cos1 - 3.0 * sin1,
+                2.0 * cos0,
+                sin1 + 3.0 * cos1
+            ][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rope_freq_factors() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![1.0, 0.0, 1.0, 0.0], &[1, 4], device.clone())?;
+
+        // the second pair is slowed down by the factor, and both are scaled by the attn factor
+        let rope = RopeOptions::new(RopeMode::Normal, 4)
+            .with_freq_base(100.0)
+            .with_freq_factors(Some(&[1.0, 2.0]))
+            .with_attn_factor(2.0);
+        let r1 = t1.rope_inplace(2, &rope)?;
+        assert_relative_eq!(
+            &r1.to_vec()[..],
+            &[
+                2.0 * 2.0_f32.cos(),
+                2.0 * 2.0_f32.sin(),
+                2.0 * 0.1_f32.cos(),
+                2.0 * 0.1_f32.sin()
+            ][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_norm() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0], &[4], device.clone())?;
+        let t1 = t1.layer_norm_inplace(1e-5)?;
+        assert_relative_eq!(
+            &t1.to_vec()[..],
+            &[-1.3416355, -0.44721183, 0.44721183, 1.3416355][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gelu() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![-3.0, -1.0, 0.0, 1.0, 3.0], &[5], device.clone())?;
+        let t1 = t1.gelu_inplace()?;
+        assert_relative_eq!(
+            &t1.to_vec()[..],
+            &[-0.00363752, -0.15880801, 0.0, 0.841192, 2.9963627][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_alibi() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![0.0; 6], &[2, 3], device.clone())?;
+        let t1 = t1.alibi_inplace(2, 8.0)?;
+        assert_eq!(t1.to_vec(), vec![
+            -0.125,
+            -0.0625,
+            0.0,
+            -0.0078125,
+            -0.00390625,
+            0.0
+        ]);
+
+        // the rolled kv cache, whose latest key is on the row 1
+        let t1 = CpuTensor::new(vec![0.0; 3], &[1, 3], device.clone())?;
+        let t1 = t1.alibi_inplace(4, 8.0)?;
+        assert_eq!(t1.to_vec(), vec![-0.00390625, 0.0, -0.0078125]);
+        Ok(())
+    }
+
     #[test]
     fn test_matmul() -> Result<()> {
         // 1, 2, 3
@@ -472,6 +1027,355 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_matmul_batch() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the small weights are taken by the gemv on each row
+        let w = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3], device.clone())?;
+        let x = CpuTensor::new(vec![1.0, 2.0, 3.0, 1.0, 0.0, 1.0], &[2, 3], device.clone())?;
+        let out = w.matmul(&x)?;
+        assert_eq!(out.shape(), &[2, 2]);
+        assert_eq!(out.to_vec(), &[14.0, 32.0, 4.0, 10.0]);
+
+        // each row is the same with matmul_vec, the activation is quantized on the blocks
+        let x = (0..3 * 512)
+            .map(|i| (i * 7 % 16) as f32 - 8.0)
+            .collect::<Vec<_>>();
+        let x = CpuTensor::new(x, &[3, 512], device.clone())?;
+        let mut bytes = pseudo_random_bytes(4 * 16 * 34);
+        for blk in bytes.chunks_mut(34) {
+            blk[0..2].copy_from_slice(&f16::from_f32(0.01).to_le_bytes());
+        }
+        let w_q8_0 = CpuTensor::from_bytes(&bytes, GGMLType::Q8_0, &[4, 512], device.clone())?;
+        let w_f32 = w_q8_0.clone().dequantize(GGMLType::F32)?;
+        for w in [w_q8_0, w_f32] {
+            let got = w.matmul(&x)?;
+            assert_eq!(got.shape(), &[3, 4]);
+            for bi in 0..3 {
+                let mut row = CpuTensor::alloc(&[512], None, device.clone())?;
+                row.copy_from(&x, &[bi, 0], 512)?;
+                let want = w.matmul_vec(&row)?;
+                assert_eq!(got.to_vec()[bi * 4..(bi + 1) * 4], want.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_threads() -> Result<()> {
+        let device = CpuTensorDevice::new().with_threads(3)?;
+        assert_eq!(device.threads(), 3);
+
+        // the same as on the global pool of rayon
+        let x = (0..2 * 512)
+            .map(|i| (i * 7 % 16) as f32 - 8.0)
+            .collect::<Vec<_>>();
+        let w = (0..8 * 512)
+            .map(|i| (i * 5 % 13) as f32 / 8.0)
+            .collect::<Vec<_>>();
+        let mut outs = vec![];
+        for device in [CpuTensorDevice::new(), device] {
+            let x = CpuTensor::new(x.clone(), &[2, 512], device.clone())?;
+            let w = CpuTensor::new(w.clone(), &[8, 512], device.clone())?;
+            outs.push(w.matmul(&x)?.to_vec());
+        }
+        assert_eq!(outs[0], outs[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_grouped() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // 2 kv heads of 2 keys, shared by 4 query heads
+        let k = CpuTensor::new(
+            vec![1.0, 0.0, 0.0, 1.0, 2.0, 0.0, 0.0, 2.0],
+            &[2, 2, 2],
+            device.clone(),
+        )?;
+        let q = CpuTensor::new(
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0],
+            &[4, 2],
+            device.clone(),
+        )?;
+        let out = k.batch_matmul_vec(&q)?;
+        assert_eq!(out.shape(), &[4, 2]);
+        assert_eq!(out.to_vec(), &[1.0, 2.0, 3.0, 4.0, 10.0, 12.0, 14.0, 16.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_slice() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the attention of 4 heads on 6 keys is split on the pages of 4 and 2 keys
+        let v = CpuTensor::new(
+            (0..24).map(|i| i as f32 / 8.0).collect(),
+            &[2, 3, 4],
+            device.clone(),
+        )?;
+        let attn = (0..24).map(|i| (i % 5) as f32).collect::<Vec<_>>();
+        let attn = CpuTensor::new(attn, &[4, 6], device.clone())?;
+        let page = attn.clone().slice(1, 4, 2)?;
+
+        let mut buf = vec![0.0; 8];
+        page.export(&mut buf)?;
+        let want = CpuTensor::new(buf, &[4, 2], device.clone())?;
+        let v = v.slice(2, 1, 2)?;
+        let got = v.batch_matmul_vec(&page)?;
+        let want = v.batch_matmul_vec(&want)?;
+        assert_eq!(got.shape(), &[4, 3]);
+        assert_eq!(got.to_vec(), want.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_attention() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // 4 query heads on 2 kv heads, the 70 keys are split into the pages of 32, 32 and 6 rows
+        let (n_heads, n_kv_heads, head_size, n_seq) = (4, 2, 32, 70);
+        let row = |r: usize, seed: usize| {
+            (0..n_kv_heads * head_size)
+                .map(|i| ((i * seed + r * 13) % 17) as f32 / 8.0 - 1.0)
+                .collect::<Vec<_>>()
+        };
+        let q = CpuTensor::new(
+            (0..n_heads * head_size)
+                .map(|i| ((i * 5) % 11) as f32 / 11.0 - 0.5)
+                .collect(),
+            &[n_heads, head_size],
+            device.clone(),
+        )?;
+        let scale = 1.0 / (head_size as f32).sqrt();
+
+        // the unfused attention on the whole sequence
+        let keys = (0..n_seq).flat_map(|r| row(r, 3)).collect::<Vec<_>>();
+        let values = (0..n_seq).flat_map(|r| row(r, 7)).collect::<Vec<_>>();
+        let shape = [n_seq, n_kv_heads, head_size];
+        let k = CpuTensor::new(keys, &shape, device.clone())?.transpose(&[1, 0, 2])?;
+        let v = CpuTensor::new(values, &shape, device.clone())?.transpose(&[1, 2, 0])?;
+        let attention = |alibi: Option<(usize, f32)>| -> Result<Vec<f32>> {
+            let attn = k.batch_matmul_vec(&q)?.div_scalar_inplace(1.0 / scale)?;
+            let attn = match alibi {
+                Some((pos, max_bias)) => attn.alibi_inplace(pos, max_bias)?,
+                None => attn,
+            };
+            Ok(v.batch_matmul_vec(&attn.softmax_inplace(1)?)?.to_vec())
+        };
+
+        for typ in [GGMLType::F32, GGMLType::Q8_0] {
+            let mut k_pages = vec![];
+            let mut v_pages = vec![];
+            for rows in [0..32, 32..64, 64..n_seq] {
+                let mut k = CpuTensor::alloc_cache(
+                    &[0, n_kv_heads, head_size],
+                    Some(32 * 64),
+                    typ,
+                    device.clone(),
+                )?;
+                let mut v = CpuTensor::alloc_cache(
+                    &[0, n_kv_heads, head_size],
+                    Some(32 * 64),
+                    typ,
+                    device.clone(),
+                )?;
+                for r in rows {
+                    k.extend(&CpuTensor::new(
+                        row(r, 3),
+                        &[n_kv_heads, head_size],
+                        device.clone(),
+                    )?)?;
+                    v.extend(&CpuTensor::new(
+                        row(r, 7),
+                        &[n_kv_heads, head_size],
+                        device.clone(),
+                    )?)?;
+                }
+                k_pages.push(k);
+                v_pages.push(v);
+            }
+            let k_pages = k_pages.iter().collect::<Vec<_>>();
+            let v_pages = v_pages.iter().collect::<Vec<_>>();
+            // the softmax of the unfused attention takes the exp on f16
+            let epsilon = if typ == GGMLType::F32 { 1e-3 } else { 1e-2 };
+
+            for alibi in [None, Some((n_seq - 1, 8.0)), Some((n_seq + 2, 8.0))] {
+                let got = q.flash_attention(&k_pages, &v_pages, scale, alibi)?;
+                assert_eq!(got.shape(), &[n_heads, head_size]);
+                assert_relative_eq!(&got.to_vec()[..], &attention(alibi)?[..], epsilon = epsilon);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_f16() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the values are all exact in f16
+        let w = (0..256).map(|i| (i % 13) as f32 - 6.0).collect::<Vec<_>>();
+        let b = (0..64).map(|i| (i % 7) as f32 / 4.0).collect::<Vec<_>>();
+        let bytes = w
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let w_f16 = CpuTensor::from_bytes(&bytes, GGMLType::F16, &[4, 64], device.clone())?;
+        assert_eq!(w_f16.typ(), GGMLType::F16);
+        let w_f32 = w_f16.clone().dequantize(GGMLType::F32)?;
+        assert_eq!(w_f32.to_vec(), w);
+
+        let b = CpuTensor::new(b, &[64], device.clone())?;
+        let got = w_f16.matmul_vec(&b)?;
+        let want = w_f32.matmul_vec(&b)?;
+        assert_eq!(got.to_vec(), want.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_f16_activations() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the values are all exact in f16, so the f16 activations take the same results
+        let x = (0..2 * 64)
+            .map(|i| ((i * 7 % 16) as f32 - 8.0) / 4.0)
+            .collect::<Vec<_>>();
+        let x = CpuTensor::new(x, &[2, 64], device.clone())?;
+        let x_f16 = x.to_dtype(GGMLType::F16)?;
+        assert_eq!(x_f16.dtype(), GGMLType::F16);
+        assert_eq!(x_f16.buf().as_bytes().len(), 2 * 64 * 2);
+        let mut exported = vec![0.0; 2 * 64];
+        x_f16.export(&mut exported)?;
+        assert_eq!(exported, x.to_vec());
+        assert_eq!(x_f16.to_dtype(GGMLType::F32)?.to_vec(), x.to_vec());
+
+        let mut bytes = pseudo_random_bytes(4 * 2 * 34);
+        for blk in bytes.chunks_mut(34) {
+            blk[0..2].copy_from_slice(&f16::from_f32(0.01).to_le_bytes());
+        }
+        let w_q8_0 = CpuTensor::from_bytes(&bytes, GGMLType::Q8_0, &[4, 64], device.clone())?;
+        let w_f32 = w_q8_0.clone().dequantize(GGMLType::F32)?;
+        let w_f16 = w_f32.clone().dequantize(GGMLType::F16)?;
+        // the small weights are taken by the naive gemv
+        let w_small = CpuTensor::new(vec![0.5; 3 * 64], &[3, 64], device.clone())?;
+        for w in [w_q8_0, w_f32, w_f16, w_small] {
+            assert_eq!(w.matmul(&x_f16)?.to_vec(), w.matmul(&x)?.to_vec());
+            let mut row = CpuTensor::alloc(&[64], None, device.clone())?;
+            row.copy_from(&x, &[1, 0], 64)?;
+            let got = w.matmul_vec(&row.to_dtype(GGMLType::F16)?)?;
+            assert_eq!(got.to_vec(), w.matmul_vec(&row)?.to_vec());
+        }
+
+        let err = CpuTensor::from_bytes(&bytes, GGMLType::Q8_0, &[4, 64], device.clone())?
+            .to_dtype(GGMLType::F16)
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TensorError);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend_upload() -> Result<()> {
+        // the graph code written once over the backend
+        fn forward<B: TensorBackend>(device: B, w: &CpuTensor, x: &CpuTensor) -> Result<Vec<f32>> {
+            let w = device.upload(w)?;
+            let x = device.upload(x)?;
+            let mut out = vec![0.0; w.strider().shape()[0]];
+            w.matmul_vec(&x)?.export(&mut out)?;
+            Ok(out)
+        }
+
+        let device = CpuTensorDevice::new();
+        let w = (0..256).map(|i| (i % 13) as f32 - 6.0).collect::<Vec<_>>();
+        let bytes = w
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+            .collect::<Vec<_>>();
+        let w = CpuTensor::from_bytes(&bytes, GGMLType::F16, &[4, 64], device.clone())?;
+        let x = CpuTensor::new(vec![1.0; 64], &[64], device.clone())?;
+
+        // the borrowed buffers are copied, the dtype is kept
+        let uploaded = device.upload(&w)?;
+        assert_eq!(device.name(), "cpu");
+        assert_eq!(uploaded.typ(), GGMLType::F16);
+        assert_eq!(uploaded.shape(), &[4, 64]);
+        assert_eq!(forward(device, &w, &x)?, w.matmul_vec(&x)?.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_k_quants() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the activation is quantized into Q8_K in matmul, the values in [-8, 7] are kept
+        // exactly after quantization
+        let b = (0..512)
+            .map(|i| ((i * 7 % 16) as f32 - 8.0))
+            .collect::<Vec<_>>();
+        let b = CpuTensor::new(b, &[512], device.clone())?;
+
+        for (typ, blk_size, d_offsets) in [
+            (GGMLType::Q2K, 84, vec![80, 82]),
+            (GGMLType::Q3K, 110, vec![108]),
+            (GGMLType::Q4K, 144, vec![0, 2]),
+            (GGMLType::Q5K, 176, vec![0, 2]),
+            (GGMLType::Q6K, 210, vec![208]),
+            (GGMLType::IQ2XXS, 66, vec![0]),
+            (GGMLType::IQ2XS, 74, vec![0]),
+            (GGMLType::IQ3XXS, 98, vec![0]),
+        ] {
+            // 4 rows with 2 blocks per row, patch the f16 scales to avoid NaN
+            let mut bytes = pseudo_random_bytes(8 * blk_size);
+            for blk in bytes.chunks_mut(blk_size) {
+                for offset in d_offsets.iter() {
+                    blk[*offset..*offset + 2].copy_from_slice(&f16::from_f32(0.01).to_le_bytes());
+                }
+            }
+            let w = CpuTensor::from_bytes(&bytes, typ, &[4, 512], device.clone())?;
+            let w_f32 = w.clone().dequantize(GGMLType::F32)?;
+            assert_eq!(w.typ(), typ);
+
+            let got = w.matmul_vec(&b)?;
+            let want = w_f32.matmul_vec(&b)?;
+            assert_relative_eq!(&got.to_vec()[..], &want.to_vec()[..], max_relative = 1e-4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_legacy_quants() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the activation is quantized into Q8_0 or Q8_1 in matmul, the first element of each
+        // block is 127, so the scale is 1.0 and the integers are kept exactly
+        let b = (0..512)
+            .map(|i| match i % 32 {
+                0 => 127.0,
+                _ => (i * 7 % 16) as f32 - 8.0,
+            })
+            .collect::<Vec<_>>();
+        let b = CpuTensor::new(b, &[512], device.clone())?;
+
+        for (typ, blk_size, d_offsets) in [
+            (GGMLType::Q4_1, 20, vec![0, 2]),
+            (GGMLType::Q5_0, 22, vec![0]),
+            (GGMLType::Q5_1, 24, vec![0, 2]),
+            (GGMLType::IQ4NL, 18, vec![0]),
+        ] {
+            let mut bytes = pseudo_random_bytes(64 * blk_size);
+            for blk in bytes.chunks_mut(blk_size) {
+                for offset in d_offsets.iter() {
+                    blk[*offset..*offset + 2].copy_from_slice(&f16::from_f32(0.01).to_le_bytes());
+                }
+            }
+            let w = CpuTensor::from_bytes(&bytes, typ, &[4, 512], device.clone())?;
+            let w_f32 = w.clone().dequantize(GGMLType::F32)?;
+            assert_eq!(w.typ(), typ);
+
+            let got = w.matmul_vec(&b)?;
+            let want = w_f32.matmul_vec(&b)?;
+            assert_relative_eq!(&got.to_vec()[..], &want.to_vec()[..], max_relative = 1e-3);
+
+            let err = w.dequantize(GGMLType::F16).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::NotImplemented);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_softmax() -> Result<()> {
         let device = CpuTensorDevice::new();
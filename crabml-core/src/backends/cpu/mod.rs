@@ -1,10 +1,16 @@
+pub mod arch;
+mod arena;
 pub mod buf;
 mod cpu_device;
+mod cpu_loader;
 mod cpu_tensor;
 mod primitives;
 
+pub use arena::CpuTensorArena;
+pub use arena::DEFAULT_ARENA_BYTES;
 pub use buf::CpuTensorBuf;
 pub use cpu_device::CpuTensorDevice;
 pub use cpu_device::CpuTensorDeviceOptions;
 pub use cpu_device::CpuTensorDeviceRef;
+pub use cpu_loader::CpuTensorLoader;
 pub use cpu_tensor::CpuTensor;
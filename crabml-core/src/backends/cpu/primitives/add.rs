@@ -2,22 +2,31 @@ use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::error::Result;
 use crate::tensor::TensorStrider;
 
+// the rhs is broadcasted onto the shape of the lhs, which has to be contiguous
 pub fn add_inplace<'a>(
     buf1: &mut CpuTensorBuf<'a>,
     buf2: &CpuTensorBuf<'a>,
     strider1: &TensorStrider,
     strider2: &TensorStrider,
 ) -> Result<()> {
-    assert!(buf1.len() == buf2.len());
-    assert!(strider1.shape() == strider2.shape());
     assert!(strider1.is_contiguous());
-    assert!(strider2.is_contiguous());
 
+    if strider1.shape() == strider2.shape() && strider2.is_contiguous() {
+        assert!(buf1.len() == buf2.len());
+        buf1.iter_f32_mut()
+            .zip(buf2.iter_f32())
+            .for_each(|(ia, ib)| {
+                *ia += ib;
+            });
+        return Ok(());
+    }
+
+    let strider2 = strider2.broadcast_to(strider1.shape())?;
+    let bufb = buf2.as_f32_ref();
     buf1.iter_f32_mut()
-        .zip(buf2.iter_f32())
+        .zip(strider2.iter())
         .for_each(|(ia, ib)| {
-            *ia += ib;
+            *ia += bufb[ib];
         });
-
     Ok(())
 }
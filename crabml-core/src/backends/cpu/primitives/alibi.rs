@@ -0,0 +1,23 @@
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::error::Result;
+use crate::tensor::alibi_slopes;
+use crate::tensor::TensorStrider;
+
+// the attention scores in (n_heads, n_seq)
+pub fn alibi_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    pos: usize,
+    max_bias: f32,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(strider.shape().len() == 2);
+
+    let (n_heads, n_seq) = (strider.shape()[0], strider.shape()[1]);
+    let slopes = alibi_slopes(n_heads, max_bias);
+    buf.iter_f32_mut().enumerate().for_each(|(i, n)| {
+        let (h, j) = (i / n_seq, i % n_seq);
+        *n -= slopes[h] * ((pos - j) % n_seq) as f32;
+    });
+    Ok(())
+}
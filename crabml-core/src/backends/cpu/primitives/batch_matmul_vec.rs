@@ -1,12 +1,20 @@
 use rayon::prelude::*;
 
+#[cfg(target_arch = "x86_64")]
+use crate::backends::cpu::arch::X86Simd;
+#[cfg(target_arch = "x86_64")]
+use crate::backends::cpu::buf::buf_f32::vec_dot_f32_f32;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::error::Result;
 use crate::tensor::TensorStrider;
 
-// (b, m, k) @ (b, k, ) -> (b, m, )
-// a is allowed to be not contiguous, but not quantized
+// (b, m, k) @ (b * g, k, ) -> (b * g, m, )
+// a is allowed to be not contiguous, but not quantized. every g rows of b share a batch of a,
+// like the query heads of a group share a kv head in the grouped-query attention. both a and
+// the rows of b may be the views at an offset, like the slices of the attention on a page
 pub fn batch_matmul_vec<'a>(
+    device: CpuTensorDeviceRef<'a>,
     a: &CpuTensorBuf<'a>,
     b: &CpuTensorBuf<'a>,
     c: &mut CpuTensorBuf<'a>,
@@ -15,30 +23,35 @@ pub fn batch_matmul_vec<'a>(
 ) -> Result<()> {
     assert!(strider1.shape().len() == 3);
     assert!(strider2.shape().len() == 2);
-    assert!(strider1.shape()[0] == strider2.shape()[0]);
+    assert!(strider2.shape()[0] % strider1.shape()[0] == 0);
     assert!(strider1.shape()[2] == strider2.shape()[1]);
-    assert!(strider2.is_contiguous());
+    assert!(strider2.strides()[1] == 1);
 
     let bufa = a.as_f32_ref();
     let bufb = b.as_f32_ref();
     let bufc = c.as_f32_mut();
 
+    let g = strider2.shape()[0] / strider1.shape()[0];
     let m = strider1.shape()[1];
     let k = strider1.shape()[2];
     let bi_stride = strider1.strides()[0];
     let mi_stride = strider1.strides()[1];
     let ki_stride = strider1.strides()[2];
-
-    bufc.par_iter_mut().enumerate().for_each(|(i, bufcp)| {
-        let mi = i % m;
-        let bi = (i - mi) / m;
-        *bufcp = dot_product_f32(
-            bufa,
-            bi * bi_stride + mi * mi_stride,
-            ki_stride,
-            k,
-            &bufb[bi * k..(bi + 1) * k],
-        );
+    let (a_offset, b_offset) = (strider1.offset(), strider2.offset());
+    let b_stride = strider2.strides()[0];
+
+    device.install(|| {
+        bufc.par_iter_mut().enumerate().for_each(|(i, bufcp)| {
+            let mi = i % m;
+            let bi = (i - mi) / m;
+            *bufcp = dot_product_f32(
+                bufa,
+                a_offset + bi / g * bi_stride + mi * mi_stride,
+                ki_stride,
+                k,
+                &bufb[b_offset + bi * b_stride..b_offset + bi * b_stride + k],
+            );
+        })
     });
 
     Ok(())
@@ -50,24 +63,25 @@ pub fn dot_product_f32(a: &[f32], a_base: usize, a_stride: usize, k: usize, b: &
     {
         dot_product_f32_simd(a, a_base, a_stride, k, b)
     }
+    // the rows of the key cache are contiguous, which are taken by the kernels of the
+    // contiguous vectors, and the avx2 kernel gathers the strided values of the value cache
     #[cfg(target_arch = "x86_64")]
-    #[cfg(target_feature = "avx2")]
     {
-        dot_product_f32_simd(a, a_base, a_stride, k, b)
+        if a_stride == 1 {
+            return vec_dot_f32_f32(a, a_base, b, 0, k);
+        }
+        if X86Simd::detect() >= X86Simd::Avx2 {
+            return unsafe { dot_product_f32_simd(a, a_base, a_stride, k, b) };
+        }
+        dot_product_f32_fallback(a, a_base, a_stride, k, b)
     }
-    #[cfg(not(any(
-        target_arch = "aarch64",
-        all(target_arch = "x86_64", target_feature = "avx2")
-    )))]
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
     {
         dot_product_f32_fallback(a, a_base, a_stride, k, b)
     }
 }
 
-#[cfg(not(any(
-    target_arch = "aarch64",
-    all(target_arch = "x86_64", target_feature = "avx2")
-)))]
+#[cfg(not(target_arch = "aarch64"))]
 fn dot_product_f32_fallback(a: &[f32], a_base: usize, a_stride: usize, k: usize, b: &[f32]) -> f32 {
     let mut sum = 0.0;
     let k_rounded = k - k % 4;
@@ -121,39 +135,43 @@ fn dot_product_f32_simd(a: &[f32], a_base: usize, a_stride: usize, k: usize, b:
 }
 
 #[cfg(target_arch = "x86_64")]
-#[cfg(target_feature = "avx2")]
-fn dot_product_f32_simd(a: &[f32], a_base: usize, a_stride: usize, k: usize, b: &[f32]) -> f32 {
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_product_f32_simd(
+    a: &[f32],
+    a_base: usize,
+    a_stride: usize,
+    k: usize,
+    b: &[f32],
+) -> f32 {
     use std::arch::x86_64::*;
 
-    unsafe {
-        let a_ptr = a.as_ptr().add(a_base);
-
-        let mut sumv = _mm256_setzero_ps();
-        let k_rounded_down = k - k % 8; // Round down to the nearest multiple of 8
-
-        for ki in (0..k_rounded_down).step_by(8) {
-            let mut av_tmp = [0.0_f32; 8];
-            // Load elements from 'a' with stride
-            for i in 0..8 {
-                av_tmp[i] = *a_ptr.add(ki * a_stride + i * a_stride);
-            }
-            let av = _mm256_loadu_ps(av_tmp.as_ptr());
-            let bv = _mm256_loadu_ps(b.as_ptr().add(ki));
-            // Fused multiply-add operation: sumv += av * bv
-            sumv = _mm256_fmadd_ps(av, bv, sumv);
-        }
+    let a_ptr = a.as_ptr().add(a_base);
 
-        // Horizontal sum of the vector elements
-        let mut sum_arr = [0.0_f32; 8];
-        _mm256_storeu_ps(sum_arr.as_mut_ptr(), sumv);
-        let partial_sum = sum_arr.iter().sum::<f32>();
+    let mut sumv = _mm256_setzero_ps();
+    let k_rounded_down = k - k % 8; // Round down to the nearest multiple of 8
 
-        // Scalar computation for the remaining elements
-        let mut scalar_sum = 0.0;
-        for ki in k_rounded_down..k {
-            scalar_sum += a[a_base + ki * a_stride] * b[ki];
+    for ki in (0..k_rounded_down).step_by(8) {
+        let mut av_tmp = [0.0_f32; 8];
+        // Load elements from 'a' with stride
+        for (i, av) in av_tmp.iter_mut().enumerate() {
+            *av = *a_ptr.add((ki + i) * a_stride);
         }
+        let av = _mm256_loadu_ps(av_tmp.as_ptr());
+        let bv = _mm256_loadu_ps(b.as_ptr().add(ki));
+        // Fused multiply-add operation: sumv += av * bv
+        sumv = _mm256_fmadd_ps(av, bv, sumv);
+    }
+
+    // Horizontal sum of the vector elements
+    let mut sum_arr = [0.0_f32; 8];
+    _mm256_storeu_ps(sum_arr.as_mut_ptr(), sumv);
+    let partial_sum = sum_arr.iter().sum::<f32>();
 
-        partial_sum + scalar_sum
+    // Scalar computation for the remaining elements
+    let mut scalar_sum = 0.0;
+    for ki in k_rounded_down..k {
+        scalar_sum += a[a_base + ki * a_stride] * b[ki];
     }
+
+    partial_sum + scalar_sum
 }
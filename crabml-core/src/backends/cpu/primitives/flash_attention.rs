@@ -0,0 +1,95 @@
+use rayon::prelude::*;
+
+use crate::backends::cpu::buf::buf_f32::vec_dot_f32_f32;
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::CpuTensorDeviceRef;
+use crate::error::Result;
+use crate::tensor::alibi_slopes;
+
+// the keys taken together on updating the running max of the softmax
+const TILE: usize = 32;
+
+// q: (n_heads, head_size), the keys and values of each page: (page_len, n_kv_heads, head_size),
+// out: (n_heads, head_size). the pages are contiguous and not quantized. each head keeps the
+// running max, the running sum and the weighted sum of the values over the tiles of the keys,
+// which are rescaled when the max grows, so the scores of the whole sequence are never kept.
+#[allow(clippy::too_many_arguments)]
+pub fn flash_attention<'a>(
+    device: CpuTensorDeviceRef<'a>,
+    q: &CpuTensorBuf<'a>,
+    k_pages: &[&CpuTensorBuf<'a>],
+    v_pages: &[&CpuTensorBuf<'a>],
+    out: &mut CpuTensorBuf<'a>,
+    n_kv_heads: usize,
+    head_size: usize,
+    scale: f32,
+    alibi: Option<(usize, f32)>,
+) -> Result<()> {
+    assert!(k_pages.len() == v_pages.len());
+
+    let bufq = q.as_f32_ref();
+    let bufo = out.as_f32_mut();
+    let n_heads = bufq.len() / head_size;
+    assert!(n_heads % n_kv_heads == 0);
+    let g = n_heads / n_kv_heads;
+
+    let pages = k_pages
+        .iter()
+        .zip(v_pages.iter())
+        .map(|(k, v)| (k.as_f32_ref(), v.as_f32_ref()))
+        .collect::<Vec<_>>();
+    let row_size = n_kv_heads * head_size;
+    let n_seq = pages.iter().map(|(k, _)| k.len() / row_size).sum::<usize>();
+    let slopes = alibi.map(|(_, max_bias)| alibi_slopes(n_heads, max_bias));
+
+    device.install(|| {
+        bufo.par_chunks_mut(head_size)
+            .enumerate()
+            .for_each(|(h, acc)| {
+                let qh = &bufq[h * head_size..(h + 1) * head_size];
+                let kvh = h / g;
+                let mut max = f32::NEG_INFINITY;
+                let mut sum = 0.0;
+                let mut scores = [0.0; TILE];
+                let mut j = 0; // the row of the key in the sequence
+                acc.fill(0.0);
+
+                for (k, v) in pages.iter() {
+                    let page_len = k.len() / row_size;
+                    for tile_start in (0..page_len).step_by(TILE) {
+                        let tile_len = TILE.min(page_len - tile_start);
+                        let mut tile_max = f32::NEG_INFINITY;
+                        for (ti, score) in scores[..tile_len].iter_mut().enumerate() {
+                            let base = (tile_start + ti) * row_size + kvh * head_size;
+                            *score = vec_dot_f32_f32(k, base, qh, 0, head_size) * scale;
+                            if let (Some((pos, _)), Some(slopes)) = (alibi, &slopes) {
+                                *score -= slopes[h] * ((pos - (j + ti)) % n_seq) as f32;
+                            }
+                            tile_max = tile_max.max(*score);
+                        }
+
+                        // rescale the sums on the old max once per tile
+                        if tile_max > max {
+                            let c = (max - tile_max).exp();
+                            acc.iter_mut().for_each(|a| *a *= c);
+                            sum *= c;
+                            max = tile_max;
+                        }
+                        for (ti, score) in scores[..tile_len].iter().enumerate() {
+                            let w = (score - max).exp();
+                            let base = (tile_start + ti) * row_size + kvh * head_size;
+                            acc.iter_mut()
+                                .zip(v[base..base + head_size].iter())
+                                .for_each(|(a, v)| *a += w * v);
+                            sum += w;
+                        }
+                        j += tile_len;
+                    }
+                }
+
+                acc.iter_mut().for_each(|a| *a /= sum);
+            })
+    });
+
+    Ok(())
+}
@@ -0,0 +1,13 @@
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::error::Result;
+
+const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+
+// TODO: support f16
+pub fn gelu_inplace(buf: &mut CpuTensorBuf<'_>) -> Result<()> {
+    buf.iter_f32_mut().for_each(|n| {
+        let x = *n;
+        *n = 0.5 * x * (1.0 + (SQRT_2_OVER_PI * x * (1.0 + 0.044715 * x * x)).tanh());
+    });
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+pub fn layer_norm_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    eps: f32,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(strider.shape().len() == 1);
+
+    let len = strider.shape()[0] as f32;
+    let mean = buf.iter_f32().sum::<f32>() / len;
+    let var = buf.iter_f32().fold(0.0, |s, n| s + (n - mean) * (n - mean)) / len;
+    let std = (var + eps).sqrt();
+    buf.iter_f32_mut().for_each(|n| *n = (*n - mean) / std);
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use rayon::prelude::*;
+
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::CpuTensorDeviceRef;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+// matmul is the batched GEMV: A (m,k) @ B (b,k) -> xout (b,m), each row of B is taken as
+// a vector. all the rows of B are quantized at once, and each row of A is loaded once for
+// the whole batch. A is allowed to be quantized, but has to be contiguous
+pub fn matmul<'a>(
+    device: CpuTensorDeviceRef<'a>,
+    bufa: &CpuTensorBuf<'a>,
+    bufb: &CpuTensorBuf<'a>,
+    bufc: &mut CpuTensorBuf<'a>,
+    strider1: &TensorStrider,
+    strider2: &TensorStrider,
+) -> Result<()> {
+    assert!(strider1.shape().len() == 2);
+    assert!(strider2.shape().len() == 2);
+    assert!(strider1.shape()[1] == strider2.shape()[1]);
+    assert!(strider1.is_contiguous());
+    assert!(strider2.is_contiguous());
+
+    let metrics = device.metrics().clone();
+    let m = strider1.shape()[0];
+    let k = strider1.shape()[1];
+    let b = strider2.shape()[0];
+
+    let bufb = {
+        let _t = metrics.matmul_quantize_walltime.track();
+        &bufb.quantize(bufa.vec_dot_dtype())?
+    };
+
+    // (m, b), every row of A is dotted with all the rows of B in a task
+    let _t = metrics.matmul_vec_dot_walltime.track();
+    let mut ct = vec![0.0; m * b];
+    device.install(|| {
+        ct.par_chunks_exact_mut(b).enumerate().for_each(|(mi, cp)| {
+            for (bi, c) in cp.iter_mut().enumerate() {
+                *c = bufa.vec_dot(mi * k, bufb, bi * k, k);
+            }
+        })
+    });
+
+    let bufc = bufc.as_f32_mut();
+    for (mi, row) in ct.chunks_exact(b).enumerate() {
+        for (bi, c) in row.iter().enumerate() {
+            bufc[bi * m + mi] = *c;
+        }
+    }
+    Ok(())
+}
@@ -3,6 +3,7 @@ use rayon::prelude::*;
 use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::error::Result;
+use crate::gguf::GGMLType;
 use crate::tensor::TensorStrider;
 
 // matmul_vec is an implementation of GEMV: A (m,k) @ B (k,) -> xout (m,).
@@ -26,7 +27,12 @@ pub fn matmul_vec<'a>(
         return Ok(());
     }
 
-    // fall back to the naive implementation if stride1 is not contiguous
+    // fall back to the naive implementation if stride1 is not contiguous, the f16
+    // activations are widened to f32 on it
+    if bufb.dtype() == GGMLType::F16 {
+        gemv_naive_f32(bufa, &bufb.quantize(GGMLType::F32)?, bufc, strider1);
+        return Ok(());
+    }
     gemv_naive_f32(bufa, bufb, bufc, strider1);
     Ok(())
 }
@@ -66,19 +72,21 @@ fn gemv_simd<'a>(
     let bufc = bufc.as_f32_mut();
     let bufb = {
         let _t = metrics.matmul_quantize_walltime.track();
-        &bufb.quantize(bufa.dtype()).unwrap()
+        &bufb.quantize(bufa.vec_dot_dtype()).unwrap()
     };
 
     let _t = metrics.matmul_vec_dot_walltime.track();
 
     let k = bufb.len();
-    bufc.par_chunks_exact_mut(4)
-        .enumerate()
-        .for_each(|(cn, cp)| {
-            let mi = cn * 4;
-            cp[0] = bufa.vec_dot(mi * k, bufb, 0, k);
-            cp[1] = bufa.vec_dot((mi + 1) * k, bufb, 0, k);
-            cp[2] = bufa.vec_dot((mi + 2) * k, bufb, 0, k);
-            cp[3] = bufa.vec_dot((mi + 3) * k, bufb, 0, k);
-        });
+    device.install(|| {
+        bufc.par_chunks_exact_mut(4)
+            .enumerate()
+            .for_each(|(cn, cp)| {
+                let mi = cn * 4;
+                cp[0] = bufa.vec_dot(mi * k, bufb, 0, k);
+                cp[1] = bufa.vec_dot((mi + 1) * k, bufb, 0, k);
+                cp[2] = bufa.vec_dot((mi + 2) * k, bufb, 0, k);
+                cp[3] = bufa.vec_dot((mi + 3) * k, bufb, 0, k);
+            })
+    });
 }
@@ -1,6 +1,11 @@
 mod add;
+mod alibi;
 mod batch_matmul_vec;
 mod div;
+mod flash_attention;
+mod gelu;
+mod layer_norm;
+mod matmul;
 mod matmul_vec;
 mod mul;
 mod rms_norm;
@@ -9,11 +14,18 @@ mod silu;
 mod softmax;
 
 pub use add::add_inplace;
+pub use alibi::alibi_inplace;
 pub use batch_matmul_vec::batch_matmul_vec;
 pub use div::div_inplace;
+pub use flash_attention::flash_attention;
+pub use gelu::gelu_inplace;
+pub use layer_norm::layer_norm_inplace;
+pub use matmul::matmul;
 pub use matmul_vec::matmul_vec;
 pub use mul::mul_inplace;
 pub use rms_norm::rms_norm_inplace;
+pub use rms_norm::rms_norm_mul_inplace;
 pub use rope::rope_inplace;
 pub use silu::silu_inplace;
+pub use silu::silu_mul_inplace;
 pub use softmax::softmax_inplace;
@@ -4,7 +4,8 @@ use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::error::Result;
 use crate::tensor::TensorStrider;
 
-// both buf1 and buf2 have to be owned, the dtype should be the same
+// both buf1 and buf2 have to be owned, the dtype should be the same. the rhs is broadcasted
+// onto the shape of the lhs, which has to be contiguous
 pub fn mul_inplace<'a>(
     buf1: &mut CpuTensorBuf<'a>,
     buf2: &CpuTensorBuf<'a>,
@@ -12,15 +13,21 @@ pub fn mul_inplace<'a>(
     strider2: &TensorStrider,
 ) -> Result<()> {
     assert!(buf1.is_owned());
-    assert!(strider1.shape() == strider2.shape());
     assert!(strider1.is_contiguous());
-    assert!(strider2.is_contiguous());
     assert!(buf1.dtype() == buf2.dtype());
 
-    for (ia, ib) in buf1.iter_f32_mut().zip(buf2.iter_f32()) {
-        *ia *= ib;
+    if strider1.shape() == strider2.shape() && strider2.is_contiguous() {
+        for (ia, ib) in buf1.iter_f32_mut().zip(buf2.iter_f32()) {
+            *ia *= ib;
+        }
+        return Ok(());
     }
 
+    let strider2 = strider2.broadcast_to(strider1.shape())?;
+    let bufb = buf2.as_f32_ref();
+    for (ia, ib) in buf1.iter_f32_mut().zip(strider2.iter()) {
+        *ia *= bufb[ib];
+    }
     Ok(())
 }
 
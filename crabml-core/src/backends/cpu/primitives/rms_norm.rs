@@ -26,6 +26,32 @@ pub fn rms_norm_inplace(
     Ok(())
 }
 
+/// the rms norm scaled by the weight, which takes the same rounding as the rms norm followed
+/// by the mul, but reads and writes the buffer only once more.
+pub fn rms_norm_mul_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    weight: &CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    eps: f32,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(strider.shape().len() == 1);
+    assert!(weight.len() == strider.shape()[0]);
+
+    if let (CpuTensorBuf::F32(Cow::Owned(xb)), CpuTensorBuf::F32(w)) = (&mut *buf, weight) {
+        rms_norm_mul_inplace_vec_f32(xb, w, eps);
+        return Ok(());
+    }
+
+    let len = strider.shape()[0];
+    let sum = buf.iter_f32().fold(0.0, |s, n| s + n * n);
+    let rms = ((sum / len as f32) + eps).sqrt();
+    buf.iter_f32_mut()
+        .zip(weight.iter_f32())
+        .for_each(|(n, w)| *n = *n / rms * w);
+    Ok(())
+}
+
 fn rms_norm_inplace_vec_f32(x: &mut [f32], eps: f32) {
     let len = x.len();
     assert!(len % 32 == 0);
@@ -42,3 +68,22 @@ fn rms_norm_inplace_vec_f32(x: &mut [f32], eps: f32) {
         v.copy_to_slice(chunk);
     }
 }
+
+fn rms_norm_mul_inplace_vec_f32(x: &mut [f32], w: &[f32], eps: f32) {
+    let len = x.len();
+    assert!(len % 32 == 0);
+    let mut sum = 0.0;
+    for chunk in x.as_chunks::<32>().0 {
+        let mut v = f32x32::from_slice(chunk);
+        v *= v;
+        sum += v.reduce_sum();
+    }
+    let rms = ((sum / len as f32) + eps).sqrt();
+    let wc = w.as_chunks::<32>().0;
+    for (chunk, w) in x.as_chunks_mut::<32>().0.iter_mut().zip(wc) {
+        let mut v = f32x32::from_slice(chunk);
+        v /= f32x32::splat(rms);
+        v *= f32x32::from_slice(w);
+        v.copy_to_slice(chunk);
+    }
+}
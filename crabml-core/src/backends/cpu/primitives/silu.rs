@@ -12,3 +12,18 @@ pub fn silu_inplace<'a>(device: CpuTensorDeviceRef<'a>, buf: &mut CpuTensorBuf<'
     });
     Ok(())
 }
+
+/// silu(buf) * rhs, the gate of the ffn.
+pub fn silu_mul_inplace<'a>(
+    device: CpuTensorDeviceRef<'a>,
+    buf: &mut CpuTensorBuf<'a>,
+    rhs: &CpuTensorBuf<'a>,
+) -> Result<()> {
+    assert!(buf.len() == rhs.len());
+    let exp_cache = &device.exp_cache;
+    buf.iter_f32_mut().zip(rhs.iter_f32()).for_each(|(n, r)| {
+        let nexp = exp_f32_cached(-*n, exp_cache);
+        *n = *n / (1.0 + nexp) * r
+    });
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use cudarc::cublas::sys::cublasOperation_t;
+use cudarc::cublas::CudaBlas;
+use cudarc::driver::result as cu;
+use cudarc::driver::sys::CUdeviceptr;
+use cudarc::driver::CudaDevice;
+use cudarc::driver::CudaFunction;
+use cudarc::driver::CudaSlice;
+use cudarc::driver::DevicePtr;
+use cudarc::driver::LaunchAsync;
+use cudarc::driver::LaunchConfig;
+use cudarc::nvrtc::CompileError;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::Tensor;
+
+const MODULE: &str = "crabml";
+
+const KERNELS: [&str; 18] = [
+    "add_inplace",
+    "mul_inplace",
+    "div_scalar_inplace",
+    "silu_inplace",
+    "silu_mul_inplace",
+    "gelu_inplace",
+    "rms_norm_inplace",
+    "rms_norm_mul_inplace",
+    "layer_norm_inplace",
+    "softmax_inplace",
+    "rope_inplace",
+    "alibi_inplace",
+    "batch_matmul",
+    "matmul_q8_0",
+    "matmul_q4_0",
+    "flash_attention",
+    "dequantize_q8_0",
+    "dequantize_q4_0",
+];
+
+fn cuda_error(op: &str, err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error {
+        kind: ErrorKind::Unexpected,
+        message: format!("{} failed: {}", op, err),
+        cause: Some(Box::new(err)),
+    }
+}
+
+/// a chunk of the device memory, which is freed on drop.
+pub(crate) struct CudaBuffer(CudaSlice<u8>);
+
+impl CudaBuffer {
+    pub(crate) fn ptr(&self) -> CUdeviceptr {
+        *self.0.device_ptr()
+    }
+}
+
+#[derive(Default)]
+pub struct CudaTensorDeviceOptions {
+    pub device_id: usize,
+    pub debug_named_tensor: bool,
+}
+
+impl CudaTensorDeviceOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_device_id(mut self, v: usize) -> Self {
+        self.device_id = v;
+        self
+    }
+
+    pub fn with_debug_named_tensor(mut self, v: bool) -> Self {
+        self.debug_named_tensor = v;
+        self
+    }
+}
+
+/// the nvidia gpu on cudarc. the kernels are compiled by nvrtc on creating the device, and all
+/// of them are launched on the default stream, so they run in the order of launching, and the
+/// host waits them on `sync` only.
+///
+/// the f32 matmuls run on cublas, the quantized weights are kept quantized in the device
+/// memory, and multiplied block by block by the matmul kernels of their dtypes.
+pub struct CudaTensorDevice {
+    pub(crate) opts: CudaTensorDeviceOptions,
+    device: Arc<CudaDevice>,
+    functions: HashMap<&'static str, CudaFunction>,
+    cublas: CudaBlas,
+
+    /// used for test only
+    pub debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
+}
+
+pub type CudaTensorDeviceRef = Rc<CudaTensorDevice>;
+
+impl CudaTensorDevice {
+    pub fn new(opts: CudaTensorDeviceOptions) -> CudaTensorDeviceRef {
+        Self::try_new(opts).unwrap()
+    }
+
+    pub fn try_new(opts: CudaTensorDeviceOptions) -> Result<CudaTensorDeviceRef> {
+        let device = CudaDevice::new(opts.device_id).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("no cuda device {} found", opts.device_id),
+            cause: Some(Box::new(err)),
+        })?;
+
+        // the compile log is taken as the error message
+        let ptx = cudarc::nvrtc::compile_ptx(include_str!("kernels/kernels.cu")).map_err(
+            |err| match err {
+                CompileError::CompileError { ref log, .. } => Error {
+                    kind: ErrorKind::Unexpected,
+                    message: format!("compiling the kernels failed: {}", log.to_string_lossy()),
+                    cause: Some(Box::new(err)),
+                },
+                err => cuda_error("compiling the kernels", err),
+            },
+        )?;
+        device
+            .load_ptx(ptx, MODULE, &KERNELS)
+            .map_err(|err| cuda_error("loading the kernels", err))?;
+        let functions = KERNELS
+            .iter()
+            .map(|&name| (name, device.get_func(MODULE, name).unwrap()))
+            .collect();
+
+        let cublas =
+            CudaBlas::new(device.clone()).map_err(|err| cuda_error("creating cublas", err))?;
+
+        Ok(Rc::new(Self {
+            opts,
+            device,
+            functions,
+            cublas,
+            debug_tensors: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    /// alloc a zeroed buffer in the device memory.
+    pub(crate) fn new_buffer(&self, bytes: usize) -> Result<CudaBuffer> {
+        // the empty buffer is not allowed
+        let buf = self
+            .device
+            .alloc_zeros::<u8>(bytes.max(4))
+            .map_err(|err| cuda_error("allocating", err))?;
+        Ok(CudaBuffer(buf))
+    }
+
+    pub(crate) fn new_buffer_with_data(&self, data: &[u8]) -> Result<CudaBuffer> {
+        if data.is_empty() {
+            return self.new_buffer(0);
+        }
+        let buf = self
+            .device
+            .htod_sync_copy(data)
+            .map_err(|err| cuda_error("copying to the device", err))?;
+        Ok(CudaBuffer(buf))
+    }
+
+    /// launch the kernel on the default stream, the buffers are passed in order, and the meta
+    /// is passed by value after the buffers.
+    pub(crate) fn dispatch(
+        &self,
+        kernel: &'static str,
+        buffers: &[CUdeviceptr],
+        meta: &[u8],
+        groups: (usize, usize),
+        threads: usize,
+    ) -> Result<()> {
+        let mut buffers = buffers.to_vec();
+        let mut params = buffers
+            .iter_mut()
+            .map(|ptr| ptr as *mut CUdeviceptr as *mut c_void)
+            .collect::<Vec<_>>();
+        params.push(meta.as_ptr() as *mut c_void);
+        let cfg = LaunchConfig {
+            grid_dim: (groups.0 as u32, groups.1 as u32, 1),
+            block_dim: (threads as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        unsafe { self.functions[kernel].clone().launch(cfg, &mut params[..]) }
+            .map_err(|err| cuda_error(kernel, err))
+    }
+
+    /// (m, k) @ (n, k) => (n, m) on cublas, all the matrices are row major, which are taken
+    /// as the transposed column major matrices by cublas.
+    pub(crate) fn sgemm(
+        &self,
+        w: CUdeviceptr,
+        x: CUdeviceptr,
+        out: CUdeviceptr,
+        (m, k, n): (usize, usize, usize),
+    ) -> Result<()> {
+        let (alpha, beta) = (1.0f32, 0.0f32);
+        unsafe {
+            cudarc::cublas::result::sgemm(
+                *self.cublas.handle(),
+                cublasOperation_t::CUBLAS_OP_T,
+                cublasOperation_t::CUBLAS_OP_N,
+                m as i32,
+                n as i32,
+                k as i32,
+                &alpha,
+                w as *const f32,
+                k as i32,
+                x as *const f32,
+                k as i32,
+                &beta,
+                out as *mut f32,
+                m as i32,
+            )
+        }
+        .map_err(|err| cuda_error("cublas sgemm", err))
+    }
+
+    pub(crate) fn copy_buffer(
+        &self,
+        src: CUdeviceptr,
+        dst: CUdeviceptr,
+        bytes: usize,
+    ) -> Result<()> {
+        unsafe { cu::memcpy_dtod_async(dst, src, bytes, *self.device.cu_stream()) }
+            .map_err(|err| cuda_error("copying on the device", err))
+    }
+
+    /// copy the buffer to the host after the pending kernels are done.
+    pub(crate) fn read_buffer(&self, src: CUdeviceptr, dst: &mut [u8]) -> Result<()> {
+        self.sync()?;
+        unsafe { cu::memcpy_dtoh_sync(dst, src) }
+            .map_err(|err| cuda_error("copying to the host", err))
+    }
+
+    /// wait the pending kernels done.
+    pub fn sync(&self) -> Result<()> {
+        self.device
+            .synchronize()
+            .map_err(|err| cuda_error("synchronizing", err))
+    }
+
+    pub fn record_debug_tensor(&self, name: String, tensor: &impl Tensor) {
+        let mut dst = vec![0.0; tensor.strider().len()];
+        tensor.export(&mut dst).unwrap();
+        self.debug_tensors.borrow_mut().insert(name, dst);
+    }
+
+    pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
+        self.debug_tensors.borrow().get(name).cloned()
+    }
+}
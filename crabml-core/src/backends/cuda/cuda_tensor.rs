@@ -0,0 +1,955 @@
+use std::rc::Rc;
+
+use super::cuda_device::CudaBuffer;
+use super::meta::AlibiMeta;
+use super::meta::BatchMatmulMeta;
+use super::meta::BroadcastMeta;
+use super::meta::DequantizeMeta;
+use super::meta::ElementwiseMeta;
+use super::meta::FlashAttentionMeta;
+use super::meta::MatmulMeta;
+use super::meta::NormMeta;
+use super::meta::RopeMeta;
+use super::meta::ScalarMeta;
+use super::CudaTensorDeviceRef;
+use crate::backends::cpu::CpuTensor;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::tensor::alibi_slopes;
+use crate::tensor::RopeMode;
+use crate::tensor::RopeOptions;
+use crate::tensor::Tensor;
+use crate::tensor::TensorBackend;
+use crate::tensor::TensorStrider;
+
+/// the threads of a block on the elementwise and the reduction kernels.
+const N_THREADS: usize = 256;
+
+#[derive(Clone)]
+pub struct CudaTensor {
+    buf: Rc<CudaBuffer>,
+    dtype: GGMLType,
+    capacity: usize, // max element count
+    strider: TensorStrider,
+    device: CudaTensorDeviceRef,
+    name: Option<String>,
+}
+
+impl CudaTensor {
+    pub fn new(src: &[f32], shape: &[usize], device: CudaTensorDeviceRef) -> Result<Self> {
+        let strider = TensorStrider::new(shape.to_vec());
+        if strider.len() != src.len() {
+            return Err((ErrorKind::TensorError, "buffer size mismatch").into());
+        };
+        let buf = device.new_buffer_with_data(bytemuck::cast_slice(src))?;
+        Ok(Self {
+            buf: Rc::new(buf),
+            capacity: src.len(),
+            dtype: GGMLType::F32,
+            strider,
+            device,
+            name: None,
+        })
+    }
+
+    /// the weights of the dtype in the same layout as GGUF, the Q8_0 and Q4_0 weights are kept
+    /// quantized, and multiplied on the device without being dequantized.
+    pub fn from_buf(
+        buf: &[u8],
+        dtype: GGMLType,
+        shape: &[usize],
+        device: CudaTensorDeviceRef,
+    ) -> Result<Self> {
+        if !matches!(dtype, GGMLType::F32 | GGMLType::Q8_0 | GGMLType::Q4_0) {
+            return Err((
+                ErrorKind::NotImplemented,
+                format!("the tensor of {} is not supported on cuda yet", dtype),
+            )
+                .into());
+        }
+        let strider = TensorStrider::new(shape.to_vec());
+        let buf = device.new_buffer_with_data(buf)?;
+        Ok(Self {
+            buf: Rc::new(buf),
+            dtype,
+            capacity: strider.len(),
+            strider,
+            device,
+            name: None,
+        })
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.strider.is_contiguous()
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.strider.shape()
+    }
+
+    fn check_f32(&self, op: &str) -> Result<()> {
+        if self.dtype != GGMLType::F32 {
+            return Err((
+                ErrorKind::TensorError,
+                format!("{} on the tensor of {} is not supported", op, self.dtype),
+            )
+                .into());
+        }
+        Ok(())
+    }
+
+    fn dispatch_elementwise(
+        &self,
+        kernel: &'static str,
+        buffers: &[&CudaBuffer],
+        meta: &[u8],
+    ) -> Result<()> {
+        let n = self.strider.len();
+        let buffers = buffers.iter().map(|buf| buf.ptr()).collect::<Vec<_>>();
+        self.device.dispatch(
+            kernel,
+            &buffers,
+            meta,
+            (n.div_ceil(N_THREADS), 1),
+            N_THREADS,
+        )
+    }
+
+    /// (m, k) @ (n, k) => (n, m) on the f32 or the quantized matrix.
+    fn matmul_rows(&self, y: &Self, output: &Self) -> Result<()> {
+        let (m, k) = (self.shape()[0], self.shape()[1]);
+        let n = y.strider.len() / k;
+        let kernel = match self.dtype {
+            GGMLType::F32 => {
+                return self
+                    .device
+                    .sgemm(self.buf.ptr(), y.buf.ptr(), output.buf.ptr(), (m, k, n));
+            }
+            GGMLType::Q8_0 if k % 32 == 0 => "matmul_q8_0",
+            GGMLType::Q4_0 if k % 32 == 0 => "matmul_q4_0",
+            _ => {
+                return Err((
+                    ErrorKind::NotImplemented,
+                    format!(
+                        "matmul on {} of {:?} is not supported",
+                        self.dtype,
+                        self.shape()
+                    ),
+                )
+                    .into());
+            }
+        };
+
+        let meta = MatmulMeta {
+            m: m as u32,
+            n: n as u32,
+            k: k as u32,
+        };
+        self.device.dispatch(
+            kernel,
+            &[self.buf.ptr(), y.buf.ptr(), output.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (m, n),
+            32,
+        )
+    }
+}
+
+impl Tensor for CudaTensor {
+    type Device = CudaTensorDeviceRef;
+
+    fn alloc(shape: &[usize], capacity: Option<usize>, device: Self::Device) -> Result<Self> {
+        let n_elms = shape.iter().product::<usize>();
+        let capacity = capacity.unwrap_or(n_elms);
+        assert!(capacity >= n_elms);
+
+        let buf = device.new_buffer(capacity * std::mem::size_of::<f32>())?;
+        let strider = TensorStrider::new(shape.to_vec());
+        Ok(Self {
+            buf: Rc::new(buf),
+            dtype: GGMLType::F32,
+            capacity,
+            strider,
+            device,
+            name: None,
+        })
+    }
+
+    fn alloc_cache(
+        shape: &[usize],
+        capacity: Option<usize>,
+        dtype: GGMLType,
+        device: Self::Device,
+    ) -> Result<Self> {
+        match dtype {
+            GGMLType::F32 => Self::alloc(shape, capacity, device),
+            _ => Err((
+                ErrorKind::NotImplemented,
+                format!("the kv cache of {} is not supported on cuda yet", dtype),
+            )
+                .into()),
+        }
+    }
+
+    fn from_vec(buf: Vec<f32>, shape: &[usize], device: Self::Device) -> Result<Self> {
+        Self::new(&buf, shape, device)
+    }
+
+    fn dtype(&self) -> GGMLType {
+        self.dtype
+    }
+
+    fn with_strider(self, strider: TensorStrider) -> Result<Self> {
+        Ok(Self {
+            buf: self.buf,
+            capacity: self.capacity,
+            dtype: self.dtype,
+            strider,
+            device: self.device,
+            name: None,
+        })
+    }
+
+    fn with_name(mut self, name: String) -> Self {
+        if self.device.opts.debug_named_tensor {
+            self.device.record_debug_tensor(name.clone(), &self);
+        }
+
+        self.name = Some(name);
+        self
+    }
+
+    fn reshape(self, shape: &[usize]) -> Result<Self> {
+        let strider = self.strider.reshape(shape.to_vec())?;
+        self.with_strider(strider)
+    }
+
+    fn transpose(self, dims: &[usize]) -> Result<Self> {
+        let strider = self.strider.transpose(dims)?;
+        self.with_strider(strider)
+    }
+
+    fn slice(self, axis: usize, start: usize, len: usize) -> Result<Self> {
+        let strider = self.strider.slice(axis, start, len)?;
+        self.with_strider(strider)
+    }
+
+    fn strider(&self) -> &TensorStrider {
+        &self.strider
+    }
+
+    fn extend(&mut self, rhs: &Self) -> Result<()> {
+        let new_len = self.strider.len() + rhs.strider.len();
+        if new_len > self.capacity {
+            return Err((
+                ErrorKind::TensorError,
+                format!("exceeded capacity at {}", self.capacity),
+            )
+                .into());
+        }
+        if !rhs.shape().eq(&self.shape()[1..]) {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "shape mismatch on extend, want {:?} but got {:?}",
+                    &self.shape()[1..],
+                    &rhs.shape()
+                ),
+            )
+                .into());
+        }
+
+        let f32_size = std::mem::size_of::<f32>();
+        self.device.copy_buffer(
+            rhs.buf.ptr(),
+            self.buf.ptr() + (self.strider.len() * f32_size) as u64,
+            rhs.strider.len() * f32_size,
+        )?;
+
+        let mut new_shape = self.shape().to_vec();
+        new_shape[0] += 1;
+        self.strider = TensorStrider::new(new_shape);
+        Ok(())
+    }
+
+    fn set_row(&mut self, row: usize, rhs: &Self) -> Result<()> {
+        if !rhs.shape().eq(&self.shape()[1..]) || row >= self.shape()[0] {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "shape mismatch on set_row {}, want {:?} but got {:?}",
+                    row,
+                    &self.shape(),
+                    &rhs.shape()
+                ),
+            )
+                .into());
+        }
+
+        let row_bytes = rhs.strider.len() * std::mem::size_of::<f32>();
+        self.device.copy_buffer(
+            rhs.buf.ptr(),
+            self.buf.ptr() + (row * row_bytes) as u64,
+            row_bytes,
+        )
+    }
+
+    fn repeat_n(self, n: usize) -> Result<Self> {
+        let mut tmp_shape = self.shape().to_vec();
+        tmp_shape.insert(0, 0);
+        let capacity = self.strider.len() * n;
+        let mut new_tensor = Self::alloc(&tmp_shape, Some(capacity), self.device.clone())?;
+        for _ in 0..n {
+            new_tensor.extend(&self)?;
+        }
+        let mut new_shape = self.shape().to_vec();
+        new_shape[0] *= n;
+        new_tensor.reshape(&new_shape)
+    }
+
+    fn copy_from(&mut self, rhs: &Self, pos: &[usize], len: usize) -> Result<()> {
+        if !self.is_contiguous() {
+            return Err((ErrorKind::TensorError, "not contiguous").into());
+        }
+
+        let offset = rhs.strider.at(pos)?;
+        let kernel = match rhs.dtype {
+            GGMLType::F32 => {
+                let f32_size = std::mem::size_of::<f32>();
+                return self.device.copy_buffer(
+                    rhs.buf.ptr() + (offset * f32_size) as u64,
+                    self.buf.ptr(),
+                    len * f32_size,
+                );
+            }
+            // the rows of the quantized embeddings are dequantized on copying
+            GGMLType::Q8_0 if offset % 32 == 0 => "dequantize_q8_0",
+            GGMLType::Q4_0 if offset % 32 == 0 => "dequantize_q4_0",
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!(
+                        "can not copy from the tensor of {} at {}",
+                        rhs.dtype, offset
+                    ),
+                )
+                    .into());
+            }
+        };
+        let meta = DequantizeMeta {
+            offset: (offset / 32) as u32,
+            n: len as u32,
+        };
+        self.device.dispatch(
+            kernel,
+            &[rhs.buf.ptr(), self.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (len.div_ceil(N_THREADS), 1),
+            N_THREADS,
+        )
+    }
+
+    fn export(&self, dst: &mut [f32]) -> Result<()> {
+        self.check_f32("export")?;
+
+        self.device
+            .read_buffer(self.buf.ptr(), bytemuck::cast_slice_mut(dst))
+    }
+
+    fn dup(&self) -> Result<Self> {
+        // keep the capacity, so the duplicated kv cache can still be extended
+        let mut new_tensor = Self::alloc(
+            self.strider.shape(),
+            Some(self.capacity),
+            self.device.clone(),
+        )?;
+        new_tensor.copy_from(self, &vec![0; self.shape().len()], self.strider.len())?;
+        Ok(new_tensor)
+    }
+
+    fn to_dtype(&self, dtype: GGMLType) -> Result<Self> {
+        if dtype == self.dtype {
+            return self.dup();
+        }
+        Err((
+            ErrorKind::NotImplemented,
+            format!("the {} activations are not supported on cuda yet", dtype),
+        )
+            .into())
+    }
+
+    fn rope_inplace(self, pos: usize, rope: &RopeOptions) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(self.is_contiguous());
+        self.check_f32("rope")?;
+
+        let (n_heads, head_size) = (self.shape()[0], self.shape()[1]);
+        let meta = RopeMeta {
+            n_heads: n_heads as u32,
+            head_size: head_size as u32,
+            rope_dims: rope.dims as u32,
+            mode: match rope.mode {
+                RopeMode::Normal => 0,
+                RopeMode::Neox => 1,
+            },
+        };
+        let cos_sin = rope
+            .cos_sin(pos)
+            .into_iter()
+            .flat_map(|(cos, sin)| [cos, sin])
+            .collect::<Vec<_>>();
+        let cos_sin_buf = self
+            .device
+            .new_buffer_with_data(bytemuck::cast_slice(&cos_sin))?;
+        let n_pairs = rope.dims / 2;
+        self.device.dispatch(
+            "rope_inplace",
+            &[self.buf.ptr(), cos_sin_buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (n_pairs.div_ceil(32), n_heads),
+            32,
+        )?;
+        Ok(self)
+    }
+
+    fn alibi_inplace(self, pos: usize, max_bias: f32) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(self.is_contiguous());
+        self.check_f32("alibi")?;
+
+        let (n_heads, n_seq) = (self.shape()[0], self.shape()[1]);
+        let meta = AlibiMeta {
+            n_heads: n_heads as u32,
+            n_seq: n_seq as u32,
+            pos: pos as u32,
+        };
+        let slopes = alibi_slopes(n_heads, max_bias);
+        let slopes_buf = self
+            .device
+            .new_buffer_with_data(bytemuck::cast_slice(&slopes))?;
+        self.device.dispatch(
+            "alibi_inplace",
+            &[self.buf.ptr(), slopes_buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (n_seq.div_ceil(32), n_heads),
+            32,
+        )?;
+        Ok(self)
+    }
+
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("layer_norm")?;
+
+        let meta = NormMeta {
+            m: 1,
+            n: self.strider.len() as u32,
+            eps,
+        };
+        self.device.dispatch(
+            "layer_norm_inplace",
+            &[self.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (1, 1),
+            N_THREADS,
+        )?;
+        Ok(self)
+    }
+
+    fn rms_norm_inplace(self, eps: f32) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("rms_norm")?;
+
+        let meta = NormMeta {
+            m: 1,
+            n: self.strider.len() as u32,
+            eps,
+        };
+        self.device.dispatch(
+            "rms_norm_inplace",
+            &[self.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (1, 1),
+            N_THREADS,
+        )?;
+        Ok(self)
+    }
+
+    fn rms_norm_mul_inplace(self, eps: f32, weight: &Self) -> Result<Self> {
+        assert!(self.is_contiguous() && weight.is_contiguous());
+        assert!(self.strider.len() == weight.strider.len());
+        self.check_f32("rms_norm")?;
+        weight.check_f32("rms_norm")?;
+
+        let meta = NormMeta {
+            m: 1,
+            n: self.strider.len() as u32,
+            eps,
+        };
+        self.device.dispatch(
+            "rms_norm_mul_inplace",
+            &[self.buf.ptr(), weight.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (1, 1),
+            N_THREADS,
+        )?;
+        Ok(self)
+    }
+
+    fn softmax_inplace(self, axis: usize) -> Result<Self> {
+        assert!(axis == 1);
+        assert!(self.is_contiguous());
+        assert!(self.shape().len() == 2);
+        self.check_f32("softmax")?;
+
+        let (m, n) = (self.shape()[0], self.shape()[1]);
+        let meta = NormMeta {
+            m: m as u32,
+            n: n as u32,
+            eps: 0.0,
+        };
+        self.device.dispatch(
+            "softmax_inplace",
+            &[self.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (m, 1),
+            N_THREADS,
+        )?;
+        Ok(self)
+    }
+
+    fn silu_inplace(self) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("silu")?;
+
+        let meta = ElementwiseMeta {
+            n: self.strider.len() as u32,
+        };
+        self.dispatch_elementwise("silu_inplace", &[&self.buf], bytemuck::bytes_of(&meta))?;
+        Ok(self)
+    }
+
+    fn silu_mul_inplace(self, rhs: &Self) -> Result<Self> {
+        assert!(self.shape() == rhs.shape());
+        assert!(self.is_contiguous() && rhs.is_contiguous());
+        self.check_f32("silu")?;
+        rhs.check_f32("silu")?;
+
+        let meta = ElementwiseMeta {
+            n: self.strider.len() as u32,
+        };
+        self.dispatch_elementwise(
+            "silu_mul_inplace",
+            &[&self.buf, &rhs.buf],
+            bytemuck::bytes_of(&meta),
+        )?;
+        Ok(self)
+    }
+
+    fn gelu_inplace(self) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("gelu")?;
+
+        let meta = ElementwiseMeta {
+            n: self.strider.len() as u32,
+        };
+        self.dispatch_elementwise("gelu_inplace", &[&self.buf], bytemuck::bytes_of(&meta))?;
+        Ok(self)
+    }
+
+    fn mul_inplace(self, rhs: &Self) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("mul")?;
+        rhs.check_f32("mul")?;
+
+        let meta = BroadcastMeta::new(&rhs.strider.broadcast_to(self.shape())?)?;
+        self.dispatch_elementwise(
+            "mul_inplace",
+            &[&self.buf, &rhs.buf],
+            bytemuck::bytes_of(&meta),
+        )?;
+        Ok(self)
+    }
+
+    fn add_inplace(self, rhs: &Self) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("add")?;
+        rhs.check_f32("add")?;
+
+        let meta = BroadcastMeta::new(&rhs.strider.broadcast_to(self.shape())?)?;
+        self.dispatch_elementwise(
+            "add_inplace",
+            &[&self.buf, &rhs.buf],
+            bytemuck::bytes_of(&meta),
+        )?;
+        Ok(self)
+    }
+
+    fn div_scalar_inplace(self, rhs: f32) -> Result<Self> {
+        assert!(self.is_contiguous());
+        self.check_f32("div")?;
+
+        let meta = ScalarMeta {
+            rhs,
+            n: self.strider.len() as u32,
+        };
+        self.dispatch_elementwise(
+            "div_scalar_inplace",
+            &[&self.buf],
+            bytemuck::bytes_of(&meta),
+        )?;
+        Ok(self)
+    }
+
+    fn matmul_vec(&self, y: &Self) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(y.shape().len() == 1);
+        assert!(self.shape()[1] == y.shape()[0]);
+        assert!(self.is_contiguous());
+        assert!(y.is_contiguous());
+        y.check_f32("matmul")?;
+
+        let output = Self::alloc(&[self.shape()[0]], None, self.device.clone())?;
+        self.matmul_rows(y, &output)?;
+        Ok(output)
+    }
+
+    fn matmul(&self, y: &Self) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(y.shape().len() == 2);
+        assert!(self.shape()[1] == y.shape()[1]);
+        assert!(self.is_contiguous());
+        assert!(y.is_contiguous());
+        y.check_f32("matmul")?;
+
+        // (m, k) @ (n, k) => (n, m)
+        let output = Self::alloc(&[y.shape()[0], self.shape()[0]], None, self.device.clone())?;
+        self.matmul_rows(y, &output)?;
+        Ok(output)
+    }
+
+    fn batch_matmul_vec(&self, y: &Self) -> Result<Self> {
+        assert!(self.shape().len() == 3);
+        assert!(y.shape().len() == 2);
+        assert!(y.shape()[0] % self.shape()[0] == 0);
+        assert!(self.shape()[2] == y.shape()[1]);
+        assert!(y.strider.strides()[1] == 1);
+        self.check_f32("batch_matmul")?;
+
+        // (m / g, n, k) @ (m, k) => (m, n), both may be the views at an offset
+        let (m, n) = (y.shape()[0], self.shape()[1]);
+        let output = Self::alloc(&[m, n], None, self.device.clone())?;
+        let strides = self.strider.strides();
+        let meta = BatchMatmulMeta {
+            m: m as u32,
+            n: n as u32,
+            k: self.shape()[2] as u32,
+            g: (m / self.shape()[0]) as u32,
+            strides: [strides[0] as u32, strides[1] as u32, strides[2] as u32],
+            offsets: [self.strider.offset() as u32, y.strider.offset() as u32],
+            b_stride: y.strider.strides()[0] as u32,
+        };
+        self.device.dispatch(
+            "batch_matmul",
+            &[self.buf.ptr(), y.buf.ptr(), output.buf.ptr()],
+            bytemuck::bytes_of(&meta),
+            (n, m),
+            32,
+        )?;
+        Ok(output)
+    }
+
+    fn flash_attention(
+        &self,
+        k_pages: &[&Self],
+        v_pages: &[&Self],
+        scale: f32,
+        alibi: Option<(usize, f32)>,
+    ) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(self.is_contiguous());
+        assert!(k_pages.len() == v_pages.len());
+        if k_pages.is_empty() {
+            return Err((ErrorKind::TensorError, "the kv cache is empty").into());
+        }
+        for t in k_pages.iter().chain(v_pages) {
+            assert!(t.is_contiguous());
+            t.check_f32("flash_attention")?;
+        }
+
+        // (n_heads, head_size) on the pages of (page_len, n_kv_heads, head_size)
+        let (n_heads, head_size) = (self.shape()[0], self.shape()[1]);
+        let n_kv_heads = k_pages[0].shape()[1];
+        let n_seq = k_pages.iter().map(|k| k.shape()[0]).sum::<usize>();
+        let output = Self::alloc(&[n_heads, head_size], None, self.device.clone())?;
+        let state = Self::alloc(&[n_heads, head_size + 2], None, self.device.clone())?;
+        let (pos, slopes) = match alibi {
+            Some((pos, max_bias)) => (pos, alibi_slopes(n_heads, max_bias)),
+            None => (0, vec![0.0; n_heads]),
+        };
+        let slopes_buf = self
+            .device
+            .new_buffer_with_data(bytemuck::cast_slice(&slopes))?;
+
+        let mut offset = 0;
+        for (i, (k, v)) in k_pages.iter().zip(v_pages).enumerate() {
+            let first = (i == 0) as u32;
+            let last = (i == k_pages.len() - 1) as u32;
+            let meta = FlashAttentionMeta {
+                n_heads: n_heads as u32,
+                n_kv_heads: n_kv_heads as u32,
+                head_size: head_size as u32,
+                page_len: k.shape()[0] as u32,
+                offset: offset as u32,
+                n_seq: n_seq as u32,
+                pos: pos as u32,
+                flags: first | last << 1 | (alibi.is_some() as u32) << 2,
+                scale,
+            };
+            self.device.dispatch(
+                "flash_attention",
+                &[
+                    self.buf.ptr(),
+                    k.buf.ptr(),
+                    v.buf.ptr(),
+                    slopes_buf.ptr(),
+                    state.buf.ptr(),
+                    output.buf.ptr(),
+                ],
+                bytemuck::bytes_of(&meta),
+                (n_heads, 1),
+                32,
+            )?;
+            offset += k.shape()[0];
+        }
+        Ok(output)
+    }
+}
+
+impl TensorBackend for CudaTensorDeviceRef {
+    type Tensor = CudaTensor;
+
+    fn name(&self) -> &'static str {
+        "cuda"
+    }
+
+    fn upload(&self, tensor: &CpuTensor) -> Result<CudaTensor> {
+        // the Q8_0 and Q4_0 weights are kept quantized, the others are dequantized
+        match tensor.dtype() {
+            GGMLType::F32 | GGMLType::Q8_0 | GGMLType::Q4_0 => CudaTensor::from_buf(
+                tensor.buf().as_bytes(),
+                tensor.dtype(),
+                tensor.shape(),
+                self.clone(),
+            ),
+            _ => {
+                let tensor = tensor.clone().dequantize(GGMLType::F32)?;
+                CudaTensor::from_buf(
+                    tensor.buf().as_bytes(),
+                    GGMLType::F32,
+                    tensor.shape(),
+                    self.clone(),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::LazyLock;
+
+    use approx::assert_relative_eq;
+
+    use super::CudaTensor;
+    use crate::backends::cpu::CpuTensor;
+    use crate::backends::cpu::CpuTensorBuf;
+    use crate::backends::cpu::CpuTensorDevice;
+    use crate::backends::cuda::CudaTensorDevice;
+    use crate::backends::cuda::CudaTensorDeviceOptions;
+    use crate::backends::cuda::CudaTensorDeviceRef;
+    use crate::error::Result;
+    use crate::gguf::GGMLType;
+    use crate::tensor::RopeMode;
+    use crate::tensor::RopeOptions;
+    use crate::tensor::Tensor;
+
+    #[thread_local]
+    static DEVICE: LazyLock<CudaTensorDeviceRef> = LazyLock::new(|| {
+        CudaTensorDevice::new(CudaTensorDeviceOptions::new().with_debug_named_tensor(true))
+    });
+
+    fn export(t: &CudaTensor) -> Result<Vec<f32>> {
+        let mut dst = vec![0.0; t.strider().len()];
+        t.export(&mut dst)?;
+        Ok(dst)
+    }
+
+    #[test]
+    fn test_cuda_elementwise() -> Result<()> {
+        let t1 = CudaTensor::new(&[2.0; 1000], &[1000], DEVICE.clone())?;
+        let t2 = CudaTensor::new(&[3.0; 1000], &[1000], DEVICE.clone())?;
+        let t1 = t1.add_inplace(&t2)?;
+        assert_eq!(export(&t1)?, vec![5.0; 1000]);
+        let t1 = t1.mul_inplace(&t2)?.div_scalar_inplace(5.0)?;
+        assert_eq!(export(&t1)?, vec![3.0; 1000]);
+
+        let t1 = CudaTensor::alloc(&[16, 2], None, DEVICE.clone())?;
+        assert_eq!(export(&t1)?, vec![0.0; 32]);
+        let _ = t1.with_name("t1".to_string());
+        assert_eq!(DEVICE.dump_debug_tensor("t1").unwrap(), vec![0.0; 32]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cuda_activations_and_norms() -> Result<()> {
+        let device_cpu = CpuTensorDevice::new();
+        let v = (0..96).map(|i| (i as f32 - 48.0) / 8.0).collect::<Vec<_>>();
+        let t1 = || CudaTensor::new(&v, &[96], DEVICE.clone());
+        let t2 = || CpuTensor::new(v.clone(), &[96], device_cpu.clone());
+        let pairs = [
+            (t1()?.silu_inplace()?, t2()?.silu_inplace()?),
+            (t1()?.gelu_inplace()?, t2()?.gelu_inplace()?),
+            (t1()?.rms_norm_inplace(1e-5)?, t2()?.rms_norm_inplace(1e-5)?),
+            (
+                t1()?.layer_norm_inplace(1e-5)?,
+                t2()?.layer_norm_inplace(1e-5)?,
+            ),
+        ];
+        for (got, want) in pairs {
+            assert_relative_eq!(
+                export(&got)?[..],
+                want.buf().as_f32_ref()[..],
+                epsilon = 1e-5
+            );
+        }
+
+        let t1 = CudaTensor::new(&[1.0, 2.0, 3.0, 1.0, 1.0, 1.0], &[2, 3], DEVICE.clone())?;
+        let t1 = t1.softmax_inplace(1)?;
+        assert_relative_eq!(
+            export(&t1)?[..],
+            [
+                0.09003057, 0.24472847, 0.66524096, 0.33333334, 0.33333334, 0.33333334
+            ][..],
+            epsilon = 1e-6
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cuda_rope_and_alibi() -> Result<()> {
+        let device_cpu = CpuTensorDevice::new();
+        let v = (0..64).map(|i| i as f32).collect::<Vec<_>>();
+        for mode in [RopeMode::Normal, RopeMode::Neox] {
+            let rope = RopeOptions::new(mode, 8);
+            let t1 = CudaTensor::new(&v, &[4, 16], DEVICE.clone())?.rope_inplace(3, &rope)?;
+            let t2 =
+                CpuTensor::new(v.clone(), &[4, 16], device_cpu.clone())?.rope_inplace(3, &rope)?;
+            assert_relative_eq!(export(&t1)?[..], t2.buf().as_f32_ref()[..], epsilon = 1e-4);
+        }
+
+        let t1 = CudaTensor::new(&v[..16], &[2, 8], DEVICE.clone())?.alibi_inplace(7, 8.0)?;
+        let t2 =
+            CpuTensor::new(v[..16].to_vec(), &[2, 8], device_cpu.clone())?.alibi_inplace(7, 8.0)?;
+        assert_relative_eq!(export(&t1)?[..], t2.buf().as_f32_ref()[..], epsilon = 1e-6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cuda_matmul() -> Result<()> {
+        // more blocks in a row than the lanes of a warp
+        let (m, k, n) = (48, 36 * 32, 3);
+        let w = (0..m * k)
+            .map(|i| ((i * 7 % 13) as f32 - 6.0) / 10.0)
+            .collect::<Vec<_>>();
+        let x = (0..n * k).map(|i| (i % 5) as f32 - 2.0).collect::<Vec<_>>();
+        let x_cuda = CudaTensor::new(&x, &[n, k], DEVICE.clone())?;
+        for dtype in [GGMLType::F32, GGMLType::Q8_0, GGMLType::Q4_0] {
+            // compare with the f32 weights dequantized from the same blocks
+            let w_buf = CpuTensorBuf::F32(w.clone().into()).quantize(dtype)?;
+            let w_cuda = CudaTensor::from_buf(w_buf.as_bytes(), dtype, &[m, k], DEVICE.clone())?;
+            let w_deq = w_buf.dequantize(GGMLType::F32)?;
+            let w_deq = w_deq.as_f32_ref();
+            let mut want = vec![0.0; n * m];
+            for c in 0..n {
+                for r in 0..m {
+                    want[c * m + r] = (0..k).map(|i| w_deq[r * k + i] * x[c * k + i]).sum();
+                }
+            }
+
+            let got = w_cuda.matmul(&x_cuda)?;
+            assert_eq!(got.shape(), &[n, m]);
+            assert_relative_eq!(
+                export(&got)?[..],
+                want[..],
+                epsilon = 1e-3,
+                max_relative = 1e-4
+            );
+            let x_row = CudaTensor::new(&x[..k], &[k], DEVICE.clone())?;
+            let got = w_cuda.matmul_vec(&x_row)?;
+            assert_relative_eq!(
+                export(&got)?[..],
+                want[..m],
+                epsilon = 1e-3,
+                max_relative = 1e-4
+            );
+
+            // the embeddings are dequantized on copy_from
+            let mut row = CudaTensor::alloc(&[k], None, DEVICE.clone())?;
+            row.copy_from(&w_cuda, &[2, 0], k)?;
+            assert_relative_eq!(export(&row)?[..], w_deq[2 * k..3 * k], epsilon = 1e-6);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cuda_batch_matmul() -> Result<()> {
+        // (2, 3, 4) @ (4, 4) => (4, 3), every 2 rows share a batch
+        let a = (0..24).map(|i| i as f32).collect::<Vec<_>>();
+        let b = (0..16).map(|i| (i % 3) as f32).collect::<Vec<_>>();
+        let t1 = CudaTensor::new(&a, &[2, 3, 4], DEVICE.clone())?;
+        let t2 = CudaTensor::new(&b, &[4, 4], DEVICE.clone())?;
+        let got = export(&t1.batch_matmul_vec(&t2)?)?;
+
+        let mut want = vec![0.0; 12];
+        for mi in 0..4 {
+            for ni in 0..3 {
+                want[mi * 3 + ni] = (0..4)
+                    .map(|ki| a[(mi / 2) * 12 + ni * 4 + ki] * b[mi * 4 + ki])
+                    .sum();
+            }
+        }
+        assert_eq!(got, want);
+
+        // the transposed values
+        let t1 = CudaTensor::new(&a, &[2, 4, 3], DEVICE.clone())?.transpose(&[0, 2, 1])?;
+        let got = export(&t1.batch_matmul_vec(&t2)?)?;
+        for mi in 0..4 {
+            for ni in 0..3 {
+                want[mi * 3 + ni] = (0..4)
+                    .map(|ki| a[(mi / 2) * 12 + ki * 3 + ni] * b[mi * 4 + ki])
+                    .sum();
+            }
+        }
+        assert_eq!(got, want);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cuda_extend_and_set_row() -> Result<()> {
+        let mut t1 = CudaTensor::alloc(&[0, 4], Some(12), DEVICE.clone())?;
+        let row = |v: f32| CudaTensor::new(&[v; 4], &[4], DEVICE.clone());
+        t1.extend(&row(1.0)?)?;
+        t1.extend(&row(2.0)?)?;
+        assert_eq!(t1.shape(), &[2, 4]);
+        t1.set_row(0, &row(3.0)?)?;
+        assert_eq!(export(&t1)?, [[3.0; 4], [2.0; 4]].concat());
+
+        let mut t2 = t1.dup()?;
+        t2.extend(&row(4.0)?)?;
+        assert_eq!(export(&t2)?, [[3.0; 4], [2.0; 4], [4.0; 4]].concat());
+        assert!(t2.extend(&row(5.0)?).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,504 @@
+// compiled by nvrtc on creating the device, there's no include path in nvrtc, so the halfs are
+// converted by ptx instead of cuda_fp16.h, and there's no INFINITY of math.h
+
+#define NEG_INFINITY __int_as_float(0xff800000)
+
+// the blocks in the same layout as GGUF, the quantized weights are kept as they are in the
+// device memory, and multiplied block by block on matmul
+struct block_q8_0 {
+    unsigned short d;
+    signed char qs[32];
+};
+
+struct block_q4_0 {
+    unsigned short d;
+    unsigned char qs[16]; // the low nibbles are the first 16 quants, the high nibbles the rest
+};
+
+struct ElementwiseMeta {
+    unsigned int n;
+};
+
+// the rhs of add and mul broadcasted onto the lhs of n elements, the broadcasted axes of the
+// rhs have the stride of 0
+struct BroadcastMeta {
+    unsigned int n;
+    unsigned int rank;
+    unsigned int offset;
+    unsigned int _padding;
+    unsigned int shape[4];
+    unsigned int strides[4];
+};
+
+// the index of the rhs on the i-th element of the lhs
+__device__ unsigned int broadcast_index(const BroadcastMeta &meta, unsigned int i) {
+    unsigned int j = meta.offset;
+    for (unsigned int d = meta.rank; d > 0; d--) {
+        j += (i % meta.shape[d - 1]) * meta.strides[d - 1];
+        i /= meta.shape[d - 1];
+    }
+    return j;
+}
+
+struct ScalarMeta {
+    float rhs;
+    unsigned int n;
+};
+
+struct NormMeta {
+    unsigned int m; // number of vectors
+    unsigned int n; // length of each vector
+    float eps;
+};
+
+struct RopeMeta {
+    unsigned int n_heads;
+    unsigned int head_size;
+    unsigned int rope_dims;
+    unsigned int mode; // 0: normal, 1: neox
+};
+
+struct AlibiMeta {
+    unsigned int n_heads;
+    unsigned int n_seq;
+    unsigned int pos;
+};
+
+// (m / g, n, k) @ (m, k) => (m, n), every g rows of the input share a batch of the matrix
+struct BatchMatmulMeta {
+    unsigned int m;
+    unsigned int n;
+    unsigned int k;
+    unsigned int g;
+    unsigned int strides[3];
+    unsigned int offsets[2]; // the offsets of a and b, which may be the slices
+    unsigned int b_stride;   // the stride of the rows of b
+};
+
+// (m, k) @ (n, k) => (n, m) on the quantized matrix
+struct MatmulMeta {
+    unsigned int m;
+    unsigned int n;
+    unsigned int k;
+};
+
+struct FlashAttentionMeta {
+    unsigned int n_heads;
+    unsigned int n_kv_heads;
+    unsigned int head_size;
+    unsigned int page_len;
+    unsigned int offset; // the row of the first key of the page in the sequence
+    unsigned int n_seq;
+    unsigned int pos;
+    unsigned int flags; // 1: the first page, 2: the last page, 4: with alibi
+    float scale;
+};
+
+struct DequantizeMeta {
+    unsigned int offset; // in blocks
+    unsigned int n;
+};
+
+static __device__ __forceinline__ float half_to_float(unsigned short h) {
+    float f;
+    asm("{ .reg .f16 t; mov.b16 t, %1; cvt.f32.f16 %0, t; }" : "=f"(f) : "h"(h));
+    return f;
+}
+
+static __device__ __forceinline__ float warp_sum(float v) {
+    for (int offset = 16; offset > 0; offset /= 2) {
+        v += __shfl_xor_sync(0xffffffff, v, offset);
+    }
+    return v;
+}
+
+static __device__ __forceinline__ float warp_max(float v) {
+    for (int offset = 16; offset > 0; offset /= 2) {
+        v = fmaxf(v, __shfl_xor_sync(0xffffffff, v, offset));
+    }
+    return v;
+}
+
+static __device__ float block_sum(float v, float *sums) {
+    unsigned int lane = threadIdx.x % 32;
+    unsigned int warp = threadIdx.x / 32;
+    v = warp_sum(v);
+    if (lane == 0) {
+        sums[warp] = v;
+    }
+    __syncthreads();
+    float sum = 0.0f;
+    for (unsigned int i = 0; i < blockDim.x / 32; i++) {
+        sum += sums[i];
+    }
+    __syncthreads();
+    return sum;
+}
+
+static __device__ float block_max(float v, float *maxs) {
+    unsigned int lane = threadIdx.x % 32;
+    unsigned int warp = threadIdx.x / 32;
+    v = warp_max(v);
+    if (lane == 0) {
+        maxs[warp] = v;
+    }
+    __syncthreads();
+    float m = NEG_INFINITY;
+    for (unsigned int i = 0; i < blockDim.x / 32; i++) {
+        m = fmaxf(m, maxs[i]);
+    }
+    __syncthreads();
+    return m;
+}
+
+extern "C" __global__ void add_inplace(float *a, const float *b, BroadcastMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < meta.n) {
+        a[i] += b[broadcast_index(meta, i)];
+    }
+}
+
+extern "C" __global__ void mul_inplace(float *a, const float *b, BroadcastMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < meta.n) {
+        a[i] *= b[broadcast_index(meta, i)];
+    }
+}
+
+extern "C" __global__ void div_scalar_inplace(float *a, ScalarMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < meta.n) {
+        a[i] /= meta.rhs;
+    }
+}
+
+extern "C" __global__ void silu_inplace(float *a, ElementwiseMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < meta.n) {
+        float v = a[i];
+        a[i] = v / (1.0f + expf(-v));
+    }
+}
+
+extern "C" __global__ void silu_mul_inplace(float *a, const float *b, ElementwiseMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < meta.n) {
+        float v = a[i];
+        a[i] = v / (1.0f + expf(-v)) * b[i];
+    }
+}
+
+// the tanh approximation: 0.5 * v * (1 + tanh(sqrt(2 / pi) * (v + 0.044715 * v^3)))
+extern "C" __global__ void gelu_inplace(float *a, ElementwiseMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < meta.n) {
+        float v = a[i];
+        a[i] = 0.5f * v * (1.0f + tanhf(0.7978846f * v * (1.0f + 0.044715f * v * v)));
+    }
+}
+
+// each block normalizes a vector
+
+extern "C" __global__ void rms_norm_inplace(float *x, NormMeta meta) {
+    __shared__ float sums[32];
+    float *v = x + blockIdx.x * meta.n;
+
+    float ss = 0.0f;
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        ss += v[i] * v[i];
+    }
+    ss = block_sum(ss, sums);
+
+    float scale = rsqrtf(ss / (float)meta.n + meta.eps);
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        v[i] *= scale;
+    }
+}
+
+extern "C" __global__ void rms_norm_mul_inplace(float *x, const float *w, NormMeta meta) {
+    __shared__ float sums[32];
+    float *v = x + blockIdx.x * meta.n;
+
+    float ss = 0.0f;
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        ss += v[i] * v[i];
+    }
+    ss = block_sum(ss, sums);
+
+    float scale = rsqrtf(ss / (float)meta.n + meta.eps);
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        v[i] = v[i] * scale * w[i];
+    }
+}
+
+extern "C" __global__ void layer_norm_inplace(float *x, NormMeta meta) {
+    __shared__ float sums[32];
+    float *v = x + blockIdx.x * meta.n;
+
+    float sum = 0.0f;
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        sum += v[i];
+    }
+    float mean = block_sum(sum, sums) / (float)meta.n;
+
+    float ss = 0.0f;
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        float d = v[i] - mean;
+        ss += d * d;
+    }
+    ss = block_sum(ss, sums);
+
+    float scale = rsqrtf(ss / (float)meta.n + meta.eps);
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        v[i] = (v[i] - mean) * scale;
+    }
+}
+
+extern "C" __global__ void softmax_inplace(float *x, NormMeta meta) {
+    __shared__ float shared[32];
+    float *v = x + blockIdx.x * meta.n;
+
+    float m = NEG_INFINITY;
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        m = fmaxf(m, v[i]);
+    }
+    m = block_max(m, shared);
+
+    float sum = 0.0f;
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        float e = expf(v[i] - m);
+        v[i] = e;
+        sum += e;
+    }
+    sum = block_sum(sum, shared);
+
+    for (unsigned int i = threadIdx.x; i < meta.n; i += blockDim.x) {
+        v[i] /= sum;
+    }
+}
+
+// each thread rotates a pair of a head, the (cos, sin) of each pair are computed on the host
+extern "C" __global__ void rope_inplace(float *x, const float2 *cos_sin, RopeMeta meta) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    unsigned int h = blockIdx.y;
+    unsigned int half_dims = meta.rope_dims / 2;
+    if (i >= half_dims || h >= meta.n_heads) {
+        return;
+    }
+
+    unsigned int i0 = i * 2;
+    unsigned int i1 = i * 2 + 1;
+    if (meta.mode == 1) {
+        i0 = i;
+        i1 = i + half_dims;
+    }
+    float *v = x + h * meta.head_size;
+    float2 cs = cos_sin[i];
+    float q0 = v[i0];
+    float q1 = v[i1];
+    v[i0] = q0 * cs.x - q1 * cs.y;
+    v[i1] = q0 * cs.y + q1 * cs.x;
+}
+
+extern "C" __global__ void alibi_inplace(float *x, const float *slopes, AlibiMeta meta) {
+    unsigned int j = blockIdx.x * blockDim.x + threadIdx.x;
+    unsigned int h = blockIdx.y;
+    if (j >= meta.n_seq || h >= meta.n_heads) {
+        return;
+    }
+    unsigned int distance = (meta.pos - j) % meta.n_seq;
+    x[h * meta.n_seq + j] -= slopes[h] * (float)distance;
+}
+
+// each block of a warp takes an output, the matrix is strided like the transposed value cache
+extern "C" __global__ void batch_matmul(
+    const float *a,
+    const float *b,
+    float *out,
+    BatchMatmulMeta meta
+) {
+    unsigned int ni = blockIdx.x;
+    unsigned int mi = blockIdx.y;
+    if (ni >= meta.n || mi >= meta.m) {
+        return;
+    }
+
+    const float *ab = a + meta.offsets[0] + (mi / meta.g) * meta.strides[0] + ni * meta.strides[1];
+    const float *bb = b + meta.offsets[1] + mi * meta.b_stride;
+    float sum = 0.0f;
+    for (unsigned int ki = threadIdx.x; ki < meta.k; ki += 32) {
+        sum += ab[ki * meta.strides[2]] * bb[ki];
+    }
+    sum = warp_sum(sum);
+    if (threadIdx.x == 0) {
+        out[mi * meta.n + ni] = sum;
+    }
+}
+
+// each block of a warp takes an output of the quantized matmul, the lanes split the blocks
+// of the row, and the quants are multiplied without being dequantized into the memory
+
+extern "C" __global__ void matmul_q8_0(
+    const block_q8_0 *w,
+    const float *x,
+    float *out,
+    MatmulMeta meta
+) {
+    unsigned int mi = blockIdx.x;
+    unsigned int ni = blockIdx.y;
+    if (mi >= meta.m || ni >= meta.n) {
+        return;
+    }
+
+    unsigned int nb = meta.k / 32;
+    const block_q8_0 *wr = w + mi * nb;
+    const float *xr = x + ni * meta.k;
+    float sum = 0.0f;
+    for (unsigned int bi = threadIdx.x; bi < nb; bi += 32) {
+        const block_q8_0 *block = wr + bi;
+        const float *xb = xr + bi * 32;
+        float s = 0.0f;
+        for (unsigned int j = 0; j < 32; j++) {
+            s += (float)block->qs[j] * xb[j];
+        }
+        sum += s * half_to_float(block->d);
+    }
+    sum = warp_sum(sum);
+    if (threadIdx.x == 0) {
+        out[ni * meta.m + mi] = sum;
+    }
+}
+
+extern "C" __global__ void matmul_q4_0(
+    const block_q4_0 *w,
+    const float *x,
+    float *out,
+    MatmulMeta meta
+) {
+    unsigned int mi = blockIdx.x;
+    unsigned int ni = blockIdx.y;
+    if (mi >= meta.m || ni >= meta.n) {
+        return;
+    }
+
+    unsigned int nb = meta.k / 32;
+    const block_q4_0 *wr = w + mi * nb;
+    const float *xr = x + ni * meta.k;
+    float sum = 0.0f;
+    for (unsigned int bi = threadIdx.x; bi < nb; bi += 32) {
+        const block_q4_0 *block = wr + bi;
+        const float *xb = xr + bi * 32;
+        float s = 0.0f;
+        for (unsigned int j = 0; j < 16; j++) {
+            unsigned char q = block->qs[j];
+            s += ((float)(q & 0x0F) - 8.0f) * xb[j] + ((float)(q >> 4) - 8.0f) * xb[j + 16];
+        }
+        sum += s * half_to_float(block->d);
+    }
+    sum = warp_sum(sum);
+    if (threadIdx.x == 0) {
+        out[ni * meta.m + mi] = sum;
+    }
+}
+
+// each block of a warp takes a head on a page of the kv cache, the lanes split the
+// head. the running max, the running sum and the weighted sum of the values are kept in the
+// state across the pages, and the output is normalized on the last page
+
+extern "C" __global__ void flash_attention(
+    const float *q,
+    const float *k,
+    const float *v,
+    const float *slopes,
+    float *state,
+    float *out,
+    FlashAttentionMeta meta
+) {
+    unsigned int h = blockIdx.x;
+    unsigned int lane = threadIdx.x;
+    if (h >= meta.n_heads) {
+        return;
+    }
+
+    unsigned int head_size = meta.head_size;
+    unsigned int kvh = h / (meta.n_heads / meta.n_kv_heads);
+    unsigned int row_size = meta.n_kv_heads * head_size;
+    const float *qh = q + h * head_size;
+    float *s = state + h * (head_size + 2);
+    float *acc = s + 2;
+    if (meta.flags & 1) {
+        for (unsigned int i = lane; i < head_size; i += 32) {
+            acc[i] = 0.0f;
+        }
+    }
+
+    float m = (meta.flags & 1) ? NEG_INFINITY : s[0];
+    float sum = (meta.flags & 1) ? 0.0f : s[1];
+    for (unsigned int j = 0; j < meta.page_len; j++) {
+        unsigned int base = j * row_size + kvh * head_size;
+        float score = 0.0f;
+        for (unsigned int i = lane; i < head_size; i += 32) {
+            score += qh[i] * k[base + i];
+        }
+        score = warp_sum(score) * meta.scale;
+        if (meta.flags & 4) {
+            unsigned int distance = (meta.pos - (meta.offset + j)) % meta.n_seq;
+            score -= slopes[h] * (float)distance;
+        }
+
+        if (score > m) {
+            float c = expf(m - score);
+            for (unsigned int i = lane; i < head_size; i += 32) {
+                acc[i] *= c;
+            }
+            sum *= c;
+            m = score;
+        }
+        float w = expf(score - m);
+        for (unsigned int i = lane; i < head_size; i += 32) {
+            acc[i] += w * v[base + i];
+        }
+        sum += w;
+    }
+    if (lane == 0) {
+        s[0] = m;
+        s[1] = sum;
+    }
+
+    if (meta.flags & 2) {
+        for (unsigned int i = lane; i < head_size; i += 32) {
+            out[h * head_size + i] = acc[i] / sum;
+        }
+    }
+}
+
+// dequantize the rows of the quantized matrix into f32, like the token embeddings
+
+extern "C" __global__ void dequantize_q8_0(
+    const block_q8_0 *src,
+    float *dst,
+    DequantizeMeta meta
+) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i >= meta.n) {
+        return;
+    }
+    const block_q8_0 *block = src + meta.offset + i / 32;
+    dst[i] = (float)block->qs[i % 32] * half_to_float(block->d);
+}
+
+extern "C" __global__ void dequantize_q4_0(
+    const block_q4_0 *src,
+    float *dst,
+    DequantizeMeta meta
+) {
+    unsigned int i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i >= meta.n) {
+        return;
+    }
+    const block_q4_0 *block = src + meta.offset + i / 32;
+    unsigned int j = i % 32;
+    unsigned char q = block->qs[j % 16];
+    float v = j < 16 ? (float)(q & 0x0F) : (float)(q >> 4);
+    dst[i] = (v - 8.0f) * half_to_float(block->d);
+}
@@ -0,0 +1,131 @@
+use bytemuck;
+
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+// the metas are passed by value as the last parameter of the kernels, which should be in the
+// same layout as the structs in kernels/kernels.cu
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct ElementwiseMeta {
+    pub n: u32,
+}
+
+// the rhs of add and mul broadcasted onto the lhs of n elements, the axes of the rhs are
+// taken by the shape and the strides, the broadcasted ones have the stride of 0
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct BroadcastMeta {
+    pub n: u32,
+    pub rank: u32,
+    pub offset: u32,
+    pub _padding: u32,
+    pub shape: [u32; 4],
+    pub strides: [u32; 4],
+}
+
+impl BroadcastMeta {
+    /// take the strider of the rhs already broadcasted onto the lhs by `broadcast_to`.
+    pub fn new(strider: &TensorStrider) -> Result<Self> {
+        let rank = strider.shape().len();
+        if rank > 4 {
+            return Err((
+                ErrorKind::TensorError,
+                format!("can not broadcast the tensor of {} axes", rank),
+            )
+                .into());
+        }
+        let mut meta = Self {
+            n: strider.len() as u32,
+            rank: rank as u32,
+            offset: strider.offset() as u32,
+            _padding: 0,
+            shape: [1; 4],
+            strides: [0; 4],
+        };
+        for (i, (dim, stride)) in strider.shape().iter().zip(strider.strides()).enumerate() {
+            meta.shape[i] = *dim as u32;
+            meta.strides[i] = *stride as u32;
+        }
+        Ok(meta)
+    }
+}
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct ScalarMeta {
+    pub rhs: f32,
+    pub n: u32,
+}
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct NormMeta {
+    pub m: u32,
+    pub n: u32,
+    pub eps: f32,
+}
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct RopeMeta {
+    pub n_heads: u32,
+    pub head_size: u32,
+    pub rope_dims: u32,
+    pub mode: u32,
+}
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct AlibiMeta {
+    pub n_heads: u32,
+    pub n_seq: u32,
+    pub pos: u32,
+}
+
+// (m / g, n, k) @ (m, k) => (m, n)
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct BatchMatmulMeta {
+    pub m: u32,
+    pub n: u32,
+    pub k: u32,
+    pub g: u32,
+    pub strides: [u32; 3],
+    pub offsets: [u32; 2], // the offsets of a and b, which may be the slices
+    pub b_stride: u32,     // the stride of the rows of b
+}
+
+// (m, k) @ (n, k) => (n, m) on the quantized matrix
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct MatmulMeta {
+    pub m: u32,
+    pub n: u32,
+    pub k: u32,
+}
+
+// the attention of (n_heads, head_size) on a page of (page_len, n_kv_heads, head_size), the
+// state of (n_heads, 2 + head_size) is kept across the pages
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct FlashAttentionMeta {
+    pub n_heads: u32,
+    pub n_kv_heads: u32,
+    pub head_size: u32,
+    pub page_len: u32,
+    pub offset: u32, // the row of the first key of the page in the sequence
+    pub n_seq: u32,
+    pub pos: u32,
+    pub flags: u32, // 1: the first page, 2: the last page, 4: with alibi
+    pub scale: f32,
+}
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct DequantizeMeta {
+    pub offset: u32, // in blocks
+    pub n: u32,
+}
@@ -0,0 +1,8 @@
+mod cuda_device;
+mod cuda_tensor;
+mod meta;
+
+pub use cuda_device::CudaTensorDevice;
+pub use cuda_device::CudaTensorDeviceOptions;
+pub use cuda_device::CudaTensorDeviceRef;
+pub use cuda_tensor::CudaTensor;
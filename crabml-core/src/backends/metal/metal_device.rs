@@ -0,0 +1,213 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::rc::Rc;
+
+use metal;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::Tensor;
+
+const KERNELS: [&str; 19] = [
+    "add_inplace",
+    "mul_inplace",
+    "div_scalar_inplace",
+    "silu_inplace",
+    "silu_mul_inplace",
+    "gelu_inplace",
+    "rms_norm_inplace",
+    "rms_norm_mul_inplace",
+    "layer_norm_inplace",
+    "softmax_inplace",
+    "rope_inplace",
+    "alibi_inplace",
+    "matmul_f32",
+    "matmul_q8_0",
+    "matmul_q4_0",
+    "batch_matmul",
+    "flash_attention",
+    "dequantize_q8_0",
+    "dequantize_q4_0",
+];
+
+#[derive(Default)]
+pub struct MetalTensorDeviceOptions {
+    pub debug_named_tensor: bool,
+}
+
+impl MetalTensorDeviceOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_debug_named_tensor(mut self, v: bool) -> Self {
+        self.debug_named_tensor = v;
+        self
+    }
+}
+
+/// the gpu of apple silicon. the buffers are allocated in the shared storage mode, which the
+/// cpu and the gpu access on the same unified memory without copying, so there's no staging
+/// buffer like wgpu.
+///
+/// the kernels are encoded into a pending command buffer, which is committed on `sync` only,
+/// like on exporting the logits, so a forward pass takes a single submission.
+pub struct MetalTensorDevice {
+    pub(crate) opts: MetalTensorDeviceOptions,
+    pub(crate) inner: metal::Device,
+    pub(crate) queue: metal::CommandQueue,
+    pub(crate) pipelines: HashMap<&'static str, metal::ComputePipelineState>,
+    command_buffer: RefCell<Option<metal::CommandBuffer>>,
+
+    /// used for test only
+    pub debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
+}
+
+pub type MetalTensorDeviceRef = Rc<MetalTensorDevice>;
+
+impl MetalTensorDevice {
+    pub fn new(opts: MetalTensorDeviceOptions) -> MetalTensorDeviceRef {
+        Self::try_new(opts).unwrap()
+    }
+
+    pub fn try_new(opts: MetalTensorDeviceOptions) -> Result<MetalTensorDeviceRef> {
+        let device = metal::Device::system_default().ok_or_else(|| Error {
+            kind: ErrorKind::IOError,
+            message: "no metal device found".to_string(),
+            cause: None,
+        })?;
+        let queue = device.new_command_queue();
+        let pipelines = Self::load_pipelines(&device)?;
+        Ok(Rc::new(Self {
+            opts,
+            inner: device,
+            queue,
+            pipelines,
+            command_buffer: RefCell::new(None),
+            debug_tensors: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    fn load_pipelines(
+        device: &metal::Device,
+    ) -> Result<HashMap<&'static str, metal::ComputePipelineState>> {
+        let compile_error = |message: String| Error {
+            kind: ErrorKind::Unexpected,
+            message,
+            cause: None,
+        };
+        let library = device
+            .new_library_with_source(
+                include_str!("shaders/kernels.metal"),
+                &metal::CompileOptions::new(),
+            )
+            .map_err(|err| compile_error(format!("failed to compile the kernels: {}", err)))?;
+        let mut pipelines = HashMap::new();
+        for name in KERNELS {
+            let function = library
+                .get_function(name, None)
+                .map_err(|err| compile_error(format!("no kernel {}: {}", name, err)))?;
+            let pipeline = device
+                .new_compute_pipeline_state_with_function(&function)
+                .map_err(|err| compile_error(format!("failed to load {}: {}", name, err)))?;
+            pipelines.insert(name, pipeline);
+        }
+        Ok(pipelines)
+    }
+
+    /// alloc a zeroed buffer in the shared memory.
+    pub(crate) fn new_buffer(&self, bytes: usize) -> metal::Buffer {
+        // the empty buffer is not allowed
+        let bytes = bytes.max(4);
+        let buf = self
+            .inner
+            .new_buffer(bytes as u64, metal::MTLResourceOptions::StorageModeShared);
+        unsafe { std::ptr::write_bytes(buf.contents() as *mut u8, 0, bytes) };
+        buf
+    }
+
+    pub(crate) fn new_buffer_with_data(&self, data: &[u8]) -> metal::Buffer {
+        if data.is_empty() {
+            return self.new_buffer(0);
+        }
+        self.inner.new_buffer_with_data(
+            data.as_ptr() as *const c_void,
+            data.len() as u64,
+            metal::MTLResourceOptions::StorageModeShared,
+        )
+    }
+
+    fn with_command_buffer<R>(&self, f: impl FnOnce(&metal::CommandBufferRef) -> R) -> R {
+        let mut command_buffer = self.command_buffer.borrow_mut();
+        let command_buffer =
+            command_buffer.get_or_insert_with(|| self.queue.new_command_buffer().to_owned());
+        f(command_buffer)
+    }
+
+    /// encode the kernel into the pending command buffer, the buffers are bound in order, and
+    /// the meta is bound after the buffers. the kernels run in the order of encoding.
+    pub(crate) fn dispatch(
+        &self,
+        kernel: &'static str,
+        buffers: &[&metal::BufferRef],
+        meta: &[u8],
+        groups: (usize, usize),
+        threads: usize,
+    ) {
+        let pipeline = &self.pipelines[kernel];
+        self.with_command_buffer(|command_buffer| {
+            let encoder = command_buffer.new_compute_command_encoder();
+            encoder.set_compute_pipeline_state(pipeline);
+            for (i, buf) in buffers.iter().enumerate() {
+                encoder.set_buffer(i as u64, Some(*buf), 0);
+            }
+            encoder.set_bytes(
+                buffers.len() as u64,
+                meta.len() as u64,
+                meta.as_ptr() as *const c_void,
+            );
+            encoder.dispatch_thread_groups(
+                metal::MTLSize::new(groups.0 as u64, groups.1 as u64, 1),
+                metal::MTLSize::new(threads as u64, 1, 1),
+            );
+            encoder.end_encoding();
+        })
+    }
+
+    /// encode a copy between the buffers into the pending command buffer.
+    pub(crate) fn copy_buffer(
+        &self,
+        src: &metal::BufferRef,
+        src_offset: usize,
+        dst: &metal::BufferRef,
+        dst_offset: usize,
+        bytes: usize,
+    ) {
+        self.with_command_buffer(|command_buffer| {
+            let encoder = command_buffer.new_blit_command_encoder();
+            encoder.copy_from_buffer(src, src_offset as u64, dst, dst_offset as u64, bytes as u64);
+            encoder.end_encoding();
+        })
+    }
+
+    /// commit the pending command buffer and wait it done, it's required before reading the
+    /// buffers on the host.
+    pub fn sync(&self) {
+        if let Some(command_buffer) = self.command_buffer.borrow_mut().take() {
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+        }
+    }
+
+    pub fn record_debug_tensor(&self, name: String, tensor: &impl Tensor) {
+        let mut dst = vec![0.0; tensor.strider().len()];
+        tensor.export(&mut dst).unwrap();
+        self.debug_tensors.borrow_mut().insert(name, dst);
+    }
+
+    pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
+        self.debug_tensors.borrow().get(name).cloned()
+    }
+}
@@ -0,0 +1,8 @@
+mod meta;
+mod metal_device;
+mod metal_tensor;
+
+pub use metal_device::MetalTensorDevice;
+pub use metal_device::MetalTensorDeviceOptions;
+pub use metal_device::MetalTensorDeviceRef;
+pub use metal_tensor::MetalTensor;
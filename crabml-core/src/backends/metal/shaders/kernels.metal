@@ -0,0 +1,608 @@
+#include <metal_stdlib>
+
+using namespace metal;
+
+// the blocks in the same layout as GGUF, the quantized weights are kept as they are in the
+// shared memory, and dequantized on the fly in the matmul kernels
+struct block_q8_0 {
+    half d;
+    int8_t qs[32];
+};
+
+struct block_q4_0 {
+    half d;
+    uint8_t qs[16]; // the low nibbles are the first 16 quants, the high nibbles the rest
+};
+
+struct ElementwiseMeta {
+    uint n;
+};
+
+// the rhs of add and mul broadcasted onto the lhs of n elements, the broadcasted axes of the
+// rhs have the stride of 0
+struct BroadcastMeta {
+    uint n;
+    uint rank;
+    uint offset;
+    uint _padding;
+    uint shape[4];
+    uint strides[4];
+};
+
+// the index of the rhs on the i-th element of the lhs
+inline uint broadcast_index(constant BroadcastMeta &meta, uint i) {
+    uint j = meta.offset;
+    for (uint d = meta.rank; d > 0; d--) {
+        j += (i % meta.shape[d - 1]) * meta.strides[d - 1];
+        i /= meta.shape[d - 1];
+    }
+    return j;
+}
+
+struct ScalarMeta {
+    float rhs;
+    uint n;
+};
+
+struct NormMeta {
+    uint m; // number of vectors
+    uint n; // length of each vector
+    float eps;
+};
+
+struct RopeMeta {
+    uint n_heads;
+    uint head_size;
+    uint rope_dims;
+    uint mode; // 0: normal, 1: neox
+};
+
+struct AlibiMeta {
+    uint n_heads;
+    uint n_seq;
+    uint pos;
+};
+
+// (m, k) @ (n, k) => (n, m)
+struct MatmulMeta {
+    uint m;
+    uint k;
+    uint n;
+};
+
+// (m / g, n, k) @ (m, k) => (m, n), every g rows of the input share a batch of the matrix
+struct BatchMatmulMeta {
+    uint m;
+    uint n;
+    uint k;
+    uint g;
+    uint strides[3];
+    uint offsets[2]; // the offsets of a and b, which may be the slices
+    uint b_stride;   // the stride of the rows of b
+};
+
+struct FlashAttentionMeta {
+    uint n_heads;
+    uint n_kv_heads;
+    uint head_size;
+    uint page_len;
+    uint offset; // the row of the first key of the page in the sequence
+    uint n_seq;
+    uint pos;
+    uint flags; // 1: the first page, 2: the last page, 4: with alibi
+    float scale;
+};
+
+struct DequantizeMeta {
+    uint offset; // in blocks
+    uint n;
+};
+
+// the rows handled by a threadgroup of the matmul kernels, a simdgroup takes a row
+constant uint MATMUL_N_SIMDGROUPS = 2;
+
+static inline float threadgroup_sum(
+    float v,
+    threadgroup float *sums,
+    uint lane,
+    uint sg,
+    uint n_sg
+) {
+    v = simd_sum(v);
+    if (lane == 0) {
+        sums[sg] = v;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    float sum = 0.0f;
+    for (uint i = 0; i < n_sg; i++) {
+        sum += sums[i];
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    return sum;
+}
+
+static inline float threadgroup_max(
+    float v,
+    threadgroup float *maxs,
+    uint lane,
+    uint sg,
+    uint n_sg
+) {
+    v = simd_max(v);
+    if (lane == 0) {
+        maxs[sg] = v;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    float m = -INFINITY;
+    for (uint i = 0; i < n_sg; i++) {
+        m = max(m, maxs[i]);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    return m;
+}
+
+kernel void add_inplace(
+    device float *a [[buffer(0)]],
+    device const float *b [[buffer(1)]],
+    constant BroadcastMeta &meta [[buffer(2)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i < meta.n) {
+        a[i] += b[broadcast_index(meta, i)];
+    }
+}
+
+kernel void mul_inplace(
+    device float *a [[buffer(0)]],
+    device const float *b [[buffer(1)]],
+    constant BroadcastMeta &meta [[buffer(2)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i < meta.n) {
+        a[i] *= b[broadcast_index(meta, i)];
+    }
+}
+
+kernel void div_scalar_inplace(
+    device float *a [[buffer(0)]],
+    constant ScalarMeta &meta [[buffer(1)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i < meta.n) {
+        a[i] /= meta.rhs;
+    }
+}
+
+kernel void silu_inplace(
+    device float *a [[buffer(0)]],
+    constant ElementwiseMeta &meta [[buffer(1)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i < meta.n) {
+        float v = a[i];
+        a[i] = v / (1.0f + exp(-v));
+    }
+}
+
+kernel void silu_mul_inplace(
+    device float *a [[buffer(0)]],
+    device const float *b [[buffer(1)]],
+    constant ElementwiseMeta &meta [[buffer(2)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i < meta.n) {
+        float v = a[i];
+        a[i] = v / (1.0f + exp(-v)) * b[i];
+    }
+}
+
+// the tanh approximation: 0.5 * v * (1 + tanh(sqrt(2 / pi) * (v + 0.044715 * v^3))), the fast
+// tanh may give nan on the large values, so the precise one is taken
+kernel void gelu_inplace(
+    device float *a [[buffer(0)]],
+    constant ElementwiseMeta &meta [[buffer(1)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i < meta.n) {
+        float v = a[i];
+        a[i] = 0.5f * v * (1.0f + precise::tanh(0.7978846f * v * (1.0f + 0.044715f * v * v)));
+    }
+}
+
+// each threadgroup normalizes a vector
+
+kernel void rms_norm_inplace(
+    device float *x [[buffer(0)]],
+    constant NormMeta &meta [[buffer(1)]],
+    uint row [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint n_threads [[threads_per_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]],
+    uint n_sg [[simdgroups_per_threadgroup]]
+) {
+    threadgroup float sums[32];
+    device float *v = x + row * meta.n;
+
+    float ss = 0.0f;
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        ss += v[i] * v[i];
+    }
+    ss = threadgroup_sum(ss, sums, lane, sg, n_sg);
+
+    float scale = 1.0f / sqrt(ss / float(meta.n) + meta.eps);
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        v[i] *= scale;
+    }
+}
+
+kernel void rms_norm_mul_inplace(
+    device float *x [[buffer(0)]],
+    device const float *w [[buffer(1)]],
+    constant NormMeta &meta [[buffer(2)]],
+    uint row [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint n_threads [[threads_per_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]],
+    uint n_sg [[simdgroups_per_threadgroup]]
+) {
+    threadgroup float sums[32];
+    device float *v = x + row * meta.n;
+
+    float ss = 0.0f;
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        ss += v[i] * v[i];
+    }
+    ss = threadgroup_sum(ss, sums, lane, sg, n_sg);
+
+    float scale = 1.0f / sqrt(ss / float(meta.n) + meta.eps);
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        v[i] = v[i] * scale * w[i];
+    }
+}
+
+kernel void layer_norm_inplace(
+    device float *x [[buffer(0)]],
+    constant NormMeta &meta [[buffer(1)]],
+    uint row [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint n_threads [[threads_per_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]],
+    uint n_sg [[simdgroups_per_threadgroup]]
+) {
+    threadgroup float sums[32];
+    device float *v = x + row * meta.n;
+
+    float sum = 0.0f;
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        sum += v[i];
+    }
+    float mean = threadgroup_sum(sum, sums, lane, sg, n_sg) / float(meta.n);
+
+    float ss = 0.0f;
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        float d = v[i] - mean;
+        ss += d * d;
+    }
+    ss = threadgroup_sum(ss, sums, lane, sg, n_sg);
+
+    float scale = 1.0f / sqrt(ss / float(meta.n) + meta.eps);
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        v[i] = (v[i] - mean) * scale;
+    }
+}
+
+kernel void softmax_inplace(
+    device float *x [[buffer(0)]],
+    constant NormMeta &meta [[buffer(1)]],
+    uint row [[threadgroup_position_in_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint n_threads [[threads_per_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]],
+    uint n_sg [[simdgroups_per_threadgroup]]
+) {
+    threadgroup float shared[32];
+    device float *v = x + row * meta.n;
+
+    float m = -INFINITY;
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        m = max(m, v[i]);
+    }
+    m = threadgroup_max(m, shared, lane, sg, n_sg);
+
+    float sum = 0.0f;
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        float e = exp(v[i] - m);
+        v[i] = e;
+        sum += e;
+    }
+    sum = threadgroup_sum(sum, shared, lane, sg, n_sg);
+
+    for (uint i = tid; i < meta.n; i += n_threads) {
+        v[i] /= sum;
+    }
+}
+
+// each thread rotates a pair of a head, the (cos, sin) of each pair are computed on the host
+kernel void rope_inplace(
+    device float *x [[buffer(0)]],
+    device const float2 *cos_sin [[buffer(1)]],
+    constant RopeMeta &meta [[buffer(2)]],
+    uint2 gid [[thread_position_in_grid]]
+) {
+    uint i = gid.x;
+    uint h = gid.y;
+    uint half_dims = meta.rope_dims / 2;
+    if (i >= half_dims || h >= meta.n_heads) {
+        return;
+    }
+
+    uint i0 = i * 2;
+    uint i1 = i * 2 + 1;
+    if (meta.mode == 1) {
+        i0 = i;
+        i1 = i + half_dims;
+    }
+    device float *v = x + h * meta.head_size;
+    float2 cs = cos_sin[i];
+    float q0 = v[i0];
+    float q1 = v[i1];
+    v[i0] = q0 * cs.x - q1 * cs.y;
+    v[i1] = q0 * cs.y + q1 * cs.x;
+}
+
+kernel void alibi_inplace(
+    device float *x [[buffer(0)]],
+    device const float *slopes [[buffer(1)]],
+    constant AlibiMeta &meta [[buffer(2)]],
+    uint2 gid [[thread_position_in_grid]]
+) {
+    uint j = gid.x;
+    uint h = gid.y;
+    if (j >= meta.n_seq || h >= meta.n_heads) {
+        return;
+    }
+    uint distance = (meta.pos - j) % meta.n_seq;
+    x[h * meta.n_seq + j] -= slopes[h] * float(distance);
+}
+
+// each simdgroup takes a row of the matrix on a row of the input, the lanes split the row and
+// the partial sums are reduced in the simdgroup
+
+kernel void matmul_f32(
+    device const float *w [[buffer(0)]],
+    device const float *x [[buffer(1)]],
+    device float *out [[buffer(2)]],
+    constant MatmulMeta &meta [[buffer(3)]],
+    uint2 tg [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]]
+) {
+    uint r = tg.x * MATMUL_N_SIMDGROUPS + sg;
+    uint c = tg.y;
+    if (r >= meta.m) {
+        return;
+    }
+
+    device const float4 *wr = (device const float4 *)(w + (ulong)r * meta.k);
+    device const float4 *xr = (device const float4 *)(x + (ulong)c * meta.k);
+    float sum = 0.0f;
+    for (uint i = lane; i < meta.k / 4; i += 32) {
+        sum += dot(wr[i], xr[i]);
+    }
+    sum = simd_sum(sum);
+    if (lane == 0) {
+        out[c * meta.m + r] = sum;
+    }
+}
+
+// every 4 lanes take a block, each lane takes 8 quants of the block
+
+kernel void matmul_q8_0(
+    device const block_q8_0 *w [[buffer(0)]],
+    device const float *x [[buffer(1)]],
+    device float *out [[buffer(2)]],
+    constant MatmulMeta &meta [[buffer(3)]],
+    uint2 tg [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]]
+) {
+    uint r = tg.x * MATMUL_N_SIMDGROUPS + sg;
+    uint c = tg.y;
+    if (r >= meta.m) {
+        return;
+    }
+
+    uint nb = meta.k / 32;
+    uint sub = lane % 4;
+    device const block_q8_0 *wr = w + (ulong)r * nb;
+    device const float *xr = x + (ulong)c * meta.k;
+    float sum = 0.0f;
+    for (uint b = lane / 4; b < nb; b += 8) {
+        device const int8_t *q = wr[b].qs + sub * 8;
+        device const float *xb = xr + b * 32 + sub * 8;
+        float s = 0.0f;
+        for (uint j = 0; j < 8; j++) {
+            s += float(q[j]) * xb[j];
+        }
+        sum += s * float(wr[b].d);
+    }
+    sum = simd_sum(sum);
+    if (lane == 0) {
+        out[c * meta.m + r] = sum;
+    }
+}
+
+// every 4 lanes take a block, each lane takes 4 bytes of the block, which are 8 quants
+
+kernel void matmul_q4_0(
+    device const block_q4_0 *w [[buffer(0)]],
+    device const float *x [[buffer(1)]],
+    device float *out [[buffer(2)]],
+    constant MatmulMeta &meta [[buffer(3)]],
+    uint2 tg [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint sg [[simdgroup_index_in_threadgroup]]
+) {
+    uint r = tg.x * MATMUL_N_SIMDGROUPS + sg;
+    uint c = tg.y;
+    if (r >= meta.m) {
+        return;
+    }
+
+    uint nb = meta.k / 32;
+    uint sub = lane % 4;
+    device const block_q4_0 *wr = w + (ulong)r * nb;
+    device const float *xr = x + (ulong)c * meta.k;
+    float sum = 0.0f;
+    for (uint b = lane / 4; b < nb; b += 8) {
+        device const uint8_t *q = wr[b].qs + sub * 4;
+        device const float *xb = xr + b * 32 + sub * 4;
+        float s = 0.0f;
+        for (uint j = 0; j < 4; j++) {
+            s += (float(q[j] & 0x0F) - 8.0f) * xb[j];
+            s += (float(q[j] >> 4) - 8.0f) * xb[j + 16];
+        }
+        sum += s * float(wr[b].d);
+    }
+    sum = simd_sum(sum);
+    if (lane == 0) {
+        out[c * meta.m + r] = sum;
+    }
+}
+
+// each threadgroup of a simdgroup takes an output, the matrix is strided like the transposed
+// value cache
+
+kernel void batch_matmul(
+    device const float *a [[buffer(0)]],
+    device const float *b [[buffer(1)]],
+    device float *out [[buffer(2)]],
+    constant BatchMatmulMeta &meta [[buffer(3)]],
+    uint2 tg [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    uint ni = tg.x;
+    uint mi = tg.y;
+    if (ni >= meta.n || mi >= meta.m) {
+        return;
+    }
+
+    device const float *ab =
+        a + meta.offsets[0] + (mi / meta.g) * meta.strides[0] + ni * meta.strides[1];
+    device const float *bb = b + meta.offsets[1] + mi * meta.b_stride;
+    float sum = 0.0f;
+    for (uint ki = lane; ki < meta.k; ki += 32) {
+        sum += ab[ki * meta.strides[2]] * bb[ki];
+    }
+    sum = simd_sum(sum);
+    if (lane == 0) {
+        out[mi * meta.n + ni] = sum;
+    }
+}
+
+// each threadgroup of a simdgroup takes a head on a page of the kv cache, the lanes split the
+// head. the running max, the running sum and the weighted sum of the values are kept in the
+// state across the pages, and the output is normalized on the last page
+
+kernel void flash_attention(
+    device const float *q [[buffer(0)]],
+    device const float *k [[buffer(1)]],
+    device const float *v [[buffer(2)]],
+    device const float *slopes [[buffer(3)]],
+    device float *state [[buffer(4)]],
+    device float *out [[buffer(5)]],
+    constant FlashAttentionMeta &meta [[buffer(6)]],
+    uint2 tg [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    uint h = tg.x;
+    if (h >= meta.n_heads) {
+        return;
+    }
+
+    uint head_size = meta.head_size;
+    uint kvh = h / (meta.n_heads / meta.n_kv_heads);
+    uint row_size = meta.n_kv_heads * head_size;
+    device const float *qh = q + h * head_size;
+    device float *s = state + h * (head_size + 2);
+    device float *acc = s + 2;
+    if (meta.flags & 1) {
+        for (uint i = lane; i < head_size; i += 32) {
+            acc[i] = 0.0f;
+        }
+    }
+
+    float m = (meta.flags & 1) ? -INFINITY : s[0];
+    float sum = (meta.flags & 1) ? 0.0f : s[1];
+    for (uint j = 0; j < meta.page_len; j++) {
+        uint base = j * row_size + kvh * head_size;
+        float score = 0.0f;
+        for (uint i = lane; i < head_size; i += 32) {
+            score += qh[i] * k[base + i];
+        }
+        score = simd_sum(score) * meta.scale;
+        if (meta.flags & 4) {
+            uint distance = (meta.pos - (meta.offset + j)) % meta.n_seq;
+            score -= slopes[h] * float(distance);
+        }
+
+        if (score > m) {
+            float c = exp(m - score);
+            for (uint i = lane; i < head_size; i += 32) {
+                acc[i] *= c;
+            }
+            sum *= c;
+            m = score;
+        }
+        float w = exp(score - m);
+        for (uint i = lane; i < head_size; i += 32) {
+            acc[i] += w * v[base + i];
+        }
+        sum += w;
+    }
+    if (lane == 0) {
+        s[0] = m;
+        s[1] = sum;
+    }
+
+    if (meta.flags & 2) {
+        for (uint i = lane; i < head_size; i += 32) {
+            out[h * head_size + i] = acc[i] / sum;
+        }
+    }
+}
+
+// dequantize the rows of a quantized matrix into f32, like the token embeddings
+
+kernel void dequantize_q8_0(
+    device const block_q8_0 *src [[buffer(0)]],
+    device float *dst [[buffer(1)]],
+    constant DequantizeMeta &meta [[buffer(2)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= meta.n) {
+        return;
+    }
+    device const block_q8_0 *block = src + meta.offset + i / 32;
+    dst[i] = float(block->qs[i % 32]) * float(block->d);
+}
+
+kernel void dequantize_q4_0(
+    device const block_q4_0 *src [[buffer(0)]],
+    device float *dst [[buffer(1)]],
+    constant DequantizeMeta &meta [[buffer(2)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= meta.n) {
+        return;
+    }
+    device const block_q4_0 *block = src + meta.offset + i / 32;
+    uint j = i % 32;
+    uint8_t q = block->qs[j % 16];
+    float v = j < 16 ? float(q & 0x0F) : float(q >> 4);
+    dst[i] = (v - 8.0f) * float(block->d);
+}
@@ -1,4 +1,8 @@
 pub mod cpu;
+#[cfg(feature = "cuda")]
+pub mod cuda;
+#[cfg(target_os = "macos")]
+pub mod metal;
 pub mod wgpu;
 
 pub use cpu::CpuTensor;
@@ -1,5 +1,9 @@
 use bytemuck;
 
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, align(16))]
 pub struct RmsNormMeta {
@@ -9,6 +13,46 @@ pub struct RmsNormMeta {
     pub _padding: f32,
 }
 
+// the rhs of add and mul broadcasted onto the lhs of n elements, the axes of the rhs are
+// taken by the shape and the strides, the broadcasted ones have the stride of 0
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct BroadcastMeta {
+    pub n: u32,
+    pub rank: u32,
+    pub offset: u32,
+    pub _padding: u32,
+    pub shape: [u32; 4],
+    pub strides: [u32; 4],
+}
+
+impl BroadcastMeta {
+    /// take the strider of the rhs already broadcasted onto the lhs by `broadcast_to`.
+    pub fn new(strider: &TensorStrider) -> Result<Self> {
+        let rank = strider.shape().len();
+        if rank > 4 {
+            return Err((
+                ErrorKind::TensorError,
+                format!("can not broadcast the tensor of {} axes", rank),
+            )
+                .into());
+        }
+        let mut meta = Self {
+            n: strider.len() as u32,
+            rank: rank as u32,
+            offset: strider.offset() as u32,
+            _padding: 0,
+            shape: [1; 4],
+            strides: [0; 4],
+        };
+        for (i, (dim, stride)) in strider.shape().iter().zip(strider.strides()).enumerate() {
+            meta.shape[i] = *dim as u32;
+            meta.strides[i] = *stride as u32;
+        }
+        Ok(meta)
+    }
+}
+
 // (M, N) x (N, K) = (M, K), now we only support K = 1
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, align(16))]
@@ -26,9 +70,12 @@ pub struct BatchMatmulMeta {
     pub m: u32,
     pub n: u32,
     pub k: u32,
-    pub _padding_0: u32,
+    pub g: u32,
     pub strides_0: [u32; 3],
-    pub _padding_1: u32,
+    pub offset_0: u32,
+    pub offset_1: u32, // the offset and the row stride of input_1, which may be a slice
+    pub stride_1: u32,
+    pub _padding_1: [u32; 2],
 }
 
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -36,8 +83,23 @@ pub struct BatchMatmulMeta {
 pub struct RopeMeta {
     pub m: u32,
     pub n: u32,
-    pub pos: u32,
     pub n_heads: u32,
     pub rope_dims: u32,
+    pub mode: u32,
     pub _padding: [u32; 7],
 }
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+#[repr(C, align(16))]
+pub struct FlashAttentionMeta {
+    pub n_heads: u32,
+    pub n_kv_heads: u32,
+    pub head_size: u32,
+    pub page_len: u32,
+    pub offset: u32,
+    pub n_seq: u32,
+    pub pos: u32,
+    pub flags: u32,
+    pub scale: f32,
+    pub _padding: [u32; 3],
+}
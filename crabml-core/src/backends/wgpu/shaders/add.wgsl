@@ -1,6 +1,12 @@
+// the rhs is broadcasted onto the lhs of n elements, the broadcasted axes of the rhs have the
+// stride of 0
 struct Meta {
-    M: u32, // number of vectors
-    N: u32, // length of each vector
+    n: u32,
+    rank: u32,
+    offset: u32,
+    _padding: u32,
+    shape: vec4<u32>,
+    strides: vec4<u32>,
 }
 
 @group(0) @binding(0)
@@ -12,21 +18,25 @@ var<storage, read> input_1: array<f32>;
 @group(0) @binding(2)
 var<storage, read> input_m: Meta;
 
-// each workgroup will process a single vector
+// the index of the rhs on the i-th element of the lhs
+fn broadcast_index(i: u32) -> u32 {
+    var j = input_m.offset;
+    var r = i;
+    for (var d = input_m.rank; d > 0u; d = d - 1u) {
+        j += (r % input_m.shape[d - 1u]) * input_m.strides[d - 1u];
+        r /= input_m.shape[d - 1u];
+    }
+    return j;
+}
+
+// a single workgroup takes all the elements, each thread takes every 32th of them
 
 @compute
 @workgroup_size(32)
 fn main(
-    @builtin(workgroup_id) workgroup_id: vec3<u32>,
     @builtin(local_invocation_id) local_id: vec3<u32>,
 ) {
-    let mi = 32u * workgroup_id.x + local_id.x;
-    if mi >= input_m.M {
-        return;
+    for (var i = local_id.x; i < input_m.n; i = i + 32u) {
+        input_0[i] += input_1[broadcast_index(i)];
     }
-
-    for (var ni = 0u; ni < input_m.N; ni = ni + 1u) {
-        let idx = mi * input_m.N + ni;
-        input_0[idx] += input_1[idx % arrayLength(&input_1)];
-    }
-}
\ No newline at end of file
+}
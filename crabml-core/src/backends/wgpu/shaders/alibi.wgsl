@@ -0,0 +1,34 @@
+struct Meta {
+    M: u32, // number of heads
+    N: u32, // number of keys
+    pos: u32,
+    _padding: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> input: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> input_m: Meta;
+
+// the slope of each head, computed on the host
+@group(0) @binding(2)
+var<storage, read> slopes: array<f32>;
+
+// each thread adds the biases on the scores of a head
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    let h = workgroup_id.x * 32u + local_id.x;
+    if h >= input_m.M {
+        return;
+    }
+
+    for (var j = 0u; j < input_m.N; j += 1u) {
+        let distance = (input_m.pos - j) % input_m.N;
+        input[h * input_m.N + j] -= slopes[h] * f32(distance);
+    }
+}
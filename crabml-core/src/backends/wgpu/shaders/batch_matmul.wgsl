@@ -1,10 +1,13 @@
-// (m, n, k) * (m, k) = (m, n)
+// (m / g, n, k) * (m, k) = (m, n), every g rows of input_1 share a batch of input_0
 struct Meta {
     M: u32,
     N: u32,
     K: u32,
-    _padding_0: u32,
+    G: u32,
     strides_0: vec3<u32>,
+    offset_0: u32,
+    offset_1: u32, // input_1 may be a slice, whose rows are strided
+    stride_1: u32,
 };
 
 @group(0) @binding(0)
@@ -32,11 +35,12 @@ fn main(
         var sum = 0.0f;
         for (var ki = 0u; ki < input_m.K; ki = ki + 1u) {
             let a = input_0[
-                mi * input_m.strides_0.x +
+                input_m.offset_0 +
+                (mi / input_m.G) * input_m.strides_0.x +
                 ni * input_m.strides_0.y +
                 ki * input_m.strides_0.z
             ];
-            let b = input_1[input_m.K * mi + ki];
+            let b = input_1[input_m.offset_1 + input_m.stride_1 * mi + ki];
             sum += a * b;
         }
         output[mi * input_m.N + ni] = sum;
@@ -0,0 +1,102 @@
+// the attention of the query heads on a page of the kv cache, the running max, the running sum
+// and the weighted sum of the values of each head are kept in the state across the pages, and
+// the output is normalized on the last page
+struct Meta {
+    n_heads: u32,
+    n_kv_heads: u32,
+    head_size: u32,
+    page_len: u32,
+    offset: u32, // the row of the first key of the page in the sequence
+    n_seq: u32,
+    pos: u32,
+    flags: u32, // 1: the first page, 2: the last page, 4: with alibi
+    scale: f32,
+    _padding_0: u32,
+    _padding_1: u32,
+    _padding_2: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> q: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> k: array<f32>;
+
+@group(0) @binding(2)
+var<storage, read> v: array<f32>;
+
+@group(0) @binding(3)
+var<storage, read> input_m: Meta;
+
+// the slope of each head, computed on the host
+@group(0) @binding(4)
+var<storage, read> slopes: array<f32>;
+
+// (n_heads, 2 + head_size) of the running max, the running sum and the weighted sum
+@group(0) @binding(5)
+var<storage, read_write> state: array<f32>;
+
+@group(0) @binding(6)
+var<storage, read_write> output: array<f32>;
+
+// each thread takes a head
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    let h = workgroup_id.x * 32u + local_id.x;
+    if h >= input_m.n_heads {
+        return;
+    }
+
+    let head_size = input_m.head_size;
+    let kvh = h / (input_m.n_heads / input_m.n_kv_heads);
+    let row_size = input_m.n_kv_heads * head_size;
+    let s = h * (head_size + 2u);
+    if (input_m.flags & 1u) != 0u {
+        state[s] = -3.4e38;
+        state[s + 1u] = 0.0;
+        for (var i = 0u; i < head_size; i += 1u) {
+            state[s + 2u + i] = 0.0;
+        }
+    }
+
+    var max = state[s];
+    var sum = state[s + 1u];
+    for (var j = 0u; j < input_m.page_len; j += 1u) {
+        let base = j * row_size + kvh * head_size;
+        var score = 0.0f;
+        for (var i = 0u; i < head_size; i += 1u) {
+            score += q[h * head_size + i] * k[base + i];
+        }
+        score *= input_m.scale;
+        if (input_m.flags & 4u) != 0u {
+            let distance = (input_m.pos - (input_m.offset + j)) % input_m.n_seq;
+            score -= slopes[h] * f32(distance);
+        }
+
+        if score > max {
+            let c = exp(max - score);
+            for (var i = 0u; i < head_size; i += 1u) {
+                state[s + 2u + i] *= c;
+            }
+            sum *= c;
+            max = score;
+        }
+        let w = exp(score - max);
+        for (var i = 0u; i < head_size; i += 1u) {
+            state[s + 2u + i] += w * v[base + i];
+        }
+        sum += w;
+    }
+    state[s] = max;
+    state[s + 1u] = sum;
+
+    if (input_m.flags & 2u) != 0u {
+        for (var i = 0u; i < head_size; i += 1u) {
+            output[h * head_size + i] = state[s + 2u + i] / sum;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+struct Meta {
+    M: u32, // number of vectors
+    N: u32, // length of each vector
+    eps: f32,
+    _padding: f32,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> input: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> input_m: Meta;
+
+// workgroup local to reduce the sum and the squared sum
+var<workgroup> thread_sums: array<f32, 32>;
+
+// each workgroup normalize a single vector
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    let workgroup_size: u32 = 32u;
+    let offset = input_m.N * workgroup_id.x;
+
+    // the mean
+    for (var i = local_id.x; i < input_m.N; i += workgroup_size) {
+        thread_sums[local_id.x] += input[offset + i];
+    }
+    workgroupBarrier();
+    if local_id.x == 0u {
+        for (var i = 1u; i < workgroup_size; i += 1u) {
+            thread_sums[0] += thread_sums[i];
+        }
+    }
+    workgroupBarrier();
+    let mean = thread_sums[0] / f32(input_m.N);
+    workgroupBarrier();
+
+    // the variance
+    thread_sums[local_id.x] = 0.0;
+    for (var i = local_id.x; i < input_m.N; i += workgroup_size) {
+        let d = input[offset + i] - mean;
+        thread_sums[local_id.x] += d * d;
+    }
+    workgroupBarrier();
+    if local_id.x == 0u {
+        for (var i = 1u; i < workgroup_size; i += 1u) {
+            thread_sums[0] += thread_sums[i];
+        }
+    }
+    workgroupBarrier();
+
+    // normalize to output
+    let scale = 1.0 / sqrt((thread_sums[0] / f32(input_m.N)) + input_m.eps);
+    for (var i = local_id.x; i < input_m.N; i += workgroup_size) {
+        input[offset + i] = (input[offset + i] - mean) * scale;
+    }
+}
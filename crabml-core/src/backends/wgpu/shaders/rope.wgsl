@@ -1,9 +1,9 @@
 struct Meta {
     M: u32, // number of vectors
     N: u32, // length of vector
-    pos: u32,
     n_heads: u32,
     rope_dims: u32,
+    mode: u32, // 0: normal, 1: neox
     _padding: vec3<u32>,
 };
 
@@ -13,6 +13,10 @@ var<storage, read_write> input: array<f32>;
 @group(0) @binding(1)
 var<storage, read> input_m: Meta;
 
+// the (cos, sin) of each rotary pair, computed on the host
+@group(0) @binding(2)
+var<storage, read> cos_sin: array<f32>;
+
 @compute @workgroup_size(32)
 fn main(
     @builtin(workgroup_id) workgroup_id: vec3<u32>,
@@ -28,16 +32,19 @@ fn main(
 
     for (var h = 0u; h < input_m.n_heads; h++) {
         for (var i = 0u; i < input_m.rope_dims / 2u; i++) {
-            let theta_scale = pow(10000.0, -2.0 * f32(i) / f32(head_size));
-            let theta = f32(input_m.pos) * theta_scale;
+            let cos_theta = cos_sin[i * 2u];
+            let sin_theta = cos_sin[i * 2u + 1u];
 
-            let cos_theta = cos(theta);
-            let sin_theta = sin(theta);
-            let qp_offset = idx_m * input_m.N + h * head_size + i * 2u;
+            var qp_offset = idx_m * input_m.N + h * head_size + i * 2u;
+            var qp_stride = 1u;
+            if input_m.mode == 1u {
+                qp_offset = idx_m * input_m.N + h * head_size + i;
+                qp_stride = input_m.rope_dims / 2u;
+            }
             let qp0 = input[qp_offset];
-            let qp1 = input[qp_offset + 1u];
+            let qp1 = input[qp_offset + qp_stride];
             input[qp_offset] = qp0 * cos_theta - qp1 * sin_theta;
-            input[qp_offset+1u] = qp0 * sin_theta + qp1 * cos_theta;
+            input[qp_offset + qp_stride] = qp0 * sin_theta + qp1 * cos_theta;
         }
     }
-}
\ No newline at end of file
+}
@@ -17,8 +17,8 @@ var<storage, read> md: Meta;
 @group(0) @binding(3)
 var<storage, read_write> C: array<vec4<f32>>;
 
-// (M, K) * (K, 1) = (M, 1)
-// split the work by M / 32
+// (M, K) * (K, N) = (N, M), each of the N rows of B is taken as a vector
+// split the work by M / 32 and N, M is expected to be a multiple of 4
 
 @compute @workgroup_size(8)
 fn main(
@@ -30,15 +30,19 @@ fn main(
     let N = md.N;
     let K = md.K;
     let m = global_id.x * 4u;
+    let n = global_id.y;
+    if m >= M || n >= N {
+        return;
+    }
 
     var tmp = vec4<f32>();
     for (var k = 0u; k < K; k += 4u) {
-        let bc = B[k / 4u];
+        let bc = B[n * K / 4u + k / 4u];
         let x = dot(A[m * K / 4u + k / 4u], bc);
         let y = dot(A[(m + 1u) * K / 4u + k / 4u], bc);
         let z = dot(A[(m + 2u) * K / 4u + k / 4u], bc);
         let w = dot(A[(m + 3u) * K / 4u + k / 4u], bc);
         tmp += vec4<f32>(x, y, z, w);
     }
-    C[m / 4u] = tmp;
+    C[(n * M + m) / 4u] = tmp;
 }
\ No newline at end of file
@@ -0,0 +1,26 @@
+struct Meta {
+    N: u32, // number of the pairs
+    _padding: u32,
+}
+
+// each pair of f16 is packed in an u32
+@group(0) @binding(0)
+var<storage, read> input: array<u32>;
+
+@group(0) @binding(1)
+var<storage, read> input_m: Meta;
+
+@group(0) @binding(2)
+var<storage, read_write> output: array<vec2<f32>>;
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    let i = workgroup_id.x * 32u + local_id.x;
+    if i >= input_m.N {
+        return;
+    }
+    output[i] = unpack2x16float(input[i]);
+}
@@ -6,6 +6,9 @@ use std::rc::Rc;
 use wgpu;
 use wgpu::util::DeviceExt;
 
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
 use crate::tensor::Tensor;
 pub struct WgpuTensorDeviceOptions {
     pub staging_buf_bytes: usize,
@@ -53,7 +56,19 @@ pub type WgpuTensorDeviceRef = Rc<WgpuTensorDevice>;
 
 impl WgpuTensorDevice {
     pub fn new(opts: WgpuTensorDeviceOptions) -> WgpuTensorDeviceRef {
-        let (device, queue) = pollster::block_on(Self::init_wgpu());
+        Self::try_new(opts).unwrap()
+    }
+
+    /// like `new`, but returns an error if there's no gpu adapter available instead of
+    /// panicking, like selecting the gpu at runtime on a machine without one.
+    pub fn try_new(opts: WgpuTensorDeviceOptions) -> Result<WgpuTensorDeviceRef> {
+        pollster::block_on(Self::new_async(opts))
+    }
+
+    /// like `try_new`, but awaits the adapter and the device instead of blocking on them,
+    /// which is the only way on the WebGPU of the browsers.
+    pub async fn new_async(opts: WgpuTensorDeviceOptions) -> Result<WgpuTensorDeviceRef> {
+        let (device, queue) = Self::init_wgpu().await?;
         let staging_buf = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("staging buffer"),
             size: opts.staging_buf_bytes as u64,
@@ -69,7 +84,7 @@ impl WgpuTensorDevice {
             debug_tensors: RefCell::new(HashMap::new()),
         };
         d.load_modules();
-        Rc::new(d)
+        Ok(Rc::new(d))
     }
 
     pub(crate) fn load_modules(&mut self) {
@@ -78,11 +93,29 @@ impl WgpuTensorDevice {
             ("mul_inplace", include_str!("shaders/mul.wgsl")),
             ("div_inplace", include_str!("shaders/div.wgsl")),
             ("rms_norm_inplace", include_str!("shaders/rms_norm.wgsl")),
+            (
+                "rms_norm_mul_inplace",
+                include_str!("shaders/rms_norm_mul.wgsl"),
+            ),
+            (
+                "layer_norm_inplace",
+                include_str!("shaders/layer_norm.wgsl"),
+            ),
             ("sgemv", include_str!("shaders/sgemv.wgsl")),
+            ("sgemv_f16", include_str!("shaders/sgemv_f16.wgsl")),
+            ("to_f16", include_str!("shaders/to_f16.wgsl")),
+            ("to_f32", include_str!("shaders/to_f32.wgsl")),
             ("rope_inplace", include_str!("shaders/rope.wgsl")),
+            ("alibi_inplace", include_str!("shaders/alibi.wgsl")),
             ("softmax_inplace", include_str!("shaders/softmax.wgsl")),
             ("silu_inplace", include_str!("shaders/silu.wgsl")),
+            ("silu_mul_inplace", include_str!("shaders/silu_mul.wgsl")),
+            ("gelu_inplace", include_str!("shaders/gelu.wgsl")),
             ("batch_matmul", include_str!("shaders/batch_matmul.wgsl")),
+            (
+                "flash_attention",
+                include_str!("shaders/flash_attention.wgsl"),
+            ),
         ];
         let mut modules = HashMap::new();
         for (module_name, module_source) in module_sources {
@@ -117,20 +150,27 @@ impl WgpuTensorDevice {
             })
     }
 
-    async fn init_wgpu() -> (wgpu::Device, wgpu::Queue) {
+    async fn init_wgpu() -> Result<(wgpu::Device, wgpu::Queue)> {
         let instance = wgpu::Instance::default();
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions::default())
             .await
-            .unwrap();
+            .ok_or_else(|| Error {
+                kind: ErrorKind::IOError,
+                message: "no gpu adapter found".to_string(),
+                cause: None,
+            })?;
 
         // `request_device` instantiates the feature specific connection to the GPU, defining some parameters,
         //  `features` being the available features.
-        let (device, queue) = adapter
+        adapter
             .request_device(&wgpu::DeviceDescriptor::default(), None)
             .await
-            .unwrap();
-        (device, queue)
+            .map_err(|err| Error {
+                kind: ErrorKind::IOError,
+                message: "failed to request the gpu device".to_string(),
+                cause: Some(Box::new(err)),
+            })
     }
 
     pub fn encode_pipeline_commnad(
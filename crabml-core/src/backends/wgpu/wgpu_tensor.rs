@@ -6,12 +6,21 @@ use wgpu::util::DeviceExt;
 use super::meta::MatmulMeta;
 use super::meta::RmsNormMeta;
 use super::WgpuTensorDeviceRef;
+use crate::backends::cpu::CpuTensor;
+use crate::backends::cpu::CpuTensorBuf;
 use crate::backends::wgpu::meta::BatchMatmulMeta;
+use crate::backends::wgpu::meta::BroadcastMeta;
+use crate::backends::wgpu::meta::FlashAttentionMeta;
 use crate::backends::wgpu::meta::RopeMeta;
+use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::tensor::alibi_slopes;
+use crate::tensor::RopeMode;
+use crate::tensor::RopeOptions;
 use crate::tensor::Tensor;
+use crate::tensor::TensorBackend;
 use crate::tensor::TensorStrider;
 
 #[derive(Clone)]
@@ -71,24 +80,18 @@ impl WgpuTensor {
         })
     }
 
-    pub fn is_contiguous(&self) -> bool {
-        self.strider.is_contiguous()
-    }
-
-    pub fn shape(&self) -> &[usize] {
-        self.strider.shape()
-    }
-}
-
-impl Tensor for WgpuTensor {
-    type Device = WgpuTensorDeviceRef;
-
-    fn alloc(shape: &[usize], capacity: Option<usize>, device: Self::Device) -> Result<Self> {
+    /// alloc a tensor of F32 or F16, the f16 elements are packed in pairs of u32.
+    fn alloc_dtype(
+        shape: &[usize],
+        capacity: Option<usize>,
+        dtype: GGMLType,
+        device: WgpuTensorDeviceRef,
+    ) -> Result<Self> {
         let n_elms = shape.iter().product::<usize>();
         let capacity = capacity.unwrap_or(n_elms);
         assert!(capacity >= n_elms);
 
-        let buf_bytes = capacity * std::mem::size_of::<f32>();
+        let buf_bytes = capacity * dtype_size(dtype);
         let buf = device.inner.create_buffer(&wgpu::BufferDescriptor {
             label: Some("tensor storage buffer"),
             size: buf_bytes as u64,
@@ -100,7 +103,7 @@ impl Tensor for WgpuTensor {
         let strider = TensorStrider::new(shape.to_vec());
         Ok(Self {
             buf: Rc::new(buf),
-            dtype: GGMLType::F32,
+            dtype,
             capacity,
             strider,
             device,
@@ -108,6 +111,50 @@ impl Tensor for WgpuTensor {
         })
     }
 
+    pub fn is_contiguous(&self) -> bool {
+        self.strider.is_contiguous()
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        self.strider.shape()
+    }
+}
+
+impl Tensor for WgpuTensor {
+    type Device = WgpuTensorDeviceRef;
+
+    fn alloc(shape: &[usize], capacity: Option<usize>, device: Self::Device) -> Result<Self> {
+        Self::alloc_dtype(shape, capacity, GGMLType::F32, device)
+    }
+
+    fn alloc_cache(
+        shape: &[usize],
+        capacity: Option<usize>,
+        dtype: GGMLType,
+        device: Self::Device,
+    ) -> Result<Self> {
+        match dtype {
+            GGMLType::F32 => Self::alloc(shape, capacity, device),
+            _ => Err((
+                ErrorKind::NotImplemented,
+                format!("the kv cache of {} is not supported on wgpu yet", dtype),
+            )
+                .into()),
+        }
+    }
+
+    fn from_vec(buf: Vec<f32>, shape: &[usize], device: Self::Device) -> Result<Self> {
+        if shape.iter().product::<usize>() != buf.len() {
+            return Err((ErrorKind::TensorError, "buffer size mismatch").into());
+        }
+        let tensor = Self::alloc(shape, None, device)?;
+        tensor
+            .device
+            .queue
+            .write_buffer(&tensor.buf, 0, bytemuck::cast_slice(&buf));
+        Ok(tensor)
+    }
+
     fn dtype(&self) -> GGMLType {
         self.dtype
     }
@@ -142,6 +189,11 @@ impl Tensor for WgpuTensor {
         self.with_strider(strider)
     }
 
+    fn slice(self, axis: usize, start: usize, len: usize) -> Result<Self> {
+        let strider = self.strider.slice(axis, start, len)?;
+        self.with_strider(strider)
+    }
+
     fn strider(&self) -> &TensorStrider {
         &self.strider
     }
@@ -196,6 +248,40 @@ impl Tensor for WgpuTensor {
         Ok(())
     }
 
+    fn set_row(&mut self, row: usize, rhs: &Self) -> Result<()> {
+        if !rhs.shape().eq(&self.shape()[1..]) || row >= self.shape()[0] {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "shape mismatch on set_row {}, want {:?} but got {:?}",
+                    row,
+                    &self.shape(),
+                    &rhs.shape()
+                ),
+            )
+                .into());
+        }
+
+        let f32_size = std::mem::size_of::<f32>();
+        let copy_bytes_len = rhs.strider().len() * f32_size;
+        let copy_offset = row * copy_bytes_len;
+
+        // enqueue copy from rhs to the row of self's buffer
+        let mut encoder = self
+            .device
+            .inner
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(
+            &rhs.buf,
+            0_u64,
+            &self.buf,
+            copy_offset as u64,
+            copy_bytes_len as u64,
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
     fn repeat_n(self, n: usize) -> Result<Self> {
         let mut tmp_shape = self.shape().to_vec();
         tmp_shape.insert(0, 0);
@@ -215,9 +301,9 @@ impl Tensor for WgpuTensor {
             return Err((ErrorKind::TensorError, "not contiguous").into());
         }
 
-        let f32_size = std::mem::size_of::<f32>();
-        let offset = rhs.strider.at(pos).unwrap() * f32_size;
-        let bytes_len = len * f32_size;
+        let elem_size = dtype_size(rhs.dtype);
+        let offset = rhs.strider.at(pos).unwrap() * elem_size;
+        let bytes_len = len * elem_size;
 
         // enqueue copy from rhs to self's buffer
         let mut encoder = self
@@ -231,6 +317,9 @@ impl Tensor for WgpuTensor {
     }
 
     fn export(&self, dst: &mut [f32]) -> Result<()> {
+        if self.dtype == GGMLType::F16 {
+            return self.to_dtype(GGMLType::F32)?.export(dst);
+        }
         let buf_size = self.strider.len() * std::mem::size_of::<f32>();
         if buf_size > self.device.opts.staging_buf_bytes {
             return Err((
@@ -275,14 +364,70 @@ impl Tensor for WgpuTensor {
     }
 
     fn dup(&self) -> Result<Self> {
-        let mut new_tensor = Self::alloc(self.strider.shape(), None, self.device.clone())?;
+        // keep the capacity, so the duplicated kv cache can still be extended
+        let mut new_tensor = Self::alloc_dtype(
+            self.strider.shape(),
+            Some(self.capacity),
+            self.dtype,
+            self.device.clone(),
+        )?;
         new_tensor
             .copy_from(self, &vec![0; self.shape().len()], self.strider.len())
             .unwrap();
         Ok(new_tensor)
     }
 
-    fn rope_inplace(self, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn to_dtype(&self, dtype: GGMLType) -> Result<Self> {
+        assert!(self.is_contiguous());
+        let n = self.strider.len();
+        let pipeline = match (self.dtype, dtype) {
+            (from, to) if from == to => return self.dup(),
+            (GGMLType::F32, GGMLType::F16) => "to_f16",
+            (GGMLType::F16, GGMLType::F32) => "to_f32",
+            (from, to) => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("converting {} to {} is not supported", from, to),
+                )
+                    .into());
+            }
+        };
+        if n % 2 != 0 {
+            return Err((
+                ErrorKind::TensorError,
+                format!("the f16 tensor should be in pairs, got {:?}", self.shape()),
+            )
+                .into());
+        }
+
+        let output = Self::alloc_dtype(self.shape(), None, dtype, self.device.clone())?;
+
+        let n_pairs = (n / 2) as u32;
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_pairs, 0]));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: output.buf.as_entire_binding(),
+            },
+        ];
+        let encoder =
+            self.device
+                .encode_pipeline_commnad(pipeline, entries, (n_pairs.div_ceil(32), 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(output)
+    }
+
+    fn rope_inplace(self, pos: usize, rope: &RopeOptions) -> Result<Self> {
         assert!(self.shape().len() == 2);
         assert!(self.is_contiguous());
 
@@ -290,15 +435,26 @@ impl Tensor for WgpuTensor {
         let meta = RopeMeta {
             m: 1,
             n: self.strider.len() as u32,
-            pos: pos as u32,
             n_heads: n_heads as u32,
-            rope_dims: rope_dims as u32,
+            rope_dims: rope.dims as u32,
+            mode: match rope.mode {
+                RopeMode::Normal => 0,
+                RopeMode::Neox => 1,
+            },
             _padding: [0; 7],
         };
+        let cos_sin = rope
+            .cos_sin(pos)
+            .into_iter()
+            .flat_map(|(cos, sin)| [cos, sin])
+            .collect::<Vec<_>>();
 
         let meta_buf = self
             .device
             .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+        let cos_sin_buf = self
+            .device
+            .make_storage_buffer("cos_sin", bytemuck::cast_slice(&cos_sin));
         let entries = &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -308,6 +464,10 @@ impl Tensor for WgpuTensor {
                 binding: 1,
                 resource: meta_buf.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: cos_sin_buf.as_entire_binding(),
+            },
         ];
         let encoder = self
             .device
@@ -317,6 +477,69 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn alibi_inplace(self, pos: usize, max_bias: f32) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(self.is_contiguous());
+
+        let (n_heads, n_seq) = (self.shape()[0], self.shape()[1]);
+        let meta = [n_heads as u32, n_seq as u32, pos as u32, 0];
+        let slopes = alibi_slopes(n_heads, max_bias);
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&meta));
+        let slopes_buf = self
+            .device
+            .make_storage_buffer("slopes", bytemuck::cast_slice(&slopes));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: slopes_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_commnad(
+            "alibi_inplace",
+            entries,
+            (n_heads.div_ceil(32) as u32, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self> {
+        let meta_buf = self.device.make_storage_buffer(
+            "meta",
+            bytemuck::bytes_of(&RmsNormMeta {
+                m: 1,
+                n: self.strider.len() as u32,
+                eps,
+                _padding: 0.0,
+            }),
+        );
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self
+            .device
+            .encode_pipeline_commnad("layer_norm_inplace", entries, (1, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn rms_norm_inplace(self, eps: f32) -> Result<Self> {
         let meta_buf = self.device.make_storage_buffer(
             "meta",
@@ -344,6 +567,38 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn rms_norm_mul_inplace(self, eps: f32, weight: &Self) -> Result<Self> {
+        assert!(weight.strider.len() == self.strider.len());
+        let meta_buf = self.device.make_storage_buffer(
+            "meta",
+            bytemuck::bytes_of(&RmsNormMeta {
+                m: 1,
+                n: self.strider.len() as u32,
+                eps,
+                _padding: 0.0,
+            }),
+        );
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: weight.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder =
+            self.device
+                .encode_pipeline_commnad("rms_norm_mul_inplace", entries, (1, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
         assert!(axis == 1);
         assert!(self.is_contiguous());
@@ -397,11 +652,69 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn silu_mul_inplace(self, rhs: &Self) -> Result<Self> {
+        assert!(self.is_contiguous() && rhs.is_contiguous());
+        assert!(self.shape().len() == 1);
+        assert!(self.shape() == rhs.shape());
+
+        let m = 1;
+        let n = self.shape()[0] as u32;
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[m, n]));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: rhs.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self
+            .device
+            .encode_pipeline_commnad("silu_mul_inplace", entries, (1, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
+    fn gelu_inplace(self) -> Result<Self> {
+        assert!(self.is_contiguous());
+        assert!(self.shape().len() == 1);
+
+        let m = 1;
+        let n = self.shape()[0] as u32;
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[m, n]));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self
+            .device
+            .encode_pipeline_commnad("gelu_inplace", entries, (1, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn mul_inplace(self, rhs: &Self) -> Result<Self> {
-        let meta_buf = self.device.make_storage_buffer(
-            "meta",
-            bytemuck::cast_slice(&[1u32, self.strider.len() as u32]),
-        );
+        assert!(self.is_contiguous());
+        let meta = BroadcastMeta::new(&rhs.strider.broadcast_to(self.shape())?)?;
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
         let entries = &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -424,11 +737,11 @@ impl Tensor for WgpuTensor {
     }
 
     fn add_inplace(self, rhs: &Self) -> Result<Self> {
-        assert!(self.strider().len() % 32 == 0);
-        let meta_buf = self.device.make_storage_buffer(
-            "meta",
-            bytemuck::cast_slice(&[1u32, self.strider.len() as u32]),
-        );
+        assert!(self.is_contiguous());
+        let meta = BroadcastMeta::new(&rhs.strider.broadcast_to(self.shape())?)?;
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
         let entries = &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -516,37 +829,90 @@ impl Tensor for WgpuTensor {
                 resource: output.buf.as_entire_binding(),
             },
         ];
-        let encoder = self
-            .device
-            .encode_pipeline_commnad("sgemv", entries, (meta.m / 32, 1, 1));
+        let encoder = self.device.encode_pipeline_commnad(
+            sgemv_pipeline(y)?,
+            entries,
+            (meta.m.div_ceil(32), 1, 1),
+        );
         self.device.queue.submit(Some(encoder.finish()));
 
         Ok(output)
     }
 
-    fn batch_matmul_vec(&self, y: &Self) -> Result<Self> {
-        assert!(self.shape().len() == 3);
+    fn matmul(&self, y: &Self) -> Result<Self> {
+        assert!(self.shape().len() == 2);
         assert!(y.shape().len() == 2);
-        assert!(self.shape()[0] == y.shape()[0]);
-        assert!(self.shape()[2] == y.shape()[1]);
+        assert!(self.shape()[1] == y.shape()[1]);
+        assert!(self.is_contiguous());
         assert!(y.is_contiguous());
 
-        // (m, n, k) @ (m, k) => (m, n)
+        // (m, k) @ (n, k) => (n, m)
         let output = Self::alloc(
-            &[self.shape()[0], self.shape()[1]],
+            &[y.strider.shape()[0], self.strider.shape()[0]],
             None,
             self.device.clone(),
         )?;
+        let meta = MatmulMeta {
+            m: self.strider.shape()[0] as u32,
+            k: self.strider.shape()[1] as u32,
+            n: y.strider.shape()[0] as u32,
+            _padding: 0,
+        };
+
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: y.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: meta_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: output.buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_commnad(
+            sgemv_pipeline(y)?,
+            entries,
+            (meta.m.div_ceil(32), meta.n, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+
+        Ok(output)
+    }
+
+    fn batch_matmul_vec(&self, y: &Self) -> Result<Self> {
+        assert!(self.shape().len() == 3);
+        assert!(y.shape().len() == 2);
+        assert!(y.shape()[0] % self.shape()[0] == 0);
+        assert!(self.shape()[2] == y.shape()[1]);
+        assert!(y.strider.strides()[1] == 1);
+
+        // (m / g, n, k) @ (m, k) => (m, n), both may be the views at an offset
+        let output = Self::alloc(&[y.shape()[0], self.shape()[1]], None, self.device.clone())?;
 
         let meta = BatchMatmulMeta {
-            m: self.strider.shape()[0] as u32,
+            m: y.shape()[0] as u32,
             n: self.strider.shape()[1] as u32,
             k: self.strider.shape()[2] as u32,
+            g: (y.shape()[0] / self.shape()[0]) as u32,
             strides_0: [
                 self.strider.strides()[0] as u32,
                 self.strider.strides()[1] as u32,
                 self.strider.strides()[2] as u32,
             ],
+            offset_0: self.strider.offset() as u32,
+            offset_1: y.strider.offset() as u32,
+            stride_1: y.strider.strides()[0] as u32,
             ..Default::default()
         };
         let meta_bytes = bytemuck::bytes_of(&meta);
@@ -577,6 +943,148 @@ impl Tensor for WgpuTensor {
 
         Ok(output)
     }
+
+    fn flash_attention(
+        &self,
+        k_pages: &[&Self],
+        v_pages: &[&Self],
+        scale: f32,
+        alibi: Option<(usize, f32)>,
+    ) -> Result<Self> {
+        assert!(self.shape().len() == 2);
+        assert!(self.is_contiguous());
+        assert!(k_pages.len() == v_pages.len());
+        if k_pages.is_empty() {
+            return Err((ErrorKind::TensorError, "the kv cache is empty").into());
+        }
+
+        // (n_heads, head_size) on the pages of (page_len, n_kv_heads, head_size)
+        let (n_heads, head_size) = (self.shape()[0], self.shape()[1]);
+        let n_kv_heads = k_pages[0].shape()[1];
+        let n_seq = k_pages.iter().map(|k| k.shape()[0]).sum::<usize>();
+        let output = Self::alloc(&[n_heads, head_size], None, self.device.clone())?;
+        let state = Self::alloc(&[n_heads, head_size + 2], None, self.device.clone())?;
+        let (pos, slopes) = match alibi {
+            Some((pos, max_bias)) => (pos, alibi_slopes(n_heads, max_bias)),
+            None => (0, vec![0.0; n_heads]),
+        };
+        let slopes_buf = self
+            .device
+            .make_storage_buffer("slopes", bytemuck::cast_slice(&slopes));
+
+        let mut offset = 0;
+        for (i, (k, v)) in k_pages.iter().zip(v_pages).enumerate() {
+            assert!(k.is_contiguous() && v.is_contiguous());
+            let first = (i == 0) as u32;
+            let last = (i == k_pages.len() - 1) as u32;
+            let meta = FlashAttentionMeta {
+                n_heads: n_heads as u32,
+                n_kv_heads: n_kv_heads as u32,
+                head_size: head_size as u32,
+                page_len: k.shape()[0] as u32,
+                offset: offset as u32,
+                n_seq: n_seq as u32,
+                pos: pos as u32,
+                flags: first | last << 1 | (alibi.is_some() as u32) << 2,
+                scale,
+                ..Default::default()
+            };
+            let meta_buf = self
+                .device
+                .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+            let entries = &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: k.buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: v.buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: meta_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: slopes_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: state.buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: output.buf.as_entire_binding(),
+                },
+            ];
+            let encoder = self.device.encode_pipeline_commnad(
+                "flash_attention",
+                entries,
+                (n_heads.div_ceil(32) as u32, 1, 1),
+            );
+            self.device.queue.submit(Some(encoder.finish()));
+            offset += k.shape()[0];
+        }
+
+        Ok(output)
+    }
+}
+
+/// the bytes of an element in the buffer, the f16 elements are packed in pairs.
+fn dtype_size(dtype: GGMLType) -> usize {
+    match dtype {
+        GGMLType::F16 => 2,
+        _ => 4,
+    }
+}
+
+/// the f16 activations are taken by the sgemv unpacking them into f32.
+fn sgemv_pipeline(y: &WgpuTensor) -> Result<&'static str> {
+    match y.dtype {
+        GGMLType::F32 => Ok("sgemv"),
+        GGMLType::F16 => Ok("sgemv_f16"),
+        dtype => Err((
+            ErrorKind::TensorError,
+            format!("the activation of {} is not supported in matmul", dtype),
+        )
+            .into()),
+    }
+}
+
+impl TensorBackend for WgpuTensorDeviceRef {
+    type Tensor = WgpuTensor;
+
+    fn name(&self) -> &'static str {
+        "wgpu"
+    }
+
+    fn upload(&self, tensor: &CpuTensor) -> Result<WgpuTensor> {
+        // the gpu takes the f32 weights only, the quantized ones are dequantized
+        let dequantized;
+        let tensor = match tensor.dtype() {
+            GGMLType::F32 => tensor,
+            _ => {
+                dequantized = tensor.clone().dequantize(GGMLType::F32)?;
+                &dequantized
+            }
+        };
+        let buf = match tensor.buf() {
+            CpuTensorBuf::F32(buf) => buf,
+            buf => {
+                return Err(Error {
+                    kind: ErrorKind::TensorError,
+                    message: format!("unsupported tensor type on gpu {:?}", buf),
+                    cause: None,
+                });
+            }
+        };
+        WgpuTensor::new(buf, tensor.shape(), self.clone())
+    }
 }
 
 #[cfg(test)]
@@ -584,13 +1092,20 @@ mod tests {
     use std::sync::LazyLock;
 
     use approx::assert_relative_eq;
+    use half::f16;
 
     use super::WgpuTensor;
+    use crate::backends::cpu::CpuTensor;
+    use crate::backends::cpu::CpuTensorDevice;
     use crate::backends::wgpu::WgpuTensorDevice;
     use crate::backends::wgpu::WgpuTensorDeviceOptions;
     use crate::backends::wgpu::WgpuTensorDeviceRef;
     use crate::error::Result;
+    use crate::gguf::GGMLType;
+    use crate::tensor::RopeMode;
+    use crate::tensor::RopeOptions;
     use crate::tensor::Tensor;
+    use crate::tensor::TensorBackend;
 
     #[thread_local]
     static DEVICE: LazyLock<WgpuTensorDeviceRef> = LazyLock::new(|| {
@@ -643,6 +1158,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_tensor_broadcast() -> Result<()> {
+        // the bias of (32, ) on the rows of (4, 32), then the scale of (4, 1) on them
+        let v1 = (0..128).map(|i| i as f32).collect::<Vec<_>>();
+        let v2 = (0..32).map(|i| i as f32 / 4.0).collect::<Vec<_>>();
+        let v3 = vec![1.0, -2.0, 0.5, 3.0];
+        let t1 = WgpuTensor::new(&v1, &[4, 32], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&v2, &[32], DEVICE.clone())?;
+        let t3 = WgpuTensor::new(&v3, &[4, 1], DEVICE.clone())?;
+        let t1 = t1.add_inplace(&t2)?.mul_inplace(&t3)?;
+
+        let device = CpuTensorDevice::new();
+        let c1 = CpuTensor::new(v1, &[4, 32], device.clone())?;
+        let c2 = CpuTensor::new(v2, &[32], device.clone())?;
+        let c3 = CpuTensor::new(v3, &[4, 1], device.clone())?;
+        let c1 = c1.add_inplace(&c2)?.mul_inplace(&c3)?;
+
+        let mut dst1 = vec![0.0; 128];
+        let mut dst2 = vec![0.0; 128];
+        t1.export(&mut dst1)?;
+        c1.export(&mut dst2)?;
+        assert_eq!(dst1, dst2);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_tensor_div_scalar() -> Result<()> {
         let t1 = WgpuTensor::new(&[6.0; 1024], &[512, 2], DEVICE.clone())?;
@@ -668,6 +1208,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_tensor_from_vec() -> Result<()> {
+        let buf = (0..32).map(|v| v as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::from_vec(buf, &[16, 2], DEVICE.clone())?;
+        let t1 = t1.add_inplace(&WgpuTensor::new(&[1.0; 32], &[16, 2], DEVICE.clone())?)?;
+
+        let mut dst = vec![0.0; 32];
+        t1.export(&mut dst)?;
+
+        assert_eq!(&dst[0..3], [1.0, 2.0, 3.0]);
+        assert_eq!(dst[31], 32.0);
+        assert!(WgpuTensor::from_vec(vec![1.0; 3], &[2, 2], DEVICE.clone()).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_tensor_with_name() -> Result<()> {
         let t1 = WgpuTensor::alloc(&[512, 2], None, DEVICE.clone())?;
@@ -740,6 +1295,65 @@ mod tests {
             1592.0, 1720.0, 1848.0, 1976.0, 2104.0, 2232.0, 2360.0, 2488.0, 2616.0, 2744.0, 2872.0,
             3000.0, 3128.0, 3256.0, 3384.0, 3512.0, 3640.0, 3768.0, 3896.0, 4024.0
         ]);
+
+        // the rows which are not a multiple of 32, like the single kv head of 48
+        let t1 = WgpuTensor::new(&v1[..48 * 4], &[48, 4], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&[1.0; 4], &[4], DEVICE.clone())?;
+        let t3 = t1.matmul_vec(&t2)?;
+        let mut dst1 = vec![0.0; 48];
+        t3.export(&mut dst1)?;
+        assert_eq!(dst1[47], (188..192).sum::<i32>() as f32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_matmul_batch() -> Result<()> {
+        let v1 = (0..256).map(|i| i as f32).collect::<Vec<_>>();
+        let v2 = (0..16).map(|i| (i / 8 + 1) as f32).collect::<Vec<_>>();
+
+        // the rows of t2 are all ones and all twos
+        let t1 = WgpuTensor::new(&v1, &[32, 8], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&v2, &[2, 8], DEVICE.clone())?;
+        let t3 = t1.matmul(&t2)?;
+        assert_eq!(t3.shape(), &[2, 32]);
+        let mut dst1 = vec![0.0; 64];
+        t3.export(&mut dst1)?;
+        assert_eq!(dst1[0..4], [28.0, 92.0, 156.0, 220.0]);
+        assert_eq!(dst1[32..36], [56.0, 184.0, 312.0, 440.0]);
+        assert_eq!(dst1[63], 4024.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_matmul_f16() -> Result<()> {
+        let v1 = (0..256).map(|i| i as f32).collect::<Vec<_>>();
+        let v2 = (0..16)
+            .map(|i| (i % 8) as f32 / 4.0 - 1.0)
+            .collect::<Vec<_>>();
+
+        // the values are all exact in f16
+        let t2 = WgpuTensor::new(&v2, &[2, 8], DEVICE.clone())?;
+        let t2_f16 = t2.to_dtype(GGMLType::F16)?;
+        assert_eq!(t2_f16.dtype(), GGMLType::F16);
+        let mut dst2 = vec![0.0; 16];
+        t2_f16.export(&mut dst2)?;
+        assert_eq!(dst2, v2);
+        t2_f16.dup()?.to_dtype(GGMLType::F32)?.export(&mut dst2)?;
+        assert_eq!(dst2, v2);
+
+        let t1 = WgpuTensor::new(&v1, &[32, 8], DEVICE.clone())?;
+        let (mut want, mut got) = (vec![0.0; 64], vec![0.0; 64]);
+        t1.matmul(&t2)?.export(&mut want)?;
+        t1.matmul(&t2_f16)?.export(&mut got)?;
+        assert_eq!(got, want);
+
+        let mut row = WgpuTensor::alloc(&[8], None, DEVICE.clone())?;
+        row.copy_from(&t2, &[1, 0], 8)?;
+        let (mut want, mut got) = (vec![0.0; 32], vec![0.0; 32]);
+        t1.matmul_vec(&row)?.export(&mut want)?;
+        t1.matmul_vec(&row.to_dtype(GGMLType::F16)?)?
+            .export(&mut got)?;
+        assert_eq!(got, want);
         Ok(())
     }
 
@@ -774,11 +1388,142 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_batch_matmul_grouped() -> Result<()> {
+        // 2 kv heads of 16 keys, shared by 4 query heads
+        let v1 = (0..64).map(|i| i as f32).collect::<Vec<_>>();
+        let v2 = (0..8).map(|i| i as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&v1, &[2, 16, 2], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&v2, &[4, 2], DEVICE.clone())?;
+        let t3 = t1.batch_matmul_vec(&t2)?;
+        assert_eq!(t3.shape(), &[4, 16]);
+
+        let device = CpuTensorDevice::new();
+        let c1 = CpuTensor::new(v1, &[2, 16, 2], device.clone())?;
+        let c2 = CpuTensor::new(v2, &[4, 2], device.clone())?;
+        let c3 = c1.batch_matmul_vec(&c2)?;
+
+        let mut dst1 = vec![0.0; 64];
+        let mut dst2 = vec![0.0; 64];
+        t3.export(&mut dst1)?;
+        c3.export(&mut dst2)?;
+        assert_eq!(dst1, dst2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_batch_matmul_slice() -> Result<()> {
+        // 2 kv heads of 4 keys on the slice of the attention of 4 heads on 12 keys
+        let v1 = (0..64).map(|i| i as f32).collect::<Vec<_>>();
+        let v2 = (0..48).map(|i| (i % 7) as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&v1, &[2, 8, 4], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&v2, &[4, 12], DEVICE.clone())?;
+        let t3 = t1.slice(1, 2, 4)?.batch_matmul_vec(&t2.slice(1, 8, 4)?)?;
+        assert_eq!(t3.shape(), &[4, 4]);
+
+        let device = CpuTensorDevice::new();
+        let c1 = CpuTensor::new(v1, &[2, 8, 4], device.clone())?;
+        let c2 = CpuTensor::new(v2, &[4, 12], device.clone())?;
+        let c3 = c1.slice(1, 2, 4)?.batch_matmul_vec(&c2.slice(1, 8, 4)?)?;
+
+        let mut dst1 = vec![0.0; 16];
+        let mut dst2 = vec![0.0; 16];
+        t3.export(&mut dst1)?;
+        c3.export(&mut dst2)?;
+        assert_eq!(dst1, dst2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_flash_attention() -> Result<()> {
+        // 4 query heads on 2 kv heads of 8, the keys are split into the pages of 3 and 5 rows
+        let q = (0..32).map(|i| (i % 7) as f32 / 7.0).collect::<Vec<_>>();
+        let rows = |n: usize, seed: usize| {
+            (0..n * 16)
+                .map(|i| ((i * seed) % 13) as f32 / 13.0 - 0.5)
+                .collect::<Vec<_>>()
+        };
+        let pages = [(rows(3, 3), rows(3, 5)), (rows(5, 7), rows(5, 11))];
+
+        let t1 = WgpuTensor::new(&q, &[4, 8], DEVICE.clone())?;
+        let k_pages = pages
+            .iter()
+            .map(|(k, _)| WgpuTensor::new(k, &[k.len() / 16, 2, 8], DEVICE.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let v_pages = pages
+            .iter()
+            .map(|(_, v)| WgpuTensor::new(v, &[v.len() / 16, 2, 8], DEVICE.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let device = CpuTensorDevice::new();
+        let c1 = CpuTensor::new(q.clone(), &[4, 8], device.clone())?;
+        let c_k_pages = pages
+            .iter()
+            .map(|(k, _)| CpuTensor::new(k.clone(), &[k.len() / 16, 2, 8], device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let c_v_pages = pages
+            .iter()
+            .map(|(_, v)| CpuTensor::new(v.clone(), &[v.len() / 16, 2, 8], device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        for alibi in [None, Some((9, 8.0))] {
+            let t2 = t1.flash_attention(
+                &k_pages.iter().collect::<Vec<_>>(),
+                &v_pages.iter().collect::<Vec<_>>(),
+                0.5,
+                alibi,
+            )?;
+            let c2 = c1.flash_attention(
+                &c_k_pages.iter().collect::<Vec<_>>(),
+                &c_v_pages.iter().collect::<Vec<_>>(),
+                0.5,
+                alibi,
+            )?;
+            assert_eq!(t2.shape(), &[4, 8]);
+
+            let mut dst1 = vec![0.0; 32];
+            let mut dst2 = vec![0.0; 32];
+            t2.export(&mut dst1)?;
+            c2.export(&mut dst2)?;
+            assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_upload() -> Result<()> {
+        // the f16 weights are dequantized into f32 on uploading
+        let w = (0..256).map(|i| (i % 13) as f32 - 6.0).collect::<Vec<_>>();
+        let bytes = w
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_le_bytes())
+            .collect::<Vec<_>>();
+        let device = CpuTensorDevice::new();
+        let c1 = CpuTensor::from_bytes(&bytes, GGMLType::F16, &[4, 64], device.clone())?;
+        let c2 = CpuTensor::new(vec![0.5; 64], &[64], device.clone())?;
+
+        let t1 = DEVICE.upload(&c1)?;
+        let t2 = DEVICE.upload(&c2)?;
+        assert_eq!(DEVICE.name(), "wgpu");
+        assert_eq!(t1.dtype(), GGMLType::F32);
+        assert_eq!(t1.shape(), &[4, 64]);
+
+        let mut dst1 = vec![0.0; 256];
+        t1.export(&mut dst1)?;
+        assert_eq!(dst1, w);
+        let mut dst2 = vec![0.0; 4];
+        let mut want = vec![0.0; 4];
+        t1.matmul_vec(&t2)?.export(&mut dst2)?;
+        c1.matmul_vec(&c2)?.export(&mut want)?;
+        assert_eq!(dst2, want);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_rope() -> Result<()> {
         let v1 = (0..32).map(|i| i as f32).collect::<Vec<_>>();
         let t1 = WgpuTensor::new(&v1, &[2, 16], DEVICE.clone())?;
-        let t1 = t1.rope_inplace(1, 2)?;
+        let t1 = t1.rope_inplace(1, &RopeOptions::new(RopeMode::Normal, 2))?;
 
         let mut dst1 = vec![0.0; 32];
         t1.export(&mut dst1)?;
@@ -825,6 +1570,142 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_rope_neox() -> Result<()> {
+        let v1 = (0..8).map(|i| i as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&v1, &[2, 4], DEVICE.clone())?;
+        let t1 = t1.rope_inplace(
+            1,
+            &RopeOptions::new(RopeMode::Neox, 4).with_freq_base(100.0),
+        )?;
+
+        let mut dst1 = vec![0.0; 8];
+        t1.export(&mut dst1)?;
+
+        // the pairs are (0, 2) and (1, 3) in each head
+        let (cos0, sin0) = (1.0_f32.cos(), 1.0_f32.sin());
+        let (cos1, sin1) = (0.1_f32.cos(), 0.1_f32.sin());
+        assert_relative_eq!(
+            &dst1[..],
+            &[
+                -2.0 * sin0,
+                cos1 - 3.0 * sin1,
+                2.0 * cos0,
+                sin1 + 3.0 * cos1,
+                4.0 * cos0 - 6.0 * sin0,
+                5.0 * cos1 - 7.0 * sin1,
+                4.0 * sin0 + 6.0 * cos0,
+                5.0 * sin1 + 7.0 * cos1
+            ][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_alibi() -> Result<()> {
+        let t1 = WgpuTensor::new(&[0.0; 6], &[2, 3], DEVICE.clone())?;
+        let t1 = t1.alibi_inplace(2, 8.0)?;
+
+        let mut dst1 = vec![0.0; 6];
+        t1.export(&mut dst1)?;
+        assert_eq!(dst1, vec![
+            -0.125,
+            -0.0625,
+            0.0,
+            -0.0078125,
+            -0.00390625,
+            0.0
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_layer_norm() -> Result<()> {
+        let t1 = WgpuTensor::new(&[1.0, 2.0, 3.0, 4.0], &[4], DEVICE.clone())?;
+        let t1 = t1.layer_norm_inplace(1e-5)?;
+
+        let mut dst1 = vec![0.0; 4];
+        t1.export(&mut dst1)?;
+        assert_relative_eq!(
+            &dst1[..],
+            &[-1.3416355, -0.44721183, 0.44721183, 1.3416355][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_gelu() -> Result<()> {
+        let t1 = WgpuTensor::new(&[-3.0, -1.0, 0.0, 1.0, 3.0], &[5], DEVICE.clone())?;
+        let t1 = t1.gelu_inplace()?;
+
+        let mut dst1 = vec![0.0; 5];
+        t1.export(&mut dst1)?;
+        assert_relative_eq!(
+            &dst1[..],
+            &[-0.00363752, -0.15880801, 0.0, 0.841192, 2.9963627][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_set_row() -> Result<()> {
+        let mut t1 = WgpuTensor::alloc(&[0, 4], Some(1024), DEVICE.clone())?;
+        t1.extend(&WgpuTensor::new(
+            &[0.0, 1.0, 2.0, 3.0],
+            &[4],
+            DEVICE.clone(),
+        )?)?;
+        t1.extend(&WgpuTensor::new(
+            &[4.0, 5.0, 6.0, 7.0],
+            &[4],
+            DEVICE.clone(),
+        )?)?;
+
+        let t2 = WgpuTensor::new(&[10.0, 11.0, 12.0, 13.0], &[4], DEVICE.clone())?;
+        t1.set_row(0, &t2)?;
+
+        let mut dst1 = vec![0.0; 8];
+        t1.export(&mut dst1)?;
+        assert_relative_eq!(
+            &dst1[..],
+            &[10.0, 11.0, 12.0, 13.0, 4.0, 5.0, 6.0, 7.0][..],
+            epsilon = 1e-5
+        );
+        assert!(t1.set_row(2, &t2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_fused_ops() -> Result<()> {
+        let v1 = (0..64)
+            .map(|i| (i % 9) as f32 / 3.0 - 1.5)
+            .collect::<Vec<_>>();
+        let v2 = (0..64).map(|i| (i % 5) as f32 / 2.0).collect::<Vec<_>>();
+        let w = WgpuTensor::new(&v2, &[64], DEVICE.clone())?;
+
+        let t1 = WgpuTensor::new(&v1, &[64], DEVICE.clone())?.rms_norm_mul_inplace(1e-5, &w)?;
+        let t2 = WgpuTensor::new(&v1, &[64], DEVICE.clone())?
+            .rms_norm_inplace(1e-5)?
+            .mul_inplace(&w)?;
+        let mut dst1 = vec![0.0; 64];
+        let mut dst2 = vec![0.0; 64];
+        t1.export(&mut dst1)?;
+        t2.export(&mut dst2)?;
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+
+        let t1 = WgpuTensor::new(&v1, &[64], DEVICE.clone())?.silu_mul_inplace(&w)?;
+        let t2 = WgpuTensor::new(&v1, &[64], DEVICE.clone())?
+            .silu_inplace()?
+            .mul_inplace(&w)?;
+        t1.export(&mut dst1)?;
+        t2.export(&mut dst2)?;
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_softmax() -> Result<()> {
         let v1 = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
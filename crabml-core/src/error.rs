@@ -19,18 +19,28 @@ pub enum ErrorKind {
     NotImplemented,
 }
 
+/// the error of all the crabml crates. the typed errors like GGUFError are kept as the cause,
+/// which is also the `source()`, so the callers can downcast it. it's Send + Sync to be carried
+/// across the threads and into `anyhow` or `Box<dyn Error + Send + Sync>` with `?`.
 #[derive(Debug)]
 pub struct Error {
     pub kind: ErrorKind,
     pub message: String,
-    pub cause: Option<Box<dyn std::error::Error>>,
+    pub cause: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{:?}: {}", self.kind, self.message)?;
-        if let Some(cause) = self.cause.as_ref() {
-            write!(f, "\ncaused by: {}", cause)?;
+        // the errors converted from a typed error keep it as the cause with the same message,
+        // only its sources are worth printing
+        let mut cause = match std::error::Error::source(self) {
+            Some(cause) if cause.to_string() == self.message => cause.source(),
+            cause => cause,
+        };
+        while let Some(err) = cause {
+            write!(f, "\ncaused by: {}", err)?;
+            cause = err.source();
         }
         Ok(())
     }
@@ -46,6 +56,62 @@ impl<S: Into<String>> From<(ErrorKind, S)> for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self {
+            kind: ErrorKind::IOError,
+            message: err.to_string(),
+            cause: Some(Box::new(err)),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause
+            .as_deref()
+            .map(|cause| cause as &(dyn std::error::Error + 'static))
+    }
+}
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+    use crate::gguf::GGUFError;
+
+    #[test]
+    fn test_error_source() {
+        fn read_config() -> Result<String> {
+            Ok(std::fs::read_to_string("/non-existent/crabml.toml")?)
+        }
+        let err = read_config().unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        let io_err = err.source().unwrap().downcast_ref::<std::io::Error>();
+        assert_eq!(io_err.unwrap().kind(), std::io::ErrorKind::NotFound);
+
+        let err: Error = GGUFError::MissingKey {
+            key: "general.architecture".to_string(),
+        }
+        .into();
+        assert_eq!(
+            err.to_string(),
+            "FormatError: missing metadata general.architecture"
+        );
+        assert!(err.source().unwrap().is::<GGUFError>());
+
+        // converts into the boxed errors like the ones of anyhow
+        fn run() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            Err(Error::from((ErrorKind::BadInput, "bad input")))?;
+            Ok(())
+        }
+        let err = run().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>().unwrap().kind,
+            ErrorKind::BadInput
+        );
+    }
+}
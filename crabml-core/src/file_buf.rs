@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::ops::Deref;
+
+#[cfg(unix)]
+use memmap2::Advice;
+#[cfg(not(target_arch = "wasm32"))]
+use memmap2::Mmap;
+#[cfg(not(target_arch = "wasm32"))]
+use memmap2::MmapMut;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// how a model file is loaded into the memory, which trades the startup time with the stalls
+/// on the page faults of the first generation. the options are ignored on wasm32, where the
+/// file is always read into the memory.
+#[derive(Debug, Clone, Copy)]
+pub struct FileBufOptions {
+    /// mmap the file, so the weights are paged in on their first access. otherwise the file
+    /// is read into the memory on opening, which takes longer to start, but the first
+    /// generation has no page faults.
+    pub mmap: bool,
+
+    /// lock the bytes in the RAM, so the weights are not swapped out or evicted from the page
+    /// cache under the memory pressure. the locked bytes are limited by `ulimit -l`.
+    pub mlock: bool,
+
+    /// ask the kernel to read ahead the whole mmaped file in the background on opening, which
+    /// saves the page faults of the first generation without blocking the startup.
+    pub prefetch: bool,
+}
+
+impl Default for FileBufOptions {
+    fn default() -> Self {
+        Self {
+            mmap: true,
+            mlock: false,
+            prefetch: false,
+        }
+    }
+}
+
+/// the bytes of a model file, which the loaded tensors are zero-copy slices of. the file is
+/// mmaped on the native targets. on the targets without mmap like `wasm32-unknown-unknown`,
+/// the bytes are read into the memory, which can also be taken from the bytes fetched by the
+/// caller, like a `fetch()` in the browser.
+pub enum FileBuf {
+    #[cfg(not(target_arch = "wasm32"))]
+    Mmap(Mmap),
+    Bytes(AlignedBytes),
+}
+
+impl FileBuf {
+    /// mmap the file, or read it into the memory on wasm32.
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_with_options(path, FileBufOptions::default())
+    }
+
+    pub fn open_with_options(path: &str, opts: FileBufOptions) -> Result<Self> {
+        let file = File::open(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to open the file: {}", path),
+            cause: Some(Box::new(err)),
+        })?;
+        Self::from_file(file, path, opts)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn from_file(mut file: File, path: &str, opts: FileBufOptions) -> Result<Self> {
+        let io_error = |message: String| {
+            move |err: std::io::Error| Error {
+                kind: ErrorKind::IOError,
+                message,
+                cause: Some(Box::new(err)),
+            }
+        };
+        let mmap = if opts.mmap {
+            unsafe { Mmap::map(&file).map_err(io_error(format!("failed to mmap file: {}", path)))? }
+        } else {
+            // read into an anonymous map, which is aligned to the pages and can be locked like
+            // the mmaped file
+            let len = file
+                .metadata()
+                .map_err(io_error(format!("failed to read the file: {}", path)))?
+                .len() as usize;
+            let mut buf = MmapMut::map_anon(len.max(1))
+                .map_err(io_error(format!("failed to allocate {} bytes", len)))?;
+            std::io::Read::read_exact(&mut file, &mut buf[..len])
+                .map_err(io_error(format!("failed to read the file: {}", path)))?;
+            buf.make_read_only()
+                .map_err(io_error(format!("failed to read the file: {}", path)))?
+        };
+
+        #[cfg(unix)]
+        if opts.mmap && opts.prefetch {
+            mmap.advise(Advice::WillNeed)
+                .map_err(io_error(format!("failed to prefetch the file: {}", path)))?;
+        }
+        #[cfg(unix)]
+        if opts.mlock {
+            mmap.lock().map_err(io_error(format!(
+                "failed to mlock the file: {}, the locked memory is limited by `ulimit -l`",
+                path
+            )))?;
+        }
+        #[cfg(not(unix))]
+        if opts.mlock {
+            return Err((ErrorKind::NotImplemented, "mlock is only supported on unix").into());
+        }
+        Ok(Self::Mmap(mmap))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn from_file(mut file: File, path: &str, _opts: FileBufOptions) -> Result<Self> {
+        let mut bytes = vec![];
+        std::io::Read::read_to_end(&mut file, &mut bytes).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read the file: {}", path),
+            cause: Some(Box::new(err)),
+        })?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    /// copy the bytes of a whole file, the copy is aligned for the tensors to be cast into
+    /// the slices of f32 or the quantized blocks.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut buf = AlignedBytes::zeroed(bytes.len());
+        buf.as_mut().copy_from_slice(bytes);
+        Self::Bytes(buf)
+    }
+
+    /// read the whole file from the reader by the ranges of `chunk_bytes`, like the http range
+    /// requests of a file on the server.
+    pub fn from_range_reader(reader: &mut impl RangeReader, chunk_bytes: usize) -> Result<Self> {
+        let mut buf = AlignedBytes::zeroed(reader.size() as usize);
+        for (i, chunk) in buf.as_mut().chunks_mut(chunk_bytes.max(1)).enumerate() {
+            let offset = (i * chunk_bytes.max(1)) as u64;
+            reader.read_range(offset, chunk)?;
+        }
+        Ok(Self::Bytes(buf))
+    }
+}
+
+impl Deref for FileBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Mmap(mmap) => &mmap[..],
+            Self::Bytes(buf) => buf.as_ref(),
+        }
+    }
+}
+
+/// reads the ranges of a file of a known size, like the http range requests, so the model
+/// files can be loaded from where there's no file system.
+pub trait RangeReader {
+    /// the size of the file in bytes.
+    fn size(&self) -> u64;
+
+    /// fill `buf` with the bytes of the file starting at `offset`.
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+impl RangeReader for &[u8] {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_range(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let start = offset as usize;
+        let src = self.get(start..start + buf.len()).ok_or_else(|| {
+            Error::from((
+                ErrorKind::IOError,
+                format!(
+                    "failed to read {} bytes at {}, out of the {} bytes",
+                    buf.len(),
+                    offset,
+                    self.len()
+                ),
+            ))
+        })?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(32))]
+struct AlignedBlock([u8; 32]);
+
+/// the bytes allocated in 32-byte aligned blocks, like the data section of a GGUF file.
+pub struct AlignedBytes {
+    blocks: Vec<AlignedBlock>,
+    len: usize,
+}
+
+impl AlignedBytes {
+    fn zeroed(len: usize) -> Self {
+        Self {
+            blocks: vec![AlignedBlock([0; 32]); len.div_ceil(32)],
+            len,
+        }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBytes {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.blocks.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl AsMut<[u8]> for AlignedBytes {
+    fn as_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.blocks.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_buf() -> Result<()> {
+        let bytes = (0..100).map(|i| i as u8).collect::<Vec<_>>();
+        let buf = FileBuf::from_bytes(&bytes);
+        assert_eq!(&buf[..], &bytes[..]);
+        assert_eq!(buf.as_ptr() as usize % 32, 0);
+
+        for chunk_bytes in [1, 7, 32, 100, 1000] {
+            let buf = FileBuf::from_range_reader(&mut &bytes[..], chunk_bytes)?;
+            assert_eq!(&buf[..], &bytes[..]);
+        }
+
+        let mut reader = &bytes[..];
+        let mut out = [0u8; 8];
+        let err = reader.read_range(96, &mut out).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_buf_options() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let expected = FileBuf::open(path)?;
+        for (mmap, prefetch) in [(true, true), (false, false)] {
+            let opts = FileBufOptions {
+                mmap,
+                mlock: false,
+                prefetch,
+            };
+            let buf = FileBuf::open_with_options(path, opts)?;
+            assert_eq!(&buf[..], &expected[..]);
+            assert_eq!(buf.as_ptr() as usize % 32, 0);
+        }
+
+        let opts = FileBufOptions {
+            mmap: false,
+            ..Default::default()
+        };
+        let err = FileBuf::open_with_options("../testdata/not-exists.gguf", opts)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        Ok(())
+    }
+}
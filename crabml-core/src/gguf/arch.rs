@@ -0,0 +1,63 @@
+use std::fmt;
+
+/// the model architectures in `general.architecture`, not all of them can be run yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelArch {
+    Llama,
+    Qwen2,
+    Phi2,
+    Phi3,
+    Falcon,
+    StableLM,
+    Mpt,
+    Bloom,
+    Rwkv6,
+    Bert,
+    NomicBert,
+}
+
+const ARCH_NAMES: &[(ModelArch, &str)] = &[
+    (ModelArch::Llama, "llama"),
+    (ModelArch::Qwen2, "qwen2"),
+    (ModelArch::Phi2, "phi2"),
+    (ModelArch::Phi3, "phi3"),
+    (ModelArch::Falcon, "falcon"),
+    (ModelArch::StableLM, "stablelm"),
+    (ModelArch::Mpt, "mpt"),
+    (ModelArch::Bloom, "bloom"),
+    (ModelArch::Rwkv6, "rwkv6"),
+    (ModelArch::Bert, "bert"),
+    (ModelArch::NomicBert, "nomic-bert"),
+];
+
+impl ModelArch {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ARCH_NAMES
+            .iter()
+            .find_map(|(arch, n)| (*n == name).then_some(*arch))
+    }
+
+    pub fn name(&self) -> &'static str {
+        ARCH_NAMES.iter().find(|(arch, _)| arch == self).unwrap().1
+    }
+}
+
+impl fmt::Display for ModelArch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_arch() {
+        for (arch, name) in ARCH_NAMES {
+            assert_eq!(ModelArch::from_name(name), Some(*arch));
+            assert_eq!(arch.to_string(), *name);
+        }
+        assert_eq!(ModelArch::from_name("gpt3"), None);
+    }
+}
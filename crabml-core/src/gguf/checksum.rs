@@ -0,0 +1,161 @@
+use rayon::prelude::*;
+
+use super::GGUFFile;
+use super::KEY_CHECKSUM_SHA256;
+use super::KEY_CHECKSUM_TENSORS_SHA256;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::sha256::to_hex;
+use crate::sha256::Digest;
+use crate::sha256::Sha256;
+
+impl<'a> GGUFFile<'a> {
+    /// the SHA-256 of the data of all the tensors in their order. unlike the hash of the
+    /// whole file, it's kept when only the metadata is changed, so it can be stored in the
+    /// metadata of the file itself.
+    pub fn tensor_data_sha256(&self) -> String {
+        let mut hasher = Sha256::new();
+        for info in self.tensor_infos() {
+            hasher.update(info.data());
+        }
+        to_hex(&hasher.finalize())
+    }
+
+    /// the SHA-256 of the data of each tensor, hashed in parallel.
+    pub fn tensor_sha256s(&self) -> Vec<String> {
+        self.tensor_infos()
+            .par_iter()
+            .map(|info| {
+                let mut hasher = Sha256::new();
+                hasher.update(info.data());
+                to_hex(&hasher.finalize())
+            })
+            .collect()
+    }
+
+    /// whether the checksums of the tensor data are stored in the metadata.
+    pub fn has_checksums(&self) -> bool {
+        self.metadata().get(KEY_CHECKSUM_SHA256).is_some()
+            || self.metadata().get(KEY_CHECKSUM_TENSORS_SHA256).is_some()
+    }
+
+    /// verify the data of the tensors against the checksums in the metadata, the checksums
+    /// of each tensor are taken if they're stored, which tell the corrupted tensor. returns
+    /// false if there's no checksum stored.
+    pub fn verify_checksums(&self) -> Result<bool> {
+        let metadata = self.metadata();
+        if let Some(expected) = metadata.get_string_array(KEY_CHECKSUM_TENSORS_SHA256) {
+            if expected.len() != self.tensor_infos().len() {
+                return Err(Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!(
+                        "{} has {} checksums, but there are {} tensors",
+                        KEY_CHECKSUM_TENSORS_SHA256,
+                        expected.len(),
+                        self.tensor_infos().len()
+                    ),
+                    cause: None,
+                });
+            }
+            let actual = self.tensor_sha256s();
+            for ((info, expected), actual) in self.tensor_infos().iter().zip(expected).zip(actual) {
+                if !actual.eq_ignore_ascii_case(expected) {
+                    return Err(checksum_mismatch(
+                        &format!("the tensor {}", info.name()),
+                        &actual,
+                        expected,
+                    ));
+                }
+            }
+            return Ok(true);
+        }
+
+        if let Some(expected) = metadata.get_string(KEY_CHECKSUM_SHA256) {
+            let actual = self.tensor_data_sha256();
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(checksum_mismatch("the tensor data", &actual, expected));
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+fn checksum_mismatch(what: &str, actual: &str, expected: &str) -> Error {
+    Error {
+        kind: ErrorKind::FormatError,
+        message: format!(
+            "the sha256 of {} is {}, but {} is expected, the file is corrupted",
+            what, actual, expected
+        ),
+        cause: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::GGUFFileLoader;
+    use super::super::GGUFMetadataArray;
+    use super::super::GGUFMetadataValue;
+    use super::super::GGUFWriter;
+    use super::*;
+
+    #[test]
+    fn test_verify_checksums() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        assert!(!gf.has_checksums());
+        assert!(!gf.verify_checksums()?);
+
+        let file_sha256 = gf.tensor_data_sha256();
+        let tensor_sha256s = gf.tensor_sha256s();
+        assert_eq!(tensor_sha256s.len(), gf.tensor_infos().len());
+
+        let write = |tensors: bool, corrupt: bool| -> Result<Vec<u8>> {
+            let mut w = GGUFWriter::new();
+            for (key, value) in gf.metadata().as_hashmap() {
+                w.add_metadata(key, value.clone());
+            }
+            w.add_metadata(KEY_CHECKSUM_SHA256, GGUFMetadataValue::String(&file_sha256));
+            if tensors {
+                w.add_metadata(
+                    KEY_CHECKSUM_TENSORS_SHA256,
+                    GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                        tensor_sha256s.iter().map(|s| s.as_str()).collect(),
+                    )),
+                );
+            }
+            for (i, info) in gf.tensor_infos().iter().enumerate() {
+                let mut data = info.data().to_vec();
+                if corrupt && i == 3 {
+                    data[7] ^= 1;
+                }
+                w.add_tensor(info.name(), info.dimensions(), info.typ(), data)?;
+            }
+            let mut buf = vec![];
+            w.write(&mut buf)?;
+            Ok(buf)
+        };
+
+        for tensors in [false, true] {
+            let buf = write(tensors, false)?;
+            let gl = GGUFFileLoader::from_bytes(&[&buf])?;
+            let gf2 = gl.open()?;
+            assert!(gf2.has_checksums());
+            assert!(gf2.verify_checksums()?);
+
+            let buf = write(tensors, true)?;
+            let gl = GGUFFileLoader::from_bytes(&[&buf])?;
+            let err = gl.open()?.verify_checksums().unwrap_err();
+            assert_eq!(err.kind, ErrorKind::FormatError);
+            let what = if tensors {
+                format!("the tensor {}", gf.tensor_infos()[3].name())
+            } else {
+                "the tensor data".to_string()
+            };
+            assert!(err.message.contains(&what), "{}", err);
+        }
+        Ok(())
+    }
+}
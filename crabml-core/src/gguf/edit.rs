@@ -0,0 +1,179 @@
+use std::fs::OpenOptions;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use super::GGUFBufReader;
+use super::GGUFFile;
+use super::GGUFMetadataValue;
+use super::GGUFWriter;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::file_buf::FileBuf;
+
+/// how the metadata of a GGUF file is rewritten by `edit_metadata`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GGUFEditMode {
+    /// the new metadata fits in the bytes before the tensor data, so only the header is
+    /// overwritten in place.
+    InPlace,
+    /// the header grows over or shrinks below the padding before the tensor data, so the
+    /// file is copied with the new header, and the copy replaces the file.
+    CopyOnWrite,
+}
+
+/// set and remove the metadata kvs of the GGUF file at `path`, like fixing a wrong
+/// `rope.freq_base` without converting the model again. the tensor data is left untouched if
+/// the new header takes the same bytes as the old one padded to the alignment, otherwise the
+/// file is rewritten into `<path>.tmp` and renamed over it.
+///
+/// the file is written in GGUF v3, so the files of the older versions are always copied.
+/// only the given file is edited for a shard of a split model.
+pub fn edit_metadata(
+    path: &str,
+    set: &[(&str, GGUFMetadataValue)],
+    remove: &[&str],
+) -> Result<GGUFEditMode> {
+    let buf = FileBuf::open(path)?;
+    let gf = GGUFFile::decode(&mut GGUFBufReader::new(&buf[..]))?;
+    for key in remove {
+        if gf.metadata().get(key).is_none() {
+            return Err((
+                ErrorKind::BadInput,
+                format!("the metadata key {} is not found in {}", key, path),
+            )
+                .into());
+        }
+    }
+
+    let mut w = GGUFWriter::new();
+    for (key, value) in gf.metadata().iter() {
+        w.add_metadata(key, value.clone());
+    }
+    for (key, value) in set {
+        w.add_metadata(key, value.clone());
+    }
+    for key in remove {
+        w.remove_metadata(key);
+    }
+    for info in gf.tensor_infos() {
+        w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?;
+    }
+
+    // the tensors keep their offsets if they're packed as the writer packs them
+    let data_start = buf.len() - gf.tensor_data().len();
+    let same_offsets = gf
+        .tensor_infos()
+        .iter()
+        .zip(w.tensor_offsets())
+        .all(|(info, offset)| {
+            info.data().as_ptr() as usize - gf.tensor_data().as_ptr() as usize == offset
+        });
+    let mut header = Vec::with_capacity(data_start);
+    w.write_header(&mut header)?;
+
+    if same_offsets && header.len() == data_start {
+        drop(w);
+        drop(gf);
+        drop(buf);
+        let io_error = |err: std::io::Error| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to write the header of {}", path),
+            cause: Some(Box::new(err)),
+        };
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(io_error)?;
+        f.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        f.write_all(&header).map_err(io_error)?;
+        f.sync_all().map_err(io_error)?;
+        return Ok(GGUFEditMode::InPlace);
+    }
+
+    let tmp_path = format!("{}.tmp", path);
+    if let Err(err) = w.write_to_file(&tmp_path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    drop(w);
+    drop(gf);
+    drop(buf);
+    std::fs::rename(&tmp_path, path).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to rename {} to {}", tmp_path, path),
+        cause: Some(Box::new(err)),
+    })?;
+    Ok(GGUFEditMode::CopyOnWrite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_edit_metadata() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("crabml-edit-{}.gguf", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::copy("../testdata/tinyllamas-stories-260k-f32.gguf", path).unwrap();
+        let (tensor_data_sha256, keys) = {
+            let gl = GGUFFileLoader::new(path)?;
+            let gf = gl.open()?;
+            let keys = gf
+                .metadata()
+                .iter()
+                .map(|(k, _)| k.to_string())
+                .collect::<Vec<_>>();
+            (gf.tensor_data_sha256(), keys)
+        };
+
+        // the test file is in GGUF v1, which is rewritten into v3
+        let key = "llama.rope.freq_base";
+        let name = "a long name which takes more bytes than the padding before the tensor data";
+        let mode = edit_metadata(
+            path,
+            &[
+                ("general.name", GGUFMetadataValue::String(name)),
+                (key, GGUFMetadataValue::F32(10000.0)),
+            ],
+            &[],
+        )?;
+        assert_eq!(mode, GGUFEditMode::CopyOnWrite);
+
+        // a value of the same size is written in place
+        let mode = edit_metadata(path, &[(key, GGUFMetadataValue::F32(500000.0))], &[])?;
+        assert_eq!(mode, GGUFEditMode::InPlace);
+        {
+            let gl = GGUFFileLoader::new(path)?;
+            let gf = gl.open()?;
+            assert_eq!(gf.metadata().get_f32(key), Some(500000.0));
+            assert_eq!(gf.metadata().get_string("general.name"), Some(name));
+            assert_eq!(gf.tensor_data_sha256(), tensor_data_sha256);
+        }
+
+        edit_metadata(path, &[], &["general.name", key])?;
+        {
+            let gl = GGUFFileLoader::new(path)?;
+            let gf = gl.open()?;
+            assert_eq!(gf.metadata().get(key), None);
+            assert_eq!(gf.metadata().get("general.name"), None);
+            assert_eq!(gf.tensor_data_sha256(), tensor_data_sha256);
+
+            // the other kvs are written back in the order of the file
+            let got = gf.metadata().iter().map(|(k, _)| k).collect::<Vec<_>>();
+            let want = keys
+                .iter()
+                .filter(|k| *k != "general.name" && *k != key)
+                .collect::<Vec<_>>();
+            assert_eq!(got, want);
+        }
+
+        let err = edit_metadata(path, &[], &["not.found"]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        std::fs::remove_file(path).unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::fmt::Display;
+
+use int_enum::IntEnum;
+
+use super::GGUFVersion;
+use crate::error::Error;
+use crate::error::ErrorKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GGUFErrorKind {
+    InvalidMagic,
+    UnsupportedVersion,
+    Truncated,
+    InvalidMetadata,
+    DuplicateKey,
+    MissingKey,
+    MetadataTypeMismatch,
+    InvalidAlignment,
+    InvalidTensorInfo,
+    DuplicateTensor,
+    MisalignedTensor,
+    TensorOutOfBounds,
+    OverlappingTensors,
+}
+
+/// GGUFError is a problem in a GGUF file, found on loading or by `validate()`. the offsets are
+/// the positions in the file where the problematic items start, the offset of a tensor is where
+/// its tensor info starts. the errors on decoding a metadata or a tensor info keep the error of
+/// the underlying read as their source.
+#[derive(Debug)]
+pub enum GGUFError {
+    InvalidMagic {
+        magic: u32,
+    },
+    UnsupportedVersion {
+        version: u32,
+    },
+    /// `len` bytes are read at `offset`, but only `available` bytes are left.
+    Truncated {
+        offset: usize,
+        len: usize,
+        available: usize,
+    },
+    /// the length of a string or an array can not fit in the memory.
+    InvalidLength {
+        offset: usize,
+        len: usize,
+    },
+    InvalidValueType {
+        offset: usize,
+        typ: u32,
+    },
+    InvalidString {
+        offset: usize,
+        source: std::str::Utf8Error,
+    },
+    /// the key is None if the key itself can not be decoded.
+    InvalidMetadata {
+        offset: usize,
+        key: Option<String>,
+        reason: String,
+        source: Option<Box<GGUFError>>,
+    },
+    DuplicateKey {
+        offset: usize,
+        key: String,
+    },
+    MissingKey {
+        key: String,
+    },
+    /// the offset is None on the metadata already loaded, which keeps no positions.
+    MetadataTypeMismatch {
+        offset: Option<usize>,
+        key: String,
+        expected: String,
+        actual: String,
+    },
+    InvalidAlignment {
+        offset: usize,
+        alignment: usize,
+    },
+    /// the tensor is None if the name itself can not be decoded.
+    InvalidTensorInfo {
+        offset: usize,
+        tensor: Option<String>,
+        reason: String,
+        source: Option<Box<GGUFError>>,
+    },
+    DuplicateTensor {
+        offset: usize,
+        tensor: String,
+    },
+    /// the data offset is relative to the start of the tensor data.
+    MisalignedTensor {
+        offset: usize,
+        tensor: String,
+        data_offset: usize,
+        alignment: usize,
+    },
+    /// the data position is relative to the start of the file.
+    TensorOutOfBounds {
+        offset: usize,
+        tensor: String,
+        data_position: usize,
+        size: usize,
+        file_size: usize,
+    },
+    OverlappingTensors {
+        offset: usize,
+        tensor: String,
+        other: String,
+    },
+}
+
+impl GGUFError {
+    pub fn kind(&self) -> GGUFErrorKind {
+        match self {
+            GGUFError::InvalidMagic { .. } => GGUFErrorKind::InvalidMagic,
+            GGUFError::UnsupportedVersion { .. } => GGUFErrorKind::UnsupportedVersion,
+            GGUFError::Truncated { .. } => GGUFErrorKind::Truncated,
+            GGUFError::InvalidLength { .. }
+            | GGUFError::InvalidValueType { .. }
+            | GGUFError::InvalidString { .. }
+            | GGUFError::InvalidMetadata { .. } => GGUFErrorKind::InvalidMetadata,
+            GGUFError::DuplicateKey { .. } => GGUFErrorKind::DuplicateKey,
+            GGUFError::MissingKey { .. } => GGUFErrorKind::MissingKey,
+            GGUFError::MetadataTypeMismatch { .. } => GGUFErrorKind::MetadataTypeMismatch,
+            GGUFError::InvalidAlignment { .. } => GGUFErrorKind::InvalidAlignment,
+            GGUFError::InvalidTensorInfo { .. } => GGUFErrorKind::InvalidTensorInfo,
+            GGUFError::DuplicateTensor { .. } => GGUFErrorKind::DuplicateTensor,
+            GGUFError::MisalignedTensor { .. } => GGUFErrorKind::MisalignedTensor,
+            GGUFError::TensorOutOfBounds { .. } => GGUFErrorKind::TensorOutOfBounds,
+            GGUFError::OverlappingTensors { .. } => GGUFErrorKind::OverlappingTensors,
+        }
+    }
+
+    /// the position in the file where the problem starts, None if it's not in the file, like a
+    /// missing key.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            GGUFError::InvalidMagic { .. } => Some(0),
+            GGUFError::UnsupportedVersion { .. } => Some(4),
+            GGUFError::MissingKey { .. } => None,
+            GGUFError::MetadataTypeMismatch { offset, .. } => *offset,
+            GGUFError::Truncated { offset, .. }
+            | GGUFError::InvalidLength { offset, .. }
+            | GGUFError::InvalidValueType { offset, .. }
+            | GGUFError::InvalidString { offset, .. }
+            | GGUFError::InvalidMetadata { offset, .. }
+            | GGUFError::DuplicateKey { offset, .. }
+            | GGUFError::InvalidAlignment { offset, .. }
+            | GGUFError::InvalidTensorInfo { offset, .. }
+            | GGUFError::DuplicateTensor { offset, .. }
+            | GGUFError::MisalignedTensor { offset, .. }
+            | GGUFError::TensorOutOfBounds { offset, .. }
+            | GGUFError::OverlappingTensors { offset, .. } => Some(*offset),
+        }
+    }
+}
+
+impl Display for GGUFError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GGUFError::InvalidMagic { magic } => {
+                write!(f, "invalid magic number {:#x}, not a GGUF file", magic)
+            }
+            // the magic number is defined at the byte level, so a big-endian file can only be
+            // distinguished by its byte-swapped version number.
+            GGUFError::UnsupportedVersion { version }
+                if GGUFVersion::from_int(version.swap_bytes()).is_ok() =>
+            {
+                write!(
+                    f,
+                    "big-endian GGUF files are not supported yet, version: {}",
+                    version.swap_bytes()
+                )
+            }
+            GGUFError::UnsupportedVersion { version } => write!(
+                f,
+                "unsupported version {}, only 1, 2 and 3 are supported",
+                version
+            ),
+            GGUFError::Truncated {
+                offset,
+                len,
+                available,
+            } => write!(
+                f,
+                "failed to read {} bytes at offset {}, only {} bytes left, the file may be truncated",
+                len, offset, available
+            ),
+            GGUFError::InvalidLength { offset, len } => {
+                write!(f, "invalid length {} at offset {}", len, offset)
+            }
+            GGUFError::InvalidValueType { offset, typ } => {
+                write!(f, "invalid value type {} at offset {}", typ, offset)
+            }
+            GGUFError::InvalidString { offset, .. } => {
+                write!(f, "invalid UTF-8 string at offset {}", offset)
+            }
+            GGUFError::InvalidMetadata {
+                offset,
+                key: Some(key),
+                reason,
+                ..
+            } => write!(
+                f,
+                "invalid metadata {} at offset {}: {}",
+                key, offset, reason
+            ),
+            GGUFError::InvalidMetadata { offset, reason, .. } => {
+                write!(f, "invalid metadata at offset {}: {}", offset, reason)
+            }
+            GGUFError::DuplicateKey { offset, key } => {
+                write!(f, "duplicated metadata key {} at offset {}", key, offset)
+            }
+            GGUFError::MissingKey { key } => write!(f, "missing metadata {}", key),
+            GGUFError::MetadataTypeMismatch {
+                offset,
+                key,
+                expected,
+                actual,
+            } => {
+                write!(f, "metadata {}", key)?;
+                if let Some(offset) = offset {
+                    write!(f, " at offset {}", offset)?;
+                }
+                write!(f, " is expected to be {}, but got {}", expected, actual)
+            }
+            GGUFError::InvalidAlignment { offset, alignment } => write!(
+                f,
+                "the alignment {} at offset {} is not a multiple of 8",
+                alignment, offset
+            ),
+            GGUFError::InvalidTensorInfo {
+                offset,
+                tensor: Some(tensor),
+                reason,
+                ..
+            } => write!(
+                f,
+                "invalid tensor {} at offset {}: {}",
+                tensor, offset, reason
+            ),
+            GGUFError::InvalidTensorInfo { offset, reason, .. } => {
+                write!(f, "invalid tensor at offset {}: {}", offset, reason)
+            }
+            GGUFError::DuplicateTensor { offset, tensor } => {
+                write!(f, "duplicated tensor {} at offset {}", tensor, offset)
+            }
+            GGUFError::MisalignedTensor {
+                offset,
+                tensor,
+                data_offset,
+                alignment,
+            } => write!(
+                f,
+                "the data offset {} of tensor {} at offset {} is not aligned to {}",
+                data_offset, tensor, offset, alignment
+            ),
+            GGUFError::TensorOutOfBounds {
+                offset,
+                tensor,
+                data_position,
+                size,
+                file_size,
+            } => write!(
+                f,
+                "the data of tensor {} at offset {} ({} bytes at {}) exceeds the file of {} bytes, the file may be truncated",
+                tensor, offset, size, data_position, file_size
+            ),
+            GGUFError::OverlappingTensors {
+                offset,
+                tensor,
+                other,
+            } => write!(
+                f,
+                "the data of tensor {} at offset {} overlaps with tensor {}",
+                tensor, offset, other
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GGUFError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GGUFError::InvalidString { source, .. } => Some(source),
+            GGUFError::InvalidMetadata {
+                source: Some(source),
+                ..
+            }
+            | GGUFError::InvalidTensorInfo {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<GGUFError> for Error {
+    fn from(err: GGUFError) -> Self {
+        Error {
+            kind: ErrorKind::FormatError,
+            message: err.to_string(),
+            cause: Some(Box::new(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn test_gguf_error() {
+        let err = GGUFError::InvalidMetadata {
+            offset: 24,
+            key: Some("general.name".to_string()),
+            reason: "failed to decode the value".to_string(),
+            source: Some(Box::new(GGUFError::Truncated {
+                offset: 52,
+                len: 8,
+                available: 3,
+            })),
+        };
+        assert_eq!(err.kind(), GGUFErrorKind::InvalidMetadata);
+        assert_eq!(err.offset(), Some(24));
+        assert_eq!(
+            err.to_string(),
+            "invalid metadata general.name at offset 24: failed to decode the value"
+        );
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "failed to read 8 bytes at offset 52, only 3 bytes left, the file may be truncated"
+        );
+
+        let err: Error = err.into();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert_eq!(
+            err.to_string(),
+            "FormatError: invalid metadata general.name at offset 24: failed to decode the value\ncaused by: failed to read 8 bytes at offset 52, only 3 bytes left, the file may be truncated"
+        );
+
+        let err = GGUFError::UnsupportedVersion {
+            version: 3_u32.swap_bytes(),
+        };
+        assert_eq!(err.offset(), Some(4));
+        assert!(err.to_string().contains("big-endian"));
+        let err = GGUFError::MissingKey {
+            key: "llama.block_count".to_string(),
+        };
+        assert_eq!(err.offset(), None);
+    }
+}
@@ -1,14 +1,36 @@
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::fs::File;
 use std::mem;
 
 use int_enum::IntEnum;
-use memmap2::Mmap;
 
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
+use crate::file_buf::FileBuf;
+use crate::file_buf::FileBufOptions;
+use crate::file_buf::RangeReader;
+
+mod arch;
+mod checksum;
+mod edit;
+mod error;
+mod split;
+mod tensor_names;
+mod validate;
+mod writer;
+pub use arch::ModelArch;
+pub use edit::edit_metadata;
+pub use edit::GGUFEditMode;
+pub use error::GGUFError;
+pub use error::GGUFErrorKind;
+pub use split::split_path;
+pub use split::split_prefix;
+pub use tensor_names::ModelTensor;
+pub use tensor_names::TensorDiagnostics;
+pub use tensor_names::TensorNameMap;
+pub use validate::validate;
+pub use writer::GGUFWriter;
 
 const GGUF_MAGIC: u32 = 0x46554747;
 const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
@@ -26,13 +48,25 @@ pub const KEY_GENERAL_SOURCE_URL: &str = "general.source.url";
 pub const KEY_GENERAL_SOURCE_HF_REPO: &str = "general.source.hugginface.repository";
 pub const KEY_GENERAL_FILE_TYPE: &str = "general.file_type";
 
+// Split
+pub const KEY_SPLIT_NO: &str = "split.no";
+pub const KEY_SPLIT_COUNT: &str = "split.count";
+pub const KEY_SPLIT_TENSORS_COUNT: &str = "split.tensors.count";
+pub const KEY_CHECKSUM_SHA256: &str = "crabml.checksum.sha256";
+pub const KEY_CHECKSUM_TENSORS_SHA256: &str = "crabml.checksum.tensors.sha256";
+
 // LLM
 pub const KEY_CONTEXT_LENGTH: &str = "{arch}.context_length";
 pub const KEY_EMBEDDING_LENGTH: &str = "{arch}.embedding_length";
 pub const KEY_BLOCK_COUNT: &str = "{arch}.block_count";
 pub const KEY_FEED_FORWARD_LENGTH: &str = "{arch}.feed_forward_length";
+pub const KEY_EXPERT_COUNT: &str = "{arch}.expert_count";
+pub const KEY_EXPERT_USED_COUNT: &str = "{arch}.expert_used_count";
 pub const KEY_USE_PARALLEL_RESIDUAL: &str = "{arch}.use_parallel_residual";
 pub const KEY_TENSOR_DATA_LAYOUT: &str = "{arch}.tensor_data_layout";
+pub const KEY_RESCALE_EVERY_N_LAYERS: &str = "{arch}.rescale_every_n_layers";
+pub const KEY_WKV_HEAD_SIZE: &str = "{arch}.wkv.head_size";
+pub const KEY_POOLING_TYPE: &str = "{arch}.pooling_type";
 
 // Attention
 pub const KEY_ATTENTION_HEAD_COUNT: &str = "{arch}.attention.head_count";
@@ -41,14 +75,20 @@ pub const KEY_ATTENTION_MAX_ALIBI_BIAS: &str = "{arch}.attention.max_alibi_bias"
 pub const KEY_ATTENTION_CLAMP_KQV: &str = "{arch}.attention.clamp_kqv";
 pub const KEY_ATTENTION_LAYERNORM_EPS: &str = "{arch}.attention.layer_norm_epsilon";
 pub const KEY_ATTENTION_LAYERNORM_RMS_EPS: &str = "{arch}.attention.layer_norm_rms_epsilon";
+pub const KEY_ATTENTION_SLIDING_WINDOW: &str = "{arch}.attention.sliding_window";
 
 // RoPE
 pub const KEY_ROPE_DIMENSION_COUNT: &str = "{arch}.rope.dimension_count";
 pub const KEY_ROPE_FREQ_BASE: &str = "{arch}.rope.freq_base";
 pub const KEY_ROPE_SCALE_LINEAR: &str = "{arch}.rope.scale_linear";
+pub const KEY_ROPE_SCALING_TYPE: &str = "{arch}.rope.scaling.type";
+pub const KEY_ROPE_SCALING_FACTOR: &str = "{arch}.rope.scaling.factor";
+pub const KEY_ROPE_SCALING_ATTN_FACTOR: &str = "{arch}.rope.scaling.attn_factor";
+pub const KEY_ROPE_SCALING_ORIG_CTX_LEN: &str = "{arch}.rope.scaling.original_context_length";
 
 // Tokenization
 pub const KEY_TOKENIZER_MODEL: &str = "tokenizer.ggml.model";
+pub const KEY_TOKENIZER_PRE: &str = "tokenizer.ggml.pre";
 pub const KEY_TOKENIZER_LIST: &str = "tokenizer.ggml.tokens";
 pub const KEY_TOKENIZER_TOKEN_TYPE: &str = "tokenizer.ggml.token_type";
 pub const KEY_TOKENIZER_SCORES: &str = "tokenizer.ggml.scores";
@@ -57,7 +97,11 @@ pub const KEY_TOKENIZER_BOS_ID: &str = "tokenizer.ggml.bos_token_id";
 pub const KEY_TOKENIZER_EOS_ID: &str = "tokenizer.ggml.eos_token_id";
 pub const KEY_TOKENIZER_UNK_ID: &str = "tokenizer.ggml.unknown_token_id";
 pub const KEY_TOKENIZER_SEP_ID: &str = "tokenizer.ggml.seperator_token_id";
+pub const KEY_TOKENIZER_CLS_ID: &str = "tokenizer.ggml.cls_token_id";
 pub const KEY_TOKENIZER_PAD_ID: &str = "tokenizer.ggml.padding_token_id";
+pub const KEY_TOKENIZER_ADD_BOS: &str = "tokenizer.ggml.add_bos_token";
+pub const KEY_TOKENIZER_ADD_EOS: &str = "tokenizer.ggml.add_eos_token";
+pub const KEY_TOKENIZER_CHAT_TEMPLATE: &str = "tokenizer.chat_template";
 pub const KEY_TOKENIZER_HF_JSON: &str = "tokenizer.huggingface.json";
 pub const KEY_TOKENIZER_RWKV: &str = "tokenizer.rwkv.world";
 
@@ -66,6 +110,7 @@ pub const KEY_TOKENIZER_RWKV: &str = "tokenizer.rwkv.world";
 pub enum GGUFVersion {
     V1 = 1,
     V2 = 2,
+    V3 = 3,
 }
 
 impl Display for GGUFVersion {
@@ -73,6 +118,7 @@ impl Display for GGUFVersion {
         match self {
             GGUFVersion::V1 => write!(f, "1"),
             GGUFVersion::V2 => write!(f, "2"),
+            GGUFVersion::V3 => write!(f, "3"),
         }
     }
 }
@@ -97,10 +143,23 @@ pub enum GGMLType {
     Q5K = 13,
     Q6K = 14,
     Q8K = 15,
-    I8 = 16,
-    I16 = 17,
-    I32 = 18,
-    COUNT = 19,
+    // i-quantizations
+    IQ2XXS = 16,
+    IQ2XS = 17,
+    IQ3XXS = 18,
+    IQ1S = 19,
+    IQ4NL = 20,
+    IQ3S = 21,
+    IQ2S = 22,
+    IQ4XS = 23,
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    I64 = 27,
+    F64 = 28,
+    IQ1M = 29,
+    BF16 = 30,
+    COUNT = 31,
 }
 
 impl Display for GGMLType {
@@ -120,14 +179,104 @@ impl Display for GGMLType {
             GGMLType::Q5K => write!(f, "Q5_K"),
             GGMLType::Q6K => write!(f, "Q6_K"),
             GGMLType::Q8K => write!(f, "Q8_K"),
+            GGMLType::IQ2XXS => write!(f, "IQ2_XXS"),
+            GGMLType::IQ2XS => write!(f, "IQ2_XS"),
+            GGMLType::IQ3XXS => write!(f, "IQ3_XXS"),
+            GGMLType::IQ1S => write!(f, "IQ1_S"),
+            GGMLType::IQ4NL => write!(f, "IQ4_NL"),
+            GGMLType::IQ3S => write!(f, "IQ3_S"),
+            GGMLType::IQ2S => write!(f, "IQ2_S"),
+            GGMLType::IQ4XS => write!(f, "IQ4_XS"),
             GGMLType::I8 => write!(f, "I8"),
             GGMLType::I16 => write!(f, "I16"),
             GGMLType::I32 => write!(f, "I32"),
+            GGMLType::I64 => write!(f, "I64"),
+            GGMLType::F64 => write!(f, "F64"),
+            GGMLType::IQ1M => write!(f, "IQ1_M"),
+            GGMLType::BF16 => write!(f, "BF16"),
             GGMLType::COUNT => write!(f, "COUNT"),
         }
     }
 }
 
+impl GGMLType {
+    /// the number of elements stored in one block of this type. the non-quantized types
+    /// are considered as having a block of a single element.
+    pub fn block_size(&self) -> usize {
+        match self {
+            GGMLType::F32
+            | GGMLType::F16
+            | GGMLType::BF16
+            | GGMLType::F64
+            | GGMLType::I8
+            | GGMLType::I16
+            | GGMLType::I32
+            | GGMLType::I64 => 1,
+            GGMLType::Q4_0
+            | GGMLType::Q4_1
+            | GGMLType::Q5_0
+            | GGMLType::Q5_1
+            | GGMLType::Q8_0
+            | GGMLType::Q8_1
+            | GGMLType::IQ4NL => 32,
+            GGMLType::COUNT => 0,
+            _ => 256,
+        }
+    }
+
+    /// the bytes of one block of this type.
+    pub fn type_size(&self) -> usize {
+        match self {
+            GGMLType::F32 => 4,
+            GGMLType::F16 => 2,
+            GGMLType::BF16 => 2,
+            GGMLType::F64 => 8,
+            GGMLType::I8 => 1,
+            GGMLType::I16 => 2,
+            GGMLType::I32 => 4,
+            GGMLType::I64 => 8,
+            GGMLType::Q4_0 => 18,
+            GGMLType::Q4_1 => 20,
+            GGMLType::Q5_0 => 22,
+            GGMLType::Q5_1 => 24,
+            GGMLType::Q8_0 => 34,
+            GGMLType::Q8_1 => 36,
+            GGMLType::Q2K => 84,
+            GGMLType::Q3K => 110,
+            GGMLType::Q4K => 144,
+            GGMLType::Q5K => 176,
+            GGMLType::Q6K => 210,
+            GGMLType::Q8K => 292,
+            GGMLType::IQ2XXS => 66,
+            GGMLType::IQ2XS => 74,
+            GGMLType::IQ3XXS => 98,
+            GGMLType::IQ1S => 50,
+            GGMLType::IQ4NL => 18,
+            GGMLType::IQ3S => 110,
+            GGMLType::IQ2S => 82,
+            GGMLType::IQ4XS => 136,
+            GGMLType::IQ1M => 56,
+            GGMLType::COUNT => 0,
+        }
+    }
+
+    /// the bytes occupied by a tensor of this type with `n_elems` elements.
+    pub fn bytes_of(&self, n_elems: usize) -> Result<usize> {
+        let block_size = self.block_size();
+        if block_size == 0 || n_elems % block_size != 0 {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!(
+                    "{} elements can not be stored in blocks of {} for type {}",
+                    n_elems, block_size, self
+                ),
+                cause: None,
+            });
+        }
+        Ok(n_elems / block_size * self.type_size())
+    }
+}
+
 impl TryFrom<u32> for GGMLType {
     type Error = Error;
 
@@ -241,6 +390,49 @@ pub enum GGUFMetadataArray<'a> {
     NestedArray(Vec<GGUFMetadataArray<'a>>),
 }
 
+impl<'a> GGUFMetadataArray<'a> {
+    /// the type of the items in the array.
+    pub fn typ(&self) -> GGUFMetadataValueType {
+        match self {
+            GGUFMetadataArray::U8Array(_) => GGUFMetadataValueType::U8,
+            GGUFMetadataArray::I8Array(_) => GGUFMetadataValueType::I8,
+            GGUFMetadataArray::U16Array(_) => GGUFMetadataValueType::U16,
+            GGUFMetadataArray::I16Array(_) => GGUFMetadataValueType::I16,
+            GGUFMetadataArray::U32Array(_) => GGUFMetadataValueType::U32,
+            GGUFMetadataArray::I32Array(_) => GGUFMetadataValueType::I32,
+            GGUFMetadataArray::U64Array(_) => GGUFMetadataValueType::U64,
+            GGUFMetadataArray::I64Array(_) => GGUFMetadataValueType::I64,
+            GGUFMetadataArray::F32Array(_) => GGUFMetadataValueType::F32,
+            GGUFMetadataArray::F64Array(_) => GGUFMetadataValueType::F64,
+            GGUFMetadataArray::BoolArray(_) => GGUFMetadataValueType::Bool,
+            GGUFMetadataArray::StringArray(_) => GGUFMetadataValueType::String,
+            GGUFMetadataArray::NestedArray(_) => GGUFMetadataValueType::Array,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            GGUFMetadataArray::U8Array(v) => v.len(),
+            GGUFMetadataArray::I8Array(v) => v.len(),
+            GGUFMetadataArray::U16Array(v) => v.len(),
+            GGUFMetadataArray::I16Array(v) => v.len(),
+            GGUFMetadataArray::U32Array(v) => v.len(),
+            GGUFMetadataArray::I32Array(v) => v.len(),
+            GGUFMetadataArray::U64Array(v) => v.len(),
+            GGUFMetadataArray::I64Array(v) => v.len(),
+            GGUFMetadataArray::F32Array(v) => v.len(),
+            GGUFMetadataArray::F64Array(v) => v.len(),
+            GGUFMetadataArray::BoolArray(v) => v.len(),
+            GGUFMetadataArray::StringArray(v) => v.len(),
+            GGUFMetadataArray::NestedArray(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 pub struct GGUFBufReader<'a> {
     cursor: &'a [u8],
     read_bytes: usize,
@@ -254,16 +446,12 @@ impl<'a> GGUFBufReader<'a> {
         }
     }
 
-    pub fn read(&mut self, n: usize) -> Result<&'a [u8]> {
+    pub fn read(&mut self, n: usize) -> std::result::Result<&'a [u8], GGUFError> {
         if n > self.cursor.len() {
-            return Err(Error {
-                kind: ErrorKind::FormatError,
-                message: format!(
-                    "failed to read {} bytes from the buffer, only {} bytes left",
-                    n,
-                    self.cursor.len()
-                ),
-                cause: None,
+            return Err(GGUFError::Truncated {
+                offset: self.read_bytes,
+                len: n,
+                available: self.cursor.len(),
             });
         }
         let v = &self.cursor[0..n];
@@ -290,9 +478,13 @@ where 'a: 'b
 
 macro_rules! define_gguf_metadata_value_read_fn {
     ($read_array_func:ident, $read_item_func:ident, $typ:ty) => {
-        fn $read_array_func(&mut self, n: usize) -> Result<&'a [$typ]> {
+        fn $read_array_func(&mut self, n: usize) -> std::result::Result<&'a [$typ], GGUFError> {
             let typ_size = mem::size_of::<$typ>();
-            let data = self.buf.read(n * typ_size)?;
+            let size = n.checked_mul(typ_size).ok_or(GGUFError::InvalidLength {
+                offset: self.buf.read_bytes(),
+                len: n,
+            })?;
+            let data = self.buf.read(size)?;
             let transmuted_data = unsafe {
                 assert!(data.len() % typ_size == 0);
                 let ptr = data.as_ptr();
@@ -301,7 +493,7 @@ macro_rules! define_gguf_metadata_value_read_fn {
             Ok(transmuted_data)
         }
 
-        fn $read_item_func(&mut self) -> Result<$typ> {
+        fn $read_item_func(&mut self) -> std::result::Result<$typ, GGUFError> {
             let arr = self.$read_array_func(1)?;
             Ok(arr[0])
         }
@@ -313,9 +505,31 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
         GGUFMetadataReader { buf, version }
     }
 
-    pub fn read_value(&mut self) -> Result<GGUFMetadataValue<'a>> {
-        let typ = self.read_u32()?;
-        let typ = GGUFMetadataValueType::try_from(typ)?;
+    /// read a key-value pair of the metadata, the failure is reported with the key and the
+    /// offset where the pair starts.
+    pub fn read_kv(&mut self) -> std::result::Result<(&'a str, GGUFMetadataValue<'a>), GGUFError> {
+        let offset = self.buf.read_bytes();
+        let key = self
+            .read_string()
+            .map_err(|err| GGUFError::InvalidMetadata {
+                offset,
+                key: None,
+                reason: "failed to decode the key".to_string(),
+                source: Some(Box::new(err)),
+            })?;
+        let value = self
+            .read_value()
+            .map_err(|err| GGUFError::InvalidMetadata {
+                offset,
+                key: Some(key.to_string()),
+                reason: "failed to decode the value".to_string(),
+                source: Some(Box::new(err)),
+            })?;
+        Ok((key, value))
+    }
+
+    pub fn read_value(&mut self) -> std::result::Result<GGUFMetadataValue<'a>, GGUFError> {
+        let typ = self.read_value_type()?;
         let v = match typ {
             GGUFMetadataValueType::U8 => GGUFMetadataValue::U8(self.read_u8()?),
             GGUFMetadataValueType::I8 => GGUFMetadataValue::I8(self.read_i8()?),
@@ -334,9 +548,8 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
         Ok(v)
     }
 
-    pub fn read_array(&mut self) -> Result<GGUFMetadataArray<'a>> {
-        let typ = self.read_u32()?;
-        let typ = GGUFMetadataValueType::try_from(typ)?;
+    pub fn read_array(&mut self) -> std::result::Result<GGUFMetadataArray<'a>, GGUFError> {
+        let typ = self.read_value_type()?;
         let len = self.read_len()?;
         let arr = match typ {
             GGUFMetadataValueType::U8 => GGUFMetadataArray::U8Array(self.read_u8_array(len)?),
@@ -351,14 +564,14 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
             GGUFMetadataValueType::I64 => GGUFMetadataArray::I64Array(self.read_i64_array(len)?),
             GGUFMetadataValueType::Bool => GGUFMetadataArray::BoolArray(self.read_u8_array(len)?),
             GGUFMetadataValueType::String => {
-                let mut v = Vec::with_capacity(len);
+                let mut v = Vec::with_capacity(len.min(self.buf.cursor().len()));
                 for _ in 0..len {
                     v.push(self.read_string()?);
                 }
                 GGUFMetadataArray::StringArray(v)
             }
             GGUFMetadataValueType::Array => {
-                let mut v = Vec::with_capacity(len);
+                let mut v = Vec::with_capacity(len.min(self.buf.cursor().len()));
                 for _ in 0..len {
                     v.push(self.read_array()?);
                 }
@@ -379,38 +592,41 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
     define_gguf_metadata_value_read_fn!(read_f32_array, read_f32, f32);
     define_gguf_metadata_value_read_fn!(read_f64_array, read_f64, f64);
 
-    pub fn read_string(&mut self) -> Result<&'a str> {
+    fn read_value_type(&mut self) -> std::result::Result<GGUFMetadataValueType, GGUFError> {
+        let offset = self.buf.read_bytes();
+        let typ = self.read_u32()?;
+        GGUFMetadataValueType::from_int(typ)
+            .map_err(|_| GGUFError::InvalidValueType { offset, typ })
+    }
+
+    pub fn read_string(&mut self) -> std::result::Result<&'a str, GGUFError> {
+        let offset = self.buf.read_bytes();
         let len = self.read_len()?;
         let buf = self.buf.read(len)?;
-        let s = std::str::from_utf8(buf).map_err(|e| Error {
-            kind: ErrorKind::FormatError,
-            message: "Invalid UTF-8 string".to_string(),
-            cause: Some(Box::new(e)),
-        });
-        s
+        std::str::from_utf8(buf).map_err(|source| GGUFError::InvalidString { offset, source })
     }
 
     /// Read the length for string & array. It would be an 32 bit unsigned integer on spec v1, but 64
-    /// bit on spec v2. For more infomation:
+    /// bit since spec v2. For more infomation:
     /// https://github.com/philpax/ggml/commit/b021b2577d4294800ece200c9f26c9c65b0f6f51
-    fn read_len(&mut self) -> Result<usize> {
+    fn read_len(&mut self) -> std::result::Result<usize, GGUFError> {
         let v = match self.version {
             GGUFVersion::V1 => self.read_u32()? as usize,
-            GGUFVersion::V2 => self.read_u64()? as usize,
+            GGUFVersion::V2 | GGUFVersion::V3 => self.read_u64()? as usize,
         };
         Ok(v)
     }
 
     /// compat v1 & v2 on the type change of the field dimensions[n]. for more infomation:
     /// https://github.com/philpax/ggml/commit/b021b2577d4294800ece200c9f26c9c65b0f6f51#diff-d553f5c3bea777978686f7fd4ed40a185a2d8cdec90cba5e2d8a4d5504148505L154
-    fn read_len_array(&mut self, n: usize) -> Result<Vec<usize>> {
+    fn read_len_array(&mut self, n: usize) -> std::result::Result<Vec<usize>, GGUFError> {
         let v = match self.version {
             GGUFVersion::V1 => self
                 .read_u32_array(n)?
                 .iter()
                 .map(|v| *v as usize)
                 .collect(),
-            GGUFVersion::V2 => self
+            GGUFVersion::V2 | GGUFVersion::V3 => self
                 .read_u64_array(n)?
                 .iter()
                 .map(|v| *v as usize)
@@ -422,6 +638,8 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
 
 pub struct GGUFMetadata<'a> {
     metadata_kv: HashMap<String, GGUFMetadataValue<'a>>,
+    // the keys in the order of the file
+    keys: Vec<String>,
 }
 
 macro_rules! define_gguf_metadata_get_primitive_fn {
@@ -453,6 +671,17 @@ impl<'a> GGUFMetadata<'a> {
         &self.metadata_kv
     }
 
+    /// the kvs in the order of the file, to write them back in the same order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &GGUFMetadataValue<'a>)> {
+        self.keys
+            .iter()
+            .map(|key| (key.as_str(), &self.metadata_kv[key]))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GGUFMetadataValue<'a>> {
+        self.metadata_kv.get(key)
+    }
+
     define_gguf_metadata_get_primitive_fn!(get_u8, get_u8_array, u8, U8, U8Array);
     define_gguf_metadata_get_primitive_fn!(get_i8, get_i8_array, i8, I8, I8Array);
     define_gguf_metadata_get_primitive_fn!(get_u16, get_u16_array, u16, U16, U16Array);
@@ -462,6 +691,7 @@ impl<'a> GGUFMetadata<'a> {
     define_gguf_metadata_get_primitive_fn!(get_u64, get_u64_array, u64, U64, U64Array);
     define_gguf_metadata_get_primitive_fn!(get_i64, get_i64_array, i64, I64, I64Array);
     define_gguf_metadata_get_primitive_fn!(get_f32, get_f32_array, f32, F32, F32Array);
+    define_gguf_metadata_get_primitive_fn!(get_f64, get_f64_array, f64, F64, F64Array);
     define_gguf_metadata_get_primitive_fn!(get_bool, get_bool_array, u8, Bool, BoolArray);
 
     pub fn get_string(&self, key: &str) -> Option<&str> {
@@ -485,7 +715,25 @@ impl<'a> GGUFMetadata<'a> {
     }
 }
 
-struct GGUFHeader<'a> {
+macro_rules! define_gguf_header_get_primitive_fn {
+    ($get_item_func:ident, $get_array_func:ident, $typ:ty) => {
+        pub fn $get_item_func(&self, key: &str) -> Result<$typ> {
+            let key = self.expand_key(key);
+            self.metadata
+                .$get_item_func(&key)
+                .ok_or_else(|| self.missing_key_error(&key, stringify!($typ)))
+        }
+
+        pub fn $get_array_func(&self, key: &str) -> Result<&[$typ]> {
+            let key = self.expand_key(key);
+            self.metadata
+                .$get_array_func(&key)
+                .ok_or_else(|| self.missing_key_error(&key, concat!("[", stringify!($typ), "]")))
+        }
+    };
+}
+
+pub struct GGUFHeader<'a> {
     // Magic number to announce that this is a GGUF file.
     // Must be `GGUF` at the byte level: `0x47` `0x47` `0x55` `0x46`.
     // Your executor might do little-endian byte order, so it might be
@@ -495,7 +743,8 @@ struct GGUFHeader<'a> {
     magic: u32,
 
     // The version of the format implemented.
-    // Must be `2` for version described in this spec.
+    // Must be `3` for version described in this spec, which introduces big-endian
+    // support.
     //
     // This version should only be increased for structural changes to the format.
     // Changes that do not affect the structure of the file should instead update the metadata
@@ -515,26 +764,16 @@ struct GGUFHeader<'a> {
 }
 
 impl<'a> GGUFHeader<'a> {
-    fn decode(buf: &mut GGUFBufReader<'a>) -> Result<Self> {
+    fn decode(buf: &mut GGUFBufReader<'a>) -> std::result::Result<Self, GGUFError> {
         let mut r = GGUFMetadataReader::new(buf, GGUFVersion::V2);
         let magic = r.read_u32()?;
         if magic != GGUF_MAGIC {
-            return Err(Error {
-                kind: ErrorKind::FormatError,
-                message: format!("Invalid magic number: {}", magic),
-                cause: None,
-            });
+            return Err(GGUFError::InvalidMagic { magic });
         }
 
         let version = r.read_u32()?;
-        let version = GGUFVersion::from_int(version).map_err(|err| Error {
-            kind: ErrorKind::FormatError,
-            message: format!(
-                "Unsupported version number: {}, only 1, 2 is supported yet",
-                version
-            ),
-            cause: Some(Box::new(err)),
-        })?;
+        let version = GGUFVersion::from_int(version)
+            .map_err(|_| GGUFError::UnsupportedVersion { version })?;
         r.version = version;
 
         let tensor_count = r.read_len()?;
@@ -542,21 +781,25 @@ impl<'a> GGUFHeader<'a> {
 
         // load metadata
         let mut metadata_kv = HashMap::new();
+        let mut keys = vec![];
         for _ in 0..metadata_kv_count {
-            let key = r.read_string()?;
-            let value = r.read_value()?;
-            metadata_kv.insert(key.to_string(), value);
+            let (key, value) = r.read_kv()?;
+            if metadata_kv.insert(key.to_string(), value).is_none() {
+                keys.push(key.to_string());
+            }
         }
-        let metadata = GGUFMetadata { metadata_kv };
+        let metadata = GGUFMetadata { metadata_kv, keys };
 
-        // load the required fields
+        // load the required fields, the shards except the first one of a split model may
+        // contain no metadata besides the split keys.
         let architecture = match metadata.get_string(KEY_GENERAL_ARCHITECTURE) {
             Some(s) => s.to_string(),
+            None if split::get_split_value(&metadata, KEY_SPLIT_NO).unwrap_or(0) > 0 => {
+                String::new()
+            }
             _ => {
-                return Err(Error {
-                    kind: ErrorKind::FormatError,
-                    message: "Missing string metadata general.architecture".to_string(),
-                    cause: None,
+                return Err(GGUFError::MissingKey {
+                    key: KEY_GENERAL_ARCHITECTURE.to_string(),
                 });
             }
         };
@@ -570,6 +813,72 @@ impl<'a> GGUFHeader<'a> {
         })
     }
 
+    pub fn version(&self) -> GGUFVersion {
+        self.version
+    }
+
+    pub fn tensor_count(&self) -> usize {
+        self.tensor_count
+    }
+
+    pub fn metadata(&self) -> &GGUFMetadata<'a> {
+        &self.metadata
+    }
+
+    /// replaces the `{arch}` placeholder in keys like `KEY_CONTEXT_LENGTH` with the architecture
+    /// of this file, keys without the placeholder are returned as is.
+    pub fn expand_key(&self, key: &str) -> String {
+        key.replace("{arch}", &self.architecture)
+    }
+
+    define_gguf_header_get_primitive_fn!(get_u8, get_u8_array, u8);
+    define_gguf_header_get_primitive_fn!(get_i8, get_i8_array, i8);
+    define_gguf_header_get_primitive_fn!(get_u16, get_u16_array, u16);
+    define_gguf_header_get_primitive_fn!(get_i16, get_i16_array, i16);
+    define_gguf_header_get_primitive_fn!(get_u32, get_u32_array, u32);
+    define_gguf_header_get_primitive_fn!(get_i32, get_i32_array, i32);
+    define_gguf_header_get_primitive_fn!(get_u64, get_u64_array, u64);
+    define_gguf_header_get_primitive_fn!(get_i64, get_i64_array, i64);
+    define_gguf_header_get_primitive_fn!(get_f32, get_f32_array, f32);
+    define_gguf_header_get_primitive_fn!(get_f64, get_f64_array, f64);
+
+    pub fn get_bool(&self, key: &str) -> Result<bool> {
+        let key = self.expand_key(key);
+        self.metadata
+            .get_bool(&key)
+            .map(|v| v != 0)
+            .ok_or_else(|| self.missing_key_error(&key, "bool"))
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<&str> {
+        let key = self.expand_key(key);
+        self.metadata
+            .get_string(&key)
+            .ok_or_else(|| self.missing_key_error(&key, "string"))
+    }
+
+    pub fn get_str_array(&self, key: &str) -> Result<&[&str]> {
+        let key = self.expand_key(key);
+        self.metadata
+            .get_string_array(&key)
+            .ok_or_else(|| self.missing_key_error(&key, "[string]"))
+    }
+
+    fn missing_key_error(&self, key: &str, typ: &str) -> Error {
+        let err = match self.metadata.get(key) {
+            Some(v) => GGUFError::MetadataTypeMismatch {
+                offset: None,
+                key: key.to_string(),
+                expected: typ.to_string(),
+                actual: format!("{:?}", v.typ()),
+            },
+            None => GGUFError::MissingKey {
+                key: key.to_string(),
+            },
+        };
+        err.into()
+    }
+
     /// the global alignment to use, as described above. This can vary to allow for different alignment schemes,
     /// but it must be a multiple of 8. Some writers may not write the alignment. If the alignment is not specified,
     /// assume it is 32.
@@ -627,21 +936,54 @@ struct GGUFOnDiskTensorInfo {
     // file to make it easier to read the data.
     // Must be a multiple of `ALIGNMENT`.
     offset: u64,
+    // The position in the file where this tensor info starts, to locate the problems.
+    info_offset: usize,
 }
 
 impl GGUFOnDiskTensorInfo {
-    pub fn decode(buf: &mut GGUFBufReader, version: GGUFVersion) -> Result<Self> {
+    pub fn decode(
+        buf: &mut GGUFBufReader,
+        version: GGUFVersion,
+    ) -> std::result::Result<Self, GGUFError> {
+        let info_offset = buf.read_bytes();
         let mut r = GGUFMetadataReader::new(buf, version);
-        let name = r.read_string()?.to_string();
-        let n_dimensions = r.read_u32()? as usize;
-        let dimensions = r.read_len_array(n_dimensions)?;
-        let typ = GGMLType::try_from(r.read_u32()?)?;
-        let offset = r.read_u64()?;
+        let name = r
+            .read_string()
+            .map_err(|err| GGUFError::InvalidTensorInfo {
+                offset: info_offset,
+                tensor: None,
+                reason: "failed to decode the name".to_string(),
+                source: Some(Box::new(err)),
+            })?
+            .to_string();
+        let invalid = |reason: &str, err: Option<GGUFError>| GGUFError::InvalidTensorInfo {
+            offset: info_offset,
+            tensor: Some(name.clone()),
+            reason: reason.to_string(),
+            source: err.map(Box::new),
+        };
+
+        let n_dimensions = r
+            .read_u32()
+            .map_err(|err| invalid("failed to decode the dimensions", Some(err)))?
+            as usize;
+        let dimensions = r
+            .read_len_array(n_dimensions)
+            .map_err(|err| invalid("failed to decode the dimensions", Some(err)))?;
+        let typ = r
+            .read_u32()
+            .map_err(|err| invalid("failed to decode the type", Some(err)))?;
+        let typ = GGMLType::from_int(typ)
+            .map_err(|_| invalid(&format!("unknown ggml type {}", typ), None))?;
+        let offset = r
+            .read_u64()
+            .map_err(|err| invalid("failed to decode the data offset", Some(err)))?;
         Ok(Self {
             name,
             dimensions,
             typ,
             offset,
+            info_offset,
         })
     }
 }
@@ -704,7 +1046,7 @@ pub struct GGUFFile<'a> {
     // Each tensor's data must be stored within this array, and located through its `tensor_infos` entry.
     // The offset of each tensor's data must be a multiple of `ALIGNMENT`, and the space between tensors
     // should be padded to `ALIGNMENT` bytes.
-    _tensor_data: &'a [u8],
+    tensor_data: &'a [u8],
 }
 
 impl<'a> GGUFFile<'a> {
@@ -712,41 +1054,90 @@ impl<'a> GGUFFile<'a> {
         let header = GGUFHeader::decode(buf)?;
 
         // load on disk tensor infos
-        let mut on_disk_tensor_infos = Vec::with_capacity(header.tensor_count);
+        let mut on_disk_tensor_infos =
+            Vec::with_capacity(header.tensor_count.min(buf.cursor().len()));
         for _ in 0..header.tensor_count {
             let tensor_info = GGUFOnDiskTensorInfo::decode(buf, header.version)?;
             on_disk_tensor_infos.push(tensor_info);
         }
 
-        // find the tensor_data position
+        // find the tensor_data position, it's padded to the alignment
         let position = buf.read_bytes();
         let alignment = header.alignment() as usize;
-        let next_position = position - (position % alignment) + alignment;
+        let next_position = position.div_ceil(alignment) * alignment;
         let _ = buf.read(next_position - position)?;
         let tensor_data = buf.cursor();
 
         // convert the on-disk tensor infos to in-memory
-        let tensor_infos = Self::convert_tensor_infos(&on_disk_tensor_infos, tensor_data)?;
+        let tensor_infos = Self::convert_tensor_infos(
+            &on_disk_tensor_infos,
+            next_position,
+            tensor_data,
+            alignment,
+        )?;
 
         Ok(Self {
             header,
             tensor_infos,
-            _tensor_data: tensor_data,
+            tensor_data,
         })
     }
 
+    /// slice the tensor data of each tensor out of the mmaped `tensor_data` without copying. the
+    /// slice covers exactly the bytes of the tensor, the padding between tensors is excluded.
+    /// `data_start` is the position of the tensor data in the file, to report the problems.
     fn convert_tensor_infos(
         tensor_infos: &[GGUFOnDiskTensorInfo],
+        data_start: usize,
         tensor_data: &'a [u8],
+        alignment: usize,
     ) -> Result<Vec<GGUFTensorInfo<'a>>> {
         let mut result = Vec::with_capacity(tensor_infos.len());
-        for (i, tensor_info) in tensor_infos.iter().enumerate() {
-            let next_offset = if i + 1 >= tensor_infos.len() {
-                tensor_data.len()
-            } else {
-                tensor_infos[i + 1].offset as usize
+        for tensor_info in tensor_infos.iter() {
+            let invalid = |reason: String| GGUFError::InvalidTensorInfo {
+                offset: tensor_info.info_offset,
+                tensor: Some(tensor_info.name.clone()),
+                reason,
+                source: None,
             };
-            let data = &tensor_data[tensor_info.offset as usize..next_offset];
+            let offset = usize::try_from(tensor_info.offset).map_err(|_| {
+                invalid(format!("the data offset {} overflows", tensor_info.offset))
+            })?;
+            if offset % alignment != 0 {
+                return Err(GGUFError::MisalignedTensor {
+                    offset: tensor_info.info_offset,
+                    tensor: tensor_info.name.clone(),
+                    data_offset: offset,
+                    alignment,
+                }
+                .into());
+            }
+            let n_elems = tensor_info
+                .dimensions
+                .iter()
+                .try_fold(1_usize, |acc, d| acc.checked_mul(*d))
+                .ok_or_else(|| invalid("the dimensions overflow".to_string()))?;
+            let size = tensor_info
+                .typ
+                .bytes_of(n_elems)
+                .map_err(|err| invalid(err.message))?;
+            let end = offset.checked_add(size).ok_or_else(|| {
+                invalid(format!(
+                    "the data offset {} with the size {} overflows",
+                    offset, size
+                ))
+            })?;
+            if end > tensor_data.len() {
+                return Err(GGUFError::TensorOutOfBounds {
+                    offset: tensor_info.info_offset,
+                    tensor: tensor_info.name.clone(),
+                    data_position: data_start.saturating_add(offset),
+                    size,
+                    file_size: data_start + tensor_data.len(),
+                }
+                .into());
+            }
+            let data = &tensor_data[offset..end];
 
             let item = GGUFTensorInfo::new(
                 tensor_info.name.clone(),
@@ -759,6 +1150,10 @@ impl<'a> GGUFFile<'a> {
         Ok(result)
     }
 
+    pub fn header(&self) -> &GGUFHeader<'a> {
+        &self.header
+    }
+
     pub fn architecture(&self) -> &str {
         self.header.architecture()
     }
@@ -779,6 +1174,12 @@ impl<'a> GGUFFile<'a> {
         &self.tensor_infos
     }
 
+    /// the bytes from the start of the tensor data to the end of the file, the data of each
+    /// tensor is sliced out of it by its offset.
+    pub fn tensor_data(&self) -> &'a [u8] {
+        self.tensor_data
+    }
+
     pub fn get_tensor_info(&self, name: &str) -> Option<GGUFTensorInfo> {
         self.tensor_infos
             .iter()
@@ -787,32 +1188,93 @@ impl<'a> GGUFFile<'a> {
     }
 }
 
+/// GGUFFileLoader mmaps the model file, the tensors in the opened GGUFFile are zero-copy slices
+/// of the mapped memory, so the weights are only paged in when they're accessed. on the targets
+/// without mmap like wasm32, the file is read into the memory, or taken from the bytes fetched
+/// by the caller with `from_bytes` or `from_range_reader`.
+///
+/// If the file is a shard of a split model like `model-00001-of-00003.gguf`, all the shards
+/// will be mapped, and the opened GGUFFile presents the tensors of all the shards.
 pub struct GGUFFileLoader {
-    mmap: memmap2::Mmap,
+    mmaps: Vec<FileBuf>,
 }
 
 impl GGUFFileLoader {
     pub fn new(path: &str) -> Result<Self> {
-        let file = File::open(path).map_err(|err| Error {
-            kind: ErrorKind::IOError,
-            message: format!("failed to open the file: {}", path),
-            cause: Some(Box::new(err)),
-        })?;
+        Self::new_with_options(path, FileBufOptions::default())
+    }
 
-        let mmap = unsafe {
-            Mmap::map(&file).map_err(|err| Error {
-                kind: ErrorKind::IOError,
-                message: format!("failed to mmap file: {}", path),
-                cause: Some(Box::new(err)),
-            })?
+    /// open the file and its splits with the options of mmap, mlock and prefetch.
+    pub fn new_with_options(path: &str, opts: FileBufOptions) -> Result<Self> {
+        let mmap = FileBuf::open_with_options(path, opts)?;
+        let split_count = {
+            let header = GGUFHeader::decode(&mut GGUFBufReader::new(&mmap[..]))?;
+            split::get_split_value(header.metadata(), KEY_SPLIT_COUNT).unwrap_or(0)
         };
+        if split_count <= 1 {
+            return Ok(Self { mmaps: vec![mmap] });
+        }
+
+        let prefix = split::split_prefix(path).ok_or_else(|| Error {
+            kind: ErrorKind::BadInput,
+            message: format!(
+                "the file {} is a split of {} files, but not named like <prefix>-00001-of-{:05}.gguf",
+                path, split_count, split_count
+            ),
+            cause: None,
+        })?;
+        let mut mmaps = Vec::with_capacity(split_count);
+        for split_no in 0..split_count {
+            mmaps.push(FileBuf::open_with_options(
+                &split::split_path(prefix, split_no, split_count),
+                opts,
+            )?);
+        }
+        Ok(Self { mmaps })
+    }
 
-        Ok(Self { mmap })
+    /// load the model from the bytes of the file, or of each shard of a split model in order.
+    pub fn from_bytes(splits: &[&[u8]]) -> Result<Self> {
+        if splits.is_empty() {
+            return Err((ErrorKind::BadInput, "no GGUF file is given").into());
+        }
+        Ok(Self {
+            mmaps: splits.iter().map(|b| FileBuf::from_bytes(b)).collect(),
+        })
+    }
+
+    /// load the file written by the writer from the memory, without writing it into a temp file.
+    pub fn from_writer(w: &GGUFWriter) -> Result<Self> {
+        let mut buf = vec![];
+        w.write(&mut buf)?;
+        Self::from_bytes(&[&buf])
+    }
+
+    /// load the model by reading the file in the ranges of `chunk_bytes`, like the http range
+    /// requests of the file on the server.
+    pub fn from_range_reader(reader: &mut impl RangeReader, chunk_bytes: usize) -> Result<Self> {
+        Ok(Self {
+            mmaps: vec![FileBuf::from_range_reader(reader, chunk_bytes)?],
+        })
+    }
+
+    /// validate the mapped file, or each shard of a split model. see `validate()` for details.
+    pub fn validate(&self) -> Vec<GGUFError> {
+        self.mmaps.iter().flat_map(|mmap| validate(mmap)).collect()
     }
 
     pub fn open(&self) -> Result<GGUFFile<'_>> {
-        let buf = &mut GGUFBufReader::new(&self.mmap[..]);
-        GGUFFile::decode(buf)
+        if self.mmaps.len() == 1 {
+            let buf = &mut GGUFBufReader::new(&self.mmaps[0][..]);
+            return GGUFFile::decode(buf);
+        }
+
+        let splits = self
+            .mmaps
+            .iter()
+            .map(|mmap| GGUFFile::decode(&mut GGUFBufReader::new(&mmap[..])))
+            .collect::<Result<Vec<_>>>()?;
+        GGUFFile::merge_splits(splits)
     }
 }
 
@@ -897,12 +1359,190 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_load_from_bytes() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let bytes = std::fs::read(path).unwrap();
+        let loader = GGUFFileLoader::new(path)?;
+        let gf = loader.open()?;
+
+        let loaders = [
+            GGUFFileLoader::from_bytes(&[&bytes])?,
+            GGUFFileLoader::from_range_reader(&mut &bytes[..], 4096)?,
+        ];
+        for loader in loaders.iter() {
+            let gf2 = loader.open()?;
+            assert_eq!(gf2.tensor_infos().len(), gf.tensor_infos().len());
+            for (a, b) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+                assert_eq!(a.name(), b.name());
+                assert_eq!(a.data(), b.data());
+            }
+        }
+
+        assert!(GGUFFileLoader::from_bytes(&[]).is_err());
+        assert!(
+            GGUFFileLoader::from_bytes(&[&bytes[..100]])?
+                .open()
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_tensor_infos_overflow() {
+        let info = |dimensions: Vec<usize>, offset: u64| GGUFOnDiskTensorInfo {
+            name: "blk.0.attn_q.weight".to_string(),
+            dimensions,
+            typ: GGMLType::F32,
+            offset,
+            info_offset: 48,
+        };
+        let data = [0_u8; 64];
+
+        let infos = [info(vec![4, 4], 0)];
+        let result = GGUFFile::convert_tensor_infos(&infos, 0, &data, 32).unwrap();
+        assert_eq!(result[0].data().len(), 64);
+
+        let infos = [info(vec![16], u64::MAX & !31)];
+        let err = GGUFFile::convert_tensor_infos(&infos, 0, &data, 32)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert_eq!(
+            err.to_string(),
+            "FormatError: invalid tensor blk.0.attn_q.weight at offset 48: the data offset 18446744073709551584 with the size 64 overflows"
+        );
+
+        let infos = [info(vec![usize::MAX, 2], 0)];
+        let err = GGUFFile::convert_tensor_infos(&infos, 0, &data, 32)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "FormatError: invalid tensor blk.0.attn_q.weight at offset 48: the dimensions overflow"
+        );
+    }
+
+    #[test]
+    fn test_decode_header_versions() -> Result<()> {
+        fn header_bytes(version: [u8; 4]) -> Vec<u8> {
+            let mut buf = vec![];
+            buf.extend_from_slice(&GGUF_MAGIC.to_le_bytes());
+            buf.extend_from_slice(&version);
+            buf.extend_from_slice(&0_u64.to_le_bytes());
+            buf.extend_from_slice(&1_u64.to_le_bytes());
+            buf.extend_from_slice(&(KEY_GENERAL_ARCHITECTURE.len() as u64).to_le_bytes());
+            buf.extend_from_slice(KEY_GENERAL_ARCHITECTURE.as_bytes());
+            buf.extend_from_slice(&(GGUFMetadataValueType::String as u32).to_le_bytes());
+            buf.extend_from_slice(&5_u64.to_le_bytes());
+            buf.extend_from_slice(b"llama");
+            buf
+        }
+
+        let buf = header_bytes(3_u32.to_le_bytes());
+        let header = GGUFHeader::decode(&mut GGUFBufReader::new(&buf))?;
+        assert_eq!(header.version().to_string(), "3");
+        assert_eq!(header.tensor_count(), 0);
+        assert_eq!(header.architecture(), "llama");
+
+        let buf = header_bytes(3_u32.to_be_bytes());
+        let err = GGUFHeader::decode(&mut GGUFBufReader::new(&buf))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), GGUFErrorKind::UnsupportedVersion);
+        assert!(err.to_string().contains("big-endian"));
+
+        let buf = header_bytes(4_u32.to_le_bytes());
+        assert!(GGUFHeader::decode(&mut GGUFBufReader::new(&buf)).is_err());
+
+        // the value of general.architecture is cut in the middle
+        let buf = header_bytes(3_u32.to_le_bytes());
+        let err: Error = GGUFHeader::decode(&mut GGUFBufReader::new(&buf[..buf.len() - 2]))
+            .err()
+            .unwrap()
+            .into();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert_eq!(
+            err.to_string(),
+            "FormatError: invalid metadata general.architecture at offset 24: failed to decode the value\ncaused by: failed to read 5 bytes at offset 64, only 3 bytes left, the file may be truncated"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_value() -> Result<()> {
+        fn push_str(buf: &mut Vec<u8>, s: &str) {
+            buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+        }
+
+        let mut buf = vec![];
+        buf.extend_from_slice(&(GGUFMetadataValueType::U8 as u32).to_le_bytes());
+        buf.push(7);
+        buf.extend_from_slice(&(GGUFMetadataValueType::I16 as u32).to_le_bytes());
+        buf.extend_from_slice(&(-3_i16).to_le_bytes());
+        buf.extend_from_slice(&(GGUFMetadataValueType::F64 as u32).to_le_bytes());
+        buf.extend_from_slice(&1.5_f64.to_le_bytes());
+        buf.extend_from_slice(&(GGUFMetadataValueType::Bool as u32).to_le_bytes());
+        buf.push(1);
+        buf.extend_from_slice(&(GGUFMetadataValueType::String as u32).to_le_bytes());
+        push_str(&mut buf, "crab");
+        // an array of two string arrays
+        buf.extend_from_slice(&(GGUFMetadataValueType::Array as u32).to_le_bytes());
+        buf.extend_from_slice(&(GGUFMetadataValueType::Array as u32).to_le_bytes());
+        buf.extend_from_slice(&2_u64.to_le_bytes());
+        for strs in [vec!["a", "b"], vec!["c"]] {
+            buf.extend_from_slice(&(GGUFMetadataValueType::String as u32).to_le_bytes());
+            buf.extend_from_slice(&(strs.len() as u64).to_le_bytes());
+            for s in strs {
+                push_str(&mut buf, s);
+            }
+        }
+
+        let mut br = GGUFBufReader::new(&buf);
+        let mut r = GGUFMetadataReader::new(&mut br, GGUFVersion::V2);
+        assert_eq!(r.read_value()?, GGUFMetadataValue::U8(7));
+        assert_eq!(r.read_value()?, GGUFMetadataValue::I16(-3));
+        assert_eq!(r.read_value()?, GGUFMetadataValue::F64(1.5));
+        assert_eq!(r.read_value()?, GGUFMetadataValue::Bool(1));
+        assert_eq!(r.read_value()?, GGUFMetadataValue::String("crab"));
+        assert_eq!(
+            r.read_value()?,
+            GGUFMetadataValue::Array(GGUFMetadataArray::NestedArray(vec![
+                GGUFMetadataArray::StringArray(vec!["a", "b"]),
+                GGUFMetadataArray::StringArray(vec!["c"]),
+            ]))
+        );
+        assert!(r.read_value().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_q8_0_tensors() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = loader.open()?;
+        let base = loader.mmaps[0].as_ptr() as usize;
+
+        for info in gf.tensor_infos() {
+            let n_elems = info.dimensions().iter().product::<usize>();
+            let want = n_elems / info.typ().block_size() * info.typ().type_size();
+            assert_eq!(info.data().len(), want, "tensor: {}", info.name());
+            assert_eq!((info.data().as_ptr() as usize - base) % 32, 0);
+        }
+
+        let info = gf.get_tensor_info("blk.0.attn_k.weight").unwrap();
+        assert_eq!(info.typ(), GGMLType::Q8_0);
+        assert_eq!(info.data().len(), 288 * 288 / 32 * 34);
+        Ok(())
+    }
+
     #[test]
     fn test_load_metadata() -> Result<()> {
         let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
         let gf = loader.open()?;
         assert_eq!(gf.header.architecture(), "llama");
         assert_eq!(gf.header.alignment(), 32);
+        assert_eq!(gf.header().version().to_string(), "1");
 
         let mut keys = gf
             .header
@@ -966,10 +1606,40 @@ mod tests {
             ),
         ];
         for (k, v) in tests {
-            let got = gf.header.metadata.as_hashmap().get(k);
+            let got = gf.header.metadata.get(k);
             assert_eq!(v, format!("{:?}", got));
         }
+        assert_eq!(gf.metadata().get_u32("llama.block_count"), Some(5));
+        assert_eq!(gf.metadata().get_f64("llama.block_count"), None);
+
+        Ok(())
+    }
 
+    #[test]
+    fn test_header_accessors() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = loader.open()?;
+        let header = gf.header();
+
+        assert_eq!(
+            header.expand_key(KEY_CONTEXT_LENGTH),
+            "llama.context_length"
+        );
+        assert_eq!(header.expand_key(KEY_TOKENIZER_LIST), KEY_TOKENIZER_LIST);
+        assert_eq!(header.get_u32(KEY_CONTEXT_LENGTH)?, 512);
+        assert_eq!(header.get_u32("llama.block_count")?, 5);
+        assert_eq!(header.get_f32(KEY_ATTENTION_LAYERNORM_RMS_EPS)?, 1e-5);
+        assert_eq!(header.get_str(KEY_GENERAL_NAME)?, "tinyllamas-stories-260k");
+        assert_eq!(header.get_str_array(KEY_TOKENIZER_LIST)?[1], "<s>");
+        assert_eq!(header.get_f32_array(KEY_TOKENIZER_SCORES)?.len(), 512);
+
+        let err = header.get_u32(KEY_ROPE_FREQ_BASE).unwrap_err();
+        assert_eq!(err.message, "missing metadata llama.rope.freq_base");
+        let err = header.get_f32(KEY_BLOCK_COUNT).unwrap_err();
+        assert_eq!(
+            err.message,
+            "metadata llama.block_count is expected to be f32, but got U32"
+        );
         Ok(())
     }
 }
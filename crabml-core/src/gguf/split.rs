@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+
+use super::GGUFFile;
+use super::GGUFMetadata;
+use super::GGUFMetadataValue;
+use super::KEY_SPLIT_COUNT;
+use super::KEY_SPLIT_NO;
+use super::KEY_SPLIT_TENSORS_COUNT;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the path of a shard, the shards of a model are named like `model-00001-of-00003.gguf`,
+/// the `split_no` starts from 0.
+pub fn split_path(prefix: &str, split_no: usize, split_count: usize) -> String {
+    format!("{}-{:05}-of-{:05}.gguf", prefix, split_no + 1, split_count)
+}
+
+/// the prefix of a shard path, returns None if the path does not look like a shard.
+pub fn split_prefix(path: &str) -> Option<&str> {
+    // -00001-of-00003.gguf
+    const SUFFIX_LEN: usize = 20;
+    let pos = path.len().checked_sub(SUFFIX_LEN)?;
+    if !path.is_char_boundary(pos) {
+        return None;
+    }
+    let (prefix, suffix) = path.split_at(pos);
+    let suffix = suffix.as_bytes();
+    let is_digits = |s: &[u8]| s.iter().all(|c| c.is_ascii_digit());
+    if suffix[0] != b'-'
+        || !is_digits(&suffix[1..6])
+        || &suffix[6..10] != b"-of-"
+        || !is_digits(&suffix[10..15])
+        || &suffix[15..] != b".gguf"
+    {
+        return None;
+    }
+    Some(prefix)
+}
+
+/// the split keys are u16 in llama.cpp, but we'd accept any integer type here.
+pub(crate) fn get_split_value(metadata: &GGUFMetadata, key: &str) -> Option<usize> {
+    match metadata.get(key)? {
+        GGUFMetadataValue::U8(v) => Some(*v as usize),
+        GGUFMetadataValue::U16(v) => Some(*v as usize),
+        GGUFMetadataValue::U32(v) => Some(*v as usize),
+        GGUFMetadataValue::U64(v) => Some(*v as usize),
+        GGUFMetadataValue::I8(v) if *v >= 0 => Some(*v as usize),
+        GGUFMetadataValue::I16(v) if *v >= 0 => Some(*v as usize),
+        GGUFMetadataValue::I32(v) if *v >= 0 => Some(*v as usize),
+        GGUFMetadataValue::I64(v) if *v >= 0 => Some(*v as usize),
+        _ => None,
+    }
+}
+
+impl<'a> GGUFFile<'a> {
+    /// stitch the shards of a model into a single file. the metadata is taken from the first
+    /// shard, and the tensors of all the shards are presented in a single namespace.
+    pub(crate) fn merge_splits(splits: Vec<GGUFFile<'a>>) -> Result<GGUFFile<'a>> {
+        let split_count = splits.len();
+        let mut tensor_names = HashSet::new();
+        let mut tensor_infos = vec![];
+        for (split_no, split) in splits.iter().enumerate() {
+            let metadata = split.metadata();
+            let got_no = get_split_value(metadata, KEY_SPLIT_NO);
+            let got_count = get_split_value(metadata, KEY_SPLIT_COUNT);
+            if got_no != Some(split_no) || got_count != Some(split_count) {
+                return Err(Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!(
+                        "invalid split {} of {}: {}={:?}, {}={:?}",
+                        split_no, split_count, KEY_SPLIT_NO, got_no, KEY_SPLIT_COUNT, got_count
+                    ),
+                    cause: None,
+                });
+            }
+
+            for info in split.tensor_infos.iter() {
+                if !tensor_names.insert(info.name().to_string()) {
+                    return Err(Error {
+                        kind: ErrorKind::FormatError,
+                        message: format!("duplicated tensor {} in split {}", info.name(), split_no),
+                        cause: None,
+                    });
+                }
+                tensor_infos.push(info.clone());
+            }
+        }
+
+        let mut splits = splits.into_iter();
+        let first = splits.next().ok_or_else(|| Error {
+            kind: ErrorKind::BadInput,
+            message: "no split to merge".to_string(),
+            cause: None,
+        })?;
+        if let Some(expected) = get_split_value(first.metadata(), KEY_SPLIT_TENSORS_COUNT) {
+            if expected != tensor_infos.len() {
+                return Err(Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!(
+                        "expected {} tensors in the splits, but got {}",
+                        expected,
+                        tensor_infos.len()
+                    ),
+                    cause: None,
+                });
+            }
+        }
+
+        Ok(GGUFFile {
+            header: first.header,
+            tensor_infos,
+            tensor_data: first.tensor_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileLoader;
+    use crate::gguf::GGUFWriter;
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(split_path("/a/model", 0, 3), "/a/model-00001-of-00003.gguf");
+        assert_eq!(
+            split_prefix("/a/model-00002-of-00003.gguf"),
+            Some("/a/model")
+        );
+        assert_eq!(split_prefix("/a/model.gguf"), None);
+        assert_eq!(split_prefix("/a/model-0002-of-00003.gguf"), None);
+        assert_eq!(split_prefix(".gguf"), None);
+        assert_eq!(split_prefix("模型-00002-of-0003.gguf"), None);
+    }
+
+    #[test]
+    fn test_load_splits() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+
+        // split the tensors into 3 shards, only the first shard contains the metadata
+        let dir = std::env::temp_dir().join(format!("crabml-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("260k").to_str().unwrap().to_string();
+        let chunks = gf
+            .tensor_infos()
+            .chunks(gf.tensor_infos().len().div_ceil(3));
+        let n_tensors = gf.tensor_infos().len() as i32;
+        for (split_no, chunk) in chunks.enumerate() {
+            let mut w = GGUFWriter::new();
+            if split_no == 0 {
+                for (key, value) in gf.metadata().as_hashmap() {
+                    w.add_metadata(key, value.clone());
+                }
+                w.add_metadata(KEY_SPLIT_TENSORS_COUNT, GGUFMetadataValue::I32(n_tensors));
+            }
+            w.add_metadata(KEY_SPLIT_NO, GGUFMetadataValue::U16(split_no as u16));
+            w.add_metadata(KEY_SPLIT_COUNT, GGUFMetadataValue::U16(3));
+            for info in chunk {
+                w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?;
+            }
+            w.write_to_file(&split_path(&prefix, split_no, 3))?;
+        }
+
+        let gl2 = GGUFFileLoader::new(&split_path(&prefix, 1, 3))?;
+        let gf2 = gl2.open()?;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(gf2.architecture(), "llama");
+        assert_eq!(gf2.metadata().get_u32("llama.block_count"), Some(5));
+        assert_eq!(gf2.tensor_infos().len(), gf.tensor_infos().len());
+        for info in gf.tensor_infos() {
+            let info2 = gf2.get_tensor_info(info.name()).unwrap();
+            assert_eq!(info2.dimensions(), info.dimensions());
+            assert_eq!(info2.data(), info.data());
+        }
+        Ok(())
+    }
+}
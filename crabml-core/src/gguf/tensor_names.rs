@@ -0,0 +1,941 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use super::GGUFFile;
+use super::ModelArch;
+use crate::error::Error;
+
+/// ModelTensor names the tensors of a model independently of the file format, the tensors in
+/// a block are identified together with the layer index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelTensor {
+    TokenEmbd,
+    TokenEmbdNorm,
+    TokenEmbdNormBias,
+    TokenTypes,
+    PosEmbd,
+    OutputNorm,
+    OutputNormBias,
+    Output,
+    OutputBias,
+    RopeFactorsLong,
+    RopeFactorsShort,
+    AttnNorm,
+    AttnNormBias,
+    AttnNorm2,
+    AttnNorm2Bias,
+    AttnQkv,
+    AttnQkvBias,
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnQBias,
+    AttnKBias,
+    AttnVBias,
+    AttnOutput,
+    AttnOutputBias,
+    AttnOutputNorm,
+    AttnOutputNormBias,
+    FfnNorm,
+    FfnNormBias,
+    FfnGate,
+    FfnDown,
+    FfnUp,
+    FfnDownBias,
+    FfnUpBias,
+    FfnGateInp,
+    FfnGateExps,
+    FfnDownExps,
+    FfnUpExps,
+    LayerOutputNorm,
+    LayerOutputNormBias,
+    TimeMixLerpX,
+    TimeMixLerpFused,
+    TimeMixLerpW,
+    TimeMixLerpK,
+    TimeMixLerpV,
+    TimeMixLerpR,
+    TimeMixLerpG,
+    TimeMixW1,
+    TimeMixW2,
+    TimeMixDecay,
+    TimeMixDecayW1,
+    TimeMixDecayW2,
+    TimeMixFirst,
+    TimeMixReceptance,
+    TimeMixKey,
+    TimeMixValue,
+    TimeMixGate,
+    TimeMixOutput,
+    TimeMixLn,
+    TimeMixLnBias,
+    ChannelMixLerpK,
+    ChannelMixLerpR,
+    ChannelMixKey,
+    ChannelMixValue,
+    ChannelMixReceptance,
+}
+
+// (tensor, gguf name, huggingface name), `{bid}` is the placeholder of the layer index. only
+// the tensors in the layout of llama have a huggingface name.
+const TENSOR_NAMES: &[(ModelTensor, &str, Option<&str>)] = &[
+    (
+        ModelTensor::TokenEmbd,
+        "token_embd.weight",
+        Some("model.embed_tokens.weight"),
+    ),
+    (ModelTensor::TokenEmbdNorm, "token_embd_norm.weight", None),
+    (ModelTensor::TokenEmbdNormBias, "token_embd_norm.bias", None),
+    (ModelTensor::TokenTypes, "token_types.weight", None),
+    (ModelTensor::PosEmbd, "position_embd.weight", None),
+    (
+        ModelTensor::OutputNorm,
+        "output_norm.weight",
+        Some("model.norm.weight"),
+    ),
+    (ModelTensor::OutputNormBias, "output_norm.bias", None),
+    (ModelTensor::Output, "output.weight", Some("lm_head.weight")),
+    (ModelTensor::OutputBias, "output.bias", None),
+    (
+        ModelTensor::RopeFactorsLong,
+        "rope_factors_long.weight",
+        None,
+    ),
+    (
+        ModelTensor::RopeFactorsShort,
+        "rope_factors_short.weight",
+        None,
+    ),
+    (
+        ModelTensor::AttnNorm,
+        "blk.{bid}.attn_norm.weight",
+        Some("model.layers.{bid}.input_layernorm.weight"),
+    ),
+    (ModelTensor::AttnNormBias, "blk.{bid}.attn_norm.bias", None),
+    (ModelTensor::AttnNorm2, "blk.{bid}.attn_norm_2.weight", None),
+    (
+        ModelTensor::AttnNorm2Bias,
+        "blk.{bid}.attn_norm_2.bias",
+        None,
+    ),
+    (ModelTensor::AttnQkv, "blk.{bid}.attn_qkv.weight", None),
+    (ModelTensor::AttnQkvBias, "blk.{bid}.attn_qkv.bias", None),
+    (
+        ModelTensor::AttnQ,
+        "blk.{bid}.attn_q.weight",
+        Some("model.layers.{bid}.self_attn.q_proj.weight"),
+    ),
+    (
+        ModelTensor::AttnK,
+        "blk.{bid}.attn_k.weight",
+        Some("model.layers.{bid}.self_attn.k_proj.weight"),
+    ),
+    (
+        ModelTensor::AttnV,
+        "blk.{bid}.attn_v.weight",
+        Some("model.layers.{bid}.self_attn.v_proj.weight"),
+    ),
+    (
+        ModelTensor::AttnQBias,
+        "blk.{bid}.attn_q.bias",
+        Some("model.layers.{bid}.self_attn.q_proj.bias"),
+    ),
+    (
+        ModelTensor::AttnKBias,
+        "blk.{bid}.attn_k.bias",
+        Some("model.layers.{bid}.self_attn.k_proj.bias"),
+    ),
+    (
+        ModelTensor::AttnVBias,
+        "blk.{bid}.attn_v.bias",
+        Some("model.layers.{bid}.self_attn.v_proj.bias"),
+    ),
+    (
+        ModelTensor::AttnOutput,
+        "blk.{bid}.attn_output.weight",
+        Some("model.layers.{bid}.self_attn.o_proj.weight"),
+    ),
+    (
+        ModelTensor::AttnOutputBias,
+        "blk.{bid}.attn_output.bias",
+        None,
+    ),
+    (
+        ModelTensor::AttnOutputNorm,
+        "blk.{bid}.attn_output_norm.weight",
+        None,
+    ),
+    (
+        ModelTensor::AttnOutputNormBias,
+        "blk.{bid}.attn_output_norm.bias",
+        None,
+    ),
+    (
+        ModelTensor::FfnNorm,
+        "blk.{bid}.ffn_norm.weight",
+        Some("model.layers.{bid}.post_attention_layernorm.weight"),
+    ),
+    (ModelTensor::FfnNormBias, "blk.{bid}.ffn_norm.bias", None),
+    (
+        ModelTensor::FfnGate,
+        "blk.{bid}.ffn_gate.weight",
+        Some("model.layers.{bid}.mlp.gate_proj.weight"),
+    ),
+    (
+        ModelTensor::FfnDown,
+        "blk.{bid}.ffn_down.weight",
+        Some("model.layers.{bid}.mlp.down_proj.weight"),
+    ),
+    (
+        ModelTensor::FfnUp,
+        "blk.{bid}.ffn_up.weight",
+        Some("model.layers.{bid}.mlp.up_proj.weight"),
+    ),
+    (ModelTensor::FfnDownBias, "blk.{bid}.ffn_down.bias", None),
+    (ModelTensor::FfnUpBias, "blk.{bid}.ffn_up.bias", None),
+    (
+        ModelTensor::FfnGateInp,
+        "blk.{bid}.ffn_gate_inp.weight",
+        None,
+    ),
+    (
+        ModelTensor::FfnGateExps,
+        "blk.{bid}.ffn_gate_exps.weight",
+        None,
+    ),
+    (
+        ModelTensor::FfnDownExps,
+        "blk.{bid}.ffn_down_exps.weight",
+        None,
+    ),
+    (ModelTensor::FfnUpExps, "blk.{bid}.ffn_up_exps.weight", None),
+    (
+        ModelTensor::LayerOutputNorm,
+        "blk.{bid}.layer_output_norm.weight",
+        None,
+    ),
+    (
+        ModelTensor::LayerOutputNormBias,
+        "blk.{bid}.layer_output_norm.bias",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpX,
+        "blk.{bid}.time_mix_lerp_x.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpFused,
+        "blk.{bid}.time_mix_lerp_fused.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpW,
+        "blk.{bid}.time_mix_lerp_w.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpK,
+        "blk.{bid}.time_mix_lerp_k.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpV,
+        "blk.{bid}.time_mix_lerp_v.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpR,
+        "blk.{bid}.time_mix_lerp_r.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixLerpG,
+        "blk.{bid}.time_mix_lerp_g.weight",
+        None,
+    ),
+    (ModelTensor::TimeMixW1, "blk.{bid}.time_mix_w1.weight", None),
+    (ModelTensor::TimeMixW2, "blk.{bid}.time_mix_w2.weight", None),
+    (
+        ModelTensor::TimeMixDecay,
+        "blk.{bid}.time_mix_decay.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixDecayW1,
+        "blk.{bid}.time_mix_decay_w1.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixDecayW2,
+        "blk.{bid}.time_mix_decay_w2.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixFirst,
+        "blk.{bid}.time_mix_first.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixReceptance,
+        "blk.{bid}.time_mix_receptance.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixKey,
+        "blk.{bid}.time_mix_key.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixValue,
+        "blk.{bid}.time_mix_value.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixGate,
+        "blk.{bid}.time_mix_gate.weight",
+        None,
+    ),
+    (
+        ModelTensor::TimeMixOutput,
+        "blk.{bid}.time_mix_output.weight",
+        None,
+    ),
+    (ModelTensor::TimeMixLn, "blk.{bid}.time_mix_ln.weight", None),
+    (
+        ModelTensor::TimeMixLnBias,
+        "blk.{bid}.time_mix_ln.bias",
+        None,
+    ),
+    (
+        ModelTensor::ChannelMixLerpK,
+        "blk.{bid}.channel_mix_lerp_k.weight",
+        None,
+    ),
+    (
+        ModelTensor::ChannelMixLerpR,
+        "blk.{bid}.channel_mix_lerp_r.weight",
+        None,
+    ),
+    (
+        ModelTensor::ChannelMixKey,
+        "blk.{bid}.channel_mix_key.weight",
+        None,
+    ),
+    (
+        ModelTensor::ChannelMixValue,
+        "blk.{bid}.channel_mix_value.weight",
+        None,
+    ),
+    (
+        ModelTensor::ChannelMixReceptance,
+        "blk.{bid}.channel_mix_receptance.weight",
+        None,
+    ),
+];
+
+use ModelTensor::*;
+
+const LLAMA_TENSORS: &[ModelTensor] = &[
+    TokenEmbd, OutputNorm, AttnNorm, AttnQ, AttnK, AttnV, AttnOutput, FfnNorm, FfnGate, FfnDown,
+    FfnUp,
+];
+
+const QWEN2_TENSORS: &[ModelTensor] = &[
+    TokenEmbd, OutputNorm, AttnNorm, AttnQ, AttnK, AttnV, AttnQBias, AttnKBias, AttnVBias,
+    AttnOutput, FfnNorm, FfnGate, FfnDown, FfnUp,
+];
+
+const PHI2_TENSORS: &[ModelTensor] = &[
+    TokenEmbd,
+    OutputNorm,
+    OutputNormBias,
+    Output,
+    OutputBias,
+    AttnNorm,
+    AttnNormBias,
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnQBias,
+    AttnKBias,
+    AttnVBias,
+    AttnOutput,
+    AttnOutputBias,
+    FfnUp,
+    FfnUpBias,
+    FfnDown,
+    FfnDownBias,
+];
+
+const PHI3_TENSORS: &[ModelTensor] = &[
+    TokenEmbd, OutputNorm, Output, AttnNorm, AttnQ, AttnK, AttnV, AttnOutput, FfnNorm, FfnUp,
+    FfnDown,
+];
+
+const FALCON_TENSORS: &[ModelTensor] = &[
+    TokenEmbd,
+    OutputNorm,
+    OutputNormBias,
+    AttnNorm,
+    AttnNormBias,
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnOutput,
+    FfnUp,
+    FfnDown,
+];
+
+const STABLELM_TENSORS: &[ModelTensor] = &[
+    TokenEmbd,
+    OutputNorm,
+    OutputNormBias,
+    Output,
+    AttnNorm,
+    AttnNormBias,
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnOutput,
+    FfnNorm,
+    FfnNormBias,
+    FfnGate,
+    FfnDown,
+    FfnUp,
+];
+
+const MPT_TENSORS: &[ModelTensor] = &[
+    TokenEmbd, OutputNorm, AttnNorm, AttnQ, AttnK, AttnV, AttnOutput, FfnNorm, FfnUp, FfnDown,
+];
+
+const BLOOM_TENSORS: &[ModelTensor] = &[
+    TokenEmbd,
+    TokenEmbdNorm,
+    OutputNorm,
+    AttnNorm,
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnOutput,
+    FfnNorm,
+    FfnUp,
+    FfnDown,
+];
+
+const BLOOM_OPTIONAL_TENSORS: &[ModelTensor] = &[
+    Output,
+    TokenEmbdNormBias,
+    OutputNormBias,
+    AttnNormBias,
+    AttnQkv,
+    AttnQkvBias,
+    AttnQBias,
+    AttnKBias,
+    AttnVBias,
+    AttnOutputBias,
+    FfnNormBias,
+    FfnUpBias,
+    FfnDownBias,
+];
+
+const RWKV6_TENSORS: &[ModelTensor] = &[
+    TokenEmbd,
+    TokenEmbdNorm,
+    TokenEmbdNormBias,
+    OutputNorm,
+    OutputNormBias,
+    Output,
+    AttnNorm,
+    AttnNormBias,
+    AttnNorm2,
+    AttnNorm2Bias,
+    TimeMixLerpX,
+    TimeMixLerpW,
+    TimeMixLerpK,
+    TimeMixLerpV,
+    TimeMixLerpR,
+    TimeMixLerpG,
+    TimeMixW1,
+    TimeMixW2,
+    TimeMixDecay,
+    TimeMixDecayW1,
+    TimeMixDecayW2,
+    TimeMixFirst,
+    TimeMixReceptance,
+    TimeMixKey,
+    TimeMixValue,
+    TimeMixGate,
+    TimeMixOutput,
+    TimeMixLn,
+    TimeMixLnBias,
+    ChannelMixLerpK,
+    ChannelMixLerpR,
+    ChannelMixKey,
+    ChannelMixValue,
+    ChannelMixReceptance,
+];
+
+const BERT_TENSORS: &[ModelTensor] = &[
+    TokenEmbd,
+    TokenEmbdNorm,
+    TokenEmbdNormBias,
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnOutput,
+    AttnOutputNorm,
+    AttnOutputNormBias,
+    FfnUp,
+    FfnDown,
+    LayerOutputNorm,
+    LayerOutputNormBias,
+];
+
+// (arch, the tensors expected in the files, the optional tensors loaded if present)
+const ARCH_TENSORS: &[(ModelArch, &[ModelTensor], &[ModelTensor])] = &[
+    (ModelArch::Llama, LLAMA_TENSORS, &[
+        Output,
+        AttnQBias,
+        AttnKBias,
+        AttnVBias,
+        AttnOutputBias,
+    ]),
+    (ModelArch::Qwen2, QWEN2_TENSORS, &[Output]),
+    (ModelArch::Phi2, PHI2_TENSORS, &[AttnQkv, AttnQkvBias]),
+    (ModelArch::Phi3, PHI3_TENSORS, &[
+        AttnQkv,
+        FfnGate,
+        RopeFactorsLong,
+        RopeFactorsShort,
+    ]),
+    (ModelArch::Falcon, FALCON_TENSORS, &[
+        Output,
+        AttnQkv,
+        AttnNorm2,
+        AttnNorm2Bias,
+    ]),
+    (ModelArch::StableLM, STABLELM_TENSORS, &[
+        AttnQBias, AttnKBias, AttnVBias,
+    ]),
+    (ModelArch::Mpt, MPT_TENSORS, &[
+        Output,
+        OutputNormBias,
+        AttnNormBias,
+        AttnQkv,
+        AttnQkvBias,
+        AttnOutputBias,
+        FfnNormBias,
+        FfnUpBias,
+        FfnDownBias,
+    ]),
+    (ModelArch::Bloom, BLOOM_TENSORS, BLOOM_OPTIONAL_TENSORS),
+    (ModelArch::Rwkv6, RWKV6_TENSORS, &[TimeMixLerpFused]),
+    (ModelArch::Bert, BERT_TENSORS, BERT_OPTIONAL_TENSORS),
+    (ModelArch::NomicBert, BERT_TENSORS, BERT_OPTIONAL_TENSORS),
+];
+
+const BERT_OPTIONAL_TENSORS: &[ModelTensor] = &[
+    TokenTypes,
+    PosEmbd,
+    AttnQkv,
+    AttnQkvBias,
+    AttnQBias,
+    AttnKBias,
+    AttnVBias,
+    AttnOutputBias,
+    FfnGate,
+    FfnUpBias,
+    FfnDownBias,
+];
+
+// (tensor, the tensor it may be fused in), the tensor is not missing if the fused one is found
+const FUSED_TENSORS: &[(ModelTensor, ModelTensor)] = &[
+    (AttnQ, AttnQkv),
+    (AttnK, AttnQkv),
+    (AttnV, AttnQkv),
+    (AttnQBias, AttnQkvBias),
+    (AttnKBias, AttnQkvBias),
+    (AttnVBias, AttnQkvBias),
+    (TimeMixLerpW, TimeMixLerpFused),
+    (TimeMixLerpK, TimeMixLerpFused),
+    (TimeMixLerpV, TimeMixLerpFused),
+    (TimeMixLerpR, TimeMixLerpFused),
+    (TimeMixLerpG, TimeMixLerpFused),
+];
+
+// (the dense ffn tensor, the stacked tensor of the experts)
+const EXPERT_TENSORS: &[(ModelTensor, ModelTensor)] = &[
+    (FfnGate, FfnGateExps),
+    (FfnDown, FfnDownExps),
+    (FfnUp, FfnUpExps),
+];
+
+impl ModelTensor {
+    pub fn all() -> impl Iterator<Item = ModelTensor> {
+        TENSOR_NAMES.iter().map(|(t, _, _)| *t)
+    }
+
+    /// whether the tensor is repeated in each transformer block.
+    pub fn is_block(&self) -> bool {
+        self.gguf_pattern().contains("{bid}")
+    }
+
+    /// the name of the tensor in GGUF files, like `blk.0.attn_q.weight`. the layer is ignored
+    /// for the tensors not in a block.
+    pub fn gguf_name(&self, layer: usize) -> String {
+        self.gguf_pattern().replace("{bid}", &layer.to_string())
+    }
+
+    /// the name of the tensor in huggingface checkpoints, like `model.layers.0.self_attn.q_proj.weight`,
+    /// None if the tensor is not in the layout of llama.
+    pub fn hf_name(&self, layer: usize) -> Option<String> {
+        self.hf_pattern()
+            .map(|pattern| pattern.replace("{bid}", &layer.to_string()))
+    }
+
+    pub fn from_gguf_name(name: &str) -> Option<(ModelTensor, Option<usize>)> {
+        TENSOR_NAMES
+            .iter()
+            .find_map(|(t, pattern, _)| match_pattern(pattern, name).map(|layer| (*t, layer)))
+    }
+
+    pub fn from_hf_name(name: &str) -> Option<(ModelTensor, Option<usize>)> {
+        TENSOR_NAMES
+            .iter()
+            .find_map(|(t, _, pattern)| match_pattern((*pattern)?, name).map(|layer| (*t, layer)))
+    }
+
+    fn gguf_pattern(&self) -> &'static str {
+        TENSOR_NAMES.iter().find(|(t, _, _)| t == self).unwrap().1
+    }
+
+    fn hf_pattern(&self) -> Option<&'static str> {
+        TENSOR_NAMES.iter().find(|(t, _, _)| t == self).unwrap().2
+    }
+}
+
+/// returns Some(layer) if the name matches the pattern, the layer is None if the pattern has
+/// no `{bid}` placeholder.
+fn match_pattern(pattern: &str, name: &str) -> Option<Option<usize>> {
+    let (prefix, suffix) = match pattern.split_once("{bid}") {
+        None => return (pattern == name).then_some(None),
+        Some(v) => v,
+    };
+    let layer = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    if layer.is_empty() || !layer.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    layer.parse().ok().map(Some)
+}
+
+/// TensorNameMap names the tensors of a model in GGUF files by the architecture, and tells the
+/// tensors missing or unexpected in a file, which explains the failure of loading better than
+/// the first tensor not found.
+#[derive(Debug, Clone, Copy)]
+pub struct TensorNameMap {
+    arch: ModelArch,
+    n_layers: usize,
+    n_experts: usize,
+}
+
+impl TensorNameMap {
+    pub fn new(arch: ModelArch, n_layers: usize) -> Self {
+        Self {
+            arch,
+            n_layers,
+            n_experts: 0,
+        }
+    }
+
+    /// the dense ffn is replaced by the experts, like mixtral.
+    pub fn with_experts(mut self, n_experts: usize) -> Self {
+        self.n_experts = n_experts;
+        self
+    }
+
+    pub fn arch(&self) -> ModelArch {
+        self.arch
+    }
+
+    /// the name of the tensor in the layer, like `blk.0.attn_q.weight`.
+    pub fn name(&self, tensor: ModelTensor, layer: usize) -> String {
+        tensor.gguf_name(layer)
+    }
+
+    /// the name of an expert stored on its own in the older mixtral files, like
+    /// `blk.0.ffn_gate.3.weight` for `FfnGateExps`.
+    pub fn expert_name(&self, tensor: ModelTensor, layer: usize, expert: usize) -> String {
+        self.name(tensor, layer)
+            .replace("_exps.", &format!(".{}.", expert))
+    }
+
+    /// the tensors expected in the files of the architecture.
+    pub fn required(&self) -> Vec<ModelTensor> {
+        let mut tensors = self.arch_tensors().0.to_vec();
+        if self.n_experts > 0 {
+            for tensor in tensors.iter_mut() {
+                if let Some((_, exps)) = EXPERT_TENSORS.iter().find(|(t, _)| t == tensor) {
+                    *tensor = *exps;
+                }
+            }
+            tensors.push(FfnGateInp);
+        }
+        tensors
+    }
+
+    /// the tensors loaded if they're present in the files of the architecture.
+    pub fn optional(&self) -> Vec<ModelTensor> {
+        self.arch_tensors().1.to_vec()
+    }
+
+    /// compare the tensor names found in a file with the tensors of the architecture.
+    pub fn diagnose<'b>(&self, names: impl IntoIterator<Item = &'b str>) -> TensorDiagnostics {
+        let names = names.into_iter().collect::<HashSet<_>>();
+        let required = self.required();
+        let optional = self.optional();
+        let is_known =
+            |tensor: &ModelTensor| required.contains(tensor) || optional.contains(tensor);
+        let found = |tensor: ModelTensor, layer: usize| {
+            names.contains(self.name(tensor, layer).as_str())
+                || (EXPERT_TENSORS.iter().any(|(_, exps)| *exps == tensor)
+                    && names.contains(self.expert_name(tensor, layer, 0).as_str()))
+        };
+
+        let mut missing = vec![];
+        for tensor in required.iter().copied() {
+            let n_layers = if tensor.is_block() { self.n_layers } else { 1 };
+            for layer in 0..n_layers {
+                let fused = FUSED_TENSORS
+                    .iter()
+                    .any(|(t, fused)| *t == tensor && is_known(fused) && found(*fused, layer));
+                if !found(tensor, layer) && !fused {
+                    missing.push(self.name(tensor, layer));
+                }
+            }
+        }
+
+        let mut extra = names
+            .iter()
+            .filter(|name| match self.parse(name) {
+                Some((tensor, layer)) => {
+                    !is_known(&tensor) || layer.is_some_and(|layer| layer >= self.n_layers)
+                }
+                None => true,
+            })
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        extra.sort();
+        TensorDiagnostics { missing, extra }
+    }
+
+    /// attach the diagnostics of the tensors in the file to the error of loading them.
+    pub fn explain_error(&self, gf: &GGUFFile, err: Error) -> Error {
+        let diagnostics = self.diagnose(gf.tensor_infos().iter().map(|info| info.name()));
+        if diagnostics.is_empty() {
+            return err;
+        }
+        Error {
+            kind: err.kind,
+            message: format!(
+                "{}, the {} model of {} layers has {}",
+                err.message, self.arch, self.n_layers, diagnostics
+            ),
+            cause: err.cause,
+        }
+    }
+
+    fn arch_tensors(&self) -> (&'static [ModelTensor], &'static [ModelTensor]) {
+        ARCH_TENSORS
+            .iter()
+            .find(|(arch, _, _)| *arch == self.arch)
+            .map(|(_, required, optional)| (*required, *optional))
+            .unwrap()
+    }
+
+    /// like `ModelTensor::from_gguf_name`, and the experts stored on their own are taken as
+    /// the stacked tensor.
+    fn parse(&self, name: &str) -> Option<(ModelTensor, Option<usize>)> {
+        if let Some(v) = ModelTensor::from_gguf_name(name) {
+            return Some(v);
+        }
+        let (prefix, expert) = name.strip_suffix(".weight")?.rsplit_once('.')?;
+        if self.n_experts == 0 || expert.parse::<usize>().ok()? >= self.n_experts {
+            return None;
+        }
+        match ModelTensor::from_gguf_name(&format!("{}_exps.weight", prefix))? {
+            (tensor, layer) if EXPERT_TENSORS.iter().any(|(_, exps)| *exps == tensor) => {
+                Some((tensor, layer))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// the tensors missing or unexpected in a file by `TensorNameMap::diagnose`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TensorDiagnostics {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+impl TensorDiagnostics {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+impl fmt::Display for TensorDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the names are listed up to a few, a wrong architecture may miss all of them
+        let list = |names: &[String]| -> String {
+            let mut s = names.iter().take(8).cloned().collect::<Vec<_>>().join(", ");
+            if names.len() > 8 {
+                s += &format!(" and {} more", names.len() - 8);
+            }
+            s
+        };
+        let mut parts = vec![];
+        if !self.missing.is_empty() {
+            parts.push(format!("missing tensors: {}", list(&self.missing)));
+        }
+        if !self.extra.is_empty() {
+            parts.push(format!("unexpected tensors: {}", list(&self.extra)));
+        }
+        match parts.is_empty() {
+            true => write!(f, "no missing or unexpected tensors"),
+            false => write!(f, "{}", parts.join("; ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tensor_names() {
+        assert_eq!(ModelTensor::AttnQ.gguf_name(3), "blk.3.attn_q.weight");
+        assert_eq!(
+            ModelTensor::FfnDown.hf_name(12).unwrap(),
+            "model.layers.12.mlp.down_proj.weight"
+        );
+        assert_eq!(ModelTensor::Output.gguf_name(3), "output.weight");
+        assert_eq!(ModelTensor::AttnQkv.hf_name(3), None);
+        assert!(ModelTensor::AttnNorm.is_block());
+        assert!(!ModelTensor::TokenEmbd.is_block());
+
+        assert_eq!(
+            ModelTensor::from_gguf_name("blk.10.ffn_up.weight"),
+            Some((ModelTensor::FfnUp, Some(10)))
+        );
+        assert_eq!(
+            ModelTensor::from_gguf_name("blk.1.attn_norm_2.bias"),
+            Some((ModelTensor::AttnNorm2Bias, Some(1)))
+        );
+        assert_eq!(
+            ModelTensor::from_hf_name("model.norm.weight"),
+            Some((ModelTensor::OutputNorm, None))
+        );
+        assert_eq!(ModelTensor::from_gguf_name("blk.x.ffn_up.weight"), None);
+        assert_eq!(
+            ModelTensor::from_hf_name("model.layers.0.self_attn.rotary_emb.inv_freq"),
+            None
+        );
+
+        for t in ModelTensor::all() {
+            assert_eq!(
+                ModelTensor::from_gguf_name(&t.gguf_name(7)),
+                Some((t, t.is_block().then_some(7)))
+            );
+            if let Some(name) = t.hf_name(7) {
+                assert_eq!(
+                    ModelTensor::from_hf_name(&name),
+                    Some((t, t.is_block().then_some(7)))
+                );
+            }
+        }
+    }
+
+    fn all_names(map: &TensorNameMap, n_layers: usize) -> Vec<String> {
+        let mut names = vec![];
+        for tensor in map.required() {
+            match tensor.is_block() {
+                true => (0..n_layers).for_each(|layer| names.push(map.name(tensor, layer))),
+                false => names.push(map.name(tensor, 0)),
+            }
+        }
+        names
+    }
+
+    #[test]
+    fn test_tensor_name_map() {
+        for (arch, _, _) in ARCH_TENSORS {
+            let map = TensorNameMap::new(*arch, 2);
+            let names = all_names(&map, 2);
+            let diagnostics = map.diagnose(names.iter().map(|s| s.as_str()));
+            assert!(diagnostics.is_empty(), "{}: {}", arch, diagnostics);
+        }
+
+        let map = TensorNameMap::new(ModelArch::Llama, 2);
+        let mut names = all_names(&map, 3);
+        names.retain(|name| name != "blk.1.attn_k.weight" && name != "output_norm.weight");
+        names.push("blk.0.attn_qkv.weight".to_string());
+        let diagnostics = map.diagnose(names.iter().map(|s| s.as_str()));
+        assert_eq!(diagnostics.missing, vec![
+            "output_norm.weight",
+            "blk.1.attn_k.weight"
+        ]);
+        assert_eq!(diagnostics.extra.len(), 10);
+        assert!(
+            diagnostics
+                .extra
+                .contains(&"blk.0.attn_qkv.weight".to_string())
+        );
+        assert!(
+            diagnostics
+                .extra
+                .contains(&"blk.2.attn_q.weight".to_string())
+        );
+        assert_eq!(
+            diagnostics.to_string(),
+            "missing tensors: output_norm.weight, blk.1.attn_k.weight; unexpected tensors: blk.0.attn_qkv.weight, blk.2.attn_k.weight, blk.2.attn_norm.weight, blk.2.attn_output.weight, blk.2.attn_q.weight, blk.2.attn_v.weight, blk.2.ffn_down.weight, blk.2.ffn_gate.weight and 2 more"
+        );
+
+        // the q, k, v projections may be fused
+        let map = TensorNameMap::new(ModelArch::Phi3, 1);
+        let mut names = all_names(&map, 1);
+        names.retain(|name| !name.starts_with("blk.0.attn_") || name.contains("norm"));
+        names.push("blk.0.attn_output.weight".to_string());
+        assert_eq!(
+            map.diagnose(names.iter().map(|s| s.as_str())).missing,
+            vec![
+                "blk.0.attn_q.weight",
+                "blk.0.attn_k.weight",
+                "blk.0.attn_v.weight"
+            ]
+        );
+        names.push("blk.0.attn_qkv.weight".to_string());
+        let diagnostics = map.diagnose(names.iter().map(|s| s.as_str()));
+        assert!(diagnostics.is_empty(), "{}", diagnostics);
+
+        // the experts may be stacked, or stored one by one
+        let map = TensorNameMap::new(ModelArch::Llama, 1).with_experts(2);
+        assert_eq!(
+            map.expert_name(ModelTensor::FfnUpExps, 0, 1),
+            "blk.0.ffn_up.1.weight"
+        );
+        let names = all_names(&map, 1);
+        assert!(names.contains(&"blk.0.ffn_gate_exps.weight".to_string()));
+        assert!(map.diagnose(names.iter().map(|s| s.as_str())).is_empty());
+        let names = names
+            .iter()
+            .flat_map(|name| match name.strip_suffix("_exps.weight") {
+                Some(prefix) => (0..2).map(|e| format!("{}.{}.weight", prefix, e)).collect(),
+                None => vec![name.clone()],
+            })
+            .collect::<Vec<_>>();
+        let diagnostics = map.diagnose(names.iter().map(|s| s.as_str()));
+        assert!(diagnostics.is_empty(), "{}", diagnostics);
+    }
+}
@@ -0,0 +1,616 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use int_enum::IntEnum;
+
+use super::GGUFBufReader;
+use super::GGUFError;
+use super::GGUFMetadataReader;
+use super::GGUFMetadataValue;
+use super::GGUFMetadataValueType;
+use super::GGUFOnDiskTensorInfo;
+use super::GGUFVersion;
+use super::GGUF_DEFAULT_ALIGNMENT;
+use super::GGUF_MAGIC;
+use super::KEY_ATTENTION_HEAD_COUNT;
+use super::KEY_ATTENTION_HEAD_COUNT_KV;
+use super::KEY_ATTENTION_LAYERNORM_EPS;
+use super::KEY_ATTENTION_LAYERNORM_RMS_EPS;
+use super::KEY_ATTENTION_SLIDING_WINDOW;
+use super::KEY_BLOCK_COUNT;
+use super::KEY_CONTEXT_LENGTH;
+use super::KEY_EMBEDDING_LENGTH;
+use super::KEY_EXPERT_COUNT;
+use super::KEY_EXPERT_USED_COUNT;
+use super::KEY_FEED_FORWARD_LENGTH;
+use super::KEY_GENERAL_ALIGNMENT;
+use super::KEY_GENERAL_ARCHITECTURE;
+use super::KEY_GENERAL_FILE_TYPE;
+use super::KEY_GENERAL_NAME;
+use super::KEY_GENERAL_QUANTIZATION_VERSION;
+use super::KEY_POOLING_TYPE;
+use super::KEY_RESCALE_EVERY_N_LAYERS;
+use super::KEY_ROPE_DIMENSION_COUNT;
+use super::KEY_ROPE_FREQ_BASE;
+use super::KEY_ROPE_SCALE_LINEAR;
+use super::KEY_ROPE_SCALING_ATTN_FACTOR;
+use super::KEY_ROPE_SCALING_FACTOR;
+use super::KEY_ROPE_SCALING_ORIG_CTX_LEN;
+use super::KEY_ROPE_SCALING_TYPE;
+use super::KEY_SPLIT_NO;
+use super::KEY_TOKENIZER_ADD_BOS;
+use super::KEY_TOKENIZER_ADD_EOS;
+use super::KEY_TOKENIZER_BOS_ID;
+use super::KEY_TOKENIZER_CHAT_TEMPLATE;
+use super::KEY_TOKENIZER_CLS_ID;
+use super::KEY_TOKENIZER_EOS_ID;
+use super::KEY_TOKENIZER_LIST;
+use super::KEY_TOKENIZER_MERGES;
+use super::KEY_TOKENIZER_MODEL;
+use super::KEY_TOKENIZER_PAD_ID;
+use super::KEY_TOKENIZER_PRE;
+use super::KEY_TOKENIZER_SCORES;
+use super::KEY_TOKENIZER_SEP_ID;
+use super::KEY_TOKENIZER_TOKEN_TYPE;
+use super::KEY_TOKENIZER_UNK_ID;
+use super::KEY_WKV_HEAD_SIZE;
+
+enum ExpectedType {
+    Value(GGUFMetadataValueType),
+    Array(GGUFMetadataValueType),
+}
+
+// the types of the well known keys, the `{arch}` placeholder is expanded before checking
+const EXPECTED_TYPES: &[(&str, ExpectedType)] = &[
+    (
+        KEY_GENERAL_ARCHITECTURE,
+        ExpectedType::Value(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_GENERAL_QUANTIZATION_VERSION,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_GENERAL_ALIGNMENT,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_GENERAL_NAME,
+        ExpectedType::Value(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_GENERAL_FILE_TYPE,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_CONTEXT_LENGTH,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_EMBEDDING_LENGTH,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_BLOCK_COUNT,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_FEED_FORWARD_LENGTH,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_EXPERT_COUNT,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_EXPERT_USED_COUNT,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_ATTENTION_HEAD_COUNT,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_ATTENTION_HEAD_COUNT_KV,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_ATTENTION_LAYERNORM_EPS,
+        ExpectedType::Value(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_ATTENTION_LAYERNORM_RMS_EPS,
+        ExpectedType::Value(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_ATTENTION_SLIDING_WINDOW,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_ROPE_DIMENSION_COUNT,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_ROPE_FREQ_BASE,
+        ExpectedType::Value(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_ROPE_SCALE_LINEAR,
+        ExpectedType::Value(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_ROPE_SCALING_TYPE,
+        ExpectedType::Value(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_ROPE_SCALING_FACTOR,
+        ExpectedType::Value(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_ROPE_SCALING_ATTN_FACTOR,
+        ExpectedType::Value(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_ROPE_SCALING_ORIG_CTX_LEN,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_RESCALE_EVERY_N_LAYERS,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_WKV_HEAD_SIZE,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_POOLING_TYPE,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_MODEL,
+        ExpectedType::Value(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_TOKENIZER_PRE,
+        ExpectedType::Value(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_TOKENIZER_LIST,
+        ExpectedType::Array(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_TOKENIZER_TOKEN_TYPE,
+        ExpectedType::Array(GGUFMetadataValueType::I32),
+    ),
+    (
+        KEY_TOKENIZER_SCORES,
+        ExpectedType::Array(GGUFMetadataValueType::F32),
+    ),
+    (
+        KEY_TOKENIZER_MERGES,
+        ExpectedType::Array(GGUFMetadataValueType::String),
+    ),
+    (
+        KEY_TOKENIZER_BOS_ID,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_EOS_ID,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_UNK_ID,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_SEP_ID,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_CLS_ID,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_PAD_ID,
+        ExpectedType::Value(GGUFMetadataValueType::U32),
+    ),
+    (
+        KEY_TOKENIZER_ADD_BOS,
+        ExpectedType::Value(GGUFMetadataValueType::Bool),
+    ),
+    (
+        KEY_TOKENIZER_ADD_EOS,
+        ExpectedType::Value(GGUFMetadataValueType::Bool),
+    ),
+    (
+        KEY_TOKENIZER_CHAT_TEMPLATE,
+        ExpectedType::Value(GGUFMetadataValueType::String),
+    ),
+];
+
+struct Validator<'a> {
+    buf: GGUFBufReader<'a>,
+    version: GGUFVersion,
+    errors: Vec<GGUFError>,
+}
+
+/// validate the GGUF file in the buffer, and returns all the problems found, an empty result
+/// means the file is valid. the validation goes on after the problems on the metadata values
+/// or the tensors, but stops on the problems which make the rest of the file unreadable, like
+/// a truncated header.
+pub fn validate(buf: &[u8]) -> Vec<GGUFError> {
+    let mut v = Validator {
+        buf: GGUFBufReader::new(buf),
+        version: GGUFVersion::V1,
+        errors: vec![],
+    };
+    // the fatal errors are also recorded in v.errors
+    let _ = v.validate();
+    v.errors
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, err: GGUFError) {
+        self.errors.push(err);
+    }
+
+    /// records the error, Err(()) is returned to stop the validation.
+    fn fatal<T>(&mut self, err: GGUFError) -> Result<T, ()> {
+        self.error(err);
+        Err(())
+    }
+
+    /// read with a GGUFMetadataReader at the current position, the failure on reading is fatal.
+    fn read<T>(
+        &mut self,
+        f: impl FnOnce(&mut GGUFMetadataReader<'a, '_>) -> Result<T, GGUFError>,
+    ) -> Result<T, ()> {
+        let result = f(&mut GGUFMetadataReader::new(&mut self.buf, self.version));
+        result.or_else(|err| self.fatal(err))
+    }
+
+    fn offset(&self) -> usize {
+        self.buf.read_bytes()
+    }
+
+    fn validate(&mut self) -> Result<(), ()> {
+        let magic = self.read(|r| r.read_u32())?;
+        if magic != GGUF_MAGIC {
+            return self.fatal(GGUFError::InvalidMagic { magic });
+        }
+
+        let version = self.read(|r| r.read_u32())?;
+        self.version = match GGUFVersion::from_int(version) {
+            Ok(v) => v,
+            Err(_) => return self.fatal(GGUFError::UnsupportedVersion { version }),
+        };
+
+        let tensor_count = self.read(|r| r.read_len())?;
+        let kv_count = self.read(|r| r.read_len())?;
+
+        let metadata = self.validate_metadata(kv_count)?;
+        let alignment = self.validate_alignment(&metadata);
+
+        let tensor_infos = self.validate_tensor_infos(tensor_count)?;
+        let position = self.offset();
+        let data_start = position.div_ceil(alignment) * alignment;
+        let file_size = position + self.buf.cursor().len();
+        if data_start > file_size {
+            return self.fatal(GGUFError::Truncated {
+                offset: position,
+                len: data_start - position,
+                available: file_size - position,
+            });
+        }
+        self.validate_tensor_data(&tensor_infos, data_start, file_size, alignment);
+        Ok(())
+    }
+
+    fn validate_metadata(
+        &mut self,
+        kv_count: usize,
+    ) -> Result<HashMap<String, (usize, GGUFMetadataValue<'a>)>, ()> {
+        let mut metadata: HashMap<String, (usize, GGUFMetadataValue<'a>)> = HashMap::new();
+        for _ in 0..kv_count {
+            let offset = self.offset();
+            let (key, value) = self.read(|r| r.read_kv())?;
+            if metadata.contains_key(key) {
+                self.error(GGUFError::DuplicateKey {
+                    offset,
+                    key: key.to_string(),
+                });
+                continue;
+            }
+            metadata.insert(key.to_string(), (offset, value));
+        }
+
+        let arch = match metadata.get(KEY_GENERAL_ARCHITECTURE) {
+            Some((_, GGUFMetadataValue::String(arch))) => arch.to_string(),
+            // the shards of a split model except the first one carry no architecture
+            _ if metadata.contains_key(KEY_SPLIT_NO) => String::new(),
+            _ => {
+                self.error(GGUFError::MissingKey {
+                    key: KEY_GENERAL_ARCHITECTURE.to_string(),
+                });
+                String::new()
+            }
+        };
+
+        for (key, expected) in EXPECTED_TYPES {
+            let key = key.replace("{arch}", &arch);
+            let (offset, value) = match metadata.get(&key) {
+                Some(v) => v,
+                None => continue,
+            };
+            let (expected, actual) = match (expected, value) {
+                (ExpectedType::Array(typ), GGUFMetadataValue::Array(arr)) => {
+                    (format!("[{:?}]", typ), format!("[{:?}]", arr.typ()))
+                }
+                (ExpectedType::Array(typ), v) => (format!("[{:?}]", typ), format!("{:?}", v.typ())),
+                (ExpectedType::Value(typ), v) => (format!("{:?}", typ), format!("{:?}", v.typ())),
+            };
+            if expected != actual {
+                let offset = Some(*offset);
+                self.error(GGUFError::MetadataTypeMismatch {
+                    offset,
+                    key,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        // the tokenizer arrays are indexed by the token id
+        let n_tokens = match metadata.get(KEY_TOKENIZER_LIST) {
+            Some((_, GGUFMetadataValue::Array(arr))) => Some(arr.len()),
+            _ => None,
+        };
+        for key in [KEY_TOKENIZER_SCORES, KEY_TOKENIZER_TOKEN_TYPE] {
+            if let (Some(n_tokens), Some((offset, GGUFMetadataValue::Array(arr)))) =
+                (n_tokens, metadata.get(key))
+            {
+                if arr.len() != n_tokens {
+                    let offset = *offset;
+                    self.error(GGUFError::InvalidMetadata {
+                        offset,
+                        key: Some(key.to_string()),
+                        reason: format!("{} items, but there are {} tokens", arr.len(), n_tokens),
+                        source: None,
+                    });
+                }
+            }
+        }
+        Ok(metadata)
+    }
+
+    fn validate_alignment(
+        &mut self,
+        metadata: &HashMap<String, (usize, GGUFMetadataValue<'a>)>,
+    ) -> usize {
+        let (offset, alignment) = match metadata.get(KEY_GENERAL_ALIGNMENT) {
+            Some((offset, GGUFMetadataValue::U32(v))) => (*offset, *v as usize),
+            Some((offset, GGUFMetadataValue::U64(v))) => (*offset, *v as usize),
+            _ => return GGUF_DEFAULT_ALIGNMENT as usize,
+        };
+        if alignment == 0 || alignment % 8 != 0 {
+            self.error(GGUFError::InvalidAlignment { offset, alignment });
+            return GGUF_DEFAULT_ALIGNMENT as usize;
+        }
+        alignment
+    }
+
+    fn validate_tensor_infos(
+        &mut self,
+        tensor_count: usize,
+    ) -> Result<Vec<GGUFOnDiskTensorInfo>, ()> {
+        let mut names = HashSet::new();
+        let mut tensor_infos = vec![];
+        for _ in 0..tensor_count {
+            let info = match GGUFOnDiskTensorInfo::decode(&mut self.buf, self.version) {
+                Ok(info) => info,
+                Err(err) => return self.fatal(err),
+            };
+            let invalid = |reason: String| GGUFError::InvalidTensorInfo {
+                offset: info.info_offset,
+                tensor: Some(info.name.clone()),
+                reason,
+                source: None,
+            };
+            if !names.insert(info.name.clone()) {
+                self.error(GGUFError::DuplicateTensor {
+                    offset: info.info_offset,
+                    tensor: info.name.clone(),
+                });
+            }
+            if info.name.len() > 64 {
+                self.error(invalid("the name is longer than 64 bytes".to_string()));
+            }
+            if info.dimensions.len() > 4 {
+                self.error(invalid(format!(
+                    "{} dimensions, at most 4 are supported",
+                    info.dimensions.len()
+                )));
+            }
+            tensor_infos.push(info);
+        }
+        Ok(tensor_infos)
+    }
+
+    fn validate_tensor_data(
+        &mut self,
+        tensor_infos: &[GGUFOnDiskTensorInfo],
+        data_start: usize,
+        file_size: usize,
+        alignment: usize,
+    ) {
+        let data_size = file_size - data_start;
+        let mut ranges = vec![];
+        for info in tensor_infos {
+            let offset = info.info_offset;
+            let invalid = |reason: String| GGUFError::InvalidTensorInfo {
+                offset,
+                tensor: Some(info.name.clone()),
+                reason,
+                source: None,
+            };
+            let n_elems = info
+                .dimensions
+                .iter()
+                .try_fold(1_usize, |acc, d| acc.checked_mul(*d));
+            let size = match n_elems.map(|n| info.typ.bytes_of(n)) {
+                Some(Ok(size)) => size,
+                Some(Err(e)) => {
+                    self.error(invalid(e.message));
+                    continue;
+                }
+                None => {
+                    self.error(invalid("the dimensions overflow".to_string()));
+                    continue;
+                }
+            };
+            let begin = info.offset as usize;
+            if begin % alignment != 0 {
+                self.error(GGUFError::MisalignedTensor {
+                    offset,
+                    tensor: info.name.clone(),
+                    data_offset: begin,
+                    alignment,
+                });
+            }
+            if begin.saturating_add(size) > data_size {
+                self.error(GGUFError::TensorOutOfBounds {
+                    offset,
+                    tensor: info.name.clone(),
+                    data_position: data_start.saturating_add(begin),
+                    size,
+                    file_size,
+                });
+                continue;
+            }
+            ranges.push((begin, begin + size, offset, &info.name));
+        }
+
+        ranges.sort();
+        for w in ranges.windows(2) {
+            let (_, prev_end, _, prev_name) = w[0];
+            let (begin, _, offset, name) = w[1];
+            if begin < prev_end {
+                self.error(GGUFError::OverlappingTensors {
+                    offset,
+                    tensor: name.clone(),
+                    other: prev_name.clone(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGMLType;
+    use crate::gguf::GGUFErrorKind;
+    use crate::gguf::GGUFFileLoader;
+    use crate::gguf::GGUFWriter;
+
+    fn write_test_file(
+        metadata: Vec<(&str, GGUFMetadataValue)>,
+        tensors: &[(&str, usize)],
+    ) -> Vec<u8> {
+        let mut w = GGUFWriter::new();
+        for (k, v) in metadata {
+            w.add_metadata(k, v);
+        }
+        for (name, n_elems) in tensors {
+            w.add_tensor(name, &[*n_elems], GGMLType::F32, vec![0; n_elems * 4])
+                .unwrap();
+        }
+        let mut buf = vec![];
+        w.write(&mut buf).unwrap();
+        buf
+    }
+
+    fn replace_bytes(buf: &mut [u8], from: &str, to: &str) {
+        let pos = buf
+            .windows(from.len())
+            .position(|w| w == from.as_bytes())
+            .unwrap();
+        buf[pos..pos + to.len()].copy_from_slice(to.as_bytes());
+    }
+
+    fn error_kinds(buf: &[u8]) -> Vec<GGUFErrorKind> {
+        validate(buf).iter().map(|e| e.kind()).collect()
+    }
+
+    #[test]
+    fn test_validate_testdata() -> crate::error::Result<()> {
+        for path in [
+            "../testdata/tinyllamas-stories-260k-f32.gguf",
+            "../testdata/tinyllamas-stories-15m-q8_0.gguf",
+        ] {
+            let gl = GGUFFileLoader::new(path)?;
+            let errs = gl.validate();
+            assert!(errs.is_empty(), "{}: {:?}", path, errs);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let arch = ("general.architecture", GGUFMetadataValue::String("llama"));
+        let buf = write_test_file(vec![arch.clone()], &[("a.weight", 8), ("b.weight", 16)]);
+        assert_eq!(error_kinds(&buf), vec![]);
+
+        // truncated download
+        let kinds = error_kinds(&buf[..buf.len() - 10]);
+        assert_eq!(kinds, vec![GGUFErrorKind::TensorOutOfBounds]);
+        let errs = validate(&buf[..100]);
+        assert_eq!(errs[0].kind(), GGUFErrorKind::InvalidTensorInfo);
+
+        // bad magic and version
+        let mut buf2 = buf.clone();
+        buf2[0] = b'X';
+        assert_eq!(error_kinds(&buf2), vec![GGUFErrorKind::InvalidMagic]);
+        let mut buf2 = buf.clone();
+        buf2[4..8].copy_from_slice(&9_u32.to_le_bytes());
+        assert_eq!(error_kinds(&buf2), vec![GGUFErrorKind::UnsupportedVersion]);
+
+        // duplicated tensors
+        let mut buf2 = buf.clone();
+        replace_bytes(&mut buf2, "b.weight", "a.weight");
+        let errs = validate(&buf2);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].kind(), GGUFErrorKind::DuplicateTensor);
+        assert_eq!(
+            errs[0].to_string(),
+            "duplicated tensor a.weight at offset 109"
+        );
+
+        // type mismatch, duplicated key and bad alignment
+        let mut buf2 = write_test_file(
+            vec![
+                arch,
+                ("llama.block_count", GGUFMetadataValue::F32(1.0)),
+                ("llama.xlock_count", GGUFMetadataValue::F32(1.0)),
+                ("general.alignmenx", GGUFMetadataValue::U32(7)),
+            ],
+            &[],
+        );
+        replace_bytes(&mut buf2, "llama.xlock_count", "llama.block_count");
+        replace_bytes(&mut buf2, "general.alignmenx", "general.alignment");
+        let errs = validate(&buf2);
+        assert_eq!(errs.iter().map(|e| e.kind()).collect::<Vec<_>>(), vec![
+            GGUFErrorKind::DuplicateKey,
+            GGUFErrorKind::MetadataTypeMismatch,
+            GGUFErrorKind::InvalidAlignment
+        ]);
+        assert_eq!(
+            errs[1].to_string(),
+            "metadata llama.block_count at offset 69 is expected to be U32, but got F32"
+        );
+
+        // missing architecture
+        let buf2 = write_test_file(vec![], &[]);
+        assert_eq!(error_kinds(&buf2), vec![GGUFErrorKind::MissingKey]);
+    }
+}
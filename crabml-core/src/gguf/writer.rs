@@ -0,0 +1,386 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+
+use super::GGMLType;
+use super::GGUFMetadata;
+use super::GGUFMetadataArray;
+use super::GGUFMetadataValue;
+use super::GGUFVersion;
+use super::GGUF_DEFAULT_ALIGNMENT;
+use super::GGUF_MAGIC;
+use super::KEY_GENERAL_ALIGNMENT;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+struct GGUFWriterTensor<'a> {
+    name: String,
+    dimensions: Vec<usize>,
+    typ: GGMLType,
+    data: Cow<'a, [u8]>,
+}
+
+/// GGUFWriter serializes the metadata kvs and the tensors into a GGUF v3 file. The data of
+/// the tensors can either be borrowed from another GGUF file or owned, this allows copying
+/// the tensors from an mmaped file without loading them into memory.
+///
+/// The metadata and tensors are written in the order they are added.
+#[derive(Default)]
+pub struct GGUFWriter<'a> {
+    metadata_kv: Vec<(String, GGUFMetadataValue<'a>)>,
+    tensors: Vec<GGUFWriterTensor<'a>>,
+}
+
+impl<'a> GGUFWriter<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add a metadata kv, the value of an existing key will be replaced.
+    pub fn add_metadata(&mut self, key: &str, value: GGUFMetadataValue<'a>) {
+        match self.metadata_kv.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.metadata_kv.push((key.to_string(), value)),
+        }
+    }
+
+    /// add all the kvs of another file in their order, like on rewriting the file.
+    pub fn add_metadata_of(&mut self, metadata: &GGUFMetadata<'a>) {
+        for (key, value) in metadata.iter() {
+            self.add_metadata(key, value.clone());
+        }
+    }
+
+    pub fn remove_metadata(&mut self, key: &str) -> Option<GGUFMetadataValue<'a>> {
+        let idx = self.metadata_kv.iter().position(|(k, _)| k == key)?;
+        Some(self.metadata_kv.remove(idx).1)
+    }
+
+    /// add a tensor, the dimensions are in the GGUF order, which is the reverse order of the
+    /// numpy's shape.
+    pub fn add_tensor(
+        &mut self,
+        name: &str,
+        dimensions: &[usize],
+        typ: GGMLType,
+        data: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<()> {
+        let data = data.into();
+        let n_elems = dimensions.iter().product::<usize>();
+        let size = typ.bytes_of(n_elems)?;
+        if size != data.len() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "tensor {} of type {} with dimensions {:?} takes {} bytes, but got {} bytes",
+                    name,
+                    typ,
+                    dimensions,
+                    size,
+                    data.len()
+                ),
+                cause: None,
+            });
+        }
+        if self.tensors.iter().any(|t| t.name == name) {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!("duplicated tensor name {}", name),
+                cause: None,
+            });
+        }
+
+        self.tensors.push(GGUFWriterTensor {
+            name: name.to_string(),
+            dimensions: dimensions.to_vec(),
+            typ,
+            data,
+        });
+        Ok(())
+    }
+
+    pub fn alignment(&self) -> usize {
+        let alignment = self
+            .metadata_kv
+            .iter()
+            .find(|(k, _)| k == KEY_GENERAL_ALIGNMENT)
+            .map(|(_, v)| v);
+        match alignment {
+            Some(GGUFMetadataValue::U32(v)) => *v as usize,
+            Some(GGUFMetadataValue::U64(v)) => *v as usize,
+            _ => GGUF_DEFAULT_ALIGNMENT as usize,
+        }
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to create the file: {}", path),
+            cause: Some(Box::new(err)),
+        })?;
+        let mut w = BufWriter::new(file);
+        self.write(&mut w)?;
+        w.flush().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to flush the file: {}", path),
+            cause: Some(Box::new(err)),
+        })
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        let mut w = GGUFBufWriter::new(w);
+        self.write_header_to(&mut w)?;
+
+        // tensor data
+        let alignment = self.alignment();
+        for tensor in self.tensors.iter() {
+            w.write_bytes(&tensor.data)?;
+            w.write_padding(alignment)?;
+        }
+        Ok(())
+    }
+
+    /// write the metadata kvs and the tensor infos padded to the alignment, which is all the
+    /// bytes before the tensor data. returns the number of the bytes written.
+    pub fn write_header(&self, w: &mut impl Write) -> Result<usize> {
+        let mut w = GGUFBufWriter::new(w);
+        self.write_header_to(&mut w)?;
+        Ok(w.written_bytes)
+    }
+
+    /// the offsets of the tensors relative to the start of the tensor data.
+    pub fn tensor_offsets(&self) -> Vec<usize> {
+        let alignment = self.alignment();
+        let mut offsets = Vec::with_capacity(self.tensors.len());
+        let mut offset = 0;
+        for tensor in self.tensors.iter() {
+            offsets.push(offset);
+            offset = (offset + tensor.data.len()).div_ceil(alignment) * alignment;
+        }
+        offsets
+    }
+
+    fn write_header_to<W: Write>(&self, w: &mut GGUFBufWriter<W>) -> Result<()> {
+        let alignment = self.alignment();
+        if alignment == 0 || alignment % 8 != 0 {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!("the alignment must be a multiple of 8, got {}", alignment),
+                cause: None,
+            });
+        }
+
+        // header
+        w.write_u32(GGUF_MAGIC)?;
+        w.write_u32(GGUFVersion::V3 as u32)?;
+        w.write_u64(self.tensors.len() as u64)?;
+        w.write_u64(self.metadata_kv.len() as u64)?;
+        for (key, value) in self.metadata_kv.iter() {
+            w.write_string(key)?;
+            w.write_value(value)?;
+        }
+
+        // tensor infos, the offsets are relative to the start of the tensor data
+        for (tensor, offset) in self.tensors.iter().zip(self.tensor_offsets()) {
+            w.write_string(&tensor.name)?;
+            w.write_u32(tensor.dimensions.len() as u32)?;
+            for dim in tensor.dimensions.iter() {
+                w.write_u64(*dim as u64)?;
+            }
+            w.write_u32(tensor.typ as u32)?;
+            w.write_u64(offset as u64)?;
+        }
+        w.write_padding(alignment)
+    }
+}
+
+struct GGUFBufWriter<'w, W: Write> {
+    inner: &'w mut W,
+    written_bytes: usize,
+}
+
+macro_rules! define_gguf_value_write_fn {
+    ($write_array_func:ident, $write_item_func:ident, $typ:ty) => {
+        fn $write_array_func(&mut self, arr: &[$typ]) -> Result<()> {
+            for v in arr {
+                self.$write_item_func(*v)?;
+            }
+            Ok(())
+        }
+
+        fn $write_item_func(&mut self, v: $typ) -> Result<()> {
+            self.write_bytes(&v.to_le_bytes())
+        }
+    };
+}
+
+impl<'w, W: Write> GGUFBufWriter<'w, W> {
+    fn new(inner: &'w mut W) -> Self {
+        Self {
+            inner,
+            written_bytes: 0,
+        }
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.write_all(buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to write {} bytes", buf.len()),
+            cause: Some(Box::new(err)),
+        })?;
+        self.written_bytes += buf.len();
+        Ok(())
+    }
+
+    fn write_padding(&mut self, alignment: usize) -> Result<()> {
+        let padding = self.written_bytes.div_ceil(alignment) * alignment - self.written_bytes;
+        self.write_bytes(&vec![0; padding])
+    }
+
+    define_gguf_value_write_fn!(write_u8_array, write_u8, u8);
+    define_gguf_value_write_fn!(write_i8_array, write_i8, i8);
+    define_gguf_value_write_fn!(write_u16_array, write_u16, u16);
+    define_gguf_value_write_fn!(write_i16_array, write_i16, i16);
+    define_gguf_value_write_fn!(write_u32_array, write_u32, u32);
+    define_gguf_value_write_fn!(write_i32_array, write_i32, i32);
+    define_gguf_value_write_fn!(write_u64_array, write_u64, u64);
+    define_gguf_value_write_fn!(write_i64_array, write_i64, i64);
+    define_gguf_value_write_fn!(write_f32_array, write_f32, f32);
+    define_gguf_value_write_fn!(write_f64_array, write_f64, f64);
+
+    fn write_string(&mut self, s: &str) -> Result<()> {
+        self.write_u64(s.len() as u64)?;
+        self.write_bytes(s.as_bytes())
+    }
+
+    fn write_value(&mut self, value: &GGUFMetadataValue) -> Result<()> {
+        self.write_u32(value.typ() as u32)?;
+        match value {
+            GGUFMetadataValue::U8(v) => self.write_u8(*v),
+            GGUFMetadataValue::I8(v) => self.write_i8(*v),
+            GGUFMetadataValue::U16(v) => self.write_u16(*v),
+            GGUFMetadataValue::I16(v) => self.write_i16(*v),
+            GGUFMetadataValue::U32(v) => self.write_u32(*v),
+            GGUFMetadataValue::I32(v) => self.write_i32(*v),
+            GGUFMetadataValue::U64(v) => self.write_u64(*v),
+            GGUFMetadataValue::I64(v) => self.write_i64(*v),
+            GGUFMetadataValue::F32(v) => self.write_f32(*v),
+            GGUFMetadataValue::F64(v) => self.write_f64(*v),
+            GGUFMetadataValue::Bool(v) => self.write_u8(*v),
+            GGUFMetadataValue::String(v) => self.write_string(v),
+            GGUFMetadataValue::Array(arr) => self.write_array(arr),
+        }
+    }
+
+    fn write_array(&mut self, arr: &GGUFMetadataArray) -> Result<()> {
+        self.write_u32(arr.typ() as u32)?;
+        self.write_u64(arr.len() as u64)?;
+
+        match arr {
+            GGUFMetadataArray::U8Array(v) => self.write_u8_array(v),
+            GGUFMetadataArray::I8Array(v) => self.write_i8_array(v),
+            GGUFMetadataArray::U16Array(v) => self.write_u16_array(v),
+            GGUFMetadataArray::I16Array(v) => self.write_i16_array(v),
+            GGUFMetadataArray::U32Array(v) => self.write_u32_array(v),
+            GGUFMetadataArray::I32Array(v) => self.write_i32_array(v),
+            GGUFMetadataArray::U64Array(v) => self.write_u64_array(v),
+            GGUFMetadataArray::I64Array(v) => self.write_i64_array(v),
+            GGUFMetadataArray::F32Array(v) => self.write_f32_array(v),
+            GGUFMetadataArray::F64Array(v) => self.write_f64_array(v),
+            GGUFMetadataArray::BoolArray(v) => self.write_u8_array(v),
+            GGUFMetadataArray::StringArray(v) => {
+                for s in v.iter() {
+                    self.write_string(s)?;
+                }
+                Ok(())
+            }
+            GGUFMetadataArray::NestedArray(v) => {
+                for nested in v.iter() {
+                    self.write_array(nested)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFBufReader;
+    use crate::gguf::GGUFFile;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_write_and_decode() -> Result<()> {
+        let f32_data = [1.0_f32, 2.0, 3.0, 4.0, 5.0, 6.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let q8_0_data = [1_u8; 34 * 2];
+
+        let mut w = GGUFWriter::new();
+        w.add_metadata("general.architecture", GGUFMetadataValue::String("llama"));
+        w.add_metadata("llama.block_count", GGUFMetadataValue::U32(1));
+        w.add_metadata("llama.block_count", GGUFMetadataValue::U32(2));
+        w.add_metadata(
+            "tokenizer.ggml.tokens",
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(vec!["<s>", "</s>"])),
+        );
+        w.add_tensor("a.weight", &[3, 2], GGMLType::F32, f32_data.clone())?;
+        w.add_tensor("b.weight", &[64], GGMLType::Q8_0, &q8_0_data[..])?;
+        assert!(
+            w.add_tensor("c.weight", &[64], GGMLType::F32, &q8_0_data[..])
+                .is_err()
+        );
+        assert!(
+            w.add_tensor("b.weight", &[64], GGMLType::Q8_0, &q8_0_data[..])
+                .is_err()
+        );
+
+        let mut buf = vec![];
+        w.write(&mut buf)?;
+
+        let gf = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+        assert_eq!(gf.version().to_string(), "3");
+        assert_eq!(gf.architecture(), "llama");
+        assert_eq!(gf.metadata().get_u32("llama.block_count"), Some(2));
+        assert_eq!(
+            gf.metadata().get_string_array("tokenizer.ggml.tokens"),
+            Some(&["<s>", "</s>"][..])
+        );
+        let a = gf.get_tensor_info("a.weight").unwrap();
+        assert_eq!(a.dimensions(), &[3, 2]);
+        assert_eq!(a.data(), &f32_data[..]);
+        let b = gf.get_tensor_info("b.weight").unwrap();
+        assert_eq!(b.typ(), GGMLType::Q8_0);
+        assert_eq!(b.data(), &q8_0_data[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_gguf_file() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = loader.open()?;
+
+        let mut w = GGUFWriter::new();
+        w.add_metadata_of(gf.metadata());
+        for info in gf.tensor_infos() {
+            w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?;
+        }
+        let mut buf = vec![];
+        w.write(&mut buf)?;
+
+        let gf2 = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+        assert_eq!(gf2.metadata().as_hashmap(), gf.metadata().as_hashmap());
+        assert_eq!(gf2.tensor_infos().len(), gf.tensor_infos().len());
+        for (t1, t2) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+            assert_eq!(t1.name(), t2.name());
+            assert_eq!(t1.dimensions(), t2.dimensions());
+            assert_eq!(t1.data(), t2.data());
+        }
+        Ok(())
+    }
+}
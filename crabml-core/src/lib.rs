@@ -1,12 +1,18 @@
 #![feature(portable_simd)]
 #![feature(slice_as_chunks)]
 #![feature(stdsimd)]
+#![feature(avx512_target_feature)]
 #![feature(thread_local)]
 #![feature(lazy_cell)]
+#![feature(dropck_eyepatch)]
 
 #[allow(unreachable_patterns)]
 pub mod backends;
 pub mod error;
+pub mod file_buf;
 pub mod gguf;
+pub mod safetensors;
+pub mod sha256;
 pub mod tensor;
+pub mod time;
 pub mod tokenizer;
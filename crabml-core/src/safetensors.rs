@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use half::bf16;
+use half::f16;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::file_buf::FileBuf;
+use crate::gguf::ModelTensor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetensorsDType {
+    Bool,
+    U8,
+    I8,
+    I16,
+    U16,
+    F16,
+    BF16,
+    I32,
+    U32,
+    F32,
+    F64,
+    I64,
+    U64,
+}
+
+impl SafetensorsDType {
+    pub fn type_size(&self) -> usize {
+        match self {
+            SafetensorsDType::Bool | SafetensorsDType::U8 | SafetensorsDType::I8 => 1,
+            SafetensorsDType::I16
+            | SafetensorsDType::U16
+            | SafetensorsDType::F16
+            | SafetensorsDType::BF16 => 2,
+            SafetensorsDType::I32 | SafetensorsDType::U32 | SafetensorsDType::F32 => 4,
+            SafetensorsDType::F64 | SafetensorsDType::I64 | SafetensorsDType::U64 => 8,
+        }
+    }
+}
+
+impl TryFrom<&str> for SafetensorsDType {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        let typ = match s {
+            "BOOL" => SafetensorsDType::Bool,
+            "U8" => SafetensorsDType::U8,
+            "I8" => SafetensorsDType::I8,
+            "I16" => SafetensorsDType::I16,
+            "U16" => SafetensorsDType::U16,
+            "F16" => SafetensorsDType::F16,
+            "BF16" => SafetensorsDType::BF16,
+            "I32" => SafetensorsDType::I32,
+            "U32" => SafetensorsDType::U32,
+            "F32" => SafetensorsDType::F32,
+            "F64" => SafetensorsDType::F64,
+            "I64" => SafetensorsDType::I64,
+            "U64" => SafetensorsDType::U64,
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!("unknown safetensors dtype: {}", s),
+                    cause: None,
+                });
+            }
+        };
+        Ok(typ)
+    }
+}
+
+impl Display for SafetensorsDType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            SafetensorsDType::Bool => "BOOL",
+            SafetensorsDType::U8 => "U8",
+            SafetensorsDType::I8 => "I8",
+            SafetensorsDType::I16 => "I16",
+            SafetensorsDType::U16 => "U16",
+            SafetensorsDType::F16 => "F16",
+            SafetensorsDType::BF16 => "BF16",
+            SafetensorsDType::I32 => "I32",
+            SafetensorsDType::U32 => "U32",
+            SafetensorsDType::F32 => "F32",
+            SafetensorsDType::F64 => "F64",
+            SafetensorsDType::I64 => "I64",
+            SafetensorsDType::U64 => "U64",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SafetensorsTensorInfo<'a> {
+    name: String,
+    dtype: SafetensorsDType,
+    // in the numpy's order, unlike GGUF
+    shape: Vec<usize>,
+    data: &'a [u8],
+}
+
+impl<'a> SafetensorsTensorInfo<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dtype(&self) -> SafetensorsDType {
+        self.dtype
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// converts the float data into f32, the data is always copied, as the data in the
+    /// safetensors file is not guaranteed to be aligned.
+    pub fn to_f32_vec(&self) -> Result<Vec<f32>> {
+        let v = match self.dtype {
+            SafetensorsDType::F32 => self
+                .data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            SafetensorsDType::F16 => self
+                .data
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+            SafetensorsDType::BF16 => self
+                .data
+                .chunks_exact(2)
+                .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+            _ => {
+                return Err(Error {
+                    kind: ErrorKind::NotImplemented,
+                    message: format!(
+                        "converting tensor {} of dtype {} to f32 is not supported",
+                        self.name, self.dtype
+                    ),
+                    cause: None,
+                });
+            }
+        };
+        Ok(v)
+    }
+}
+
+/// SafetensorsFile is the huggingface's safetensors format: an u64 of the header size, a json
+/// header which describes the dtype, shape and data offsets of each tensor, then the tensor data.
+/// See https://github.com/huggingface/safetensors for details.
+pub struct SafetensorsFile<'a> {
+    metadata: HashMap<String, String>,
+    tensor_infos: Vec<SafetensorsTensorInfo<'a>>,
+}
+
+impl<'a> SafetensorsFile<'a> {
+    pub fn decode(buf: &'a [u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: "safetensors file is too short".to_string(),
+                cause: None,
+            });
+        }
+        let header_size = u64::from_le_bytes(buf[0..8].try_into().unwrap()) as usize;
+        if header_size > buf.len() - 8 {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!(
+                    "safetensors header size {} exceeds the file size {}",
+                    header_size,
+                    buf.len()
+                ),
+                cause: None,
+            });
+        }
+        let tensor_data = &buf[8 + header_size..];
+
+        let header: serde_json::Value =
+            serde_json::from_slice(&buf[8..8 + header_size]).map_err(|err| Error {
+                kind: ErrorKind::FormatError,
+                message: "failed to parse the safetensors header".to_string(),
+                cause: Some(Box::new(err)),
+            })?;
+        let header = header.as_object().ok_or_else(|| Error {
+            kind: ErrorKind::FormatError,
+            message: "the safetensors header is not an object".to_string(),
+            cause: None,
+        })?;
+
+        let mut metadata = HashMap::new();
+        let mut tensor_infos = Vec::with_capacity(header.len());
+        for (name, value) in header {
+            if name == "__metadata__" {
+                for (k, v) in value.as_object().into_iter().flatten() {
+                    if let Some(v) = v.as_str() {
+                        metadata.insert(k.to_string(), v.to_string());
+                    }
+                }
+                continue;
+            }
+            tensor_infos.push(Self::decode_tensor_info(name, value, tensor_data)?);
+        }
+        // the json object is not ordered, keep the tensors in the order of the data
+        tensor_infos.sort_by_key(|info| info.data.as_ptr() as usize);
+
+        Ok(Self {
+            metadata,
+            tensor_infos,
+        })
+    }
+
+    fn decode_tensor_info(
+        name: &str,
+        value: &serde_json::Value,
+        tensor_data: &'a [u8],
+    ) -> Result<SafetensorsTensorInfo<'a>> {
+        let invalid = |field: &str| Error {
+            kind: ErrorKind::FormatError,
+            message: format!("invalid {} of tensor {} in safetensors header", field, name),
+            cause: None,
+        };
+
+        let dtype = value["dtype"].as_str().ok_or_else(|| invalid("dtype"))?;
+        let dtype = SafetensorsDType::try_from(dtype)?;
+        let shape = value["shape"]
+            .as_array()
+            .ok_or_else(|| invalid("shape"))?
+            .iter()
+            .map(|v| {
+                v.as_u64()
+                    .map(|v| v as usize)
+                    .ok_or_else(|| invalid("shape"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let offsets = value["data_offsets"]
+            .as_array()
+            .ok_or_else(|| invalid("data_offsets"))?
+            .iter()
+            .map(|v| {
+                v.as_u64()
+                    .map(|v| v as usize)
+                    .ok_or_else(|| invalid("data_offsets"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (begin, end) = match offsets[..] {
+            [begin, end] if begin <= end && end <= tensor_data.len() => (begin, end),
+            _ => return Err(invalid("data_offsets")),
+        };
+        let size = shape
+            .iter()
+            .try_fold(1_usize, |acc, d| acc.checked_mul(*d))
+            .and_then(|n| n.checked_mul(dtype.type_size()));
+        if size != Some(end - begin) {
+            return Err(invalid("data size"));
+        }
+
+        Ok(SafetensorsTensorInfo {
+            name: name.to_string(),
+            dtype,
+            shape,
+            data: &tensor_data[begin..end],
+        })
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn tensor_infos(&self) -> &[SafetensorsTensorInfo<'a>] {
+        &self.tensor_infos
+    }
+
+    pub fn get_tensor_info(&self, name: &str) -> Option<&SafetensorsTensorInfo<'a>> {
+        self.tensor_infos.iter().find(|info| info.name == name)
+    }
+
+    /// find the tensor by its huggingface name.
+    pub fn get_model_tensor(
+        &self,
+        tensor: ModelTensor,
+        layer: usize,
+    ) -> Option<&SafetensorsTensorInfo<'a>> {
+        self.get_tensor_info(&tensor.hf_name(layer)?)
+    }
+}
+
+/// SafetensorsFileLoader mmaps the safetensors file, like GGUFFileLoader.
+pub struct SafetensorsFileLoader {
+    mmap: FileBuf,
+}
+
+impl SafetensorsFileLoader {
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(Self {
+            mmap: FileBuf::open(path)?,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            mmap: FileBuf::from_bytes(bytes),
+        }
+    }
+
+    pub fn open(&self) -> Result<SafetensorsFile<'_>> {
+        SafetensorsFile::decode(&self.mmap[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut buf = (header.len() as u64).to_le_bytes().to_vec();
+        buf.extend_from_slice(header.as_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn test_decode_safetensors() -> Result<()> {
+        let mut data = vec![];
+        data.extend([1.0_f32, 2.0].iter().flat_map(|v| v.to_le_bytes()));
+        data.extend(
+            [3.0_f32, 4.0]
+                .iter()
+                .flat_map(|v| f16::from_f32(*v).to_le_bytes()),
+        );
+        data.extend(
+            [5.0_f32, 6.0]
+                .iter()
+                .flat_map(|v| bf16::from_f32(*v).to_le_bytes()),
+        );
+        let header = r#"{
+            "__metadata__": {"format": "pt"},
+            "model.norm.weight": {"dtype": "BF16", "shape": [2], "data_offsets": [12, 16]},
+            "lm_head.weight": {"dtype": "F16", "shape": [1, 2], "data_offsets": [8, 12]},
+            "model.embed_tokens.weight": {"dtype": "F32", "shape": [2, 1], "data_offsets": [0, 8]}
+        }"#;
+        let buf = encode(header, &data);
+
+        let sf = SafetensorsFile::decode(&buf)?;
+        assert_eq!(sf.metadata().get("format").map(|s| s.as_str()), Some("pt"));
+        let names = sf
+            .tensor_infos()
+            .iter()
+            .map(|t| t.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            "model.embed_tokens.weight",
+            "lm_head.weight",
+            "model.norm.weight"
+        ]);
+
+        let embd = sf.get_model_tensor(ModelTensor::TokenEmbd, 0).unwrap();
+        assert_eq!(embd.dtype(), SafetensorsDType::F32);
+        assert_eq!(embd.shape(), &[2, 1]);
+        assert_eq!(embd.to_f32_vec()?, vec![1.0, 2.0]);
+        let output = sf.get_model_tensor(ModelTensor::Output, 0).unwrap();
+        assert_eq!(output.to_f32_vec()?, vec![3.0, 4.0]);
+        let norm = sf.get_model_tensor(ModelTensor::OutputNorm, 0).unwrap();
+        assert_eq!(norm.to_f32_vec()?, vec![5.0, 6.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_invalid_safetensors() {
+        let tests = vec![
+            r#"{"a": {"dtype": "F32", "shape": [3], "data_offsets": [0, 8]}}"#,
+            r#"{"a": {"dtype": "F32", "shape": [4], "data_offsets": [0, 16]}}"#,
+            // the size wraps to 0 without the overflow checks
+            r#"{"a": {"dtype": "F32", "shape": [4611686018427387904, 2], "data_offsets": [0, 0]}}"#,
+            r#"{"a": {"dtype": "X32", "shape": [2], "data_offsets": [0, 8]}}"#,
+            r#"{"a": {"dtype": "F32", "shape": [2]}}"#,
+            r#"{"a": "#,
+        ];
+        for header in tests {
+            let buf = encode(header, &[0; 8]);
+            assert!(SafetensorsFile::decode(&buf).is_err(), "{}", header);
+        }
+        assert!(SafetensorsFile::decode(&[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! SHA-256 of the model files, which is the `oid` of the files stored by git lfs, like the
+//! GGUF files on the HuggingFace Hub. the hasher is the one of the `sha2` crate.
+
+use std::fmt::Write;
+use std::io::Read;
+
+pub use sha2::Digest;
+pub use sha2::Sha256;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the SHA-256 of the bytes in the lowercase hex.
+pub fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    to_hex(&hasher.finalize())
+}
+
+/// the SHA-256 of all the bytes read from the reader in the lowercase hex.
+pub fn sha256_hex_reader(r: &mut impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = r.read(&mut buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to read the data to hash".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() -> Result<()> {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        // the same hash however the bytes are split
+        let data = (0..1000).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+        let expected = sha256_hex(&data);
+        for piece in [1, 3, 63, 64, 65, 500] {
+            let mut hasher = Sha256::new();
+            for chunk in data.chunks(piece) {
+                hasher.update(chunk);
+            }
+            assert_eq!(to_hex(&hasher.finalize()), expected);
+        }
+        assert_eq!(sha256_hex_reader(&mut &data[..])?, expected);
+        Ok(())
+    }
+}
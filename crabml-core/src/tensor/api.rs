@@ -2,6 +2,159 @@ use super::strider::TensorStrider;
 use crate::error::Result;
 use crate::gguf::GGMLType;
 
+/// the layout of the rotary pairs in a head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RopeMode {
+    /// the pairs are adjacent, like llama in GGUF.
+    #[default]
+    Normal,
+    /// the pairs are apart by the half of the rope dims, like gpt-neox and qwen2.
+    Neox,
+}
+
+/// how the positions beyond the trained context are scaled with `freq_scale`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RopeScaling {
+    /// interpolate all the dims by the scale.
+    #[default]
+    Linear,
+    /// interpolate the low frequency dims and extrapolate the high frequency ones, blended by
+    /// a ramp between the dims which rotate 32 and 1 times in the original context.
+    NtkByParts { orig_ctx_len: usize },
+    /// the ntk-by-parts with the magnitude scaled by `1 + 0.1 * ln(1 / freq_scale)`.
+    Yarn { orig_ctx_len: usize },
+}
+
+/// the rotary embedding on the heads.
+#[derive(Debug, Clone, Copy)]
+pub struct RopeOptions<'a> {
+    pub mode: RopeMode,
+    /// the dims to rotate in each head, the rest of the head is kept as it is.
+    pub dims: usize,
+    pub freq_base: f32,
+    /// the multiplier of the positions, which interpolates the positions by the linear
+    /// scaling of the long context models.
+    pub freq_scale: f32,
+    /// how the `freq_scale` applies on each rotary pair.
+    pub scaling: RopeScaling,
+    /// the divisors of the frequency of each rotary pair, like the LongRope of phi3.
+    pub freq_factors: Option<&'a [f32]>,
+    /// the scale of the rotated values.
+    pub attn_factor: f32,
+    /// rotate backward by the position in the unit magnitude, which moves the roped keys
+    /// back by the position, like the k-shift of llama.cpp.
+    pub backward: bool,
+}
+
+impl<'a> RopeOptions<'a> {
+    pub fn new(mode: RopeMode, dims: usize) -> Self {
+        Self {
+            mode,
+            dims,
+            freq_base: 10000.0,
+            freq_scale: 1.0,
+            scaling: RopeScaling::Linear,
+            freq_factors: None,
+            attn_factor: 1.0,
+            backward: false,
+        }
+    }
+
+    pub fn with_freq_base(mut self, freq_base: f32) -> Self {
+        self.freq_base = freq_base;
+        self
+    }
+
+    pub fn with_freq_scale(mut self, freq_scale: f32) -> Self {
+        self.freq_scale = freq_scale;
+        self
+    }
+
+    pub fn with_scaling(mut self, scaling: RopeScaling) -> Self {
+        self.scaling = scaling;
+        self
+    }
+
+    pub fn with_freq_factors(mut self, freq_factors: Option<&'a [f32]>) -> Self {
+        self.freq_factors = freq_factors;
+        self
+    }
+
+    pub fn with_attn_factor(mut self, attn_factor: f32) -> Self {
+        self.attn_factor = attn_factor;
+        self
+    }
+
+    pub fn with_backward(mut self, backward: bool) -> Self {
+        self.backward = backward;
+        self
+    }
+
+    /// the (cos, sin) of each rotary pair on the position, scaled by the attn factor, like
+    /// `rope_yarn` in ggml.
+    pub fn cos_sin(&self, pos: usize) -> Vec<(f32, f32)> {
+        let theta_scale = self.freq_base.powf(-2.0 / self.dims as f32);
+        let (ramp_dims, mscale) = match self.scaling {
+            RopeScaling::Linear => (None, 1.0),
+            RopeScaling::NtkByParts { orig_ctx_len } => (Some(self.corr_dims(orig_ctx_len)), 1.0),
+            RopeScaling::Yarn { orig_ctx_len } => (
+                Some(self.corr_dims(orig_ctx_len)),
+                1.0 + 0.1 * (1.0 / self.freq_scale).ln(),
+            ),
+        };
+        let (mscale, sign) = match self.backward {
+            true => (1.0, -1.0),
+            false => (mscale * self.attn_factor, 1.0),
+        };
+        let mut theta = pos as f32;
+        (0..self.dims / 2)
+            .map(|i| {
+                let theta_extrap = match self.freq_factors {
+                    Some(factors) => theta / factors[i],
+                    None => theta,
+                };
+                theta *= theta_scale;
+                let theta_interp = theta_extrap * self.freq_scale;
+                let t = match ramp_dims {
+                    Some((low, high)) => {
+                        let y = (i as f32 - low) / (high - low).max(0.001);
+                        let ramp_mix = 1.0 - y.clamp(0.0, 1.0);
+                        theta_interp * (1.0 - ramp_mix) + theta_extrap * ramp_mix
+                    }
+                    None => theta_interp,
+                };
+                (t.cos() * mscale, t.sin() * mscale * sign)
+            })
+            .collect()
+    }
+
+    /// the pairs between which the ntk-by-parts ramps from the extrapolation to the
+    /// interpolation, they rotate 32 and 1 times in the original context.
+    fn corr_dims(&self, orig_ctx_len: usize) -> (f32, f32) {
+        let corr_dim = |n_rot: f32| {
+            let n_dims = self.dims as f32;
+            let ratio = orig_ctx_len as f32 / (n_rot * 2.0 * std::f32::consts::PI);
+            n_dims * ratio.ln() / (2.0 * self.freq_base.ln())
+        };
+        let low = corr_dim(32.0).floor().max(0.0);
+        let high = corr_dim(1.0).ceil().min(self.dims as f32 - 1.0);
+        (low, high)
+    }
+}
+
+/// the slopes of the linear biases on each head of alibi, like `ggml_alibi` in llama.cpp.
+pub fn alibi_slopes(n_heads: usize, max_bias: f32) -> Vec<f32> {
+    let n_floor = 1 << (usize::BITS - 1 - n_heads.leading_zeros());
+    let m0 = 2.0f32.powf(-max_bias / n_floor as f32);
+    let m1 = 2.0f32.powf(-(max_bias / 2.0) / n_floor as f32);
+    (0..n_heads)
+        .map(|h| match h < n_floor {
+            true => m0.powi(h as i32 + 1),
+            false => m1.powi(2 * (h - n_floor) as i32 + 1),
+        })
+        .collect()
+}
+
 pub trait Tensor: Sized + Clone {
     type Device: Clone;
 
@@ -10,6 +163,19 @@ pub trait Tensor: Sized + Clone {
     /// TODO: add dtype parameter
     fn alloc(shape: &[usize], capacity: Option<usize>, device: Self::Device) -> Result<Self>;
 
+    /// alloc an owned kv cache of the dtype, the rows are quantized on `extend` and `set_row`,
+    /// and dequantized on `batch_matmul_vec`. only F32, Q8_0 and Q4_0 are supported.
+    fn alloc_cache(
+        shape: &[usize],
+        capacity: Option<usize>,
+        dtype: GGMLType,
+        device: Self::Device,
+    ) -> Result<Self>;
+
+    /// an owned F32 tensor of the data on the host, used on the activations computed on the
+    /// host, like the recurrent layers of rwkv.
+    fn from_vec(buf: Vec<f32>, shape: &[usize], device: Self::Device) -> Result<Self>;
+
     fn dtype(&self) -> GGMLType;
 
     fn with_strider(self, strider: TensorStrider) -> Result<Self>;
@@ -22,12 +188,21 @@ pub trait Tensor: Sized + Clone {
 
     fn transpose(self, shape: &[usize]) -> Result<Self>;
 
+    /// narrow the axis into [start, start + len) as a view on the same buffer, without
+    /// copying. the view is not contiguous, which is only taken by the ops on the strided
+    /// inputs, like `batch_matmul_vec`.
+    fn slice(self, axis: usize, start: usize, len: usize) -> Result<Self>;
+
     fn strider(&self) -> &TensorStrider;
 
     fn extend(&mut self, rhs: &Self) -> Result<()>;
 
+    /// overwrite the row at the index of the first dimension, used on the rolling kv cache.
+    fn set_row(&mut self, row: usize, rhs: &Self) -> Result<()>;
+
     /// copy from another tensor. used on loading weights from vocab table.
-    /// the src and dst tensor must have the same dtype.
+    /// the src and dst tensor must have the same dtype, except the kv caches allocated by
+    /// `alloc_cache`, which are dequantized into f32.
     fn copy_from(&mut self, rhs: &Self, pos: &[usize], len: usize) -> Result<()>;
 
     fn export(&self, buf: &mut [f32]) -> Result<()>;
@@ -35,7 +210,19 @@ pub trait Tensor: Sized + Clone {
     /// duplicate the tensor and the underlying storage
     fn dup(&self) -> Result<Self>;
 
-    fn rope_inplace(self, pos: usize, rope_dims: usize) -> Result<Self>;
+    /// convert the activations between F32 and F16 into a new tensor. the F16 activations
+    /// halve the memory and the bandwidth of the inputs of `matmul_vec` and `matmul`, which
+    /// are the only ops taking them, and still accumulate in f32.
+    fn to_dtype(&self, dtype: GGMLType) -> Result<Self>;
+
+    fn rope_inplace(self, pos: usize, rope: &RopeOptions) -> Result<Self>;
+
+    /// add the linear biases of alibi on the attention scores of (n_heads, n_seq), the distance
+    /// of each key is taken as `(pos - i) % n_seq`, which also holds on the rolling kv cache.
+    fn alibi_inplace(self, pos: usize, max_bias: f32) -> Result<Self>;
+
+    /// normalize to the zero mean and the unit variance, without the weight and bias.
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self>;
 
     fn rms_norm_inplace(self, eps: f32) -> Result<Self>;
 
@@ -43,13 +230,110 @@ pub trait Tensor: Sized + Clone {
 
     fn silu_inplace(self) -> Result<Self>;
 
+    /// the tanh approximation of gelu.
+    fn gelu_inplace(self) -> Result<Self>;
+
+    /// self * rhs, the rhs is broadcasted onto the shape of self by the numpy rules, like the
+    /// scale of (n_heads, 1) on the heads of (n_heads, head_size).
     fn mul_inplace(self, rhs: &Self) -> Result<Self>;
 
+    /// the rms norm scaled by the weight in a single pass, the fusion of `rms_norm_inplace`
+    /// and `mul_inplace` taken by `TensorGraph`.
+    fn rms_norm_mul_inplace(self, eps: f32, weight: &Self) -> Result<Self>;
+
+    /// silu(self) * rhs in a single pass, like the gate of the ffn.
+    fn silu_mul_inplace(self, rhs: &Self) -> Result<Self>;
+
+    /// self + rhs, the rhs is broadcasted onto the shape of self like `mul_inplace`, like the
+    /// bias of (dim, ) on the rows of (n_rows, dim).
     fn add_inplace(self, rhs: &Self) -> Result<Self>;
 
     fn div_scalar_inplace(self, rhs: f32) -> Result<Self>;
 
     fn matmul_vec(&self, y: &Self) -> Result<Self>;
 
+    /// (m, k) @ (b, k) => (b, m), the matmul_vec on each row of y in a batch, which loads
+    /// self once for all the rows, like the tokens of a prompt evaluated together.
+    fn matmul(&self, y: &Self) -> Result<Self>;
+
+    /// (b, m, k) @ (b * g, k) => (b * g, m), every g rows of y share a batch of self, like
+    /// the query heads of a group share a kv head in the grouped-query attention.
     fn batch_matmul_vec(&self, y: &Self) -> Result<Self>;
+
+    /// the fused attention of the query heads of (n_heads, head_size) on the keys and values
+    /// of (seq_len, n_kv_heads, head_size) split into the pages, which is (n_heads, head_size).
+    /// the scores are scaled by `scale` and biased by alibi on `(pos, max_bias)`, and the
+    /// softmax is taken online on the tiles of the keys like flash attention, so the scores of
+    /// (n_heads, seq_len) are never materialized.
+    fn flash_attention(
+        &self,
+        k_pages: &[&Self],
+        v_pages: &[&Self],
+        scale: f32,
+        alibi: Option<(usize, f32)>,
+    ) -> Result<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alibi_slopes() {
+        assert_eq!(alibi_slopes(2, 8.0), vec![0.0625, 0.00390625]);
+        // the heads beyond the power of 2 take the slopes in between
+        assert_eq!(alibi_slopes(3, 8.0), vec![0.0625, 0.00390625, 0.25]);
+        assert_eq!(alibi_slopes(8, 8.0)[7], 0.00390625);
+    }
+
+    #[test]
+    fn test_rope_freq_scale() {
+        let rope = RopeOptions::new(RopeMode::Normal, 8).with_freq_base(500000.0);
+        // the linear scaling of 4 takes the position 8 as 2
+        assert_eq!(rope.with_freq_scale(0.25).cos_sin(8), rope.cos_sin(2));
+        assert_ne!(rope.cos_sin(8), rope.cos_sin(2));
+    }
+
+    #[test]
+    fn test_rope_yarn() {
+        let rope = RopeOptions::new(RopeMode::Normal, 64).with_freq_scale(0.25);
+        let scaling = RopeScaling::NtkByParts { orig_ctx_len: 32 };
+        assert_eq!(rope.with_scaling(scaling).corr_dims(32), (0.0, 6.0));
+
+        // the high frequency pairs are extrapolated, the low frequency ones are interpolated
+        let unscaled = rope.with_freq_scale(1.0).cos_sin(100);
+        let linear = rope.cos_sin(100);
+        let ntk = rope.with_scaling(scaling).cos_sin(100);
+        assert_eq!(ntk[0], unscaled[0]);
+        assert_ne!(ntk[3], unscaled[3]);
+        assert_ne!(ntk[3], linear[3]);
+        assert_eq!(ntk[6..], linear[6..]);
+
+        // yarn takes the same angles, but scales the magnitude
+        let yarn = rope
+            .with_scaling(RopeScaling::Yarn { orig_ctx_len: 32 })
+            .cos_sin(100);
+        let mscale = 1.0 + 0.1 * 4.0f32.ln();
+        for ((cos, sin), (ntk_cos, ntk_sin)) in yarn.iter().zip(ntk.iter()) {
+            assert!((cos - ntk_cos * mscale).abs() < 1e-6);
+            assert!((sin - ntk_sin * mscale).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_rope_backward() {
+        let rope = RopeOptions::new(RopeMode::Normal, 8)
+            .with_freq_scale(0.25)
+            .with_scaling(RopeScaling::Yarn { orig_ctx_len: 4 });
+        let backward = rope.with_backward(true);
+
+        // rotating backward by 30 moves the position 100 to 70
+        let shifted = rope.cos_sin(100);
+        for (i, (cos, sin)) in backward.cos_sin(30).into_iter().enumerate() {
+            let (c, s) = shifted[i];
+            let (want_cos, want_sin) = rope.cos_sin(70)[i];
+            assert!((c * cos - s * sin - want_cos).abs() < 1e-3);
+            assert!((c * sin + s * cos - want_sin).abs() < 1e-3);
+        }
+    }
 }
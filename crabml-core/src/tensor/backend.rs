@@ -0,0 +1,18 @@
+use crate::backends::cpu::CpuTensor;
+use crate::error::Result;
+use crate::tensor::Tensor;
+
+/// the device of a backend, which the tensors of the backend live on. the ops like the
+/// matmul, rope, rms_norm, softmax and copy are taken on `Self::Tensor`, so the models are
+/// written once on `Tensor` and moved onto any backend by uploading the weights loaded on the
+/// cpu, and each backend is tested apart against the cpu.
+pub trait TensorBackend: Clone {
+    type Tensor: Tensor<Device = Self>;
+
+    /// the name of the backend, like "cpu" or "wgpu".
+    fn name(&self) -> &'static str;
+
+    /// upload a tensor on the cpu, like the weights loaded from GGUF, onto the device. the
+    /// dtypes not supported by the backend are dequantized into f32.
+    fn upload(&self, tensor: &CpuTensor) -> Result<Self::Tensor>;
+}
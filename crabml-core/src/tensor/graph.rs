@@ -0,0 +1,176 @@
+use crate::error::Result;
+use crate::tensor::Tensor;
+
+/// an elementwise op recorded on the graph, the operands are borrowed until it's evaluated.
+enum TensorGraphOp<'a, T: Tensor> {
+    RmsNorm(f32),
+    LayerNorm(f32),
+    Silu,
+    Gelu,
+    Mul(&'a T),
+    Add(&'a T),
+    DivScalar(f32),
+    // the fused ops, which are only taken after `fuse`
+    RmsNormMul(f32, &'a T),
+    SiluMul(&'a T),
+}
+
+/// a small compute graph of the elementwise ops on a tensor, like the norm or the gate of the
+/// ffn on each step of the forward pass. the ops are recorded lazily, and the adjacent ops
+/// which have a fused kernel on the backends are merged on `eval`, like rms_norm + mul and
+/// silu + mul, so the tensor is read and written once less for each of the fusions.
+pub struct TensorGraph<'a, T: Tensor> {
+    input: T,
+    ops: Vec<TensorGraphOp<'a, T>>,
+}
+
+impl<'a, T: Tensor> TensorGraph<'a, T> {
+    pub fn new(input: T) -> Self {
+        Self { input, ops: vec![] }
+    }
+
+    pub fn rms_norm(self, eps: f32) -> Self {
+        self.push(TensorGraphOp::RmsNorm(eps))
+    }
+
+    pub fn layer_norm(self, eps: f32) -> Self {
+        self.push(TensorGraphOp::LayerNorm(eps))
+    }
+
+    pub fn silu(self) -> Self {
+        self.push(TensorGraphOp::Silu)
+    }
+
+    pub fn gelu(self) -> Self {
+        self.push(TensorGraphOp::Gelu)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn mul(self, rhs: &'a T) -> Self {
+        self.push(TensorGraphOp::Mul(rhs))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(self, rhs: &'a T) -> Self {
+        self.push(TensorGraphOp::Add(rhs))
+    }
+
+    pub fn div_scalar(self, rhs: f32) -> Self {
+        self.push(TensorGraphOp::DivScalar(rhs))
+    }
+
+    fn push(mut self, op: TensorGraphOp<'a, T>) -> Self {
+        self.ops.push(op);
+        self
+    }
+
+    /// the number of the kernels launched on evaluating the graph, after the fusion.
+    pub fn n_kernels(&self) -> usize {
+        Self::fuse(&self.ops).len()
+    }
+
+    /// merge the adjacent ops which have a fused kernel, from the first op on.
+    fn fuse(ops: &[TensorGraphOp<'a, T>]) -> Vec<TensorGraphOp<'a, T>> {
+        let mut fused = Vec::with_capacity(ops.len());
+        let mut i = 0;
+        while i < ops.len() {
+            let op = match (&ops[i], ops.get(i + 1)) {
+                (TensorGraphOp::RmsNorm(eps), Some(TensorGraphOp::Mul(rhs))) => {
+                    i += 1;
+                    TensorGraphOp::RmsNormMul(*eps, *rhs)
+                }
+                (TensorGraphOp::Silu, Some(TensorGraphOp::Mul(rhs))) => {
+                    i += 1;
+                    TensorGraphOp::SiluMul(*rhs)
+                }
+                (op, _) => op.clone(),
+            };
+            fused.push(op);
+            i += 1;
+        }
+        fused
+    }
+
+    /// fuse the ops and run them on the input in order.
+    pub fn eval(self) -> Result<T> {
+        Self::fuse(&self.ops)
+            .into_iter()
+            .try_fold(self.input, |x, op| match op {
+                TensorGraphOp::RmsNorm(eps) => x.rms_norm_inplace(eps),
+                TensorGraphOp::LayerNorm(eps) => x.layer_norm_inplace(eps),
+                TensorGraphOp::Silu => x.silu_inplace(),
+                TensorGraphOp::Gelu => x.gelu_inplace(),
+                TensorGraphOp::Mul(rhs) => x.mul_inplace(rhs),
+                TensorGraphOp::Add(rhs) => x.add_inplace(rhs),
+                TensorGraphOp::DivScalar(rhs) => x.div_scalar_inplace(rhs),
+                TensorGraphOp::RmsNormMul(eps, weight) => x.rms_norm_mul_inplace(eps, weight),
+                TensorGraphOp::SiluMul(rhs) => x.silu_mul_inplace(rhs),
+            })
+    }
+}
+
+impl<'a, T: Tensor> Clone for TensorGraphOp<'a, T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::RmsNorm(eps) => Self::RmsNorm(*eps),
+            Self::LayerNorm(eps) => Self::LayerNorm(*eps),
+            Self::Silu => Self::Silu,
+            Self::Gelu => Self::Gelu,
+            Self::Mul(rhs) => Self::Mul(*rhs),
+            Self::Add(rhs) => Self::Add(*rhs),
+            Self::DivScalar(rhs) => Self::DivScalar(*rhs),
+            Self::RmsNormMul(eps, weight) => Self::RmsNormMul(*eps, *weight),
+            Self::SiluMul(rhs) => Self::SiluMul(*rhs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::CpuTensor;
+    use crate::backends::cpu::CpuTensorDevice;
+
+    fn export(t: &CpuTensor) -> Result<Vec<f32>> {
+        let mut buf = vec![0.0; t.strider().len()];
+        t.export(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_graph_fusion() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let v = (0..64)
+            .map(|i| (i % 9) as f32 / 3.0 - 1.5)
+            .collect::<Vec<_>>();
+        let w = (0..64).map(|i| (i % 5) as f32 / 2.0).collect::<Vec<_>>();
+        let x = CpuTensor::new(v, &[64], device.clone())?;
+        let w = CpuTensor::new(w, &[64], device.clone())?;
+        let b = CpuTensor::new(vec![0.5; 64], &[64], device.clone())?;
+
+        // rms_norm + mul + add takes 2 kernels, and the same values as the eager ops
+        let graph = TensorGraph::new(x.dup()?).rms_norm(1e-5).mul(&w).add(&b);
+        assert_eq!(graph.n_kernels(), 2);
+        let got = graph.eval()?;
+        let want = x
+            .dup()?
+            .rms_norm_inplace(1e-5)?
+            .mul_inplace(&w)?
+            .add_inplace(&b)?;
+        assert_eq!(export(&got)?, export(&want)?);
+
+        // silu + mul
+        let graph = TensorGraph::new(x.dup()?).silu().mul(&w);
+        assert_eq!(graph.n_kernels(), 1);
+        let got = graph.eval()?;
+        let want = x.dup()?.silu_inplace()?.mul_inplace(&w)?;
+        assert_eq!(export(&got)?, export(&want)?);
+
+        // the ops without a fused kernel are taken as they are
+        let graph = TensorGraph::new(x.dup()?).layer_norm(1e-5).mul(&w).silu();
+        assert_eq!(graph.n_kernels(), 3);
+        let graph = TensorGraph::new(x.dup()?).mul(&w).silu().gelu();
+        assert_eq!(graph.n_kernels(), 3);
+        Ok(())
+    }
+}
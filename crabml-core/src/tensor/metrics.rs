@@ -5,31 +5,39 @@ use std::sync::Arc;
 #[derive(Debug, Default, Clone)]
 pub struct TensorDeviceMetrics {
     pub rms_norm_walltime: TimeMetric,
+    pub layer_norm_walltime: TimeMetric,
     pub add_walltime: TimeMetric,
     pub total_walltime: TimeMetric,
     pub mul_walltime: TimeMetric,
     pub rope_walltime: TimeMetric,
+    pub alibi_walltime: TimeMetric,
     pub softmax_walltime: TimeMetric,
     pub silu_walltime: TimeMetric,
+    pub gelu_walltime: TimeMetric,
     pub matmul_walltime: TimeMetric,
     pub matmul_quantize_walltime: TimeMetric,
     pub matmul_vec_dot_walltime: TimeMetric,
     pub batch_matmul_walltime: TimeMetric,
+    pub flash_attention_walltime: TimeMetric,
 }
 
 impl TensorDeviceMetrics {
     pub fn reset(&self) {
         self.rms_norm_walltime.reset();
+        self.layer_norm_walltime.reset();
         self.add_walltime.reset();
         self.mul_walltime.reset();
         self.rope_walltime.reset();
+        self.alibi_walltime.reset();
         self.softmax_walltime.reset();
         self.matmul_walltime.reset();
         self.silu_walltime.reset();
+        self.gelu_walltime.reset();
         self.total_walltime.reset();
         self.matmul_quantize_walltime.reset();
         self.matmul_vec_dot_walltime.reset();
         self.batch_matmul_walltime.reset();
+        self.flash_attention_walltime.reset();
     }
 
     pub fn as_vec(&self) -> Vec<(String, f64)> {
@@ -39,12 +47,21 @@ impl TensorDeviceMetrics {
                 self.rms_norm_walltime.as_millis(),
             ),
             ("add_walltime".to_string(), self.add_walltime.as_millis()),
+            (
+                "layer_norm_walltime".to_string(),
+                self.layer_norm_walltime.as_millis(),
+            ),
             ("silu_walltime".to_string(), self.silu_walltime.as_millis()),
+            ("gelu_walltime".to_string(), self.gelu_walltime.as_millis()),
             (
                 "total_walltime".to_string(),
                 self.total_walltime.as_millis(),
             ),
             ("rope_walltime".to_string(), self.rope_walltime.as_millis()),
+            (
+                "alibi_walltime".to_string(),
+                self.alibi_walltime.as_millis(),
+            ),
             (
                 "softmax_walltime".to_string(),
                 self.softmax_walltime.as_millis(),
@@ -66,6 +83,10 @@ impl TensorDeviceMetrics {
                 "batch_matmul_walltime".to_string(),
                 self.batch_matmul_walltime.as_millis(),
             ),
+            (
+                "flash_attention_walltime".to_string(),
+                self.flash_attention_walltime.as_millis(),
+            ),
         ]
     }
 }
@@ -77,7 +98,7 @@ pub struct TimeMetric {
 
 pub struct TimeMetricGuard {
     m: TimeMetric,
-    start_at: std::time::Instant,
+    start_at: crate::time::Instant,
 }
 
 impl TimeMetric {
@@ -98,7 +119,7 @@ impl TimeMetric {
     pub fn track(&self) -> TimeMetricGuard {
         TimeMetricGuard {
             m: self.clone(),
-            start_at: std::time::Instant::now(),
+            start_at: crate::time::Instant::now(),
         }
     }
 }
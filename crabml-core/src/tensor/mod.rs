@@ -1,7 +1,17 @@
 mod api;
+mod backend;
+mod graph;
 pub mod metrics;
+pub mod profiler;
 mod strider;
 
+pub use api::alibi_slopes;
+pub use api::RopeMode;
+pub use api::RopeOptions;
+pub use api::RopeScaling;
 pub use api::Tensor;
+pub use backend::TensorBackend;
+pub use graph::TensorGraph;
 pub use metrics::TensorDeviceMetrics;
+pub use profiler::TensorProfiler;
 pub use strider::TensorStrider;
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::time::Instant;
+
+/// an op recorded by the profiler, the flops and the bytes moved are estimated by the shapes
+/// of the inputs and the outputs.
+#[derive(Debug, Clone)]
+pub struct TensorProfileEvent {
+    pub op: &'static str,
+    pub start: Duration, // since the profiler is created
+    pub walltime: Duration,
+    pub flops: usize,
+    pub bytes: usize,
+    pub pass: usize, // the forward pass of the op
+}
+
+/// the ops of the same name summed up over the recorded passes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TensorProfileSummary {
+    pub op: &'static str,
+    pub calls: usize,
+    pub walltime: Duration,
+    pub flops: usize,
+    pub bytes: usize,
+}
+
+impl TensorProfileSummary {
+    pub fn gflops_per_sec(&self) -> f64 {
+        self.flops as f64 / self.walltime.as_secs_f64().max(1e-9) / 1e9
+    }
+
+    pub fn gbytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.walltime.as_secs_f64().max(1e-9) / 1e9
+    }
+}
+
+#[derive(Debug)]
+struct TensorProfilerInner {
+    started_at: Instant,
+    events: Vec<TensorProfileEvent>,
+    pass: usize,
+}
+
+/// an opt-in profiler of the ops on a device, which records the walltime, the flops and the
+/// bytes moved of each op, like the matmuls and the norms on each layer. the events are
+/// grouped by the forward passes marked by `next_pass`, and can be summed up into a table by
+/// the ops or dumped in the chrome trace format, which is opened by chrome://tracing or
+/// perfetto. it's cloned as a handle of the same events, like `TensorDeviceMetrics`.
+#[derive(Debug, Clone)]
+pub struct TensorProfiler {
+    inner: Arc<Mutex<TensorProfilerInner>>,
+}
+
+pub struct TensorProfileGuard {
+    profiler: TensorProfiler,
+    op: &'static str,
+    flops: usize,
+    bytes: usize,
+    start_at: Instant,
+}
+
+impl Default for TensorProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TensorProfiler {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(TensorProfilerInner {
+                started_at: Instant::now(),
+                events: vec![],
+                pass: 0,
+            })),
+        }
+    }
+
+    /// record the op until the guard is dropped.
+    pub fn track(&self, op: &'static str, flops: usize, bytes: usize) -> TensorProfileGuard {
+        TensorProfileGuard {
+            profiler: self.clone(),
+            op,
+            flops,
+            bytes,
+            start_at: Instant::now(),
+        }
+    }
+
+    /// mark the end of a forward pass, the ops after it are taken in the next pass.
+    pub fn next_pass(&self) {
+        self.inner.lock().unwrap().pass += 1;
+    }
+
+    /// the number of the passes which have recorded any op.
+    pub fn passes(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let mut passes = inner.events.iter().map(|e| e.pass).collect::<Vec<_>>();
+        passes.dedup();
+        passes.len()
+    }
+
+    pub fn events(&self) -> Vec<TensorProfileEvent> {
+        self.inner.lock().unwrap().events.clone()
+    }
+
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.events.clear();
+        inner.pass = 0;
+    }
+
+    /// sum up the events by the ops, ordered by the walltime descending.
+    pub fn summary(&self) -> Vec<TensorProfileSummary> {
+        let inner = self.inner.lock().unwrap();
+        let mut ops: HashMap<&'static str, TensorProfileSummary> = HashMap::new();
+        for e in inner.events.iter() {
+            let s = ops.entry(e.op).or_insert_with(|| TensorProfileSummary {
+                op: e.op,
+                ..Default::default()
+            });
+            s.calls += 1;
+            s.walltime += e.walltime;
+            s.flops += e.flops;
+            s.bytes += e.bytes;
+        }
+        let mut summary = ops.into_values().collect::<Vec<_>>();
+        summary.sort_by(|a, b| b.walltime.cmp(&a.walltime).then(a.op.cmp(b.op)));
+        summary
+    }
+
+    /// the summary as a table, with the walltime per pass and the throughput of each op.
+    pub fn summary_table(&self) -> String {
+        let summary = self.summary();
+        let passes = self.passes().max(1);
+        let total = summary.iter().map(|s| s.walltime).sum::<Duration>();
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:<20} {:>8} {:>12} {:>8} {:>10} {:>10}",
+            "op", "calls", "ms/pass", "%", "GFLOP/s", "GB/s"
+        )
+        .unwrap();
+        for s in summary.iter() {
+            writeln!(
+                out,
+                "{:<20} {:>8} {:>12.3} {:>8.2} {:>10.2} {:>10.2}",
+                s.op,
+                s.calls,
+                s.walltime.as_secs_f64() * 1000.0 / passes as f64,
+                s.walltime.as_secs_f64() * 100.0 / total.as_secs_f64().max(1e-9),
+                s.gflops_per_sec(),
+                s.gbytes_per_sec(),
+            )
+            .unwrap();
+        }
+        write!(
+            out,
+            "{} passes, {:.3} ms/pass",
+            passes,
+            total.as_secs_f64() * 1000.0 / passes as f64
+        )
+        .unwrap();
+        out
+    }
+
+    /// the events in the chrome trace format, each op is a complete event on the thread of
+    /// its pass.
+    pub fn chrome_trace(&self) -> String {
+        let events = self
+            .events()
+            .iter()
+            .map(|e| {
+                serde_json::json!({
+                    "name": e.op,
+                    "ph": "X",
+                    "ts": e.start.as_secs_f64() * 1e6,
+                    "dur": e.walltime.as_secs_f64() * 1e6,
+                    "pid": 0,
+                    "tid": 0,
+                    "args": {"pass": e.pass, "flops": e.flops, "bytes": e.bytes},
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "traceEvents": events }).to_string()
+    }
+}
+
+impl Drop for TensorProfileGuard {
+    fn drop(&mut self) {
+        let walltime = self.start_at.elapsed();
+        let mut inner = self.profiler.inner.lock().unwrap();
+        let event = TensorProfileEvent {
+            op: self.op,
+            start: self.start_at.duration_since(inner.started_at),
+            walltime,
+            flops: self.flops,
+            bytes: self.bytes,
+            pass: inner.pass,
+        };
+        inner.events.push(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler_summary() {
+        let profiler = TensorProfiler::new();
+        for _ in 0..2 {
+            drop(profiler.track("matmul", 200, 400));
+            drop(profiler.track("matmul", 200, 400));
+            drop(profiler.track("rms_norm", 10, 80));
+            profiler.next_pass();
+        }
+        assert_eq!(profiler.passes(), 2);
+        assert_eq!(profiler.events().len(), 6);
+
+        let mut summary = profiler.summary();
+        summary.sort_by_key(|s| s.op);
+        assert_eq!(
+            summary
+                .iter()
+                .map(|s| (s.op, s.calls, s.flops, s.bytes))
+                .collect::<Vec<_>>(),
+            vec![("matmul", 4, 800, 1600), ("rms_norm", 2, 20, 160)]
+        );
+        assert!(profiler.summary_table().contains("rms_norm"));
+
+        let trace: serde_json::Value = serde_json::from_str(&profiler.chrome_trace()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[5]["name"], "rms_norm");
+        assert_eq!(events[5]["args"]["pass"], 1);
+
+        profiler.reset();
+        assert_eq!(profiler.passes(), 0);
+    }
+}
@@ -5,12 +5,17 @@ use crate::error::Result;
 pub struct TensorStrider {
     shape: Vec<usize>,
     strides: Vec<usize>,
+    offset: usize, // the index of the first element in the buffer, non zero on the slices
 }
 
 impl TensorStrider {
     pub fn new(shape: Vec<usize>) -> Self {
         let strides = Self::compute_strides(&shape);
-        Self { shape, strides }
+        Self {
+            shape,
+            strides,
+            offset: 0,
+        }
     }
 
     pub fn shape(&self) -> &[usize] {
@@ -21,6 +26,10 @@ impl TensorStrider {
         &self.strides
     }
 
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
     pub fn at(&self, idx: &[usize]) -> Result<usize> {
         if idx.len() != self.shape.len() {
             return Err((
@@ -49,7 +58,7 @@ impl TensorStrider {
     }
 
     pub fn at_unchecked(&self, idx: &[usize]) -> usize {
-        let mut offset = 0;
+        let mut offset = self.offset;
         for (dim, stride) in idx.iter().zip(self.strides.iter()) {
             offset += dim * stride;
         }
@@ -142,17 +151,82 @@ impl TensorStrider {
         let strider = TensorStrider {
             shape: new_shape,
             strides: new_strides,
+            offset: self.offset,
         };
         Ok(strider)
     }
 
+    /// narrow the axis into [start, start + len), which takes the same strides and moves the
+    /// offset, so the slice is a view on the same buffer without copying.
+    pub fn slice(&self, axis: usize, start: usize, len: usize) -> Result<Self> {
+        if axis >= self.shape.len() || start + len > self.shape[axis] {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "invalid slice [{}, {}) on the axis {} of a tensor of shape {:?}",
+                    start,
+                    start + len,
+                    axis,
+                    self.shape
+                ),
+            )
+                .into());
+        }
+
+        let mut shape = self.shape.clone();
+        shape[axis] = len;
+        Ok(TensorStrider {
+            shape,
+            strides: self.strides.clone(),
+            offset: self.offset + start * self.strides[axis],
+        })
+    }
+
+    /// broadcast onto the shape by the numpy rules: the shapes are aligned on the last axis,
+    /// and each axis should be the same or 1, the missing leading axes are taken as 1. the
+    /// broadcasted axes take the stride of 0, so the same elements are read again on them.
+    pub fn broadcast_to(&self, shape: &[usize]) -> Result<Self> {
+        let err = || {
+            (
+                ErrorKind::TensorError,
+                format!(
+                    "can not broadcast the shape {:?} to {:?}",
+                    self.shape, shape
+                ),
+            )
+                .into()
+        };
+        if self.shape.len() > shape.len() {
+            return Err(err());
+        }
+
+        let pad = shape.len() - self.shape.len();
+        let mut strides = vec![0; shape.len()];
+        for (i, (dim, stride)) in self.shape.iter().zip(self.strides.iter()).enumerate() {
+            match *dim {
+                d if d == shape[pad + i] => strides[pad + i] = *stride,
+                1 => strides[pad + i] = 0,
+                _ => return Err(err()),
+            }
+        }
+        Ok(TensorStrider {
+            shape: shape.to_vec(),
+            strides,
+            offset: self.offset,
+        })
+    }
+
     pub fn is_contiguous(&self) -> bool {
         self.is_contiguous_on_axis(0)
     }
 
     // if the tensor is contiguous on the given axis, you can safely iterate
-    // the axis with a simple `.iter().step_by(strides[axis])`.
+    // the axis with a simple `.iter().step_by(strides[axis])`. the slices at an offset are
+    // never contiguous, they're taken by the strides only.
     pub fn is_contiguous_on_axis(&self, axis: usize) -> bool {
+        if self.offset != 0 {
+            return false;
+        }
         if self.strides.is_empty() {
             return true;
         }
@@ -264,6 +338,51 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_strider_slice() -> Result<()> {
+        // 0, 1, 2, 3
+        // 4, 5, 6, 7
+        // 8, 9, 10, 11
+        let s = TensorStrider::new(vec![3, 4]);
+
+        let r = s.slice(1, 1, 2)?;
+        assert_eq!(r.shape(), &[3, 2]);
+        assert_eq!(r.offset(), 1);
+        assert_eq!(r.iter().collect::<Vec<_>>(), vec![1, 2, 5, 6, 9, 10]);
+        assert!(!r.is_contiguous());
+
+        // the slices of the slices and the transposed ones keep the offset
+        let r = r.slice(0, 1, 2)?;
+        assert_eq!(r.iter().collect::<Vec<_>>(), vec![5, 6, 9, 10]);
+        let r = r.transpose(&[1, 0])?;
+        assert_eq!(r.iter().collect::<Vec<_>>(), vec![5, 9, 6, 10]);
+        assert_eq!(r.at(&[1, 0])?, 6);
+
+        assert!(s.slice(0, 2, 2).is_err());
+        assert!(s.slice(2, 0, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strider_broadcast() -> Result<()> {
+        // the bias of (3, ) on each of the rows of (2, 3)
+        let s = TensorStrider::new(vec![3]).broadcast_to(&[2, 3])?;
+        assert_eq!(s.strides(), &[0, 1]);
+        assert_eq!(s.iter().collect::<Vec<_>>(), vec![0, 1, 2, 0, 1, 2]);
+
+        // the scale of (2, 1) on each of the heads of (2, 3)
+        let s = TensorStrider::new(vec![2, 1]).broadcast_to(&[2, 3])?;
+        assert_eq!(s.iter().collect::<Vec<_>>(), vec![0, 0, 0, 1, 1, 1]);
+
+        // the same shape is kept as it is
+        let s = TensorStrider::new(vec![2, 3]).broadcast_to(&[2, 3])?;
+        assert!(s.is_contiguous());
+
+        assert!(TensorStrider::new(vec![2]).broadcast_to(&[2, 3]).is_err());
+        assert!(TensorStrider::new(vec![2, 3]).broadcast_to(&[3]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_is_contigous() -> Result<()> {
         let s = TensorStrider::new(vec![2, 3]);
@@ -1,19 +1,113 @@
 use std::collections::HashMap;
 
+use super::gpt2::Gpt2Bpe;
+use super::gpt2::Gpt2PreTokenizer;
+use super::hf::HfTokenizer;
+use super::rwkv::parse_world_vocab;
+use super::rwkv::unescape_token;
+use super::rwkv::RwkvTokenizer;
+use super::wpm::WpmTokenizer;
+use super::BpeStreamDecoder;
+use crate::error::Error;
+use crate::error::ErrorKind;
 use crate::error::Result;
+use crate::gguf::GGUFFile;
+use crate::gguf::KEY_TOKENIZER_ADD_BOS;
+use crate::gguf::KEY_TOKENIZER_ADD_EOS;
+use crate::gguf::KEY_TOKENIZER_BOS_ID;
+use crate::gguf::KEY_TOKENIZER_CLS_ID;
+use crate::gguf::KEY_TOKENIZER_EOS_ID;
+use crate::gguf::KEY_TOKENIZER_HF_JSON;
+use crate::gguf::KEY_TOKENIZER_LIST;
+use crate::gguf::KEY_TOKENIZER_MERGES;
+use crate::gguf::KEY_TOKENIZER_MODEL;
+use crate::gguf::KEY_TOKENIZER_PAD_ID;
+use crate::gguf::KEY_TOKENIZER_PRE;
+use crate::gguf::KEY_TOKENIZER_RWKV;
+use crate::gguf::KEY_TOKENIZER_SCORES;
+use crate::gguf::KEY_TOKENIZER_SEP_ID;
+use crate::gguf::KEY_TOKENIZER_TOKEN_TYPE;
+use crate::gguf::KEY_TOKENIZER_UNK_ID;
 
 type Token = String;
 type TokenID = usize;
 
+/// the types of the tokens in `tokenizer.ggml.token_type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BpeTokenType {
+    Normal,
+    Unknown,
+    /// the special tokens like bos/eos, which are not a part of the text.
+    Control,
+    /// the tokens added by the users, which are matched as a whole on encoding.
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+impl BpeTokenType {
+    pub fn from_gguf(typ: i32) -> Self {
+        match typ {
+            2 => BpeTokenType::Unknown,
+            3 => BpeTokenType::Control,
+            4 => BpeTokenType::UserDefined,
+            5 => BpeTokenType::Unused,
+            6 => BpeTokenType::Byte,
+            _ => BpeTokenType::Normal,
+        }
+    }
+
+    pub fn to_gguf(&self) -> i32 {
+        match self {
+            BpeTokenType::Normal => 1,
+            BpeTokenType::Unknown => 2,
+            BpeTokenType::Control => 3,
+            BpeTokenType::UserDefined => 4,
+            BpeTokenType::Unused => 5,
+            BpeTokenType::Byte => 6,
+        }
+    }
+}
+
+/// how the special tokens are decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BpeSpecialMode {
+    /// decode the control tokens into empty strings.
+    Skip,
+    /// decode the control tokens into their literals like "</s>".
+    Render,
+}
+
 pub struct BpeTokenizer {
     tokens: Vec<Token>,
     token_scores: Vec<f32>,
     token_ids: HashMap<String, TokenID>,
+    token_types: Vec<BpeTokenType>,
     bos_token: TokenID,
     eos_token: TokenID,
+    unk_token: Option<TokenID>,
+    pad_token: Option<TokenID>,
+    add_bos_token: bool,
+    add_eos_token: bool,
+    // the literals of the control and user defined tokens, the longer first
+    special_literals: Vec<(String, TokenID)>,
     // the state on decoding
-    byte_pieces: [u8; 256],
+    byte_tokens: [Option<TokenID>; 256],
     token_buf_len: usize,
+    model: BpeModel,
+}
+
+// how the text is split and merged into the tokens
+enum BpeModel {
+    // merge the pairs by the scores of the tokens, like SentencePiece
+    SentencePiece,
+    // the byte-level BPE of the GPT-2 style vocabularies
+    Gpt2(Gpt2Bpe),
+    HuggingFace(HfTokenizer),
+    // the greedy longest match of the RWKV world models
+    Rwkv(RwkvTokenizer),
+    // the WordPiece of the BERT models, which decodes like SentencePiece
+    Wpm(WpmTokenizer),
 }
 
 impl BpeTokenizer {
@@ -23,25 +117,233 @@ impl BpeTokenizer {
         bos_token: TokenID,
         eos_token: TokenID,
     ) -> Self {
-        let token_ids = tokens
+        let token_ids: HashMap<String, TokenID> = tokens
             .iter()
             .enumerate()
             .map(|(i, v)| (v.clone(), i))
             .collect();
-        let mut byte_pieces = [0u8; 256];
-        for (i, p) in byte_pieces.iter_mut().enumerate() {
-            *p = i as u8
+        // the byte fallback tokens like <0x0A>
+        let mut byte_tokens = [None; 256];
+        for (byte, token) in byte_tokens.iter_mut().enumerate() {
+            *token = token_ids.get(&format!("<0x{:02X}>", byte)).copied();
         }
 
-        Self {
+        let mut tokenizer = Self {
             tokens,
             token_ids,
             token_scores,
+            token_types: vec![],
             token_buf_len: 128,
-            byte_pieces,
+            byte_tokens,
             bos_token,
             eos_token,
+            unk_token: None,
+            pad_token: None,
+            add_bos_token: true,
+            add_eos_token: false,
+            special_literals: vec![],
+            model: BpeModel::SentencePiece,
+        };
+        tokenizer.set_token_types(vec![]);
+        tokenizer
+    }
+
+    /// the tokenizer of the GPT-2 style vocabularies, which merges the pairs by the order
+    /// of `merges` instead of the scores of the tokens.
+    pub fn new_gpt2(
+        tokens: Vec<String>,
+        merges: &[String],
+        bos_token: TokenID,
+        eos_token: TokenID,
+    ) -> Self {
+        let token_scores = vec![0.0; tokens.len()];
+        let mut tokenizer = Self::new(tokens, token_scores, bos_token, eos_token);
+        tokenizer.model = BpeModel::Gpt2(Gpt2Bpe::new(merges));
+        tokenizer.add_bos_token = false;
+        tokenizer
+    }
+
+    /// the tokenizer of the RWKV world models, the tokens are the raw bytes ordered by the
+    /// ids. the empty token 0 is taken as `<s>`, which is both the bos and eos token of the
+    /// world models.
+    pub fn new_rwkv(mut tokens: Vec<Vec<u8>>, bos_token: TokenID, eos_token: TokenID) -> Self {
+        if tokens.first().map_or(false, |t| t.is_empty()) {
+            tokens[0] = b"<s>".to_vec();
         }
+        let texts = tokens
+            .iter()
+            .map(|t| String::from_utf8_lossy(t).to_string())
+            .collect::<Vec<_>>();
+        let token_scores = vec![0.0; texts.len()];
+        let mut tokenizer = Self::new(texts, token_scores, bos_token, eos_token);
+        tokenizer.model = BpeModel::Rwkv(RwkvTokenizer::new(tokens));
+        tokenizer.add_bos_token = false;
+        tokenizer
+    }
+
+    /// the WordPiece tokenizer of the BERT models, the tokens are in the GGUF export which
+    /// starts the words with `▁`. the text is wrapped into the cls and sep tokens, which are
+    /// taken as the bos and eos tokens.
+    pub fn new_wpm(tokens: Vec<String>, cls_token: TokenID, sep_token: TokenID) -> Self {
+        let token_scores = vec![0.0; tokens.len()];
+        let wpm = WpmTokenizer::new(&tokens);
+        let mut tokenizer = Self::new(tokens, token_scores, cls_token, sep_token);
+        tokenizer.model = BpeModel::Wpm(wpm);
+        tokenizer.add_eos_token = true;
+        tokenizer
+    }
+
+    /// build the tokenizer from the `tokenizer.json` of HuggingFace, which tokenizes the
+    /// same as the `tokenizers` library when the GGML export of the vocabulary is lossy.
+    pub fn from_hf_json(json: &str, bos_token: TokenID, eos_token: TokenID) -> Result<Self> {
+        let (hf, vocab) = HfTokenizer::from_json(json)?;
+        let token_scores = vec![0.0; vocab.tokens.len()];
+        let mut tokenizer = Self::new(vocab.tokens, token_scores, bos_token, eos_token);
+        tokenizer.model = BpeModel::HuggingFace(hf);
+        tokenizer.add_bos_token = vocab.add_bos_token;
+        tokenizer.unk_token = vocab.unk_token;
+        tokenizer.set_token_types(vocab.token_types);
+        Ok(tokenizer)
+    }
+
+    /// load the tokenizer from the `tokenizer.*` metadata of a GGUF file, without loading
+    /// any tensor of the model.
+    pub fn from_gguf(gf: &GGUFFile) -> Result<Self> {
+        let header = gf.header();
+        let tokenizer = Self::from_gguf_model(gf)?;
+        // the special tokens in the metadata override the defaults of the tokenizer
+        let unk_token = header.get_u32(KEY_TOKENIZER_UNK_ID).ok();
+        let pad_token = header.get_u32(KEY_TOKENIZER_PAD_ID).ok();
+        let mut tokenizer = tokenizer
+            .with_unk_token(unk_token.map(|t| t as usize))
+            .with_pad_token(pad_token.map(|t| t as usize));
+        if let Ok(token_types) = header.get_i32_array(KEY_TOKENIZER_TOKEN_TYPE) {
+            let token_types = token_types.iter().map(|t| BpeTokenType::from_gguf(*t));
+            tokenizer = tokenizer.with_token_types(token_types.collect());
+        }
+        if let Ok(add_bos) = header.get_bool(KEY_TOKENIZER_ADD_BOS) {
+            tokenizer = tokenizer.with_add_bos_token(add_bos);
+        }
+        if let Ok(add_eos) = header.get_bool(KEY_TOKENIZER_ADD_EOS) {
+            tokenizer = tokenizer.with_add_eos_token(add_eos);
+        }
+        Ok(tokenizer)
+    }
+
+    fn from_gguf_model(gf: &GGUFFile) -> Result<Self> {
+        let header = gf.header();
+        if let Ok("rwkv") = header.get_str(KEY_TOKENIZER_MODEL) {
+            let tokens = match header.get_str(KEY_TOKENIZER_RWKV) {
+                Ok(world) => parse_world_vocab(world)?,
+                Err(_) => header
+                    .get_str_array(KEY_TOKENIZER_LIST)?
+                    .iter()
+                    .map(|t| unescape_token(t))
+                    .collect(),
+            };
+            let bos_token = header.get_u32(KEY_TOKENIZER_BOS_ID).unwrap_or(0) as usize;
+            let eos_token = header.get_u32(KEY_TOKENIZER_EOS_ID).unwrap_or(0) as usize;
+            return Ok(Self::new_rwkv(tokens, bos_token, eos_token));
+        }
+        if let Ok("bert") = header.get_str(KEY_TOKENIZER_MODEL) {
+            let tokens = header
+                .get_str_array(KEY_TOKENIZER_LIST)?
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>();
+            let cls_token = header
+                .get_u32(KEY_TOKENIZER_CLS_ID)
+                .or_else(|_| header.get_u32(KEY_TOKENIZER_BOS_ID))?;
+            let sep_token = header
+                .get_u32(KEY_TOKENIZER_SEP_ID)
+                .or_else(|_| header.get_u32(KEY_TOKENIZER_EOS_ID))?;
+            return Ok(Self::new_wpm(
+                tokens,
+                cls_token as usize,
+                sep_token as usize,
+            ));
+        }
+        let vocab = header
+            .get_str_array(KEY_TOKENIZER_LIST)?
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let eos_token = header.get_u32(KEY_TOKENIZER_EOS_ID)? as usize;
+        // some vocabularies like qwen2 have no bos token
+        let bos_token = header
+            .get_u32(KEY_TOKENIZER_BOS_ID)
+            .map_or(eos_token, |id| id as usize);
+        // the tokenizer.json is preferred over the GGML export which may be lossy
+        if let Ok(json) = header.get_str(KEY_TOKENIZER_HF_JSON) {
+            return Self::from_hf_json(json, bos_token, eos_token);
+        }
+        if let Ok("gpt2") = header.get_str(KEY_TOKENIZER_MODEL) {
+            let merges = header
+                .get_str_array(KEY_TOKENIZER_MERGES)?
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>();
+            let pre_tokenizer = header
+                .get_str(KEY_TOKENIZER_PRE)
+                .map_or(Gpt2PreTokenizer::Gpt2, Gpt2PreTokenizer::from_name);
+            let mut tokenizer = Self::new_gpt2(vocab, &merges, bos_token, eos_token);
+            if let BpeModel::Gpt2(bpe) = &mut tokenizer.model {
+                bpe.set_pre_tokenizer(pre_tokenizer);
+            }
+            return Ok(tokenizer);
+        }
+        let vocab_scores = header.get_f32_array(KEY_TOKENIZER_SCORES)?.to_vec();
+        Ok(Self::new(vocab, vocab_scores, bos_token, eos_token))
+    }
+
+    /// the types of the tokens, the control and user defined tokens are matched as a whole
+    /// by `encode_special`. the bos/eos tokens are always control tokens.
+    pub fn with_token_types(mut self, token_types: Vec<BpeTokenType>) -> Self {
+        self.set_token_types(token_types);
+        self
+    }
+
+    pub fn with_unk_token(mut self, unk_token: Option<TokenID>) -> Self {
+        self.unk_token = unk_token;
+        self
+    }
+
+    pub fn with_pad_token(mut self, pad_token: Option<TokenID>) -> Self {
+        self.pad_token = pad_token;
+        self
+    }
+
+    /// whether to prepend the bos token on encoding the prompts, it's true by default on
+    /// the SentencePiece vocabularies and false on the GPT-2 ones.
+    pub fn with_add_bos_token(mut self, add_bos_token: bool) -> Self {
+        self.add_bos_token = add_bos_token;
+        self
+    }
+
+    pub fn with_add_eos_token(mut self, add_eos_token: bool) -> Self {
+        self.add_eos_token = add_eos_token;
+        self
+    }
+
+    fn set_token_types(&mut self, mut token_types: Vec<BpeTokenType>) {
+        token_types.resize(self.tokens.len(), BpeTokenType::Normal);
+        for token in [self.bos_token, self.eos_token] {
+            if let Some(typ) = token_types.get_mut(token) {
+                *typ = BpeTokenType::Control;
+            }
+        }
+        let mut special_literals = token_types
+            .iter()
+            .enumerate()
+            .filter(|(token, typ)| {
+                matches!(typ, BpeTokenType::Control | BpeTokenType::UserDefined)
+                    && !self.tokens[*token].is_empty()
+            })
+            .map(|(token, _)| (self.tokens[token].clone(), token))
+            .collect::<Vec<_>>();
+        special_literals.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.1.cmp(&b.1)));
+        self.special_literals = special_literals;
+        self.token_types = token_types;
     }
 
     pub fn vocab(&self) -> &[String] {
@@ -52,30 +354,198 @@ impl BpeTokenizer {
         self.tokens[token_id].clone()
     }
 
+    pub fn bos_token(&self) -> TokenID {
+        self.bos_token
+    }
+
+    pub fn eos_token(&self) -> TokenID {
+        self.eos_token
+    }
+
+    pub fn unk_token(&self) -> Option<TokenID> {
+        self.unk_token
+    }
+
+    pub fn pad_token(&self) -> Option<TokenID> {
+        self.pad_token
+    }
+
+    pub fn add_bos_token(&self) -> bool {
+        self.add_bos_token
+    }
+
+    pub fn add_eos_token(&self) -> bool {
+        self.add_eos_token
+    }
+
+    pub fn token_type(&self, token_id: TokenID) -> BpeTokenType {
+        self.token_types[token_id]
+    }
+
+    /// the control tokens like bos/eos/pad, which are not a part of the text.
+    pub fn is_special(&self, token_id: TokenID) -> bool {
+        self.token_types[token_id] == BpeTokenType::Control || Some(token_id) == self.pad_token
+    }
+
+    /// the text of the token on its own, returns None for the special tokens and the byte
+    /// tokens which are a part of a multi-byte character.
+    pub fn token_text(&self, token_id: TokenID) -> Option<String> {
+        if self.is_special(token_id) {
+            return None;
+        }
+        let piece = &self.tokens[token_id];
+        match &self.model {
+            BpeModel::SentencePiece => {}
+            BpeModel::Gpt2(gpt2) => return String::from_utf8(gpt2.decode(piece)).ok(),
+            BpeModel::HuggingFace(hf) => return String::from_utf8(hf.decode(piece, false)).ok(),
+            BpeModel::Rwkv(rwkv) => return String::from_utf8(rwkv.decode(token_id).to_vec()).ok(),
+            BpeModel::Wpm(_) => {}
+        }
+        if let Some(byte) = parse_byte_token(piece) {
+            return byte.is_ascii().then(|| (byte as char).to_string());
+        }
+        Some(piece.replace('▁', " "))
+    }
+
+    /// decode the token like `decode`, and skip or render the special tokens by the mode.
+    pub fn decode_with(
+        &self,
+        prev_token: usize,
+        token: usize,
+        mode: BpeSpecialMode,
+    ) -> Result<Token> {
+        if mode == BpeSpecialMode::Skip && self.is_special(token) {
+            return Ok(String::new());
+        }
+        self.decode(prev_token, token)
+    }
+
+    /// decode the token, the special tokens are rendered into their literals. the bytes
+    /// which are not valid UTF-8 on their own like the ones of the byte fallback tokens
+    /// are replaced, use `decode_bytes` or `BpeStreamDecoder` to decode them across tokens.
     pub fn decode(&self, prev_token: usize, token: usize) -> Result<Token> {
-        let mut piece: &[u8] = self.tokens[token].as_bytes();
-        // following BOS (1) token, sentencepiece decoder strips any leading whitespace (see PR #89)
-        if prev_token == 1 && piece[0] == b' ' {
-            piece = &piece[1..];
-        }
-        // careful, some tokens designate raw bytes, and look like e.g. '<0x01>'
-        // parse this and convert and return the actual byte
-        if piece.starts_with(b"<0x") && piece[piece.len() - 1] == b'>' {
-            let s = String::from_utf8_lossy(&piece[1..piece.len() - 1]);
-            let s = s.trim_start_matches("0x");
-            if let Ok(byte) = u8::from_str_radix(s, 16) {
-                piece = &self.byte_pieces[(byte as usize)..(byte as usize) + 1]
+        let bytes = self.decode_bytes(prev_token, token)?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    /// decode the token into the raw bytes, a byte fallback token like <0xE4> is decoded
+    /// into the byte itself.
+    pub fn decode_bytes(&self, prev_token: usize, token: usize) -> Result<Vec<u8>> {
+        let piece = self.tokens.get(token).ok_or_else(|| Error {
+            kind: ErrorKind::BadInput,
+            message: format!("token {} is out of the vocabulary", token),
+            cause: None,
+        })?;
+        match &self.model {
+            BpeModel::Gpt2(gpt2) => return Ok(gpt2.decode(piece)),
+            BpeModel::HuggingFace(hf) => return Ok(hf.decode(piece, prev_token == self.bos_token)),
+            BpeModel::Rwkv(rwkv) => return Ok(rwkv.decode(token).to_vec()),
+            BpeModel::SentencePiece | BpeModel::Wpm(_) => {}
+        }
+
+        if let Some(byte) = parse_byte_token(piece) {
+            return Ok(vec![byte]);
+        }
+        // following BOS token, sentencepiece decoder strips any leading whitespace (see PR #89)
+        let mut piece = piece.as_str();
+        if prev_token == self.bos_token {
+            piece = piece.strip_prefix('▁').unwrap_or(piece);
+        }
+        Ok(piece.replace('▁', " ").into_bytes())
+    }
+
+    fn with_bos_eos(&self, mut tokens: Vec<TokenID>, bos: bool, eos: bool) -> Vec<TokenID> {
+        if bos {
+            tokens.insert(0, self.bos_token);
+        }
+        if eos {
+            tokens.push(self.eos_token);
+        }
+        tokens
+    }
+
+    /// decode a sequence of tokens into the text, the characters split across the byte
+    /// fallback tokens are decoded as a whole.
+    pub fn decode_tokens(&self, tokens: &[TokenID], mode: BpeSpecialMode) -> Result<String> {
+        let mut decoder = BpeStreamDecoder::new(mode);
+        let mut text = String::new();
+        let mut prev_token = self.bos_token;
+        for token in tokens {
+            text.push_str(&decoder.decode(self, prev_token, *token)?);
+            prev_token = *token;
+        }
+        text.push_str(&decoder.flush());
+        Ok(text)
+    }
+
+    /// encode the text like `encode`, but the literals of the special tokens in the text
+    /// like "<s>" are encoded into the special tokens instead of the plain text.
+    pub fn encode_special(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<TokenID>> {
+        let span = tracing::debug_span!(
+            "tokenize",
+            n_bytes = text.len(),
+            n_tokens = tracing::field::Empty
+        )
+        .entered();
+        let mut tokens = vec![];
+        if bos {
+            tokens.push(self.bos_token);
+        }
+        let mut rest = text;
+        while !rest.is_empty() {
+            let Some((pos, literal, token)) = self.find_special(rest) else {
+                tokens.extend(self.encode(rest, false, false)?);
+                break;
+            };
+            if pos > 0 {
+                tokens.extend(self.encode(&rest[..pos], false, false)?);
             }
+            tokens.push(token);
+            rest = &rest[pos + literal.len()..];
+        }
+        if eos {
+            tokens.push(self.eos_token);
         }
+        span.record("n_tokens", tokens.len());
+        Ok(tokens)
+    }
 
-        let mut s = String::from_utf8(piece.to_vec()).unwrap();
-        s = s.replace('▁', " ");
-        Ok(s)
+    /// find the first special literal in the text, the longer one wins on the same position.
+    fn find_special(&self, text: &str) -> Option<(usize, &str, TokenID)> {
+        let mut found: Option<(usize, &str, TokenID)> = None;
+        for (literal, token) in self.special_literals.iter() {
+            if let Some(pos) = text.find(literal.as_str()) {
+                if found.map_or(true, |(found_pos, _, _)| pos < found_pos) {
+                    found = Some((pos, literal, *token));
+                }
+            }
+        }
+        found
     }
 
     // encode the string text (input) into an upper-bound preallocated tokens[] array
     // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
     pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<TokenID>> {
+        match &self.model {
+            BpeModel::SentencePiece => {}
+            BpeModel::Gpt2(gpt2) => {
+                let pieces = gpt2.encode(text);
+                return Ok(self.encode_pieces(pieces, bos, eos));
+            }
+            BpeModel::HuggingFace(hf) => {
+                let tokens = hf.encode(text, &self.token_ids);
+                return Ok(self.with_bos_eos(tokens, bos, eos));
+            }
+            BpeModel::Rwkv(rwkv) => {
+                let tokens = rwkv.encode(text.as_bytes(), self.unk_token.unwrap_or(0));
+                return Ok(self.with_bos_eos(tokens, bos, eos));
+            }
+            BpeModel::Wpm(wpm) => {
+                let tokens = wpm.encode(text, &self.token_ids, self.unk_token.unwrap_or(0));
+                return Ok(self.with_bos_eos(tokens, bos, eos));
+            }
+        }
+
         // create a temporary buffer that will store merge candidates of always two consecutive tokens
         // *2 for concat, +1 for null terminator +2 for UTF8 (in case max_token_length is 1)
         let mut token_buf = String::with_capacity(self.token_buf_len * 2 + 1 + 2);
@@ -105,11 +575,11 @@ impl BpeTokenizer {
                 // we found this codepoint in vocab, add it as a token
                 tokens.push(*tok);
             } else {
-                // byte_fallback encoding: just encode each byte as a token
-                // +3 is here because the first 3 vocab elements are <unk>, <s>, </s>
-                // so the individual bytes only start at index 3
+                // byte_fallback encoding: encode each byte as a byte token like <0xE4>,
+                // or the unknown token if the vocabulary has no byte tokens
                 for byte in token_buf.bytes() {
-                    tokens.push(byte as usize + 3);
+                    let unk_token = self.unk_token.unwrap_or(0);
+                    tokens.push(self.byte_tokens[byte as usize].unwrap_or(unk_token));
                 }
             }
         }
@@ -152,8 +622,45 @@ impl BpeTokenizer {
     }
 }
 
+impl BpeTokenizer {
+    fn encode_pieces(&self, pieces: Vec<String>, bos: bool, eos: bool) -> Vec<TokenID> {
+        let mut tokens = vec![];
+        if bos {
+            tokens.push(self.bos_token);
+        }
+        for piece in pieces {
+            match self.token_ids.get(&piece) {
+                Some(token) => tokens.push(*token),
+                // the merged pieces are always in the vocabulary, but fall back to the
+                // characters in case of a broken vocabulary
+                None => tokens.extend(
+                    piece
+                        .chars()
+                        .filter_map(|c| self.token_ids.get(&c.to_string()).copied()),
+                ),
+            }
+        }
+        if eos {
+            tokens.push(self.eos_token);
+        }
+        tokens
+    }
+}
+
+/// parse the byte fallback token like <0x0A> into the byte.
+fn parse_byte_token(piece: &str) -> Option<u8> {
+    let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
     use super::*;
     use crate::gguf::GGUFFileLoader;
 
@@ -210,6 +717,208 @@ mod tests {
                 .join(" - ");
             assert_eq!(tokens_in_string, tt.1, "failed to encode {}", tt.0);
         }
+
+        assert_eq!(tk.token_text(10842), Some(" Captain".to_string()));
+        assert_eq!(tk.token_text(2), None);
+        assert_eq!(tk.token_text(13), Some("\n".to_string()));
+        assert_eq!(tk.token_text(200), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_tokens() -> Result<()> {
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gf_loader.open()?;
+        let metadata = gf.metadata();
+        let tokens = metadata
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let token_scores = metadata.get_f32_array("tokenizer.ggml.scores").unwrap();
+        let token_types = metadata
+            .get_i32_array("tokenizer.ggml.token_type")
+            .unwrap()
+            .iter()
+            .map(|t| BpeTokenType::from_gguf(*t))
+            .collect();
+        let tk = BpeTokenizer::new(tokens, token_scores.to_vec(), 1, 2)
+            .with_token_types(token_types)
+            .with_unk_token(Some(0));
+        assert_eq!(tk.token_type(0), BpeTokenType::Unknown);
+        assert_eq!(tk.token_type(3), BpeTokenType::Byte);
+        assert_eq!(tk.unk_token(), Some(0));
+        assert!(tk.add_bos_token());
+        assert!(!tk.add_eos_token());
+
+        let encode = |text: &str| -> Result<String> {
+            Ok(tk_join(&tk, &tk.encode_special(text, true, false)?))
+        };
+        assert_eq!(
+            encode("hello</s><s>world")?,
+            "<s> - ▁hello - </s> - <s> - ▁world"
+        );
+        assert_eq!(encode("</s>")?, "<s> - </s>");
+        assert_eq!(
+            encode("a <s")?,
+            tk_join(&tk, &tk.encode("a <s", true, false)?)
+        );
+
+        // the control tokens are skipped or rendered on decoding
+        assert_eq!(tk.decode_with(1, 2, BpeSpecialMode::Skip)?, "");
+        assert_eq!(tk.decode_with(1, 2, BpeSpecialMode::Render)?, "</s>");
+        assert_eq!(tk.decode_with(1, 10842, BpeSpecialMode::Skip)?, "Captain");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenizer_from_gguf() -> Result<()> {
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gf_loader.open()?;
+        let tk = BpeTokenizer::from_gguf(&gf)?;
+        assert_eq!(tk.vocab().len(), 32000);
+        assert_eq!((tk.bos_token(), tk.eos_token()), (1, 2));
+        assert_eq!(tk.token_type(0), BpeTokenType::Unknown);
+        assert_eq!(tk.unk_token(), Some(0));
+
+        let tokens = tk.encode_special("Hello 🦀</s>", true, false)?;
+        assert_eq!(tokens[..2], [1, 15043]);
+        assert_eq!(tk.decode_tokens(&tokens, BpeSpecialMode::Skip)?, "Hello 🦀");
+        assert_eq!(
+            tk.decode_tokens(&tokens, BpeSpecialMode::Render)?,
+            "<s>Hello 🦀</s>"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_span() -> Result<()> {
+        #[derive(Clone, Default)]
+        struct TestWriter(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for TestWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gf_loader.open()?;
+        let tk = BpeTokenizer::from_gguf(&gf)?;
+
+        let writer = TestWriter::default();
+        let make_writer = writer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(move || make_writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || tk.encode_special("Hello", true, false))?;
+
+        let logs = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("tokenize{n_bytes=5 n_tokens=2}: "),
+            "{}",
+            logs
+        );
+        assert!(logs.contains("close time.busy="), "{}", logs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rwkv_tokenizer() -> Result<()> {
+        let src = "1 'h' 1\n2 'i' 1\n3 ' ' 1\n4 'hi' 2\n5 b'\\xe4\\xb8' 2\n6 b'\\x96' 1\n";
+        let tokens = parse_world_vocab(src)?;
+        let tk = BpeTokenizer::new_rwkv(tokens, 0, 0);
+        assert_eq!(tk.token(0), "<s>");
+        assert!(!tk.add_bos_token());
+
+        let tokens = tk.encode_special("hi hi<s>世", false, false)?;
+        assert_eq!(tokens, vec![4, 3, 4, 0, 5, 6]);
+        assert_eq!(tk.decode_tokens(&tokens, BpeSpecialMode::Skip)?, "hi hi世");
+        assert_eq!(tk.token_text(4), Some("hi".to_string()));
+        assert_eq!(tk.token_text(5), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wpm_tokenizer() -> Result<()> {
+        let tokens = [
+            "[PAD]", "[UNK]", "[CLS]", "[SEP]", "▁hello", "▁crab", "s", "▁!",
+        ]
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+        let tk = BpeTokenizer::new_wpm(tokens, 2, 3).with_unk_token(Some(1));
+        let tokens = tk.encode("Hello crabs! hi", tk.add_bos_token(), tk.add_eos_token())?;
+        assert_eq!(tokens, vec![2, 4, 5, 6, 7, 1, 3]);
+        assert_eq!(
+            tk.decode_tokens(&tokens[1..5], BpeSpecialMode::Skip)?,
+            "hello crabs !"
+        );
+        Ok(())
+    }
+
+    fn tk_join(tk: &BpeTokenizer, tokens: &[usize]) -> String {
+        tokens
+            .iter()
+            .map(|t| tk.vocab()[*t].clone())
+            .collect::<Vec<_>>()
+            .join(" - ")
+    }
+
+    #[test]
+    fn test_gpt2_tokenizer() -> Result<()> {
+        let tokens = [
+            "<|endoftext|>",
+            "h",
+            "e",
+            "l",
+            "o",
+            "Ġ",
+            "w",
+            "r",
+            "d",
+            "he",
+            "ll",
+            "hell",
+            "hello",
+            "Ġw",
+            "or",
+            "Ġwor",
+            "ld",
+            "Ã",
+            "©",
+        ]
+        .map(String::from)
+        .to_vec();
+        let merges = [
+            "h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "l d",
+        ]
+        .map(String::from)
+        .to_vec();
+        let tk = BpeTokenizer::new_gpt2(tokens, &merges, 0, 0);
+
+        let tokens = tk.encode("hello world", false, true)?;
+        assert_eq!(tokens, vec![12, 15, 16, 0]);
+        let text = tokens[..3]
+            .iter()
+            .map(|t| tk.decode(0, *t))
+            .collect::<Result<String>>()?;
+        assert_eq!(text, "hello world");
+        assert_eq!(tk.token_text(15), Some(" wor".to_string()));
+        assert_eq!(tk.token_text(0), None);
+
+        // the bytes of a multi-byte character are not valid on their own
+        assert_eq!(tk.encode("é", false, false)?, vec![17, 18]);
+        assert_eq!(tk.token_text(17), None);
         Ok(())
     }
 }
@@ -0,0 +1,127 @@
+use super::bpe::BpeSpecialMode;
+use super::BpeTokenizer;
+use crate::error::Result;
+
+/// the streaming detokenizer, which decodes the tokens one by one and buffers the bytes
+/// of an incomplete UTF-8 sequence until the following tokens complete it. a multi-byte
+/// character like an emoji or a CJK character may be split into several byte fallback
+/// tokens, decoding them one by one gives the replacement characters.
+pub struct BpeStreamDecoder {
+    mode: BpeSpecialMode,
+    buf: Vec<u8>,
+}
+
+impl BpeStreamDecoder {
+    pub fn new(mode: BpeSpecialMode) -> Self {
+        Self { mode, buf: vec![] }
+    }
+
+    /// decode the token, returns the text which is complete so far, which may be empty
+    /// if the token ends in the middle of a character.
+    pub fn decode(
+        &mut self,
+        tokenizer: &BpeTokenizer,
+        prev_token: usize,
+        token: usize,
+    ) -> Result<String> {
+        if self.mode == BpeSpecialMode::Skip && tokenizer.is_special(token) {
+            return Ok(String::new());
+        }
+        let bytes = tokenizer.decode_bytes(prev_token, token)?;
+        self.buf.extend_from_slice(&bytes);
+        Ok(self.take_complete())
+    }
+
+    /// the bytes held back which are not a complete character yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// take the bytes held back on the end of the stream, the incomplete character is
+    /// replaced.
+    pub fn flush(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.buf).to_string();
+        self.buf.clear();
+        text
+    }
+
+    fn take_complete(&mut self) -> String {
+        let mut text = String::new();
+        loop {
+            let err = match std::str::from_utf8(&self.buf) {
+                Ok(s) => {
+                    text.push_str(s);
+                    self.buf.clear();
+                    return text;
+                }
+                Err(err) => err,
+            };
+            let valid = err.valid_up_to();
+            text.push_str(std::str::from_utf8(&self.buf[..valid]).unwrap());
+            match err.error_len() {
+                // the sequence is incomplete at the end, wait for the following bytes
+                None => {
+                    self.buf.drain(..valid);
+                    return text;
+                }
+                // the invalid bytes can never be completed, replace them
+                Some(len) => {
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    self.buf.drain(..valid + len);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_stream_decoder() -> Result<()> {
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gf_loader.open()?;
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let token_scores = gf
+            .metadata()
+            .get_f32_array("tokenizer.ggml.scores")
+            .unwrap();
+        let tk = BpeTokenizer::new(tokens, token_scores.to_vec(), 1, 2);
+
+        // the emoji is not in the vocabulary, and falls back to the 4 byte tokens
+        let tokens = tk.encode("hi 🦀", false, false)?;
+        let byte_tokens = &tokens[tokens.len() - 4..];
+        assert_eq!(byte_tokens, &[0xF0 + 3, 0x9F + 3, 0xA6 + 3, 0x80 + 3]);
+        assert_eq!(tk.token(byte_tokens[0]), "<0xF0>");
+        assert_eq!(tk.decode(1, byte_tokens[0])?, "\u{FFFD}");
+
+        let mut decoder = BpeStreamDecoder::new(BpeSpecialMode::Skip);
+        let mut texts = vec![];
+        let mut prev_token = 1;
+        for token in tokens.iter().chain([2].iter()) {
+            texts.push(decoder.decode(&tk, prev_token, *token)?);
+            prev_token = *token;
+        }
+        assert_eq!(texts.concat(), "hi 🦀");
+        assert_eq!(&texts[texts.len() - 5..], &["", "", "", "🦀", ""]);
+        assert!(decoder.pending().is_empty());
+
+        // the incomplete character is replaced on flushing
+        assert_eq!(decoder.decode(&tk, 1, byte_tokens[0])?, "");
+        assert_eq!(decoder.pending(), &[0xF0]);
+        assert_eq!(decoder.flush(), "\u{FFFD}");
+
+        // the bytes which can never be completed are replaced at once
+        assert_eq!(decoder.decode(&tk, 1, byte_tokens[3])?, "\u{FFFD}");
+        assert!(decoder.pending().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+/// the byte-level BPE of GPT-2, used by the vocabularies of GPT-2, Falcon, Qwen and so
+/// on. the text is split into words by the pre-tokenizer, then the bytes of each word are
+/// mapped into printable unicode characters and merged by the ranks of the merges.
+pub struct Gpt2Bpe {
+    // the rank of the merge of a pair, the lower merges first
+    merges: HashMap<(String, String), usize>,
+    byte_encoder: [char; 256],
+    byte_decoder: HashMap<char, u8>,
+    pre_tokenizer: Gpt2PreTokenizer,
+}
+
+/// the regex to split the text into words before merging, by `tokenizer.ggml.pre`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Gpt2PreTokenizer {
+    #[default]
+    Gpt2,
+    Qwen2,
+}
+
+impl Gpt2PreTokenizer {
+    /// the unknown names fall back to the regex of GPT-2.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "qwen2" => Self::Qwen2,
+            _ => Self::Gpt2,
+        }
+    }
+}
+
+impl Gpt2Bpe {
+    /// the merges are in the format of `tokenizer.ggml.merges`, like "Ġ t".
+    pub fn new(merges: &[String]) -> Self {
+        let merges = merges
+            .iter()
+            .filter_map(|merge| {
+                let (a, b) = merge.split_once(' ')?;
+                Some((a.to_string(), b.to_string()))
+            })
+            .collect();
+        Self::from_pairs(merges)
+    }
+
+    pub fn from_pairs(merges: Vec<(String, String)>) -> Self {
+        let merges = merges
+            .into_iter()
+            .enumerate()
+            .map(|(rank, pair)| (pair, rank))
+            .collect();
+        let byte_encoder = bytes_to_unicode();
+        let byte_decoder = byte_encoder
+            .iter()
+            .enumerate()
+            .map(|(b, c)| (*c, b as u8))
+            .collect();
+        Self {
+            merges,
+            byte_encoder,
+            byte_decoder,
+            pre_tokenizer: Gpt2PreTokenizer::Gpt2,
+        }
+    }
+
+    pub fn set_pre_tokenizer(&mut self, pre_tokenizer: Gpt2PreTokenizer) {
+        self.pre_tokenizer = pre_tokenizer;
+    }
+
+    /// split the text into the pieces of the tokens, which are looked up in the vocabulary
+    /// by the caller.
+    pub fn encode(&self, text: &str) -> Vec<String> {
+        let words = match self.pre_tokenizer {
+            Gpt2PreTokenizer::Gpt2 => pre_tokenize(text),
+            Gpt2PreTokenizer::Qwen2 => pre_tokenize_qwen2(text),
+        };
+        words
+            .into_iter()
+            .flat_map(|word| self.bpe(&self.byte_encode(word)))
+            .collect()
+    }
+
+    /// map the bytes of the text into the printable characters.
+    pub(super) fn byte_encode(&self, text: &str) -> String {
+        text.bytes()
+            .map(|b| self.byte_encoder[b as usize])
+            .collect()
+    }
+
+    /// the raw bytes of a token, the characters not in the byte mapping like the ones of
+    /// the special tokens are kept as they are.
+    pub fn decode(&self, piece: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        for c in piece.chars() {
+            match self.byte_decoder.get(&c) {
+                Some(b) => bytes.push(*b),
+                None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        bytes
+    }
+
+    /// merge the characters of the word by the ranks of the merges.
+    pub(super) fn bpe(&self, word: &str) -> Vec<String> {
+        let mut symbols = word.chars().map(|c| c.to_string()).collect::<Vec<_>>();
+        loop {
+            // find the pair with the lowest rank
+            let best = symbols
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    let rank = self.merges.get(&(pair[0].clone(), pair[1].clone()))?;
+                    Some((*rank, i))
+                })
+                .min();
+            let Some((_, i)) = best else {
+                break;
+            };
+            let merged = symbols.remove(i + 1);
+            symbols[i].push_str(&merged);
+        }
+        symbols
+    }
+}
+
+/// the mapping of GPT-2 from the bytes to the printable unicode characters, the printable
+/// ASCII and latin-1 bytes are mapped to themselves, the others are shifted after 255.
+fn bytes_to_unicode() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        chars[b as usize] = if printable {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    chars
+}
+
+/// split the text like the regex of GPT-2:
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+`
+pub(super) fn pre_tokenize(text: &str) -> Vec<&str> {
+    #[derive(PartialEq)]
+    enum Class {
+        Letter,
+        Number,
+        Space,
+        Other,
+    }
+    let class = |c: char| {
+        if c.is_alphabetic() {
+            Class::Letter
+        } else if c.is_numeric() {
+            Class::Number
+        } else if c.is_whitespace() {
+            Class::Space
+        } else {
+            Class::Other
+        }
+    };
+
+    let chars = text.char_indices().collect::<Vec<_>>();
+    let offset = |i: usize| chars.get(i).map_or(text.len(), |(pos, _)| *pos);
+    let mut words = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i].1;
+
+        // the contractions
+        if c == '\'' {
+            let rest = &text[offset(i + 1)..];
+            let suffix = ["s", "t", "re", "ve", "m", "ll", "d"]
+                .iter()
+                .find(|s| rest.starts_with(*s));
+            if let Some(suffix) = suffix {
+                i += 1 + suffix.len();
+                words.push(&text[offset(start)..offset(i)]);
+                continue;
+            }
+        }
+
+        // the letters, numbers or others with an optional leading space
+        let first = if c == ' ' && i + 1 < chars.len() {
+            i + 1
+        } else {
+            i
+        };
+        let first_class = class(chars[first].1);
+        if first_class != Class::Space {
+            i = first + 1;
+            while i < chars.len() && class(chars[i].1) == first_class {
+                i += 1;
+            }
+            words.push(&text[offset(start)..offset(i)]);
+            continue;
+        }
+
+        // the spaces, leave the last space to the next word if it's followed by a non-space
+        while i < chars.len() && class(chars[i].1) == Class::Space {
+            i += 1;
+        }
+        if i < chars.len() && i - start > 1 {
+            i -= 1;
+        }
+        words.push(&text[offset(start)..offset(i)]);
+    }
+    words
+}
+
+/// split the text like the regex of Qwen2, which keeps the punctuations before the letters
+/// and the newlines after the punctuations in the words, and splits the digits one by one:
+/// `(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+`
+pub(super) fn pre_tokenize_qwen2(text: &str) -> Vec<&str> {
+    let is_newline = |c: char| c == '\r' || c == '\n';
+    let is_other = |c: char| !c.is_alphabetic() && !c.is_numeric() && !c.is_whitespace();
+
+    let chars = text.char_indices().collect::<Vec<_>>();
+    let offset = |i: usize| chars.get(i).map_or(text.len(), |(pos, _)| *pos);
+    let char_at = |i: usize| chars.get(i).map(|(_, c)| *c);
+    let mut words = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i].1;
+        let next = char_at(i + 1);
+
+        // the contractions, in any case
+        if c == '\'' {
+            let rest = text[offset(i + 1)..].to_lowercase();
+            let suffix = ["s", "t", "re", "ve", "m", "ll", "d"]
+                .iter()
+                .find(|s| rest.starts_with(*s));
+            if let Some(suffix) = suffix {
+                i += 1 + suffix.len();
+                words.push(&text[offset(start)..offset(i)]);
+                continue;
+            }
+        }
+
+        // the letters with an optional leading character which is not a newline, letter or number
+        let leading = !is_newline(c) && !c.is_alphabetic() && !c.is_numeric();
+        if c.is_alphabetic() || (leading && next.is_some_and(|c| c.is_alphabetic())) {
+            i += 1;
+            while char_at(i).is_some_and(|c| c.is_alphabetic()) {
+                i += 1;
+            }
+            words.push(&text[offset(start)..offset(i)]);
+            continue;
+        }
+
+        // a single digit
+        if c.is_numeric() {
+            i += 1;
+            words.push(&text[offset(start)..offset(i)]);
+            continue;
+        }
+
+        // the punctuations with an optional leading space and the trailing newlines
+        let first = if c == ' ' { i + 1 } else { i };
+        if char_at(first).is_some_and(is_other) {
+            i = first;
+            while char_at(i).is_some_and(is_other) {
+                i += 1;
+            }
+            while char_at(i).is_some_and(is_newline) {
+                i += 1;
+            }
+            words.push(&text[offset(start)..offset(i)]);
+            continue;
+        }
+
+        // the spaces until the last newline, or leave the last space to the next word if it's
+        // followed by a non-space
+        let mut end = i;
+        while char_at(end).is_some_and(|c| c.is_whitespace()) {
+            end += 1;
+        }
+        i = match (start..end).rev().find(|j| is_newline(chars[*j].1)) {
+            Some(last_newline) => last_newline + 1,
+            None if end < chars.len() && end - start > 1 => end - 1,
+            None => end,
+        };
+        words.push(&text[offset(start)..offset(i)]);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pre_tokenize() {
+        assert_eq!(pre_tokenize("Hello world's  big\n\nnew 123!! ok "), vec![
+            "Hello", " world", "'s", " ", " big", "\n", "\n", "new", " 123", "!!", " ok", " "
+        ]);
+        assert_eq!(pre_tokenize("I'm   "), vec!["I", "'m", "   "]);
+        assert_eq!(pre_tokenize("héllo, 世界"), vec!["héllo", ",", " 世界"]);
+        assert_eq!(pre_tokenize(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_pre_tokenize_qwen2() {
+        assert_eq!(
+            pre_tokenize_qwen2("Hello world's  big\n\nnew 123!! ok "),
+            vec![
+                "Hello", " world", "'s", " ", " big", "\n\n", "new", " ", "1", "2", "3", "!!",
+                " ok", " "
+            ]
+        );
+        assert_eq!(pre_tokenize_qwen2("I'M fine.\n(yes)"), vec![
+            "I", "'M", " fine", ".\n", "(yes", ")"
+        ]);
+        assert_eq!(pre_tokenize_qwen2("a  \n  b"), vec!["a", "  \n", " ", " b"]);
+        assert_eq!(pre_tokenize_qwen2(" \n\n x\t1"), vec![
+            " \n\n", " x", "\t", "1"
+        ]);
+        assert_eq!(pre_tokenize_qwen2("héllo, 世界"), vec![
+            "héllo", ",", " 世界"
+        ]);
+        assert_eq!(pre_tokenize_qwen2(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_gpt2_bpe() {
+        let merges = [
+            "h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "l d",
+        ]
+        .map(String::from)
+        .to_vec();
+        let bpe = Gpt2Bpe::new(&merges);
+        assert_eq!(bpe.byte_encoder[b' ' as usize], 'Ġ');
+        assert_eq!(bpe.byte_encoder[b'\n' as usize], 'Ċ');
+        assert_eq!(bpe.encode("hello world"), vec!["hello", "Ġwor", "ld"]);
+        assert_eq!(bpe.encode("hello\n"), vec!["hello", "Ċ"]);
+
+        // the multi-byte characters are split into the bytes
+        let pieces = bpe.encode(" é");
+        assert_eq!(pieces, vec!["Ġ", "Ã", "©"]);
+        let bytes = pieces
+            .iter()
+            .flat_map(|p| bpe.decode(p))
+            .collect::<Vec<_>>();
+        assert_eq!(String::from_utf8(bytes).unwrap(), " é");
+        assert_eq!(bpe.decode("<|endoftext|>"), b"<|endoftext|>");
+    }
+}
@@ -0,0 +1,743 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde_json::Value;
+
+use super::bpe::BpeTokenType;
+use super::gpt2::pre_tokenize;
+use super::gpt2::Gpt2Bpe;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the BPE tokenizer built from the `tokenizer.json` of HuggingFace, which runs the same
+/// pipeline as the `tokenizers` library: the normalizers, the pre-tokenizers, the merges of
+/// the BPE model, and the decoders on decoding.
+pub struct HfTokenizer {
+    normalizers: Vec<HfNormalizer>,
+    pre_tokenizers: Vec<HfPreTokenizer>,
+    decoders: Vec<HfDecoder>,
+    bpe: Gpt2Bpe,
+    byte_fallback: bool,
+    ignore_merges: bool,
+    unk_token: Option<String>,
+}
+
+enum HfNormalizer {
+    Prepend(String),
+    Replace(HfPattern, String),
+    Lowercase,
+    Strip { left: bool, right: bool },
+}
+
+enum HfPreTokenizer {
+    ByteLevel {
+        add_prefix_space: bool,
+        use_regex: bool,
+    },
+    Metaspace {
+        replacement: char,
+        prepend: HfPrependScheme,
+        split: bool,
+    },
+    Split {
+        pattern: HfPattern,
+        remove_matches: bool,
+    },
+    Digits {
+        individual: bool,
+    },
+}
+
+#[derive(PartialEq)]
+enum HfPrependScheme {
+    Always,
+    First,
+    Never,
+}
+
+enum HfDecoder {
+    Replace(String, String),
+    ByteFallback,
+    Fuse,
+    Strip {
+        content: char,
+        start: usize,
+        stop: usize,
+    },
+    ByteLevel,
+    Metaspace {
+        replacement: char,
+        prepend: bool,
+    },
+}
+
+enum HfPattern {
+    String(String),
+    // the regex crate does not support the look-around, `\s+(?!\S)` is rewritten into
+    // an alternative with the group `lookahead`, and only the group is taken as the match
+    Regex(Regex),
+}
+
+/// the vocabulary in the tokenizer.json.
+pub struct HfVocab {
+    /// the tokens ordered by the ids, including the added tokens.
+    pub tokens: Vec<String>,
+    /// the special added tokens are the control tokens, the others are user defined.
+    pub token_types: Vec<BpeTokenType>,
+    pub unk_token: Option<usize>,
+    /// whether the post processor prepends a special token like bos.
+    pub add_bos_token: bool,
+}
+
+impl HfTokenizer {
+    /// parse the tokenizer.json, returns the tokenizer and the vocabulary.
+    pub fn from_json(json: &str) -> Result<(Self, HfVocab)> {
+        let root: Value = serde_json::from_str(json).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "failed to parse tokenizer.json".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+
+        let model = &root["model"];
+        if model["type"].as_str().unwrap_or("BPE") != "BPE" {
+            return Err(not_implemented("model", &model["type"]));
+        }
+        for key in ["continuing_subword_prefix", "end_of_word_suffix"] {
+            if !model[key].is_null() && model[key] != "" {
+                return Err(not_implemented("model option", &Value::from(key)));
+            }
+        }
+
+        let mut tokens = vec![];
+        let mut token_types = vec![];
+        let mut add_token = |id: Option<u64>, token: &str, typ: BpeTokenType| -> Result<()> {
+            let id = id.ok_or_else(|| format_error("the id of a token is not an integer"))?;
+            let id = id as usize;
+            if id >= tokens.len() {
+                tokens.resize(id + 1, String::new());
+                token_types.resize(id + 1, BpeTokenType::Normal);
+            }
+            tokens[id] = token.to_string();
+            token_types[id] = typ;
+            Ok(())
+        };
+        let vocab = model["vocab"]
+            .as_object()
+            .ok_or_else(|| format_error("model.vocab is not an object"))?;
+        for (token, id) in vocab {
+            add_token(id.as_u64(), token, BpeTokenType::Normal)?;
+        }
+        for added in root["added_tokens"].as_array().into_iter().flatten() {
+            let content = added["content"]
+                .as_str()
+                .ok_or_else(|| format_error("the content of an added token is not a string"))?;
+            let typ = if added["special"].as_bool().unwrap_or(false) {
+                BpeTokenType::Control
+            } else {
+                BpeTokenType::UserDefined
+            };
+            add_token(added["id"].as_u64(), content, typ)?;
+        }
+        let unk_token = model["unk_token"]
+            .as_str()
+            .and_then(|unk| tokens.iter().position(|t| t == unk));
+        let vocab = HfVocab {
+            tokens,
+            token_types,
+            unk_token,
+            add_bos_token: prepends_special_token(&root["post_processor"]),
+        };
+
+        let mut merges = vec![];
+        for merge in model["merges"].as_array().into_iter().flatten() {
+            let pair = match merge {
+                Value::String(merge) => merge
+                    .split_once(' ')
+                    .map(|(a, b)| (a.to_string(), b.to_string())),
+                Value::Array(pair) => match (pair.first(), pair.get(1)) {
+                    (Some(Value::String(a)), Some(Value::String(b))) => {
+                        Some((a.clone(), b.clone()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            merges.push(pair.ok_or_else(|| format_error("invalid merge"))?);
+        }
+
+        let mut normalizers = vec![];
+        parse_normalizer(&root["normalizer"], &mut normalizers)?;
+        let mut pre_tokenizers = vec![];
+        parse_pre_tokenizer(&root["pre_tokenizer"], &mut pre_tokenizers)?;
+        let mut decoders = vec![];
+        parse_decoder(&root["decoder"], &mut decoders)?;
+
+        let tokenizer = Self {
+            normalizers,
+            pre_tokenizers,
+            decoders,
+            bpe: Gpt2Bpe::from_pairs(merges),
+            byte_fallback: model["byte_fallback"].as_bool().unwrap_or(false),
+            ignore_merges: model["ignore_merges"].as_bool().unwrap_or(false),
+            unk_token: model["unk_token"].as_str().map(|s| s.to_string()),
+        };
+        Ok((tokenizer, vocab))
+    }
+
+    pub fn encode(&self, text: &str, token_ids: &HashMap<String, usize>) -> Vec<usize> {
+        let mut text = text.to_string();
+        for normalizer in self.normalizers.iter() {
+            text = normalizer.normalize(&text);
+        }
+        let mut pieces = vec![text];
+        for pre_tokenizer in self.pre_tokenizers.iter() {
+            pieces = pre_tokenizer.pre_tokenize(pieces, &self.bpe);
+        }
+
+        let mut tokens = vec![];
+        for piece in pieces.iter().filter(|p| !p.is_empty()) {
+            if self.ignore_merges {
+                if let Some(token) = token_ids.get(piece) {
+                    tokens.push(*token);
+                    continue;
+                }
+            }
+            for symbol in self.bpe.bpe(piece) {
+                if let Some(token) = token_ids.get(&symbol) {
+                    tokens.push(*token);
+                    continue;
+                }
+                let byte_tokens = symbol
+                    .bytes()
+                    .map(|b| token_ids.get(&format!("<0x{:02X}>", b)).copied())
+                    .collect::<Option<Vec<_>>>();
+                match byte_tokens {
+                    Some(byte_tokens) if self.byte_fallback => tokens.extend(byte_tokens),
+                    _ => tokens.extend(
+                        self.unk_token
+                            .as_ref()
+                            .and_then(|unk| token_ids.get(unk).copied()),
+                    ),
+                }
+            }
+        }
+        tokens
+    }
+
+    /// decode a token into the bytes, `is_first` is true on the first token of the output,
+    /// where the decoders like `Strip` after `Fuse` take effect.
+    pub fn decode(&self, piece: &str, is_first: bool) -> Vec<u8> {
+        let mut bytes = piece.as_bytes().to_vec();
+        let mut fused = false;
+        for decoder in self.decoders.iter() {
+            match decoder {
+                HfDecoder::Replace(pattern, content) => {
+                    bytes = replace_bytes(&bytes, pattern.as_bytes(), content.as_bytes());
+                }
+                HfDecoder::ByteFallback => {
+                    if let Some(byte) = parse_byte_token(&bytes) {
+                        bytes = vec![byte];
+                    }
+                }
+                HfDecoder::Fuse => fused = true,
+                HfDecoder::Strip {
+                    content,
+                    start,
+                    stop,
+                } => {
+                    // the fused output is stripped once on its beginning
+                    let mut buf = [0; 4];
+                    let content = content.encode_utf8(&mut buf).as_bytes();
+                    if !fused || is_first {
+                        for _ in 0..*start {
+                            if !bytes.starts_with(content) {
+                                break;
+                            }
+                            bytes.drain(..content.len());
+                        }
+                    }
+                    if !fused {
+                        for _ in 0..*stop {
+                            if !bytes.ends_with(content) {
+                                break;
+                            }
+                            bytes.truncate(bytes.len() - content.len());
+                        }
+                    }
+                }
+                HfDecoder::ByteLevel => {
+                    bytes = self.bpe.decode(&String::from_utf8_lossy(&bytes));
+                }
+                HfDecoder::Metaspace {
+                    replacement,
+                    prepend,
+                } => {
+                    let mut buf = [0; 4];
+                    let replacement = replacement.encode_utf8(&mut buf).as_bytes();
+                    bytes = replace_bytes(&bytes, replacement, b" ");
+                    if *prepend && is_first && bytes.starts_with(b" ") {
+                        bytes.remove(0);
+                    }
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl HfNormalizer {
+    fn normalize(&self, text: &str) -> String {
+        match self {
+            HfNormalizer::Prepend(prefix) if !text.is_empty() => format!("{}{}", prefix, text),
+            HfNormalizer::Prepend(_) => text.to_string(),
+            HfNormalizer::Replace(HfPattern::String(pattern), content) => {
+                text.replace(pattern.as_str(), content)
+            }
+            HfNormalizer::Replace(HfPattern::Regex(regex), content) => {
+                regex.replace_all(text, content.as_str()).to_string()
+            }
+            HfNormalizer::Lowercase => text.to_lowercase(),
+            HfNormalizer::Strip { left, right } => {
+                let text = if *left { text.trim_start() } else { text };
+                let text = if *right { text.trim_end() } else { text };
+                text.to_string()
+            }
+        }
+    }
+}
+
+impl HfPreTokenizer {
+    fn pre_tokenize(&self, pieces: Vec<String>, bpe: &Gpt2Bpe) -> Vec<String> {
+        let mut output = vec![];
+        for (i, piece) in pieces.into_iter().enumerate() {
+            match self {
+                HfPreTokenizer::ByteLevel {
+                    add_prefix_space,
+                    use_regex,
+                } => {
+                    let piece = if *add_prefix_space && !piece.starts_with(' ') {
+                        format!(" {}", piece)
+                    } else {
+                        piece
+                    };
+                    if *use_regex {
+                        output.extend(pre_tokenize(&piece).iter().map(|w| bpe.byte_encode(w)));
+                    } else {
+                        output.push(bpe.byte_encode(&piece));
+                    }
+                }
+                HfPreTokenizer::Metaspace {
+                    replacement,
+                    prepend,
+                    split,
+                } => {
+                    let mut piece = piece.replace(' ', &replacement.to_string());
+                    let need_prepend = match prepend {
+                        HfPrependScheme::Always => true,
+                        HfPrependScheme::First => i == 0,
+                        HfPrependScheme::Never => false,
+                    };
+                    if need_prepend && !piece.starts_with(*replacement) {
+                        piece.insert(0, *replacement);
+                    }
+                    if !*split {
+                        output.push(piece);
+                        continue;
+                    }
+                    // split before each replacement, which is merged with the next word
+                    let mut start = 0;
+                    for (pos, _) in piece.match_indices(*replacement) {
+                        if pos > start {
+                            output.push(piece[start..pos].to_string());
+                        }
+                        start = pos;
+                    }
+                    output.push(piece[start..].to_string());
+                }
+                HfPreTokenizer::Split {
+                    pattern,
+                    remove_matches,
+                } => {
+                    let mut start = 0;
+                    for (match_start, match_end) in pattern.find_all(&piece) {
+                        if match_start > start {
+                            output.push(piece[start..match_start].to_string());
+                        }
+                        if !*remove_matches {
+                            output.push(piece[match_start..match_end].to_string());
+                        }
+                        start = match_end;
+                    }
+                    if start < piece.len() {
+                        output.push(piece[start..].to_string());
+                    }
+                }
+                HfPreTokenizer::Digits { individual } => {
+                    let mut word = String::new();
+                    let mut prev_digit = None;
+                    for c in piece.chars() {
+                        let is_digit = c.is_ascii_digit();
+                        let split = match prev_digit {
+                            Some(prev) => prev != is_digit || (is_digit && *individual),
+                            None => false,
+                        };
+                        if split {
+                            output.push(std::mem::take(&mut word));
+                        }
+                        word.push(c);
+                        prev_digit = Some(is_digit);
+                    }
+                    if !word.is_empty() {
+                        output.push(word);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+impl HfPattern {
+    fn parse(value: &Value) -> Result<Self> {
+        if let Some(pattern) = value["String"].as_str() {
+            return Ok(HfPattern::String(pattern.to_string()));
+        }
+        let Some(pattern) = value["Regex"].as_str() else {
+            return Err(format_error("invalid pattern"));
+        };
+        let rewritten = pattern.replace(r"\s+(?!\S)", r"(?:\s+\z|(?P<lookahead>\s+)\s)");
+        let regex = Regex::new(&rewritten).map_err(|err| Error {
+            kind: ErrorKind::NotImplemented,
+            message: format!("unsupported regex in tokenizer.json: {}", pattern),
+            cause: Some(Box::new(err)),
+        })?;
+        Ok(HfPattern::Regex(regex))
+    }
+
+    /// the (start, end) of all the non-overlapping matches.
+    fn find_all(&self, text: &str) -> Vec<(usize, usize)> {
+        match self {
+            HfPattern::String(pattern) if pattern.is_empty() => vec![],
+            HfPattern::String(pattern) => text
+                .match_indices(pattern.as_str())
+                .map(|(pos, m)| (pos, pos + m.len()))
+                .collect(),
+            HfPattern::Regex(regex) => {
+                let mut matches = vec![];
+                let mut pos = 0;
+                while pos < text.len() {
+                    let Some(caps) = regex.captures_at(text, pos) else {
+                        break;
+                    };
+                    let m = caps
+                        .name("lookahead")
+                        .unwrap_or_else(|| caps.get(0).unwrap());
+                    if m.end() == m.start() {
+                        // skip the empty match by a character
+                        pos = m.end() + text[m.end()..].chars().next().map_or(1, char::len_utf8);
+                        continue;
+                    }
+                    matches.push((m.start(), m.end()));
+                    pos = m.end();
+                }
+                matches
+            }
+        }
+    }
+}
+
+/// whether the post processor `TemplateProcessing` starts with a special token.
+fn prepends_special_token(processor: &Value) -> bool {
+    match processor["type"].as_str() {
+        Some("TemplateProcessing") => processor["single"]
+            .as_array()
+            .and_then(|single| single.first())
+            .map_or(false, |piece| !piece["SpecialToken"].is_null()),
+        Some("Sequence") => processor["processors"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(prepends_special_token),
+        _ => false,
+    }
+}
+
+fn parse_normalizer(value: &Value, normalizers: &mut Vec<HfNormalizer>) -> Result<()> {
+    let normalizer = match value["type"].as_str() {
+        None if value.is_null() => return Ok(()),
+        Some("Sequence") => {
+            for value in value["normalizers"].as_array().into_iter().flatten() {
+                parse_normalizer(value, normalizers)?;
+            }
+            return Ok(());
+        }
+        // the text is assumed to be composed already
+        Some("NFC") => return Ok(()),
+        Some("Prepend") => HfNormalizer::Prepend(str_field(value, "prepend")?),
+        Some("Replace") => HfNormalizer::Replace(
+            HfPattern::parse(&value["pattern"])?,
+            str_field(value, "content")?,
+        ),
+        Some("Lowercase") => HfNormalizer::Lowercase,
+        Some("Strip") => HfNormalizer::Strip {
+            left: value["strip_left"].as_bool().unwrap_or(false),
+            right: value["strip_right"].as_bool().unwrap_or(false),
+        },
+        _ => return Err(not_implemented("normalizer", &value["type"])),
+    };
+    normalizers.push(normalizer);
+    Ok(())
+}
+
+fn parse_pre_tokenizer(value: &Value, pre_tokenizers: &mut Vec<HfPreTokenizer>) -> Result<()> {
+    let pre_tokenizer = match value["type"].as_str() {
+        None if value.is_null() => return Ok(()),
+        Some("Sequence") => {
+            for value in value["pretokenizers"].as_array().into_iter().flatten() {
+                parse_pre_tokenizer(value, pre_tokenizers)?;
+            }
+            return Ok(());
+        }
+        Some("ByteLevel") => HfPreTokenizer::ByteLevel {
+            add_prefix_space: value["add_prefix_space"].as_bool().unwrap_or(true),
+            use_regex: value["use_regex"].as_bool().unwrap_or(true),
+        },
+        Some("Metaspace") => {
+            let add_prefix_space = value["add_prefix_space"].as_bool().unwrap_or(true);
+            let prepend = match value["prepend_scheme"].as_str() {
+                Some("always") => HfPrependScheme::Always,
+                Some("first") => HfPrependScheme::First,
+                Some("never") => HfPrependScheme::Never,
+                _ if add_prefix_space => HfPrependScheme::Always,
+                _ => HfPrependScheme::Never,
+            };
+            HfPreTokenizer::Metaspace {
+                replacement: char_field(value, "replacement")?,
+                prepend,
+                split: value["split"].as_bool().unwrap_or(true),
+            }
+        }
+        Some("Split") => {
+            if value["invert"].as_bool().unwrap_or(false) {
+                return Err(not_implemented("split option", &Value::from("invert")));
+            }
+            let remove_matches = match value["behavior"].as_str() {
+                Some("Isolated") => false,
+                Some("Removed") => true,
+                _ => return Err(not_implemented("split behavior", &value["behavior"])),
+            };
+            HfPreTokenizer::Split {
+                pattern: HfPattern::parse(&value["pattern"])?,
+                remove_matches,
+            }
+        }
+        Some("Digits") => HfPreTokenizer::Digits {
+            individual: value["individual_digits"].as_bool().unwrap_or(false),
+        },
+        _ => return Err(not_implemented("pre-tokenizer", &value["type"])),
+    };
+    pre_tokenizers.push(pre_tokenizer);
+    Ok(())
+}
+
+fn parse_decoder(value: &Value, decoders: &mut Vec<HfDecoder>) -> Result<()> {
+    let decoder = match value["type"].as_str() {
+        None if value.is_null() => return Ok(()),
+        Some("Sequence") => {
+            for value in value["decoders"].as_array().into_iter().flatten() {
+                parse_decoder(value, decoders)?;
+            }
+            return Ok(());
+        }
+        Some("Replace") => match HfPattern::parse(&value["pattern"])? {
+            HfPattern::String(pattern) => HfDecoder::Replace(pattern, str_field(value, "content")?),
+            HfPattern::Regex(_) => {
+                return Err(not_implemented("decoder pattern", &value["pattern"]));
+            }
+        },
+        Some("ByteFallback") => HfDecoder::ByteFallback,
+        Some("Fuse") => HfDecoder::Fuse,
+        Some("Strip") => HfDecoder::Strip {
+            content: char_field(value, "content")?,
+            start: value["start"].as_u64().unwrap_or(0) as usize,
+            stop: value["stop"].as_u64().unwrap_or(0) as usize,
+        },
+        Some("ByteLevel") => HfDecoder::ByteLevel,
+        Some("Metaspace") => HfDecoder::Metaspace {
+            replacement: char_field(value, "replacement")?,
+            prepend: value["prepend_scheme"].as_str().map_or_else(
+                || value["add_prefix_space"].as_bool().unwrap_or(true),
+                |scheme| scheme != "never",
+            ),
+        },
+        _ => return Err(not_implemented("decoder", &value["type"])),
+    };
+    decoders.push(decoder);
+    Ok(())
+}
+
+fn str_field(value: &Value, key: &str) -> Result<String> {
+    value[key]
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| format_error(&format!("missing the string field {}", key)))
+}
+
+fn char_field(value: &Value, key: &str) -> Result<char> {
+    let s = str_field(value, key)?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format_error(&format!("{} is not a single character", key))),
+    }
+}
+
+fn format_error(message: &str) -> Error {
+    Error {
+        kind: ErrorKind::FormatError,
+        message: format!("invalid tokenizer.json: {}", message),
+        cause: None,
+    }
+}
+
+fn not_implemented(what: &str, typ: &Value) -> Error {
+    Error {
+        kind: ErrorKind::NotImplemented,
+        message: format!("unsupported {} in tokenizer.json: {}", what, typ),
+        cause: None,
+    }
+}
+
+/// parse the byte tokens like `<0x0A>`.
+fn parse_byte_token(piece: &[u8]) -> Option<u8> {
+    let hex = piece.strip_prefix(b"<0x")?.strip_suffix(b">")?;
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+fn replace_bytes(bytes: &[u8], pattern: &[u8], content: &[u8]) -> Vec<u8> {
+    if pattern.is_empty() {
+        return bytes.to_vec();
+    }
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(pattern) {
+            output.extend_from_slice(content);
+            i += pattern.len();
+        } else {
+            output.push(bytes[i]);
+            i += 1;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::BpeTokenizer;
+
+    const GPT2_PATTERN: &str =
+        r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+    #[test]
+    fn test_hf_pattern() -> Result<()> {
+        let pattern = HfPattern::parse(&serde_json::json!({ "Regex": GPT2_PATTERN }))?;
+        for text in [
+            "Hello world's  big\n\nnew 123!! ok ",
+            "I'm   ",
+            "a \n  b\t\tc",
+        ] {
+            let words = pattern
+                .find_all(text)
+                .iter()
+                .map(|(start, end)| &text[*start..*end])
+                .collect::<Vec<_>>();
+            assert_eq!(words, pre_tokenize(text), "failed to split {:?}", text);
+        }
+
+        let err = HfPattern::parse(&serde_json::json!({ "Regex": "a(?=b)" })).err();
+        assert_eq!(err.map(|e| e.kind), Some(ErrorKind::NotImplemented));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_sentencepiece() -> Result<()> {
+        let json = r#"{
+            "added_tokens": [{"id": 16, "content": "<|im_end|>", "special": true}],
+            "normalizer": {"type": "Sequence", "normalizers": [
+                {"type": "Prepend", "prepend": "▁"},
+                {"type": "Replace", "pattern": {"String": " "}, "content": "▁"}
+            ]},
+            "pre_tokenizer": null,
+            "decoder": {"type": "Sequence", "decoders": [
+                {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                {"type": "ByteFallback"},
+                {"type": "Fuse"},
+                {"type": "Strip", "content": " ", "start": 1, "stop": 0}
+            ]},
+            "model": {
+                "type": "BPE", "unk_token": "<unk>", "byte_fallback": true,
+                "vocab": {
+                    "<unk>": 0, "<s>": 1, "</s>": 2, "<0x0A>": 3, "<0xC3>": 4, "<0xA9>": 5,
+                    "▁": 6, "h": 7, "e": 8, "l": 9, "o": 10, "▁h": 11, "ll": 12, "▁he": 13,
+                    "▁hell": 14, "▁hello": 15
+                },
+                "merges": ["▁ h", "l l", "▁h e", "▁he ll", "▁hell o"]
+            }
+        }"#;
+        let tk = BpeTokenizer::from_hf_json(json, 1, 2)?;
+        assert_eq!(tk.vocab().len(), 17);
+        assert_eq!(tk.token(16), "<|im_end|>");
+
+        // the unknown characters fall back to the bytes
+        let tokens = tk.encode("hello\né hello", true, false)?;
+        assert_eq!(tokens, vec![1, 15, 3, 4, 5, 15]);
+        assert_eq!(tk.decode(1, 15)?, "hello");
+        assert_eq!(tk.decode(15, 15)?, " hello");
+        assert_eq!(tk.decode(15, 3)?, "\n");
+        assert_eq!(tk.token_text(15), Some(" hello".to_string()));
+        assert_eq!(tk.token_text(4), None);
+
+        // the added special tokens are matched as a whole
+        assert_eq!(tk.token_type(16), BpeTokenType::Control);
+        assert_eq!(tk.unk_token(), Some(0));
+        assert!(!tk.add_bos_token());
+        let tokens = tk.encode_special("hello<|im_end|>", false, false)?;
+        assert_eq!(tokens, vec![15, 16]);
+        assert_eq!(tk.token_text(16), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hf_byte_level() -> Result<()> {
+        let json = serde_json::json!({
+            "pre_tokenizer": {"type": "Sequence", "pretokenizers": [
+                {"type": "Split", "pattern": {"Regex": GPT2_PATTERN}, "behavior": "Isolated"},
+                {"type": "ByteLevel", "add_prefix_space": false, "use_regex": false}
+            ]},
+            "decoder": {"type": "ByteLevel"},
+            "model": {
+                "type": "BPE", "ignore_merges": true,
+                "vocab": {
+                    "h": 0, "e": 1, "l": 2, "o": 3, "hello": 4, "Ġ": 5, "w": 6, "r": 7,
+                    "d": 8, "Ġw": 9, "or": 10, "ld": 11, "Ġwor": 12, "<|endoftext|>": 13
+                },
+                "merges": [["Ġ", "w"], ["o", "r"], ["Ġw", "or"], ["l", "d"]]
+            }
+        });
+        let tk = BpeTokenizer::from_hf_json(&json.to_string(), 13, 13)?;
+
+        // "hello" is taken as a whole by ignore_merges without any merge
+        let tokens = tk.encode("hello world", false, true)?;
+        assert_eq!(tokens, vec![4, 12, 11, 13]);
+        assert_eq!(tk.decode(4, 12)?, " wor");
+        assert_eq!(tk.token_text(9), Some(" w".to_string()));
+
+        let json = json.to_string().replace("ByteLevel\"}", "WordPiece\"}");
+        let err = BpeTokenizer::from_hf_json(&json, 13, 13).err();
+        assert_eq!(err.map(|e| e.kind), Some(ErrorKind::NotImplemented));
+        Ok(())
+    }
+}
@@ -1,3 +1,11 @@
 mod bpe;
+mod decoder;
+mod gpt2;
+mod hf;
+mod rwkv;
+mod wpm;
 
+pub use bpe::BpeSpecialMode;
+pub use bpe::BpeTokenType;
 pub use bpe::BpeTokenizer;
+pub use decoder::BpeStreamDecoder;
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the tokenizer of the RWKV world models, which has no merges or scores, the text is
+/// split into the longest tokens greedily from the left by a trie of the token bytes.
+pub struct RwkvTokenizer {
+    tokens: Vec<Vec<u8>>,
+    // the root is at 0
+    trie: Vec<RwkvTrieNode>,
+}
+
+#[derive(Default)]
+struct RwkvTrieNode {
+    children: HashMap<u8, usize>,
+    token: Option<usize>,
+}
+
+impl RwkvTokenizer {
+    /// the tokens are the raw bytes ordered by the ids.
+    pub fn new(tokens: Vec<Vec<u8>>) -> Self {
+        let mut trie = vec![RwkvTrieNode::default()];
+        for (token, bytes) in tokens.iter().enumerate() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for b in bytes {
+                node = match trie[node].children.get(b) {
+                    Some(child) => *child,
+                    None => {
+                        trie.push(RwkvTrieNode::default());
+                        let child = trie.len() - 1;
+                        trie[node].children.insert(*b, child);
+                        child
+                    }
+                };
+            }
+            // the first one wins on the duplicated tokens
+            trie[node].token.get_or_insert(token);
+        }
+        Self { tokens, trie }
+    }
+
+    /// split the bytes into the longest tokens, the bytes not in the vocabulary are
+    /// encoded as `unk_token`.
+    pub fn encode(&self, text: &[u8], unk_token: usize) -> Vec<usize> {
+        let mut tokens = vec![];
+        let mut pos = 0;
+        while pos < text.len() {
+            let mut node = 0;
+            let mut longest = None;
+            for (i, b) in text[pos..].iter().enumerate() {
+                match self.trie[node].children.get(b) {
+                    Some(child) => node = *child,
+                    None => break,
+                }
+                if let Some(token) = self.trie[node].token {
+                    longest = Some((token, i + 1));
+                }
+            }
+            match longest {
+                Some((token, len)) => {
+                    tokens.push(token);
+                    pos += len;
+                }
+                None => {
+                    tokens.push(unk_token);
+                    pos += 1;
+                }
+            }
+        }
+        tokens
+    }
+
+    pub fn decode(&self, token: usize) -> &[u8] {
+        &self.tokens[token]
+    }
+}
+
+/// parse the world vocabulary in `tokenizer.rwkv.world`, each line is the id, the python
+/// literal of the token and its length in bytes, like `33 '!' 1` or `125 b'\xe4\xb8' 2`.
+/// the ids start from 1 in the world vocabulary, the missing ids are left empty.
+pub fn parse_world_vocab(src: &str) -> Result<Vec<Vec<u8>>> {
+    let mut tokens: Vec<Vec<u8>> = vec![];
+    for (lineno, line) in src.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(parsed) = parse_world_line(line) else {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!("invalid rwkv vocabulary at line {}: {}", lineno + 1, line),
+                cause: None,
+            });
+        };
+        let (id, bytes) = parsed;
+        if id >= tokens.len() {
+            tokens.resize(id + 1, vec![]);
+        }
+        tokens[id] = bytes;
+    }
+    Ok(tokens)
+}
+
+fn parse_world_line(line: &str) -> Option<(usize, Vec<u8>)> {
+    let (id, rest) = line.split_once(' ')?;
+    let (literal, len) = rest.rsplit_once(' ')?;
+    let id = id.parse().ok()?;
+    let len: usize = len.trim().parse().ok()?;
+    let (is_bytes, literal) = match literal.strip_prefix('b') {
+        Some(literal) => (true, literal),
+        None => (false, literal),
+    };
+    let quote = literal.chars().next()?;
+    if !matches!(quote, '\'' | '"') || literal.len() < 2 || !literal.ends_with(quote) {
+        return None;
+    }
+    let bytes = unescape_literal(&literal[1..literal.len() - 1], is_bytes)?;
+    (bytes.len() == len).then_some((id, bytes))
+}
+
+/// unescape the content of a python string or bytes literal. `\xNN` is a byte in a bytes
+/// literal, and a code point in a string literal.
+fn unescape_literal(s: &str, is_bytes: bool) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let c = chars.next()?;
+        let hex_len = match c {
+            'x' => 2,
+            'u' if !is_bytes => 4,
+            'U' if !is_bytes => 8,
+            _ => 0,
+        };
+        if hex_len == 0 {
+            let escaped = match c {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c => c,
+            };
+            bytes.push(escaped as u8);
+            continue;
+        }
+        let hex = chars.by_ref().take(hex_len).collect::<String>();
+        let n = u32::from_str_radix(&hex, 16).ok()?;
+        if is_bytes {
+            bytes.push(n as u8);
+        } else {
+            let c = char::from_u32(n)?;
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    Some(bytes)
+}
+
+/// unescape a token in `tokenizer.ggml.tokens` of the RWKV models, which escapes the
+/// control characters and the non UTF-8 bytes like `\n` and `\xe4`.
+pub fn unescape_token(token: &str) -> Vec<u8> {
+    unescape_literal(token, true).unwrap_or_else(|| token.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rwkv_world_vocab() -> Result<()> {
+        let src = "1 '\\x00' 1\n2 'a' 1\n3 'b' 1\n4 ' ' 1\n5 'ab' 2\n6 'abc' 3\n\
+                   7 b'\\xe4\\xb8' 2\n8 '\\u4e16' 3\n9 '\\n' 1\n10 \"'s\" 2\n";
+        let tokens = parse_world_vocab(src)?;
+        assert_eq!(tokens.len(), 11);
+        assert_eq!(tokens[0], b"");
+        assert_eq!(tokens[1], b"\0");
+        assert_eq!(tokens[7], [0xe4, 0xb8]);
+        assert_eq!(tokens[8], "世".as_bytes());
+        assert_eq!(tokens[10], b"'s");
+        assert!(parse_world_vocab("1 'ab' 1").is_err());
+
+        // the longest tokens are taken greedily
+        let tk = RwkvTokenizer::new(tokens);
+        assert_eq!(tk.encode(b"abc ab a\n's", 0), vec![6, 4, 5, 4, 2, 9, 10]);
+        assert_eq!(tk.encode("世".as_bytes(), 0), vec![8]);
+        assert_eq!(tk.encode(b"abz", 0), vec![5, 0]);
+        assert_eq!(tk.decode(7), &[0xe4, 0xb8]);
+
+        assert_eq!(unescape_token("a\\nb\\x80\\\\"), b"a\nb\x80\\");
+        Ok(())
+    }
+}
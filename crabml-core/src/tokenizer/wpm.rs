@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+/// the WordPiece tokenizer of the BERT models. the GGUF export prefixes the tokens which
+/// start a word with `▁` and drops the `##` of the ones which continue a word, so each word
+/// is prefixed with `▁` and split into the longest tokens greedily from the left. a word
+/// which can not be split into the tokens is encoded as `unk_token` as a whole.
+///
+/// the text is lowercased and split on the whitespaces and the punctuations, the unicode
+/// normalization of the uncased models is not done yet.
+pub struct WpmTokenizer {
+    // the max chars of a token
+    max_token_len: usize,
+}
+
+impl WpmTokenizer {
+    pub fn new(tokens: &[String]) -> Self {
+        let max_token_len = tokens.iter().map(|t| t.chars().count()).max().unwrap_or(0);
+        Self { max_token_len }
+    }
+
+    pub fn encode(
+        &self,
+        text: &str,
+        token_ids: &HashMap<String, usize>,
+        unk_token: usize,
+    ) -> Vec<usize> {
+        let mut tokens = vec![];
+        for word in pre_tokenize(text) {
+            let word = format!("▁{}", word);
+            // the byte offsets of the chars, with the end of the word
+            let bounds = word
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([word.len()])
+                .collect::<Vec<_>>();
+            let n_tokens = tokens.len();
+            let mut start = 0;
+            while start < bounds.len() - 1 {
+                let end = (start + self.max_token_len).min(bounds.len() - 1);
+                let found = (start + 1..=end)
+                    .rev()
+                    .find_map(|e| Some((token_ids.get(&word[bounds[start]..bounds[e]])?, e)));
+                match found {
+                    Some((token, e)) => {
+                        tokens.push(*token);
+                        start = e;
+                    }
+                    None => {
+                        tokens.truncate(n_tokens);
+                        tokens.push(unk_token);
+                        break;
+                    }
+                }
+            }
+        }
+        tokens
+    }
+}
+
+fn pre_tokenize(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    for c in text.chars() {
+        if c.is_whitespace() || is_punctuation(c) || is_cjk(c) {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            if !c.is_whitespace() {
+                words.push(c.to_string());
+            }
+            continue;
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || matches!(c, '\u{2000}'..='\u{206F}' | '\u{3000}'..='\u{303F}')
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{4E00}'..='\u{9FFF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{20000}'..='\u{2A6DF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{2F800}'..='\u{2FA1F}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wpm_encode() {
+        let tokens = [
+            "[UNK]", "▁hello", "▁un", "aff", "able", "▁,", "▁world", "▁世", "▁w",
+        ]
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+        let token_ids = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let wpm = WpmTokenizer::new(&tokens);
+
+        assert_eq!(wpm.encode("Hello, World unaffable", &token_ids, 0), vec![
+            1, 5, 6, 2, 3, 4
+        ]);
+        // the word can not be split is taken as unk as a whole
+        assert_eq!(wpm.encode("wx 世", &token_ids, 0), vec![0, 7]);
+        assert_eq!(pre_tokenize("Hi!  你好"), vec!["hi", "!", "你", "好"]);
+    }
+}
@@ -0,0 +1,40 @@
+/* generate on a prompt by the C api:
+ *
+ *   cargo build --release -p crabml-ffi
+ *   cc crabml-ffi/examples/simple.c -Icrabml-ffi/include -Ltarget/release -lcrabml_ffi -o simple
+ *   LD_LIBRARY_PATH=target/release ./simple model.gguf "Once upon a time"
+ */
+
+#include <stdio.h>
+
+#include "crabml.h"
+
+static bool print_token(const char *text, size_t len, void *user_data) {
+    (void)user_data;
+    fwrite(text, 1, len, stdout);
+    fflush(stdout);
+    return true;
+}
+
+int main(int argc, char **argv) {
+    if (argc < 3) {
+        fprintf(stderr, "usage: %s <model.gguf> <prompt>\n", argv[0]);
+        return 1;
+    }
+    crabml_model *model = crabml_model_load(argv[1], 0);
+    if (model == NULL) {
+        fprintf(stderr, "failed to load the model: %s\n", crabml_last_error());
+        return 1;
+    }
+
+    crabml_generate_params params = crabml_generate_default_params();
+    params.max_tokens = 64;
+    printf("%s", argv[2]);
+    int32_t n = crabml_generate(model, argv[2], &params, print_token, NULL);
+    printf("\n");
+    if (n < 0) {
+        fprintf(stderr, "failed to generate: %s\n", crabml_last_error());
+    }
+    crabml_free(model);
+    return n < 0;
+}
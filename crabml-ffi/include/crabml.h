@@ -0,0 +1,55 @@
+/* the C api of crabml, built as libcrabml_ffi by the crabml-ffi crate. */
+
+#ifndef CRABML_H
+#define CRABML_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* a loaded model, which is used on the thread loading it. */
+typedef struct crabml_model crabml_model;
+
+typedef struct crabml_generate_params {
+    /* the max number of the generated tokens, up to the context of the model if <= 0. */
+    int32_t max_tokens;
+    /* 0 takes the most likely token on each step. */
+    float temperature;
+    float top_p;
+    /* the seed of the sampling, random if 0. */
+    uint64_t seed;
+} crabml_generate_params;
+
+/* called with the text of each generated token, which is NUL terminated and `len` bytes
+ * long, and valid only during the call. returns false to stop the generation. */
+typedef bool (*crabml_token_callback)(const char *text, size_t len, void *user_data);
+
+/* load the GGUF model at `path` on the cpu with `n_threads`, the number of the cpus if 0.
+ * returns NULL on error. */
+crabml_model *crabml_model_load(const char *path, int32_t n_threads);
+
+crabml_generate_params crabml_generate_default_params(void);
+
+/* generate on the prompt, the text of each token is passed to the callback as it's
+ * generated. the default parameters are taken if `params` is NULL, and the callback may be
+ * NULL. returns the number of the generated tokens, or -1 on error. */
+int32_t crabml_generate(crabml_model *model, const char *prompt,
+                        const crabml_generate_params *params, crabml_token_callback callback,
+                        void *user_data);
+
+/* free the model, NULL is ignored. */
+void crabml_free(crabml_model *model);
+
+/* the message of the last error on the calling thread, which is empty if there's none. the
+ * string is valid until the next failed call on the thread. */
+const char *crabml_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CRABML_H */
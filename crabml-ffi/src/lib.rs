@@ -0,0 +1,313 @@
+//! the C api of crabml, see `include/crabml.h` for the declarations.
+//!
+//! a model is loaded into an opaque handle, which keeps the mmap of the GGUF file, the
+//! weights and a runner, and generates on the thread which loads it. the errors are kept
+//! per thread and taken by `crabml_last_error`, the panics are caught and reported as the
+//! errors instead of unwinding into C.
+
+use std::cell::RefCell;
+use std::ffi::c_char;
+use std::ffi::c_void;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::panic::AssertUnwindSafe;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::sampler::Llama2Sampler;
+use crabml_llama2::CpuLlama2Model;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// a loaded model, `crabml_model` in C. the tensors borrow the mmap of the loader, so the
+/// loader, the file and the model are boxed into the `'static` borrows on loading, and freed
+/// in the reverse order on dropping.
+pub struct CrabmlModel {
+    runner: Option<Llama2Runner<CpuTensor<'static>>>,
+    model: *mut CpuLlama2Model<'static>,
+    gf: *mut GGUFFile<'static>,
+    gl: *mut GGUFFileLoader,
+}
+
+impl CrabmlModel {
+    fn load(path: &str, n_threads: usize) -> Result<Self> {
+        // the boxes taken so far are freed by dropping the handle on error
+        let mut handle = Self {
+            runner: None,
+            model: std::ptr::null_mut(),
+            gf: std::ptr::null_mut(),
+            gl: Box::into_raw(Box::new(GGUFFileLoader::new(path)?)),
+        };
+        let gl: &'static GGUFFileLoader = unsafe { &*handle.gl };
+        handle.gf = Box::into_raw(Box::new(gl.open()?));
+        let gf: &'static GGUFFile<'static> = unsafe { &*handle.gf };
+        let device = CpuTensorDevice::new().with_threads(n_threads)?;
+        handle.model = Box::into_raw(Box::new(CpuLlama2Model::load(gf, device)?));
+        let model: &'static CpuLlama2Model<'static> = unsafe { &*handle.model };
+        handle.runner = Some(Llama2Runner::try_from(model)?);
+        Ok(handle)
+    }
+
+    fn model(&self) -> &CpuLlama2Model<'static> {
+        unsafe { &*self.model }
+    }
+
+    fn generate(
+        &mut self,
+        prompt: &str,
+        params: &CrabmlGenerateParams,
+        mut on_text: impl FnMut(&str) -> bool,
+    ) -> Result<usize> {
+        let mut sampler = Llama2Sampler::new(
+            self.model().conf().vocab_size,
+            params.temperature,
+            params.top_p,
+        );
+        if params.seed != 0 {
+            sampler = sampler.with_seed(params.seed);
+        }
+        let max_tokens = match params.max_tokens {
+            n if n > 0 => n as usize,
+            _ => self.model().conf().seq_len,
+        };
+        let runner = self.runner.as_mut().unwrap();
+        let mut generator = runner
+            .generate(prompt, max_tokens, &mut sampler)?
+            .with_max_tokens(max_tokens);
+        let mut n_tokens = 0;
+        while let Some(token) = generator.next_token()? {
+            n_tokens += 1;
+            if !token.text.is_empty() && !on_text(&token.text) {
+                break;
+            }
+        }
+        Ok(n_tokens)
+    }
+}
+
+impl Drop for CrabmlModel {
+    fn drop(&mut self) {
+        self.runner.take();
+        unsafe {
+            if !self.model.is_null() {
+                drop(Box::from_raw(self.model));
+            }
+            if !self.gf.is_null() {
+                drop(Box::from_raw(self.gf));
+            }
+            drop(Box::from_raw(self.gl));
+        }
+    }
+}
+
+/// the parameters of `crabml_generate`, `crabml_generate_params` in C.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CrabmlGenerateParams {
+    /// the max number of the generated tokens, up to the context of the model if <= 0.
+    pub max_tokens: i32,
+    /// 0 takes the most likely token on each step.
+    pub temperature: f32,
+    pub top_p: f32,
+    /// the seed of the sampling, random if 0.
+    pub seed: u64,
+}
+
+impl Default for CrabmlGenerateParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            temperature: 0.8,
+            top_p: 0.9,
+            seed: 0,
+        }
+    }
+}
+
+/// called with the text of each generated token, which is NUL terminated and `len` bytes
+/// long, and valid only during the call. returns false to stop the generation.
+pub type CrabmlTokenCallback =
+    Option<unsafe extern "C" fn(text: *const c_char, len: usize, user_data: *mut c_void) -> bool>;
+
+fn set_last_error(err: &Error) {
+    let message = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+/// run the call with the panics caught, the errors are kept in the last error of the thread.
+fn catch<T>(f: impl FnOnce() -> Result<T>) -> Option<T> {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = match panic.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        Err((ErrorKind::Unexpected, format!("panicked: {}", message)).into())
+    });
+    match result {
+        Ok(v) => Some(v),
+        Err(err) => {
+            set_last_error(&err);
+            None
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err((ErrorKind::BadInput, format!("{} is null", name)).into());
+    }
+    CStr::from_ptr(s).to_str().map_err(|err| Error {
+        kind: ErrorKind::BadInput,
+        message: format!("{} is not valid utf-8", name),
+        cause: Some(Box::new(err)),
+    })
+}
+
+/// load the GGUF model at `path` on the cpu with `n_threads`, the number of the cpus if 0.
+/// returns null on error.
+///
+/// # Safety
+///
+/// `path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_model_load(
+    path: *const c_char,
+    n_threads: i32,
+) -> *mut CrabmlModel {
+    catch(|| {
+        let path = str_arg(path, "path")?;
+        let model = CrabmlModel::load(path, n_threads.max(0) as usize)?;
+        Ok(Box::into_raw(Box::new(model)))
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn crabml_generate_default_params() -> CrabmlGenerateParams {
+    CrabmlGenerateParams::default()
+}
+
+/// generate on the prompt, the text of each token is passed to the callback as it's
+/// generated. the default parameters are taken if `params` is null. returns the number of
+/// the generated tokens, or -1 on error.
+///
+/// # Safety
+///
+/// `model` must be a handle from `crabml_model_load` not yet freed, and used on the thread
+/// which loads it. `prompt` must be a NUL terminated string, and `params` null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_generate(
+    model: *mut CrabmlModel,
+    prompt: *const c_char,
+    params: *const CrabmlGenerateParams,
+    callback: CrabmlTokenCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    catch(|| {
+        let model = model
+            .as_mut()
+            .ok_or_else(|| Error::from((ErrorKind::BadInput, "model is null")))?;
+        let prompt = str_arg(prompt, "prompt")?;
+        let params = params.as_ref().copied().unwrap_or_default();
+        let n_tokens = model.generate(prompt, &params, |text| match callback {
+            Some(callback) => {
+                let text = CString::new(text.replace('\0', " ")).unwrap_or_default();
+                callback(text.as_ptr(), text.as_bytes().len(), user_data)
+            }
+            None => true,
+        })?;
+        Ok(n_tokens as i32)
+    })
+    .unwrap_or(-1)
+}
+
+/// free the model, null is ignored.
+///
+/// # Safety
+///
+/// `model` must be null or a handle from `crabml_model_load` not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_free(model: *mut CrabmlModel) {
+    if !model.is_null() {
+        catch(|| {
+            drop(Box::from_raw(model));
+            Ok(())
+        });
+    }
+}
+
+/// the message of the last error on the calling thread, which is empty if there's none. the
+/// string is valid until the next failed call on the thread.
+#[no_mangle]
+pub extern "C" fn crabml_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn collect(text: *const c_char, len: usize, user_data: *mut c_void) -> bool {
+        let texts = &mut *(user_data as *mut Vec<String>);
+        let text = CStr::from_ptr(text).to_str().unwrap();
+        assert_eq!(text.len(), len);
+        texts.push(text.to_string());
+        texts.len() < 5
+    }
+
+    #[test]
+    fn test_ffi_generate() {
+        unsafe {
+            let path = CString::new("../testdata/tinyllamas-stories-260k-f32.gguf").unwrap();
+            let model = crabml_model_load(path.as_ptr(), 1);
+            assert!(!model.is_null());
+
+            let prompt = CString::new("Lily").unwrap();
+            let params = CrabmlGenerateParams {
+                max_tokens: 8,
+                temperature: 0.0,
+                ..crabml_generate_default_params()
+            };
+            let mut texts: Vec<String> = vec![];
+            let n = crabml_generate(model, prompt.as_ptr(), &params, None, std::ptr::null_mut());
+            assert_eq!(n, 8);
+
+            // the callback stops the generation
+            let n = crabml_generate(
+                model,
+                prompt.as_ptr(),
+                &params,
+                Some(collect),
+                &mut texts as *mut _ as *mut c_void,
+            );
+            assert!(n >= 5);
+            assert_eq!(texts.len(), 5);
+            crabml_free(model);
+            crabml_free(std::ptr::null_mut());
+
+            // the errors are taken by crabml_last_error
+            let path = CString::new("not-found.gguf").unwrap();
+            assert!(crabml_model_load(path.as_ptr(), 0).is_null());
+            let err = CStr::from_ptr(crabml_last_error()).to_str().unwrap();
+            assert!(err.contains("not-found.gguf"), "{}", err);
+            let n = crabml_generate(
+                std::ptr::null_mut(),
+                prompt.as_ptr(),
+                std::ptr::null(),
+                None,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(n, -1);
+            let err = CStr::from_ptr(crabml_last_error()).to_str().unwrap();
+            assert!(err.contains("model is null"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! the gRPC service of crabml, see `proto/crabml.proto` for the rpcs.
+//!
+//! the runner is not `Send` since the weights are shared by `Rc`, so the model is loaded and
+//! run on a thread of its own, which takes the requests one by one from a channel, and sends
+//! the generated tokens back to the async handlers as they're generated. a generation is
+//! aborted once its response stream is dropped, like on the client cancelling the call.
+
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::mpsc as std_mpsc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::BpeTokenizer;
+use crabml_llama2::abort::Llama2AbortHandle;
+use crabml_llama2::llama2::Llama2EmbeddingOptions;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::Llama2Config;
+use crabml_llama2::sampler::Llama2Sampler;
+use crabml_llama2::CpuLlama2Model;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+
+use crate::pb::crabml_server::Crabml;
+use crate::pb::EmbedRequest;
+use crate::pb::EmbedResponse;
+use crate::pb::Embedding;
+use crate::pb::GenerateRequest;
+use crate::pb::GenerateResponse;
+use crate::pb::TokenizeRequest;
+use crate::pb::TokenizeResponse;
+use crate::pb::Usage;
+
+pub mod pb {
+    tonic::include_proto!("crabml.v1");
+}
+
+type RpcResult<T> = std::result::Result<T, Status>;
+
+/// a request taken by the model thread, with the channel of its responses.
+enum Job {
+    Generate(
+        GenerateRequest,
+        mpsc::Sender<RpcResult<GenerateResponse>>,
+        Llama2AbortHandle,
+    ),
+    Embed(EmbedRequest, oneshot::Sender<Result<EmbedResponse>>),
+    Tokenize(TokenizeRequest, oneshot::Sender<Result<TokenizeResponse>>),
+}
+
+/// the `Crabml` service, serve it by `pb::crabml_server::CrabmlServer::new(service)`.
+pub struct CrabmlService {
+    jobs: Mutex<std_mpsc::Sender<Job>>,
+}
+
+impl CrabmlService {
+    /// load the GGUF model on a new thread with `threads` threads for the matmuls, the number
+    /// of the cpus if 0. returns after the model is loaded.
+    pub fn spawn(model_path: &str, threads: usize) -> Result<Self> {
+        let (jobs_tx, jobs_rx) = std_mpsc::channel::<Job>();
+        let (loaded_tx, loaded_rx) = std_mpsc::sync_channel::<Result<()>>(1);
+        let model_path = model_path.to_string();
+        std::thread::Builder::new()
+            .name("crabml-model".to_string())
+            .spawn(move || {
+                let run = || -> Result<()> {
+                    let gl = GGUFFileLoader::new(&model_path)?;
+                    let gf = gl.open()?;
+                    let device = CpuTensorDevice::new().with_threads(threads)?;
+                    let model = CpuLlama2Model::load(&gf, device)?;
+                    let mut worker = ModelWorker {
+                        conf: *model.conf(),
+                        tokenizer: model.tokenizer(),
+                        runner: Llama2Runner::try_from(&model)?,
+                    };
+                    let _ = loaded_tx.send(Ok(()));
+                    for job in jobs_rx {
+                        worker.run(job);
+                    }
+                    Ok(())
+                };
+                if let Err(err) = run() {
+                    let _ = loaded_tx.send(Err(err));
+                }
+            })
+            .map_err(|err| Error {
+                kind: ErrorKind::Unexpected,
+                message: "failed to spawn the model thread".to_string(),
+                cause: Some(Box::new(err)),
+            })?;
+        loaded_rx
+            .recv()
+            .map_err(|_| Error::from((ErrorKind::Unexpected, "the model thread exited")))??;
+        Ok(Self {
+            jobs: Mutex::new(jobs_tx),
+        })
+    }
+
+    fn send(&self, job: Job) -> RpcResult<()> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(job)
+            .map_err(|_| Status::unavailable("the model thread exited"))
+    }
+}
+
+/// the responses of a generation, which aborts the generation once it's dropped. tonic drops
+/// the stream when the client cancels the call or goes away, so the model thread stops on the
+/// next token or the next batch of the prompt, instead of on failing to send the next token.
+pub struct GenerateStream {
+    inner: ReceiverStream<RpcResult<GenerateResponse>>,
+    abort: Llama2AbortHandle,
+}
+
+impl Stream for GenerateStream {
+    type Item = RpcResult<GenerateResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Drop for GenerateStream {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+#[tonic::async_trait]
+impl Crabml for CrabmlService {
+    type GenerateStream = GenerateStream;
+
+    async fn generate(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> RpcResult<Response<Self::GenerateStream>> {
+        let (tx, rx) = mpsc::channel(16);
+        let abort = Llama2AbortHandle::new();
+        self.send(Job::Generate(request.into_inner(), tx, abort.clone()))?;
+        Ok(Response::new(GenerateStream {
+            inner: ReceiverStream::new(rx),
+            abort,
+        }))
+    }
+
+    async fn embed(&self, request: Request<EmbedRequest>) -> RpcResult<Response<EmbedResponse>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Job::Embed(request.into_inner(), tx))?;
+        let resp = rx
+            .await
+            .map_err(|_| Status::unavailable("the model thread exited"))?
+            .map_err(status)?;
+        Ok(Response::new(resp))
+    }
+
+    async fn tokenize(
+        &self,
+        request: Request<TokenizeRequest>,
+    ) -> RpcResult<Response<TokenizeResponse>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Job::Tokenize(request.into_inner(), tx))?;
+        let resp = rx
+            .await
+            .map_err(|_| Status::unavailable("the model thread exited"))?
+            .map_err(status)?;
+        Ok(Response::new(resp))
+    }
+}
+
+fn status(err: Error) -> Status {
+    match err.kind {
+        ErrorKind::BadInput => Status::invalid_argument(err.to_string()),
+        ErrorKind::NotImplemented => Status::unimplemented(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+/// the model and the runner owned by the model thread.
+struct ModelWorker<'a> {
+    conf: Llama2Config,
+    tokenizer: Rc<BpeTokenizer>,
+    runner: Llama2Runner<CpuTensor<'a>>,
+}
+
+impl<'a> ModelWorker<'a> {
+    fn run(&mut self, job: Job) {
+        match job {
+            Job::Generate(req, tx, abort) => {
+                if let Err(err) = self.generate(req, &tx, abort) {
+                    let _ = tx.blocking_send(Err(status(err)));
+                }
+            }
+            Job::Embed(req, tx) => {
+                let _ = tx.send(self.embed(req));
+            }
+            Job::Tokenize(req, tx) => {
+                let _ = tx.send(self.tokenize(req));
+            }
+        }
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<usize>> {
+        let tokenizer = &self.tokenizer;
+        tokenizer.encode_special(text, tokenizer.add_bos_token(), tokenizer.add_eos_token())
+    }
+
+    /// send the text of each token as it's generated, then the finish reason and the usage.
+    /// stops early once it's aborted or the client has gone away.
+    fn generate(
+        &mut self,
+        req: GenerateRequest,
+        tx: &mpsc::Sender<RpcResult<GenerateResponse>>,
+        abort: Llama2AbortHandle,
+    ) -> Result<()> {
+        if abort.is_aborted() {
+            return Ok(());
+        }
+        let tokens = self.encode(&req.prompt)?;
+        let seq_len = self.conf.seq_len;
+        let max_tokens = match req.max_tokens as usize {
+            0 => seq_len,
+            n => n.min(seq_len),
+        };
+        let mut sampler = Llama2Sampler::new(
+            self.conf.vocab_size,
+            req.temperature.unwrap_or(1.0),
+            req.top_p.unwrap_or(1.0),
+        );
+        if let Some(seed) = req.seed {
+            sampler = sampler.with_seed(seed);
+        }
+
+        // keep the prefix shared with the last request in the kv cache
+        self.runner.prefill(&tokens)?;
+        let mut generator = self
+            .runner
+            .generate(&req.prompt, max_tokens, &mut sampler)?
+            .with_max_tokens(max_tokens)
+            .with_stop_sequences(req.stop)
+            .with_abort_handle(abort);
+        while let Some(token) = generator.next_token()? {
+            let resp = GenerateResponse {
+                text: token.text,
+                token: token.token as u32,
+                ..Default::default()
+            };
+            if tx.blocking_send(Ok(resp)).is_err() {
+                return Ok(());
+            }
+        }
+
+        let finish_reason = generator
+            .finish_reason()
+            .map_or("stop", |reason| reason.as_str());
+        let stats = generator.stats();
+        let _ = tx.blocking_send(Ok(GenerateResponse {
+            finish_reason: finish_reason.to_string(),
+            usage: Some(Usage {
+                prompt_tokens: stats.n_prompt_tokens as u32,
+                completion_tokens: stats.n_eval_tokens as u32,
+            }),
+            ..Default::default()
+        }));
+        Ok(())
+    }
+
+    fn embed(&mut self, req: EmbedRequest) -> Result<EmbedResponse> {
+        let mut embeddings = Vec::with_capacity(req.input.len());
+        let mut n_tokens = 0;
+        for input in req.input.iter() {
+            let values = self
+                .runner
+                .embeddings(input, &Llama2EmbeddingOptions::default())?;
+            n_tokens += self.encode(input)?.len();
+            embeddings.push(Embedding { values });
+        }
+        Ok(EmbedResponse {
+            embeddings,
+            prompt_tokens: n_tokens as u32,
+        })
+    }
+
+    fn tokenize(&mut self, req: TokenizeRequest) -> Result<TokenizeResponse> {
+        let tokenizer = &self.tokenizer;
+        let ids = if req.add_special {
+            self.encode(&req.text)?
+        } else {
+            tokenizer.encode_special(&req.text, false, false)?
+        };
+        Ok(TokenizeResponse {
+            pieces: ids.iter().map(|id| tokenizer.token(*id)).collect(),
+            ids: ids.into_iter().map(|id| id as u32).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_grpc_service() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let service = CrabmlService::spawn("../testdata/tinyllamas-stories-260k-f32.gguf", 1)?;
+
+        let resp = service
+            .tokenize(Request::new(TokenizeRequest {
+                text: "Lily".to_string(),
+                add_special: true,
+            }))
+            .await?
+            .into_inner();
+        assert!(!resp.ids.is_empty());
+        assert_eq!(resp.ids.len(), resp.pieces.len());
+
+        let req = GenerateRequest {
+            prompt: "Lily".to_string(),
+            max_tokens: 8,
+            temperature: Some(0.0),
+            ..Default::default()
+        };
+        let mut stream = service.generate(Request::new(req)).await?.into_inner();
+        let mut resps = vec![];
+        while let Some(resp) = stream.next().await {
+            resps.push(resp?);
+        }
+        let last = resps.pop().unwrap();
+        assert_eq!(resps.len(), 8);
+        assert_eq!(last.finish_reason, "length");
+        assert_eq!(last.usage.unwrap().completion_tokens, 8);
+
+        // cancelling the call drops the stream, which aborts the generation
+        let req = GenerateRequest {
+            prompt: "Lily".to_string(),
+            temperature: Some(0.0),
+            ..Default::default()
+        };
+        let mut stream = service.generate(Request::new(req)).await?.into_inner();
+        stream.next().await.unwrap()?;
+        let abort = stream.abort.clone();
+        drop(stream);
+        assert!(abort.is_aborted());
+
+        let resp = service
+            .embed(Request::new(EmbedRequest {
+                input: vec!["Lily".to_string(), "a cat".to_string()],
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(resp.embeddings.len(), 2);
+
+        let err = CrabmlService::spawn("not-found.gguf", 1).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use clap::Parser;
+use crabml_grpc::pb::crabml_server::CrabmlServer;
+use crabml_grpc::CrabmlService;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "serve a GGUF model on gRPC", long_about = None)]
+struct Args {
+    /// The GGUF model file to serve
+    #[arg(short, long)]
+    model: String,
+
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:50051")]
+    addr: String,
+
+    /// The number of threads, the number of the cpus if 0
+    #[arg(short, long, default_value_t = 0)]
+    threads: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let service = CrabmlService::spawn(&args.model, args.threads)?;
+    let addr = args.addr.parse()?;
+    eprintln!("serving {} on grpc://{}", args.model, addr);
+    tonic::transport::Server::builder()
+        .add_service(CrabmlServer::new(service))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// a handle to stop an in-flight generation from another thread, like on a client gone away.
+/// the clones share the same flag, the generator checks it between the tokens and between the
+/// batches of the prompt, and stops with the kv cache of the generation released.
+#[derive(Debug, Clone, Default)]
+pub struct Llama2AbortHandle {
+    aborted: Arc<AtomicBool>,
+}
+
+impl Llama2AbortHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// stop the generations holding the handle, it takes effect on their next step.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abort_handle() {
+        let handle = Llama2AbortHandle::new();
+        let cloned = handle.clone();
+        assert!(!cloned.is_aborted());
+
+        std::thread::spawn(move || handle.abort()).join().unwrap();
+        assert!(cloned.is_aborted());
+        assert!(!Llama2AbortHandle::new().is_aborted());
+    }
+}
@@ -0,0 +1,286 @@
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use crabml::time::Instant;
+use crabml::tokenizer::BpeSpecialMode;
+use crabml::tokenizer::BpeStreamDecoder;
+
+use crate::llama2::Llama2GeneratedToken;
+use crate::llama2::Llama2Row;
+use crate::llama2::Llama2Runner;
+use crate::llama2::Llama2TokenLogprobs;
+use crate::sampler::Llama2Sampler;
+
+/// a sequence generated along with the others in the batch.
+struct Llama2BatchSeq {
+    seq: usize,         // the sequence in the kv cache
+    tokens: Vec<usize>, // the prompt and the generated tokens
+    n_prompt: usize,
+    pos: usize, // the tokens forwarded
+    steps: usize,
+    sampler: Llama2Sampler,
+    decoder: BpeStreamDecoder,
+    last_time: Instant,
+}
+
+/// a token generated on a sequence of the batch, the token is None once the sequence is
+/// finished, and the sequence is removed from the batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2BatchToken {
+    pub id: usize,
+    pub token: Option<Llama2GeneratedToken>,
+}
+
+/// generate the independent sequences together, each sequence takes its own sequence in the
+/// kv cache and its own sampler. every step forwards the next token of all the sequences in
+/// a single pass, so the weights are loaded once for all of them, like a server serving the
+/// requests of multiple users with one model.
+///
+/// the sequences can be added and removed between the steps, the prompt of a new sequence is
+/// forwarded in the batches of `batch_size` rows along with the tokens of the others.
+pub struct Llama2BatchGenerator<'a, T: Tensor> {
+    runner: &'a mut Llama2Runner<T>,
+    seqs: Vec<Option<Llama2BatchSeq>>, // by id, None if the sequence is removed
+}
+
+impl<'a, T: Tensor> Llama2BatchGenerator<'a, T> {
+    /// add a sequence generated on the steps like `generate`, returns its id.
+    pub fn add(&mut self, prompt: &str, steps: usize, mut sampler: Llama2Sampler) -> Result<usize> {
+        let tokenizer = &self.runner.tokenizer;
+        let tokens = tokenizer.encode_special(
+            prompt,
+            tokenizer.add_bos_token(),
+            tokenizer.add_eos_token(),
+        )?;
+        if tokens.is_empty() {
+            return Err((
+                ErrorKind::BadInput,
+                "something is wrong, expected at least 1 prompt token",
+            )
+                .into());
+        }
+        sampler.reset(&tokens);
+
+        let seq = Llama2BatchSeq {
+            seq: self.runner.kv_cache.alloc_seq(),
+            n_prompt: tokens.len(),
+            tokens,
+            pos: 0,
+            steps,
+            sampler,
+            decoder: BpeStreamDecoder::new(BpeSpecialMode::Skip),
+            last_time: Instant::now(),
+        };
+        self.seqs.push(Some(seq));
+        Ok(self.seqs.len() - 1)
+    }
+
+    /// stop generating the sequence and release its kv cache.
+    pub fn remove(&mut self, id: usize) -> Result<()> {
+        match self.seqs.get_mut(id).and_then(|seq| seq.take()) {
+            Some(seq) => self.runner.kv_cache.free_seq(seq.seq),
+            None => Err((
+                ErrorKind::BadInput,
+                format!("no sequence {} in the batch", id),
+            )
+                .into()),
+        }
+    }
+
+    /// the sequences not finished yet.
+    pub fn len(&self) -> usize {
+        self.seqs.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// forward the next tokens of all the sequences together, returns the tokens sampled on
+    /// the sequences in the order of the ids. the sequences still forwarding the prompt take
+    /// no token on the step.
+    pub fn step(&mut self) -> Result<Vec<Llama2BatchToken>> {
+        let seq_len = self.runner.conf.seq_len;
+        let mut outputs = vec![];
+
+        // the sequences out of the steps or the context are finished
+        for id in 0..self.seqs.len() {
+            let Some(seq) = &self.seqs[id] else {
+                continue;
+            };
+            if seq.tokens.len() > seq.n_prompt + seq.steps || seq.pos >= seq_len {
+                self.remove(id)?;
+                outputs.push(Llama2BatchToken { id, token: None });
+            }
+        }
+
+        // the generating sequences take a row each, the rest of the rows are taken by the
+        // prompts, only the last row of a sequence takes the logits
+        let mut ids = self
+            .seqs
+            .iter()
+            .enumerate()
+            .filter_map(|(id, seq)| seq.as_ref().map(|seq| (id, seq.tokens.len() - seq.pos)))
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|(_, n)| *n);
+        let mut rows = vec![];
+        let mut budget = self.runner.batch_size.max(ids.len());
+        for (id, _) in ids.iter() {
+            let seq = self.seqs[*id].as_ref().unwrap();
+            let end = seq.tokens.len().min(seq.pos + budget).min(seq_len);
+            rows.extend((seq.pos..end).map(|pos| Llama2Row {
+                seq: seq.seq,
+                token: seq.tokens[pos],
+                pos,
+                logits: pos == seq.tokens.len() - 1,
+            }));
+            budget -= end - seq.pos;
+        }
+        if rows.is_empty() {
+            return Ok(outputs);
+        }
+
+        let mut logits = self.runner.forward_rows(&rows)?.into_iter();
+        for (id, _) in ids {
+            let seq = self.seqs[id].as_mut().unwrap();
+            let n = rows.iter().filter(|row| row.seq == seq.seq).count();
+            seq.pos += n;
+            if n == 0 || seq.pos < seq.tokens.len() {
+                continue;
+            }
+
+            // sample the next token from the logits
+            let mut logits = logits.next().unwrap();
+            let next_token = seq.sampler.sample(&mut logits)?;
+            seq.sampler.accept(next_token);
+
+            // the BOS token delimits sequences
+            let tokenizer = &self.runner.tokenizer;
+            if next_token == tokenizer.bos_token() || next_token == tokenizer.eos_token() {
+                self.remove(id)?;
+                outputs.push(Llama2BatchToken { id, token: None });
+                continue;
+            }
+
+            let prev_token = seq.tokens[seq.pos - 1];
+            seq.tokens.push(next_token);
+            let logprobs = seq
+                .sampler
+                .take_logprobs()
+                .map(|logprobs| {
+                    Llama2TokenLogprobs::decode(
+                        tokenizer,
+                        prev_token,
+                        logprobs,
+                        BpeSpecialMode::Skip,
+                    )
+                })
+                .transpose()?;
+            let token = Llama2GeneratedToken {
+                token: next_token,
+                text: seq.decoder.decode(tokenizer, prev_token, next_token)?,
+                pos: seq.pos,
+                elapsed: seq.last_time.elapsed(),
+                logprobs,
+            };
+            seq.last_time = Instant::now();
+            outputs.push(Llama2BatchToken {
+                id,
+                token: Some(token),
+            });
+        }
+        outputs.sort_by_key(|output| output.id);
+        Ok(outputs)
+    }
+}
+
+impl<'a, T: Tensor> Drop for Llama2BatchGenerator<'a, T> {
+    fn drop(&mut self) {
+        for seq in self.seqs.iter().flatten() {
+            // the sequence is known to be in the cache
+            let _ = self.runner.kv_cache.free_seq(seq.seq);
+        }
+    }
+}
+
+impl<T: Tensor> Llama2Runner<T> {
+    /// generate several sequences together, the sequences are kept apart from the one of
+    /// `generate`.
+    pub fn batch(&mut self) -> Result<Llama2BatchGenerator<'_, T>> {
+        if !self.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the batch generation on the recurrent state is not supported yet",
+            )
+                .into());
+        }
+        Ok(Llama2BatchGenerator {
+            runner: self,
+            seqs: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_generate_batch_seqs() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let vocab_size = lm.conf.vocab_size;
+
+        let prompts = [
+            "Lily is a cat",
+            "Tom is a dog who likes to run",
+            "Once upon",
+        ];
+        let mut expected = vec![];
+        for prompt in prompts {
+            let mut runner = Llama2Runner::try_from(&lm)?;
+            let mut sampler = Llama2Sampler::new(vocab_size, 0.0, 0.0);
+            let output = runner.generate(prompt, 10, &mut sampler)?;
+            expected.push(output.collect::<Result<Vec<String>>>()?.join(""));
+        }
+
+        // the sequences are decoded together, the third one joins on the second step, the
+        // prompts are forwarded in the batches of 4 rows
+        let mut runner = Llama2Runner::try_from(&lm)?.with_batch_size(4);
+        {
+            let mut batch = runner.batch()?;
+            batch.add(prompts[0], 10, Llama2Sampler::new(vocab_size, 0.0, 0.0))?;
+            batch.add(prompts[1], 10, Llama2Sampler::new(vocab_size, 0.0, 0.0))?;
+            let mut texts = vec![String::new(); 3];
+            let mut n_steps = 0;
+            while !batch.is_empty() || n_steps == 0 {
+                if n_steps == 1 {
+                    batch.add(prompts[2], 10, Llama2Sampler::new(vocab_size, 0.0, 0.0))?;
+                }
+                for output in batch.step()? {
+                    if let Some(token) = output.token {
+                        texts[output.id] += &token.text;
+                    }
+                }
+                n_steps += 1;
+            }
+            assert_eq!(texts, expected);
+            assert!(n_steps < 30);
+        }
+
+        // the removed sequences release their kv cache
+        let mut batch = runner.batch()?;
+        let id = batch.add(prompts[0], 10, Llama2Sampler::new(vocab_size, 0.0, 0.0))?;
+        batch.step()?;
+        batch.remove(id)?;
+        assert!(batch.remove(id).is_err());
+        drop(batch);
+        assert_eq!(runner.kv_cache.n_used_pages(), 0);
+        Ok(())
+    }
+}
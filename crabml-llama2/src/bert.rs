@@ -0,0 +1,545 @@
+use std::rc::Rc;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDeviceRef;
+use crabml::backends::cpu::CpuTensorLoader;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::ModelTensor;
+use crabml::gguf::TensorNameMap;
+use crabml::tensor::RopeOptions;
+use crabml::tensor::Tensor;
+use crabml::tensor::TensorBackend;
+use crabml::tensor::TensorGraph;
+use crabml::tokenizer::BpeTokenizer;
+
+use crate::model::CpuLlama2Model;
+use crate::model::Llama2Config;
+use crate::model::Llama2Pooling;
+
+/// the weights of the encoders like bert, which take the post norms after the residuals.
+pub struct BertWeights<T: Tensor> {
+    pub token_embedding_table: T, // (vocab_size, dim)
+    // the embedding of the token type 0 is added on all the tokens
+    pub token_types: Option<T>, // (n_token_types, dim)
+    // the absolute positions, none on the roped encoders like nomic-bert
+    pub position_embd: Option<T>, // (seq_len, dim)
+    // the layer norm on the embeddings
+    pub token_embd_norm: T,      // (dim, )
+    pub token_embd_norm_bias: T, // (dim, )
+    pub layers: Vec<BertLayerWeights<T>>,
+}
+
+pub struct BertLayerWeights<T: Tensor> {
+    pub wq: T, // (dim, dim)
+    pub wk: T, // (dim, dim)
+    pub wv: T, // (dim, dim)
+    pub wo: T, // (dim, dim)
+    pub bq: Option<T>,
+    pub bk: Option<T>,
+    pub bv: Option<T>,
+    pub bo: Option<T>,
+    // the layer norm after the residual of the attention
+    pub att_norm: T,      // (dim, )
+    pub att_norm_bias: T, // (dim, )
+    // the ffn is a swiglu if there's the gate like nomic-bert, otherwise a gelu mlp
+    pub ffn_gate: Option<T>,      // (hidden_dim, dim)
+    pub ffn_up: T,                // (hidden_dim, dim)
+    pub ffn_up_bias: Option<T>,   // (hidden_dim, )
+    pub ffn_down: T,              // (dim, hidden_dim)
+    pub ffn_down_bias: Option<T>, // (dim, )
+    // the layer norm after the residual of the ffn
+    pub ffn_norm: T,      // (dim, )
+    pub ffn_norm_bias: T, // (dim, )
+}
+
+/// the encoder models like bert, which attend to all the tokens at once without the kv cache,
+/// and pool the hidden states into the embedding of the text.
+pub struct BertModel<T: Tensor> {
+    pub conf: Llama2Config,
+    pub weights: Rc<BertWeights<T>>,
+    pub tokenizer: Rc<BpeTokenizer>,
+    pub device: T::Device,
+}
+
+impl<'a> BertModel<CpuTensor<'a>> {
+    pub fn load(gf: &'a GGUFFile<'a>, device: CpuTensorDeviceRef<'a>) -> Result<Self> {
+        let conf = CpuLlama2Model::load_config(gf)?;
+        if !conf.is_encoder() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!("the architecture {} is not an encoder", conf.arch),
+                cause: None,
+            });
+        }
+        let names = TensorNameMap::new(conf.arch, conf.n_layers);
+        let weights = Self::load_weights(gf, &conf, &names, device.clone())
+            .map_err(|err| names.explain_error(gf, err))?;
+        let tokenizer = BpeTokenizer::from_gguf(gf)?;
+        Ok(Self {
+            conf,
+            weights: Rc::new(weights),
+            tokenizer: Rc::new(tokenizer),
+            device,
+        })
+    }
+
+    fn load_weights(
+        gf: &'a GGUFFile<'a>,
+        conf: &Llama2Config,
+        names: &TensorNameMap,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<BertWeights<CpuTensor<'a>>> {
+        let loader = CpuTensorLoader::new(gf, device);
+        loader.preload_f32(&CpuLlama2Model::preload_tensors(gf))?;
+        let contains =
+            |tensor: ModelTensor, layer: usize| loader.contains(&names.name(tensor, layer));
+        let load = |tensor: ModelTensor, layer: usize| loader.load(&names.name(tensor, layer));
+        let load_f32 = |tensor: ModelTensor, layer: usize| {
+            loader.load_as(&names.name(tensor, layer), GGMLType::F32)
+        };
+        let load_optional = |tensor: ModelTensor, layer: usize| -> Result<Option<CpuTensor<'a>>> {
+            match contains(tensor, layer) {
+                true => Ok(Some(load_f32(tensor, layer)?)),
+                false => Ok(None),
+            }
+        };
+
+        let mut layers = vec![];
+        for layer in 0..conf.n_layers {
+            // the q, k, v projections may be fused in one tensor, like nomic-bert
+            let (wq, wk, wv) = match contains(ModelTensor::AttnQkv, layer) {
+                true => {
+                    let rows = [conf.embedding_dim, conf.kv_dim(), conf.kv_dim()];
+                    let qkv = names.name(ModelTensor::AttnQkv, layer);
+                    let mut qkv = loader.load_split(&qkv, &rows)?;
+                    let wv = qkv.pop().unwrap();
+                    let wk = qkv.pop().unwrap();
+                    (qkv.pop().unwrap(), wk, wv)
+                }
+                false => (
+                    load(ModelTensor::AttnQ, layer)?,
+                    load(ModelTensor::AttnK, layer)?,
+                    load(ModelTensor::AttnV, layer)?,
+                ),
+            };
+            layers.push(BertLayerWeights {
+                wq,
+                wk,
+                wv,
+                wo: load(ModelTensor::AttnOutput, layer)?,
+                bq: load_optional(ModelTensor::AttnQBias, layer)?,
+                bk: load_optional(ModelTensor::AttnKBias, layer)?,
+                bv: load_optional(ModelTensor::AttnVBias, layer)?,
+                bo: load_optional(ModelTensor::AttnOutputBias, layer)?,
+                att_norm: load_f32(ModelTensor::AttnOutputNorm, layer)?,
+                att_norm_bias: load_f32(ModelTensor::AttnOutputNormBias, layer)?,
+                ffn_gate: match contains(ModelTensor::FfnGate, layer) {
+                    true => Some(load(ModelTensor::FfnGate, layer)?),
+                    false => None,
+                },
+                ffn_up: load(ModelTensor::FfnUp, layer)?,
+                ffn_up_bias: load_optional(ModelTensor::FfnUpBias, layer)?,
+                ffn_down: load(ModelTensor::FfnDown, layer)?,
+                ffn_down_bias: load_optional(ModelTensor::FfnDownBias, layer)?,
+                ffn_norm: load_f32(ModelTensor::LayerOutputNorm, layer)?,
+                ffn_norm_bias: load_f32(ModelTensor::LayerOutputNormBias, layer)?,
+            });
+        }
+        Ok(BertWeights {
+            token_embedding_table: load_f32(ModelTensor::TokenEmbd, 0)?,
+            token_types: load_optional(ModelTensor::TokenTypes, 0)?,
+            position_embd: load_optional(ModelTensor::PosEmbd, 0)?,
+            token_embd_norm: load_f32(ModelTensor::TokenEmbdNorm, 0)?,
+            token_embd_norm_bias: load_f32(ModelTensor::TokenEmbdNormBias, 0)?,
+            layers,
+        })
+    }
+}
+
+impl<T: Tensor<Device = B>, B: TensorBackend<Tensor = T>> BertModel<T> {
+    /// upload the weights of the model on the cpu onto the device of a backend, like wgpu.
+    pub fn from_cpu(cpu_model: &BertModel<CpuTensor>, device: B) -> Result<Self> {
+        let convert = |t: &CpuTensor| device.upload(t);
+        let convert_optional = |t: &Option<CpuTensor>| t.as_ref().map(convert).transpose();
+        let w = &cpu_model.weights;
+        let layers = w
+            .layers
+            .iter()
+            .map(|l| {
+                Ok(BertLayerWeights {
+                    wq: convert(&l.wq)?,
+                    wk: convert(&l.wk)?,
+                    wv: convert(&l.wv)?,
+                    wo: convert(&l.wo)?,
+                    bq: convert_optional(&l.bq)?,
+                    bk: convert_optional(&l.bk)?,
+                    bv: convert_optional(&l.bv)?,
+                    bo: convert_optional(&l.bo)?,
+                    att_norm: convert(&l.att_norm)?,
+                    att_norm_bias: convert(&l.att_norm_bias)?,
+                    ffn_gate: convert_optional(&l.ffn_gate)?,
+                    ffn_up: convert(&l.ffn_up)?,
+                    ffn_up_bias: convert_optional(&l.ffn_up_bias)?,
+                    ffn_down: convert(&l.ffn_down)?,
+                    ffn_down_bias: convert_optional(&l.ffn_down_bias)?,
+                    ffn_norm: convert(&l.ffn_norm)?,
+                    ffn_norm_bias: convert(&l.ffn_norm_bias)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let weights = BertWeights {
+            token_embedding_table: convert(&w.token_embedding_table)?,
+            token_types: convert_optional(&w.token_types)?,
+            position_embd: convert_optional(&w.position_embd)?,
+            token_embd_norm: convert(&w.token_embd_norm)?,
+            token_embd_norm_bias: convert(&w.token_embd_norm_bias)?,
+            layers,
+        };
+        Ok(Self {
+            conf: cpu_model.conf,
+            weights: Rc::new(weights),
+            tokenizer: cpu_model.tokenizer.clone(),
+            device,
+        })
+    }
+}
+
+impl<T: Tensor> BertModel<T> {
+    /// the embedding of the text, pooled from the hidden states of the tokens by the pooling
+    /// type of the model.
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let tokens = self.tokenizer.encode_special(
+            text,
+            self.tokenizer.add_bos_token(),
+            self.tokenizer.add_eos_token(),
+        )?;
+        let hidden = self.encode(&tokens)?;
+        pool(&hidden, self.conf.pooling)
+    }
+
+    /// the hidden states of the last layer on each token, every token attends to all the
+    /// tokens in both directions.
+    pub fn encode(&self, tokens: &[usize]) -> Result<Vec<Vec<f32>>> {
+        if tokens.is_empty() || tokens.len() > self.conf.seq_len {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "expected 1 to {} tokens to encode, got {}",
+                    self.conf.seq_len,
+                    tokens.len()
+                ),
+                cause: None,
+            });
+        }
+        let mut xs = tokens
+            .iter()
+            .enumerate()
+            .map(|(pos, token)| self.forward_embedding(*token, pos))
+            .collect::<Result<Vec<_>>>()?;
+        for l in 0..self.conf.n_layers {
+            xs = self.forward_layer(xs, l)?;
+        }
+        xs.iter()
+            .map(|x| {
+                let mut buf = vec![0.0; self.conf.embedding_dim];
+                x.export(&mut buf)?;
+                Ok(buf)
+            })
+            .collect()
+    }
+
+    fn forward_embedding(&self, token: usize, pos: usize) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let w = &self.weights;
+        let mut x = T::alloc(&[embed_dim], None, self.device.clone())?;
+        x.copy_from(&w.token_embedding_table, &[token, 0], embed_dim)?;
+        for (table, row) in [(&w.token_types, 0), (&w.position_embd, pos)] {
+            if let Some(table) = table {
+                let mut e = T::alloc(&[embed_dim], None, self.device.clone())?;
+                e.copy_from(table, &[row, 0], embed_dim)?;
+                x = x.add_inplace(&e)?;
+            }
+        }
+        self.forward_norm(x, &w.token_embd_norm, &w.token_embd_norm_bias)
+    }
+
+    fn forward_layer(&self, xs: Vec<T>, l: usize) -> Result<Vec<T>> {
+        let conf = &self.conf;
+        let (n_heads, head_size) = (conf.n_heads, conf.head_size());
+        let w = &self.weights.layers[l];
+        // the encoders without the position embeddings take the rope, like nomic-bert
+        let rope = match self.weights.position_embd {
+            Some(_) => None,
+            None => Some(
+                RopeOptions::new(conf.rope_mode, conf.rope_dim)
+                    .with_freq_base(conf.rope_freq_base)
+                    .with_freq_scale(conf.rope_freq_scale)
+                    .with_scaling(conf.rope_scaling),
+            ),
+        };
+
+        // the keys and values of all the tokens, in the layout of the kv cache of the runner
+        let cache_shape = [0, n_heads, head_size];
+        let capacity = Some(xs.len() * conf.embedding_dim);
+        let mut k_cache = T::alloc(&cache_shape, capacity, self.device.clone())?;
+        let mut v_cache = T::alloc(&cache_shape, capacity, self.device.clone())?;
+        let mut qs = vec![];
+        for (pos, x) in xs.iter().enumerate() {
+            let q = add_bias(w.wq.matmul_vec(x)?, &w.bq)?.reshape(&[n_heads, head_size])?;
+            let k = add_bias(w.wk.matmul_vec(x)?, &w.bk)?.reshape(&[n_heads, head_size])?;
+            let v = add_bias(w.wv.matmul_vec(x)?, &w.bv)?.reshape(&[n_heads, head_size])?;
+            let (q, k) = match &rope {
+                Some(rope) => (q.rope_inplace(pos, rope)?, k.rope_inplace(pos, rope)?),
+                None => (q, k),
+            };
+            k_cache.extend(&k)?;
+            v_cache.extend(&v)?;
+            qs.push(q);
+        }
+        // (n_heads, n_seq, head_size) and (n_heads, head_size, n_seq)
+        let k_cache = k_cache.transpose(&[1, 0, 2])?;
+        let v_cache = v_cache.transpose(&[1, 2, 0])?;
+
+        xs.into_iter()
+            .zip(qs)
+            .map(|(x, q)| {
+                let attn = k_cache.batch_matmul_vec(&q)?;
+                let attn = attn
+                    .div_scalar_inplace((head_size as f32).sqrt())?
+                    .softmax_inplace(1)?;
+                let h = v_cache
+                    .batch_matmul_vec(&attn)?
+                    .reshape(&[conf.embedding_dim])?;
+                let h = add_bias(w.wo.matmul_vec(&h)?, &w.bo)?;
+                let x = self.forward_norm(h.add_inplace(&x)?, &w.att_norm, &w.att_norm_bias)?;
+
+                let h = self.forward_ffn(&x, w)?;
+                self.forward_norm(h.add_inplace(&x)?, &w.ffn_norm, &w.ffn_norm_bias)
+            })
+            .collect()
+    }
+
+    fn forward_ffn(&self, x: &T, w: &BertLayerWeights<T>) -> Result<T> {
+        let h = add_bias(w.ffn_up.matmul_vec(x)?, &w.ffn_up_bias)?;
+        let h = match &w.ffn_gate {
+            Some(gate) => TensorGraph::new(gate.matmul_vec(x)?)
+                .silu()
+                .mul(&h)
+                .eval()?,
+            None => h.gelu_inplace()?,
+        };
+        add_bias(w.ffn_down.matmul_vec(&h)?, &w.ffn_down_bias)
+    }
+
+    fn forward_norm(&self, x: T, weight: &T, bias: &T) -> Result<T> {
+        TensorGraph::new(x)
+            .layer_norm(self.conf.norm_eps)
+            .mul(weight)
+            .add(bias)
+            .eval()
+    }
+}
+
+fn add_bias<T: Tensor>(x: T, bias: &Option<T>) -> Result<T> {
+    match bias {
+        Some(bias) => x.add_inplace(bias),
+        None => Ok(x),
+    }
+}
+
+/// pool the hidden states of the tokens into one embedding.
+pub fn pool(hidden: &[Vec<f32>], pooling: Llama2Pooling) -> Result<Vec<f32>> {
+    match pooling {
+        Llama2Pooling::None => Err(Error {
+            kind: ErrorKind::BadInput,
+            message: "the model takes no pooling of the embeddings".to_string(),
+            cause: None,
+        }),
+        Llama2Pooling::Mean => {
+            let mut sum = vec![0.0; hidden[0].len()];
+            for h in hidden {
+                sum.iter_mut().zip(h).for_each(|(s, h)| *s += h);
+            }
+            Ok(sum.iter().map(|s| s / hidden.len() as f32).collect())
+        }
+        Llama2Pooling::Cls => Ok(hidden[0].clone()),
+        Llama2Pooling::Last => Ok(hidden[hidden.len() - 1].clone()),
+    }
+}
+
+/// scale the embedding to the unit length, so the dot product of two embeddings is their
+/// cosine similarity.
+pub fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::backends::wgpu::WgpuTensorDevice;
+    use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataArray;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFWriter;
+
+    use super::*;
+
+    const TOKENS: [&str; 8] = ["[PAD]", "[UNK]", "[CLS]", "[SEP]", "▁a", "▁b", "▁c", "▁d"];
+
+    /// write a tiny encoder of 2 layers and 2 heads with the pseudo random weights in the
+    /// memory. bert takes the position embeddings and the gelu mlp with the biases, while
+    /// nomic-bert takes the rope, the fused qkv and the swiglu without the biases.
+    fn write_bert_model(arch: &'static str) -> Result<GGUFFileLoader> {
+        let (embed, ffn, vocab) = (32, 64, TOKENS.len());
+        let nomic = arch == "nomic-bert";
+        let key = |key: &str| format!("{}.{}", arch, key);
+        let mut w = GGUFWriter::new();
+        w.add_metadata("general.architecture", GGUFMetadataValue::String(arch));
+        w.add_metadata(&key("context_length"), GGUFMetadataValue::U32(16));
+        w.add_metadata(
+            &key("embedding_length"),
+            GGUFMetadataValue::U32(embed as u32),
+        );
+        w.add_metadata(&key("block_count"), GGUFMetadataValue::U32(2));
+        w.add_metadata(
+            &key("feed_forward_length"),
+            GGUFMetadataValue::U32(ffn as u32),
+        );
+        w.add_metadata(&key("attention.head_count"), GGUFMetadataValue::U32(2));
+        w.add_metadata(
+            &key("attention.layer_norm_epsilon"),
+            GGUFMetadataValue::F32(1e-12),
+        );
+        w.add_metadata(&key("attention.causal"), GGUFMetadataValue::Bool(0));
+        w.add_metadata(&key("pooling_type"), GGUFMetadataValue::U32(1));
+        w.add_metadata("tokenizer.ggml.model", GGUFMetadataValue::String("bert"));
+        w.add_metadata(
+            "tokenizer.ggml.tokens",
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(TOKENS.to_vec())),
+        );
+        w.add_metadata("tokenizer.ggml.cls_token_id", GGUFMetadataValue::U32(2));
+        w.add_metadata(
+            "tokenizer.ggml.seperator_token_id",
+            GGUFMetadataValue::U32(3),
+        );
+        w.add_metadata("tokenizer.ggml.unknown_token_id", GGUFMetadataValue::U32(1));
+
+        let mut seed = 0;
+        let mut add = |name: &str, dims: &[usize], offset: f32| -> Result<()> {
+            let buf = (0..dims.iter().product::<usize>())
+                .flat_map(|_| {
+                    seed += 1;
+                    let v = offset + (seed as f32 * 12.9898).sin() * 0.5;
+                    v.to_le_bytes()
+                })
+                .collect::<Vec<u8>>();
+            w.add_tensor(name, dims, GGMLType::F32, buf)
+        };
+        add("token_embd.weight", &[embed, vocab], 0.0)?;
+        add("token_types.weight", &[embed, 2], 0.0)?;
+        if !nomic {
+            add("position_embd.weight", &[embed, 16], 0.0)?;
+        }
+        add("token_embd_norm.weight", &[embed], 1.0)?;
+        add("token_embd_norm.bias", &[embed], 0.0)?;
+        for l in 0..2 {
+            let name = |name: &str| format!("blk.{}.{}", l, name);
+            if nomic {
+                add(&name("attn_qkv.weight"), &[embed, embed * 3], 0.0)?;
+                add(&name("ffn_gate.weight"), &[embed, ffn], 0.0)?;
+            } else {
+                for m in ["attn_q", "attn_k", "attn_v"] {
+                    add(&name(&format!("{}.weight", m)), &[embed, embed], 0.0)?;
+                    add(&name(&format!("{}.bias", m)), &[embed], 0.0)?;
+                }
+                add(&name("attn_output.bias"), &[embed], 0.0)?;
+                add(&name("ffn_up.bias"), &[ffn], 0.0)?;
+                add(&name("ffn_down.bias"), &[embed], 0.0)?;
+            }
+            add(&name("attn_output.weight"), &[embed, embed], 0.0)?;
+            add(&name("ffn_up.weight"), &[embed, ffn], 0.0)?;
+            add(&name("ffn_down.weight"), &[ffn, embed], 0.0)?;
+            for norm in ["attn_output_norm", "layer_output_norm"] {
+                add(&name(&format!("{}.weight", norm)), &[embed], 1.0)?;
+                add(&name(&format!("{}.bias", norm)), &[embed], 0.0)?;
+            }
+        }
+
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    fn assert_encode_same_on_gpu(model_cpu: &BertModel<CpuTensor>, tokens: &[usize]) -> Result<()> {
+        let device_wgpu = WgpuTensorDevice::new(WgpuTensorDeviceOptions::new());
+        let model_wgpu = BertModel::from_cpu(model_cpu, device_wgpu)?;
+        let hidden_cpu = model_cpu.encode(tokens)?;
+        let hidden_wgpu = model_wgpu.encode(tokens)?;
+        for (a, b) in hidden_cpu
+            .iter()
+            .flatten()
+            .zip(hidden_wgpu.iter().flatten())
+        {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_bert() -> Result<()> {
+        let gl = write_bert_model("bert")?;
+        let gf = gl.open()?;
+        let model = BertModel::load(&gf, CpuTensorDevice::new())?;
+        assert!(CpuLlama2Model::load(&gf, CpuTensorDevice::new()).is_err());
+
+        // the tokens attend to the ones after them
+        let hidden = model.encode(&[2, 4, 5, 3])?;
+        let hidden2 = model.encode(&[2, 4, 6, 3])?;
+        assert_eq!(hidden.len(), 4);
+        assert_ne!(hidden[1], hidden2[1]);
+
+        let embedding = model.embed("a b")?;
+        assert_eq!(embedding, pool(&hidden, Llama2Pooling::Mean)?);
+        assert!(model.encode(&[4; 17]).is_err());
+
+        assert_encode_same_on_gpu(&model, &[2, 4, 5, 3])
+    }
+
+    #[test]
+    fn test_encode_nomic_bert() -> Result<()> {
+        let gl = write_bert_model("nomic-bert")?;
+        let gf = gl.open()?;
+        let model = BertModel::load(&gf, CpuTensorDevice::new())?;
+
+        // the positions are taken by the rope
+        let hidden = model.encode(&[2, 4, 5, 3])?;
+        let hidden2 = model.encode(&[2, 5, 4, 3])?;
+        assert_ne!(hidden[0], hidden2[0]);
+
+        assert_encode_same_on_gpu(&model, &[2, 4, 5, 3])
+    }
+
+    #[test]
+    fn test_pool() -> Result<()> {
+        let hidden = vec![vec![1.0, 2.0], vec![3.0, 6.0]];
+        assert_eq!(pool(&hidden, Llama2Pooling::Mean)?, vec![2.0, 4.0]);
+        assert_eq!(pool(&hidden, Llama2Pooling::Cls)?, vec![1.0, 2.0]);
+        assert_eq!(pool(&hidden, Llama2Pooling::Last)?, vec![3.0, 6.0]);
+        assert!(pool(&hidden, Llama2Pooling::None).is_err());
+
+        let mut embedding = vec![3.0, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, vec![0.6, 0.8]);
+        let mut embedding = vec![0.0, 0.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, vec![0.0, 0.0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,1413 @@
+//! chat templates to format a conversation into the prompt. the template is read from
+//! `tokenizer.chat_template` of the model, which is a Jinja template like:
+//!
+//! ```text
+//! {% for message in messages %}
+//! {{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}
+//! {% endfor %}
+//! {% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}
+//! ```
+//!
+//! only the subset of Jinja used by the chat templates is supported: the `if`, `for` and
+//! `set` statements, the expressions with the usual operators, the `loop` variable, the
+//! common filters, tests and string methods, and `raise_exception`. the templates are
+//! rendered with `trim_blocks` and `lstrip_blocks` like the `transformers` library. if the
+//! template can not be parsed, one of the built-in formats is picked by the look of the
+//! template.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::KEY_TOKENIZER_CHAT_TEMPLATE;
+use crabml::tokenizer::BpeTokenizer;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    /// the role of the message, like "system", "user" or "assistant".
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ChatTemplate {
+    /// `<s>[INST] <<SYS>>\n{system}\n<</SYS>>\n\n{user} [/INST] {assistant} </s>`
+    Llama2,
+    /// `<|im_start|>{role}\n{content}<|im_end|>\n`
+    ChatMl,
+    /// `<s>[INST] {user} [/INST]{assistant}</s>`
+    Mistral,
+    Jinja(JinjaTemplate),
+}
+
+impl ChatTemplate {
+    /// the template in the metadata of the model, Llama-2 is used if there's no template.
+    pub fn from_gguf(gf: &GGUFFile) -> Self {
+        match gf.header().get_str(KEY_TOKENIZER_CHAT_TEMPLATE) {
+            Ok(src) => Self::parse(src),
+            Err(_) => ChatTemplate::Llama2,
+        }
+    }
+
+    /// parse the Jinja template, falls back to a built-in format if it's not supported.
+    pub fn parse(src: &str) -> Self {
+        match JinjaTemplate::parse(src) {
+            Ok(template) => ChatTemplate::Jinja(template),
+            Err(_) => Self::detect(src),
+        }
+    }
+
+    /// pick the built-in format by the special tokens in the template.
+    pub fn detect(src: &str) -> Self {
+        if src.contains("<|im_start|>") {
+            ChatTemplate::ChatMl
+        } else if src.contains("[INST]") && !src.contains("<<SYS>>") {
+            ChatTemplate::Mistral
+        } else {
+            ChatTemplate::Llama2
+        }
+    }
+
+    /// the built-in format by the name: "llama2", "chatml" or "mistral".
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "llama2" => Some(ChatTemplate::Llama2),
+            "chatml" => Some(ChatTemplate::ChatMl),
+            "mistral" => Some(ChatTemplate::Mistral),
+            _ => None,
+        }
+    }
+
+    /// format the messages into the prompt, with the beginning of the assistant's reply if
+    /// `add_generation_prompt`. the leading bos token is left out if the tokenizer prepends
+    /// it on encoding.
+    pub fn render(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+        tokenizer: &BpeTokenizer,
+    ) -> Result<String> {
+        let bos = tokenizer.token(tokenizer.bos_token());
+        let eos = tokenizer.token(tokenizer.eos_token());
+        let prompt = match self {
+            ChatTemplate::Llama2 => render_llama2(messages, &bos, &eos),
+            ChatTemplate::ChatMl => render_chatml(messages, add_generation_prompt),
+            ChatTemplate::Mistral => render_mistral(messages, &bos, &eos),
+            ChatTemplate::Jinja(template) => {
+                template.render(messages, add_generation_prompt, &bos, &eos)?
+            }
+        };
+        if tokenizer.add_bos_token() && !bos.is_empty() {
+            if let Some(prompt) = prompt.strip_prefix(bos.as_str()) {
+                return Ok(prompt.to_string());
+            }
+        }
+        Ok(prompt)
+    }
+}
+
+/// the system message is merged into the first user message.
+fn split_system(messages: &[ChatMessage]) -> (Option<&str>, &[ChatMessage]) {
+    match messages.first() {
+        Some(m) if m.role == "system" => (Some(m.content.as_str()), &messages[1..]),
+        _ => (None, messages),
+    }
+}
+
+fn render_llama2(messages: &[ChatMessage], bos: &str, eos: &str) -> String {
+    let (mut system, messages) = split_system(messages);
+    let mut prompt = String::new();
+    for message in messages {
+        if message.role == "user" {
+            let content = match system.take() {
+                Some(system) => format!("<<SYS>>\n{}\n<</SYS>>\n\n{}", system, message.content),
+                None => message.content.clone(),
+            };
+            prompt.push_str(&format!("{}[INST] {} [/INST]", bos, content.trim()));
+        } else {
+            prompt.push_str(&format!(" {} {}", message.content.trim(), eos));
+        }
+    }
+    prompt
+}
+
+fn render_chatml(messages: &[ChatMessage], add_generation_prompt: bool) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        prompt.push_str(&format!(
+            "<|im_start|>{}\n{}<|im_end|>\n",
+            message.role, message.content
+        ));
+    }
+    if add_generation_prompt {
+        prompt.push_str("<|im_start|>assistant\n");
+    }
+    prompt
+}
+
+fn render_mistral(messages: &[ChatMessage], bos: &str, eos: &str) -> String {
+    let (mut system, messages) = split_system(messages);
+    let mut prompt = bos.to_string();
+    for message in messages {
+        if message.role == "user" {
+            let content = match system.take() {
+                Some(system) => format!("{}\n\n{}", system, message.content),
+                None => message.content.clone(),
+            };
+            prompt.push_str(&format!("[INST] {} [/INST]", content));
+        } else {
+            prompt.push_str(&format!("{}{}", message.content, eos));
+        }
+    }
+    prompt
+}
+
+fn template_error(message: impl Into<String>) -> Error {
+    Error {
+        kind: ErrorKind::BadInput,
+        message: format!("failed to render chat template: {}", message.into()),
+        cause: None,
+    }
+}
+
+/// a parsed Jinja template.
+#[derive(Debug, Clone)]
+pub struct JinjaTemplate {
+    nodes: Vec<Node>,
+}
+
+impl JinjaTemplate {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut parser = TemplateParser {
+            segments: split_segments(src)?,
+            pos: 0,
+        };
+        let (nodes, _) = parser.parse_block(&[])?;
+        Ok(Self { nodes })
+    }
+
+    pub fn render(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+        bos_token: &str,
+        eos_token: &str,
+    ) -> Result<String> {
+        let messages = messages
+            .iter()
+            .map(|m| {
+                Value::Map(vec![
+                    ("role".to_string(), Value::Str(m.role.clone())),
+                    ("content".to_string(), Value::Str(m.content.clone())),
+                ])
+            })
+            .collect();
+        let globals = HashMap::from([
+            ("messages".to_string(), Value::List(messages)),
+            (
+                "add_generation_prompt".to_string(),
+                Value::Bool(add_generation_prompt),
+            ),
+            ("bos_token".to_string(), Value::Str(bos_token.to_string())),
+            ("eos_token".to_string(), Value::Str(eos_token.to_string())),
+        ]);
+        let mut renderer = Renderer {
+            scopes: vec![globals],
+            out: String::new(),
+        };
+        renderer.render(&self.nodes)?;
+        Ok(renderer.out)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Expr(String),
+    Stmt(String),
+}
+
+/// split the template into the texts, the `{{ }}` expressions and the `{% %}` statements,
+/// with the whitespaces around the tags trimmed by `-`, `trim_blocks` and `lstrip_blocks`.
+fn split_segments(src: &str) -> Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let mut rest = src;
+    // how the leading whitespaces of the next text are trimmed by the previous tag
+    let mut trim_all = false;
+    let mut trim_newline = false;
+    loop {
+        let start = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+        let mut text = &rest[..start.unwrap_or(rest.len())];
+        if trim_all {
+            text = text.trim_start();
+        } else if trim_newline {
+            text = text
+                .strip_prefix("\r\n")
+                .or_else(|| text.strip_prefix('\n'))
+                .unwrap_or(text);
+        }
+        let Some(start) = start else {
+            if !text.is_empty() {
+                segments.push(Segment::Text(text.to_string()));
+            }
+            return Ok(segments);
+        };
+
+        let tag = &rest[start..];
+        let is_block = !tag.starts_with("{{");
+        let close = match &tag[..2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let mut inner = &tag[2..];
+        if let Some(stripped) = inner.strip_prefix('-') {
+            text = text.trim_end();
+            inner = stripped;
+        } else if let Some(stripped) = inner.strip_prefix('+') {
+            inner = stripped;
+        } else if is_block {
+            // lstrip_blocks: strip the spaces before the block tag at the start of a line
+            let line_start = text.rfind('\n').map_or(0, |i| i + 1);
+            let at_line_start = line_start > 0 || rest.len() == src.len();
+            if at_line_start && text[line_start..].chars().all(|c| c == ' ' || c == '\t') {
+                text = &text[..line_start];
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text.to_string()));
+        }
+
+        let end = find_close(inner, close)
+            .ok_or_else(|| template_error(format!("unclosed tag {}", &tag[..2])))?;
+        let mut content = &inner[..end];
+        trim_all = false;
+        if let Some(stripped) = content.strip_suffix('-') {
+            content = stripped;
+            trim_all = true;
+        }
+        trim_newline = is_block;
+        match &tag[..2] {
+            "{{" => segments.push(Segment::Expr(content.trim().to_string())),
+            "{%" => segments.push(Segment::Stmt(content.trim().to_string())),
+            _ => {}
+        }
+        rest = &inner[end + close.len()..];
+    }
+}
+
+/// find the closing of a tag, skipping the string literals.
+fn find_close(s: &str, close: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if s[i..].starts_with(close) => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Expr(Expr),
+    If {
+        branches: Vec<(Expr, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        targets: Vec<String>,
+        iter: Expr,
+        body: Vec<Node>,
+    },
+    Set(String, Expr),
+}
+
+struct TemplateParser {
+    segments: Vec<Segment>,
+    pos: usize,
+}
+
+impl TemplateParser {
+    /// parse the nodes until one of the end statements, returns the nodes and the tokens
+    /// of the end statement.
+    fn parse_block(&mut self, ends: &[&str]) -> Result<(Vec<Node>, Vec<Tok>)> {
+        let mut nodes = vec![];
+        while self.pos < self.segments.len() {
+            let segment = self.segments[self.pos].clone();
+            self.pos += 1;
+            let src = match segment {
+                Segment::Text(text) => {
+                    nodes.push(Node::Text(text));
+                    continue;
+                }
+                Segment::Expr(src) => {
+                    nodes.push(Node::Expr(ExprParser::parse_all(lex(&src)?)?));
+                    continue;
+                }
+                Segment::Stmt(src) => src,
+            };
+
+            let toks = lex(&src)?;
+            let keyword = match toks.first() {
+                Some(Tok::Name(name)) => name.clone(),
+                _ => return Err(template_error(format!("invalid statement {}", src))),
+            };
+            if ends.contains(&keyword.as_str()) {
+                return Ok((nodes, toks));
+            }
+            let node = match keyword.as_str() {
+                "if" => self.parse_if(toks)?,
+                "for" => self.parse_for(toks)?,
+                "set" => parse_set(toks)?,
+                _ => return Err(template_error(format!("unsupported statement {}", src))),
+            };
+            nodes.push(node);
+        }
+        match ends.last() {
+            Some(end) => Err(template_error(format!("missing {}", end))),
+            None => Ok((nodes, vec![])),
+        }
+    }
+
+    fn parse_if(&mut self, mut toks: Vec<Tok>) -> Result<Node> {
+        let mut branches = vec![];
+        loop {
+            let cond = ExprParser::parse_all(toks.split_off(1))?;
+            let (body, end) = self.parse_block(&["elif", "else", "endif"])?;
+            branches.push((cond, body));
+            match &end[0] {
+                Tok::Name(name) if name == "elif" => toks = end,
+                Tok::Name(name) if name == "else" => {
+                    let (otherwise, _) = self.parse_block(&["endif"])?;
+                    return Ok(Node::If {
+                        branches,
+                        otherwise,
+                    });
+                }
+                _ => {
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: vec![],
+                    });
+                }
+            }
+        }
+    }
+
+    fn parse_for(&mut self, toks: Vec<Tok>) -> Result<Node> {
+        let mut targets = vec![];
+        let mut pos = 1;
+        loop {
+            match toks.get(pos) {
+                Some(Tok::Name(name)) if name != "in" => targets.push(name.clone()),
+                _ => return Err(template_error("invalid for statement")),
+            }
+            pos += 1;
+            match toks.get(pos) {
+                Some(Tok::Op(",")) => pos += 1,
+                Some(Tok::Name(name)) if name == "in" => break,
+                _ => return Err(template_error("invalid for statement")),
+            }
+        }
+        let iter = ExprParser::parse_all(toks[pos + 1..].to_vec())?;
+        let (body, _) = self.parse_block(&["endfor"])?;
+        Ok(Node::For {
+            targets,
+            iter,
+            body,
+        })
+    }
+}
+
+fn parse_set(toks: Vec<Tok>) -> Result<Node> {
+    match (toks.get(1), toks.get(2)) {
+        (Some(Tok::Name(name)), Some(Tok::Op("="))) => {
+            let expr = ExprParser::parse_all(toks[3..].to_vec())?;
+            Ok(Node::Set(name.clone(), expr))
+        }
+        _ => Err(template_error("unsupported set statement")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Op(&'static str),
+}
+
+const OPS: &[&str] = &[
+    "==", "!=", "<=", ">=", "//", "(", ")", "[", "]", "{", "}", ".", ",", ":", "|", "+", "-", "*",
+    "/", "%", "~", "<", ">", "=",
+];
+
+fn lex(src: &str) -> Result<Vec<Tok>> {
+    let chars = src.chars().collect::<Vec<_>>();
+    let mut toks = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            toks.push(Tok::Name(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let n: String = chars[start..i].iter().collect();
+            let n = n.parse().map_err(|_| template_error("invalid number"))?;
+            toks.push(Tok::Int(n));
+        } else if c == '\'' || c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(template_error("unclosed string")),
+                    Some(q) if *q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some(c) => s.push(*c),
+                            None => return Err(template_error("unclosed string")),
+                        }
+                    }
+                    Some(c) => s.push(*c),
+                }
+                i += 1;
+            }
+            i += 1;
+            toks.push(Tok::Str(s));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let op = OPS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| template_error(format!("unexpected character {}", c)))?;
+            toks.push(Tok::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(toks)
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Lit(Value),
+    Var(String),
+    List(Vec<Expr>),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Call(Box<Expr>, Vec<Expr>),
+    Filter(Box<Expr>, String, Vec<Expr>),
+    Test(Box<Expr>, String, bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+/// the expressions in the precedence of Jinja, from the lowest: the conditional
+/// expression, `or`, `and`, `not`, the comparisons, `+ -`, `~`, `* / // %`, the unary
+/// minus, the filters and tests, and the postfix `.`, `[]`, `()`.
+struct ExprParser {
+    toks: Vec<Tok>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn parse_all(toks: Vec<Tok>) -> Result<Expr> {
+        let mut parser = Self { toks, pos: 0 };
+        let expr = parser.parse_expr()?;
+        if parser.pos < parser.toks.len() {
+            return Err(template_error(format!(
+                "unexpected token {:?}",
+                parser.toks[parser.pos]
+            )));
+        }
+        Ok(expr)
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Tok::Op(o)) if *o == op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_name(&mut self, name: &str) -> bool {
+        if matches!(self.peek(), Some(Tok::Name(n)) if n == name) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if !self.eat_op(op) {
+            return Err(template_error(format!("expected {}", op)));
+        }
+        Ok(())
+    }
+
+    fn expect_name(&mut self) -> Result<String> {
+        match self.peek().cloned() {
+            Some(Tok::Name(name)) => {
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(template_error("expected a name")),
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr> {
+        let expr = self.parse_or()?;
+        if self.eat_name("if") {
+            let cond = self.parse_or()?;
+            let otherwise = match self.eat_name("else") {
+                true => Some(Box::new(self.parse_expr()?)),
+                false => None,
+            };
+            return Ok(Expr::Cond(Box::new(cond), Box::new(expr), otherwise));
+        }
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.eat_name("or") {
+            expr = Expr::Binary("or", Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        while self.eat_name("and") {
+            expr = Expr::Binary("and", Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_name("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_add()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tok::Op(op)) if ["==", "!=", "<", ">", "<=", ">="].contains(op) => *op,
+                Some(Tok::Name(name)) if name == "in" => "in",
+                Some(Tok::Name(name))
+                    if name == "not"
+                        && matches!(self.toks.get(self.pos + 1), Some(Tok::Name(n)) if n == "in") =>
+                {
+                    self.pos += 1;
+                    "not in"
+                }
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_add()?));
+        }
+    }
+
+    fn parse_add(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_concat()?;
+        loop {
+            let op = if self.eat_op("+") {
+                "+"
+            } else if self.eat_op("-") {
+                "-"
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_concat()?));
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_mul()?;
+        while self.eat_op("~") {
+            expr = Expr::Binary("~", Box::new(expr), Box::new(self.parse_mul()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_mul(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tok::Op(op)) if ["*", "/", "//", "%"].contains(op) => *op,
+                _ => return Ok(expr),
+            };
+            self.pos += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.eat_op("-") {
+            return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+        }
+        let mut expr = self.parse_postfix()?;
+        loop {
+            if self.eat_op("|") {
+                let name = self.expect_name()?;
+                let args = match self.eat_op("(") {
+                    true => self.parse_args(")")?,
+                    false => vec![],
+                };
+                expr = Expr::Filter(Box::new(expr), name, args);
+            } else if self.eat_name("is") {
+                let negated = self.eat_name("not");
+                let name = self.expect_name()?;
+                expr = Expr::Test(Box::new(expr), name, negated);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            if self.eat_op(".") {
+                expr = Expr::Attr(Box::new(expr), self.expect_name()?);
+            } else if self.eat_op("(") {
+                expr = Expr::Call(Box::new(expr), self.parse_args(")")?);
+            } else if self.eat_op("[") {
+                let start = match self.peek() {
+                    Some(Tok::Op(":")) => None,
+                    _ => Some(Box::new(self.parse_expr()?)),
+                };
+                if self.eat_op(":") {
+                    let end = match self.peek() {
+                        Some(Tok::Op("]")) => None,
+                        _ => Some(Box::new(self.parse_expr()?)),
+                    };
+                    expr = Expr::Slice(Box::new(expr), start, end);
+                } else {
+                    let index = start.ok_or_else(|| template_error("expected an index"))?;
+                    expr = Expr::Index(Box::new(expr), index);
+                }
+                self.expect_op("]")?;
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_args(&mut self, close: &str) -> Result<Vec<Expr>> {
+        let mut args = vec![];
+        while !self.eat_op(close) {
+            if !args.is_empty() {
+                self.expect_op(",")?;
+            }
+            // the keyword arguments like `tojson(indent=4)` are not supported
+            if matches!(self.toks.get(self.pos + 1), Some(Tok::Op("="))) {
+                return Err(template_error("keyword arguments are not supported"));
+            }
+            args.push(self.parse_expr()?);
+        }
+        Ok(args)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let tok = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| template_error("unexpected end of expression"))?;
+        self.pos += 1;
+        let expr = match tok {
+            Tok::Name(name) => match name.as_str() {
+                "true" | "True" => Expr::Lit(Value::Bool(true)),
+                "false" | "False" => Expr::Lit(Value::Bool(false)),
+                "none" | "None" => Expr::Lit(Value::None),
+                _ => Expr::Var(name),
+            },
+            Tok::Str(mut s) => {
+                // the adjacent string literals are concatenated
+                while let Some(Tok::Str(next)) = self.peek() {
+                    s.push_str(next);
+                    self.pos += 1;
+                }
+                Expr::Lit(Value::Str(s))
+            }
+            Tok::Int(n) => Expr::Lit(Value::Int(n)),
+            Tok::Op("(") => {
+                let expr = self.parse_expr()?;
+                self.expect_op(")")?;
+                expr
+            }
+            Tok::Op("[") => Expr::List(self.parse_args("]")?),
+            tok => return Err(template_error(format!("unexpected token {:?}", tok))),
+        };
+        Ok(expr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Undefined | Value::None => false,
+            Value::Bool(b) => *b,
+            Value::Int(n) => *n != 0,
+            Value::Str(s) => !s.is_empty(),
+            Value::List(items) => !items.is_empty(),
+            Value::Map(items) => !items.is_empty(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Value {
+        match self {
+            Value::Map(items) => items
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or(Value::Undefined, |(_, v)| v.clone()),
+            _ => Value::Undefined,
+        }
+    }
+
+    fn as_str(&self) -> Result<&str> {
+        match self {
+            Value::Str(s) => Ok(s),
+            v => Err(template_error(format!(
+                "expected a string, got {}",
+                v.repr()
+            ))),
+        }
+    }
+
+    fn as_int(&self) -> Result<i64> {
+        match self {
+            Value::Int(n) => Ok(*n),
+            Value::Bool(b) => Ok(*b as i64),
+            v => Err(template_error(format!(
+                "expected an integer, got {}",
+                v.repr()
+            ))),
+        }
+    }
+
+    fn len(&self) -> Result<usize> {
+        match self {
+            Value::Str(s) => Ok(s.chars().count()),
+            Value::List(items) => Ok(items.len()),
+            Value::Map(items) => Ok(items.len()),
+            v => Err(template_error(format!("{} has no length", v.repr()))),
+        }
+    }
+
+    fn items(&self) -> Result<Vec<Value>> {
+        match self {
+            Value::List(items) => Ok(items.clone()),
+            Value::Map(items) => Ok(items.iter().map(|(k, _)| Value::Str(k.clone())).collect()),
+            Value::Str(s) => Ok(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+            v => Err(template_error(format!("{} is not iterable", v.repr()))),
+        }
+    }
+
+    /// the representation in python, like the strings in a list.
+    fn repr(&self) -> String {
+        match self {
+            Value::Str(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+            v => v.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Undefined | Value::None => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(*b),
+            Value::Int(n) => serde_json::Value::from(*n),
+            Value::Str(s) => serde_json::Value::String(s.clone()),
+            Value::List(items) => items.iter().map(|v| v.to_json()).collect(),
+            Value::Map(items) => {
+                let map = items.iter().map(|(k, v)| (k.clone(), v.to_json()));
+                serde_json::Value::Object(map.collect())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Undefined => Ok(()),
+            Value::None => write!(f, "None"),
+            Value::Bool(true) => write!(f, "True"),
+            Value::Bool(false) => write!(f, "False"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(items) => {
+                let items = items.iter().map(|v| v.repr()).collect::<Vec<_>>();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map(items) => {
+                let items = items
+                    .iter()
+                    .map(|(k, v)| format!("'{}': {}", k, v.repr()))
+                    .collect::<Vec<_>>();
+                write!(f, "{{{}}}", items.join(", "))
+            }
+        }
+    }
+}
+
+struct Renderer {
+    // the global variables, and a scope for each iteration of the loops
+    scopes: Vec<HashMap<String, Value>>,
+    out: String,
+}
+
+impl Renderer {
+    fn render(&mut self, nodes: &[Node]) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => self.out.push_str(text),
+                Node::Expr(expr) => {
+                    let value = self.eval(expr)?;
+                    self.out.push_str(&value.to_string());
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let mut body = otherwise;
+                    for (cond, branch) in branches {
+                        if self.eval(cond)?.is_truthy() {
+                            body = branch;
+                            break;
+                        }
+                    }
+                    self.render(body)?;
+                }
+                Node::For {
+                    targets,
+                    iter,
+                    body,
+                } => {
+                    let items = self.eval(iter)?.items()?;
+                    for (i, item) in items.iter().enumerate() {
+                        let mut scope = HashMap::new();
+                        if targets.len() == 1 {
+                            scope.insert(targets[0].clone(), item.clone());
+                        } else {
+                            match item {
+                                Value::List(values) if values.len() == targets.len() => {
+                                    scope.extend(targets.iter().cloned().zip(values.clone()));
+                                }
+                                _ => return Err(template_error("failed to unpack the item")),
+                            }
+                        }
+                        let n = items.len() as i64;
+                        let i = i as i64;
+                        let loop_var = Value::Map(vec![
+                            ("index0".to_string(), Value::Int(i)),
+                            ("index".to_string(), Value::Int(i + 1)),
+                            ("revindex0".to_string(), Value::Int(n - i - 1)),
+                            ("revindex".to_string(), Value::Int(n - i)),
+                            ("first".to_string(), Value::Bool(i == 0)),
+                            ("last".to_string(), Value::Bool(i == n - 1)),
+                            ("length".to_string(), Value::Int(n)),
+                        ]);
+                        scope.insert("loop".to_string(), loop_var);
+                        self.scopes.push(scope);
+                        let result = self.render(body);
+                        self.scopes.pop();
+                        result?;
+                    }
+                }
+                Node::Set(name, expr) => {
+                    let value = self.eval(expr)?;
+                    self.scopes.last_mut().unwrap().insert(name.clone(), value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Value {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .unwrap_or(Value::Undefined)
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value> {
+        let value = match expr {
+            Expr::Lit(value) => value.clone(),
+            Expr::Var(name) => self.lookup(name),
+            Expr::List(items) => {
+                let items = items.iter().map(|e| self.eval(e));
+                Value::List(items.collect::<Result<_>>()?)
+            }
+            Expr::Attr(obj, name) => self.eval(obj)?.get(name),
+            Expr::Index(obj, index) => {
+                let obj = self.eval(obj)?;
+                match (&obj, self.eval(index)?) {
+                    (Value::Map(_), Value::Str(key)) => obj.get(&key),
+                    (Value::List(_) | Value::Str(_), Value::Int(i)) => {
+                        let items = obj.items()?;
+                        let i = if i < 0 { i + items.len() as i64 } else { i };
+                        items.get(i as usize).cloned().unwrap_or(Value::Undefined)
+                    }
+                    _ => Value::Undefined,
+                }
+            }
+            Expr::Slice(obj, start, end) => {
+                let obj = self.eval(obj)?;
+                let n = obj.len()? as i64;
+                let bound = |e: &Option<Box<Expr>>, default: i64| -> Result<usize> {
+                    let i = match e {
+                        Some(e) => self.eval(e)?.as_int()?,
+                        None => default,
+                    };
+                    let i = if i < 0 { i + n } else { i };
+                    Ok(i.clamp(0, n) as usize)
+                };
+                let (start, end) = (bound(start, 0)?, bound(end, n)?);
+                let items = obj.items()?;
+                let items = items[start.min(end)..end].to_vec();
+                match obj {
+                    Value::Str(_) => Value::Str(items.iter().map(|v| v.to_string()).collect()),
+                    _ => Value::List(items),
+                }
+            }
+            Expr::Call(callee, args) => {
+                let args = args.iter().map(|e| self.eval(e)).collect::<Result<_>>()?;
+                match callee.as_ref() {
+                    Expr::Attr(obj, name) => call_method(self.eval(obj)?, name, args)?,
+                    Expr::Var(name) => call_function(name, args)?,
+                    _ => return Err(template_error("invalid call")),
+                }
+            }
+            Expr::Filter(obj, name, args) => {
+                let args = args.iter().map(|e| self.eval(e)).collect::<Result<_>>()?;
+                apply_filter(self.eval(obj)?, name, args)?
+            }
+            Expr::Test(obj, name, negated) => {
+                Value::Bool(apply_test(&self.eval(obj)?, name)? != *negated)
+            }
+            Expr::Not(expr) => Value::Bool(!self.eval(expr)?.is_truthy()),
+            Expr::Neg(expr) => Value::Int(-self.eval(expr)?.as_int()?),
+            Expr::Binary("and", lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                match lhs.is_truthy() {
+                    true => self.eval(rhs)?,
+                    false => lhs,
+                }
+            }
+            Expr::Binary("or", lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                match lhs.is_truthy() {
+                    true => lhs,
+                    false => self.eval(rhs)?,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => binary_op(op, self.eval(lhs)?, self.eval(rhs)?)?,
+            Expr::Cond(cond, then, otherwise) => {
+                if self.eval(cond)?.is_truthy() {
+                    self.eval(then)?
+                } else {
+                    match otherwise {
+                        Some(otherwise) => self.eval(otherwise)?,
+                        None => Value::Undefined,
+                    }
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+fn binary_op(op: &str, lhs: Value, rhs: Value) -> Result<Value> {
+    let value = match op {
+        "==" => Value::Bool(lhs == rhs),
+        "!=" => Value::Bool(lhs != rhs),
+        "<" | ">" | "<=" | ">=" => {
+            let ord = match (&lhs, &rhs) {
+                (Value::Str(a), Value::Str(b)) => a.cmp(b),
+                _ => lhs.as_int()?.cmp(&rhs.as_int()?),
+            };
+            Value::Bool(match op {
+                "<" => ord.is_lt(),
+                ">" => ord.is_gt(),
+                "<=" => ord.is_le(),
+                _ => ord.is_ge(),
+            })
+        }
+        "in" | "not in" => {
+            let found = match &rhs {
+                Value::Str(s) => s.contains(lhs.as_str()?),
+                Value::List(items) => items.contains(&lhs),
+                Value::Map(_) => rhs.get(lhs.as_str()?) != Value::Undefined,
+                _ => false,
+            };
+            Value::Bool(found == (op == "in"))
+        }
+        "~" => Value::Str(format!("{}{}", lhs, rhs)),
+        "+" => match (lhs, rhs) {
+            (Value::Str(a), Value::Str(b)) => Value::Str(a + &b),
+            (Value::List(a), Value::List(b)) => Value::List([a, b].concat()),
+            (a, b) => Value::Int(a.as_int()? + b.as_int()?),
+        },
+        _ => {
+            let (a, b) = (lhs.as_int()?, rhs.as_int()?);
+            if b == 0 && matches!(op, "/" | "//" | "%") {
+                return Err(template_error("division by zero"));
+            }
+            Value::Int(match op {
+                "-" => a - b,
+                "*" => a * b,
+                "%" => a.rem_euclid(b),
+                _ => a.div_euclid(b),
+            })
+        }
+    };
+    Ok(value)
+}
+
+fn call_function(name: &str, args: Vec<Value>) -> Result<Value> {
+    match name {
+        "raise_exception" => {
+            let message = args.first().map(|v| v.to_string()).unwrap_or_default();
+            Err(template_error(message))
+        }
+        "range" => {
+            let (start, end) = match args.as_slice() {
+                [end] => (0, end.as_int()?),
+                [start, end] => (start.as_int()?, end.as_int()?),
+                _ => return Err(template_error("invalid arguments of range")),
+            };
+            Ok(Value::List((start..end).map(Value::Int).collect()))
+        }
+        _ => Err(template_error(format!("unknown function {}", name))),
+    }
+}
+
+fn call_method(obj: Value, name: &str, args: Vec<Value>) -> Result<Value> {
+    let arg = |i: usize| -> Result<&str> {
+        args.get(i)
+            .ok_or_else(|| template_error(format!("missing argument of {}", name)))?
+            .as_str()
+    };
+    if let Value::Map(items) = &obj {
+        return match name {
+            "get" => match obj.get(arg(0)?) {
+                Value::Undefined => Ok(args.get(1).cloned().unwrap_or(Value::None)),
+                v => Ok(v),
+            },
+            "keys" => Ok(Value::List(
+                items.iter().map(|(k, _)| Value::Str(k.clone())).collect(),
+            )),
+            "values" => Ok(Value::List(items.iter().map(|(_, v)| v.clone()).collect())),
+            "items" => Ok(Value::List(
+                items
+                    .iter()
+                    .map(|(k, v)| Value::List(vec![Value::Str(k.clone()), v.clone()]))
+                    .collect(),
+            )),
+            _ => Err(template_error(format!("unknown method {}", name))),
+        };
+    }
+
+    let s = obj.as_str()?;
+    let strip_chars = |i: usize| -> Result<Vec<char>> {
+        match args.get(i) {
+            Some(chars) => Ok(chars.as_str()?.chars().collect()),
+            None => Ok(vec![]),
+        }
+    };
+    let stripped = |chars: &[char], c: char| {
+        if chars.is_empty() {
+            c.is_whitespace()
+        } else {
+            chars.contains(&c)
+        }
+    };
+    let value = match name {
+        "strip" => {
+            let chars = strip_chars(0)?;
+            Value::Str(s.trim_matches(|c| stripped(&chars, c)).to_string())
+        }
+        "lstrip" => {
+            let chars = strip_chars(0)?;
+            Value::Str(s.trim_start_matches(|c| stripped(&chars, c)).to_string())
+        }
+        "rstrip" => {
+            let chars = strip_chars(0)?;
+            Value::Str(s.trim_end_matches(|c| stripped(&chars, c)).to_string())
+        }
+        "startswith" => Value::Bool(s.starts_with(arg(0)?)),
+        "endswith" => Value::Bool(s.ends_with(arg(0)?)),
+        "upper" => Value::Str(s.to_uppercase()),
+        "lower" => Value::Str(s.to_lowercase()),
+        "replace" => Value::Str(s.replace(arg(0)?, arg(1)?)),
+        "split" => {
+            let parts: Vec<&str> = match args.first() {
+                Some(sep) => s.split(sep.as_str()?).collect(),
+                None => s.split_whitespace().collect(),
+            };
+            Value::List(
+                parts
+                    .into_iter()
+                    .map(|p| Value::Str(p.to_string()))
+                    .collect(),
+            )
+        }
+        _ => return Err(template_error(format!("unknown method {}", name))),
+    };
+    Ok(value)
+}
+
+fn apply_filter(obj: Value, name: &str, args: Vec<Value>) -> Result<Value> {
+    let value = match name {
+        "trim" => Value::Str(obj.as_str()?.trim().to_string()),
+        "upper" => Value::Str(obj.as_str()?.to_uppercase()),
+        "lower" => Value::Str(obj.as_str()?.to_lowercase()),
+        "capitalize" => {
+            let s = obj.as_str()?.to_lowercase();
+            let mut chars = s.chars();
+            let first = chars.next().map(|c| c.to_uppercase().to_string());
+            Value::Str(first.unwrap_or_default() + chars.as_str())
+        }
+        "length" | "count" => Value::Int(obj.len()? as i64),
+        "string" => Value::Str(obj.to_string()),
+        "tojson" => Value::Str(obj.to_json().to_string()),
+        "list" => Value::List(obj.items()?),
+        "first" => obj.items()?.first().cloned().unwrap_or(Value::Undefined),
+        "last" => obj.items()?.last().cloned().unwrap_or(Value::Undefined),
+        "join" => {
+            let sep = match args.first() {
+                Some(sep) => sep.as_str()?.to_string(),
+                None => String::new(),
+            };
+            let items = obj
+                .items()?
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>();
+            Value::Str(items.join(&sep))
+        }
+        "default" | "d" => match obj {
+            Value::Undefined => args.into_iter().next().unwrap_or(Value::Undefined),
+            obj => obj,
+        },
+        _ => return Err(template_error(format!("unknown filter {}", name))),
+    };
+    Ok(value)
+}
+
+fn apply_test(obj: &Value, name: &str) -> Result<bool> {
+    let result = match name {
+        "defined" => *obj != Value::Undefined,
+        "undefined" => *obj == Value::Undefined,
+        "none" => *obj == Value::None,
+        "string" => matches!(obj, Value::Str(_)),
+        "number" | "integer" => matches!(obj, Value::Int(_)),
+        "boolean" => matches!(obj, Value::Bool(_)),
+        "true" => *obj == Value::Bool(true),
+        "false" => *obj == Value::Bool(false),
+        "mapping" => matches!(obj, Value::Map(_)),
+        "sequence" | "iterable" => matches!(obj, Value::List(_) | Value::Str(_) | Value::Map(_)),
+        "even" => obj.as_int()? % 2 == 0,
+        "odd" => obj.as_int()? % 2 != 0,
+        _ => return Err(template_error(format!("unknown test {}", name))),
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LLAMA2_TEMPLATE: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+    const CHATML_TEMPLATE: &str = "{% for message in messages %}
+    {{- '<|im_start|>' + message.role + '\n' + message.content | trim + '<|im_end|>\n' -}}
+{% endfor %}
+{% if add_generation_prompt %}
+    {{- '<|im_start|>assistant\n' -}}
+{% endif %}";
+
+    fn tokenizer() -> BpeTokenizer {
+        let tokens = ["<unk>", "<s>", "</s>"].map(String::from).to_vec();
+        BpeTokenizer::new(tokens, vec![0.0; 3], 1, 2).with_add_bos_token(false)
+    }
+
+    fn messages() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new("system", "You are a cat."),
+            ChatMessage::new("user", "Hello!"),
+            ChatMessage::new("assistant", "Meow."),
+            ChatMessage::new("user", "Who are you?"),
+        ]
+    }
+
+    #[test]
+    fn test_builtin_templates() -> Result<()> {
+        let tk = tokenizer();
+        let prompt = ChatTemplate::Llama2.render(&messages(), true, &tk)?;
+        assert_eq!(
+            prompt,
+            "<s>[INST] <<SYS>>\nYou are a cat.\n<</SYS>>\n\nHello! [/INST] Meow. </s><s>[INST] Who are you? [/INST]"
+        );
+        let prompt = ChatTemplate::ChatMl.render(&messages()[1..2], true, &tk)?;
+        assert_eq!(
+            prompt,
+            "<|im_start|>user\nHello!<|im_end|>\n<|im_start|>assistant\n"
+        );
+        let prompt = ChatTemplate::Mistral.render(&messages(), true, &tk)?;
+        assert_eq!(
+            prompt,
+            "<s>[INST] You are a cat.\n\nHello! [/INST]Meow.</s>[INST] Who are you? [/INST]"
+        );
+
+        // the tokenizer prepends the bos token
+        let tk = tk.with_add_bos_token(true);
+        let prompt = ChatTemplate::Llama2.render(&messages()[1..2], true, &tk)?;
+        assert_eq!(prompt, "[INST] Hello! [/INST]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_jinja_templates() -> Result<()> {
+        let tk = tokenizer();
+        for (src, builtin) in [
+            (LLAMA2_TEMPLATE, ChatTemplate::Llama2),
+            (CHATML_TEMPLATE, ChatTemplate::ChatMl),
+        ] {
+            let template = ChatTemplate::parse(src);
+            assert!(matches!(template, ChatTemplate::Jinja(_)));
+            for messages in [&messages()[1..], &messages()] {
+                assert_eq!(
+                    template.render(messages, true, &tk)?,
+                    builtin.render(messages, true, &tk)?
+                );
+            }
+        }
+
+        let template = ChatTemplate::parse(LLAMA2_TEMPLATE);
+        let messages = [ChatMessage::new("assistant", "Meow.")];
+        let err = template.render(&messages, true, &tk).unwrap_err();
+        assert!(err.message.contains("Conversation roles must alternate"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jinja_expressions() -> Result<()> {
+        let render = |src: &str| -> Result<String> {
+            let messages = [ChatMessage::new("user", " Hi ")];
+            JinjaTemplate::parse(src)?.render(&messages, false, "<s>", "</s>")
+        };
+        assert_eq!(
+            render("{{ 1 + 2 * 3 }} {{ 7 // 2 }} {{ -7 % 3 }}")?,
+            "7 3 2"
+        );
+        assert_eq!(render("{{ 'a' ~ 1 ~ true }} {{ 'ab' 'c' }}")?, "a1True abc");
+        assert_eq!(render("{{ messages[0].content|trim|upper }}")?, "HI");
+        assert_eq!(
+            render("{{ messages[-1]['role'] }}{{ messages[1] }}")?,
+            "user"
+        );
+        assert_eq!(render("{{ messages|length }} {{ 'abc'[1:] }}")?, "1 bc");
+        assert_eq!(
+            render("{{ foo is defined }} {{ not foo is defined }}")?,
+            "False True"
+        );
+        assert_eq!(render("{{ 'b' in 'abc' and 'x' not in ['y'] }}")?, "True");
+        assert_eq!(
+            render("{{ 'yes' if add_generation_prompt else 'no' }}")?,
+            "no"
+        );
+        assert_eq!(
+            render("{{ foo|default('bar') }} {{ [1, 'a']|tojson }}")?,
+            "bar [1,\"a\"]"
+        );
+        assert_eq!(
+            render("{% for i in range(3) %}{{ i }}{% if not loop.last %},{% endif %}{% endfor %}")?,
+            "0,1,2"
+        );
+        assert_eq!(
+            render("{% set x = 1 %}{% for m in messages %}{% set x = 2 %}{% endfor %}{{ x }}")?,
+            "1"
+        );
+
+        // the whitespaces around the tags
+        assert_eq!(render("a  {{- 'b' -}}  c")?, "abc");
+        assert_eq!(
+            render("a\n  {% if true %}\nb\n  {% endif %}\nc")?,
+            "a\nb\nc"
+        );
+        assert_eq!(render("{# comment #}\na")?, "a");
+
+        // the unsupported templates fall back to the built-in formats
+        assert!(JinjaTemplate::parse("{% macro f() %}{% endmacro %}").is_err());
+        let template = ChatTemplate::parse("{% macro f() %}<|im_start|>{% endmacro %}");
+        assert!(matches!(template, ChatTemplate::ChatMl));
+        assert!(JinjaTemplate::parse("{% if true %}").is_err());
+        assert!(JinjaTemplate::parse("{{ 'a' ").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+
+use crate::session::f32_from_bytes;
+
+/// the steering vectors added to the residual stream on the output of the layers, which pull
+/// the hidden states towards a direction like "happy" or "honest", trained by the contrastive
+/// prompts like repeng. it's loaded from the GGUF of the `direction.{layer}` tensors, like the
+/// control vectors of llama.cpp.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Llama2ControlVector {
+    pub directions: BTreeMap<usize, Vec<f32>>, // layer => (embedding_dim, )
+}
+
+impl Llama2ControlVector {
+    pub fn load(path: &str) -> Result<Self> {
+        let loader = GGUFFileLoader::new(path)?;
+        let gf = loader.open()?;
+        let mut directions = BTreeMap::new();
+        for info in gf.tensor_infos() {
+            let Some(layer) = info.name().strip_prefix("direction.") else {
+                continue;
+            };
+            let layer = layer.parse::<usize>().map_err(|_| Error {
+                kind: ErrorKind::FormatError,
+                message: format!("unexpected tensor {} in the control vector", info.name()),
+                cause: None,
+            })?;
+            if info.typ() != GGMLType::F32 || info.dimensions().len() != 1 {
+                return Err(Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!("the direction of the layer {} should be 1d f32", layer),
+                    cause: None,
+                });
+            }
+            directions.insert(layer, f32_from_bytes(info.data()));
+        }
+        if directions.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: format!("no direction found in the control vector {}", path),
+                cause: None,
+            });
+        }
+        Ok(Self { directions })
+    }
+
+    /// the sum of the control vectors scaled by their strengths, a negative strength pushes
+    /// the other way.
+    pub fn combine(vectors: &[(&Self, f32)]) -> Result<Self> {
+        let mut directions: BTreeMap<usize, Vec<f32>> = BTreeMap::new();
+        for (vector, strength) in vectors {
+            for (layer, direction) in vector.directions.iter() {
+                let sum = directions
+                    .entry(*layer)
+                    .or_insert_with(|| vec![0.0; direction.len()]);
+                if sum.len() != direction.len() {
+                    return Err(Error {
+                        kind: ErrorKind::BadInput,
+                        message: format!(
+                            "the directions of the layer {} differ in the dims {} and {}",
+                            layer,
+                            sum.len(),
+                            direction.len()
+                        ),
+                        cause: None,
+                    });
+                }
+                sum.iter_mut()
+                    .zip(direction)
+                    .for_each(|(s, d)| *s += strength * d);
+            }
+        }
+        Ok(Self { directions })
+    }
+
+    /// keep the directions on the layers in [start, end] only.
+    pub fn with_layer_range(mut self, start: usize, end: usize) -> Self {
+        self.directions
+            .retain(|layer, _| (start..=end).contains(layer));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFWriter;
+    use crabml::gguf::KEY_GENERAL_ARCHITECTURE;
+
+    use super::*;
+    use crate::llama2::Llama2Runner;
+    use crate::sampler::Llama2Sampler;
+    use crate::CpuLlama2Model;
+
+    fn write_control_vector(directions: &[(usize, Vec<f32>)], name: &str) -> Result<String> {
+        let bufs = directions
+            .iter()
+            .map(|(_, d)| d.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut w = GGUFWriter::new();
+        w.add_metadata(
+            KEY_GENERAL_ARCHITECTURE,
+            GGUFMetadataValue::String("controlvector"),
+        );
+        for ((layer, d), buf) in directions.iter().zip(bufs.iter()) {
+            w.add_tensor(
+                &format!("direction.{}", layer),
+                &[d.len()],
+                GGMLType::F32,
+                buf,
+            )?;
+        }
+        let path =
+            std::env::temp_dir().join(format!("crabml-{}-{}.gguf", name, std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        w.write_to_file(&path)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_combine_control_vectors() -> Result<()> {
+        let a = Llama2ControlVector {
+            directions: BTreeMap::from([(1, vec![1.0, 2.0]), (2, vec![1.0, 1.0])]),
+        };
+        let b = Llama2ControlVector {
+            directions: BTreeMap::from([(2, vec![2.0, 0.0])]),
+        };
+        let c = Llama2ControlVector::combine(&[(&a, 2.0), (&b, -1.0)])?;
+        assert_eq!(
+            c.directions,
+            BTreeMap::from([(1, vec![2.0, 4.0]), (2, vec![0.0, 2.0])])
+        );
+        let c = c.with_layer_range(2, 5);
+        assert_eq!(c.directions, BTreeMap::from([(2, vec![0.0, 2.0])]));
+
+        let d = Llama2ControlVector {
+            directions: BTreeMap::from([(2, vec![1.0])]),
+        };
+        assert!(Llama2ControlVector::combine(&[(&a, 1.0), (&d, 1.0)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_control_vector() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let dim = lm.conf.embedding_dim;
+        let direction = (0..dim).map(|i| (i % 5) as f32 - 2.0).collect::<Vec<_>>();
+        let path = write_control_vector(&[(2, direction.clone()), (3, direction)], "cvec")?;
+        let vector = Llama2ControlVector::load(&path)?;
+        assert_eq!(vector.directions.keys().collect::<Vec<_>>(), vec![&2, &3]);
+
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let generate = |runner: &mut Llama2Runner<_>| -> Result<String> {
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let output = runner.generate("Lily is a cat", 20, &mut sampler)?;
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+        let base = generate(&mut runner)?;
+
+        runner.set_control_vectors(&[(&vector, 0.5)])?;
+        assert!(runner.tokens().is_empty());
+        let steered = generate(&mut runner)?;
+        assert_ne!(steered, base);
+        runner.set_control_vectors(&[(&vector, 0.5), (&vector, -0.5)])?;
+        assert_eq!(generate(&mut runner)?, base);
+        runner.set_control_vectors(&[(&vector.clone().with_layer_range(0, 1), 0.5)])?;
+        assert_eq!(generate(&mut runner)?, base);
+        runner.set_control_vectors(&[(&vector, 0.5)])?;
+        assert_eq!(generate(&mut runner)?, steered);
+        runner.set_control_vectors(&[])?;
+        assert_eq!(generate(&mut runner)?, base);
+
+        // the directions should fit the model
+        let path_bad = write_control_vector(&[(1, vec![1.0; 8])], "cvec-bad")?;
+        let vector_bad = Llama2ControlVector::load(&path_bad)?;
+        assert!(runner.set_control_vectors(&[(&vector_bad, 1.0)]).is_err());
+        assert!(Llama2ControlVector::load("../testdata/tinyllamas-stories-260k-f32.gguf").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&path_bad).unwrap();
+        Ok(())
+    }
+}
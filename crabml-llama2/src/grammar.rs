@@ -0,0 +1,720 @@
+//! GBNF grammars to constrain the sampling, in the same syntax as llama.cpp:
+//!
+//! ```text
+//! root   ::= answer ("," ws answer)*
+//! answer ::= "yes" | "no" | [0-9]+
+//! ws     ::= [ \t\n]*
+//! ```
+//!
+//! a grammar is a list of rules, each rule has one or more alternatives separated by `|`,
+//! and each alternative is a sequence of string literals, character classes like `[a-z]`
+//! or `[^"]`, `.` for any character, references to the other rules, and groups in
+//! parentheses. the elements can be repeated with `*`, `+`, `?` and `{m,n}`. the matching
+//! starts from the `root` rule.
+
+use std::collections::HashMap;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+
+#[derive(Debug, Clone, PartialEq)]
+enum GrammarElement {
+    /// matches one character in (or not in, if negated) the inclusive ranges.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// matches the rule with the index.
+    Rule(usize),
+}
+
+impl GrammarElement {
+    fn matches(&self, ch: char) -> bool {
+        match self {
+            GrammarElement::Chars { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| *lo <= ch && ch <= *hi) != *negated
+            }
+            GrammarElement::Rule(_) => false,
+        }
+    }
+}
+
+type GrammarAlternative = Vec<GrammarElement>;
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    names: Vec<String>,
+    rules: Vec<Vec<GrammarAlternative>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut parser = GrammarParser {
+            chars: src.chars().collect(),
+            pos: 0,
+            names: vec![],
+            rule_ids: HashMap::new(),
+            rules: vec![],
+        };
+        parser.parse()?;
+
+        let GrammarParser { names, rules, .. } = parser;
+        let mut defined_rules = Vec::with_capacity(rules.len());
+        for (name, rule) in names.iter().zip(rules) {
+            match rule {
+                Some(rule) => defined_rules.push(rule),
+                None => return Err(grammar_error(format!("undefined rule {}", name))),
+            }
+        }
+        let root = names
+            .iter()
+            .position(|name| name == "root")
+            .ok_or_else(|| grammar_error("the grammar has no root rule"))?;
+
+        let grammar = Self {
+            names,
+            rules: defined_rules,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    /// the matching expands the rules from the left, a left recursive rule like
+    /// `expr ::= expr "+" term | term` would never stop expanding.
+    fn check_left_recursion(&self) -> Result<()> {
+        // the rules which can match the empty string
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, alts) in self.rules.iter().enumerate() {
+                if nullable[i] {
+                    continue;
+                }
+                let is_nullable = alts.iter().any(|alt| {
+                    alt.iter()
+                        .all(|elem| matches!(elem, GrammarElement::Rule(r) if nullable[*r]))
+                });
+                if is_nullable {
+                    nullable[i] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // the rules which can be expanded at the leftmost position of each rule
+        let left_rules = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut refs = vec![];
+                for alt in alts {
+                    for elem in alt {
+                        match elem {
+                            GrammarElement::Rule(r) => {
+                                refs.push(*r);
+                                if !nullable[*r] {
+                                    break;
+                                }
+                            }
+                            GrammarElement::Chars { .. } => break,
+                        }
+                    }
+                }
+                refs
+            })
+            .collect::<Vec<_>>();
+
+        // 0: unvisited, 1: visiting, 2: visited
+        fn visit(rule: usize, left_rules: &[Vec<usize>], marks: &mut [u8]) -> Option<usize> {
+            match marks[rule] {
+                1 => return Some(rule),
+                2 => return None,
+                _ => {}
+            }
+            marks[rule] = 1;
+            for r in left_rules[rule].iter() {
+                if let Some(r) = visit(*r, left_rules, marks) {
+                    return Some(r);
+                }
+            }
+            marks[rule] = 2;
+            None
+        }
+        let mut marks = vec![0; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            if let Some(r) = visit(rule, &left_rules, &mut marks) {
+                return Err(grammar_error(format!(
+                    "left recursion is not supported, found in rule {}",
+                    self.names[r]
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn grammar_error(message: impl Into<String>) -> Error {
+    Error {
+        kind: ErrorKind::BadInput,
+        message: format!("failed to parse grammar: {}", message.into()),
+        cause: None,
+    }
+}
+
+struct GrammarParser {
+    chars: Vec<char>,
+    pos: usize,
+    names: Vec<String>,
+    rule_ids: HashMap<String, usize>,
+    // None if the rule is referenced but not defined yet
+    rules: Vec<Option<Vec<GrammarAlternative>>>,
+}
+
+impl GrammarParser {
+    fn parse(&mut self) -> Result<()> {
+        self.skip_space(true);
+        while self.peek().is_some() {
+            self.parse_rule()?;
+            self.skip_space(true);
+        }
+        Ok(())
+    }
+
+    fn parse_rule(&mut self) -> Result<()> {
+        let name = self.parse_name()?;
+        self.skip_space(false);
+        self.expect("::=")?;
+        self.skip_space(true);
+
+        let id = self.rule_id(&name);
+        if self.rules[id].is_some() {
+            return Err(grammar_error(format!("rule {} is defined twice", name)));
+        }
+        let alts = self.parse_alternatives(&name, false)?;
+        self.rules[id] = Some(alts);
+
+        match self.peek() {
+            None | Some('\n') | Some('\r') => Ok(()),
+            Some(ch) => Err(self.error(format!("unexpected {:?}", ch))),
+        }
+    }
+
+    fn parse_alternatives(&mut self, name: &str, nested: bool) -> Result<Vec<GrammarAlternative>> {
+        let mut alts = vec![self.parse_sequence(name, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(name, nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, name: &str, nested: bool) -> Result<GrammarAlternative> {
+        let mut seq = vec![];
+        // the start of the last element, the repetitions apply on seq[last_start..]
+        let mut last_start = None;
+        while let Some(ch) = self.peek() {
+            let start = seq.len();
+            match ch {
+                '"' => {
+                    self.pos += 1;
+                    while self.peek() != Some('"') {
+                        let ch = self.parse_char()?;
+                        seq.push(GrammarElement::Chars {
+                            ranges: vec![(ch, ch)],
+                            negated: false,
+                        });
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let lo = self.parse_char()?;
+                        let hi = if self.peek() == Some('-') && self.peek_at(1) != Some(']') {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    self.pos += 1;
+                    seq.push(GrammarElement::Chars { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    seq.push(GrammarElement::Chars {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.parse_alternatives(name, true)?;
+                    self.expect(")")?;
+                    seq.push(GrammarElement::Rule(self.add_rule(name, alts)));
+                }
+                '*' | '+' | '?' | '{' => {
+                    let last_start = last_start
+                        .ok_or_else(|| self.error(format!("expected an item before {:?}", ch)))?;
+                    let (min, max) = self.parse_repetition()?;
+                    let item = seq.split_off(last_start);
+                    self.repeat(name, &mut seq, item, min, max);
+                }
+                ch if is_name_char(ch) => {
+                    let name = self.parse_name()?;
+                    seq.push(GrammarElement::Rule(self.rule_id(&name)));
+                }
+                _ => break,
+            }
+            if !matches!(ch, '*' | '+' | '?' | '{') {
+                last_start = Some(start);
+            }
+            self.skip_space(nested);
+        }
+        Ok(seq)
+    }
+
+    /// returns the (min, max) times of the repetition, max is None if unbounded.
+    fn parse_repetition(&mut self) -> Result<(usize, Option<usize>)> {
+        let ch = self.peek().unwrap();
+        self.pos += 1;
+        match ch {
+            '*' => return Ok((0, None)),
+            '+' => return Ok((1, None)),
+            '?' => return Ok((0, Some(1))),
+            _ => {}
+        }
+
+        self.skip_space(false);
+        let min = self.parse_int()?;
+        self.skip_space(false);
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.skip_space(false);
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.parse_int()?)
+            }
+        } else {
+            Some(min)
+        };
+        self.skip_space(false);
+        self.expect("}")?;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("the max repetition is less than the min"));
+        }
+        Ok((min, max))
+    }
+
+    /// rewrite the repetitions into the plain rules:
+    /// - `x{2,}` => `x x x_rep` where `x_rep ::= x x_rep | ""`
+    /// - `x{1,3}` => `x x_rep2` where `x_rep2 ::= x x_rep1 | ""`, `x_rep1 ::= x | ""`
+    fn repeat(
+        &mut self,
+        name: &str,
+        seq: &mut GrammarAlternative,
+        item: GrammarAlternative,
+        min: usize,
+        max: Option<usize>,
+    ) {
+        for _ in 0..min {
+            seq.extend(item.iter().cloned());
+        }
+        match max {
+            None => {
+                let id = self.add_rule(name, vec![]);
+                let mut alt = item;
+                alt.push(GrammarElement::Rule(id));
+                self.rules[id] = Some(vec![alt, vec![]]);
+                seq.push(GrammarElement::Rule(id));
+            }
+            Some(max) if max > min => {
+                let mut last = None;
+                for _ in min..max {
+                    let mut alt = item.clone();
+                    alt.extend(last.map(GrammarElement::Rule));
+                    last = Some(self.add_rule(name, vec![alt, vec![]]));
+                }
+                seq.extend(last.map(GrammarElement::Rule));
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_int(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let s = self.chars[start..self.pos].iter().collect::<String>();
+        s.parse().map_err(|_| self.error("expected an integer"))
+    }
+
+    /// parse a character in a string literal or a character class, with the escapes.
+    fn parse_char(&mut self) -> Result<char> {
+        let ch = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of input"))?;
+        self.pos += 1;
+        if ch != '\\' {
+            return Ok(ch);
+        }
+
+        let esc = self
+            .peek()
+            .ok_or_else(|| self.error("unexpected end of input"))?;
+        self.pos += 1;
+        let hex_len = match esc {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            '\\' | '"' | '[' | ']' | '-' => return Ok(esc),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Err(self.error(format!("unknown escape \\{}", esc))),
+        };
+        if self.pos + hex_len > self.chars.len() {
+            return Err(self.error("unexpected end of input"));
+        }
+        let hex = self.chars[self.pos..self.pos + hex_len]
+            .iter()
+            .collect::<String>();
+        self.pos += hex_len;
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("invalid escape \\{}{}", esc, hex)))
+    }
+
+    /// skip the spaces and the comments, the newlines are only skipped inside the
+    /// parentheses or after `::=` and `|`, otherwise a newline ends the rule.
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(ch) = self.peek() {
+            match ch {
+                ' ' | '\t' => self.pos += 1,
+                '\n' | '\r' if newline_ok => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|ch| ch != '\n' && ch != '\r') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for ch in s.chars() {
+            if self.peek() != Some(ch) {
+                return Err(self.error(format!("expected {:?}", s)));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.rule_ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len();
+        self.names.push(name.to_string());
+        self.rule_ids.insert(name.to_string(), id);
+        self.rules.push(None);
+        id
+    }
+
+    /// add an anonymous rule for the groups and the repetitions.
+    fn add_rule(&mut self, parent: &str, alts: Vec<GrammarAlternative>) -> usize {
+        let id = self.names.len();
+        self.names.push(format!("{}_{}", parent, id));
+        self.rules.push(Some(alts));
+        id
+    }
+
+    fn error(&self, message: impl Into<String>) -> Error {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|ch| **ch == '\n')
+            .count();
+        grammar_error(format!("{} at line {}", message.into(), line + 1))
+    }
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'
+}
+
+/// a position in the grammar: (rule, alternative, element).
+type GrammarPos = (usize, usize, usize);
+
+/// the matching state of a grammar. like llama.cpp, the state is a set of stacks, each
+/// stack is a possible parse of the accepted text so far, and the top of each stack is
+/// the next character element to match. an empty stack means the text matches the root
+/// rule completely.
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    grammar: Grammar,
+    stacks: Vec<Vec<GrammarPos>>,
+}
+
+impl GrammarState {
+    pub fn new(grammar: Grammar) -> Self {
+        let mut state = Self {
+            grammar,
+            stacks: vec![],
+        };
+        state.reset();
+        state
+    }
+
+    /// rewind to the start of the root rule.
+    pub fn reset(&mut self) {
+        let root = self.grammar.root;
+        let mut stacks = vec![];
+        for (i, alt) in self.grammar.rules[root].iter().enumerate() {
+            let stack = if alt.is_empty() {
+                vec![]
+            } else {
+                vec![(root, i, 0)]
+            };
+            self.expand(stack, &mut stacks);
+        }
+        stacks.sort();
+        stacks.dedup();
+        self.stacks = stacks;
+    }
+
+    /// whether the text accepted so far is a complete match of the grammar.
+    pub fn is_accepted(&self) -> bool {
+        self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// whether the text can be accepted after the text accepted so far.
+    pub fn allows(&self, text: &str) -> bool {
+        self.advance(text).is_some()
+    }
+
+    /// accept the text and returns true, or returns false and keep the state unchanged if
+    /// the grammar does not allow the text.
+    pub fn accept(&mut self, text: &str) -> bool {
+        match self.advance(text) {
+            Some(stacks) => {
+                self.stacks = stacks;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn advance(&self, text: &str) -> Option<Vec<Vec<GrammarPos>>> {
+        let mut chars = text.chars();
+        let first = chars.next()?;
+        let mut stacks = self.advance_char(&self.stacks, first);
+        for ch in chars {
+            if stacks.is_empty() {
+                break;
+            }
+            stacks = self.advance_char(&stacks, ch);
+        }
+        (!stacks.is_empty()).then_some(stacks)
+    }
+
+    fn advance_char(&self, stacks: &[Vec<GrammarPos>], ch: char) -> Vec<Vec<GrammarPos>> {
+        let mut new_stacks = vec![];
+        for stack in stacks {
+            let Some(&(rule, alt, elem)) = stack.last() else {
+                continue;
+            };
+            if !self.grammar.rules[rule][alt][elem].matches(ch) {
+                continue;
+            }
+            let mut stack = stack.clone();
+            stack.pop();
+            if elem + 1 < self.grammar.rules[rule][alt].len() {
+                stack.push((rule, alt, elem + 1));
+            }
+            self.expand(stack, &mut new_stacks);
+        }
+        new_stacks.sort();
+        new_stacks.dedup();
+        new_stacks
+    }
+
+    /// expand the rule references on the top of the stack until the top is a character
+    /// element, or the stack is empty.
+    fn expand(&self, mut stack: Vec<GrammarPos>, stacks: &mut Vec<Vec<GrammarPos>>) {
+        let Some(&(rule, alt, elem)) = stack.last() else {
+            stacks.push(stack);
+            return;
+        };
+        let seq = &self.grammar.rules[rule][alt];
+        let GrammarElement::Rule(ref_rule) = seq[elem] else {
+            stacks.push(stack);
+            return;
+        };
+
+        // continue with the next element of the current sequence after the referenced
+        // rule is matched
+        stack.pop();
+        if elem + 1 < seq.len() {
+            stack.push((rule, alt, elem + 1));
+        }
+        for (i, ref_alt) in self.grammar.rules[ref_rule].iter().enumerate() {
+            let mut new_stack = stack.clone();
+            if !ref_alt.is_empty() {
+                new_stack.push((ref_rule, i, 0));
+            }
+            self.expand(new_stack, stacks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(grammar: &Grammar, text: &str) -> bool {
+        let mut state = GrammarState::new(grammar.clone());
+        text.chars()
+            .all(|ch| state.accept(ch.encode_utf8(&mut [0; 4])))
+            && state.is_accepted()
+    }
+
+    #[test]
+    fn test_grammar_parse() -> Result<()> {
+        let grammar = Grammar::parse(
+            r#"
+            # a list of answers
+            root   ::= answer ("," ws answer)*
+            answer ::= "yes" | "no" | [0-9]+
+            ws     ::= [ \t\n]*
+            "#,
+        )?;
+        assert!(matches(&grammar, "yes"));
+        assert!(matches(&grammar, "yes, no,\n42"));
+        assert!(!matches(&grammar, ""));
+        assert!(!matches(&grammar, "yes,"));
+        assert!(!matches(&grammar, "maybe"));
+
+        let tests = vec![
+            ("root ::= foo", "undefined rule foo"),
+            ("foo ::= \"a\"", "the grammar has no root rule"),
+            (
+                "root ::= \"a\"\nroot ::= \"b\"",
+                "rule root is defined twice",
+            ),
+            ("root ::= root \"a\" | \"a\"", "left recursion"),
+            ("root ::= x? root", "left recursion"),
+            ("root ::= * \"a\"", "expected an item before '*'"),
+            ("root ::= (\"a\"", "expected \")\""),
+            (
+                "root ::= \"a\"{3,1}",
+                "the max repetition is less than the min",
+            ),
+            ("root ::= \"\\q\"", "unknown escape \\q"),
+        ];
+        for (src, want) in tests {
+            let err = Grammar::parse(&format!("{}\nx ::= \"x\"", src)).unwrap_err();
+            assert!(err.message.contains(want), "{}: {}", src, err.message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_grammar_elements() -> Result<()> {
+        let tests = vec![
+            (r#"root ::= [a-c_]"#, vec!["a", "c", "_"], vec![
+                "d", "", "ab",
+            ]),
+            (r#"root ::= [^"\\] ."#, vec!["ab", "a\"", "\n\n"], vec![
+                "\"a", "\\a",
+            ]),
+            (r#"root ::= "\x41\u00e9\n""#, vec!["Aé\n"], vec!["A"]),
+            (r#"root ::= "a"? "b"+"#, vec!["b", "ab", "abbb"], vec![
+                "a", "aab",
+            ]),
+            (r#"root ::= ("ab" | "c"){2}"#, vec!["abc", "cc"], vec![
+                "c", "ccc",
+            ]),
+            (r#"root ::= [0-9]{1,3}"#, vec!["1", "123"], vec!["", "1234"]),
+            (r#"root ::= "x"{2,} | """#, vec!["", "xx", "xxxx"], vec![
+                "x",
+            ]),
+            (
+                "root ::=\n  \"[\" (\n  item\n  (\",\" item)*\n  )? \"]\"\nitem ::= [a-z]",
+                vec!["[]", "[a]", "[a,b,c]"],
+                vec!["[", "[a,]", "[,a]"],
+            ),
+        ];
+        for (src, accepted, rejected) in tests {
+            let grammar = Grammar::parse(src)?;
+            for text in accepted {
+                assert!(matches(&grammar, text), "{} should accept {:?}", src, text);
+            }
+            for text in rejected {
+                assert!(!matches(&grammar, text), "{} should reject {:?}", src, text);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_grammar_state() -> Result<()> {
+        let grammar = Grammar::parse(r#"root ::= "{" ws "}" | "[]"  ws ::= " "*"#);
+        assert!(grammar.is_err());
+
+        let grammar = Grammar::parse("root ::= \"{\" ws \"}\" | \"[]\"\nws ::= \" \"*")?;
+        let mut state = GrammarState::new(grammar);
+        assert!(!state.is_accepted());
+        assert!(state.allows("{  }"));
+        assert!(!state.allows("{]"));
+        assert!(!state.allows(""));
+
+        assert!(state.accept("{ "));
+        assert!(!state.accept("]"));
+        assert!(state.accept("  "));
+        assert!(!state.is_accepted());
+        assert!(state.accept("}"));
+        assert!(state.is_accepted());
+        assert!(!state.allows(" "));
+
+        state.reset();
+        assert!(state.accept("[]"));
+        assert!(state.is_accepted());
+        Ok(())
+    }
+}
@@ -0,0 +1,586 @@
+//! convert a JSON schema into a GBNF grammar, so the sampled text always parses into the
+//! shape of the schema. the supported keywords:
+//!
+//! - `type`: `object`, `array`, `string`, `number`, `integer`, `boolean`, `null`, or a
+//!   list of them.
+//! - `properties` and `required` for the objects, the properties are generated in the
+//!   order of the schema, and no additional properties are allowed if `properties` is
+//!   given. without `properties`, `additionalProperties` is the schema of the values.
+//! - `items`, `prefixItems`, `minItems` and `maxItems` for the arrays.
+//! - `minLength` and `maxLength` for the strings.
+//! - `enum`, `const`, `anyOf`, `oneOf`, and the local `$ref` like `#/$defs/Foo`.
+//!
+//! the other keywords like `minimum` or `pattern` are ignored.
+
+use std::collections::HashMap;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use serde_json::Value;
+
+const PRIMITIVE_RULES: [(&str, &str, &[&str]); 10] = [
+    ("space", r#"" "?"#, &[]),
+    ("boolean", r#"("true" | "false") space"#, &["space"]),
+    ("null", r#""null" space"#, &["space"]),
+    ("integer", r#""-"? ([0-9] | [1-9] [0-9]{0,15}) space"#, &[
+        "space",
+    ]),
+    (
+        "number",
+        r#""-"? ([0-9] | [1-9] [0-9]{0,15}) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? space"#,
+        &["space"],
+    ),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\/bfnrt] | "u" [0-9a-fA-F]{4})"#,
+        &[],
+    ),
+    ("string", r#""\"" char* "\"" space"#, &["char", "space"]),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" space (string ":" space value ("," space string ":" space value)*)? "}" space"#,
+        &["string", "value", "space"],
+    ),
+    (
+        "array",
+        r#""[" space (value ("," space value)*)? "]" space"#,
+        &["value", "space"],
+    ),
+];
+
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = JsonSchemaConverter {
+        schema,
+        rules: vec![],
+        rule_ids: HashMap::new(),
+        refs: HashMap::new(),
+    };
+    let root = converter.visit(schema, "root")?;
+    converter.add_rule("root", root);
+
+    // keep the root rule at the top
+    let mut rules = converter.rules;
+    rules.sort_by_key(|(name, _)| name != "root");
+    let mut src = String::new();
+    for (name, body) in rules.iter() {
+        src.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    Ok(src)
+}
+
+struct JsonSchemaConverter<'a> {
+    schema: &'a Value,
+    rules: Vec<(String, String)>,
+    rule_ids: HashMap<String, usize>,
+    // the rule names of the visited $refs
+    refs: HashMap<String, String>,
+}
+
+impl<'a> JsonSchemaConverter<'a> {
+    /// returns the GBNF expression to match the schema, the nested rules are named after
+    /// `name`.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Object(schema) => schema,
+            _ => return Err(schema_error(format!("invalid schema {}", schema))),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.visit_ref(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = as_array(values, "enum")?;
+            let alts = values.iter().map(|v| self.literal(v)).collect::<Vec<_>>();
+            return Ok(format!("({})", alts.join(" | ")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let schemas = as_array(schemas, "anyOf")?;
+            return self.visit_alternatives(schemas.iter(), name);
+        }
+
+        let typ = match schema.get("type") {
+            None => return Ok(self.primitive("value")),
+            Some(Value::String(typ)) => typ.as_str(),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .map(|typ| {
+                        let mut schema = schema.clone();
+                        schema.insert("type".to_string(), typ.clone());
+                        Value::Object(schema)
+                    })
+                    .collect::<Vec<_>>();
+                return self.visit_alternatives(alts.iter(), name);
+            }
+            Some(typ) => return Err(schema_error(format!("invalid type {}", typ))),
+        };
+
+        match typ {
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            "string" => {
+                let min = get_usize(schema, "minLength")?;
+                let max = get_usize(schema, "maxLength")?;
+                if min.is_none() && max.is_none() {
+                    return Ok(self.primitive("string"));
+                }
+                let char = self.primitive("char");
+                let space = self.primitive("space");
+                Ok(format!(
+                    r#""\"" {}{} "\"" {}"#,
+                    char,
+                    repetition(min.unwrap_or(0), max),
+                    space
+                ))
+            }
+            "number" | "integer" | "boolean" | "null" => Ok(self.primitive(typ)),
+            _ => Err(schema_error(format!("unsupported type {}", typ))),
+        }
+    }
+
+    fn visit_alternatives<'b>(
+        &mut self,
+        schemas: impl Iterator<Item = &'b Value>,
+        name: &str,
+    ) -> Result<String> {
+        let mut alts = vec![];
+        for (i, schema) in schemas.enumerate() {
+            let alt_name = format!("{}-{}", name, i);
+            let expr = self.visit(schema, &alt_name)?;
+            alts.push(self.add_rule(&alt_name, expr));
+        }
+        Ok(format!("({})", alts.join(" | ")))
+    }
+
+    fn visit_ref(&mut self, reference: &Value) -> Result<String> {
+        let reference = reference
+            .as_str()
+            .ok_or_else(|| schema_error("$ref should be a string"))?;
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.schema.pointer(pointer))
+            .ok_or_else(|| schema_error(format!("can not resolve $ref {}", reference)))?;
+
+        // reserve the rule before visiting the target to support the recursive schemas
+        let name = reference.rsplit('/').next().unwrap_or_default();
+        let rule = self.unique_name(&format!("ref-{}", rule_name(name)));
+        self.rule_ids.insert(rule.clone(), self.rules.len());
+        self.rules.push((rule.clone(), String::new()));
+        self.refs.insert(reference.to_string(), rule.clone());
+        let expr = self.visit(target, &rule)?;
+        self.rules[self.rule_ids[&rule]].1 = expr;
+        Ok(rule)
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let space = self.primitive("space");
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) => properties,
+            Some(_) => return Err(schema_error("properties should be an object")),
+            None => {
+                let value = match schema.get("additionalProperties") {
+                    None | Some(Value::Bool(true)) => self.primitive("value"),
+                    Some(Value::Bool(false)) => {
+                        return Ok(format!(r#""{{" {} "}}" {}"#, space, space));
+                    }
+                    Some(value) => {
+                        let expr = self.visit(value, &format!("{}-value", name))?;
+                        self.add_rule(&format!("{}-value", name), expr)
+                    }
+                };
+                let string = self.primitive("string");
+                let kv = format!(r#"{} ":" {} {}"#, string, space, value);
+                return Ok(format!(
+                    r#""{{" {} ({} ("," {} {})*)? "}}" {}"#,
+                    space, kv, space, kv, space
+                ));
+            }
+        };
+        let required = match schema.get("required") {
+            None => vec![],
+            Some(required) => as_array(required, "required")?
+                .iter()
+                .map(|v| {
+                    v.as_str()
+                        .ok_or_else(|| schema_error("required should be strings"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+        };
+
+        let mut required_kvs = vec![];
+        let mut optional_kvs = vec![];
+        for (key, value) in properties.iter() {
+            let prop_name = format!("{}-{}", name, rule_name(key));
+            let expr = self.visit(value, &prop_name)?;
+            let key_literal = self.literal(&Value::String(key.clone()));
+            let kv = format!(r#"{} ":" {} {}"#, key_literal, space, expr);
+            if required.contains(&key.as_str()) {
+                required_kvs.push(kv);
+            } else {
+                optional_kvs.push(self.add_rule(&format!("{}-kv", prop_name), kv));
+            }
+        }
+
+        let mut body = required_kvs.join(&format!(r#" "," {} "#, space));
+        // each optional property may follow the previous properties with a comma
+        if !optional_kvs.is_empty() {
+            if required_kvs.is_empty() {
+                let mut alts = vec![];
+                for i in 0..optional_kvs.len() {
+                    let rest = optional_kvs[i + 1..]
+                        .iter()
+                        .map(|kv| format!(r#"("," {} {})?"#, space, kv))
+                        .collect::<Vec<_>>();
+                    alts.push(
+                        std::iter::once(optional_kvs[i].clone())
+                            .chain(rest)
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    );
+                }
+                body = format!("({})?", alts.join(" | "));
+            } else {
+                for kv in optional_kvs.iter() {
+                    body.push_str(&format!(r#" ("," {} {})?"#, space, kv));
+                }
+            }
+        }
+        Ok(format!(r#""{{" {} {} "}}" {}"#, space, body, space))
+    }
+
+    fn visit_array(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let space = self.primitive("space");
+        let prefix_items = match (schema.get("prefixItems"), schema.get("items")) {
+            (Some(items), _) | (None, Some(items @ Value::Array(_))) => {
+                Some(as_array(items, "prefixItems")?)
+            }
+            _ => None,
+        };
+        if let Some(items) = prefix_items {
+            let mut exprs = vec![];
+            for (i, item) in items.iter().enumerate() {
+                let item_name = format!("{}-{}", name, i);
+                let expr = self.visit(item, &item_name)?;
+                exprs.push(self.add_rule(&item_name, expr));
+            }
+            return Ok(format!(
+                r#""[" {} {} "]" {}"#,
+                space,
+                exprs.join(&format!(r#" "," {} "#, space)),
+                space
+            ));
+        }
+
+        let item = match schema.get("items") {
+            None => self.primitive("value"),
+            Some(items) => {
+                let item_name = format!("{}-item", name);
+                let expr = self.visit(items, &item_name)?;
+                self.add_rule(&item_name, expr)
+            }
+        };
+        let min = get_usize(schema, "minItems")?.unwrap_or(0);
+        let max = get_usize(schema, "maxItems")?;
+        let items = match (min, max) {
+            (_, Some(0)) => String::new(),
+            (0, max) => format!(
+                r#"({} ("," {} {}){})?"#,
+                item,
+                space,
+                item,
+                repetition(0, max.map(|max| max - 1))
+            ),
+            (min, max) => format!(
+                r#"{} ("," {} {}){}"#,
+                item,
+                space,
+                item,
+                repetition(min - 1, max.map(|max| max - 1))
+            ),
+        };
+        Ok(format!(r#""[" {} {} "]" {}"#, space, items, space))
+    }
+
+    /// the JSON literal of the value, like the `const` and `enum` values.
+    fn literal(&mut self, value: &Value) -> String {
+        let space = self.primitive("space");
+        let json = value.to_string();
+        let mut escaped = String::with_capacity(json.len() + 2);
+        for ch in json.chars() {
+            match ch {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                ch => escaped.push(ch),
+            }
+        }
+        format!(r#""{}" {}"#, escaped, space)
+    }
+
+    /// add the primitive rule and the rules it depends on, returns the rule name.
+    fn primitive(&mut self, name: &str) -> String {
+        if self.rule_ids.contains_key(name) {
+            return name.to_string();
+        }
+        let (_, body, deps) = PRIMITIVE_RULES
+            .iter()
+            .find(|(rule, ..)| *rule == name)
+            .unwrap();
+        self.rule_ids.insert(name.to_string(), self.rules.len());
+        self.rules.push((name.to_string(), body.to_string()));
+        for dep in deps.iter() {
+            self.primitive(dep);
+        }
+        name.to_string()
+    }
+
+    /// add a rule and returns its name, the name is suffixed if it's already taken by a
+    /// different rule.
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        if let Some(id) = self.rule_ids.get(name) {
+            if self.rules[*id].1 == body {
+                return name.to_string();
+            }
+        }
+        let rule = self.unique_name(name);
+        self.rule_ids.insert(rule.clone(), self.rules.len());
+        self.rules.push((rule.clone(), body));
+        rule
+    }
+
+    fn unique_name(&self, name: &str) -> String {
+        let mut rule = name.to_string();
+        let mut i = 1;
+        while self.rule_ids.contains_key(&rule) {
+            i += 1;
+            rule = format!("{}{}", name, i);
+        }
+        rule
+    }
+}
+
+fn repetition(min: usize, max: Option<usize>) -> String {
+    match (min, max) {
+        (0, None) => "*".to_string(),
+        (1, None) => "+".to_string(),
+        (min, None) => format!("{{{},}}", min),
+        (min, Some(max)) if min == max => format!("{{{}}}", min),
+        (min, Some(max)) => format!("{{{},{}}}", min, max),
+    }
+}
+
+/// the rule names only allow the alphanumeric characters and `-`.
+fn rule_name(name: &str) -> String {
+    name.chars()
+        .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '-' })
+        .collect()
+}
+
+fn as_array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>> {
+    value
+        .as_array()
+        .ok_or_else(|| schema_error(format!("{} should be an array", key)))
+}
+
+fn get_usize(schema: &serde_json::Map<String, Value>, key: &str) -> Result<Option<usize>> {
+    match schema.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(|v| Some(v as usize))
+            .ok_or_else(|| schema_error(format!("{} should be a non-negative integer", key))),
+    }
+}
+
+fn schema_error(message: impl Into<String>) -> Error {
+    Error {
+        kind: ErrorKind::BadInput,
+        message: format!("failed to convert json schema: {}", message.into()),
+        cause: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::grammar::GrammarState;
+
+    fn check(schema: Value, accepted: &[&str], rejected: &[&str]) -> Result<()> {
+        let src = json_schema_to_grammar(&schema)?;
+        let grammar = Grammar::parse(&src)?;
+        for (text, want) in accepted
+            .iter()
+            .map(|text| (text, true))
+            .chain(rejected.iter().map(|text| (text, false)))
+        {
+            let mut state = GrammarState::new(grammar.clone());
+            let got = text
+                .chars()
+                .all(|ch| state.accept(ch.encode_utf8(&mut [0; 4])))
+                && state.is_accepted();
+            assert_eq!(got, want, "{:?} on the grammar:\n{}", text, src);
+            if want {
+                serde_json::from_str::<Value>(text).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_schema_primitives() -> Result<()> {
+        check(json!({"type": "string"}), &[r#""""#, r#""a\"b\u00e9""#], &[
+            "a", r#""a"#, "\"\n\"",
+        ])?;
+        check(
+            json!({"type": "string", "minLength": 1, "maxLength": 2}),
+            &[r#""a""#, r#""ab""#],
+            &[r#""""#, r#""abc""#],
+        )?;
+        check(json!({"type": "integer"}), &["0", "-12"], &[
+            "01", "1.5", "",
+        ])?;
+        check(json!({"type": "number"}), &["0", "-1.5", "2e10"], &[
+            "1.", "-",
+        ])?;
+        check(
+            json!({"type": ["boolean", "null"]}),
+            &["true", "false", "null"],
+            &["1"],
+        )?;
+        check(
+            json!({"enum": ["red", 1, null]}),
+            &[r#""red""#, "1", "null"],
+            &[r#""blue""#],
+        )?;
+        check(json!({"const": {"a": "b"}}), &[r#"{"a":"b"}"#], &["{}"])?;
+        check(
+            json!({}),
+            &[r#"{"a": [1, true, {"b": null}]}"#, "\"x\""],
+            &["[1,]"],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_schema_object() -> Result<()> {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2},
+            },
+            "required": ["name"],
+        });
+        check(
+            schema,
+            &[
+                r#"{"name": "Lily"}"#,
+                r#"{"name": "Lily", "age": 3}"#,
+                r#"{"name": "Lily", "tags": ["cat", "cute"]}"#,
+                r#"{"name": "Lily", "age": 3, "tags": []}"#,
+            ],
+            &[
+                r#"{}"#,
+                r#"{"age": 3}"#,
+                r#"{"name": "Lily", "tags": ["a", "b", "c"]}"#,
+                r#"{"name": "Lily", "color": "red"}"#,
+            ],
+        )?;
+
+        // all the optional properties
+        let schema = json!({
+            "type": "object",
+            "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}},
+        });
+        check(
+            schema,
+            &["{}", r#"{"a": 1}"#, r#"{"b": 2}"#, r#"{"a": 1, "b": 2}"#],
+            &[r#"{"b": 2, "a": 1}"#, r#"{, "b": 2}"#],
+        )?;
+
+        let schema = json!({"type": "object", "additionalProperties": {"type": "integer"}});
+        check(schema, &["{}", r#"{"x": 1, "y": 2}"#], &[r#"{"x": "1"}"#])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_schema_array_and_refs() -> Result<()> {
+        check(
+            json!({"type": "array", "items": {"type": "integer"}, "minItems": 1, "maxItems": 3}),
+            &["[1]", "[1, 2, 3]"],
+            &["[]", "[1, 2, 3, 4]"],
+        )?;
+        check(
+            json!({"type": "array", "prefixItems": [{"type": "string"}, {"type": "integer"}]}),
+            &[r#"["a", 1]"#],
+            &[r#"[1, "a"]"#, r#"["a"]"#],
+        )?;
+
+        // a recursive tree
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": {"type": "integer"},
+                        "children": {"type": "array", "items": {"$ref": "#/$defs/node"}},
+                    },
+                    "required": ["value", "children"],
+                },
+            },
+        });
+        check(
+            schema,
+            &[r#"{"value": 1, "children": [{"value": 2, "children": []}]}"#],
+            &[r#"{"value": 1, "children": [{"value": 2}]}"#],
+        )?;
+
+        check(
+            json!({"anyOf": [{"type": "integer"}, {"type": "array", "items": {"type": "integer"}}]}),
+            &["1", "[1, 2]"],
+            &[r#""1""#],
+        )?;
+
+        let err = json_schema_to_grammar(&json!({"$ref": "#/$defs/missing"})).unwrap_err();
+        assert!(
+            err.message.contains("can not resolve $ref"),
+            "{}",
+            err.message
+        );
+        let err = json_schema_to_grammar(&json!({"type": "date"})).unwrap_err();
+        assert!(
+            err.message.contains("unsupported type date"),
+            "{}",
+            err.message
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,715 @@
+use std::ops::Range;
+
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::tensor::RopeOptions;
+use crabml::tensor::Tensor;
+
+use crate::model::Llama2Config;
+use crate::offload::Llama2Offload;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Llama2KvCacheOptions {
+    /// the dtype of the cached rows, F32, Q8_0 or Q4_0.
+    pub dtype: GGMLType,
+    /// the rows in a page.
+    pub page_size: usize,
+    /// the pages shared by all the sequences, the pool grows on demand if None.
+    pub max_pages: Option<usize>,
+}
+
+impl Default for Llama2KvCacheOptions {
+    fn default() -> Self {
+        Self {
+            dtype: GGMLType::F32,
+            page_size: 256,
+            max_pages: None,
+        }
+    }
+}
+
+/// the keys and values of `page_size` rows on every layer, the layers not kept in the cache
+/// are None.
+struct Llama2KvPage<T: Tensor> {
+    keys: Vec<Option<T>>,   // (layer, page_len, n_kv_heads, head_size)
+    values: Vec<Option<T>>, // (layer, page_len, n_kv_heads, head_size)
+    // the sequences sharing the page, it's copied on writing when shared
+    refs: usize,
+}
+
+/// the page table of a sequence, the row `i` is kept at the row `i % page_size` of the page
+/// `pages[i / page_size]`.
+#[derive(Debug, Clone, Default)]
+struct Llama2KvSeq {
+    pages: Vec<usize>,
+    len: usize,
+}
+
+/// the kv cache in the pages of a fixed number of rows, the sequences take the pages from a
+/// shared pool on demand instead of reserving the whole context up front, like the
+/// PagedAttention of vllm. the forked sequences share the pages until they diverge.
+pub struct Llama2KvCache<T: Tensor> {
+    options: Llama2KvCacheOptions,
+    n_layers: usize,
+    layers: Range<usize>, // the layers kept in the cache
+    n_kv_heads: usize,
+    head_size: usize,
+    device: T::Device,
+    pages: Vec<Option<Llama2KvPage<T>>>, // None if the page is free
+    free_pages: Vec<usize>,
+    seqs: Vec<Option<Llama2KvSeq>>, // None if the sequence is freed
+    offload: Option<Box<dyn Llama2Offload>>, // the first layers on another device
+}
+
+impl<T: Tensor> Llama2KvCache<T> {
+    pub fn new(
+        conf: &Llama2Config,
+        options: Llama2KvCacheOptions,
+        device: T::Device,
+    ) -> Result<Self> {
+        if options.page_size == 0 {
+            return Err((ErrorKind::BadInput, "the page size should be positive").into());
+        }
+        // fail early if the rows can not be cached in the dtype
+        if conf.n_kv_cache_layers() > 0 {
+            let shape = [0, conf.n_kv_heads, conf.head_size()];
+            T::alloc_cache(&shape, None, options.dtype, device.clone())?;
+        }
+        Ok(Self {
+            options,
+            n_layers: conf.n_kv_cache_layers(),
+            layers: 0..conf.n_kv_cache_layers(),
+            n_kv_heads: conf.n_kv_heads,
+            head_size: conf.head_size(),
+            device,
+            pages: vec![],
+            free_pages: vec![],
+            seqs: vec![],
+            offload: None,
+        })
+    }
+
+    /// keep the rows of the layers only, like the layers run on the runner of a device when
+    /// the model is split across the devices.
+    pub(crate) fn with_layers(mut self, layers: Range<usize>) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    /// the first layers offloaded to another device, the sequence ops are replayed on them.
+    pub(crate) fn set_offload(&mut self, offload: Box<dyn Llama2Offload>) {
+        self.offload = Some(offload);
+    }
+
+    pub(crate) fn take_offload(&mut self) -> Option<Box<dyn Llama2Offload>> {
+        self.offload.take()
+    }
+
+    pub(crate) fn offload_mut(&mut self) -> Option<&mut Box<dyn Llama2Offload>> {
+        self.offload.as_mut()
+    }
+
+    /// the offload taking the layer, if the layer is offloaded.
+    fn offloaded_layer(&self, l: usize) -> Option<&dyn Llama2Offload> {
+        self.offload
+            .as_deref()
+            .filter(|offload| l < offload.n_layers())
+    }
+
+    pub fn options(&self) -> Llama2KvCacheOptions {
+        self.options
+    }
+
+    /// the pages taken by the sequences.
+    pub fn n_used_pages(&self) -> usize {
+        self.pages.len() - self.free_pages.len()
+    }
+
+    /// add an empty sequence, returns its id.
+    pub fn alloc_seq(&mut self) -> usize {
+        let seq = self.add_seq(Llama2KvSeq::default());
+        if let Some(offload) = &mut self.offload {
+            let offloaded_seq = offload.alloc_seq();
+            debug_assert_eq!(seq, offloaded_seq);
+        }
+        seq
+    }
+
+    /// add a sequence sharing the rows of `seq`, returns its id.
+    pub fn fork_seq(&mut self, seq: usize) -> Result<usize> {
+        let forked = self.seq(seq)?.clone();
+        if let Some(offload) = &mut self.offload {
+            offload.fork_seq(seq)?;
+        }
+        for page in forked.pages.iter() {
+            self.pages[*page].as_mut().unwrap().refs += 1;
+        }
+        Ok(self.add_seq(forked))
+    }
+
+    /// release the sequence and the pages not shared by the others.
+    pub fn free_seq(&mut self, seq: usize) -> Result<()> {
+        self.clear_seq(seq)?;
+        self.seqs[seq] = None;
+        if let Some(offload) = &mut self.offload {
+            offload.free_seq(seq)?;
+        }
+        Ok(())
+    }
+
+    /// drop all the rows of the sequence.
+    pub fn clear_seq(&mut self, seq: usize) -> Result<()> {
+        let pages = std::mem::take(&mut self.seq_mut(seq)?.pages);
+        for page in pages {
+            self.release_page(page);
+        }
+        self.seq_mut(seq)?.len = 0;
+        if let Some(offload) = &mut self.offload {
+            offload.clear_seq(seq)?;
+        }
+        Ok(())
+    }
+
+    /// the rows cached on the sequence.
+    pub fn seq_len(&self, seq: usize) -> Result<usize> {
+        Ok(self.seq(seq)?.len)
+    }
+
+    /// the cached keys of the layer in the pages of the sequence.
+    pub fn keys(&self, seq: usize, l: usize) -> Result<Vec<&T>> {
+        Ok(self
+            .seq(seq)?
+            .pages
+            .iter()
+            .map(|page| {
+                self.pages[*page].as_ref().unwrap().keys[l]
+                    .as_ref()
+                    .unwrap()
+            })
+            .collect())
+    }
+
+    /// the cached keys and values of the layer in f32, which are (seq_len, n_kv_heads,
+    /// head_size) each. the quantized rows are dequantized.
+    pub fn read_rows(&self, seq: usize, l: usize) -> Result<(Vec<f32>, Vec<f32>)> {
+        if let Some(offload) = self.offloaded_layer(l) {
+            return offload.read_rows(seq, l);
+        }
+        let row_len = self.n_kv_heads * self.head_size;
+        let len = self.seq(seq)?.len;
+        let mut keys = vec![0.0; len * row_len];
+        let mut values = vec![0.0; len * row_len];
+        let mut offset = 0;
+        for page in self.seq(seq)?.pages.iter() {
+            let page = self.pages[*page].as_ref().unwrap();
+            for (cache, buf) in [(&page.keys[l], &mut keys), (&page.values[l], &mut values)] {
+                let cache = cache.as_ref().unwrap();
+                let shape = cache.strider().shape().to_vec();
+                let size = shape.iter().product::<usize>();
+                let mut rows = T::alloc(&shape, None, self.device.clone())?;
+                rows.copy_from(cache, &[0, 0, 0], size)?;
+                rows.export(&mut buf[offset..offset + size])?;
+            }
+            offset += page.keys[l].as_ref().unwrap().strider().shape()[0] * row_len;
+        }
+        Ok((keys, values))
+    }
+
+    /// write the key and the value of (n_kv_heads, head_size) at the row of the sequence, the
+    /// row is either appended or overwritten, like the rolling cache of the sliding window.
+    pub fn write(&mut self, seq: usize, l: usize, row: usize, k: &T, v: &T) -> Result<()> {
+        if self.offloaded_layer(l).is_some() {
+            let row_len = self.n_kv_heads * self.head_size;
+            let (mut k_buf, mut v_buf) = (vec![0.0; row_len], vec![0.0; row_len]);
+            k.export(&mut k_buf)?;
+            v.export(&mut v_buf)?;
+            let offload = self.offload.as_mut().unwrap();
+            return offload.write_rows(seq, l, row, &k_buf, &v_buf);
+        }
+        let page_size = self.options.page_size;
+        let n_pages = self.seq(seq)?.pages.len();
+        let page_idx = row / page_size;
+        if row > self.seq(seq)?.len {
+            return Err((
+                ErrorKind::TensorError,
+                format!("row {} is beyond the end of the kv cache", row),
+            )
+                .into());
+        }
+        if page_idx == n_pages {
+            let page = self.alloc_page()?;
+            self.seq_mut(seq)?.pages.push(page);
+        }
+
+        // copy the page on writing if it's shared with the other sequences
+        let mut page = self.seq(seq)?.pages[page_idx];
+        if self.pages[page].as_ref().unwrap().refs > 1 {
+            let copied = self.alloc_page()?;
+            for l in self.layers.clone() {
+                let src = self.pages[page].as_ref().unwrap();
+                let keys = src.keys[l].as_ref().unwrap().dup()?;
+                let values = src.values[l].as_ref().unwrap().dup()?;
+                let dst = self.pages[copied].as_mut().unwrap();
+                dst.keys[l] = Some(keys);
+                dst.values[l] = Some(values);
+            }
+            self.release_page(page);
+            self.seq_mut(seq)?.pages[page_idx] = copied;
+            page = copied;
+        }
+
+        let page = self.pages[page].as_mut().unwrap();
+        let page_row = row % page_size;
+        for (cache, t) in [(&mut page.keys[l], k), (&mut page.values[l], v)] {
+            let cache = cache.as_mut().unwrap();
+            if page_row < cache.strider().shape()[0] {
+                cache.set_row(page_row, t)?;
+            } else {
+                cache.extend(t)?;
+            }
+        }
+        let seq = self.seq_mut(seq)?;
+        seq.len = seq.len.max(row + 1);
+        Ok(())
+    }
+
+    /// drop the `n` rows from the row `start` of the sequence, the rows after are moved back by
+    /// `n` with the keys rotated back by `n` positions if `rope` is given, like the context
+    /// shift of llama.cpp. the rows before `start` are kept as the attention sink.
+    pub fn shift_seq(
+        &mut self,
+        seq: usize,
+        start: usize,
+        n: usize,
+        rope: Option<&RopeOptions>,
+    ) -> Result<()> {
+        let page_size = self.options.page_size;
+        let len = self.seq(seq)?.len;
+        if start + n > len {
+            return Err((
+                ErrorKind::BadInput,
+                format!("can not drop {} rows from {} of the {} rows", n, start, len),
+            )
+                .into());
+        }
+
+        // the pages before the dropped rows are kept, the rows after are copied into the new
+        // pages row by row, the quantized rows are dequantized on copying
+        let first_page = start / page_size;
+        let kept = (first_page * page_size..len)
+            .filter(|row| *row < start || *row >= start + n)
+            .collect::<Vec<_>>();
+        let mut new_pages = vec![];
+        for _ in 0..kept.len().div_ceil(page_size) {
+            match self.alloc_page() {
+                Ok(page) => new_pages.push(page),
+                Err(err) => {
+                    new_pages
+                        .into_iter()
+                        .for_each(|page| self.release_page(page));
+                    return Err(err);
+                }
+            }
+        }
+        let old_pages = self.seq_mut(seq)?.pages.split_off(first_page);
+        let rope = rope.map(|rope| rope.with_backward(true));
+        let row_len = self.n_kv_heads * self.head_size;
+        for l in self.layers.clone() {
+            for (i, row) in kept.iter().enumerate() {
+                let src = self.pages[old_pages[row / page_size - first_page]]
+                    .as_ref()
+                    .unwrap();
+                let pos = [row % page_size, 0, 0];
+                let shape = [self.n_kv_heads, self.head_size];
+                let mut k = T::alloc(&shape, None, self.device.clone())?;
+                k.copy_from(src.keys[l].as_ref().unwrap(), &pos, row_len)?;
+                let mut v = T::alloc(&shape, None, self.device.clone())?;
+                v.copy_from(src.values[l].as_ref().unwrap(), &pos, row_len)?;
+                if let (Some(rope), true) = (&rope, *row >= start) {
+                    k = k.rope_inplace(n, rope)?;
+                }
+
+                let dst = self.pages[new_pages[i / page_size]].as_mut().unwrap();
+                dst.keys[l].as_mut().unwrap().extend(&k)?;
+                dst.values[l].as_mut().unwrap().extend(&v)?;
+            }
+        }
+
+        for page in old_pages {
+            self.release_page(page);
+        }
+        let seq_mut = self.seq_mut(seq)?;
+        seq_mut.pages.extend(new_pages);
+        seq_mut.len = len - n;
+        if let Some(offload) = &mut self.offload {
+            offload.shift_seq(seq, start, n, rope.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// the attention scores of the query heads of (n_heads, head_size) on the cached keys of
+    /// the sequence, which is (n_heads, seq_len). every n_heads / n_kv_heads query heads in a
+    /// group share a kv head.
+    pub fn attn_scores(&mut self, seq: usize, l: usize, q: &T) -> Result<T> {
+        let mut scores = vec![];
+        for page in self.layer_pages(seq, l)? {
+            let page = self.pages[page].as_mut().unwrap();
+            let k_cache = page.keys[l].take().unwrap();
+            let k_cache_strider_orig = k_cache.strider().clone();
+            // (n_kv_heads, page_len, head_size) @ (n_heads, head_size) => (n_heads, page_len)
+            let k_cache = k_cache.transpose(&[1, 0, 2])?;
+            let score = k_cache.batch_matmul_vec(q);
+            page.keys[l].replace(k_cache.with_strider(k_cache_strider_orig)?);
+            scores.push(score?);
+        }
+        self.concat_pages(scores)
+    }
+
+    /// the sum of the cached values of the sequence weighted by the attention of
+    /// (n_heads, seq_len), which is (n_heads, head_size).
+    pub fn attn_values(&mut self, seq: usize, l: usize, attn: T) -> Result<T> {
+        let pages = self.layer_pages(seq, l)?;
+        let attns = self.split_pages(&pages, l, attn)?;
+        let mut out: Option<T> = None;
+        for (page, attn) in pages.into_iter().zip(attns) {
+            let page = self.pages[page].as_mut().unwrap();
+            let v_cache = page.values[l].take().unwrap();
+            let v_cache_strider_orig = v_cache.strider().clone();
+            // (n_kv_heads, head_size, page_len) @ (n_heads, page_len) => (n_heads, head_size)
+            let v_cache = v_cache.transpose(&[1, 2, 0])?;
+            let x = v_cache.batch_matmul_vec(&attn);
+            page.values[l].replace(v_cache.with_strider(v_cache_strider_orig)?);
+            out = Some(match out {
+                Some(out) => out.add_inplace(&x?)?,
+                None => x?,
+            });
+        }
+        out.ok_or_else(|| (ErrorKind::TensorError, "the kv cache is empty").into())
+    }
+
+    /// the fused attention of the query heads of (n_heads, head_size) on the cached keys and
+    /// values of the sequence, which is (n_heads, head_size), without taking the scores of
+    /// (n_heads, seq_len) like `attn_scores` and `attn_values`.
+    pub fn attn(
+        &self,
+        seq: usize,
+        l: usize,
+        q: &T,
+        scale: f32,
+        alibi: Option<(usize, f32)>,
+    ) -> Result<T> {
+        let pages = self
+            .layer_pages(seq, l)?
+            .into_iter()
+            .map(|page| self.pages[page].as_ref().unwrap())
+            .collect::<Vec<_>>();
+        let k_pages = pages
+            .iter()
+            .map(|page| page.keys[l].as_ref().unwrap())
+            .collect::<Vec<_>>();
+        let v_pages = pages
+            .iter()
+            .map(|page| page.values[l].as_ref().unwrap())
+            .collect::<Vec<_>>();
+        q.flash_attention(&k_pages, &v_pages, scale, alibi)
+    }
+
+    /// concat the scores of (n_heads, page_len) on the pages into (n_heads, seq_len).
+    fn concat_pages(&self, mut scores: Vec<T>) -> Result<T> {
+        if scores.len() == 1 {
+            return Ok(scores.pop().unwrap());
+        }
+        let n_heads = scores[0].strider().shape()[0];
+        let seq_len = scores.iter().map(|s| s.strider().shape()[1]).sum::<usize>();
+        let mut buf = vec![0.0; n_heads * seq_len];
+        let mut offset = 0;
+        for score in scores.iter() {
+            let page_len = score.strider().shape()[1];
+            let mut page_buf = vec![0.0; n_heads * page_len];
+            score.export(&mut page_buf)?;
+            for (h, row) in page_buf.chunks(page_len).enumerate() {
+                let start = h * seq_len + offset;
+                buf[start..start + page_len].copy_from_slice(row);
+            }
+            offset += page_len;
+        }
+        T::from_vec(buf, &[n_heads, seq_len], self.device.clone())
+    }
+
+    /// split the attention of (n_heads, seq_len) into the views of (n_heads, page_len) on the
+    /// pages, which share the buffer of the attention on the gpus instead of copying it.
+    fn split_pages(&self, pages: &[usize], l: usize, attn: T) -> Result<Vec<T>> {
+        if pages.len() <= 1 {
+            return Ok(vec![attn]);
+        }
+        let page_lens = pages
+            .iter()
+            .map(|page| {
+                self.pages[*page].as_ref().unwrap().keys[l]
+                    .as_ref()
+                    .unwrap()
+            })
+            .map(|k_cache| k_cache.strider().shape()[0])
+            .collect::<Vec<_>>();
+        let (last_len, page_lens) = page_lens.split_last().unwrap();
+        let mut offset = 0;
+        let mut attns = Vec::with_capacity(pages.len());
+        for page_len in page_lens {
+            attns.push(attn.clone().slice(1, offset, *page_len)?);
+            offset += page_len;
+        }
+        // the last page takes the attention itself
+        attns.push(attn.slice(1, offset, *last_len)?);
+        Ok(attns)
+    }
+
+    /// the pages of the sequence holding the rows of the layer. the layers are written one by
+    /// one on forwarding a batch of tokens, so the pages after may be still empty on the layer.
+    fn layer_pages(&self, seq: usize, l: usize) -> Result<Vec<usize>> {
+        Ok(self
+            .seq(seq)?
+            .pages
+            .iter()
+            .copied()
+            .filter(|page| {
+                let k_cache = self.pages[*page].as_ref().unwrap().keys[l].as_ref();
+                k_cache.unwrap().strider().shape()[0] > 0
+            })
+            .collect())
+    }
+
+    fn alloc_page(&mut self) -> Result<usize> {
+        let alloc = || {
+            T::alloc_cache(
+                &[0, self.n_kv_heads, self.head_size],
+                Some(self.options.page_size * self.n_kv_heads * self.head_size),
+                self.options.dtype,
+                self.device.clone(),
+            )
+            .map(Some)
+        };
+        let alloc_layer = |l| match self.layers.contains(&l) {
+            true => alloc(),
+            false => Ok(None),
+        };
+        let page = Llama2KvPage {
+            keys: (0..self.n_layers).map(alloc_layer).collect::<Result<_>>()?,
+            values: (0..self.n_layers).map(alloc_layer).collect::<Result<_>>()?,
+            refs: 1,
+        };
+
+        if let Some(id) = self.free_pages.pop() {
+            self.pages[id] = Some(page);
+            return Ok(id);
+        }
+        if let Some(max_pages) = self.options.max_pages {
+            if self.pages.len() >= max_pages {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("the kv cache is out of {} pages", max_pages),
+                )
+                    .into());
+            }
+        }
+        self.pages.push(Some(page));
+        Ok(self.pages.len() - 1)
+    }
+
+    fn release_page(&mut self, id: usize) {
+        let page = self.pages[id].as_mut().unwrap();
+        page.refs -= 1;
+        if page.refs == 0 {
+            self.pages[id] = None;
+            self.free_pages.push(id);
+        }
+    }
+
+    fn add_seq(&mut self, seq: Llama2KvSeq) -> usize {
+        match self.seqs.iter().position(|seq| seq.is_none()) {
+            Some(id) => {
+                self.seqs[id] = Some(seq);
+                id
+            }
+            None => {
+                self.seqs.push(Some(seq));
+                self.seqs.len() - 1
+            }
+        }
+    }
+
+    fn seq(&self, seq: usize) -> Result<&Llama2KvSeq> {
+        match self.seqs.get(seq) {
+            Some(Some(s)) => Ok(s),
+            _ => Err((
+                ErrorKind::BadInput,
+                format!("the sequence {} is not found", seq),
+            )
+                .into()),
+        }
+    }
+
+    fn seq_mut(&mut self, seq: usize) -> Result<&mut Llama2KvSeq> {
+        match self.seqs.get_mut(seq) {
+            Some(Some(s)) => Ok(s),
+            _ => Err((
+                ErrorKind::BadInput,
+                format!("the sequence {} is not found", seq),
+            )
+                .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crabml::backends::cpu::CpuTensor;
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::backends::cpu::CpuTensorDeviceRef;
+
+    use super::*;
+
+    fn new_cache(
+        page_size: usize,
+        max_pages: Option<usize>,
+        device: CpuTensorDeviceRef<'_>,
+    ) -> Result<Llama2KvCache<CpuTensor<'_>>> {
+        // 2 layers of 2 kv heads in the size of 4
+        let conf = Llama2Config::from_hf_config(
+            r#"{"hidden_size": 16, "intermediate_size": 32, "num_hidden_layers": 2,
+                "num_attention_heads": 4, "num_key_value_heads": 2, "vocab_size": 32,
+                "max_position_embeddings": 64}"#,
+        )?;
+        let options = Llama2KvCacheOptions {
+            page_size,
+            max_pages,
+            ..Default::default()
+        };
+        Llama2KvCache::new(&conf, options, device)
+    }
+
+    fn row(v: f32, device: CpuTensorDeviceRef<'_>) -> Result<CpuTensor<'_>> {
+        let buf = (0..8).map(|i| v + i as f32 / 8.0).collect();
+        CpuTensor::new(buf, &[2, 4], device)
+    }
+
+    fn write_rows<'a>(
+        cache: &mut Llama2KvCache<CpuTensor<'a>>,
+        seq: usize,
+        rows: std::ops::Range<usize>,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<()> {
+        for i in rows {
+            for l in 0..2 {
+                let k = row(i as f32, device.clone())?;
+                let v = row(-(i as f32), device.clone())?;
+                cache.write(seq, l, i, &k, &v)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_pages() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let mut cache = new_cache(2, Some(4), device.clone())?;
+        let seq = cache.alloc_seq();
+        write_rows(&mut cache, seq, 0..3, device.clone())?;
+        assert_eq!(cache.seq_len(seq)?, 3);
+        assert_eq!(cache.n_used_pages(), 2);
+
+        // the forked sequence shares the pages until it writes on the last one
+        let forked = cache.fork_seq(seq)?;
+        assert_eq!(cache.n_used_pages(), 2);
+        write_rows(&mut cache, forked, 3..4, device.clone())?;
+        assert_eq!(cache.n_used_pages(), 3);
+        assert_eq!(cache.seq_len(seq)?, 3);
+        assert_eq!(cache.seq_len(forked)?, 4);
+        assert_eq!(cache.keys(seq, 0)?[1].shape(), &[1, 2, 4]);
+        assert_eq!(cache.keys(forked, 0)?[1].shape(), &[2, 2, 4]);
+
+        // the pool is out of pages, the row 3 is still written on the page no longer shared
+        assert!(write_rows(&mut cache, forked, 4..6, device.clone()).is_ok());
+        assert!(write_rows(&mut cache, seq, 3..5, device.clone()).is_err());
+
+        // the shared pages are kept until both sequences are freed
+        cache.free_seq(forked)?;
+        assert_eq!(cache.n_used_pages(), 2);
+        assert_eq!(cache.seq_len(seq)?, 4);
+        cache.free_seq(seq)?;
+        assert_eq!(cache.n_used_pages(), 0);
+        assert!(cache.seq_len(seq).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_attn() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let q = (0..16).map(|i| i as f32 / 16.0).collect::<Vec<_>>();
+        let q = CpuTensor::new(q, &[4, 4], device.clone())?;
+
+        // the attention over the pages is the same as the one over a single page
+        let attn = |page_size: usize| -> Result<(Vec<f32>, Vec<f32>)> {
+            let mut cache = new_cache(page_size, None, device.clone())?;
+            let seq = cache.alloc_seq();
+            write_rows(&mut cache, seq, 0..5, device.clone())?;
+            let scores = cache.attn_scores(seq, 1, &q)?.softmax_inplace(1)?;
+            let mut scores_buf = vec![0.0; 20];
+            scores.export(&mut scores_buf)?;
+            let mut out_buf = vec![0.0; 16];
+            cache.attn_values(seq, 1, scores)?.export(&mut out_buf)?;
+            Ok((scores_buf, out_buf))
+        };
+        let (scores, out) = attn(8)?;
+        let (paged_scores, paged_out) = attn(2)?;
+        assert_relative_eq!(&scores[..], &paged_scores[..], epsilon = 1e-6);
+        assert_relative_eq!(&out[..], &paged_out[..], epsilon = 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_shift() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let rope = RopeOptions::new(Default::default(), 4);
+        let keys = |cache: &Llama2KvCache<CpuTensor>, seq: usize| -> Result<Vec<f32>> {
+            let mut buf = vec![];
+            for page in cache.keys(seq, 1)? {
+                let mut page_buf = vec![0.0; page.shape().iter().product()];
+                page.export(&mut page_buf)?;
+                buf.extend(page_buf);
+            }
+            Ok(buf)
+        };
+        let write_roped =
+            |cache: &mut Llama2KvCache<CpuTensor<'_>>, seq, rows: &[(usize, usize)]| {
+                for (i, (value, pos)) in rows.iter().enumerate() {
+                    for l in 0..2 {
+                        let k = row(*value as f32, device.clone())?.rope_inplace(*pos, &rope)?;
+                        let v = row(-(*value as f32), device.clone())?;
+                        cache.write(seq, l, i, &k, &v)?;
+                    }
+                }
+                Ok::<_, crabml::error::Error>(())
+            };
+
+        // drop the rows 1 and 2, the rows 3 and 4 are moved onto the positions 1 and 2
+        let mut cache = new_cache(2, None, device.clone())?;
+        let seq = cache.alloc_seq();
+        write_roped(&mut cache, seq, &[(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)])?;
+        cache.shift_seq(seq, 1, 2, Some(&rope))?;
+        assert_eq!(cache.seq_len(seq)?, 3);
+        assert_eq!(cache.n_used_pages(), 2);
+
+        let mut want = new_cache(2, None, device.clone())?;
+        let want_seq = want.alloc_seq();
+        write_roped(&mut want, want_seq, &[(0, 0), (3, 1), (4, 2)])?;
+        assert_relative_eq!(
+            &keys(&cache, seq)?[..],
+            &keys(&want, want_seq)?[..],
+            epsilon = 1e-5
+        );
+        assert!(cache.shift_seq(seq, 2, 2, None).is_err());
+        Ok(())
+    }
+}
@@ -1,5 +1,22 @@
+pub mod abort;
+pub mod batch;
+pub mod bert;
+pub mod chat_template;
+pub mod control_vector;
+pub mod grammar;
+pub mod json_schema;
+pub mod kv_cache;
 pub mod llama2;
+pub mod logits_processor;
+pub mod lora;
 pub mod model;
+pub mod offload;
+pub mod prompt_cache;
+pub mod rwkv;
 pub mod sampler;
+pub mod session;
+pub mod speculative;
+#[cfg(feature = "async")]
+pub mod stream;
 
 pub use model::CpuLlama2Model;
@@ -1,70 +1,126 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::ops::AddAssign;
+use std::ops::Range;
 use std::rc::Rc;
 use std::time::Duration;
-use std::time::Instant;
 use std::vec;
 
 use crabml::backends::cpu::CpuTensor;
-use crabml::backends::wgpu::WgpuTensor;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::tensor::RopeOptions;
 use crabml::tensor::Tensor;
+use crabml::tensor::TensorBackend;
+use crabml::tensor::TensorGraph;
+use crabml::time::Instant;
+use crabml::tokenizer::BpeSpecialMode;
+use crabml::tokenizer::BpeStreamDecoder;
 use crabml::tokenizer::BpeTokenizer;
 
+use crate::abort::Llama2AbortHandle;
+use crate::bert::normalize;
+use crate::bert::pool;
+use crate::control_vector::Llama2ControlVector;
+use crate::kv_cache::Llama2KvCache;
+use crate::kv_cache::Llama2KvCacheOptions;
+use crate::lora::Llama2LoraAdapter;
+use crate::lora::Llama2LoraTarget;
 use crate::model::CpuLlama2Model;
 use crate::model::Llama2Config;
+use crate::model::Llama2Model;
+use crate::model::Llama2Norm;
+use crate::model::Llama2Pooling;
 use crate::model::Llama2Weights;
-use crate::model::WgpuLlama2Model;
+use crate::prompt_cache::Llama2PromptCache;
+use crate::rwkv::RwkvState;
+use crate::sampler::log_softmax;
+use crate::sampler::softmax;
 use crate::sampler::Llama2Sampler;
+use crate::sampler::Llama2SamplerLogprobs;
+use crate::session::Llama2Session;
 
 pub struct Llama2Runner<T: Tensor> {
-    conf: Llama2Config,
-    weights: Rc<Llama2Weights<T>>,
-    tokenizer: Rc<BpeTokenizer>,
-    device: T::Device,
-    logits: Vec<f32>,            // output logits (vocab_size, )
-    key_cache: Vec<Option<T>>,   // (layer, seq_len, kv_dim)
-    value_cache: Vec<Option<T>>, // (layer, seq_len, kv_dim)
+    pub(crate) conf: Llama2Config,
+    pub(crate) weights: Rc<Llama2Weights<T>>,
+    pub(crate) tokenizer: Rc<BpeTokenizer>,
+    pub(crate) device: T::Device,
+    logits: Vec<f32>,                      // output logits (vocab_size, )
+    pub(crate) kv_cache: Llama2KvCache<T>, // (layer, seq_len, kv_dim) in pages
+    seq: usize,                            // the sequence of the kv cache to forward on
+    tokens: Vec<usize>,                    // the tokens forwarded on the sequence
+    prompt_cache: Option<Llama2PromptCache>,
+    pub(crate) rwkv_state: Vec<RwkvState>, // (layer, ), empty on the transformers
+    pub(crate) batch_size: usize,          // the max prompt tokens forwarded together
+    pub(crate) loras: HashMap<String, Rc<Llama2LoraAdapter<T>>>, // the registered adapters by name
+    active_loras: Vec<(String, Rc<Llama2LoraAdapter<T>>, f32)>, // (name, adapter, scale)
+    pub(crate) control_vector: Vec<Option<T>>, // (layer, embedding_dim), empty if there's none
+    guidance_seq: Option<usize>,           // the sequence of the negative prompt on the guidance
+    pub(crate) layers: Range<usize>,       // the layers forwarded on this runner
+    flash_attention: bool,                 // take the fused attention on the kv cache
+    activation_dtype: GGMLType,            // the dtype of the inputs of the matmuls
 }
 
 impl<'a> TryFrom<&'a CpuLlama2Model<'a>> for Llama2Runner<CpuTensor<'a>> {
     type Error = crabml::error::Error;
 
     fn try_from(model: &'a CpuLlama2Model<'a>) -> Result<Self> {
-        let conf = &model.conf;
-        let device = model.device.clone();
-        let weights = model.weights.clone();
-        let tokenizer = model.tokenizer.clone();
-        let seq_len = conf.seq_len;
+        Self::new(
+            &model.conf,
+            model.weights.clone(),
+            model.tokenizer.clone(),
+            model.device.clone(),
+        )
+    }
+}
 
-        let logits = vec![0.0; conf.vocab_size];
-        let key_cache = (0..conf.n_layers)
-            .map(|_| {
-                CpuTensor::alloc(
-                    &[0, conf.n_kv_heads, conf.head_size()],
-                    Some(seq_len * conf.embedding_dim),
-                    device.clone(),
-                )
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        let value_cache = (0..conf.n_layers)
-            .map(|_| {
-                CpuTensor::alloc(
-                    &[0, conf.n_kv_heads, conf.head_size()],
-                    Some(seq_len * conf.embedding_dim),
-                    device.clone(),
-                )
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
+impl<T: Tensor<Device = B>, B: TensorBackend<Tensor = T>> TryFrom<&Llama2Model<B>>
+    for Llama2Runner<T>
+{
+    type Error = crabml::error::Error;
 
+    fn try_from(model: &Llama2Model<B>) -> Result<Self> {
+        Self::new(
+            &model.conf,
+            model.weights.clone(),
+            model.tokenizer.clone(),
+            model.device.clone(),
+        )
+    }
+}
+
+impl<T: Tensor> Llama2Runner<T> {
+    fn new(
+        conf: &Llama2Config,
+        weights: Rc<Llama2Weights<T>>,
+        tokenizer: Rc<BpeTokenizer>,
+        device: T::Device,
+    ) -> Result<Self> {
+        let logits = vec![0.0; conf.vocab_size];
+        let mut kv_cache = Llama2KvCache::new(conf, Default::default(), device.clone())?;
+        let seq = kv_cache.alloc_seq();
+        let rwkv_state = match weights.rwkv {
+            Some(_) => vec![RwkvState::new(conf); conf.n_layers],
+            None => vec![],
+        };
         Ok(Self {
             conf: *conf,
             logits,
-            key_cache,
-            value_cache,
+            kv_cache,
+            seq,
+            tokens: vec![],
+            prompt_cache: None,
+            rwkv_state,
+            batch_size: 512,
+            loras: HashMap::new(),
+            active_loras: vec![],
+            control_vector: vec![],
+            guidance_seq: None,
+            layers: 0..conf.n_layers,
+            flash_attention: false,
+            activation_dtype: GGMLType::F32,
             weights,
             tokenizer,
             device,
@@ -72,49 +128,258 @@ impl<'a> TryFrom<&'a CpuLlama2Model<'a>> for Llama2Runner<CpuTensor<'a>> {
     }
 }
 
-impl TryFrom<&WgpuLlama2Model> for Llama2Runner<WgpuTensor> {
-    type Error = crabml::error::Error;
+impl<'a, T: Tensor> Llama2Runner<T> {
+    /// store the kv cache in Q8_0 or Q4_0 instead of F32, which takes about 1/4 or 1/8 of the
+    /// memory on the long contexts. the cache is reset, so it's expected to be called before
+    /// the generation.
+    pub fn with_kv_cache_dtype(self, dtype: GGMLType) -> Result<Self> {
+        let options = Llama2KvCacheOptions {
+            dtype,
+            ..self.kv_cache.options()
+        };
+        self.with_kv_cache_options(options)
+    }
 
-    fn try_from(model: &WgpuLlama2Model) -> Result<Self> {
-        let conf = &model.conf;
-        let device = model.device.clone();
-        let weights = model.weights.clone();
-        let tokenizer = model.tokenizer.clone();
-        let logits = vec![0.0; conf.vocab_size];
-        let seq_len = conf.seq_len;
-        let key_cache = (0..conf.n_layers)
-            .map(|_| {
-                WgpuTensor::alloc(
-                    &[0, conf.n_heads, conf.head_size()],
-                    Some(seq_len * conf.embedding_dim),
-                    device.clone(),
-                )
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        let value_cache = (0..conf.n_layers)
-            .map(|_| {
-                WgpuTensor::alloc(
-                    &[0, conf.n_heads, conf.head_size()],
-                    Some(seq_len * conf.embedding_dim),
-                    device.clone(),
-                )
-                .map(Some)
+    /// the page size, the max pages and the dtype of the kv cache, the cache is reset.
+    pub fn with_kv_cache_options(mut self, options: Llama2KvCacheOptions) -> Result<Self> {
+        let offload = self.kv_cache.take_offload();
+        self.kv_cache = Llama2KvCache::new(&self.conf, options, self.device.clone())?
+            .with_layers(self.layers.clone());
+        if let Some(mut offload) = offload {
+            offload.reset()?;
+            self.kv_cache.set_offload(offload);
+        }
+        self.seq = self.kv_cache.alloc_seq();
+        self.tokens.clear();
+        self.prompt_cache = self
+            .prompt_cache
+            .map(|cache| Llama2PromptCache::new(cache.capacity(), options.page_size));
+        Ok(self)
+    }
+
+    /// keep the kv cache of the last `capacity` prompts, so a prompt sharing a prefix with a
+    /// cached one only forwards the rest, like a chat with a long system prompt.
+    pub fn with_prompt_cache(mut self, capacity: usize) -> Result<Self> {
+        if !self.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the prompt cache on the recurrent state is not supported yet",
+            )
+                .into());
+        }
+        let page_size = self.kv_cache.options().page_size;
+        self.prompt_cache = Some(Llama2PromptCache::new(capacity, page_size));
+        Ok(self)
+    }
+
+    /// the tokens forwarded on the kv cache.
+    /// forward at most `batch_size` prompt tokens together, the larger batches take more
+    /// memory on the activations. 1 forwards the prompt token by token.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// take the attention of each token on the kv cache in a fused kernel, which keeps the
+    /// online softmax over the tiles of the keys instead of the scores of the whole sequence,
+    /// like the flash attention. it saves the memory and the bandwidth on the long contexts.
+    pub fn with_flash_attention(mut self, enabled: bool) -> Self {
+        self.flash_attention = enabled;
+        self
+    }
+
+    /// take the inputs of the matmuls in F16 instead of F32, which halves the memory and the
+    /// bandwidth of the activations on the matmuls, the products are still accumulated in
+    /// f32. only F32 and F16 are supported.
+    pub fn with_activation_dtype(mut self, dtype: GGMLType) -> Result<Self> {
+        if !matches!(dtype, GGMLType::F32 | GGMLType::F16) {
+            return Err((
+                ErrorKind::BadInput,
+                format!("the activations of {} are not supported", dtype),
+            )
+                .into());
+        }
+        self.activation_dtype = dtype;
+        Ok(self)
+    }
+
+    pub fn tokens(&self) -> &[usize] {
+        &self.tokens
+    }
+
+    /// register a lora adapter by the name, which takes no effect until it's activated by
+    /// `set_loras`. the adapter of the same name is replaced.
+    pub fn add_lora(&mut self, name: &str, adapter: Llama2LoraAdapter<T>) -> Result<()> {
+        if !self.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the lora adapters on the recurrent layers are not supported yet",
+            )
+                .into());
+        }
+        if self.layers.start > 0 {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the lora adapters on the offloaded layers are not supported yet",
+            )
+                .into());
+        }
+        adapter.validate(&self.conf)?;
+        self.remove_lora(name)?;
+        self.loras.insert(name.to_string(), Rc::new(adapter));
+        Ok(())
+    }
+
+    /// unregister the lora adapter, it's deactivated if it's active.
+    pub fn remove_lora(&mut self, name: &str) -> Result<()> {
+        if self.loras.remove(name).is_some() && self.active_loras.iter().any(|l| l.0 == name) {
+            let loras = self
+                .active_loras
+                .iter()
+                .filter(|l| l.0 != name)
+                .map(|l| (l.0.clone(), l.2))
+                .collect::<Vec<_>>();
+            let loras = loras
+                .iter()
+                .map(|(n, s)| (n.as_str(), *s))
+                .collect::<Vec<_>>();
+            self.set_loras(&loras)?;
+        }
+        Ok(())
+    }
+
+    /// steer the output of the layers by the sum of the control vectors scaled by their
+    /// strengths, an empty list removes the steering. the forwarded tokens and the cached
+    /// prompts are dropped like switching the lora adapters.
+    pub fn set_control_vectors(&mut self, vectors: &[(&Llama2ControlVector, f32)]) -> Result<()> {
+        let vector = Llama2ControlVector::combine(vectors)?;
+        if self.layers.start > 0 && !vector.directions.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the control vectors on the offloaded layers are not supported yet",
+            )
+                .into());
+        }
+        let mut control_vector = vec![];
+        for (layer, direction) in vector.directions {
+            if layer >= self.conf.n_layers || direction.len() != self.conf.embedding_dim {
+                return Err(Error {
+                    kind: ErrorKind::BadInput,
+                    message: format!(
+                        "the direction of {} dims on the layer {} does not fit the model",
+                        direction.len(),
+                        layer
+                    ),
+                    cause: None,
+                });
+            }
+            control_vector.resize_with(layer + 1, || None);
+            let direction =
+                T::from_vec(direction, &[self.conf.embedding_dim], self.device.clone())?;
+            control_vector[layer] = Some(direction);
+        }
+
+        self.truncate(0)?;
+        if let Some(cache) = &mut self.prompt_cache {
+            cache.clear(&mut self.kv_cache)?;
+        }
+        self.control_vector = control_vector;
+        Ok(())
+    }
+
+    /// the names of the registered lora adapters.
+    pub fn loras(&self) -> Vec<&str> {
+        let mut names = self.loras.keys().map(|n| n.as_str()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// activate the registered lora adapters by the names with their scales, the deltas of
+    /// the adapters are stacked, an empty list runs on the base model. the forwarded tokens
+    /// and the cached prompts are dropped on switching, since their kv cache was taken on the
+    /// other weights.
+    pub fn set_loras(&mut self, loras: &[(&str, f32)]) -> Result<()> {
+        let active_loras = loras
+            .iter()
+            .map(|(name, scale)| match self.loras.get(*name) {
+                Some(adapter) => Ok((name.to_string(), adapter.clone(), *scale)),
+                None => Err(Error {
+                    kind: ErrorKind::BadInput,
+                    message: format!("no lora adapter named {}", name),
+                    cause: None,
+                }),
             })
             .collect::<Result<Vec<_>>>()?;
-        Ok(Self {
-            conf: *conf,
-            logits,
-            key_cache,
-            value_cache,
-            weights,
-            tokenizer,
-            device,
+        let unchanged = active_loras.len() == self.active_loras.len()
+            && active_loras
+                .iter()
+                .zip(self.active_loras.iter())
+                .all(|(a, b)| a.0 == b.0 && Rc::ptr_eq(&a.1, &b.1) && a.2 == b.2);
+        if unchanged {
+            return Ok(());
+        }
+
+        self.truncate(0)?;
+        if let Some(cache) = &mut self.prompt_cache {
+            cache.clear(&mut self.kv_cache)?;
+        }
+        self.active_loras = active_loras;
+        Ok(())
+    }
+
+    /// take the forwarded tokens, the kv cache and the rng state of the sampler into a
+    /// session, which can be saved and restored later.
+    pub fn session(&self, sampler: &Llama2Sampler) -> Result<Llama2Session> {
+        let mut keys = vec![];
+        let mut values = vec![];
+        for l in 0..self.conf.n_kv_cache_layers() {
+            let (k, v) = self.kv_cache.read_rows(self.seq, l)?;
+            keys.push(k);
+            values.push(v);
+        }
+        Ok(Llama2Session {
+            tokens: self.tokens.clone(),
+            rng: sampler.rng(),
+            kv_dim: self.conf.n_kv_heads * self.conf.head_size(),
+            keys,
+            values,
+            rwkv_state: self.rwkv_state.clone(),
         })
     }
-}
 
-impl<'a, T: Tensor> Llama2Runner<T> {
+    /// restore the forwarded tokens, the kv cache and the rng state of the sampler from the
+    /// session. the generation on a prompt starting with the tokens of the session only
+    /// forwards the rest of the prompt.
+    pub fn restore_session(
+        &mut self,
+        session: &Llama2Session,
+        sampler: &mut Llama2Sampler,
+    ) -> Result<()> {
+        let head_size = self.conf.head_size();
+        let kv_dim = self.conf.n_kv_heads * head_size;
+        if session.keys.len() != self.conf.n_kv_cache_layers()
+            || (!session.keys.is_empty() && session.kv_dim != kv_dim)
+            || session.rwkv_state.len() != self.rwkv_state.len()
+        {
+            return Err((ErrorKind::BadInput, "the session does not match the model").into());
+        }
+
+        self.kv_cache.clear_seq(self.seq)?;
+        for (l, (keys, values)) in session.keys.iter().zip(session.values.iter()).enumerate() {
+            let rows = keys.chunks(kv_dim).zip(values.chunks(kv_dim));
+            for (row, (k, v)) in rows.enumerate() {
+                let shape = [self.conf.n_kv_heads, head_size];
+                let k = T::from_vec(k.to_vec(), &shape, self.device.clone())?;
+                let v = T::from_vec(v.to_vec(), &shape, self.device.clone())?;
+                self.kv_cache.write(self.seq, l, row, &k, &v)?;
+            }
+        }
+        self.rwkv_state = session.rwkv_state.clone();
+        self.tokens = session.tokens.clone();
+        sampler.set_rng(session.rng);
+        Ok(())
+    }
+
     pub fn generate(
         &'a mut self,
         prompt: &str,
@@ -125,291 +390,3246 @@ impl<'a, T: Tensor> Llama2Runner<T> {
     }
 
     pub fn forward(&mut self, token: usize, pos: usize) -> Result<&mut [f32]> {
+        self.forward_batch(&[token], pos)
+    }
+
+    /// forward the tokens on the positions from `pos` together, returns the logits of the last
+    /// token. the linear layers take all the tokens in a single matmul, so the weights are
+    /// loaded once for the batch, which speeds up the prompt evaluation a lot.
+    pub fn forward_batch(&mut self, tokens: &[usize], pos: usize) -> Result<&mut [f32]> {
+        self.logits = self.forward_tokens(tokens, pos, false)?.pop().unwrap();
+        Ok(&mut self.logits)
+    }
+
+    /// forward the tokens like `forward_batch`, but returns the logits of every token, like
+    /// verifying the draft tokens in one pass on the speculative decoding.
+    pub fn forward_batch_logits(&mut self, tokens: &[usize], pos: usize) -> Result<Vec<Vec<f32>>> {
+        let logits = self.forward_tokens(tokens, pos, true)?;
+        self.logits.copy_from_slice(logits.last().unwrap());
+        Ok(logits)
+    }
+
+    /// the negative log likelihoods of the tokens after the first `n_skip` ones, each predicted
+    /// by the logits of the token before it, like evaluating the perplexity on a chunk of the
+    /// text. the tokens are forwarded from the position 0 in the batches of `batch_size`.
+    pub fn token_nlls(&mut self, tokens: &[usize], n_skip: usize) -> Result<Vec<f32>> {
+        if tokens.len() > self.conf.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected at most {} tokens to evaluate, got {}",
+                    self.conf.seq_len,
+                    tokens.len()
+                ),
+            )
+                .into());
+        }
+        let mut nlls = vec![];
+        for (i, batch) in tokens.chunks(self.batch_size).enumerate() {
+            let pos = i * self.batch_size;
+            let logits = self.forward_batch_logits(batch, pos)?;
+            for (j, mut logits) in logits.into_iter().enumerate() {
+                let next = pos + j + 1;
+                if next < n_skip.max(1) || next >= tokens.len() {
+                    continue;
+                }
+                log_softmax(&mut logits);
+                nlls.push(-logits[tokens[next]]);
+            }
+        }
+        Ok(nlls)
+    }
+
+    /// drop the forwarded tokens after the first `len` ones and their rows in the kv cache,
+    /// like the rejected draft tokens on the speculative decoding.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.tokens.len() {
+            return Ok(());
+        }
+        if len == 0 {
+            self.kv_cache.clear_seq(self.seq)?;
+            self.rwkv_state.fill(RwkvState::new(&self.conf));
+            self.tokens.clear();
+            return Ok(());
+        }
+        // the recurrent state and the rolled over cache can not be rewound
+        if !self.rwkv_state.is_empty() || self.tokens.len() > self.conf.kv_cache_len() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the recurrent state or the rolled over kv cache can not be truncated",
+            )
+                .into());
+        }
+        let cache_len = self.kv_cache.seq_len(self.seq)?;
+        if len < cache_len {
+            self.kv_cache
+                .shift_seq(self.seq, len, cache_len - len, None)?;
+        }
+        self.tokens.truncate(len);
+        Ok(())
+    }
+
+    /// forward the prompt tokens except the last one after the prefix kept in the kv cache,
+    /// returns the number of the kept tokens. unlike `generate`, it does not stop on the bos
+    /// and eos tokens in the prompt, like the past turns of a chat rendered by the template,
+    /// and `generate` on the same prompt then only forwards the last token.
+    pub fn prefill(&mut self, prompt_tokens: &[usize]) -> Result<usize> {
+        if prompt_tokens.is_empty() || prompt_tokens.len() > self.conf.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected 1 to {} prompt tokens, got {}",
+                    self.conf.seq_len,
+                    prompt_tokens.len()
+                ),
+            )
+                .into());
+        }
+        let span = tracing::debug_span!(
+            "prefill",
+            n_tokens = prompt_tokens.len(),
+            n_reused = tracing::field::Empty
+        )
+        .entered();
+        let n_reused = self.reuse_prefix(prompt_tokens)?;
+        span.record("n_reused", n_reused);
+        let rest = &prompt_tokens[n_reused..prompt_tokens.len() - 1];
+        for (i, batch) in rest.chunks(self.batch_size).enumerate() {
+            self.forward_batch(batch, n_reused + i * self.batch_size)?;
+        }
+        Ok(n_reused)
+    }
+
+    fn forward_tokens(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        all_logits: bool,
+    ) -> Result<Vec<Vec<f32>>> {
+        if tokens.is_empty() {
+            return Err((ErrorKind::BadInput, "no tokens to forward").into());
+        }
+
+        // the sequence restarts from the position 0
+        if pos == 0 {
+            self.kv_cache.clear_seq(self.seq)?;
+            self.rwkv_state.fill(RwkvState::new(&self.conf));
+        }
+        self.tokens.truncate(pos);
+        self.tokens.extend_from_slice(tokens);
+
+        let rows = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| Llama2Row {
+                seq: self.seq,
+                token: *token,
+                pos: pos + i,
+                logits: all_logits || i == tokens.len() - 1,
+            })
+            .collect::<Vec<_>>();
+        self.forward_rows(&rows)
+    }
+
+    /// forward the rows together, which may be on the different sequences of the kv cache,
+    /// returns the logits of the rows asking for them. the rows of a sequence are expected
+    /// to be in the order of the positions. the recurrent state of rwkv is only kept for
+    /// the sequence of the runner.
+    pub(crate) fn forward_rows(&mut self, rows: &[Llama2Row]) -> Result<Vec<Vec<f32>>> {
+        let xs = self.forward_hidden(rows)?;
+        if xs.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // classifier into logits
+        let bcls = self.weights.bcls.as_ref();
+        let logits = self.matmul_rows(&self.weights.wcls, bcls, &xs)?; // (b, vocab_size)
+        logits
+            .into_iter()
+            .map(|logits| {
+                let mut buf = vec![0.0; self.conf.vocab_size];
+                logits.export(&mut buf)?;
+                Ok(buf)
+            })
+            .collect()
+    }
+
+    /// forward the rows through all the layers, returns the final normed hidden states of the
+    /// rows asking for the logits.
+    fn forward_hidden(&mut self, rows: &[Llama2Row]) -> Result<Vec<T>> {
+        let embed_dim = self.conf.embedding_dim;
+        let weights = self.weights.clone();
+
+        // copy the token embeddings into xs
+        let mut xs = rows
+            .iter()
+            .map(|row| {
+                let mut x = T::alloc(&[embed_dim], None, self.device.clone())?;
+                x.copy_from(
+                    &self.weights.token_embedding_table,
+                    &[row.token, 0],
+                    embed_dim,
+                )?;
+                if let Some(rwkv) = &weights.rwkv {
+                    x = rwkv.forward_embedding_norm(x, &self.conf, self.device.clone())?;
+                }
+                match &weights.embed_norm_weight {
+                    Some(weight) => self.forward_norm(x, weight, weights.embed_norm_bias.as_ref()),
+                    None => Ok(x),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // the first layers offloaded to another device take the hidden states in f32
+        let device = self.device.clone();
+        if let Some(offload) = self.kv_cache.offload_mut() {
+            let hs = xs
+                .iter()
+                .map(|x| {
+                    let mut buf = vec![0.0; embed_dim];
+                    x.export(&mut buf)?;
+                    Ok(buf)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            xs = offload
+                .forward_offloaded(rows, hs)?
+                .into_iter()
+                .map(|h| T::from_vec(h, &[embed_dim], device.clone()))
+                .collect::<Result<Vec<_>>>()?;
+        }
+
+        let xs = self.forward_layers(rows, xs)?;
+        let xs = self.apply_control_vector(xs, self.conf.n_layers - 1)?;
+
+        // only the rows asking for the logits take the final rmsnorm
+        xs.into_iter()
+            .zip(rows)
+            .filter(|(_, row)| row.logits)
+            .map(|(x, row)| {
+                let w = &self.weights;
+                let x = self.forward_norm(x, &w.rms_final_weight, w.final_norm_bias.as_ref())?;
+                Ok(x.with_name(format!("final_rmsnorm:{}", row.pos)))
+            })
+            .collect()
+    }
+
+    /// forward the hidden states of the rows through the layers of the runner.
+    pub(crate) fn forward_layers(&mut self, rows: &[Llama2Row], mut xs: Vec<T>) -> Result<Vec<T>> {
         let embed_dim = self.conf.embedding_dim;
         let n_heads = self.conf.n_heads;
         let n_kv_heads = self.conf.n_kv_heads;
         let head_size = self.conf.head_size();
+        let weights = self.weights.clone();
+        let rope = Self::rope(&self.conf, &weights);
+
+        for l in self.layers.clone() {
+            // the control vector steers the output of the layer before
+            if l > 0 {
+                xs = self.apply_control_vector(xs, l - 1)?;
+            }
+
+            // the recurrent layers of rwkv move the state on instead of the kv cache
+            if let Some(rwkv) = &weights.rwkv {
+                let state = &mut self.rwkv_state[l];
+                xs = xs
+                    .into_iter()
+                    .zip(rows)
+                    .map(|(x, row)| {
+                        let x = rwkv.forward_layer(x, l, state, &self.conf, self.device.clone())?;
+                        Ok(x.with_name(format!("rwkv_out:{}:{}", l, row.pos)))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                continue;
+            }
+
+            let xs_attn_orig = xs.iter().map(|x| x.dup()).collect::<Result<Vec<_>>>()?;
+
+            // attention rnsnorm
+            xs = xs
+                .into_iter()
+                .zip(rows)
+                .map(|(x, row)| {
+                    let w = &self.weights;
+                    let x = self.forward_norm(x, &w.rms_att_weight[l], w.att_norm_bias.get(l))?;
+                    Ok(x.with_name(format!("attn_rmsnorm:{}:{}", l, row.pos)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // the ffn takes the same normed input with the attention on the parallel blocks,
+            // or the input normed apart with attn_norm_2 like falcon-40b
+            let w = &self.weights;
+            let xs_ffn_parallel = match (self.conf.parallel_residual, w.rms_ffn_weight.get(l)) {
+                (false, _) => None,
+                (true, Some(weight)) => Some(
+                    xs_attn_orig
+                        .iter()
+                        .map(|x| self.forward_norm(x.dup()?, weight, w.ffn_norm_bias.get(l)))
+                        .collect::<Result<Vec<_>>>()?,
+                ),
+                (true, None) => Some(xs.iter().map(|x| x.dup()).collect::<Result<Vec<_>>>()?),
+            };
+
+            // matmul qkv for every head
+            let (qs, ks, vs) = {
+                // wq: (embed_dim, embed_dim) @ xs (b, embed_dim) => (b, embed_dim)
+                // wk: (kv_dim, embed_dim) @ xs (b, embed_dim) => (b, kv_dim)
+                // wv: (kv_dim, embed_dim) @ xs (b, embed_dim) => (b, kv_dim)
+                // the biases of qwen are added on all the rows at once
+                let w = &self.weights;
+                let (bq, bk, bv) = (w.bq.get(l), w.bk.get(l), w.bv.get(l));
+                let qs = self.linear_rows(&w.wq[l], bq, &xs, l, Llama2LoraTarget::AttnQ)?;
+                let ks = self.linear_rows(&w.wk[l], bk, &xs, l, Llama2LoraTarget::AttnK)?;
+                let vs = self.linear_rows(&w.wv[l], bv, &xs, l, Llama2LoraTarget::AttnV)?;
+                (qs, ks, vs)
+            };
+
+            // the tokens attend to the cache of their sequences one by one, each token is
+            // written into the cache before its attention, so it only sees the tokens before it
+            let mut xs_with_attn = Vec::with_capacity(rows.len());
+            for (row, ((q, k), v)) in rows.iter().zip(qs.into_iter().zip(ks).zip(vs)) {
+                let (seq, pos) = (row.seq, row.pos);
+                let q = q.with_name(format!("q:{}:{}", l, pos));
+                let k = k.with_name(format!("k:{}:{}", l, pos));
+                let v = v.with_name(format!("v:{}:{}", l, pos));
+
+                // ROPE
+                let (q, k) = {
+                    let q = q.reshape(&[n_heads, head_size])?;
+                    let k = k.reshape(&[n_kv_heads, head_size])?;
+
+                    // the positions are taken by the alibi on the attention scores instead,
+                    // like mpt
+                    let (q, k) = match self.conf.alibi_max_bias {
+                        Some(_) => (q, k),
+                        None => (q.rope_inplace(pos, &rope)?, k.rope_inplace(pos, &rope)?),
+                    };
+                    (
+                        q.with_name(format!("q_roped:{}:{}", l, pos)),
+                        k.with_name(format!("k_roped:{}:{}", l, pos)),
+                    )
+                };
+
+                // save to kv cache
+                {
+                    let v = v.reshape(&[n_kv_heads, head_size])?;
+
+                    // the cache rolls over the sliding window once it's full, the order of the
+                    // rows does not matter to the attention since the keys are roped already
+                    let row = pos % self.conf.kv_cache_len();
+                    self.kv_cache.write(seq, l, row, &k, &v)?;
+                };
+
+                // multi query attention
+                let x_with_attn = {
+                    let q = q.reshape(&[n_heads, head_size])?;
+
+                    // the cache is split into the pages, the scores and the values are taken on
+                    // each page of the sequence:
+                    // - key_cache: [seq, n_kv_head, head_size]
+                    // - key_cache = key_cache.transpose(1, 0, 2) => [n_kv_head, seq, head_size]
+                    // - q: [n_head, head_size]
+                    // - attn_score = batch_matmul(key_cache, q) => [n_head, seq]
+                    // - softmax(attn_score, axis=1) => [n_head, seq]
+                    // - val_cache: [seq, n_kv_head, head_size]
+                    // - val_cache = val_cache.transpose(1, 2, 0) => [n_kv_head, head_size, seq]
+                    // - out = batch_matmul(val_cache, atten_scores) => [n_head, head_size]
+                    // every n_head / n_kv_head query heads in a group share a kv head
+
+                    let alibi = self.conf.alibi_max_bias.map(|max_bias| (pos, max_bias));
+                    let x_with_attn = if self.flash_attention {
+                        // the fused attention takes the softmax online on the tiles of the keys
+                        let scale = 1.0 / (head_size as f32).sqrt();
+                        self.kv_cache.attn(seq, l, &q, scale, alibi)?
+                    } else {
+                        // get attention scores
+                        // (n_kv_heads, n_seq, head_size) @ (n_head, head_size) => (n_heads, n_seq)
+                        let attn = self.kv_cache.attn_scores(seq, l, &q)?;
+                        let attn = attn.div_scalar_inplace((head_size as f32).sqrt())?;
+                        let attn = match alibi {
+                            Some((pos, max_bias)) => attn.alibi_inplace(pos, max_bias)?,
+                            None => attn,
+                        };
+                        let attn = attn
+                            .softmax_inplace(1)?
+                            .with_name(format!("k_cache_attn:{}:{}", l, pos));
+
+                        // get the weighted sum of the values and attention scores
+                        // (n_kv_heads, head_size, n_seq) @ (n_heads, n_seq) => (n_heads, head_size)
+                        self.kv_cache.attn_values(seq, l, attn)? // (n_heads, head_size)
+                    };
+                    x_with_attn.reshape(&[embed_dim])?
+                };
+                xs_with_attn.push(x_with_attn);
+            }
+
+            // final matmul to get the output of the attention
+            let (wo, bo) = (&self.weights.wo[l], self.weights.bo.get(l));
+            xs = self.linear_rows(wo, bo, &xs_with_attn, l, Llama2LoraTarget::AttnOutput)?;
+
+            // parallel blocks sum up the outputs of the attention and the ffn on the residual
+            if let Some(xs_ffn) = xs_ffn_parallel {
+                let hs = self.forward_layer_ffn(&xs_ffn, l)?;
+                xs = xs
+                    .into_iter()
+                    .zip(hs.iter().zip(xs_attn_orig.iter()))
+                    .zip(rows)
+                    .map(|((x, (h, x_attn_orig)), row)| {
+                        let x = x.add_inplace(h)?.add_inplace(x_attn_orig)?;
+                        Ok(x.with_name(format!("ffn_out:{}:{}", l, row.pos)))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                continue;
+            }
+
+            // residual connection back into xs
+            xs = xs
+                .into_iter()
+                .zip(xs_attn_orig.iter())
+                .map(|(x, x_attn_orig)| x.add_inplace(x_attn_orig))
+                .collect::<Result<Vec<_>>>()?;
+
+            // ffn
+            xs = {
+                // save for redidual connection
+                let xs_orig_ffn = xs.iter().map(|x| x.dup()).collect::<Result<Vec<_>>>()?;
+
+                // ffn rmsnorm
+                xs = xs
+                    .into_iter()
+                    .map(|x| {
+                        let w = &self.weights;
+                        self.forward_norm(x, &w.rms_ffn_weight[l], w.ffn_norm_bias.get(l))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                xs = self.forward_layer_ffn(&xs, l)?;
+
+                // residual connection
+                xs.into_iter()
+                    .zip(xs_orig_ffn.iter())
+                    .zip(rows)
+                    .map(|((x, x_orig_ffn), row)| {
+                        let x = x.add_inplace(x_orig_ffn)?;
+                        Ok(x.with_name(format!("ffn_out:{}:{}", l, row.pos)))
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            }
+        }
+        Ok(xs)
+    }
+
+    /// drop the `n_discard` tokens after the first `n_keep` ones in the kv cache, the keys of the
+    /// tokens after are rotated back to continue on the position `pos - n_discard`.
+    pub fn shift_context(&mut self, n_keep: usize, n_discard: usize) -> Result<()> {
+        if self.conf.kv_cache_len() < self.conf.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                "the context can not be shifted on the sliding window",
+            )
+                .into());
+        }
+        let weights = self.weights.clone();
+        let rope = Self::rope(&self.conf, &weights);
+        // the positions are taken by the alibi on the attention scores instead, like mpt
+        let rope = match self.conf.alibi_max_bias {
+            Some(_) => None,
+            None => Some(&rope),
+        };
+        self.kv_cache.shift_seq(self.seq, n_keep, n_discard, rope)?;
+        self.tokens.drain(n_keep..n_keep + n_discard);
+        Ok(())
+    }
+
+    /// keep the forwarded tokens which are the prefix of the prompt, so only the rest of the
+    /// prompt is forwarded, returns the number of the kept tokens. the last token of the
+    /// prompt is always forwarded to take the logits.
+    fn reuse_prefix(&mut self, prompt_tokens: &[usize]) -> Result<usize> {
+        let prompt_tokens = &prompt_tokens[..prompt_tokens.len() - 1];
+        let mut n_reused = common_prefix_len(&self.tokens, prompt_tokens);
+
+        // take over a fork of the cached prompt if it shares a longer prefix
+        if let Some(cache) = &mut self.prompt_cache {
+            if let Some((seq, tokens)) = cache.lookup(prompt_tokens) {
+                let n_cached = common_prefix_len(tokens, prompt_tokens);
+                if n_cached > n_reused {
+                    self.tokens = tokens.to_vec();
+                    self.kv_cache.free_seq(self.seq)?;
+                    self.seq = self.kv_cache.fork_seq(seq)?;
+                    n_reused = n_cached;
+                }
+            }
+        }
+
+        // the recurrent state and the rolled over cache can not be rewound
+        match self.truncate(n_reused) {
+            Ok(()) => Ok(n_reused),
+            Err(err) if err.kind == ErrorKind::NotImplemented => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// keep the forwarded tokens in the prompt cache if it's enabled.
+    fn cache_prompt(&mut self) -> Result<()> {
+        // the rolled over cache does not keep the prefix any more
+        if self.tokens.len() > self.conf.kv_cache_len() {
+            return Ok(());
+        }
+        match &mut self.prompt_cache {
+            Some(cache) => cache.insert(&mut self.kv_cache, self.seq, &self.tokens),
+            None => Ok(()),
+        }
+    }
+
+    fn rope<'b>(conf: &Llama2Config, weights: &'b Llama2Weights<T>) -> RopeOptions<'b> {
+        let rope = RopeOptions::new(conf.rope_mode, conf.rope_dim)
+            .with_freq_base(conf.rope_freq_base)
+            .with_freq_scale(conf.rope_freq_scale)
+            .with_scaling(conf.rope_scaling)
+            .with_attn_factor(conf.rope_attn_factor);
+        match weights.rope_freq_factors.is_empty() {
+            true => rope,
+            false => rope.with_freq_factors(Some(&weights.rope_freq_factors)),
+        }
+    }
+
+    /// the rms norm or the layer norm of the arch, scaled by the weight and shifted by the bias.
+    /// the rms norm and the weight are fused into a single kernel by the graph.
+    fn forward_norm(&self, x: T, weight: &T, bias: Option<&T>) -> Result<T> {
+        let x = TensorGraph::new(x);
+        let x = match self.conf.norm {
+            Llama2Norm::Rms => x.rms_norm(self.conf.norm_eps),
+            Llama2Norm::Layer => x.layer_norm(self.conf.norm_eps),
+        };
+        let x = x.mul(weight);
+        match bias {
+            Some(bias) => x.add(bias).eval(),
+            None => x.eval(),
+        }
+    }
+
+    /// the weight @ each of the xs plus the bias, the xs are stacked into a single matmul if
+    /// there're more than one, which loads the weight once for all of them. the bias of (m, )
+    /// is broadcasted onto all the rows of the output of (b, m) at once. the xs are converted
+    /// into the activation dtype before the matmul.
+    fn matmul_rows(&self, w: &T, bias: Option<&T>, xs: &[T]) -> Result<Vec<T>> {
+        if xs.len() == 1 {
+            let out = match self.activation_dtype {
+                GGMLType::F32 => w.matmul_vec(&xs[0])?,
+                dtype => w.matmul_vec(&xs[0].to_dtype(dtype)?)?,
+            };
+            return Ok(vec![match bias {
+                Some(bias) => out.add_inplace(bias)?,
+                None => out,
+            }]);
+        }
+
+        // (m, k) @ (b, k) => (b, m)
+        let k = xs[0].strider().shape()[0];
+        let mut x = T::alloc(&[0, k], Some(xs.len() * k), self.device.clone())?;
+        for row in xs {
+            x.extend(row)?;
+        }
+        if self.activation_dtype != GGMLType::F32 {
+            x = x.to_dtype(self.activation_dtype)?;
+        }
+        let out = match bias {
+            Some(bias) => w.matmul(&x)?.add_inplace(bias)?,
+            None => w.matmul(&x)?,
+        };
+        let m = out.strider().shape()[1];
+        (0..xs.len())
+            .map(|i| {
+                let mut row = T::alloc(&[m], None, self.device.clone())?;
+                row.copy_from(&out, &[i, 0], m)?;
+                Ok(row)
+            })
+            .collect()
+    }
 
-        // copy the token embedding into x
-        let mut x = T::alloc(&[embed_dim], None, self.device.clone())?;
-        x.copy_from(&self.weights.token_embedding_table, &[token, 0], embed_dim)?;
+    /// add the direction of the control vector on the layer to each of the xs.
+    fn apply_control_vector(&self, xs: Vec<T>, l: usize) -> Result<Vec<T>> {
+        match self.control_vector.get(l) {
+            Some(Some(direction)) => add_rows(xs, direction),
+            _ => Ok(xs),
+        }
+    }
+
+    /// the weight @ each of the xs plus the bias like `matmul_rows`, plus the low rank deltas
+    /// of the active lora adapters on the weight: w @ x + bias + scale * b @ (a @ x).
+    fn linear_rows(
+        &self,
+        w: &T,
+        bias: Option<&T>,
+        xs: &[T],
+        l: usize,
+        target: Llama2LoraTarget,
+    ) -> Result<Vec<T>> {
+        let mut ys = self.matmul_rows(w, bias, xs)?;
+        for (_, adapter, scale) in self.active_loras.iter() {
+            let Some(lora) = adapter.get(l, target) else {
+                continue;
+            };
+            let scale = adapter.scale(*scale, lora.a.strider().shape()[0]);
+            if scale == 0.0 {
+                continue;
+            }
+            let hs = self.matmul_rows(&lora.a, None, xs)?;
+            let ds = self.matmul_rows(&lora.b, None, &hs)?;
+            ys = ys
+                .into_iter()
+                .zip(ds)
+                .map(|(y, d)| y.add_inplace(&d.div_scalar_inplace(1.0 / scale)?))
+                .collect::<Result<Vec<_>>>()?;
+        }
+        Ok(ys)
+    }
+
+    fn forward_layer_ffn(&self, xs: &[T], l: usize) -> Result<Vec<T>> {
+        let w = &self.weights;
+        if self.conf.n_experts > 0 {
+            xs.iter().map(|x| self.forward_moe(x, l)).collect()
+        } else if w.w1.is_empty() {
+            self.forward_gelu_ffn(xs, l)
+        } else {
+            self.forward_ffn(xs, &w.w1[l], &w.w2[l], &w.w3[l], Some(l))
+        }
+    }
+
+    /// the mlp without the gate like phi2: self.w2(F.gelu(self.w3(x) + b3)) + b2
+    fn forward_gelu_ffn(&self, xs: &[T], l: usize) -> Result<Vec<T>> {
+        let w = &self.weights;
+        let hs = self.linear_rows(&w.w3[l], w.b3.get(l), xs, l, Llama2LoraTarget::FfnUp)?;
+        let hs = hs
+            .into_iter()
+            .map(|h| h.gelu_inplace())
+            .collect::<Result<Vec<_>>>()?;
+
+        self.linear_rows(&w.w2[l], w.b2.get(l), &hs, l, Llama2LoraTarget::FfnDown)
+    }
+
+    /// the lora adapters apply on the layer `l`, which is None on the experts.
+    fn forward_ffn(&self, xs: &[T], w1: &T, w2: &T, w3: &T, l: Option<usize>) -> Result<Vec<T>> {
+        let linear = |w: &T, xs: &[T], target: Llama2LoraTarget| match l {
+            Some(l) => self.linear_rows(w, None, xs, l, target),
+            None => self.matmul_rows(w, None, xs),
+        };
+        // Now for FFN in PyTorch we have: self.w2(F.silu(self.w1(x)) * self.w3(x))
+        // first calculate self.w1(x) and self.w3(x)
+        // w1: (hidden_dim, embed_dim) @ xs (b, embed_dim) => (b, hidden_dim)
+        // w3: (hidden_dim, embed_dim) @ xs (b, embed_dim) => (b, hidden_dim)
+        let h1s = linear(w1, xs, Llama2LoraTarget::FfnGate)?;
+        let h2s = linear(w3, xs, Llama2LoraTarget::FfnUp)?;
+
+        // F.silu; silu(x)=x*σ(x),where σ(x) is the logistic sigmoid
+        // elementwise multiply with w3(x), fused into a single kernel
+        let h1s = h1s
+            .into_iter()
+            .zip(h2s.iter())
+            .map(|(h1, h2)| TensorGraph::new(h1).silu().mul(h2).eval())
+            .collect::<Result<Vec<_>>>()?;
+
+        // final matmul to get the output of the ffn
+        linear(w2, &h1s, Llama2LoraTarget::FfnDown)
+    }
+
+    /// route the token to the top k experts by the gate, and sum up the outputs of the experts
+    /// weighted by the softmax of their gate logits, like mixtral.
+    fn forward_moe(&self, x: &T, l: usize) -> Result<T> {
+        // ffn_gate_inp: (n_experts, embed_dim) @ x (embed_dim, ) => (n_experts, )
+        let mut gate_logits = vec![0.0; self.conf.n_experts];
+        self.weights.ffn_gate_inp[l]
+            .matmul_vec(x)?
+            .export(&mut gate_logits)?;
+
+        let mut experts = (0..self.conf.n_experts).collect::<Vec<_>>();
+        experts.sort_by(|a, b| gate_logits[*b].total_cmp(&gate_logits[*a]));
+        experts.truncate(self.conf.n_experts_used.max(1));
+
+        let max_logit = gate_logits[experts[0]];
+        let scores = experts
+            .iter()
+            .map(|e| (gate_logits[*e] - max_logit).exp())
+            .collect::<Vec<_>>();
+        let sum = scores.iter().sum::<f32>();
+
+        let mut out: Option<T> = None;
+        for (e, score) in experts.iter().zip(scores.iter()) {
+            let w = &self.weights;
+            let h = self
+                .forward_ffn(
+                    std::slice::from_ref(x),
+                    &w.w1_exps[l][*e],
+                    &w.w2_exps[l][*e],
+                    &w.w3_exps[l][*e],
+                    None,
+                )?
+                .pop()
+                .unwrap();
+            let h = h.div_scalar_inplace(sum / score)?;
+            out = Some(match out {
+                Some(out) => out.add_inplace(&h)?,
+                None => h,
+            });
+        }
+        Ok(out.unwrap())
+    }
+}
+
+pub struct Llama2RunnerOutputGenerator<'a, T: Tensor> {
+    pos: usize,
+    steps: usize,
+    seq_len: usize,
+    // the tokens kept on shifting the context, it stops at `seq_len` if None
+    n_keep: Option<usize>,
+    // the tokens dropped by shifting the context
+    n_shifted: usize,
+    prompt_tokens: Vec<usize>,
+    token: usize,
+    sampler: &'a mut Llama2Sampler,
+    runner: &'a mut Llama2Runner<T>,
+    stats: Llama2GenerationStats,
+    stop_sequences: Vec<String>,
+    special_mode: BpeSpecialMode,
+    // buffers the bytes of the characters split across the tokens
+    decoder: BpeStreamDecoder,
+    // the tokens held back since they may be the beginning of a stop sequence
+    pending: VecDeque<Llama2GeneratedToken>,
+    // the tokens known to be not a part of any stop sequence
+    ready: VecDeque<Llama2GeneratedToken>,
+    stopped: bool,
+    guidance: Option<Llama2Guidance>,
+    abort: Option<Llama2AbortHandle>,
+    // the start time and the wall-clock timeout of the generation
+    timeout: Option<(Instant, Duration)>,
+    max_tokens: Option<usize>,
+    // the reason of stopping, which is the finish reason once the ready tokens are taken
+    stop_reason: Option<Llama2FinishReason>,
+    finish_reason: Option<Llama2FinishReason>,
+}
+
+/// why the generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Llama2FinishReason {
+    /// the model generated the eos token, or a stop sequence or a grammar is matched.
+    Stop,
+    /// the max new tokens are generated, or the context is full.
+    Length,
+    /// the timeout of the generation is reached.
+    Timeout,
+    /// the generation is aborted by its `Llama2AbortHandle`.
+    Cancelled,
+}
+
+impl Llama2FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Llama2FinishReason::Stop => "stop",
+            Llama2FinishReason::Length => "length",
+            Llama2FinishReason::Timeout => "timeout",
+            Llama2FinishReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// the counts and the timings of a generation.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Llama2GenerationStats {
+    /// the tokens of the prompt, including the ones reused from the kv cache.
+    pub n_prompt_tokens: usize,
+    /// the prompt tokens reused from the kv cache, which are not forwarded again.
+    pub n_cached_tokens: usize,
+    /// the generated tokens, which may be more than the emitted ones on a stop sequence.
+    pub n_eval_tokens: usize,
+    /// the time of forwarding the prompt until the first token is sampled.
+    pub prompt_time: Duration,
+    /// the time of generating the tokens after the first one.
+    pub eval_time: Duration,
+}
+
+impl Llama2GenerationStats {
+    pub fn total_time(&self) -> Duration {
+        self.prompt_time + self.eval_time
+    }
+
+    /// the prompt tokens forwarded per second, not counting the cached ones.
+    pub fn prompt_tokens_per_second(&self) -> f32 {
+        let n_tokens = self.n_prompt_tokens - self.n_cached_tokens;
+        n_tokens as f32 / self.prompt_time.as_secs_f32()
+    }
+
+    /// the tokens generated per second after the first one.
+    pub fn eval_tokens_per_second(&self) -> f32 {
+        let n_tokens = self.n_eval_tokens.saturating_sub(1);
+        n_tokens as f32 / self.eval_time.as_secs_f32()
+    }
+}
+
+/// the result of a whole generation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2GenerationOutput {
+    pub text: String,
+    /// the ids of the emitted tokens.
+    pub tokens: Vec<usize>,
+    pub finish_reason: Llama2FinishReason,
+    pub stats: Llama2GenerationStats,
+}
+
+/// the context of the negative prompt on the classifier-free guidance, which takes a sequence
+/// of its own in the kv cache, and continues with the generated tokens.
+struct Llama2Guidance {
+    seq: usize,
+    pos: usize,
+    token: usize, // the next token to forward
+    scale: f32,
+}
+
+/// a generated token, with the text decoded as it's emitted in the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2GeneratedToken {
+    pub token: usize,
+    /// the text of the last token is truncated before the stop sequence on stopping. the
+    /// text is empty if the token ends in the middle of a multi-byte character, the whole
+    /// character is emitted with the token completing it.
+    pub text: String,
+    /// the position of the token in the sequence, counting the prompt tokens and the tokens
+    /// dropped by shifting the context.
+    pub pos: usize,
+    /// the time spent on generating the token, which includes the time of processing the
+    /// prompt on the first token.
+    pub elapsed: Duration,
+    /// only available if the sampler is built with `Llama2Sampler::with_logprobs`.
+    pub logprobs: Option<Llama2TokenLogprobs>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2TokenLogprob {
+    pub token: usize,
+    /// the decoded text of the token, the same as it's emitted in the output.
+    pub text: String,
+    pub logprob: f32,
+}
+
+/// the log probabilities of an emitted token, and the most likely tokens on the position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2TokenLogprobs {
+    pub token: Llama2TokenLogprob,
+    pub top: Vec<Llama2TokenLogprob>,
+}
 
-        // forward all the layers
-        for l in 0..self.conf.n_layers {
-            let x_attn_orig = x.dup()?;
+impl Llama2TokenLogprobs {
+    /// decode the tokens of the logprobs taken from the sampler, which follow `prev_token`.
+    pub(crate) fn decode(
+        tokenizer: &BpeTokenizer,
+        prev_token: usize,
+        logprobs: Llama2SamplerLogprobs,
+        special_mode: BpeSpecialMode,
+    ) -> Result<Self> {
+        let token_logprob = |(token, logprob)| -> Result<Llama2TokenLogprob> {
+            Ok(Llama2TokenLogprob {
+                token,
+                text: tokenizer.decode_with(prev_token, token, special_mode)?,
+                logprob,
+            })
+        };
+        Ok(Self {
+            token: token_logprob((logprobs.token, logprobs.logprob))?,
+            top: logprobs
+                .top
+                .into_iter()
+                .map(token_logprob)
+                .collect::<Result<Vec<_>>>()?,
+        })
+    }
+}
+
+impl<'a, T: Tensor> Llama2RunnerOutputGenerator<'a, T> {
+    fn new(
+        runner: &'a mut Llama2Runner<T>,
+        sampler: &'a mut Llama2Sampler,
+        prompt: &str,
+        steps: usize,
+        seq_len: usize,
+    ) -> Result<Self> {
+        let tokenizer = &runner.tokenizer;
+        let prompt_tokens = tokenizer.encode_special(
+            prompt,
+            tokenizer.add_bos_token(),
+            tokenizer.add_eos_token(),
+        )?;
+        if prompt_tokens.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: "something is wrong, expected at least 1 prompt token".to_string(),
+                cause: None,
+            });
+        }
+
+        sampler.reset(&prompt_tokens);
+
+        // the prompt tokens already on the kv cache are not forwarded again
+        let pos = runner.reuse_prefix(&prompt_tokens)?;
+        let token = prompt_tokens[pos];
+        Ok(Self {
+            pos,
+            steps,
+            token,
+            stats: Llama2GenerationStats {
+                n_prompt_tokens: prompt_tokens.len(),
+                n_cached_tokens: pos,
+                ..Default::default()
+            },
+            prompt_tokens,
+            sampler,
+            runner,
+            seq_len,
+            n_keep: None,
+            n_shifted: 0,
+            stop_sequences: vec![],
+            special_mode: BpeSpecialMode::Skip,
+            decoder: BpeStreamDecoder::new(BpeSpecialMode::Skip),
+            pending: VecDeque::new(),
+            ready: VecDeque::new(),
+            stopped: false,
+            guidance: None,
+            abort: None,
+            timeout: None,
+            max_tokens: None,
+            stop_reason: None,
+            finish_reason: None,
+        })
+    }
+
+    /// stop the generation once any of the sequences appears in the output, the stop
+    /// sequence itself is not emitted. the tokens which may be the beginning of a stop
+    /// sequence are held back until they're known not to match.
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect();
+        self
+    }
+
+    /// whether the generated special tokens like `<|im_end|>` are skipped in the texts,
+    /// they're skipped by default.
+    pub fn with_special_mode(mut self, special_mode: BpeSpecialMode) -> Self {
+        self.special_mode = special_mode;
+        self.decoder = BpeStreamDecoder::new(special_mode);
+        self
+    }
+
+    /// drop the half of the tokens after the first `n_keep` ones once the context is full, and
+    /// continue the generation on the rest instead of stopping at `seq_len`. the first tokens
+    /// are kept as the attention sink of StreamingLLM.
+    pub fn with_context_shift(mut self, n_keep: usize) -> Self {
+        self.n_keep = Some(n_keep);
+        self
+    }
+
+    /// stop the generation once the handle is aborted, which is checked before forwarding
+    /// each token and each batch of the prompt. the held back tokens are dropped, and the kv
+    /// cache of the generation is released, the prompt is still kept in the prompt cache if
+    /// it's enabled.
+    pub fn with_abort_handle(mut self, abort: Llama2AbortHandle) -> Self {
+        self.abort = Some(abort);
+        self
+    }
+
+    /// stop the generation once `max_tokens` new tokens are generated. unlike the steps of
+    /// `generate`, which take one more token after the prompt, it's the exact number of the
+    /// tokens, like the max tokens of a request.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// stop the generation once it takes longer than the timeout since now, which is checked
+    /// like the abort handle.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some((Instant::now(), timeout));
+        self
+    }
+
+    /// sample with the classifier-free guidance: the logits are pushed away from the ones
+    /// on the negative prompt by `logits_neg + scale * (logits - logits_neg)` in the log
+    /// space, which takes a second pass on each token. the scale 1.0 takes no guidance, and
+    /// the larger scale follows the prompt more strictly. the guidance stops once the context
+    /// of the negative prompt is full.
+    pub fn with_guidance(mut self, negative_prompt: &str, scale: f32) -> Result<Self> {
+        if !self.runner.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the guidance on the recurrent state is not supported yet",
+            )
+                .into());
+        }
+        let tokenizer = &self.runner.tokenizer;
+        let tokens = tokenizer.encode_special(
+            negative_prompt,
+            tokenizer.add_bos_token(),
+            tokenizer.add_eos_token(),
+        )?;
+        if tokens.is_empty() || tokens.len() > self.runner.conf.kv_cache_len() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected 1 to {} tokens in the negative prompt, got {}",
+                    self.runner.conf.kv_cache_len(),
+                    tokens.len()
+                ),
+            )
+                .into());
+        }
+
+        // the sequence is kept in the runner and reused across the generations
+        let seq = match self.runner.guidance_seq {
+            Some(seq) => seq,
+            None => self.runner.kv_cache.alloc_seq(),
+        };
+        self.runner.guidance_seq = Some(seq);
+        self.runner.kv_cache.clear_seq(seq)?;
+
+        // the last token is forwarded along with the first sampled token
+        let n = tokens.len() - 1;
+        self.guidance = Some(Llama2Guidance {
+            seq,
+            pos: n,
+            token: tokens[n],
+            scale,
+        });
+        for (i, batch) in tokens[..n].chunks(self.runner.batch_size).enumerate() {
+            let rows = batch
+                .iter()
+                .enumerate()
+                .map(|(j, token)| Llama2Row {
+                    seq,
+                    token: *token,
+                    pos: i * self.runner.batch_size + j,
+                    logits: false,
+                })
+                .collect::<Vec<_>>();
+            self.runner.forward_rows(&rows)?;
+        }
+        Ok(self)
+    }
+
+    /// the counts and the timings of the generation so far.
+    pub fn stats(&self) -> Llama2GenerationStats {
+        self.stats
+    }
+
+    /// generate until the end, and collect the text, the tokens, the finish reason and the
+    /// stats of the generation.
+    pub fn complete(mut self) -> Result<Llama2GenerationOutput> {
+        let mut text = String::new();
+        let mut tokens = vec![];
+        while let Some(token) = self.next_token()? {
+            text.push_str(&token.text);
+            tokens.push(token.token);
+        }
+        Ok(Llama2GenerationOutput {
+            text,
+            tokens,
+            finish_reason: self.finish_reason.unwrap_or(Llama2FinishReason::Stop),
+            stats: self.stats,
+        })
+    }
+
+    /// the reason of the end of the generation, None until `next_token` returns None.
+    pub fn finish_reason(&self) -> Option<Llama2FinishReason> {
+        self.finish_reason
+    }
+
+    pub fn average_tokens_per_seconds(&self) -> f32 {
+        let total_time = self.stats.total_time().as_secs_f32();
+        (self.pos + self.n_shifted) as f32 / total_time
+    }
+
+    /// iterate the generated tokens with the token ids and the timings. unlike iterating
+    /// the generator itself, the tokens decoded into empty texts are kept.
+    pub fn tokens(&mut self) -> Llama2GeneratedTokens<'_, 'a, T> {
+        Llama2GeneratedTokens { generator: self }
+    }
+
+    /// generate the next token, returns None on the end of the generation.
+    pub fn next_token(&mut self) -> Result<Option<Llama2GeneratedToken>> {
+        loop {
+            if let Some(token) = self.ready.pop_front() {
+                return Ok(Some(token));
+            }
+            if self.stopped {
+                self.finish_reason = self.stop_reason;
+                return Ok(None);
+            }
+            match self.forward_next()? {
+                Some(token) if self.stop_sequences.is_empty() => return Ok(Some(token)),
+                Some(token) => self.match_stop_sequences(token),
+                // drop the held back tokens and the kv cache of the aborted generation
+                None if self.stop_reason == Some(Llama2FinishReason::Cancelled) => {
+                    self.stopped = true;
+                    self.pending.clear();
+                    self.release_guidance()?;
+                    self.runner.truncate(0)?;
+                }
+                // flush the held back tokens on the end of the generation
+                None => {
+                    self.stopped = true;
+                    self.ready.append(&mut self.pending);
+                    self.release_guidance()?;
+                }
+            }
+        }
+    }
+
+    /// the reason to stop before forwarding the next token, if any.
+    fn check_finish(&self, n_tokens: usize) -> Option<Llama2FinishReason> {
+        if self.abort.as_ref().is_some_and(|abort| abort.is_aborted()) {
+            return Some(Llama2FinishReason::Cancelled);
+        }
+        if let Some((start_time, timeout)) = self.timeout {
+            if start_time.elapsed() >= timeout {
+                return Some(Llama2FinishReason::Timeout);
+            }
+        }
+        if n_tokens >= self.steps + self.prompt_tokens.len()
+            || self
+                .max_tokens
+                .is_some_and(|n| self.stats.n_eval_tokens >= n)
+        {
+            return Some(Llama2FinishReason::Length);
+        }
+        if self.pos >= self.seq_len && self.n_keep.is_none() {
+            return Some(Llama2FinishReason::Length);
+        }
+        None
+    }
+
+    fn finish(&mut self, reason: Llama2FinishReason) -> Result<Option<Llama2GeneratedToken>> {
+        self.stop_reason = Some(reason);
+        Ok(None)
+    }
+
+    fn forward_next(&mut self) -> Result<Option<Llama2GeneratedToken>> {
+        let start_time = Instant::now();
+        loop {
+            let n_tokens = self.pos + self.n_shifted;
+            if let Some(reason) = self.check_finish(n_tokens) {
+                return self.finish(reason);
+            }
+            if let (true, Some(n_keep)) = (self.pos >= self.seq_len, self.n_keep) {
+                self.shift_context(n_keep)?;
+            }
+
+            // forward the prompt tokens in batches, except the last one which takes the logits
+            // to sample from
+            let step_time = Instant::now();
+            let n_prompt = self.prompt_tokens.len() - 1;
+            if n_tokens < n_prompt {
+                let n = (n_prompt - n_tokens)
+                    .min(self.runner.batch_size)
+                    .min(self.seq_len - self.pos);
+
+                // the BOS token delimits sequences, stop after forwarding the token before it
+                let tokenizer = &self.runner.tokenizer;
+                let next_tokens = &self.prompt_tokens[n_tokens + 1..=n_tokens + n];
+                let n_stop = next_tokens
+                    .iter()
+                    .position(|t| *t == tokenizer.bos_token() || *t == tokenizer.eos_token());
+
+                let batch = &self.prompt_tokens[n_tokens..n_tokens + n_stop.map_or(n, |i| i + 1)];
+                let _span = tracing::debug_span!("prefill", pos = self.pos, n_tokens = batch.len())
+                    .entered();
+                self.runner.forward_batch(batch, self.pos)?;
+                self.stats.prompt_time.add_assign(step_time.elapsed());
+                if n_stop.is_some() {
+                    return self.finish(Llama2FinishReason::Stop);
+                }
+                self.pos += n;
+                self.token = self.prompt_tokens[n_tokens + n];
+                continue;
+            }
+
+            // forward the transformer to get logits for the next token
+            let decode_span = tracing::trace_span!("decode", pos = self.pos).entered();
+            self.runner.forward(self.token, self.pos)?;
+            if n_tokens == self.prompt_tokens.len() - 1 {
+                self.runner.cache_prompt()?;
+            }
+            self.guide_logits()?;
+            let logits = &mut self.runner.logits;
+
+            // sample the next token from the logits
+            let next_token = self.sampler.sample(logits)?;
+            self.sampler.accept(next_token);
+            if let Some(guidance) = &mut self.guidance {
+                guidance.token = next_token;
+            }
+            drop(decode_span);
+            // the first token is sampled on the last prompt token
+            match n_tokens == self.prompt_tokens.len() - 1 {
+                true => self.stats.prompt_time.add_assign(step_time.elapsed()),
+                false => self.stats.eval_time.add_assign(step_time.elapsed()),
+            }
+
+            // data-dependent terminating condition: the BOS token delimits sequences
+            let tokenizer = &self.runner.tokenizer;
+            if next_token == tokenizer.bos_token() || next_token == tokenizer.eos_token() {
+                return self.finish(Llama2FinishReason::Stop);
+            }
+
+            let prev_token = self.token;
+            self.pos += 1;
+            self.stats.n_eval_tokens += 1;
+            self.token = next_token;
+
+            let logprobs = self
+                .sampler
+                .take_logprobs()
+                .map(|logprobs| {
+                    Llama2TokenLogprobs::decode(
+                        &self.runner.tokenizer,
+                        prev_token,
+                        logprobs,
+                        self.special_mode,
+                    )
+                })
+                .transpose()?;
+            return Ok(Some(Llama2GeneratedToken {
+                token: next_token,
+                text: self
+                    .decoder
+                    .decode(&self.runner.tokenizer, prev_token, next_token)?,
+                pos: self.pos + self.n_shifted,
+                elapsed: start_time.elapsed(),
+                logprobs,
+            }));
+        }
+    }
+
+    /// forward the last token on the negative prompt, and combine its logits into the logits
+    /// of the runner.
+    fn guide_logits(&mut self) -> Result<()> {
+        let Some(guidance) = &mut self.guidance else {
+            return Ok(());
+        };
+        if guidance.pos >= self.runner.conf.kv_cache_len() {
+            return self.release_guidance();
+        }
+
+        let row = Llama2Row {
+            seq: guidance.seq,
+            token: guidance.token,
+            pos: guidance.pos,
+            logits: true,
+        };
+        let mut logits_neg = self.runner.forward_rows(&[row])?.pop().unwrap();
+        guidance.pos += 1;
+        let logits = &mut self.runner.logits;
+        log_softmax(logits);
+        log_softmax(&mut logits_neg);
+        for (l, n) in logits.iter_mut().zip(logits_neg.iter()) {
+            *l = n + guidance.scale * (*l - n);
+        }
+        Ok(())
+    }
+
+    /// stop the guidance and release the kv cache of the negative prompt.
+    fn release_guidance(&mut self) -> Result<()> {
+        match self.guidance.take() {
+            Some(guidance) => self.runner.kv_cache.clear_seq(guidance.seq),
+            None => Ok(()),
+        }
+    }
+
+    fn shift_context(&mut self, n_keep: usize) -> Result<()> {
+        let n_discard = self.seq_len.saturating_sub(n_keep) / 2;
+        if n_discard == 0 {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "can not shift the context of {} tokens keeping {} tokens",
+                    self.seq_len, n_keep
+                ),
+            )
+                .into());
+        }
+        self.runner.shift_context(n_keep, n_discard)?;
+        self.pos -= n_discard;
+        self.n_shifted += n_discard;
+        Ok(())
+    }
+
+    /// append the token to the pending tokens, and move the tokens which are safe to emit
+    /// into the ready tokens.
+    fn match_stop_sequences(&mut self, token: Llama2GeneratedToken) {
+        self.pending.push_back(token);
+        let text = self
+            .pending
+            .iter()
+            .map(|t| t.text.as_str())
+            .collect::<String>();
+
+        let stop_pos = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| text.find(stop.as_str()))
+            .min();
+        if let Some(stop_pos) = stop_pos {
+            self.stopped = true;
+            self.stop_reason = Some(Llama2FinishReason::Stop);
+            let mut offset = 0;
+            while let Some(mut token) = self.pending.pop_front() {
+                if offset >= stop_pos {
+                    break;
+                }
+                let end = offset + token.text.len();
+                if end > stop_pos {
+                    token.text.truncate(stop_pos - offset);
+                }
+                offset = end;
+                self.ready.push_back(token);
+            }
+            self.pending.clear();
+            return;
+        }
+
+        // hold back the tokens of the longest suffix which is a prefix of any stop sequence
+        let keep_pos = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|i| {
+                let suffix = &text[*i..];
+                self.stop_sequences
+                    .iter()
+                    .any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(text.len());
+        let mut offset = 0;
+        while let Some(token) = self.pending.front() {
+            offset += token.text.len();
+            if offset > keep_pos {
+                break;
+            }
+            let token = self.pending.pop_front().unwrap();
+            self.ready.push_back(token);
+        }
+    }
+}
+
+pub struct Llama2GeneratedTokens<'g, 'a, T: Tensor> {
+    generator: &'g mut Llama2RunnerOutputGenerator<'a, T>,
+}
+
+impl<'g, 'a, T: Tensor> Iterator for Llama2GeneratedTokens<'g, 'a, T> {
+    type Item = Result<Llama2GeneratedToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.generator.next_token().transpose()
+    }
+}
+
+impl<'a, T: Tensor> Iterator for Llama2RunnerOutputGenerator<'a, T> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let token = match self.next_token().transpose()? {
+                Ok(token) => token,
+                Err(err) => return Some(Err(err)),
+            };
+            if token.text.is_empty() {
+                continue;
+            }
+            return Some(Ok(token.text));
+        }
+    }
+}
+
+/// the options of the beam search, which keeps the `beam_width` most likely sequences on
+/// each step instead of sampling a single token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Llama2BeamSearchOptions {
+    pub beam_width: usize,
+    /// the score of a sequence is `logprob / len ^ length_penalty`, a larger penalty
+    /// prefers the longer sequences, 0.0 means no normalization on the length.
+    pub length_penalty: f32,
+    /// the number of the best completions to return.
+    pub n_best: usize,
+}
+
+impl Default for Llama2BeamSearchOptions {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            length_penalty: 1.0,
+            n_best: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2BeamSearchOutput {
+    pub text: String,
+    pub tokens: Vec<usize>,
+    /// the sum of the log probabilities of the generated tokens.
+    pub logprob: f32,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Llama2EmbeddingOptions {
+    pub pooling: Llama2Pooling,
+    /// scale the embedding to the unit length.
+    pub normalize: bool,
+}
+
+impl Default for Llama2EmbeddingOptions {
+    fn default() -> Self {
+        Self {
+            // the last token is the only one attending to the whole text on the decoders
+            pooling: Llama2Pooling::Last,
+            normalize: true,
+        }
+    }
+}
+
+struct Llama2Beam {
+    tokens: Vec<usize>,
+    logprob: f32,
+    logits: Vec<f32>,
+    // the sequence in the kv cache after forwarding the tokens, None if the beam is finished
+    seq: Option<usize>,
+}
+
+impl Llama2Beam {
+    fn score(&self, length_penalty: f32) -> f32 {
+        beam_score(self.logprob, self.tokens.len(), length_penalty)
+    }
+}
+
+/// a token forwarded on a sequence of the kv cache at the position.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Llama2Row {
+    pub seq: usize,
+    pub token: usize,
+    pub pos: usize,
+    /// whether to take the logits of the row, or its final hidden state on the embeddings.
+    pub logits: bool,
+}
+
+/// add the bias to each of the rows.
+fn add_rows<T: Tensor>(xs: Vec<T>, bias: &T) -> Result<Vec<T>> {
+    xs.into_iter().map(|x| x.add_inplace(bias)).collect()
+}
+
+fn common_prefix_len(a: &[usize], b: &[usize]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+fn beam_score(logprob: f32, len: usize, length_penalty: f32) -> f32 {
+    logprob / (len.max(1) as f32).powf(length_penalty)
+}
+
+impl<T: Tensor> Llama2Runner<T> {
+    /// generate with the beam search, returns the `n_best` completions ordered by the
+    /// score. each beam takes a sequence in the kv cache, the beams share the pages of the
+    /// common prefix, and a shared page is copied once a beam writes on it.
+    pub fn beam_search(
+        &mut self,
+        prompt: &str,
+        steps: usize,
+        options: &Llama2BeamSearchOptions,
+    ) -> Result<Vec<Llama2BeamSearchOutput>> {
+        if options.beam_width == 0 {
+            return Err((ErrorKind::BadInput, "the beam width should be positive").into());
+        }
+        if !self.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the beam search on the recurrent state is not supported yet",
+            )
+                .into());
+        }
+        let prompt_tokens = self.tokenizer.encode_special(
+            prompt,
+            self.tokenizer.add_bos_token(),
+            self.tokenizer.add_eos_token(),
+        )?;
+        if prompt_tokens.is_empty() {
+            return Err((
+                ErrorKind::BadInput,
+                "something is wrong, expected at least 1 prompt token",
+            )
+                .into());
+        }
+        let end_tokens = [self.tokenizer.bos_token(), self.tokenizer.eos_token()];
+        let start_pos = prompt_tokens.len();
+
+        let mut logits = vec![];
+        for (i, batch) in prompt_tokens.chunks(self.batch_size).enumerate() {
+            logits = self.forward_batch(batch, i * self.batch_size)?.to_vec();
+        }
+        let mut beams = vec![Llama2Beam {
+            tokens: vec![],
+            logprob: 0.0,
+            logits,
+            seq: Some(self.seq),
+        }];
+
+        for _ in 0..steps {
+            if beams.iter().all(|beam| beam.seq.is_none()) {
+                break;
+            }
+
+            // (score, beam, token, logprob), the finished beams are kept as they are
+            let mut candidates = vec![];
+            for (i, beam) in beams.iter_mut().enumerate() {
+                if beam.seq.is_none() {
+                    let score = beam.score(options.length_penalty);
+                    candidates.push((score, i, None, beam.logprob));
+                    continue;
+                }
+                softmax(&mut beam.logits);
+                let mut probs = beam.logits.iter().copied().enumerate().collect::<Vec<_>>();
+                let k = options.beam_width.min(probs.len());
+                probs.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+                for (token, prob) in probs[..k].iter() {
+                    let logprob = beam.logprob + prob.ln();
+                    let score = beam_score(logprob, beam.tokens.len() + 1, options.length_penalty);
+                    candidates.push((score, i, Some(*token), logprob));
+                }
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+            candidates.truncate(options.beam_width);
+
+            // the last child of a beam takes over its sequence, the others fork it
+            let mut children = vec![0; beams.len()];
+            for (_, i, _, _) in candidates.iter() {
+                children[*i] += 1;
+            }
+            let mut new_beams = Vec::with_capacity(candidates.len());
+            for (_, i, token, logprob) in candidates {
+                let parent = &mut beams[i];
+                let Some(token) = token else {
+                    new_beams.push(Llama2Beam {
+                        tokens: parent.tokens.clone(),
+                        logprob,
+                        logits: vec![],
+                        seq: None,
+                    });
+                    continue;
+                };
+
+                let pos = start_pos + parent.tokens.len();
+                let mut tokens = parent.tokens.clone();
+                children[i] -= 1;
+                if end_tokens.contains(&token) {
+                    new_beams.push(Llama2Beam {
+                        tokens,
+                        logprob,
+                        logits: vec![],
+                        seq: None,
+                    });
+                    continue;
+                }
+                tokens.push(token);
+                if pos >= self.conf.seq_len {
+                    new_beams.push(Llama2Beam {
+                        tokens,
+                        logprob,
+                        logits: vec![],
+                        seq: None,
+                    });
+                    continue;
+                }
+
+                let seq = match children[i] {
+                    0 => parent.seq.take().unwrap(),
+                    _ => self.kv_cache.fork_seq(parent.seq.unwrap())?,
+                };
+                self.seq = seq;
+                let logits = self.forward(token, pos)?.to_vec();
+                new_beams.push(Llama2Beam {
+                    tokens,
+                    logprob,
+                    logits,
+                    seq: Some(seq),
+                });
+            }
+            // the sequences of the finished beams are not used any more
+            for beam in beams.iter_mut() {
+                if let Some(seq) = beam.seq.take() {
+                    self.kv_cache.free_seq(seq)?;
+                }
+            }
+            beams = new_beams;
+        }
+
+        beams.sort_by(|a, b| {
+            b.score(options.length_penalty)
+                .total_cmp(&a.score(options.length_penalty))
+        });
+        // leave the sequence of the best beam in the runner, or a new one if it's finished
+        (self.seq, self.tokens) = match beams[0].seq.take() {
+            Some(seq) => (seq, [&prompt_tokens[..], &beams[0].tokens[..]].concat()),
+            None => (self.kv_cache.alloc_seq(), vec![]),
+        };
+        for beam in beams.iter_mut() {
+            if let Some(seq) = beam.seq.take() {
+                self.kv_cache.free_seq(seq)?;
+            }
+        }
+        beams.truncate(options.n_best.max(1));
+
+        let last_prompt_token = prompt_tokens[prompt_tokens.len() - 1];
+        beams
+            .iter()
+            .map(|beam| {
+                let mut text = String::new();
+                let mut prev_token = last_prompt_token;
+                for token in beam.tokens.iter() {
+                    let piece =
+                        self.tokenizer
+                            .decode_with(prev_token, *token, BpeSpecialMode::Skip)?;
+                    text.push_str(&piece);
+                    prev_token = *token;
+                }
+                Ok(Llama2BeamSearchOutput {
+                    text,
+                    tokens: beam.tokens.clone(),
+                    logprob: beam.logprob,
+                    score: beam.score(options.length_penalty),
+                })
+            })
+            .collect()
+    }
+
+    /// the embedding of the text pooled from the final hidden states of its tokens, like
+    /// searching the documents by the similarity of the embeddings on RAG. the text is
+    /// forwarded on a sequence of its own, so the tokens of `generate` are kept.
+    pub fn embeddings(&mut self, text: &str, options: &Llama2EmbeddingOptions) -> Result<Vec<f32>> {
+        if !self.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the embeddings on the recurrent state are not supported yet",
+            )
+                .into());
+        }
+        let tokens = self.tokenizer.encode_special(
+            text,
+            self.tokenizer.add_bos_token(),
+            self.tokenizer.add_eos_token(),
+        )?;
+        if tokens.is_empty() || tokens.len() > self.conf.kv_cache_len() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected 1 to {} tokens to embed, got {}",
+                    self.conf.kv_cache_len(),
+                    tokens.len()
+                ),
+            )
+                .into());
+        }
+
+        let seq = self.kv_cache.alloc_seq();
+        let hidden = self.forward_embedding_rows(seq, &tokens, options.pooling);
+        self.kv_cache.free_seq(seq)?;
+        let mut embedding = pool(&hidden?, options.pooling)?;
+        if options.normalize {
+            normalize(&mut embedding);
+        }
+        Ok(embedding)
+    }
+
+    /// the final hidden states of the tokens taken by the pooling, the tokens are forwarded
+    /// in the batches of `batch_size`.
+    fn forward_embedding_rows(
+        &mut self,
+        seq: usize,
+        tokens: &[usize],
+        pooling: Llama2Pooling,
+    ) -> Result<Vec<Vec<f32>>> {
+        let mut hidden = vec![];
+        for (i, batch) in tokens.chunks(self.batch_size).enumerate() {
+            let rows = batch
+                .iter()
+                .enumerate()
+                .map(|(j, token)| {
+                    let pos = i * self.batch_size + j;
+                    Llama2Row {
+                        seq,
+                        token: *token,
+                        pos,
+                        logits: match pooling {
+                            Llama2Pooling::Cls => pos == 0,
+                            Llama2Pooling::Last => pos == tokens.len() - 1,
+                            _ => true,
+                        },
+                    }
+                })
+                .collect::<Vec<_>>();
+            for x in self.forward_hidden(&rows)? {
+                let mut buf = vec![0.0; self.conf.embedding_dim];
+                x.export(&mut buf)?;
+                hidden.push(buf);
+            }
+        }
+        Ok(hidden)
+    }
+}
+
+#[cfg(test)]
+// Only run tests on aarch64
+#[cfg(target_arch = "aarch64")]
+mod tests {
+    use std::collections::HashMap;
+
+    use approx::assert_relative_eq;
+    use crabml::backends::cpu::CpuTensorBuf;
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::backends::cpu::CpuTensorDeviceOptions;
+    use crabml::backends::cpu::DEFAULT_ARENA_BYTES;
+    use crabml::backends::wgpu::WgpuTensorDevice;
+    use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFile;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFTensorInfo;
+    use crabml::gguf::GGUFWriter;
+    use crabml::gguf::ModelArch;
+    use crabml::tensor::RopeMode;
+
+    use super::*;
+    use crate::grammar::Grammar;
+
+    #[test]
+    fn test_generate_f32() -> Result<()> {
+        let gl: GGUFFileLoader =
+            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            debug_named_tensors: false,
+            ..Default::default()
+        });
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_arena() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let generate = |arena_bytes: usize| -> Result<(String, usize, usize)> {
+            let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+                arena_bytes,
+                ..Default::default()
+            });
+            let lm = CpuLlama2Model::load(&gf, device.clone())?;
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let mut runner = Llama2Runner::try_from(&lm)?;
+            let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+            let s = output.collect::<Result<Vec<String>>>()?.join("");
+            let arena = device.arena();
+            Ok((s, arena.hits(), arena.misses()))
+        };
+
+        // the activations are mostly taken from the buffers of the last steps
+        let (s, hits, misses) = generate(DEFAULT_ARENA_BYTES)?;
+        assert!(hits > misses * 4, "hits: {}, misses: {}", hits, misses);
+        let (want, hits, _) = generate(0)?;
+        assert_eq!(hits, 0);
+        assert_eq!(s, want);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_batch() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the batch takes the same logits with forwarding the tokens one by one
+        let tokens = [1, 365, 2354, 338, 263, 6635];
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        for (pos, token) in tokens.iter().enumerate() {
+            runner.forward(*token, pos)?;
+        }
+        let expected = runner.logits.clone();
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        runner.forward_batch(&tokens[..4], 0)?;
+        let logits = runner.forward_batch(&tokens[4..], 4)?;
+        assert_relative_eq!(logits[..], expected[..], epsilon = 1e-4);
+        assert_eq!(runner.tokens(), &tokens);
+        assert!(runner.forward_batch(&[], 6).is_err());
+
+        // the prompt is forwarded in the batches of 3 tokens
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?.with_batch_size(3);
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_embeddings() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::try_from(&lm)?.with_batch_size(3);
+        runner.forward_batch(&[1, 365, 2354], 0)?;
+
+        let embedding = runner.embeddings("Lily is a cat", &Llama2EmbeddingOptions::default())?;
+        assert_eq!(embedding.len(), lm.conf.embedding_dim);
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert_relative_eq!(norm, 1.0, epsilon = 1e-4);
+
+        // the text is forwarded apart from the tokens of the runner
+        assert_eq!(runner.tokens(), &[1, 365, 2354]);
+        assert_eq!(runner.kv_cache.seq_len(runner.seq)?, 3);
+
+        // the first token only attends to the BOS token itself
+        let options = Llama2EmbeddingOptions {
+            pooling: Llama2Pooling::Cls,
+            normalize: false,
+        };
+        let cls = runner.embeddings("Lily is a cat", &options)?;
+        let cls2 = runner.embeddings("Tom is a dog", &options)?;
+        assert_relative_eq!(cls[..], cls2[..], epsilon = 1e-5);
+
+        let options = Llama2EmbeddingOptions {
+            pooling: Llama2Pooling::Mean,
+            normalize: false,
+        };
+        let mean = runner.embeddings("Lily is a cat", &options)?;
+        let mean2 = runner.embeddings("Tom is a dog", &options)?;
+        assert_ne!(mean, mean2);
+
+        let options = Llama2EmbeddingOptions {
+            pooling: Llama2Pooling::None,
+            normalize: false,
+        };
+        assert!(runner.embeddings("Lily is a cat", &options).is_err());
+        assert_eq!(runner.kv_cache.n_used_pages(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_nlls() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::try_from(&lm)?.with_batch_size(4);
+
+        // the nll of a token takes the logits of the token before it
+        let tokens = [1, 365, 2354, 338, 263, 6635, 1058, 4188];
+        let nlls = runner.token_nlls(&tokens, 0)?;
+        assert_eq!(nlls.len(), 7);
+        let mut runner2 = Llama2Runner::try_from(&lm)?;
+        let mut logits = runner2.forward_batch(&tokens[..5], 0)?.to_vec();
+        log_softmax(&mut logits);
+        assert_relative_eq!(nlls[4], -logits[tokens[5]], epsilon = 1e-4);
+
+        // the tokens in the first half only take the context
+        assert_eq!(runner.token_nlls(&tokens, 4)?, nlls[3..]);
+
+        // the text seen in the stories is more likely than the shuffled one
+        let ppl = |nlls: &[f32]| (nlls.iter().sum::<f32>() / nlls.len() as f32).exp();
+        let shuffled = [1, 4188, 263, 1058, 2354, 6635, 365, 338];
+        assert!(ppl(&nlls) < ppl(&runner.token_nlls(&shuffled, 0)?));
+        assert!(runner.token_nlls(&vec![1; 1000], 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_guidance() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::try_from(&lm)?.with_batch_size(2);
+        let mut generate = |prompt: &str, guidance: Option<(&str, f32)>| -> Result<String> {
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let mut output = runner.generate(prompt, 20, &mut sampler)?;
+            if let Some((negative_prompt, scale)) = guidance {
+                output = output.with_guidance(negative_prompt, scale)?;
+            }
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+
+        // the scale 1.0 takes no guidance, and the scale 0.0 follows the negative prompt only
+        let base = generate("Lily is a cat", None)?;
+        let negative = generate("Tom is a dog", None)?;
+        assert_eq!(
+            generate("Lily is a cat", Some(("Tom is a dog", 1.0)))?,
+            base
+        );
+        assert_eq!(
+            generate("Lily is a cat", Some(("Tom is a dog", 0.0)))?,
+            negative
+        );
+        let guided = generate("Lily is a cat", Some(("Lily is a dog", 3.0)))?;
+        assert_ne!(guided, base);
+        assert_eq!(
+            generate("Lily is a cat", Some(("Lily is a dog", 3.0)))?,
+            guided
+        );
+
+        // the kv cache of the negative prompt is released on the end of the generation
+        assert_eq!(runner.kv_cache.n_used_pages(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_logprobs() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0).with_logprobs(3);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut output = runner.generate("Lily is a cat", 10, &mut sampler)?;
+        let tokens = output.tokens().collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens.len(), 11);
+
+        // the greedy token is always the most likely one
+        for token in tokens.iter() {
+            let logprobs = token.logprobs.as_ref().unwrap();
+            assert_eq!(logprobs.token.token, token.token);
+            assert_eq!(logprobs.token.text, token.text);
+            assert_eq!(logprobs.top.len(), 3);
+            assert_eq!(logprobs.top[0], logprobs.token);
+            assert!(logprobs.token.logprob <= 0.0);
+            assert!(logprobs.top[1].logprob <= logprobs.top[0].logprob);
+        }
+        assert_eq!(tokens[0].text, " who");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_tokens() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_stop_sequences(vec!["box".to_string()]);
+        let tokens = output.tokens().collect::<Result<Vec<_>>>()?;
+
+        let text = tokens.iter().map(|t| t.text.as_str()).collect::<String>();
+        assert_eq!(
+            text,
+            " who likes to play with yarn. She has many colors of yarn in her "
+        );
+        // the prompt "Lily is a cat" is 6 tokens with the bos
+        assert_eq!(tokens[0].pos, 6);
+        for (i, token) in tokens.iter().enumerate() {
+            assert_eq!(token.pos, i + 6);
+            assert!(token.logprobs.is_none());
+        }
+        assert!(tokens[0].elapsed >= tokens[1].elapsed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_abort() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let options = Llama2KvCacheOptions {
+            page_size: 4,
+            ..Default::default()
+        };
+
+        // it stops before forwarding the next token
+        let abort = Llama2AbortHandle::new();
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?.with_kv_cache_options(options)?;
+        let mut output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_abort_handle(abort.clone());
+        let mut texts = vec![];
+        while let Some(token) = output.next_token()? {
+            texts.push(token.text);
+            if texts.len() == 5 {
+                abort.abort();
+            }
+        }
+        assert_eq!(texts.join(""), " who likes to play");
+        assert!(output.next_token()?.is_none());
+        assert_eq!(output.finish_reason(), Some(Llama2FinishReason::Cancelled));
+        drop(output);
+        assert!(runner.tokens().is_empty());
+        assert_eq!(runner.kv_cache.n_used_pages(), 0);
+
+        // the runner generates on the next prompt as usual
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        assert_eq!(
+            output.collect::<Result<Vec<String>>>()?.join(""),
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+
+        // nothing is generated if it's aborted before the prompt is forwarded
+        let output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_abort_handle(abort);
+        assert!(output.collect::<Result<Vec<String>>>()?.is_empty());
+        assert_eq!(runner.kv_cache.n_used_pages(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_finish_reason() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut generate = |max_tokens: usize, stop: &str, timeout: Duration| {
+            let mut output = runner
+                .generate("Lily is a cat", 30, &mut sampler)?
+                .with_max_tokens(max_tokens)
+                .with_stop_sequences(vec![stop.to_string()])
+                .with_timeout(timeout);
+            let mut texts = vec![];
+            while let Some(token) = output.next_token()? {
+                assert_eq!(output.finish_reason(), None);
+                texts.push(token.text);
+            }
+            Ok::<_, Error>((texts, output.finish_reason().unwrap()))
+        };
+
+        let timeout = Duration::from_secs(60);
+        let (texts, reason) = generate(5, "box", timeout)?;
+        assert_eq!(texts.join(""), " who likes to play");
+        assert_eq!(reason, Llama2FinishReason::Length);
+        let (texts, reason) = generate(100, "box", timeout)?;
+        assert_eq!(
+            texts.join(""),
+            " who likes to play with yarn. She has many colors of yarn in her "
+        );
+        assert_eq!(reason, Llama2FinishReason::Stop);
+        // the steps of `generate` are still enforced
+        let (texts, reason) = generate(100, "cats", timeout)?;
+        assert_eq!(texts.len(), 31);
+        assert_eq!(reason, Llama2FinishReason::Length);
+        let (texts, reason) = generate(100, "box", Duration::ZERO)?;
+        assert!(texts.is_empty());
+        assert_eq!(reason, Llama2FinishReason::Timeout);
+        assert_eq!(reason.as_str(), "timeout");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_output() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_max_tokens(8)
+            .complete()?;
+        assert_eq!(output.text, " who likes to play with yarn");
+        assert_eq!(output.tokens.len(), 8);
+        assert_eq!(output.finish_reason, Llama2FinishReason::Length);
+        let stats = output.stats;
+        assert_eq!(stats.n_prompt_tokens, 6);
+        assert_eq!(stats.n_cached_tokens, 0);
+        assert_eq!(stats.n_eval_tokens, 8);
+        assert!(stats.prompt_time > Duration::ZERO && stats.eval_time > Duration::ZERO);
+        assert_eq!(stats.total_time(), stats.prompt_time + stats.eval_time);
+        assert!(stats.eval_tokens_per_second() > 0.0);
+
+        // the prompt is kept in the kv cache except its last token
+        let output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_stop_sequences(vec!["play".to_string()])
+            .complete()?;
+        assert_eq!(output.text, " who likes to ");
+        assert_eq!(output.finish_reason, Llama2FinishReason::Stop);
+        assert_eq!(output.stats.n_cached_tokens, 5);
+        assert_eq!(output.stats.n_eval_tokens, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_stop_sequences() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the greedy output is " who likes to play with yarn. She has many colors of yarn
+        // in her box.", the stop sequence "rn in" spans the tokens " ya", "rn" and " in"
+        let generate = |stop_sequences: &[&str]| -> Result<Vec<String>> {
+            let mut runner = Llama2Runner::try_from(&lm)?;
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let stop_sequences = stop_sequences.iter().map(|s| s.to_string()).collect();
+            runner
+                .generate("Lily is a cat", 30, &mut sampler)?
+                .with_stop_sequences(stop_sequences)
+                .collect::<Result<Vec<String>>>()
+        };
+
+        let output = generate(&["rn in", "box"])?;
+        assert_eq!(
+            output.join(""),
+            " who likes to play with yarn. She has many colors of ya"
+        );
+        assert!(output.iter().all(|s| !s.is_empty()));
+        assert_eq!(
+            generate(&["box", "many"])?.join(""),
+            " who likes to play with yarn. She has "
+        );
+        assert_eq!(generate(&[" who"])?, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_grammar() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let grammar = Grammar::parse(r#"root ::= (" " ("yarn" | "milk" | "fish") ","?){2,3} ".""#)?;
+        let mut sampler =
+            Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0).with_grammar(grammar, &lm.tokenizer());
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cat who likes", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+
+        assert_eq!(s, " milk, milk, milk.");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_sliding_window() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let mut lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the output and the rows in the kv cache
+        let generate = |lm: &CpuLlama2Model| -> Result<(Vec<String>, usize)> {
+            let mut runner = Llama2Runner::try_from(lm)?;
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let output = runner
+                .generate("Lily is a cat", 30, &mut sampler)?
+                .collect::<Result<Vec<String>>>()?;
+            let cache_rows = runner.kv_cache.seq_len(runner.seq)?;
+            Ok((output, cache_rows))
+        };
+        let (expected, _) = generate(&lm)?;
+
+        // a window no shorter than the context is the same as the full attention
+        lm.conf.sliding_window = Some(lm.conf.seq_len);
+        assert_eq!(generate(&lm)?.0, expected);
+
+        // the cache keeps only the last 8 positions, the attention is the same until then
+        lm.conf.sliding_window = Some(8);
+        let (output, cache_rows) = generate(&lm)?;
+        assert_eq!(output.len(), expected.len());
+        assert_eq!(output[..3], expected[..3]);
+        assert_eq!(cache_rows, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_context_shift() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let mut lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        lm.conf.seq_len = 16;
+
+        // the generation stops once the context of 16 tokens is full
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let expected = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(expected.len(), 11);
+
+        // the first 4 tokens are kept and the half of the rest are dropped on shifting
+        let mut generator = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_context_shift(4);
+        let tokens = generator
+            .tokens()
+            .collect::<Result<Vec<Llama2GeneratedToken>>>()?;
+        let output = tokens.iter().map(|t| t.text.clone()).collect::<Vec<_>>();
+        assert_eq!(output.len(), 31);
+        assert_eq!(output[..11], expected[..]);
+        assert_eq!(tokens.last().unwrap().pos, 36);
+        assert_eq!(
+            output.join(""),
+            " who likes to play with yarn. She has a big ball of yarn. She likes to play with the thread. She makes a long"
+        );
+        assert!(runner.kv_cache.seq_len(runner.seq)? <= 16);
+
+        // nothing can be dropped if all the tokens are kept
+        let output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .with_context_shift(15);
+        assert!(output.collect::<Result<Vec<String>>>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_session() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let prompt = "Lily is a cat who likes to play";
+
+        // forward the prompt and save the session
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.8, 0.9).with_seed(42);
+        let _ = runner
+            .generate("Lily is a cat", 1, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        let path = std::env::temp_dir().join(format!("crabml-session-{}.gguf", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        runner.session(&sampler)?.save(&path)?;
+
+        // the restored runner only forwards the rest of the prompt, and samples the same
+        let mut restored = Llama2Runner::try_from(&lm)?;
+        let mut restored_sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.8, 0.9);
+        let session = Llama2Session::load(&path)?;
+        std::fs::remove_file(&path).unwrap();
+        restored.restore_session(&session, &mut restored_sampler)?;
+        assert_eq!(restored.tokens(), runner.tokens());
+        assert_eq!(restored.tokens().len(), 7);
+
+        let output = restored.generate(prompt, 20, &mut restored_sampler)?;
+        assert_eq!(output.pos, 7);
+        let expected = runner
+            .generate(prompt, 20, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.collect::<Result<Vec<String>>>()?, expected);
+
+        // the session does not match the other models
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        assert!(runner.restore_session(&session, &mut sampler).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_prompt_cache() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let options = Llama2KvCacheOptions {
+            page_size: 4,
+            ..Default::default()
+        };
+        let mut runner = Llama2Runner::try_from(&lm)?
+            .with_kv_cache_options(options)?
+            .with_prompt_cache(2)?;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let system = "Lily is a cat who likes to play with yarn.";
+        let _ = runner
+            .generate(&format!("{} She", system), 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        let _ = runner
+            .generate("Tom is a dog", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+
+        // the prompt sharing the prefix with the first one takes over its kv cache
+        let prompt = format!("{} One day", system);
+        let output = runner.generate(&prompt, 10, &mut sampler)?;
+        assert_eq!(output.pos, 15);
+        let mut fresh = Llama2Runner::try_from(&lm)?;
+        let mut fresh_sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let expected = fresh
+            .generate(&prompt, 10, &mut fresh_sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.collect::<Result<Vec<String>>>()?, expected);
+        assert!(Llama2Runner::try_from(&lm)?.with_prompt_cache(0).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefill() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::try_from(&lm)?.with_batch_size(3);
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+
+        // the eos token in the prompt stops the generation before the prompt is forwarded
+        let prompt = "Lily is a cat.</s> Tom is a dog";
+        let output = runner
+            .generate(prompt, 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        assert!(output.is_empty());
+
+        // the prefilled prompt is forwarded through the eos token, after the tokens forwarded
+        // before it
+        let tokens = lm.tokenizer().encode_special(prompt, true, false)?;
+        let n_forwarded = runner.tokens().len();
+        assert!(n_forwarded < tokens.len() - 1);
+        assert_eq!(runner.prefill(&tokens)?, n_forwarded);
+        assert_eq!(runner.tokens(), &tokens[..tokens.len() - 1]);
+        let output = runner.generate(prompt, 10, &mut sampler)?;
+        assert_eq!(output.pos, tokens.len() - 1);
+        assert!(!output.collect::<Result<Vec<String>>>()?.is_empty());
+
+        // the prefix kept in the kv cache is not forwarded again
+        assert_eq!(runner.prefill(&tokens)?, tokens.len() - 1);
+        assert!(runner.prefill(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_paged_kv_cache() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the attention over the pages of 4 rows is the same as the one over a single page
+        let options = Llama2KvCacheOptions {
+            page_size: 4,
+            ..Default::default()
+        };
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?.with_kv_cache_options(options)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        assert_eq!(runner.kv_cache.seq_len(runner.seq)?, 36);
+        assert_eq!(runner.kv_cache.n_used_pages(), 9);
+
+        // the beams share the pages, only the ones of the best beam are kept at the end
+        let options = Llama2BeamSearchOptions {
+            beam_width: 3,
+            length_penalty: 1.0,
+            n_best: 3,
+        };
+        let outputs = runner.beam_search("Lily is a cat", 20, &options)?;
+        assert_eq!(
+            outputs[0].text,
+            " who likes to play with yarn. She has many colors of yarn in her box."
+        );
+        assert_eq!(runner.kv_cache.n_used_pages(), 7);
+
+        // the pool is out of pages beyond the max pages
+        let options = Llama2KvCacheOptions {
+            page_size: 4,
+            max_pages: Some(2),
+            ..Default::default()
+        };
+        let mut runner = Llama2Runner::try_from(&lm)?.with_kv_cache_options(options)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        assert!(output.collect::<Result<Vec<String>>>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_beam_search() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the beam search with a single beam is the same as the greedy sampling
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let options = Llama2BeamSearchOptions {
+            beam_width: 1,
+            ..Default::default()
+        };
+        let outputs = runner.beam_search("Lily is a cat", 30, &options)?;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(
+            outputs[0].text,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and"
+        );
+        assert_eq!(outputs[0].tokens.len(), 30);
+
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let options = Llama2BeamSearchOptions {
+            beam_width: 3,
+            length_penalty: 1.0,
+            n_best: 3,
+        };
+        let outputs = runner.beam_search("Lily is a cat", 20, &options)?;
+        assert_eq!(outputs.len(), 3);
+        assert!(outputs.windows(2).all(|w| w[0].score >= w[1].score));
+        assert!(outputs.windows(2).all(|w| w[0].tokens != w[1].tokens));
+        for output in outputs.iter() {
+            assert!(output.logprob < 0.0);
+            assert_relative_eq!(output.score, output.logprob / output.tokens.len() as f32);
+        }
+        assert_eq!(
+            outputs[0].text,
+            " who likes to play with yarn. She has many colors of yarn in her box."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_kv_cache_q8_0() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?.with_kv_cache_dtype(GGMLType::Q8_0)?;
+        let output = runner.generate("Lily is a cat", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, " who likes to play with yarn. She has");
+        assert_eq!(
+            runner.kv_cache.keys(runner.seq, 0)?[0].dtype(),
+            GGMLType::Q8_0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_flash_attention() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the fused attention over the pages of 4 rows is the same as the unfused one
+        let options = Llama2KvCacheOptions {
+            page_size: 4,
+            ..Default::default()
+        };
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?
+            .with_kv_cache_options(options)?
+            .with_flash_attention(true);
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+
+        // the quantized kv cache is dequantized on the fused attention
+        let mut runner = Llama2Runner::try_from(&lm)?
+            .with_kv_cache_dtype(GGMLType::Q8_0)?
+            .with_flash_attention(true);
+        let output = runner.generate("Lily is a cat", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, " who likes to play with yarn. She has");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_f16_activations() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the same as the f32 activations, the prompt is taken by the batched matmul and the
+        // rest by matmul_vec
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?.with_activation_dtype(GGMLType::F16)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+
+        let err = Llama2Runner::try_from(&lm)?
+            .with_activation_dtype(GGMLType::Q8_0)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_q8_0() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device)?;
+        assert_eq!(lm.conf().rope_dim, 48);
+        assert_eq!(lm.conf().head_size(), 48);
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cute cat, ", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
+    /// convert the 2d weights of the f32 model into `typ`, and load it from the memory.
+    fn write_converted_model(gf: &GGUFFile, typ: GGMLType) -> Result<GGUFFileLoader> {
+        let converted = gf
+            .tensor_infos()
+            .iter()
+            .filter(|info| info.dimensions().len() == 2)
+            .map(|info| {
+                let buf = CpuTensorBuf::from_raw_bytes(info.data(), info.typ())?;
+                Ok((info.name().to_string(), buf.quantize(typ)?))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        let mut w = GGUFWriter::new();
+        w.add_metadata_of(gf.metadata());
+        for info in gf.tensor_infos() {
+            match converted.get(info.name()) {
+                Some(buf) => w.add_tensor(info.name(), info.dimensions(), typ, buf.as_bytes())?,
+                None => w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?,
+            }
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    /// turn the ffn of the f32 model into the two same experts with a zero gate, so the moe
+    /// model gives the same output as the dense one, and load it from the memory.
+    fn write_moe_model(
+        gf: &GGUFFile,
+        n_experts_used: u32,
+        stacked: bool,
+    ) -> Result<GGUFFileLoader> {
+        let n_experts = 2;
+        let mut w = GGUFWriter::new();
+        w.add_metadata_of(gf.metadata());
+        w.add_metadata("llama.expert_count", GGUFMetadataValue::U32(n_experts));
+        w.add_metadata(
+            "llama.expert_used_count",
+            GGUFMetadataValue::U32(n_experts_used),
+        );
+        for info in gf.tensor_infos() {
+            let name = info.name();
+            let Some(ffn) = ["ffn_gate", "ffn_down", "ffn_up"]
+                .iter()
+                .find(|ffn| name.ends_with(&format!(".{}.weight", ffn)))
+            else {
+                w.add_tensor(name, info.dimensions(), info.typ(), info.data())?;
+                continue;
+            };
+
+            let layer = name.split('.').nth(1).unwrap();
+            if stacked {
+                let dims = [info.dimensions(), &[n_experts as usize]].concat();
+                let data = info.data().repeat(n_experts as usize);
+                let name = format!("blk.{}.{}_exps.weight", layer, ffn);
+                w.add_tensor(&name, &dims, info.typ(), data)?;
+            } else {
+                for expert in 0..n_experts {
+                    let name = format!("blk.{}.{}.{}.weight", layer, ffn, expert);
+                    w.add_tensor(&name, info.dimensions(), info.typ(), info.data())?;
+                }
+            }
+            if *ffn == "ffn_gate" {
+                let embed_dim = info.dimensions()[0];
+                let zeros = vec![0; embed_dim * n_experts as usize * 4];
+                let name = format!("blk.{}.ffn_gate_inp.weight", layer);
+                w.add_tensor(
+                    &name,
+                    &[embed_dim, n_experts as usize],
+                    GGMLType::F32,
+                    zeros,
+                )?;
+            }
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    #[test]
+    fn test_generate_moe() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+
+        for (n_experts_used, stacked) in [(2, true), (1, false)] {
+            let gl = write_moe_model(&gf, n_experts_used, stacked)?;
+            let gf = gl.open()?;
+
+            let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+            assert_eq!(lm.conf.n_experts, 2);
+            assert_eq!(lm.conf.n_experts_used, n_experts_used as usize);
+            assert!(lm.weights().w1.is_empty());
+            assert_eq!(lm.weights().w1_exps[0].len(), 2);
+
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let mut runner = Llama2Runner::try_from(&lm)?;
+            let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+            let s = output.collect::<Result<Vec<String>>>()?.join("");
+            assert_eq!(
+                s,
+                " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+            );
+        }
+        Ok(())
+    }
+
+    /// the interleaved rows [x0, y0, x1, y1, ..] of each head into [x0, x1, .., y0, y1, ..], which
+    /// is the layout of the q, k projections for the neox rope.
+    fn neox_rows(info: &GGUFTensorInfo, head_size: usize) -> Vec<u8> {
+        let row_bytes = info.dimensions()[0] * 4;
+        let rows = info.data().chunks_exact(row_bytes).collect::<Vec<_>>();
+        rows.chunks_exact(head_size)
+            .flat_map(|head| {
+                let pairs = head.chunks_exact(2);
+                let xs = pairs.clone().map(|pair| pair[0]);
+                let ys = pairs.map(|pair| pair[1]);
+                xs.chain(ys).collect::<Vec<_>>()
+            })
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>()
+    }
+
+    /// rewrite the f32 llama model into qwen2, which takes the q, k rows in the layout of the
+    /// neox rope and has the biases of `bias` on qkv. with the zero biases, it gives the same
+    /// output as llama.
+    fn write_qwen2_model(gf: &GGUFFile, bias: f32) -> Result<GGUFFileLoader> {
+        let mut w = GGUFWriter::new();
+        for (key, value) in gf.metadata().as_hashmap() {
+            match key.strip_prefix("llama.") {
+                Some(key) => w.add_metadata(&format!("qwen2.{}", key), value.clone()),
+                None => w.add_metadata(key, value.clone()),
+            }
+        }
+        w.add_metadata("general.architecture", GGUFMetadataValue::String("qwen2"));
+
+        let head_size = gf.metadata().get_u32("llama.embedding_length").unwrap() as usize
+            / gf.metadata().get_u32("llama.attention.head_count").unwrap() as usize;
+        for info in gf.tensor_infos() {
+            let name = info.name();
+            if !name.ends_with(".attn_q.weight") && !name.ends_with(".attn_k.weight") {
+                w.add_tensor(name, info.dimensions(), info.typ(), info.data())?;
+                continue;
+            }
+
+            let data = neox_rows(info, head_size);
+            w.add_tensor(name, info.dimensions(), info.typ(), data)?;
+
+            let rows = info.dimensions()[1];
+            let data = bias.to_le_bytes().repeat(rows);
+            w.add_tensor(
+                &name.replace(".weight", ".bias"),
+                &[rows],
+                GGMLType::F32,
+                data.clone(),
+            )?;
+            if name.ends_with(".attn_k.weight") {
+                let name = name.replace(".attn_k.weight", ".attn_v.bias");
+                w.add_tensor(&name, &[rows], GGMLType::F32, data)?;
+            }
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    #[test]
+    fn test_generate_qwen2() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl_biased = write_qwen2_model(&gf, 0.1)?;
+        let gl = write_qwen2_model(&gf, 0.0)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(lm.conf.arch, ModelArch::Qwen2);
+        assert_eq!(lm.conf.rope_mode, RopeMode::Neox);
+        assert_eq!(lm.weights().bv.len(), lm.conf.n_layers);
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+
+        // the biases are added to the q, k, v projections
+        let gf = gl_biased.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cat", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, ". Lily was a big, fluffy cat");
+        let logits = runner.forward(1, 0)?;
+        assert_relative_eq!(
+            logits[..3],
+            [-7.294753, 0.07349455, -7.2944336][..],
+            epsilon = 1e-3
+        );
+        Ok(())
+    }
+
+    /// rewrite the f32 llama model into phi3, which fuses the q, k, v projections into attn_qkv
+    /// and the gate into the first half of ffn_up, and has the rope factors of ones, so it gives
+    /// the same output as llama.
+    fn write_phi3_model(gf: &GGUFFile) -> Result<GGUFFileLoader> {
+        let mut w = GGUFWriter::new();
+        for (key, value) in gf.metadata().as_hashmap() {
+            match key.strip_prefix("llama.") {
+                Some(key) => w.add_metadata(&format!("phi3.{}", key), value.clone()),
+                None => w.add_metadata(key, value.clone()),
+            }
+        }
+        let seq_len = gf.metadata().get_u32("llama.context_length").unwrap();
+        w.add_metadata("general.architecture", GGUFMetadataValue::String("phi3"));
+        w.add_metadata(
+            "phi3.rope.scaling.original_context_length",
+            GGUFMetadataValue::U32(seq_len),
+        );
+        w.add_metadata("phi3.rope.scaling.attn_factor", GGUFMetadataValue::F32(1.0));
+
+        let head_size = gf.metadata().get_u32("llama.embedding_length").unwrap() as usize
+            / gf.metadata().get_u32("llama.attention.head_count").unwrap() as usize;
+        let rope_dim = gf.metadata().get_u32("llama.rope.dimension_count").unwrap() as usize;
+        // the long factors are not taken within the original context length
+        for (name, factor) in [("rope_factors_short", 1.0f32), ("rope_factors_long", 4.0)] {
+            let data = factor.to_le_bytes().repeat(rope_dim / 2);
+            w.add_tensor(
+                &format!("{}.weight", name),
+                &[rope_dim / 2],
+                GGMLType::F32,
+                data,
+            )?;
+        }
+
+        let infos = gf
+            .tensor_infos()
+            .iter()
+            .map(|info| (info.name(), info))
+            .collect::<HashMap<_, _>>();
+        let fuse = |names: &[String], neox: &[bool]| -> (Vec<usize>, Vec<u8>) {
+            let infos = names
+                .iter()
+                .map(|name| infos[name.as_str()])
+                .collect::<Vec<_>>();
+            let rows = infos.iter().map(|info| info.dimensions()[1]).sum::<usize>();
+            let data = infos
+                .iter()
+                .zip(neox)
+                .flat_map(|(info, neox)| match neox {
+                    true => neox_rows(info, head_size),
+                    false => info.data().to_vec(),
+                })
+                .collect::<Vec<_>>();
+            (vec![infos[0].dimensions()[0], rows], data)
+        };
+        for info in gf.tensor_infos() {
+            let name = info.name();
+            let Some(layer) = name.strip_prefix("blk.").and_then(|n| n.split('.').next()) else {
+                w.add_tensor(name, info.dimensions(), info.typ(), info.data())?;
+                continue;
+            };
+            let tensor = |name: &str| format!("blk.{}.{}.weight", layer, name);
+            if name == tensor("attn_q") {
+                let names = [tensor("attn_q"), tensor("attn_k"), tensor("attn_v")];
+                let (dims, data) = fuse(&names, &[true, true, false]);
+                w.add_tensor(&tensor("attn_qkv"), &dims, info.typ(), data)?;
+            } else if name == tensor("ffn_up") {
+                let (dims, data) = fuse(&[tensor("ffn_gate"), tensor("ffn_up")], &[false, false]);
+                w.add_tensor(&tensor("ffn_up"), &dims, info.typ(), data)?;
+            } else if ![tensor("attn_k"), tensor("attn_v"), tensor("ffn_gate")]
+                .contains(&name.into())
+            {
+                w.add_tensor(name, info.dimensions(), info.typ(), info.data())?;
+            }
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    #[test]
+    fn test_generate_phi3() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_phi3_model(&gf)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(lm.conf.arch, ModelArch::Phi3);
+        assert_eq!(lm.conf.rope_mode, RopeMode::Neox);
+        assert!(
+            !gf.tensor_infos()
+                .iter()
+                .any(|info| info.name() == "blk.0.attn_q.weight")
+        );
+        assert_eq!(lm.weights().w1.len(), lm.conf.n_layers);
+        assert_eq!(lm.weights().rope_freq_factors, vec![
+            1.0;
+            lm.conf.rope_dim / 2
+        ]);
+
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
 
-            // attention rnsnorm
-            x = {
-                x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-                x = x.mul_inplace(&self.weights.rms_att_weight[l])?;
-                x = x.with_name(format!("attn_rmsnorm:{}:{}", l, pos));
-                x
-            };
+    /// copy the metadata of the f32 llama model into the arch, which takes the layer norms.
+    fn add_layer_norm_metadata<'a>(w: &mut GGUFWriter<'a>, gf: &'a GGUFFile<'a>, arch: &'a str) {
+        for (key, value) in gf.metadata().as_hashmap() {
+            match key.strip_prefix("llama.") {
+                Some("attention.layer_norm_rms_epsilon") => w.add_metadata(
+                    &format!("{}.attention.layer_norm_epsilon", arch),
+                    value.clone(),
+                ),
+                Some(key) => w.add_metadata(&format!("{}.{}", arch, key), value.clone()),
+                None => w.add_metadata(key, value.clone()),
+            }
+        }
+        w.add_metadata("general.architecture", GGUFMetadataValue::String(arch));
+    }
 
-            // matmul qkv for every head
-            let (q, k, v) = {
-                // wq: (embed_dim, embed_dim) @ x (embed_dim, ) => (embed_dim, )
-                // wk: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
-                // wv: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
-                let q = self.weights.wq[l].matmul_vec(&x)?;
-                let k = self.weights.wk[l].matmul_vec(&x)?;
-                let v = self.weights.wv[l].matmul_vec(&x)?;
-
-                (
-                    q.with_name(format!("q:{}:{}", l, pos)),
-                    k.with_name(format!("k:{}:{}", l, pos)),
-                    v.with_name(format!("v:{}:{}", l, pos)),
-                )
-            };
+    /// the cpu and the gpu should agree on the rewritten models. returns the output and the
+    /// first logits of the bos token, which are pinned as the reference of each arch.
+    fn assert_generate_same_on_gpu<'a>(
+        model_cpu: &'a CpuLlama2Model<'a>,
+    ) -> Result<(String, Vec<f32>)> {
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        );
+        let model_wgpu = Llama2Model::from_cpu(model_cpu, device_wgpu)?;
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_heads, head_size])?;
-                let k = k.reshape(&[n_kv_heads, head_size])?;
-
-                let q = q.rope_inplace(pos, self.conf.rope_dim)?;
-                let k = k.rope_inplace(pos, self.conf.rope_dim)?;
-                (
-                    q.with_name(format!("q_roped:{}:{}", l, pos)),
-                    k.with_name(format!("k_roped:{}:{}", l, pos)),
-                )
-            };
+        let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
+        let mut runner_cpu = Llama2Runner::try_from(model_cpu)?;
+        let mut runner_wgpu = Llama2Runner::try_from(&model_wgpu)?;
+        let output_cpu = runner_cpu
+            .generate("Lily is a cat", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        let output_wgpu = runner_wgpu
+            .generate("Lily is a cat", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert!(!output_cpu.is_empty());
+        assert_eq!(output_cpu, output_wgpu);
+        let logits = runner_cpu.forward(1, 0)?[..3].to_vec();
+        Ok((output_cpu, logits))
+    }
 
-            // save to kv cache
-            {
-                let v = v
-                    .reshape(&[n_kv_heads, head_size])?
-                    .repeat_n(n_heads / n_kv_heads)?;
-                let k = k.repeat_n(n_heads / n_kv_heads)?;
+    /// rewrite the f32 llama model into phi2, which rotates half of each head, takes the layer
+    /// norms and the gelu mlp without the gate on the parallel blocks, with the zero biases.
+    fn write_phi2_model(gf: &GGUFFile) -> Result<GGUFFileLoader> {
+        let mut w = GGUFWriter::new();
+        add_layer_norm_metadata(&mut w, gf, "phi2");
+        let head_size = gf.metadata().get_u32("llama.embedding_length").unwrap()
+            / gf.metadata().get_u32("llama.attention.head_count").unwrap();
+        w.add_metadata(
+            "phi2.rope.dimension_count",
+            GGUFMetadataValue::U32(head_size / 2),
+        );
 
-                if let Some(ref mut k_cache) = self.key_cache[l] {
-                    k_cache.extend(&k)?;
-                }
-                if let Some(ref mut v_cache) = self.value_cache[l] {
-                    v_cache.extend(&v)?;
-                }
-            };
+        for info in gf.tensor_infos() {
+            let name = info.name();
+            if name.ends_with(".ffn_gate.weight") || name.ends_with(".ffn_norm.weight") {
+                continue;
+            }
+            w.add_tensor(name, info.dimensions(), info.typ(), info.data())?;
+            let rows = *info.dimensions().last().unwrap();
+            if name != "token_embd.weight" {
+                w.add_tensor(
+                    &name.replace(".weight", ".bias"),
+                    &[rows],
+                    GGMLType::F32,
+                    vec![0; rows * 4],
+                )?;
+            }
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
 
-            // multi query attention
-            x = {
-                let q = q.reshape(&[n_heads, head_size])?;
-
-                // - key_cache: [seq, n_head, head_size]
-                // - key_cache = key_cache.transpose(1, 0, 2) => [n_head, seq, head_size]
-                // - q: [n_head, head_size]
-                // - attn_score = batch_matmul(key_cache, q) => [n_head, seq]
-                // - softmax(attn_score, axis=1) => [n_head, seq]
-                // - val_cache: [seq, n_head, head_size]
-                // - val_cache = val_cache.transpose(1, 2, 0) => [n_head, head_size, seq]
-                // - out = batch_matmul(val_cache, atten_scores) => [n_head, head_size]
-
-                // get attention scores
-                let k_cache = self.key_cache[l].take().unwrap();
-                let k_cache_strider_orig = k_cache.strider().clone();
-                let k_cache = k_cache.transpose(&[1, 0, 2])?;
-                // (n_heads, n_seq, head_size) @ (n_head, head_size) => (n_heads, n_seq)
-                let attn = k_cache.batch_matmul_vec(&q)?;
-                let attn = attn.div_scalar_inplace((head_size as f32).sqrt())?;
-                let attn = attn
-                    .softmax_inplace(1)?
-                    .with_name(format!("k_cache_attn:{}:{}", l, pos));
-                self.key_cache[l].replace(k_cache.with_strider(k_cache_strider_orig)?);
-
-                let v_cache = self.value_cache[l].take().unwrap();
-                let v_cache_strider_orig = v_cache.strider().clone();
-                // get the weighted sum of the values and attention scores
-                let v_cache = v_cache.transpose(&[1, 2, 0])?;
-                // (n_heads, head_size, n_seq) @ (n_heads, n_seq) => (n_heads, head_size)
-                let x_with_attn = v_cache.batch_matmul_vec(&attn)?; // (n_heads, head_size)
-                let x_with_attn = x_with_attn.reshape(&[embed_dim])?;
-                self.value_cache[l].replace(v_cache.with_strider(v_cache_strider_orig)?);
-
-                // final matmul to get the output of the attention
-                self.weights.wo[l].matmul_vec(&x_with_attn)?
-            };
+    #[test]
+    fn test_generate_phi2_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_phi2_model(&gf)?;
+        let gf = gl.open()?;
 
-            // residual connection back into x
-            x = x.add_inplace(&x_attn_orig)?;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(model_cpu.conf.arch, ModelArch::Phi2);
+        assert_eq!(model_cpu.conf.norm, Llama2Norm::Layer);
+        assert!(model_cpu.conf.parallel_residual);
+        assert_eq!(model_cpu.conf.rope_dim * 2, model_cpu.conf.head_size());
+        assert!(model_cpu.weights().w1.is_empty());
+        assert!(model_cpu.weights().rms_ffn_weight.is_empty());
+        assert!(model_cpu.weights().bcls.is_some());
 
-            // ffn
-            x = {
-                // save for redidual connection
-                let x_orig_ffn = x.dup()?;
+        let (output, logits) = assert_generate_same_on_gpu(&model_cpu)?;
+        assert_eq!(
+            output,
+            "ting foot foot foot foot foot foot foot foot foot foot"
+        );
+        assert_relative_eq!(
+            logits[..],
+            [-1.959773, 1.0980675, -1.9596502][..],
+            epsilon = 1e-3
+        );
+        Ok(())
+    }
 
-                // ffn rmsnorm
-                x = {
-                    x = x.rms_norm_inplace(1e-5)?;
-                    x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
-                    x
-                };
+    /// rewrite the f32 llama model into falcon-40b, which fuses the multi query attention with
+    /// the first kv head into attn_qkv, and takes the attention and the ffn in parallel, with
+    /// attn_norm_2 before the ffn.
+    fn write_falcon_model(gf: &GGUFFile) -> Result<GGUFFileLoader> {
+        let mut w = GGUFWriter::new();
+        add_layer_norm_metadata(&mut w, gf, "falcon");
+        w.add_metadata("falcon.attention.head_count_kv", GGUFMetadataValue::U32(1));
+        w.remove_metadata("falcon.rope.dimension_count");
+
+        let embed_dim = gf.metadata().get_u32("llama.embedding_length").unwrap() as usize;
+        let head_size =
+            embed_dim / gf.metadata().get_u32("llama.attention.head_count").unwrap() as usize;
+        let infos = gf
+            .tensor_infos()
+            .iter()
+            .map(|info| (info.name(), info))
+            .collect::<HashMap<_, _>>();
+        for info in gf.tensor_infos() {
+            let name = info.name();
+            let dims = info.dimensions();
+            if name.ends_with(".attn_q.weight") {
+                let k = infos[name.replace("attn_q", "attn_k").as_str()];
+                let v = infos[name.replace("attn_q", "attn_v").as_str()];
+                let kv_bytes = head_size * embed_dim * 4;
+                let data = [info.data(), &k.data()[..kv_bytes], &v.data()[..kv_bytes]].concat();
+                let dims = [dims[0], dims[1] + head_size * 2];
+                w.add_tensor(&name.replace("attn_q", "attn_qkv"), &dims, info.typ(), data)?;
+            } else if name.ends_with(".ffn_norm.weight") {
+                let name = name.replace("ffn_norm", "attn_norm_2");
+                w.add_tensor(&name, dims, info.typ(), info.data())?;
+                w.add_tensor(
+                    &name.replace(".weight", ".bias"),
+                    dims,
+                    GGMLType::F32,
+                    vec![0; dims[0] * 4],
+                )?;
+            } else if name.ends_with("norm.weight") {
+                w.add_tensor(name, dims, info.typ(), info.data())?;
+                w.add_tensor(
+                    &name.replace(".weight", ".bias"),
+                    dims,
+                    GGMLType::F32,
+                    vec![0; dims[0] * 4],
+                )?;
+            } else if ["attn_k", "attn_v", "ffn_gate"]
+                .iter()
+                .all(|t| !name.contains(t))
+            {
+                w.add_tensor(name, dims, info.typ(), info.data())?;
+            }
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
 
-                // Now for FFN in PyTorch we have: self.w2(F.silu(self.w1(x)) * self.w3(x))
-                // first calculate self.w1(x) and self.w3(x)
-                // w1: (hidden_dim, embed_dim) @ x (embed_dim, ) => (hidden_dim, )
-                // w3: (hidden_dim, embed_dim) @ x (embed_dim, ) => (hidden_dim, )
-                let mut h1 = self.weights.w1[l].matmul_vec(&x)?;
-                let h2 = self.weights.w3[l].matmul_vec(&x)?;
+    #[test]
+    fn test_generate_falcon_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_falcon_model(&gf)?;
+        let gf = gl.open()?;
 
-                // F.silu; silu(x)=x*σ(x),where σ(x) is the logistic sigmoid
-                h1 = h1.silu_inplace()?;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(model_cpu.conf.arch, ModelArch::Falcon);
+        assert_eq!(model_cpu.conf.norm, Llama2Norm::Layer);
+        assert!(model_cpu.conf.parallel_residual);
+        assert_eq!(model_cpu.conf.n_kv_heads, 1);
+        assert_eq!(model_cpu.conf.rope_dim, model_cpu.conf.head_size());
+        assert_eq!(model_cpu.weights().wk[0].shape(), &[
+            model_cpu.conf.head_size(),
+            model_cpu.conf.embedding_dim
+        ]);
+        assert_eq!(
+            model_cpu.weights().rms_ffn_weight.len(),
+            model_cpu.conf.n_layers
+        );
 
-                // elementwise multiply with w3(x)
-                h1 = h1.mul_inplace(&h2)?;
+        let (output, logits) = assert_generate_same_on_gpu(&model_cpu)?;
+        assert_eq!(output, " putting clothes up up and bows way way way way");
+        assert_relative_eq!(
+            logits[..],
+            [1.1685004, 2.825737, 1.1691037][..],
+            epsilon = 1e-3
+        );
+        Ok(())
+    }
 
-                // final matmul to get the output of the ffn
-                x = self.weights.w2[l].matmul_vec(&h1)?;
+    /// keep the first n_kv_heads heads of wk and wv in the f32 llama model. with `repeat`, the
+    /// kv heads are repeated into each query head of their groups instead, which gives the
+    /// multi-head model with the same attention as the grouped one.
+    fn write_gqa_model(gf: &GGUFFile, n_kv_heads: usize, repeat: bool) -> Result<GGUFFileLoader> {
+        let embed_dim = gf.metadata().get_u32("llama.embedding_length").unwrap() as usize;
+        let n_heads = gf.metadata().get_u32("llama.attention.head_count").unwrap() as usize;
+        let head_bytes = embed_dim / n_heads * embed_dim * 4;
+        let heads = match repeat {
+            true => (0..n_heads).map(|h| h / (n_heads / n_kv_heads)).collect(),
+            false => (0..n_kv_heads).collect::<Vec<_>>(),
+        };
 
-                // residual connection
-                x = x.add_inplace(&x_orig_ffn)?;
-                x.with_name(format!("ffn_out:{}:{}", l, pos))
+        let mut w = GGUFWriter::new();
+        w.add_metadata_of(gf.metadata());
+        w.add_metadata(
+            "llama.attention.head_count_kv",
+            GGUFMetadataValue::U32(heads.len() as u32),
+        );
+        for info in gf.tensor_infos() {
+            let (name, dims) = (info.name(), info.dimensions());
+            if name.ends_with(".attn_k.weight") || name.ends_with(".attn_v.weight") {
+                let data = heads
+                    .iter()
+                    .flat_map(|h| &info.data()[h * head_bytes..(h + 1) * head_bytes])
+                    .copied()
+                    .collect::<Vec<_>>();
+                let dims = [dims[0], data.len() / 4 / dims[0]];
+                w.add_tensor(name, &dims, info.typ(), data)?;
+            } else {
+                w.add_tensor(name, dims, info.typ(), info.data())?;
             }
         }
+        GGUFFileLoader::from_writer(&w)
+    }
 
-        // final rmsnorm
-        x = {
-            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-            x = x.mul_inplace(&self.weights.rms_final_weight)?;
-            x.with_name(format!("final_rmsnorm:{}", pos))
+    #[test]
+    fn test_generate_gqa_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl_gqa = write_gqa_model(&gf, 2, false)?;
+        let gf_gqa = gl_gqa.open()?;
+        let gl_mha = write_gqa_model(&gf, 2, true)?;
+        let gf_mha = gl_mha.open()?;
+
+        let model_gqa = CpuLlama2Model::load(&gf_gqa, CpuTensorDevice::new())?;
+        let model_mha = CpuLlama2Model::load(&gf_mha, CpuTensorDevice::new())?;
+        assert_eq!(model_gqa.conf.n_kv_heads, 2);
+        assert_eq!(model_mha.conf.n_kv_heads, model_mha.conf.n_heads);
+
+        // only the kv heads are cached
+        let generate = |lm: &CpuLlama2Model| -> Result<(String, Vec<usize>)> {
+            let mut runner = Llama2Runner::try_from(lm)?;
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let output = runner
+                .generate("Lily is a cat", 20, &mut sampler)?
+                .collect::<Result<Vec<String>>>()?
+                .join("");
+            let cache_shape = runner.kv_cache.keys(runner.seq, 0)?[0].shape().to_vec();
+            Ok((output, cache_shape))
         };
+        let (output_gqa, cache_shape) = generate(&model_gqa)?;
+        let (output_mha, _) = generate(&model_mha)?;
+        assert_eq!(cache_shape[1..], [2, model_gqa.conf.head_size()]);
+        assert_eq!(output_gqa, output_mha);
 
-        // classifier into logits
-        let logits = self.weights.wcls.matmul_vec(&x)?; // (vocab_size,
-        logits.export(&mut self.logits)?;
-        Ok(&mut self.logits)
+        assert_generate_same_on_gpu(&model_gqa)?;
+        Ok(())
     }
-}
-
-pub struct Llama2RunnerOutputGenerator<'a, T: Tensor> {
-    pos: usize,
-    steps: usize,
-    seq_len: usize,
-    prompt_tokens: Vec<usize>,
-    token: usize,
-    sampler: &'a mut Llama2Sampler,
-    runner: &'a mut Llama2Runner<T>,
-    total_time: Duration,
-}
 
-impl<'a, T: Tensor> Llama2RunnerOutputGenerator<'a, T> {
-    fn new(
-        runner: &'a mut Llama2Runner<T>,
-        sampler: &'a mut Llama2Sampler,
-        prompt: &str,
-        steps: usize,
-        seq_len: usize,
-    ) -> Result<Self> {
-        let prompt_tokens = runner.tokenizer.encode(prompt, true, false)?;
-        if prompt_tokens.is_empty() {
-            return Err(Error {
-                kind: ErrorKind::BadInput,
-                message: "something is wrong, expected at least 1 prompt token".to_string(),
-                cause: None,
-            });
+    /// rewrite the f32 llama model into stablelm, which rotates a quarter of each head and takes
+    /// the layer norms with the zero biases.
+    fn write_stablelm_model(gf: &GGUFFile) -> Result<GGUFFileLoader> {
+        let mut w = GGUFWriter::new();
+        add_layer_norm_metadata(&mut w, gf, "stablelm");
+        let head_size = gf.metadata().get_u32("llama.embedding_length").unwrap()
+            / gf.metadata().get_u32("llama.attention.head_count").unwrap();
+        w.add_metadata(
+            "stablelm.rope.dimension_count",
+            GGUFMetadataValue::U32(head_size / 4),
+        );
+        for info in gf.tensor_infos() {
+            let (name, dims) = (info.name(), info.dimensions());
+            w.add_tensor(name, dims, info.typ(), info.data())?;
+            if name.ends_with("norm.weight") {
+                let bias = name.replace(".weight", ".bias");
+                w.add_tensor(&bias, dims, GGMLType::F32, vec![0; dims[0] * 4])?;
+            }
         }
-
-        let token = prompt_tokens[0];
-        Ok(Self {
-            pos: 0,
-            steps,
-            token,
-            prompt_tokens,
-            sampler,
-            runner,
-            seq_len,
-            total_time: Duration::new(0, 0),
-        })
+        GGUFFileLoader::from_writer(&w)
     }
 
-    pub fn average_tokens_per_seconds(&self) -> f32 {
-        let total_time = self.total_time.as_secs_f32();
-        self.pos as f32 / total_time
+    #[test]
+    fn test_generate_stablelm_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_stablelm_model(&gf)?;
+        let gf = gl.open()?;
+
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(model_cpu.conf.arch, ModelArch::StableLM);
+        assert_eq!(model_cpu.conf.norm, Llama2Norm::Layer);
+        assert!(!model_cpu.conf.parallel_residual);
+        assert_eq!(model_cpu.conf.rope_dim * 4, model_cpu.conf.head_size());
+        assert_eq!(
+            model_cpu.weights().ffn_norm_bias.len(),
+            model_cpu.conf.n_layers
+        );
+        let (output, logits) = assert_generate_same_on_gpu(&model_cpu)?;
+        assert_eq!(output, ". She likes to play with her dog. She");
+        assert_relative_eq!(
+            logits[..],
+            [-6.2098083, 0.90005946, -6.209428][..],
+            epsilon = 1e-3
+        );
+        Ok(())
     }
 
-    fn forward_next(&mut self) -> Result<Option<String>> {
-        if self.pos >= self.steps + self.prompt_tokens.len() {
-            return Ok(None);
+    /// rewrite the f32 llama model into mpt, which takes the alibi instead of the rope, the fused
+    /// qkv, the gelu mlp and the output tied with the embedding table. bloom takes the default
+    /// max bias of the alibi and the layer norm of the embeddings in addition.
+    fn write_alibi_model(gf: &GGUFFile, arch: &'static str) -> Result<GGUFFileLoader> {
+        let mut w = GGUFWriter::new();
+        add_layer_norm_metadata(&mut w, gf, arch);
+        w.remove_metadata(&format!("{}.rope.dimension_count", arch));
+        if arch == "bloom" {
+            let embed_dim = gf.metadata().get_u32("llama.embedding_length").unwrap() as usize;
+            let ones = [1.0f32].repeat(embed_dim);
+            let ones = ones
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>();
+            w.add_tensor("token_embd_norm.weight", &[embed_dim], GGMLType::F32, ones)?;
+            let zeros = vec![0; embed_dim * 4];
+            w.add_tensor("token_embd_norm.bias", &[embed_dim], GGMLType::F32, zeros)?;
+        } else {
+            let key = format!("{}.attention.max_alibi_bias", arch);
+            w.add_metadata(&key, GGUFMetadataValue::F32(8.0));
         }
-        if self.pos >= self.seq_len {
-            return Ok(None);
+
+        let infos = gf
+            .tensor_infos()
+            .iter()
+            .map(|info| (info.name(), info))
+            .collect::<HashMap<_, _>>();
+        for info in gf.tensor_infos() {
+            let (name, dims) = (info.name(), info.dimensions());
+            if name.ends_with(".attn_q.weight") {
+                let k = infos[name.replace("attn_q", "attn_k").as_str()];
+                let v = infos[name.replace("attn_q", "attn_v").as_str()];
+                let data = [info.data(), k.data(), v.data()].concat();
+                let dims = [dims[0], dims[1] + k.dimensions()[1] + v.dimensions()[1]];
+                w.add_tensor(&name.replace("attn_q", "attn_qkv"), &dims, info.typ(), data)?;
+            } else if name != "output.weight"
+                && ["attn_k", "attn_v", "ffn_gate"]
+                    .iter()
+                    .all(|t| !name.contains(t))
+            {
+                w.add_tensor(name, dims, info.typ(), info.data())?;
+            }
         }
+        GGUFFileLoader::from_writer(&w)
+    }
 
-        // forward the transformer to get logits for the next token
-        let start_time = Instant::now();
-        let logits = self.runner.forward(self.token, self.pos)?;
+    #[test]
+    fn test_generate_mpt_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_alibi_model(&gf, "mpt")?;
+        let gf = gl.open()?;
 
-        // advance the state state machine
-        let (next_token, is_prompt) = if self.pos < self.prompt_tokens.len() - 1 {
-            // if we are still processing the input prompt, force the next prompt token
-            (self.prompt_tokens[self.pos + 1], true)
-        } else {
-            // otherwise sample the next token from the logits
-            let token = self.sampler.sample(logits)?;
-            (token, false)
-        };
-        self.total_time.add_assign(start_time.elapsed());
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(model_cpu.conf.arch, ModelArch::Mpt);
+        assert_eq!(model_cpu.conf.alibi_max_bias, Some(8.0));
+        assert!(model_cpu.weights().w1.is_empty());
+        assert!(model_cpu.weights().embed_norm_weight.is_none());
+        let (output, logits) = assert_generate_same_on_gpu(&model_cpu)?;
+        assert_eq!(output, "ooloolooloolooloolooloolooloolool");
+        assert_relative_eq!(
+            logits[..],
+            [2.1733296, -0.85445523, 2.1735232][..],
+            epsilon = 1e-3
+        );
+        Ok(())
+    }
 
-        // data-dependent terminating condition: the BOS (=1) token delimits sequences
-        if next_token == 1 {
-            return Ok(None);
-        }
+    #[test]
+    fn test_generate_bloom_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_alibi_model(&gf, "bloom")?;
+        let gf = gl.open()?;
 
-        let prev_token = self.token;
-        self.pos += 1;
-        self.token = next_token;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(model_cpu.conf.arch, ModelArch::Bloom);
+        assert_eq!(model_cpu.conf.alibi_max_bias, Some(8.0));
+        assert!(model_cpu.weights().embed_norm_weight.is_some());
+        assert!(model_cpu.weights().embed_norm_bias.is_some());
+        assert_generate_same_on_gpu(&model_cpu)?;
+        Ok(())
+    }
 
-        if is_prompt {
-            return Ok(Some("".to_string()));
+    #[test]
+    fn test_generate_rope_scaling() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let mut w = GGUFWriter::new();
+        w.add_metadata_of(gf.metadata());
+        w.add_metadata("llama.rope.freq_base", GGUFMetadataValue::F32(500000.0));
+        w.add_metadata("llama.rope.scale_linear", GGUFMetadataValue::F32(4.0));
+        for info in gf.tensor_infos() {
+            w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?;
         }
+        let gl = GGUFFileLoader::from_writer(&w)?;
+        let gf = gl.open()?;
 
-        Ok(Some(self.runner.tokenizer.decode(prev_token, self.token)?))
-    }
-}
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(lm.conf.rope_freq_base, 500000.0);
+        assert_eq!(lm.conf.rope_freq_scale, 0.25);
 
-impl<'a, T: Tensor> Iterator for Llama2RunnerOutputGenerator<'a, T> {
-    type Item = Result<String>;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let output = runner.generate("Lily is a cat", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, " and Ben are friends. She likes to play with");
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let r = self.forward_next().transpose();
-            if let Some(Ok(s)) = &r {
-                if s.is_empty() {
-                    continue;
-                }
-            }
-            return r;
-        }
+        // the logits of the bos token are not rotated, but the ones after it are
+        runner.forward(1, 0)?;
+        let logits = runner.forward(365, 1)?;
+        assert_relative_eq!(
+            logits[..3],
+            [-3.2197905, -0.9838857, -3.2203152][..],
+            epsilon = 1e-3
+        );
+        Ok(())
     }
-}
-
-#[cfg(test)]
-// Only run tests on aarch64
-#[cfg(target_arch = "aarch64")]
-mod tests {
-    use approx::assert_relative_eq;
-    use crabml::backends::cpu::CpuTensorDevice;
-    use crabml::backends::cpu::CpuTensorDeviceOptions;
-    use crabml::backends::wgpu::WgpuTensorDevice;
-    use crabml::backends::wgpu::WgpuTensorDeviceOptions;
-    use crabml::gguf::GGUFFileLoader;
-
-    use super::*;
 
     #[test]
-    fn test_generate_f32() -> Result<()> {
-        let gl: GGUFFileLoader =
-            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+    fn test_generate_f16() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_converted_model(&gf, GGMLType::F16)?;
         let gf = gl.open()?;
 
-        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
-            debug_named_tensors: false,
-        });
-        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device)?;
+        assert_eq!(lm.weights().wq[0].dtype(), GGMLType::F16);
 
         let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
         let mut runner = Llama2Runner::try_from(&lm)?;
         let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
         let s = output.collect::<Result<Vec<String>>>()?.join("");
-
         assert_eq!(
             s,
             " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
@@ -418,20 +3638,24 @@ mod tests {
     }
 
     #[test]
-    fn test_generate_q8_0() -> Result<()> {
-        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+    fn test_generate_q4_0() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let gl = write_converted_model(&gf, GGMLType::Q4_0)?;
         let gf = gl.open()?;
 
         let device = CpuTensorDevice::new();
         let lm = CpuLlama2Model::load(&gf, device)?;
-        assert_eq!(lm.conf().rope_dim, 48);
-        assert_eq!(lm.conf().head_size(), 48);
+        assert_eq!(lm.weights().wq[0].dtype(), GGMLType::Q4_0);
 
         let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
         let mut runner = Llama2Runner::try_from(&lm)?;
-        let output = runner.generate("Lily is a cute cat, ", 10, &mut sampler)?;
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
         let s = output.collect::<Result<Vec<String>>>()?.join("");
-        assert_eq!(s, "3 years old. She likes to play with her");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many ball of yarn in her house. She likes to chase it and catch it and"
+        );
         Ok(())
     }
 
@@ -443,6 +3667,7 @@ mod tests {
 
         let device_cpu = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
             debug_named_tensors: true,
+            ..Default::default()
         });
         let model_cpu = CpuLlama2Model::load(&gf, device_cpu.clone())?;
 
@@ -451,7 +3676,7 @@ mod tests {
                 .with_staging_buf_bytes(model_cpu.conf.vocab_size * 4)
                 .with_debug_named_tensor(true),
         );
-        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu.clone())?;
+        let model_wgpu = Llama2Model::from_cpu(&model_cpu, device_wgpu.clone())?;
 
         let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
         let mut runner_cpu = Llama2Runner::try_from(&model_cpu)?;
@@ -491,9 +3716,10 @@ mod tests {
             epsilon = 1e-4
         );
 
+        // only the last token of the prompt batch takes the final rmsnorm
         assert_relative_eq!(
-            device_cpu.dump_debug_tensor("final_rmsnorm:0").unwrap()[..],
-            device_wgpu.dump_debug_tensor("final_rmsnorm:0").unwrap()[..],
+            device_cpu.dump_debug_tensor("final_rmsnorm:5").unwrap()[..],
+            device_wgpu.dump_debug_tensor("final_rmsnorm:5").unwrap()[..],
             epsilon = 1e-2
         );
 
@@ -509,4 +3735,132 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_generate_q8_0_gpu() -> Result<()> {
+        let gl: GGUFFileLoader =
+            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the quantized weights are dequantized into f32 on the gpu
+        let device_wgpu = WgpuTensorDevice::try_new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        )?;
+        let model_wgpu = Llama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
+        let mut runner_wgpu = Llama2Runner::try_from(&model_wgpu)?;
+        let output_wgpu = runner_wgpu
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output_wgpu,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_flash_attention_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let device_wgpu = WgpuTensorDevice::try_new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        )?;
+        let model_wgpu = Llama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        // the running state of the softmax is kept across the pages of 16 rows
+        let options = Llama2KvCacheOptions {
+            page_size: 16,
+            ..Default::default()
+        };
+        let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
+        let mut runner_wgpu = Llama2Runner::try_from(&model_wgpu)?
+            .with_kv_cache_options(options)?
+            .with_flash_attention(true);
+        let output_wgpu = runner_wgpu
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output_wgpu,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_f16_activations_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let device_wgpu = WgpuTensorDevice::try_new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        )?;
+        let model_wgpu = Llama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
+        let mut runner_wgpu =
+            Llama2Runner::try_from(&model_wgpu)?.with_activation_dtype(GGMLType::F16)?;
+        let output_wgpu = runner_wgpu
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output_wgpu,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_offloaded_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // the first 2 layers run on the gpu, and the rest on the cpu
+        let device_wgpu = WgpuTensorDevice::try_new(WgpuTensorDeviceOptions::new())?;
+        let model_wgpu = Llama2Model::from_cpu_layers(&model_cpu, device_wgpu.clone(), 2)?;
+        let runner_wgpu = Llama2Runner::try_from(&model_wgpu)?;
+        let mut runner =
+            Llama2Runner::try_from(&model_cpu)?.with_offloaded_layers(runner_wgpu, 2)?;
+
+        let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
+        let output = runner
+            .generate("Lily is a cat", 30, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+
+        // the prompt shares the rows in the kv cache of both devices with the last generation
+        let prompt = "Lily is a cat who likes to play with yarn. One day";
+        let mut runner_cpu = Llama2Runner::try_from(&model_cpu)?;
+        let expected = runner_cpu
+            .generate(prompt, 20, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        let output = runner
+            .generate(prompt, 20, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output, expected);
+
+        // all the layers can not be offloaded
+        let model_wgpu = Llama2Model::from_cpu(&model_cpu, device_wgpu)?;
+        let runner_wgpu = Llama2Runner::try_from(&model_wgpu)?;
+        let n_layers = model_cpu.conf.n_layers;
+        assert!(
+            Llama2Runner::try_from(&model_cpu)?
+                .with_offloaded_layers(runner_wgpu, n_layers)
+                .is_err()
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,556 @@
+//! the logits processors are applied in a chain on the logits before sampling, each
+//! processor rewrites the logits in place, like adding a bias, or masking the tokens out
+//! with `f32::NEG_INFINITY`. implement `LogitsProcessor` to plug a custom processor into
+//! `Llama2Sampler::with_processor`.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tokenizer::BpeTokenizer;
+
+use crate::grammar::Grammar;
+use crate::grammar::GrammarState;
+use crate::sampler::softmax;
+use crate::sampler::Llama2SamplerDry;
+use crate::sampler::Llama2SamplerPenalties;
+
+pub trait LogitsProcessor {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()>;
+
+    /// called with each sampled token.
+    fn accept(&mut self, _token: usize) {}
+
+    /// called on starting a new generation with the tokens of the prompt.
+    fn reset(&mut self, _prompt: &[usize]) {}
+}
+
+pub struct LogitBiasProcessor {
+    logit_bias: HashMap<usize, f32>,
+}
+
+impl LogitBiasProcessor {
+    pub fn new(logit_bias: HashMap<usize, f32>) -> Self {
+        Self { logit_bias }
+    }
+}
+
+impl LogitsProcessor for LogitBiasProcessor {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+        for (token, bias) in self.logit_bias.iter() {
+            if let Some(logit) = logits.get_mut(*token) {
+                *logit += bias;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the penalties on the tokens appeared in the last `last_n` tokens of the prompt and the
+/// generated output.
+pub struct PenaltiesProcessor {
+    penalties: Llama2SamplerPenalties,
+    history: VecDeque<usize>,
+}
+
+impl PenaltiesProcessor {
+    pub fn new(penalties: Llama2SamplerPenalties) -> Self {
+        Self {
+            penalties,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl LogitsProcessor for PenaltiesProcessor {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+        if !self.penalties.is_enabled() {
+            return Ok(());
+        }
+        let mut counts = HashMap::new();
+        for token in self.history.iter() {
+            *counts.entry(*token).or_insert(0) += 1;
+        }
+
+        let p = &self.penalties;
+        for (token, count) in counts {
+            let Some(logit) = logits.get_mut(token) else {
+                continue;
+            };
+            if *logit > 0.0 {
+                *logit /= p.repeat;
+            } else {
+                *logit *= p.repeat;
+            }
+            *logit -= count as f32 * p.frequency + p.presence;
+        }
+        Ok(())
+    }
+
+    /// only the last `last_n` tokens are kept.
+    fn accept(&mut self, token: usize) {
+        if self.penalties.last_n == 0 {
+            return;
+        }
+        if self.history.len() >= self.penalties.last_n {
+            self.history.pop_front();
+        }
+        self.history.push_back(token);
+    }
+
+    fn reset(&mut self, prompt: &[usize]) {
+        self.history.clear();
+        for token in prompt {
+            self.accept(*token);
+        }
+    }
+}
+
+pub struct DryProcessor {
+    dry: Llama2SamplerDry,
+    is_breaker: Vec<bool>,
+    history: VecDeque<usize>,
+}
+
+impl DryProcessor {
+    pub fn new(dry: Llama2SamplerDry, tokenizer: &BpeTokenizer) -> Self {
+        let is_breaker = (0..tokenizer.vocab().len())
+            .map(|token| match tokenizer.token_text(token) {
+                Some(text) => dry
+                    .sequence_breakers
+                    .iter()
+                    .any(|b| text.contains(b.as_str())),
+                None => false,
+            })
+            .collect();
+        Self {
+            dry,
+            is_breaker,
+            history: VecDeque::new(),
+        }
+    }
+
+    fn is_breaker(&self, token: usize) -> bool {
+        self.is_breaker.get(token).copied().unwrap_or(false)
+    }
+
+    /// the length of the longest repeated sequence which each token would extend.
+    fn match_lengths(&self) -> HashMap<usize, usize> {
+        let history = &self.history;
+        let n = history.len();
+        let mut lengths = HashMap::new();
+        if n == 0 || self.is_breaker(history[n - 1]) {
+            return lengths;
+        }
+
+        // match the suffix of the history with the tokens ending at each earlier position,
+        // the token after the earlier position is the one continuing the repetition
+        for end in 0..n - 1 {
+            let mut len = 0;
+            while len <= end
+                && history[end - len] == history[n - 1 - len]
+                && !self.is_breaker(history[end - len])
+            {
+                len += 1;
+            }
+            if len == 0 {
+                continue;
+            }
+            let next = history[end + 1];
+            let max_len = lengths.entry(next).or_insert(0);
+            *max_len = len.max(*max_len);
+        }
+        lengths
+    }
+}
+
+impl LogitsProcessor for DryProcessor {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+        let dry = &self.dry;
+        for (token, len) in self.match_lengths() {
+            if len < dry.allowed_length {
+                continue;
+            }
+            if let Some(logit) = logits.get_mut(token) {
+                *logit -= dry.multiplier * dry.base.powi((len - dry.allowed_length) as i32);
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, token: usize) {
+        if self.history.len() >= self.dry.last_n {
+            self.history.pop_front();
+        }
+        self.history.push_back(token);
+    }
+
+    fn reset(&mut self, prompt: &[usize]) {
+        self.history.clear();
+        for token in prompt {
+            self.accept(*token);
+        }
+    }
+}
+
+/// divide the logits by the temperature. the lower the temperature, the more
+/// deterministic the sampling.
+pub struct TemperatureProcessor {
+    temperature: f32,
+}
+
+impl TemperatureProcessor {
+    pub fn new(temperature: f32) -> Self {
+        Self { temperature }
+    }
+}
+
+impl LogitsProcessor for TemperatureProcessor {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+        if self.temperature <= 0.0 {
+            return Err((ErrorKind::BadInput, "the temperature should be positive").into());
+        }
+        for logit in logits.iter_mut() {
+            *logit /= self.temperature;
+        }
+        Ok(())
+    }
+}
+
+/// constrain the sampled text to the grammar, the tokens which are not allowed by the
+/// grammar are masked out, and the end of the generation (bos/eos) is only allowed after
+/// the grammar is matched completely.
+pub struct GrammarProcessor {
+    state: GrammarState,
+    token_texts: Vec<Option<String>>,
+    end_tokens: Vec<usize>,
+}
+
+impl GrammarProcessor {
+    pub fn new(grammar: Grammar, tokenizer: &BpeTokenizer) -> Self {
+        let token_texts = (0..tokenizer.vocab().len())
+            .map(|token| tokenizer.token_text(token).filter(|text| !text.is_empty()))
+            .collect();
+        Self {
+            state: GrammarState::new(grammar),
+            token_texts,
+            end_tokens: vec![tokenizer.bos_token(), tokenizer.eos_token()],
+        }
+    }
+}
+
+impl LogitsProcessor for GrammarProcessor {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+        let is_accepted = self.state.is_accepted();
+        let mut allowed = false;
+        for (token, logit) in logits.iter_mut().enumerate() {
+            if *logit == f32::NEG_INFINITY {
+                continue;
+            }
+            let is_allowed = if self.end_tokens.contains(&token) {
+                is_accepted
+            } else {
+                match self.token_texts.get(token) {
+                    Some(Some(text)) => self.state.allows(text),
+                    _ => false,
+                }
+            };
+            if is_allowed {
+                allowed = true;
+            } else {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if !allowed {
+            return Err(Error {
+                kind: ErrorKind::Unexpected,
+                message: "no token is allowed by the grammar".to_string(),
+                cause: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, token: usize) {
+        if let Some(Some(text)) = self.token_texts.get(token) {
+            self.state.accept(text);
+        }
+    }
+
+    fn reset(&mut self, _prompt: &[usize]) {
+        self.state.reset();
+    }
+}
+
+/// a truncation stage on the probabilities of the candidate tokens, the stages are
+/// applied in the configured order, and the kept candidates are renormalized after each
+/// stage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Llama2SamplerStage {
+    /// keep the k most likely tokens.
+    TopK(usize),
+    /// keep the smallest set of the most likely tokens whose probabilities sum up to p.
+    TopP(f32),
+    /// keep the tokens with a probability of at least `p * max_prob`.
+    MinP(f32),
+    /// locally typical sampling: keep the tokens whose information content is closest to
+    /// the entropy of the distribution, until their probabilities sum up to p.
+    Typical(f32),
+    /// tail free sampling: cut the tail where the second derivative of the sorted
+    /// probabilities flattens out, z is the kept mass of the second derivatives.
+    TailFree(f32),
+}
+
+impl Llama2SamplerStage {
+    /// the candidates are (prob, token) sorted by the probability in descending order,
+    /// and keep sorted after the stage.
+    fn apply(&self, candidates: &mut Vec<(f32, usize)>) {
+        let len = candidates.len();
+        let keep = match *self {
+            Llama2SamplerStage::TopK(k) => k.max(1).min(len),
+            Llama2SamplerStage::TopP(p) => {
+                let mut cumulative_prob = 0.0;
+                candidates
+                    .iter()
+                    .position(|(prob, _)| {
+                        cumulative_prob += prob;
+                        cumulative_prob >= p
+                    })
+                    .map_or(len, |i| i + 1)
+            }
+            Llama2SamplerStage::MinP(p) => {
+                let cutoff = candidates[0].0 * p;
+                candidates
+                    .iter()
+                    .take_while(|(prob, _)| *prob >= cutoff)
+                    .count()
+                    .max(1)
+            }
+            Llama2SamplerStage::Typical(p) => {
+                if p >= 1.0 {
+                    return;
+                }
+                let entropy = -candidates
+                    .iter()
+                    .filter(|(prob, _)| *prob > 0.0)
+                    .map(|(prob, _)| prob * prob.ln())
+                    .sum::<f32>();
+                let mut shifted = candidates
+                    .iter()
+                    .map(|(prob, token)| ((-prob.ln() - entropy).abs(), *prob, *token))
+                    .collect::<Vec<_>>();
+                shifted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                let mut cumulative_prob = 0.0;
+                let keep = shifted
+                    .iter()
+                    .position(|(_, prob, _)| {
+                        cumulative_prob += prob;
+                        cumulative_prob > p
+                    })
+                    .map_or(len, |i| i + 1);
+                *candidates = shifted[..keep]
+                    .iter()
+                    .map(|(_, prob, token)| (*prob, *token))
+                    .collect();
+                candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+                keep
+            }
+            Llama2SamplerStage::TailFree(z) => {
+                if z >= 1.0 || len <= 2 {
+                    return;
+                }
+                let first_derivatives = candidates
+                    .windows(2)
+                    .map(|w| w[0].0 - w[1].0)
+                    .collect::<Vec<_>>();
+                let mut second_derivatives = first_derivatives
+                    .windows(2)
+                    .map(|w| (w[0] - w[1]).abs())
+                    .collect::<Vec<_>>();
+                let sum = second_derivatives.iter().sum::<f32>();
+                let n = second_derivatives.len() as f32;
+                for d in second_derivatives.iter_mut() {
+                    *d = if sum > 1e-6 { *d / sum } else { 1.0 / n };
+                }
+
+                // the same as llama.cpp, keep at least one token
+                let mut cumulative = 0.0;
+                second_derivatives
+                    .iter()
+                    .enumerate()
+                    .position(|(i, d)| {
+                        cumulative += d;
+                        cumulative > z && i >= 1
+                    })
+                    .unwrap_or(len)
+            }
+        };
+
+        candidates.truncate(keep);
+        let sum = candidates.iter().map(|(prob, _)| prob).sum::<f32>();
+        for (prob, _) in candidates.iter_mut() {
+            *prob /= sum;
+        }
+    }
+}
+
+/// the truncation stages mask out the tokens not kept by the stage.
+impl LogitsProcessor for Llama2SamplerStage {
+    fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+        let mut probs = logits.to_vec();
+        softmax(&mut probs);
+        let mut candidates = probs
+            .iter()
+            .enumerate()
+            .filter(|(_, prob)| **prob > 0.0)
+            .map(|(i, prob)| (*prob, i))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        self.apply(&mut candidates);
+
+        let mut kept = vec![false; logits.len()];
+        for (_, token) in candidates {
+            kept[token] = true;
+        }
+        for (logit, kept) in logits.iter_mut().zip(kept) {
+            if !kept {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncation_stages() -> Result<()> {
+        // returns the kept tokens ordered by the probability
+        let truncate = |probs: &[f32], stages: &[Llama2SamplerStage]| -> Result<Vec<usize>> {
+            let mut logits = probs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+            for stage in stages {
+                stage.clone().process(&mut logits)?;
+            }
+            let mut kept = (0..probs.len())
+                .filter(|i| logits[*i] != f32::NEG_INFINITY)
+                .collect::<Vec<_>>();
+            kept.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+            Ok(kept)
+        };
+
+        let probs = [0.05, 0.4, 0.1, 0.3, 0.15];
+        assert_eq!(truncate(&probs, &[])?, vec![1, 3, 4, 2, 0]);
+        assert_eq!(truncate(&probs, &[Llama2SamplerStage::TopK(2)])?, vec![
+            1, 3
+        ]);
+        assert_eq!(truncate(&probs, &[Llama2SamplerStage::TopP(0.8)])?, vec![
+            1, 3, 4
+        ]);
+        assert_eq!(truncate(&probs, &[Llama2SamplerStage::MinP(0.5)])?, vec![
+            1, 3
+        ]);
+        // the entropy is ~1.39, the information content of 0.3 (~1.20) is the closest
+        assert_eq!(
+            truncate(&probs, &[Llama2SamplerStage::Typical(0.25)])?,
+            vec![3]
+        );
+        assert_eq!(
+            truncate(&probs, &[Llama2SamplerStage::Typical(0.4)])?,
+            vec![1, 3]
+        );
+
+        // the stages are applied in order
+        let stages = [
+            Llama2SamplerStage::TopK(1),
+            Llama2SamplerStage::Typical(0.25),
+        ];
+        assert_eq!(truncate(&probs, &stages)?, vec![1]);
+        let stages = [
+            Llama2SamplerStage::Typical(0.25),
+            Llama2SamplerStage::TopK(1),
+        ];
+        assert_eq!(truncate(&probs, &stages)?, vec![3]);
+
+        // the normalized second derivatives are [0, 0.43, 0.54, 0.03], the flat tail
+        // after 0.25 is cut
+        let probs = [0.03, 0.35, 0.04, 0.3, 0.03, 0.25];
+        let stages = [Llama2SamplerStage::TailFree(0.99)];
+        assert_eq!(truncate(&probs, &stages)?, vec![1, 3, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_penalties() -> Result<()> {
+        let mut processor = PenaltiesProcessor::new(Llama2SamplerPenalties {
+            last_n: 3,
+            repeat: 2.0,
+            frequency: 0.5,
+            presence: 0.25,
+        });
+        processor.reset(&[0, 1]);
+        processor.accept(1);
+        processor.accept(3);
+
+        // the token 0 is out of the window
+        let mut logits = vec![1.0, 4.0, 2.0, -1.0];
+        processor.process(&mut logits)?;
+        assert_eq!(logits, vec![
+            1.0,
+            4.0 / 2.0 - 1.0 - 0.25,
+            2.0,
+            -2.0 - 0.5 - 0.25
+        ]);
+
+        processor.reset(&[]);
+        let mut logits = vec![1.0, 4.0, 2.0, -1.0];
+        processor.process(&mut logits)?;
+        assert_eq!(logits, vec![1.0, 4.0, 2.0, -1.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry() -> Result<()> {
+        let vocab = ["<unk>", "<s>", "</s>", "a", "b", "c", "d", ":"];
+        let tokenizer = BpeTokenizer::new(
+            vocab.iter().map(|s| s.to_string()).collect(),
+            vec![0.0; vocab.len()],
+            1,
+            2,
+        );
+        let dry = Llama2SamplerDry {
+            multiplier: 1.0,
+            base: 2.0,
+            allowed_length: 2,
+            ..Default::default()
+        };
+        let mut processor = DryProcessor::new(dry, &tokenizer);
+
+        // "a b c" is repeated by "a b", the token c would extend a repetition of the length 2,
+        // and the token d would extend the repetition of "b" which is allowed
+        processor.reset(&[3, 4, 5, 4, 6, 3, 4]);
+        let mut logits = vec![0.0; vocab.len()];
+        processor.process(&mut logits)?;
+        assert_eq!(logits, vec![0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0]);
+
+        // the penalty grows with the length of the repetition
+        processor.reset(&[3, 4, 5, 6, 3, 4, 5]);
+        let mut logits = vec![0.0; vocab.len()];
+        processor.process(&mut logits)?;
+        assert_eq!(logits, vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -2.0, 0.0]);
+
+        // the matching stops at the sequence breaker
+        processor.reset(&[7, 3, 4, 7, 3]);
+        let mut logits = vec![0.0; vocab.len()];
+        processor.process(&mut logits)?;
+        assert_eq!(logits, vec![0.0; vocab.len()]);
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDeviceRef;
+use crabml::backends::cpu::CpuTensorLoader;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::ModelTensor;
+use crabml::tensor::Tensor;
+
+use crate::model::Llama2Config;
+
+const KEY_ADAPTER_TYPE: &str = "adapter.type";
+const KEY_ADAPTER_LORA_ALPHA: &str = "adapter.lora.alpha";
+
+/// the linear weights of a layer which a lora adapter applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Llama2LoraTarget {
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnOutput,
+    FfnGate, // w1
+    FfnDown, // w2
+    FfnUp,   // w3
+}
+
+impl Llama2LoraTarget {
+    const ALL: [Self; 7] = [
+        Self::AttnQ,
+        Self::AttnK,
+        Self::AttnV,
+        Self::AttnOutput,
+        Self::FfnGate,
+        Self::FfnDown,
+        Self::FfnUp,
+    ];
+
+    /// the tensor of the weight in the base model.
+    fn model_tensor(&self) -> ModelTensor {
+        match self {
+            Self::AttnQ => ModelTensor::AttnQ,
+            Self::AttnK => ModelTensor::AttnK,
+            Self::AttnV => ModelTensor::AttnV,
+            Self::AttnOutput => ModelTensor::AttnOutput,
+            Self::FfnGate => ModelTensor::FfnGate,
+            Self::FfnDown => ModelTensor::FfnDown,
+            Self::FfnUp => ModelTensor::FfnUp,
+        }
+    }
+
+    /// the name of the weight in GGUF like `blk.0.attn_q.weight`.
+    fn tensor_name(&self, l: usize) -> String {
+        self.model_tensor().gguf_name(l)
+    }
+
+    /// the (out, in) dims of the weight.
+    fn dims(&self, conf: &Llama2Config) -> (usize, usize) {
+        match self {
+            Self::AttnQ | Self::AttnOutput => (conf.embedding_dim, conf.embedding_dim),
+            Self::AttnK | Self::AttnV => (conf.kv_dim(), conf.embedding_dim),
+            Self::FfnGate | Self::FfnUp => (conf.hidden_dim, conf.embedding_dim),
+            Self::FfnDown => (conf.embedding_dim, conf.hidden_dim),
+        }
+    }
+}
+
+/// the low rank delta of a weight, w + b @ a.
+pub struct Llama2LoraWeight<T: Tensor> {
+    pub a: T, // (rank, in)
+    pub b: T, // (out, rank)
+}
+
+/// a lora adapter fine-tuned on the base model, which adds the low rank deltas to the linear
+/// weights on the fly instead of merging them, so several adapters can be switched and stacked
+/// on the same base model. the adapter is loaded from the GGUF converted by
+/// `convert_lora_to_gguf.py` of llama.cpp.
+pub struct Llama2LoraAdapter<T: Tensor> {
+    pub alpha: f32,
+    pub weights: HashMap<(usize, Llama2LoraTarget), Llama2LoraWeight<T>>, // (layer, target)
+}
+
+impl<T: Tensor> Llama2LoraAdapter<T> {
+    pub fn get(&self, l: usize, target: Llama2LoraTarget) -> Option<&Llama2LoraWeight<T>> {
+        self.weights.get(&(l, target))
+    }
+
+    /// the multiplier of the deltas on the given scale, which is scaled by alpha / rank like
+    /// peft, or taken as is if the adapter has no alpha.
+    pub fn scale(&self, scale: f32, rank: usize) -> f32 {
+        match self.alpha {
+            alpha if alpha != 0.0 => scale * alpha / rank as f32,
+            _ => scale,
+        }
+    }
+
+    /// check the shapes of the deltas against the base model.
+    pub fn validate(&self, conf: &Llama2Config) -> Result<()> {
+        for ((l, target), w) in self.weights.iter() {
+            let (out, inp) = target.dims(conf);
+            let (sa, sb) = (w.a.strider().shape(), w.b.strider().shape());
+            let ok = *l < conf.n_layers
+                && sa.len() == 2
+                && sb.len() == 2
+                && sa[0] == sb[1]
+                && sa[1] == inp
+                && sb[0] == out;
+            if !ok {
+                return Err(Error {
+                    kind: ErrorKind::BadInput,
+                    message: format!(
+                        "the lora of {} in {:?} @ {:?} does not fit the base model",
+                        target.tensor_name(*l),
+                        sb,
+                        sa
+                    ),
+                    cause: None,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Llama2LoraAdapter<CpuTensor<'a>> {
+    pub fn load(gf: &'a GGUFFile<'a>, device: CpuTensorDeviceRef<'a>) -> Result<Self> {
+        let header = gf.header();
+        if header.get_str(KEY_ADAPTER_TYPE).ok() != Some("lora") {
+            return Err(Error {
+                kind: ErrorKind::FormatError,
+                message: "the file is not a lora adapter".to_string(),
+                cause: None,
+            });
+        }
+        let alpha = header.get_f32(KEY_ADAPTER_LORA_ALPHA).unwrap_or(0.0);
+
+        let loader = CpuTensorLoader::new(gf, device);
+        let mut weights = HashMap::new();
+        for info in gf.tensor_infos() {
+            let Some(name) = info.name().strip_suffix(".lora_a") else {
+                continue;
+            };
+            let (l, target) = name
+                .strip_prefix("blk.")
+                .and_then(|s| s.split_once('.'))
+                .and_then(|(l, _)| l.parse::<usize>().ok())
+                .and_then(|l| {
+                    Llama2LoraTarget::ALL
+                        .iter()
+                        .find(|target| target.tensor_name(l) == name)
+                        .map(|target| (l, *target))
+                })
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::NotImplemented,
+                    message: format!("the lora of {} is not supported yet", name),
+                    cause: None,
+                })?;
+            let a = loader.load_as(info.name(), GGMLType::F32)?;
+            let b = loader.load_as(&format!("{}.lora_b", name), GGMLType::F32)?;
+            weights.insert((l, target), Llama2LoraWeight { a, b });
+        }
+        Ok(Self { alpha, weights })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFWriter;
+    use crabml::gguf::KEY_GENERAL_ARCHITECTURE;
+
+    use super::*;
+    use crate::llama2::Llama2Runner;
+    use crate::sampler::Llama2Sampler;
+    use crate::CpuLlama2Model;
+
+    /// write an adapter of rank 4 on the q and the down projections of the first layer, the
+    /// deltas of b are scaled by `sign`, so the adapters of the opposite signs cancel out.
+    fn write_lora_adapter(conf: &Llama2Config, sign: f32) -> Result<GGUFFileLoader> {
+        let rank = 4;
+        let weight = |n: usize, sign: f32| -> Vec<u8> {
+            (0..n)
+                .map(|i| sign * ((i * 7 % 13) as f32 - 6.0) * 0.02)
+                .flat_map(|v| v.to_le_bytes())
+                .collect()
+        };
+        let mut tensors = vec![];
+        for target in [Llama2LoraTarget::AttnQ, Llama2LoraTarget::FfnDown] {
+            let (out, inp) = target.dims(conf);
+            let name = target.tensor_name(0);
+            let a = weight(rank * inp, 1.0);
+            let b = weight(out * rank, sign);
+            tensors.push((format!("{}.lora_a", name), [inp, rank], a));
+            tensors.push((format!("{}.lora_b", name), [rank, out], b));
+        }
+        let mut w = GGUFWriter::new();
+        w.add_metadata(KEY_GENERAL_ARCHITECTURE, GGUFMetadataValue::String("llama"));
+        w.add_metadata("general.type", GGUFMetadataValue::String("adapter"));
+        w.add_metadata(KEY_ADAPTER_TYPE, GGUFMetadataValue::String("lora"));
+        w.add_metadata(KEY_ADAPTER_LORA_ALPHA, GGUFMetadataValue::F32(8.0));
+        for (name, dims, buf) in tensors.iter() {
+            w.add_tensor(name, dims, GGMLType::F32, buf)?;
+        }
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    #[test]
+    fn test_generate_lora() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let gl_lora = write_lora_adapter(&lm.conf, 1.0)?;
+        let gf_lora = gl_lora.open()?;
+        let gl_lora_neg = write_lora_adapter(&lm.conf, -1.0)?;
+        let gf_lora_neg = gl_lora_neg.open()?;
+
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let generate = |runner: &mut Llama2Runner<_>| -> Result<String> {
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let output = runner.generate("Lily is a cat", 20, &mut sampler)?;
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+        let base = generate(&mut runner)?;
+
+        let adapter = Llama2LoraAdapter::load(&gf_lora, device.clone())?;
+        assert_eq!(adapter.alpha, 8.0);
+        assert_eq!(adapter.weights.len(), 2);
+        runner.add_lora("pos", adapter)?;
+        runner.add_lora(
+            "neg",
+            Llama2LoraAdapter::load(&gf_lora_neg, device.clone())?,
+        )?;
+        assert_eq!(runner.loras(), vec!["neg", "pos"]);
+        assert_eq!(generate(&mut runner)?, base);
+
+        // the adapter takes effect once activated, and the switch drops the forwarded tokens
+        runner.set_loras(&[("pos", 1.0)])?;
+        assert!(runner.tokens().is_empty());
+        let tuned = generate(&mut runner)?;
+        assert_ne!(tuned, base);
+        runner.set_loras(&[("pos", 0.0)])?;
+        assert_eq!(generate(&mut runner)?, base);
+
+        // the stacked adapters of the opposite signs cancel out
+        runner.set_loras(&[("pos", 1.0), ("neg", 1.0)])?;
+        assert_eq!(generate(&mut runner)?, base);
+        runner.remove_lora("neg")?;
+        assert_eq!(generate(&mut runner)?, tuned);
+        runner.set_loras(&[])?;
+        assert_eq!(generate(&mut runner)?, base);
+        assert!(runner.set_loras(&[("neg", 1.0)]).is_err());
+
+        // the adapter of the other model does not fit
+        let gl_small = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf_small = gl_small.open()?;
+        let lm_small = CpuLlama2Model::load(&gf_small, device.clone())?;
+        let mut runner_small = Llama2Runner::try_from(&lm_small)?;
+        let adapter = Llama2LoraAdapter::load(&gf_lora, device.clone())?;
+        assert!(runner_small.add_lora("pos", adapter).is_err());
+        assert!(Llama2LoraAdapter::load(&gf, device.clone()).is_err());
+
+        Ok(())
+    }
+}
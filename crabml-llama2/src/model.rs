@@ -2,20 +2,92 @@ use std::rc::Rc;
 use std::vec;
 
 use crabml::backends::cpu::CpuTensor;
-use crabml::backends::cpu::CpuTensorBuf;
 use crabml::backends::cpu::CpuTensorDeviceRef;
-use crabml::backends::wgpu::WgpuTensor;
+use crabml::backends::cpu::CpuTensorLoader;
+#[cfg(feature = "cuda")]
+use crabml::backends::cuda::CudaTensorDeviceRef;
+#[cfg(target_os = "macos")]
+use crabml::backends::metal::MetalTensorDeviceRef;
 use crabml::backends::wgpu::WgpuTensorDeviceRef;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFile;
+use crabml::gguf::ModelArch;
+use crabml::gguf::ModelTensor;
+use crabml::gguf::TensorNameMap;
+use crabml::gguf::KEY_ATTENTION_CLAMP_KQV;
+use crabml::gguf::KEY_ATTENTION_HEAD_COUNT;
+use crabml::gguf::KEY_ATTENTION_HEAD_COUNT_KV;
+use crabml::gguf::KEY_ATTENTION_LAYERNORM_EPS;
+use crabml::gguf::KEY_ATTENTION_LAYERNORM_RMS_EPS;
+use crabml::gguf::KEY_ATTENTION_MAX_ALIBI_BIAS;
+use crabml::gguf::KEY_ATTENTION_SLIDING_WINDOW;
+use crabml::gguf::KEY_BLOCK_COUNT;
+use crabml::gguf::KEY_CONTEXT_LENGTH;
+use crabml::gguf::KEY_EMBEDDING_LENGTH;
+use crabml::gguf::KEY_EXPERT_COUNT;
+use crabml::gguf::KEY_EXPERT_USED_COUNT;
+use crabml::gguf::KEY_FEED_FORWARD_LENGTH;
+use crabml::gguf::KEY_POOLING_TYPE;
+use crabml::gguf::KEY_RESCALE_EVERY_N_LAYERS;
+use crabml::gguf::KEY_ROPE_DIMENSION_COUNT;
+use crabml::gguf::KEY_ROPE_FREQ_BASE;
+use crabml::gguf::KEY_ROPE_SCALE_LINEAR;
+use crabml::gguf::KEY_ROPE_SCALING_ATTN_FACTOR;
+use crabml::gguf::KEY_ROPE_SCALING_FACTOR;
+use crabml::gguf::KEY_ROPE_SCALING_ORIG_CTX_LEN;
+use crabml::gguf::KEY_ROPE_SCALING_TYPE;
+use crabml::gguf::KEY_TOKENIZER_LIST;
+use crabml::gguf::KEY_WKV_HEAD_SIZE;
+use crabml::safetensors::SafetensorsFile;
+use crabml::tensor::RopeMode;
+use crabml::tensor::RopeScaling;
 use crabml::tensor::Tensor;
+use crabml::tensor::TensorBackend;
 use crabml::tokenizer::BpeTokenizer;
 
+use crate::rwkv::RwkvWeights;
+
+/// the normalization before the attention and the ffn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Llama2Norm {
+    Rms,
+    // the layer norm with the optional biases, like phi2
+    Layer,
+}
+
+/// how the hidden states of the tokens are pooled into the embedding of the text, in the
+/// order of `pooling_type` in GGUF.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Llama2Pooling {
+    None,
+    Mean,
+    // the hidden state of the first token, which is the cls token on bert
+    Cls,
+    Last,
+}
+
+impl Llama2Pooling {
+    pub fn from_gguf(pooling_type: u32) -> Result<Self> {
+        match pooling_type {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Mean),
+            2 => Ok(Self::Cls),
+            3 => Ok(Self::Last),
+            _ => Err(Error {
+                kind: ErrorKind::NotImplemented,
+                message: format!("the pooling type {} is not supported yet", pooling_type),
+                cause: None,
+            }),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Llama2Config {
+    pub arch: ModelArch,
     pub embedding_dim: usize, // the dim of embedding
     pub hidden_dim: usize,
     pub n_layers: usize,
@@ -23,8 +95,30 @@ pub struct Llama2Config {
     pub n_kv_heads: usize,
     pub vocab_size: usize,
     pub seq_len: usize,
-    pub rms_norm_eps: f32,
+    pub norm: Llama2Norm,
+    pub norm_eps: f32,
+    // the attention and the ffn take the same normed input on the parallel blocks, like phi2
+    pub parallel_residual: bool,
+    // the dims to rotate in each head, which may be less than the head size, like phi2
     pub rope_dim: usize,
+    pub rope_mode: RopeMode,
+    pub rope_freq_base: f32,
+    // the multiplier of the positions on the linear scaling, like the long context codellama
+    pub rope_freq_scale: f32,
+    pub rope_scaling: RopeScaling,
+    // scales the cos and sin of the rope, like the LongRope of phi3
+    pub rope_attn_factor: f32,
+    // the linear biases on the attention scores instead of the rope, like mpt
+    pub alibi_max_bias: Option<f32>,
+    // attend only to the last n positions, like mistral
+    pub sliding_window: Option<usize>,
+    // the experts of the ffn and the ones routed to each token, like mixtral, 0 on the dense models
+    pub n_experts: usize,
+    pub n_experts_used: usize,
+    // halve the hidden state after every n layers, like rwkv, 0 if none
+    pub rescale_every_n_layers: usize,
+    // the pooling of the embeddings on the encoders like bert
+    pub pooling: Llama2Pooling,
 }
 
 impl Llama2Config {
@@ -35,27 +129,302 @@ impl Llama2Config {
     pub fn head_size(&self) -> usize {
         self.embedding_dim / self.n_heads
     }
+
+    /// the rows kept in the kv cache, which rolls over the sliding window if there is one.
+    pub fn kv_cache_len(&self) -> usize {
+        self.sliding_window
+            .map_or(self.seq_len, |window| window.min(self.seq_len))
+    }
+
+    /// the encoders attend to all the tokens at once without the kv cache, and run on
+    /// `BertModel` instead of the runner.
+    pub fn is_encoder(&self) -> bool {
+        matches!(self.arch, ModelArch::Bert | ModelArch::NomicBert)
+    }
+
+    /// the layers which keep a kv cache, rwkv keeps the recurrent state instead.
+    pub fn n_kv_cache_layers(&self) -> usize {
+        match self.arch {
+            ModelArch::Rwkv6 => 0,
+            _ => self.n_layers,
+        }
+    }
+
+    /// the rope mode of the architectures, the ones without the rope take the normal mode
+    /// which is never used.
+    pub fn rope_mode_of(arch: ModelArch) -> Result<RopeMode> {
+        match arch {
+            // mpt and bloom take no rope, but the alibi, rwkv takes no attention at all, and
+            // bert takes the position embeddings
+            ModelArch::Llama
+            | ModelArch::Mpt
+            | ModelArch::Bloom
+            | ModelArch::Rwkv6
+            | ModelArch::Bert => Ok(RopeMode::Normal),
+            ModelArch::NomicBert
+            | ModelArch::Qwen2
+            | ModelArch::Phi2
+            | ModelArch::Phi3
+            | ModelArch::Falcon
+            | ModelArch::StableLM => Ok(RopeMode::Neox),
+        }
+    }
+
+    /// the attention and the ffn run in parallel on these architectures.
+    pub fn parallel_residual_of(arch: ModelArch) -> bool {
+        matches!(arch, ModelArch::Phi2 | ModelArch::Falcon)
+    }
+
+    pub fn norm_of(arch: ModelArch) -> Llama2Norm {
+        match arch {
+            ModelArch::Phi2
+            | ModelArch::Falcon
+            | ModelArch::StableLM
+            | ModelArch::Mpt
+            | ModelArch::Bloom
+            | ModelArch::Rwkv6
+            | ModelArch::Bert
+            | ModelArch::NomicBert => Llama2Norm::Layer,
+            _ => Llama2Norm::Rms,
+        }
+    }
+
+    /// parse the config.json of a huggingface llama or qwen2 checkpoint.
+    pub fn from_hf_config(json: &str) -> Result<Self> {
+        let config: serde_json::Value = serde_json::from_str(json).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "failed to parse the huggingface config".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+        let get_usize = |key: &str| -> Result<usize> {
+            config[key]
+                .as_u64()
+                .map(|v| v as usize)
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!("missing integer {} in the huggingface config", key),
+                    cause: None,
+                })
+        };
+
+        let arch = match config["model_type"].as_str().unwrap_or("llama") {
+            "llama" | "mistral" | "mixtral" => ModelArch::Llama,
+            "qwen2" => ModelArch::Qwen2,
+            model_type => {
+                return Err(Error {
+                    kind: ErrorKind::NotImplemented,
+                    message: format!("the model type {} is not supported yet", model_type),
+                    cause: None,
+                });
+            }
+        };
+        let embedding_dim = get_usize("hidden_size")?;
+        let n_heads = get_usize("num_attention_heads")?;
+        let n_kv_heads = get_usize("num_key_value_heads").unwrap_or(n_heads);
+        // only the linear and the yarn scaling of the rope are taken from the config yet
+        let rope_scaling = &config["rope_scaling"];
+        let rope_scaling_type = rope_scaling["type"]
+            .as_str()
+            .or(rope_scaling["rope_type"].as_str());
+        let orig_ctx_len = rope_scaling["original_max_position_embeddings"]
+            .as_u64()
+            .map_or(get_usize("max_position_embeddings"), |len| Ok(len as usize))?;
+        let (rope_scaling, rope_freq_scale) =
+            match (rope_scaling_type, rope_scaling["factor"].as_f64()) {
+                (Some("linear"), Some(factor)) if factor > 0.0 => {
+                    (RopeScaling::Linear, 1.0 / factor as f32)
+                }
+                (Some("yarn"), Some(factor)) if factor > 0.0 => {
+                    (RopeScaling::Yarn { orig_ctx_len }, 1.0 / factor as f32)
+                }
+                _ => (RopeScaling::Linear, 1.0),
+            };
+        // qwen2 has a sliding window in the config but disables it by default
+        let sliding_window = match config["use_sliding_window"].as_bool() {
+            Some(false) => None,
+            _ => config["sliding_window"].as_u64().map(|v| v as usize),
+        };
+        Ok(Self {
+            arch,
+            embedding_dim,
+            hidden_dim: get_usize("intermediate_size")?,
+            n_layers: get_usize("num_hidden_layers")?,
+            n_heads,
+            n_kv_heads,
+            vocab_size: get_usize("vocab_size")?,
+            seq_len: get_usize("max_position_embeddings")?,
+            norm: Llama2Norm::Rms,
+            norm_eps: config["rms_norm_eps"].as_f64().unwrap_or(1e-5) as f32,
+            parallel_residual: false,
+            rope_dim: embedding_dim / n_heads,
+            rope_mode: Self::rope_mode_of(arch)?,
+            rope_freq_base: config["rope_theta"].as_f64().unwrap_or(10000.0) as f32,
+            rope_freq_scale,
+            rope_scaling,
+            rope_attn_factor: 1.0,
+            alibi_max_bias: None,
+            sliding_window,
+            n_experts: get_usize("num_local_experts").unwrap_or(0),
+            n_experts_used: get_usize("num_experts_per_tok").unwrap_or(0),
+            rescale_every_n_layers: 0,
+            pooling: Llama2Pooling::None,
+        })
+    }
 }
 
 pub struct Llama2Weights<T: Tensor> {
     // token embedding table
     pub token_embedding_table: T, // (vocab_size, dim)
+    // (optional) the norm of the embeddings before the first layer like bloom
+    pub embed_norm_weight: Option<T>, // (dim, )
+    pub embed_norm_bias: Option<T>,   // (dim, )
     // weights for rmsnorms
     pub rms_att_weight: Vec<T>, // (layer, dim) rmsnorm weights
-    pub rms_ffn_weight: Vec<T>, // (layer, dim)
+    pub rms_ffn_weight: Vec<T>, // (layer, dim), empty on the parallel blocks without attn_norm_2
+    // (optional) biases of the layer norms like phi2, empty if the model has none
+    pub att_norm_bias: Vec<T>, // (layer, dim)
+    pub ffn_norm_bias: Vec<T>, // (layer, dim)
     // weights for matmuls
     pub wq: Vec<T>, // (layer, embedding_dim, embedding_dim)
     pub wk: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wv: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wo: Vec<T>, // (layer, embedding_dim, embedding_dim)
-    // weights for ffn
+    // (optional) biases of the qkv projections like qwen2, empty if the model has none
+    pub bq: Vec<T>, // (layer, embedding_dim)
+    pub bk: Vec<T>, // (layer, kv_dim)
+    pub bv: Vec<T>, // (layer, kv_dim)
+    pub bo: Vec<T>, // (layer, embedding_dim)
+    // weights for ffn, w1 is empty on the gelu mlp like phi2, which has the up and down only
     pub w1: Vec<T>, // (layer, hidden_dim, embedding_dim)
     pub w2: Vec<T>, // (layer, embedding_dim, hidden_dim)
     pub w3: Vec<T>, // (layer, hidden_dim, embedding_dim)
+    // (optional) biases of the gelu mlp, empty if the model has none
+    pub b2: Vec<T>, // (layer, embedding_dim)
+    pub b3: Vec<T>, // (layer, hidden_dim)
+    // weights for the mixture of experts ffn, which replaces the ffn above on the moe models
+    pub ffn_gate_inp: Vec<T>, // (layer, n_experts, embedding_dim)
+    pub w1_exps: Vec<Vec<T>>, // (layer, n_experts, hidden_dim, embedding_dim)
+    pub w2_exps: Vec<Vec<T>>, // (layer, n_experts, embedding_dim, hidden_dim)
+    pub w3_exps: Vec<Vec<T>>, // (layer, n_experts, hidden_dim, embedding_dim)
     // final rmsnorm
-    pub rms_final_weight: T, // (dim, )
+    pub rms_final_weight: T,        // (dim, )
+    pub final_norm_bias: Option<T>, // (dim, )
     // (optional) classifier weights for the logits, on the last layer
-    pub wcls: T, // (vocab_size, dim)
+    pub wcls: T,         // (vocab_size, dim)
+    pub bcls: Option<T>, // (vocab_size, )
+    // the divisors of the rope frequencies like phi3, empty if the model has none
+    pub rope_freq_factors: Vec<f32>, // (rope_dim / 2, )
+    // the recurrent layers which replace the transformer layers above, like rwkv
+    pub rwkv: Option<RwkvWeights<T>>,
+}
+
+impl<T: Tensor> Llama2Weights<T> {
+    /// convert the weights onto another device, like wgpu.
+    pub fn convert<U: Tensor>(&self, f: impl Fn(&T) -> Result<U>) -> Result<Llama2Weights<U>> {
+        self.convert_with(usize::MAX, &f, &f)
+    }
+
+    /// convert the weights of the first `n_layers` layers only, like offloading them to the
+    /// gpu. the weights out of the layers, like the embeddings and the classifier, are never
+    /// taken by the offloaded layers, so they're replaced by the placeholders of `empty`.
+    pub fn convert_layers<U: Tensor>(
+        &self,
+        n_layers: usize,
+        f: impl Fn(&T) -> Result<U>,
+        empty: impl Fn() -> Result<U>,
+    ) -> Result<Llama2Weights<U>> {
+        self.convert_with(n_layers, &f, &|_| empty())
+    }
+
+    fn convert_with<U: Tensor>(
+        &self,
+        n_layers: usize,
+        f: &impl Fn(&T) -> Result<U>,
+        g: &impl Fn(&T) -> Result<U>,
+    ) -> Result<Llama2Weights<U>> {
+        let weights = self;
+        let convert_layers = |tensors: &[T]| {
+            tensors
+                .iter()
+                .take(n_layers)
+                .map(f)
+                .collect::<Result<Vec<_>>>()
+        };
+        let convert_optional = |t: &Option<T>| t.as_ref().map(g).transpose();
+        let convert_experts = |experts: &[Vec<T>]| {
+            experts
+                .iter()
+                .take(n_layers)
+                .map(|layer| layer.iter().map(f).collect::<Result<Vec<_>>>())
+                .collect::<Result<Vec<_>>>()
+        };
+        let token_embedding_table = g(&weights.token_embedding_table)?;
+        let embed_norm_weight = convert_optional(&weights.embed_norm_weight)?;
+        let embed_norm_bias = convert_optional(&weights.embed_norm_bias)?;
+        let wq = convert_layers(&weights.wq)?;
+        let wk = convert_layers(&weights.wk)?;
+        let wv = convert_layers(&weights.wv)?;
+        let wo = convert_layers(&weights.wo)?;
+        let bq = convert_layers(&weights.bq)?;
+        let bk = convert_layers(&weights.bk)?;
+        let bv = convert_layers(&weights.bv)?;
+        let bo = convert_layers(&weights.bo)?;
+        let w1 = convert_layers(&weights.w1)?;
+        let w2 = convert_layers(&weights.w2)?;
+        let w3 = convert_layers(&weights.w3)?;
+        let b2 = convert_layers(&weights.b2)?;
+        let b3 = convert_layers(&weights.b3)?;
+        let ffn_gate_inp = convert_layers(&weights.ffn_gate_inp)?;
+        let w1_exps = convert_experts(&weights.w1_exps)?;
+        let w2_exps = convert_experts(&weights.w2_exps)?;
+        let w3_exps = convert_experts(&weights.w3_exps)?;
+        let rms_att_weight = convert_layers(&weights.rms_att_weight)?;
+        let rms_ffn_weight = convert_layers(&weights.rms_ffn_weight)?;
+        let att_norm_bias = convert_layers(&weights.att_norm_bias)?;
+        let ffn_norm_bias = convert_layers(&weights.ffn_norm_bias)?;
+        let rms_final_weight = g(&weights.rms_final_weight)?;
+        let final_norm_bias = convert_optional(&weights.final_norm_bias)?;
+        let wcls = g(&weights.wcls)?;
+        let bcls = convert_optional(&weights.bcls)?;
+        let rwkv = weights
+            .rwkv
+            .as_ref()
+            .map(|rwkv| rwkv.convert(f))
+            .transpose()?;
+        let weights = Llama2Weights {
+            token_embedding_table,
+            embed_norm_weight,
+            embed_norm_bias,
+            wq,
+            wk,
+            wv,
+            wo,
+            bq,
+            bk,
+            bv,
+            bo,
+            w1,
+            w2,
+            w3,
+            b2,
+            b3,
+            ffn_gate_inp,
+            w1_exps,
+            w2_exps,
+            w3_exps,
+            rms_att_weight,
+            rms_ffn_weight,
+            att_norm_bias,
+            ffn_norm_bias,
+            rms_final_weight,
+            final_norm_bias,
+            wcls,
+            bcls,
+            rope_freq_factors: weights.rope_freq_factors.clone(),
+            rwkv,
+        };
+        Ok(weights)
+    }
 }
 
 pub struct CpuLlama2Model<'a> {
@@ -67,9 +436,24 @@ pub struct CpuLlama2Model<'a> {
 
 impl<'a> CpuLlama2Model<'a> {
     pub fn load(gf: &'a GGUFFile<'a>, device: CpuTensorDeviceRef<'a>) -> Result<Self> {
-        let conf = Self::load_config(gf);
-        let weights = Self::load_weights(gf, conf.n_layers, device.clone())?;
-        let tokenizer = Self::load_tokenizer(gf);
+        let _span = tracing::debug_span!(
+            "load_model",
+            arch = gf.architecture(),
+            n_tensors = gf.tensor_infos().len()
+        )
+        .entered();
+        let conf = Self::load_config(gf)?;
+        if conf.is_encoder() {
+            return Err(Error {
+                kind: ErrorKind::NotImplemented,
+                message: format!("the encoder {} runs on BertModel instead", conf.arch),
+                cause: None,
+            });
+        }
+        let names = TensorNameMap::new(conf.arch, conf.n_layers).with_experts(conf.n_experts);
+        let weights = Self::load_weights(gf, &conf, &names, device.clone())
+            .map_err(|err| names.explain_error(gf, err))?;
+        let tokenizer = BpeTokenizer::from_gguf(gf)?;
         Ok(Self {
             conf,
             weights: Rc::new(weights),
@@ -78,6 +462,38 @@ impl<'a> CpuLlama2Model<'a> {
         })
     }
 
+    /// load the weights from an unconverted huggingface checkpoint in safetensors. the weights
+    /// are copied into f32, and the q, k projections are permuted into the layout of GGUF, which
+    /// is expected by the rope in crabml.
+    pub fn load_safetensors(
+        sf: &'a SafetensorsFile<'a>,
+        conf: Llama2Config,
+        tokenizer: BpeTokenizer,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Self> {
+        let _span = tracing::debug_span!("load_model", arch = %conf.arch).entered();
+        let weights = Self::load_safetensors_weights(sf, &conf, device.clone())?;
+        Ok(Self {
+            conf,
+            weights: Rc::new(weights),
+            device,
+            tokenizer: Rc::new(tokenizer),
+        })
+    }
+
+    /// replace the tokenizer loaded from the model, like the one from a tokenizer.json.
+    pub fn with_tokenizer(mut self, tokenizer: BpeTokenizer) -> Self {
+        self.tokenizer = Rc::new(tokenizer);
+        self
+    }
+
+    /// shrink the context to save the memory of the kv cache, it's capped at the context
+    /// length of the model.
+    pub fn with_context_size(mut self, n_ctx: usize) -> Self {
+        self.conf.seq_len = n_ctx.clamp(1, self.conf.seq_len);
+        self
+    }
+
     pub fn conf(&self) -> &Llama2Config {
         &self.conf
     }
@@ -90,165 +506,407 @@ impl<'a> CpuLlama2Model<'a> {
         self.tokenizer.clone()
     }
 
+    /// the tensors dequantized into f32 on loading: the embeddings, the norms and the
+    /// biases, which are dequantized on the thread pool ahead of loading them one by one.
+    /// the fused biases are split before the dequantization.
+    pub(crate) fn preload_tensors(gf: &'a GGUFFile<'a>) -> Vec<&'a str> {
+        gf.tensor_infos()
+            .iter()
+            .filter(|info| match ModelTensor::from_gguf_name(info.name()) {
+                Some((
+                    ModelTensor::TokenEmbd | ModelTensor::TokenTypes | ModelTensor::PosEmbd,
+                    _,
+                )) => true,
+                Some((ModelTensor::AttnQkvBias, _)) => false,
+                Some(_) => info.dimensions().len() == 1,
+                None => false,
+            })
+            .map(|info| info.name())
+            .collect()
+    }
+
     fn load_weights(
         gf: &'a GGUFFile<'a>,
-        n_layers: usize,
+        conf: &Llama2Config,
+        names: &TensorNameMap,
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<Llama2Weights<CpuTensor<'a>>> {
+        let loader = CpuTensorLoader::new(gf, device);
+        loader.preload_f32(&Self::preload_tensors(gf))?;
+        let contains =
+            |tensor: ModelTensor, layer: usize| loader.contains(&names.name(tensor, layer));
+        let load = |tensor: ModelTensor, layer: usize| loader.load(&names.name(tensor, layer));
+        let load_f32 = |tensor: ModelTensor, layer: usize| {
+            loader.load_as(&names.name(tensor, layer), GGMLType::F32)
+        };
+        // the experts are stacked in `ffn_gate_exps`, or stored one by one like `ffn_gate.0` in
+        // the older mixtral files
+        let load_experts = |layer: usize, tensor: ModelTensor| -> Result<Vec<CpuTensor<'a>>> {
+            if contains(tensor, layer) {
+                return loader.load_experts(&names.name(tensor, layer));
+            }
+            (0..conf.n_experts)
+                .map(|expert| loader.load(&names.expert_name(tensor, layer, expert)))
+                .collect()
+        };
+
         // [64 (dim), 512 (vocab_size)]
-        let token_embedding_table = Self::load_tensor(gf, "token_embd.weight", device.clone())?
-            .dequantize(GGMLType::F32)?;
+        let token_embedding_table = load_f32(ModelTensor::TokenEmbd, 0)?;
         let mut wq = vec![];
         let mut wk = vec![];
         let mut wv = vec![];
         let mut wo = vec![];
+        let mut bq = vec![];
+        let mut bk = vec![];
+        let mut bv = vec![];
+        let mut bo = vec![];
         let mut w1 = vec![];
         let mut w2 = vec![];
         let mut w3 = vec![];
+        let mut b2 = vec![];
+        let mut b3 = vec![];
+        let mut ffn_gate_inp = vec![];
+        let mut w1_exps = vec![];
+        let mut w2_exps = vec![];
+        let mut w3_exps = vec![];
         let mut rms_att_weight = vec![];
         let mut rms_ffn_weight = vec![];
-        for layer in 0..n_layers {
-            wq.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_q.weight", layer),
-                device.clone(),
-            )?);
-            wk.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_k.weight", layer),
-                device.clone(),
-            )?);
-            wv.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_v.weight", layer),
-                device.clone(),
-            )?);
-            wo.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_output.weight", layer),
-                device.clone(),
-            )?);
-            // (hidden_dim:172, embedding_dim:64)
-            w1.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.ffn_gate.weight", layer),
-                device.clone(),
-            )?);
-            w2.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.ffn_down.weight", layer),
-                device.clone(),
-            )?);
-            w3.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.ffn_up.weight", layer),
-                device.clone(),
-            )?);
-            rms_att_weight.push(
-                Self::load_tensor(
-                    gf,
-                    &format!("blk.{}.attn_norm.weight", layer),
-                    device.clone(),
-                )?
-                .dequantize(GGMLType::F32)?,
-            );
-            rms_ffn_weight.push(
-                Self::load_tensor(
-                    gf,
-                    &format!("blk.{}.ffn_norm.weight", layer),
-                    device.clone(),
-                )?
-                .dequantize(GGMLType::F32)?,
-            );
+        let mut att_norm_bias = vec![];
+        let mut ffn_norm_bias = vec![];
+        let load_bias =
+            |tensor: ModelTensor, layer: usize, biases: &mut Vec<CpuTensor<'a>>| -> Result<()> {
+                if contains(tensor, layer) {
+                    biases.push(load_f32(tensor, layer)?);
+                }
+                Ok(())
+            };
+        let qkv_rows = [conf.embedding_dim, conf.kv_dim(), conf.kv_dim()];
+        // the recurrent layers of rwkv take the place of the transformer layers
+        let rwkv = match conf.arch {
+            ModelArch::Rwkv6 => Some(RwkvWeights::load(&loader, names, conf)?),
+            _ => None,
+        };
+        let n_transformer_layers = match rwkv {
+            Some(_) => 0,
+            None => conf.n_layers,
+        };
+        // the embeddings may be normed before the first layer like bloom, rwkv norms them in
+        // its own weights
+        let mut embed_norm_weight = vec![];
+        let mut embed_norm_bias = vec![];
+        if rwkv.is_none() && contains(ModelTensor::TokenEmbdNorm, 0) {
+            embed_norm_weight.push(load_f32(ModelTensor::TokenEmbdNorm, 0)?);
+            load_bias(ModelTensor::TokenEmbdNormBias, 0, &mut embed_norm_bias)?;
+        }
+        for layer in 0..n_transformer_layers {
+            // the q, k, v projections may be fused in one tensor, like phi
+            if contains(ModelTensor::AttnQkv, layer) {
+                let qkv = names.name(ModelTensor::AttnQkv, layer);
+                let mut qkv = loader.load_split(&qkv, &qkv_rows)?;
+                wv.push(qkv.pop().unwrap());
+                wk.push(qkv.pop().unwrap());
+                wq.push(qkv.pop().unwrap());
+            } else {
+                wq.push(load(ModelTensor::AttnQ, layer)?);
+                wk.push(load(ModelTensor::AttnK, layer)?);
+                wv.push(load(ModelTensor::AttnV, layer)?);
+            }
+            wo.push(load(ModelTensor::AttnOutput, layer)?);
+            if contains(ModelTensor::AttnQkvBias, layer) {
+                let qkv_bias = names.name(ModelTensor::AttnQkvBias, layer);
+                let mut qkv = loader.load_split(&qkv_bias, &qkv_rows)?;
+                bv.push(qkv.pop().unwrap().dequantize(GGMLType::F32)?);
+                bk.push(qkv.pop().unwrap().dequantize(GGMLType::F32)?);
+                bq.push(qkv.pop().unwrap().dequantize(GGMLType::F32)?);
+            } else if contains(ModelTensor::AttnQBias, layer) {
+                bq.push(load_f32(ModelTensor::AttnQBias, layer)?);
+                bk.push(load_f32(ModelTensor::AttnKBias, layer)?);
+                bv.push(load_f32(ModelTensor::AttnVBias, layer)?);
+            }
+            load_bias(ModelTensor::AttnOutputBias, layer, &mut bo)?;
+            if conf.n_experts > 0 {
+                ffn_gate_inp.push(load(ModelTensor::FfnGateInp, layer)?);
+                w1_exps.push(load_experts(layer, ModelTensor::FfnGateExps)?);
+                w2_exps.push(load_experts(layer, ModelTensor::FfnDownExps)?);
+                w3_exps.push(load_experts(layer, ModelTensor::FfnUpExps)?);
+            } else if contains(ModelTensor::FfnGate, layer) {
+                // (hidden_dim:172, embedding_dim:64)
+                w1.push(load(ModelTensor::FfnGate, layer)?);
+                w2.push(load(ModelTensor::FfnDown, layer)?);
+                w3.push(load(ModelTensor::FfnUp, layer)?);
+            } else {
+                // the gate may be fused in the first half of ffn_up like phi3, otherwise the
+                // ffn is a gelu mlp like phi2
+                let up = names.name(ModelTensor::FfnUp, layer);
+                if loader.load(&up)?.shape()[0] == conf.hidden_dim * 2 {
+                    let mut gate_up =
+                        loader.load_split(&up, &[conf.hidden_dim, conf.hidden_dim])?;
+                    w3.push(gate_up.pop().unwrap());
+                    w1.push(gate_up.pop().unwrap());
+                } else {
+                    w3.push(loader.load(&up)?);
+                }
+                w2.push(load(ModelTensor::FfnDown, layer)?);
+                load_bias(ModelTensor::FfnUpBias, layer, &mut b3)?;
+                load_bias(ModelTensor::FfnDownBias, layer, &mut b2)?;
+            }
+            rms_att_weight.push(load_f32(ModelTensor::AttnNorm, layer)?);
+            load_bias(ModelTensor::AttnNormBias, layer, &mut att_norm_bias)?;
+            // the parallel blocks share the attention norm with the ffn, unless there's a
+            // separate one like falcon-40b
+            if conf.parallel_residual && contains(ModelTensor::AttnNorm2, layer) {
+                rms_ffn_weight.push(load_f32(ModelTensor::AttnNorm2, layer)?);
+                load_bias(ModelTensor::AttnNorm2Bias, layer, &mut ffn_norm_bias)?;
+            } else if !conf.parallel_residual {
+                rms_ffn_weight.push(load_f32(ModelTensor::FfnNorm, layer)?);
+                load_bias(ModelTensor::FfnNormBias, layer, &mut ffn_norm_bias)?;
+            }
+        }
+        let rms_final_weight = load_f32(ModelTensor::OutputNorm, 0)?;
+        let mut final_norm_bias = vec![];
+        load_bias(ModelTensor::OutputNormBias, 0, &mut final_norm_bias)?;
+        // the output weights may be tied with the embedding table
+        let wcls = match contains(ModelTensor::Output, 0) {
+            true => load(ModelTensor::Output, 0)?,
+            false => load(ModelTensor::TokenEmbd, 0)?,
+        };
+        let mut bcls = vec![];
+        load_bias(ModelTensor::OutputBias, 0, &mut bcls)?;
+
+        // the LongRope of phi3 takes the long factors beyond the original context length
+        let orig_ctx_len = gf
+            .header()
+            .get_u32(KEY_ROPE_SCALING_ORIG_CTX_LEN)
+            .map_or(conf.seq_len, |len| len as usize);
+        let rope_factors = match conf.seq_len > orig_ctx_len {
+            true => ModelTensor::RopeFactorsLong,
+            false => ModelTensor::RopeFactorsShort,
+        };
+        let mut rope_freq_factors = vec![];
+        if contains(rope_factors, 0) {
+            let factors = load_f32(rope_factors, 0)?;
+            rope_freq_factors = vec![0.0; factors.shape().iter().product()];
+            factors.export(&mut rope_freq_factors)?;
         }
-        let rms_final_weight = Self::load_tensor(gf, "output_norm.weight", device.clone())?
-            .dequantize(GGMLType::F32)?;
-        let wcls = Self::load_tensor(gf, "output.weight", device.clone())?;
         Ok(Llama2Weights {
             token_embedding_table,
+            embed_norm_weight: embed_norm_weight.pop(),
+            embed_norm_bias: embed_norm_bias.pop(),
             wq,
             wk,
             wv,
             wo,
+            bq,
+            bk,
+            bv,
+            bo,
             w1,
             w2,
             w3,
+            b2,
+            b3,
+            ffn_gate_inp,
+            w1_exps,
+            w2_exps,
+            w3_exps,
             rms_att_weight,
             rms_ffn_weight,
+            att_norm_bias,
+            ffn_norm_bias,
             rms_final_weight,
+            final_norm_bias: final_norm_bias.pop(),
             wcls,
+            bcls: bcls.pop(),
+            rope_freq_factors,
+            rwkv,
         })
     }
 
-    pub(crate) fn load_tensor(
-        gf: &'a GGUFFile<'a>,
-        name: &str,
+    fn load_safetensors_weights(
+        sf: &'a SafetensorsFile<'a>,
+        conf: &Llama2Config,
         device: CpuTensorDeviceRef<'a>,
-    ) -> Result<CpuTensor<'a>> {
-        let info = match gf.get_tensor_info(name) {
-            None => {
-                return Err(Error {
-                    kind: ErrorKind::IOError,
-                    message: format!("failed to find tensor {}", name),
-                    cause: None,
-                });
-            }
-            Some(info) => info.clone(),
+    ) -> Result<Llama2Weights<CpuTensor<'a>>> {
+        if conf.n_experts > 0 {
+            return Err(Error {
+                kind: ErrorKind::NotImplemented,
+                message: "the mixture of experts is not supported in safetensors yet".to_string(),
+                cause: None,
+            });
+        }
+        let load = |tensor: ModelTensor, layer: usize| -> Result<CpuTensor<'a>> {
+            let info = match sf.get_model_tensor(tensor, layer) {
+                Some(info) => info,
+                // the output weights may be tied with the embedding table
+                None if tensor == ModelTensor::Output => {
+                    sf.get_model_tensor(ModelTensor::TokenEmbd, 0).unwrap()
+                }
+                None => {
+                    return Err(Error {
+                        kind: ErrorKind::IOError,
+                        message: format!(
+                            "failed to find tensor {}",
+                            tensor
+                                .hf_name(layer)
+                                .unwrap_or_else(|| tensor.gguf_name(layer))
+                        ),
+                        cause: None,
+                    });
+                }
+            };
+            let buf = info.to_f32_vec()?;
+            // the neox rope takes the rotary pairs in the layout of huggingface
+            let buf = match (tensor, conf.rope_mode) {
+                (ModelTensor::AttnQ, RopeMode::Normal) => {
+                    permute_hf_rows(buf, info.shape(), conf.n_heads)
+                }
+                (ModelTensor::AttnK, RopeMode::Normal) => {
+                    permute_hf_rows(buf, info.shape(), conf.n_kv_heads)
+                }
+                _ => buf,
+            };
+            CpuTensor::new(buf, info.shape(), device.clone())
         };
 
-        // the dimensions stored in GGUF seems in a reverse order of numpy's shape
-        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
-
-        let tensor = CpuTensor::from_bytes(info.data(), info.typ(), &dims, device.clone())?;
-        Ok(tensor)
+        let mut weights = Llama2Weights {
+            token_embedding_table: load(ModelTensor::TokenEmbd, 0)?,
+            embed_norm_weight: None,
+            embed_norm_bias: None,
+            wq: vec![],
+            wk: vec![],
+            wv: vec![],
+            wo: vec![],
+            bq: vec![],
+            bk: vec![],
+            bv: vec![],
+            bo: vec![],
+            w1: vec![],
+            w2: vec![],
+            w3: vec![],
+            b2: vec![],
+            b3: vec![],
+            ffn_gate_inp: vec![],
+            w1_exps: vec![],
+            w2_exps: vec![],
+            w3_exps: vec![],
+            rms_att_weight: vec![],
+            rms_ffn_weight: vec![],
+            att_norm_bias: vec![],
+            ffn_norm_bias: vec![],
+            rms_final_weight: load(ModelTensor::OutputNorm, 0)?,
+            final_norm_bias: None,
+            wcls: load(ModelTensor::Output, 0)?,
+            bcls: None,
+            rope_freq_factors: vec![],
+            rwkv: None,
+        };
+        for layer in 0..conf.n_layers {
+            weights.wq.push(load(ModelTensor::AttnQ, layer)?);
+            weights.wk.push(load(ModelTensor::AttnK, layer)?);
+            weights.wv.push(load(ModelTensor::AttnV, layer)?);
+            weights.wo.push(load(ModelTensor::AttnOutput, layer)?);
+            if sf.get_model_tensor(ModelTensor::AttnQBias, layer).is_some() {
+                weights.bq.push(load(ModelTensor::AttnQBias, layer)?);
+                weights.bk.push(load(ModelTensor::AttnKBias, layer)?);
+                weights.bv.push(load(ModelTensor::AttnVBias, layer)?);
+            }
+            weights.w1.push(load(ModelTensor::FfnGate, layer)?);
+            weights.w2.push(load(ModelTensor::FfnDown, layer)?);
+            weights.w3.push(load(ModelTensor::FfnUp, layer)?);
+            weights
+                .rms_att_weight
+                .push(load(ModelTensor::AttnNorm, layer)?);
+            weights
+                .rms_ffn_weight
+                .push(load(ModelTensor::FfnNorm, layer)?);
+        }
+        Ok(weights)
     }
 
-    fn load_tokenizer(gf: &GGUFFile) -> BpeTokenizer {
-        let vocab = gf
-            .metadata()
-            .get_string_array("tokenizer.ggml.tokens")
-            .unwrap()
-            .iter()
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>();
-        let vocab_scores = gf
-            .metadata()
-            .get_f32_array("tokenizer.ggml.scores")
-            .unwrap()
-            .to_vec();
-        let eos_token = gf
-            .metadata()
-            .get_u32("tokenizer.ggml.eos_token_id")
-            .unwrap() as usize;
-        let bos_token = gf
-            .metadata()
-            .get_u32("tokenizer.ggml.bos_token_id")
-            .unwrap() as usize;
-        BpeTokenizer::new(vocab, vocab_scores, bos_token, eos_token)
-    }
-
-    fn load_config(gf: &GGUFFile) -> Llama2Config {
-        // let rope_dims = gf.metadata().get_u32("llama.rope.dimension_count").unwrap();
-        let n_heads = gf.metadata().get_u32("llama.attention.head_count").unwrap() as usize;
-        let n_layers = gf.metadata().get_u32("llama.block_count").unwrap() as usize;
-        let hidden_dim = gf.metadata().get_u32("llama.feed_forward_length").unwrap() as usize;
-        let n_kv_heads = gf
-            .metadata()
-            .get_u32("llama.attention.head_count_kv")
-            .unwrap() as usize;
-        let seq_len = gf.metadata().get_u32("llama.context_length").unwrap() as usize;
-        let vocab_size = gf
-            .metadata()
-            .get_string_array("tokenizer.ggml.tokens")
-            .unwrap()
-            .len();
-        let embedding_dim = gf.metadata().get_u32("llama.embedding_length").unwrap() as usize;
-        let rms_norm_eps = gf
-            .metadata()
-            .get_f32("llama.attention.layer_norm_rms_epsilon")
-            .unwrap();
-        let n_rot = gf.metadata().get_u32("llama.rope.dimension_count").unwrap() as usize;
-        Llama2Config {
+    pub(crate) fn load_config(gf: &GGUFFile) -> Result<Llama2Config> {
+        let header = gf.header();
+        let arch = ModelArch::from_name(gf.architecture()).ok_or_else(|| Error {
+            kind: ErrorKind::NotImplemented,
+            message: format!("unknown architecture {}", gf.architecture()),
+            cause: None,
+        })?;
+        let rope_mode = Llama2Config::rope_mode_of(arch)?;
+        let norm = Llama2Config::norm_of(arch);
+        let n_layers = header.get_u32(KEY_BLOCK_COUNT)? as usize;
+        let embedding_dim = header.get_u32(KEY_EMBEDDING_LENGTH)? as usize;
+        // rwkv takes the heads of the wkv by their size
+        let n_heads = match header.get_u32(KEY_WKV_HEAD_SIZE) {
+            Ok(head_size) if arch == ModelArch::Rwkv6 => embedding_dim / head_size as usize,
+            _ => header.get_u32(KEY_ATTENTION_HEAD_COUNT)? as usize,
+        };
+        let hidden_dim = header.get_u32(KEY_FEED_FORWARD_LENGTH)? as usize;
+        let n_kv_heads = header
+            .get_u32(KEY_ATTENTION_HEAD_COUNT_KV)
+            .map_or(n_heads, |n| n as usize);
+        let seq_len = header.get_u32(KEY_CONTEXT_LENGTH)? as usize;
+        let vocab_size = header.get_str_array(KEY_TOKENIZER_LIST)?.len();
+        let norm_eps = match norm {
+            Llama2Norm::Rms => header.get_f32(KEY_ATTENTION_LAYERNORM_RMS_EPS)?,
+            Llama2Norm::Layer => header.get_f32(KEY_ATTENTION_LAYERNORM_EPS)?,
+        };
+        // rotate the whole head if the dimension count is missing, like falcon
+        let n_rot = header
+            .get_u32(KEY_ROPE_DIMENSION_COUNT)
+            .map_or(embedding_dim / n_heads, |n_rot| n_rot as usize);
+        let rope_freq_base = header.get_f32(KEY_ROPE_FREQ_BASE).unwrap_or(10000.0);
+        let rope_attn_factor = header.get_f32(KEY_ROPE_SCALING_ATTN_FACTOR).unwrap_or(1.0);
+        // the linear scaling is taken from `scale_linear` in the older files
+        let rope_scale = header
+            .get_f32(KEY_ROPE_SCALING_FACTOR)
+            .or_else(|_| header.get_f32(KEY_ROPE_SCALE_LINEAR))
+            .unwrap_or(0.0);
+        // the ntk-by-parts and yarn ramp between the interpolation and the extrapolation by
+        // the original context
+        let orig_ctx_len = header
+            .get_u32(KEY_ROPE_SCALING_ORIG_CTX_LEN)
+            .map_or(seq_len, |len| len as usize);
+        let (rope_scaling, rope_freq_scale) =
+            match header.get_str(KEY_ROPE_SCALING_TYPE).unwrap_or("linear") {
+                "linear" => (RopeScaling::Linear, rope_scale),
+                "ntk-by-parts" => (RopeScaling::NtkByParts { orig_ctx_len }, rope_scale),
+                "yarn" => (RopeScaling::Yarn { orig_ctx_len }, rope_scale),
+                _ => (RopeScaling::Linear, 0.0),
+            };
+        let rope_freq_scale = match rope_freq_scale > 0.0 {
+            true => 1.0 / rope_freq_scale,
+            false => 1.0,
+        };
+        // any architecture with a positive max bias takes the alibi instead of the rope, mpt
+        // and bloom take it by default
+        let alibi_max_bias = match header.get_f32(KEY_ATTENTION_MAX_ALIBI_BIAS) {
+            Ok(max_bias) if max_bias > 0.0 => Some(max_bias),
+            Ok(_) => None,
+            Err(_) if matches!(arch, ModelArch::Mpt | ModelArch::Bloom) => Some(8.0),
+            Err(_) => None,
+        };
+        if header.get_f32(KEY_ATTENTION_CLAMP_KQV).is_ok() {
+            return Err(Error {
+                kind: ErrorKind::NotImplemented,
+                message: "the clamp of the qkv is not supported yet".to_string(),
+                cause: None,
+            });
+        }
+        let n_experts = header.get_u32(KEY_EXPERT_COUNT).unwrap_or(0) as usize;
+        let n_experts_used = header.get_u32(KEY_EXPERT_USED_COUNT).unwrap_or(0) as usize;
+        let sliding_window = header
+            .get_u32(KEY_ATTENTION_SLIDING_WINDOW)
+            .ok()
+            .map(|window| window as usize);
+        let rescale_every_n_layers =
+            header.get_u32(KEY_RESCALE_EVERY_N_LAYERS).unwrap_or(0) as usize;
+        // the encoders pool the mean of the tokens unless specified
+        let pooling = match header.get_u32(KEY_POOLING_TYPE) {
+            Ok(pooling_type) => Llama2Pooling::from_gguf(pooling_type)?,
+            Err(_) if matches!(arch, ModelArch::Bert | ModelArch::NomicBert) => Llama2Pooling::Mean,
+            Err(_) => Llama2Pooling::None,
+        };
+        Ok(Llama2Config {
+            arch,
             n_heads,
             n_kv_heads,
             n_layers,
@@ -256,23 +914,66 @@ impl<'a> CpuLlama2Model<'a> {
             hidden_dim,
             seq_len,
             vocab_size,
-            rms_norm_eps,
+            norm,
+            norm_eps,
+            parallel_residual: Llama2Config::parallel_residual_of(arch),
             rope_dim: n_rot,
+            rope_mode,
+            rope_freq_base,
+            rope_freq_scale,
+            rope_scaling,
+            rope_attn_factor,
+            alibi_max_bias,
+            sliding_window,
+            n_experts,
+            n_experts_used,
+            rescale_every_n_layers,
+            pooling,
+        })
+    }
+}
+
+/// huggingface stores the two halves of each rotary pair of a head apart, while GGUF interleaves
+/// them, this is the same permutation as `permute()` in llama.cpp's convert.py.
+pub fn permute_hf_rows(buf: Vec<f32>, shape: &[usize], n_heads: usize) -> Vec<f32> {
+    let (n_rows, n_cols) = (shape[0], shape[1]);
+    let half = n_rows / n_heads / 2;
+    let mut out = vec![0.0; buf.len()];
+    for h in 0..n_heads {
+        for i in 0..half {
+            for j in 0..2 {
+                let dst = (h * half * 2 + i * 2 + j) * n_cols;
+                let src = (h * half * 2 + j * half + i) * n_cols;
+                out[dst..dst + n_cols].copy_from_slice(&buf[src..src + n_cols]);
+            }
         }
     }
+    out
 }
 
-#[derive(Clone)]
-pub struct WgpuLlama2Model {
+/// the model on the device of a backend, like the gpu, whose weights are uploaded from the
+/// model loaded on the cpu. the dtypes not supported by the backend are dequantized into f32.
+pub struct Llama2Model<B: TensorBackend> {
     pub conf: Llama2Config,
-    pub weights: Rc<Llama2Weights<WgpuTensor>>,
+    pub weights: Rc<Llama2Weights<B::Tensor>>,
     pub tokenizer: Rc<BpeTokenizer>,
-    pub device: WgpuTensorDeviceRef,
+    pub device: B,
 }
 
-impl WgpuLlama2Model {
-    pub fn from_cpu(cpu_model: &CpuLlama2Model, device: WgpuTensorDeviceRef) -> Result<Self> {
-        let weights = Self::convert_cpu_weights(&cpu_model.weights, device.clone())?;
+impl<B: TensorBackend> Clone for Llama2Model<B> {
+    fn clone(&self) -> Self {
+        Self {
+            conf: self.conf,
+            weights: self.weights.clone(),
+            tokenizer: self.tokenizer.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<B: TensorBackend> Llama2Model<B> {
+    pub fn from_cpu(cpu_model: &CpuLlama2Model, device: B) -> Result<Self> {
+        let weights = cpu_model.weights.convert(|t| device.upload(t))?;
         Ok(Self {
             conf: cpu_model.conf,
             weights: Rc::new(weights),
@@ -281,104 +982,55 @@ impl WgpuLlama2Model {
         })
     }
 
-    fn convert_cpu_weights(
-        weights: &Llama2Weights<CpuTensor>,
-        device: WgpuTensorDeviceRef,
-    ) -> Result<Llama2Weights<WgpuTensor>> {
-        let token_embedding_table =
-            Self::convert_cpu_tensor(&weights.token_embedding_table, device.clone())?;
-        let wq = weights
-            .wq
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let wk = weights
-            .wk
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let wv = weights
-            .wv
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let wo = weights
-            .wo
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let w1 = weights
-            .w1
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let w2 = weights
-            .w2
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let w3 = weights
-            .w3
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let rms_att_weight = weights
-            .rms_att_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let rms_ffn_weight = weights
-            .rms_ffn_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let rms_final_weight = Self::convert_cpu_tensor(&weights.rms_final_weight, device.clone())?;
-        let wcls = Self::convert_cpu_tensor(&weights.wcls, device.clone())?;
-        let weights = Llama2Weights {
-            token_embedding_table,
-            wq,
-            wk,
-            wv,
-            wo,
-            w1,
-            w2,
-            w3,
-            rms_att_weight,
-            rms_ffn_weight,
-            rms_final_weight,
-            wcls,
-        };
-        Ok(weights)
+    /// take the weights of the first `n_layers` layers only, which are offloaded from the
+    /// runner on the cpu by `Llama2Runner::with_offloaded_layers`.
+    pub fn from_cpu_layers(cpu_model: &CpuLlama2Model, device: B, n_layers: usize) -> Result<Self> {
+        let weights = cpu_model.weights.convert_layers(
+            n_layers,
+            |t| device.upload(t),
+            || B::Tensor::alloc(&[1], None, device.clone()),
+        )?;
+        Ok(Self {
+            conf: cpu_model.conf,
+            weights: Rc::new(weights),
+            tokenizer: cpu_model.tokenizer.clone(),
+            device,
+        })
     }
+}
 
-    fn convert_cpu_tensor(tensor: &CpuTensor, device: WgpuTensorDeviceRef) -> Result<WgpuTensor> {
-        let buf = tensor.buf();
-        let buf = match buf {
-            CpuTensorBuf::F32(buf) => buf,
-            _ => {
-                return Err(Error {
-                    kind: ErrorKind::TensorError,
-                    message: format!("unsupported tensor type on gpu {:?}", buf),
-                    cause: None,
-                });
-            }
-        };
+pub type WgpuLlama2Model = Llama2Model<WgpuTensorDeviceRef>;
 
-        let wgpu_tensor = WgpuTensor::new(buf, tensor.shape(), device.clone())?;
-        Ok(wgpu_tensor)
-    }
-}
+/// the model on the gpu of apple silicon, the Q8_0 and Q4_0 weights are kept quantized on
+/// the unified memory.
+#[cfg(target_os = "macos")]
+pub type MetalLlama2Model = Llama2Model<MetalTensorDeviceRef>;
+
+/// the model on the nvidia gpu, the Q8_0 and Q4_0 weights are kept quantized in the device
+/// memory.
+#[cfg(feature = "cuda")]
+pub type CudaLlama2Model = Llama2Model<CudaTensorDeviceRef>;
 
 #[cfg(test)]
 // Only run tests on aarch64
 #[cfg(target_arch = "aarch64")]
 mod tests {
     use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::error::ErrorKind;
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFWriter;
+    use crabml::gguf::ModelTensor;
+    use crabml::safetensors::SafetensorsFile;
+    use crabml::tensor::RopeScaling;
     use crabml::tensor::Tensor;
+    use crabml::tokenizer::BpeTokenizer;
 
+    use crate::llama2::Llama2Runner;
+    use crate::model::Llama2Config;
+    use crate::sampler::Llama2Sampler;
     use crate::CpuLlama2Model;
 
     #[test]
@@ -396,4 +1048,192 @@ mod tests {
         assert_eq!(lm.weights.token_embedding_table.dtype(), GGMLType::F32);
         Ok(())
     }
+
+    #[test]
+    fn test_load_safetensors() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let conf = lm.conf;
+
+        // convert the GGUF file into a huggingface checkpoint, with the q, k rows unpermuted
+        let unpermute = |buf: Vec<f32>, shape: &[usize], n_heads: usize| {
+            let (n_rows, n_cols) = (shape[0], shape[1]);
+            let half = n_rows / n_heads / 2;
+            let mut out = vec![0.0; buf.len()];
+            for h in 0..n_heads {
+                for i in 0..half {
+                    for j in 0..2 {
+                        let src = (h * half * 2 + i * 2 + j) * n_cols;
+                        let dst = (h * half * 2 + j * half + i) * n_cols;
+                        out[dst..dst + n_cols].copy_from_slice(&buf[src..src + n_cols]);
+                    }
+                }
+            }
+            out
+        };
+        let mut header = serde_json::Map::new();
+        let mut data = vec![];
+        for info in gf.tensor_infos() {
+            let (tensor, layer) = ModelTensor::from_gguf_name(info.name()).unwrap();
+            let shape = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+            let buf = info
+                .data()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect::<Vec<_>>();
+            let buf = match tensor {
+                ModelTensor::AttnQ => unpermute(buf, &shape, conf.n_heads),
+                ModelTensor::AttnK => unpermute(buf, &shape, conf.n_kv_heads),
+                _ => buf,
+            };
+            let begin = data.len();
+            data.extend(
+                buf.iter()
+                    .flat_map(|v| half::f16::from_f32(*v).to_le_bytes()),
+            );
+            header.insert(
+                tensor.hf_name(layer.unwrap_or(0)).unwrap(),
+                serde_json::json!({"dtype": "F16", "shape": shape, "data_offsets": [begin, data.len()]}),
+            );
+        }
+        let header = serde_json::Value::Object(header).to_string();
+        let mut buf = (header.len() as u64).to_le_bytes().to_vec();
+        buf.extend_from_slice(header.as_bytes());
+        buf.extend_from_slice(&data);
+        let sf = SafetensorsFile::decode(&buf)?;
+
+        let hf_conf = Llama2Config::from_hf_config(&format!(
+            r#"{{"hidden_size": {}, "intermediate_size": {}, "num_hidden_layers": {},
+                "num_attention_heads": {}, "num_key_value_heads": {}, "vocab_size": {},
+                "max_position_embeddings": {}, "rms_norm_eps": 1e-5}}"#,
+            conf.embedding_dim,
+            conf.hidden_dim,
+            conf.n_layers,
+            conf.n_heads,
+            conf.n_kv_heads,
+            conf.vocab_size,
+            conf.seq_len
+        ))?;
+        assert_eq!(hf_conf.rope_dim, conf.rope_dim);
+        let tokenizer = BpeTokenizer::from_gguf(&gf)?;
+        let lm2 = CpuLlama2Model::load_safetensors(&sf, hf_conf, tokenizer, device)?;
+
+        let generate = |lm: &CpuLlama2Model| -> Result<String> {
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let mut runner = Llama2Runner::try_from(lm)?;
+            let output = runner.generate("Lily is a cat", 10, &mut sampler)?;
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+        assert_eq!(generate(&lm2)?, generate(&lm)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_rope_scaling() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let load_with = |metadata: &[(&str, GGUFMetadataValue)]| -> Result<Llama2Config> {
+            let mut w = GGUFWriter::new();
+            w.add_metadata_of(gf.metadata());
+            for (key, value) in metadata {
+                w.add_metadata(key, value.clone());
+            }
+            for info in gf.tensor_infos() {
+                w.add_tensor(info.name(), info.dimensions(), info.typ(), info.data())?;
+            }
+            let gl = GGUFFileLoader::from_writer(&w)?;
+            let gf = gl.open()?;
+            Ok(CpuLlama2Model::load(&gf, CpuTensorDevice::new())?.conf)
+        };
+
+        assert_eq!(load_with(&[])?.rope_freq_scale, 1.0);
+        let conf = load_with(&[("llama.rope.scale_linear", GGUFMetadataValue::F32(4.0))])?;
+        assert_eq!(conf.rope_freq_scale, 0.25);
+        let conf = load_with(&[
+            (
+                "llama.rope.scaling.type",
+                GGUFMetadataValue::String("linear"),
+            ),
+            ("llama.rope.scaling.factor", GGUFMetadataValue::F32(2.0)),
+        ])?;
+        assert_eq!(conf.rope_freq_scale, 0.5);
+        assert_eq!(conf.rope_scaling, RopeScaling::Linear);
+        let conf = load_with(&[
+            ("llama.rope.scaling.type", GGUFMetadataValue::String("yarn")),
+            ("llama.rope.scaling.factor", GGUFMetadataValue::F32(4.0)),
+            (
+                "llama.rope.scaling.original_context_length",
+                GGUFMetadataValue::U32(128),
+            ),
+        ])?;
+        assert_eq!(conf.rope_freq_scale, 0.25);
+        assert_eq!(conf.rope_scaling, RopeScaling::Yarn { orig_ctx_len: 128 });
+        let conf = load_with(&[
+            (
+                "llama.rope.scaling.type",
+                GGUFMetadataValue::String("ntk-by-parts"),
+            ),
+            ("llama.rope.scaling.factor", GGUFMetadataValue::F32(4.0)),
+        ])?;
+        assert_eq!(conf.rope_scaling, RopeScaling::NtkByParts {
+            orig_ctx_len: conf.seq_len
+        });
+        let conf = load_with(&[
+            ("llama.rope.scaling.type", GGUFMetadataValue::String("none")),
+            ("llama.rope.scaling.factor", GGUFMetadataValue::F32(4.0)),
+        ])?;
+        assert_eq!(conf.rope_freq_scale, 1.0);
+
+        let hf_conf = Llama2Config::from_hf_config(
+            r#"{"hidden_size": 64, "intermediate_size": 172, "num_hidden_layers": 5,
+                "num_attention_heads": 8, "num_key_value_heads": 4, "vocab_size": 512,
+                "max_position_embeddings": 512, "rope_scaling": {"type": "linear", "factor": 4.0}}"#,
+        )?;
+        assert_eq!(hf_conf.rope_freq_scale, 0.25);
+        let hf_conf = Llama2Config::from_hf_config(
+            r#"{"hidden_size": 64, "intermediate_size": 172, "num_hidden_layers": 5,
+                "num_attention_heads": 8, "vocab_size": 512, "max_position_embeddings": 512,
+                "rope_scaling": {"rope_type": "yarn", "factor": 4.0,
+                "original_max_position_embeddings": 128}}"#,
+        )?;
+        assert_eq!(hf_conf.rope_scaling, RopeScaling::Yarn {
+            orig_ctx_len: 128
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_missing_tensors() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let mut w = GGUFWriter::new();
+        w.add_metadata_of(gf.metadata());
+        for info in gf.tensor_infos() {
+            let name = match info.name() {
+                "blk.1.attn_k.weight" | "blk.3.ffn_up.weight" => continue,
+                "blk.0.attn_q.weight" => "blk.0.attn_q_proj.weight",
+                name => name,
+            };
+            w.add_tensor(name, info.dimensions(), info.typ(), info.data())?;
+        }
+        let gl = GGUFFileLoader::from_writer(&w)?;
+        let gf = gl.open()?;
+
+        let err = CpuLlama2Model::load(&gf, CpuTensorDevice::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        assert!(
+            err.message.ends_with(
+                "the llama model of 5 layers has missing tensors: blk.0.attn_q.weight, \
+                 blk.1.attn_k.weight, blk.3.ffn_up.weight; unexpected tensors: \
+                 blk.0.attn_q_proj.weight"
+            ),
+            "{}",
+            err
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,168 @@
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::RopeOptions;
+use crabml::tensor::Tensor;
+
+use crate::kv_cache::Llama2KvCache;
+use crate::llama2::Llama2Row;
+use crate::llama2::Llama2Runner;
+
+/// the first layers of the model offloaded to another device, like the gpu, which keep the
+/// kv cache of their own. the hidden states are transferred in f32 at the boundary, and the
+/// sequence ops on the kv cache of the runner are replayed on them, so the sequences keep the
+/// same ids and rows on both devices.
+pub(crate) trait Llama2Offload {
+    /// the number of the offloaded layers.
+    fn n_layers(&self) -> usize;
+
+    /// forward the hidden states of the rows through the offloaded layers.
+    fn forward_offloaded(&mut self, rows: &[Llama2Row], xs: Vec<Vec<f32>>)
+    -> Result<Vec<Vec<f32>>>;
+
+    /// drop all the sequences, like on resetting the kv cache of the runner.
+    fn reset(&mut self) -> Result<()>;
+
+    fn alloc_seq(&mut self) -> usize;
+
+    fn fork_seq(&mut self, seq: usize) -> Result<usize>;
+
+    fn free_seq(&mut self, seq: usize) -> Result<()>;
+
+    fn clear_seq(&mut self, seq: usize) -> Result<()>;
+
+    fn shift_seq(
+        &mut self,
+        seq: usize,
+        start: usize,
+        n: usize,
+        rope: Option<&RopeOptions>,
+    ) -> Result<()>;
+
+    fn read_rows(&self, seq: usize, l: usize) -> Result<(Vec<f32>, Vec<f32>)>;
+
+    fn write_rows(&mut self, seq: usize, l: usize, row: usize, k: &[f32], v: &[f32]) -> Result<()>;
+}
+
+impl<T: Tensor> Llama2Offload for Llama2Runner<T> {
+    fn n_layers(&self) -> usize {
+        self.layers.end
+    }
+
+    fn forward_offloaded(
+        &mut self,
+        rows: &[Llama2Row],
+        xs: Vec<Vec<f32>>,
+    ) -> Result<Vec<Vec<f32>>> {
+        let embed_dim = self.conf.embedding_dim;
+        let xs = xs
+            .into_iter()
+            .map(|x| T::from_vec(x, &[embed_dim], self.device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let xs = self.forward_layers(rows, xs)?;
+        xs.iter()
+            .map(|x| {
+                let mut buf = vec![0.0; embed_dim];
+                x.export(&mut buf)?;
+                Ok(buf)
+            })
+            .collect()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let options = self.kv_cache.options();
+        self.kv_cache = Llama2KvCache::new(&self.conf, options, self.device.clone())?
+            .with_layers(self.layers.clone());
+        Ok(())
+    }
+
+    fn alloc_seq(&mut self) -> usize {
+        self.kv_cache.alloc_seq()
+    }
+
+    fn fork_seq(&mut self, seq: usize) -> Result<usize> {
+        self.kv_cache.fork_seq(seq)
+    }
+
+    fn free_seq(&mut self, seq: usize) -> Result<()> {
+        self.kv_cache.free_seq(seq)
+    }
+
+    fn clear_seq(&mut self, seq: usize) -> Result<()> {
+        self.kv_cache.clear_seq(seq)
+    }
+
+    fn shift_seq(
+        &mut self,
+        seq: usize,
+        start: usize,
+        n: usize,
+        rope: Option<&RopeOptions>,
+    ) -> Result<()> {
+        self.kv_cache.shift_seq(seq, start, n, rope)
+    }
+
+    fn read_rows(&self, seq: usize, l: usize) -> Result<(Vec<f32>, Vec<f32>)> {
+        self.kv_cache.read_rows(seq, l)
+    }
+
+    fn write_rows(&mut self, seq: usize, l: usize, row: usize, k: &[f32], v: &[f32]) -> Result<()> {
+        let shape = [self.conf.n_kv_heads, self.conf.head_size()];
+        let k = T::from_vec(k.to_vec(), &shape, self.device.clone())?;
+        let v = T::from_vec(v.to_vec(), &shape, self.device.clone())?;
+        self.kv_cache.write(seq, l, row, &k, &v)
+    }
+}
+
+impl<T: Tensor> Llama2Runner<T> {
+    /// run the first `n_layers` layers on the runner of another device, like the gpu, and the
+    /// rest on this runner, so a model larger than the memory of the gpu still runs partly on
+    /// it. the offloaded runner only needs the weights of its layers, like the model taken by
+    /// `Llama2Model::from_cpu_layers`. the kv cache of both runners is reset.
+    pub fn with_offloaded_layers<U: Tensor + 'static>(
+        mut self,
+        mut offload: Llama2Runner<U>,
+        n_layers: usize,
+    ) -> Result<Self> {
+        if n_layers == 0 || n_layers >= self.conf.n_layers {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "expected 1 to {} offloaded layers, got {}",
+                    self.conf.n_layers - 1,
+                    n_layers
+                ),
+                cause: None,
+            });
+        }
+        if offload.conf.embedding_dim != self.conf.embedding_dim
+            || offload.weights.rms_att_weight.len() < n_layers
+        {
+            return Err((
+                ErrorKind::BadInput,
+                "the offloaded runner does not match the model",
+            )
+                .into());
+        }
+        if !self.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "offloading the recurrent layers is not supported yet",
+            )
+                .into());
+        }
+        if !self.loras.is_empty() || !self.control_vector.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "offloading with the lora adapters or the control vectors is not supported yet",
+            )
+                .into());
+        }
+
+        offload.layers = 0..n_layers;
+        self.layers = n_layers..self.conf.n_layers;
+        self.kv_cache.set_offload(Box::new(offload));
+        let options = self.kv_cache.options();
+        self.with_kv_cache_options(options)
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+
+use crate::kv_cache::Llama2KvCache;
+
+struct Llama2PromptCacheEntry {
+    seq: usize,
+    tokens: Vec<usize>,
+    last_used: u64,
+}
+
+/// the recent prompts kept as the sequences forked in the kv cache, so a prompt sharing a
+/// prefix with a cached one only forwards the rest, like the prefix caching of vllm. the
+/// forked sequences share the pages with the one they're forked from, so caching a prompt
+/// takes no extra memory until the sequences diverge.
+///
+/// the prompts are indexed by the hashes of their prefixes on the page boundaries, the
+/// least recently used one is dropped beyond the capacity.
+pub struct Llama2PromptCache {
+    capacity: usize,
+    page_size: usize,
+    entries: HashMap<usize, Llama2PromptCacheEntry>,
+    index: HashMap<u64, usize>, // the hash of a prefix => the entry of the latest prompt
+    next_id: usize,
+    clock: u64,
+}
+
+impl Llama2PromptCache {
+    pub fn new(capacity: usize, page_size: usize) -> Self {
+        Self {
+            capacity,
+            page_size,
+            entries: HashMap::new(),
+            index: HashMap::new(),
+            next_id: 0,
+            clock: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the sequence and the tokens of the cached prompt sharing the longest prefix on the
+    /// page boundaries with the tokens.
+    pub fn lookup(&mut self, tokens: &[usize]) -> Option<(usize, &[usize])> {
+        let n_pages = tokens.len() / self.page_size;
+        let id = (1..=n_pages)
+            .rev()
+            .find_map(|n| self.index.get(&prefix_hash(&tokens[..n * self.page_size])))
+            .copied()?;
+        self.clock += 1;
+        let entry = self.entries.get_mut(&id)?;
+        entry.last_used = self.clock;
+        Some((entry.seq, &entry.tokens))
+    }
+
+    /// keep the tokens forwarded on the sequence by forking it, the least recently used
+    /// prompt is dropped if the cache is full.
+    pub fn insert<T: Tensor>(
+        &mut self,
+        kv_cache: &mut Llama2KvCache<T>,
+        seq: usize,
+        tokens: &[usize],
+    ) -> Result<()> {
+        if self.capacity == 0 || tokens.len() < self.page_size {
+            return Ok(());
+        }
+        self.clock += 1;
+        if let Some(entry) = self.entries.values_mut().find(|e| e.tokens == tokens) {
+            entry.last_used = self.clock;
+            return Ok(());
+        }
+        if self.entries.len() >= self.capacity {
+            self.evict(kv_cache)?;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        for n in (self.page_size..=tokens.len()).step_by(self.page_size) {
+            self.index.insert(prefix_hash(&tokens[..n]), id);
+        }
+        self.entries.insert(id, Llama2PromptCacheEntry {
+            seq: kv_cache.fork_seq(seq)?,
+            tokens: tokens.to_vec(),
+            last_used: self.clock,
+        });
+        Ok(())
+    }
+
+    /// drop all the cached prompts and release their sequences.
+    pub fn clear<T: Tensor>(&mut self, kv_cache: &mut Llama2KvCache<T>) -> Result<()> {
+        for (_, entry) in self.entries.drain() {
+            kv_cache.free_seq(entry.seq)?;
+        }
+        self.index.clear();
+        Ok(())
+    }
+
+    fn evict<T: Tensor>(&mut self, kv_cache: &mut Llama2KvCache<T>) -> Result<()> {
+        let Some(id) = self
+            .entries
+            .iter()
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(id, _)| *id)
+        else {
+            return Ok(());
+        };
+        let entry = self.entries.remove(&id).unwrap();
+        self.index.retain(|_, v| *v != id);
+        kv_cache.free_seq(entry.seq)
+    }
+}
+
+fn prefix_hash(tokens: &[usize]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensor;
+    use crabml::backends::cpu::CpuTensorDevice;
+
+    use super::*;
+    use crate::kv_cache::Llama2KvCacheOptions;
+    use crate::model::Llama2Config;
+
+    #[test]
+    fn test_prompt_cache() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let conf = Llama2Config::from_hf_config(
+            r#"{"hidden_size": 16, "intermediate_size": 32, "num_hidden_layers": 2,
+                "num_attention_heads": 4, "num_key_value_heads": 2, "vocab_size": 32,
+                "max_position_embeddings": 64}"#,
+        )?;
+        let options = Llama2KvCacheOptions {
+            page_size: 2,
+            ..Default::default()
+        };
+        let mut kv_cache = Llama2KvCache::<CpuTensor>::new(&conf, options, device.clone())?;
+        let mut cache = Llama2PromptCache::new(2, 2);
+        let seq = kv_cache.alloc_seq();
+        for row in 0..5 {
+            for l in 0..2 {
+                let k = CpuTensor::new(vec![row as f32; 8], &[2, 4], device.clone())?;
+                kv_cache.write(seq, l, row, &k, &k)?;
+            }
+        }
+
+        // the prompts are found by the prefixes on the page boundaries
+        cache.insert(&mut kv_cache, seq, &[1, 2, 3, 4, 5])?;
+        cache.insert(&mut kv_cache, seq, &[1, 2, 3, 4, 5])?;
+        assert_eq!(cache.len(), 1);
+        assert_eq!(kv_cache.n_used_pages(), 3);
+        let (cached, tokens) = cache.lookup(&[1, 2, 3, 9, 9]).unwrap();
+        assert_ne!(cached, seq);
+        assert_eq!(tokens, &[1, 2, 3, 4, 5]);
+        assert_eq!(kv_cache.seq_len(cached)?, 5);
+        assert!(cache.lookup(&[1, 9, 3, 4, 5]).is_none());
+        assert!(cache.lookup(&[1]).is_none());
+
+        // the least recently used prompt is dropped beyond the capacity
+        cache.insert(&mut kv_cache, seq, &[6, 7, 8, 9])?;
+        cache.lookup(&[1, 2, 3, 4]);
+        cache.insert(&mut kv_cache, seq, &[9, 9, 9])?;
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&[6, 7, 8, 9]).is_none());
+        assert!(cache.lookup(&[1, 2, 3, 4]).is_some());
+        assert!(kv_cache.seq_len(cached).is_ok());
+
+        cache.clear(&mut kv_cache)?;
+        assert!(cache.is_empty());
+        assert!(kv_cache.seq_len(cached).is_err());
+        Ok(())
+    }
+}
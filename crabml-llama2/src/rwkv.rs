@@ -0,0 +1,592 @@
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorLoader;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::ModelTensor;
+use crabml::gguf::ModelTensor::*;
+use crabml::gguf::TensorNameMap;
+use crabml::tensor::Tensor;
+
+use crate::model::Llama2Config;
+
+/// the eps of the group norm on the heads of the time mix, which is 1e-5 * head_size_divisor^2
+/// in the reference implementation of rwkv v6.
+const TIME_MIX_GROUP_NORM_EPS: f32 = 64e-5;
+
+/// the weights of the recurrent layers of rwkv v6, which take the token shift and the wkv
+/// state instead of the attention over a kv cache. the vectors are kept on the host, while
+/// the matrices are kept on the device.
+pub struct RwkvWeights<T: Tensor> {
+    // the layer norm on the token embedding
+    pub token_embd_norm: Vec<f32>,      // (embedding_dim, )
+    pub token_embd_norm_bias: Vec<f32>, // (embedding_dim, )
+    pub layers: Vec<RwkvLayerWeights<T>>,
+}
+
+pub struct RwkvLayerWeights<T: Tensor> {
+    // the layer norms before the time mix and the channel mix
+    pub att_norm: Vec<f32>,      // (embedding_dim, )
+    pub att_norm_bias: Vec<f32>, // (embedding_dim, )
+    pub ffn_norm: Vec<f32>,      // (embedding_dim, )
+    pub ffn_norm_bias: Vec<f32>, // (embedding_dim, )
+    // the data dependent token shift of w, k, v, r, g in the low rank
+    pub time_mix_lerp_x: Vec<f32>,    // (embedding_dim, )
+    pub time_mix_lerp: Vec<Vec<f32>>, // (5, embedding_dim)
+    pub time_mix_w1: T,               // (5 * lora_dim, embedding_dim)
+    pub time_mix_w2: Vec<T>,          // (5, embedding_dim, lora_dim)
+    // the data dependent decay in the low rank
+    pub time_mix_decay: Vec<f32>, // (embedding_dim, )
+    pub time_mix_decay_w1: T,     // (decay_lora_dim, embedding_dim)
+    pub time_mix_decay_w2: T,     // (embedding_dim, decay_lora_dim)
+    // the bonus of the current token, the `u` in the paper
+    pub time_mix_first: Vec<f32>, // (n_heads, head_size)
+    pub time_mix_receptance: T,   // (embedding_dim, embedding_dim)
+    pub time_mix_key: T,          // (embedding_dim, embedding_dim)
+    pub time_mix_value: T,        // (embedding_dim, embedding_dim)
+    pub time_mix_gate: T,         // (embedding_dim, embedding_dim)
+    pub time_mix_output: T,       // (embedding_dim, embedding_dim)
+    // the group norm on the heads of the wkv
+    pub time_mix_ln: Vec<f32>,      // (embedding_dim, )
+    pub time_mix_ln_bias: Vec<f32>, // (embedding_dim, )
+    // the channel mix, which is a squared relu mlp gated by the receptance
+    pub channel_mix_lerp_k: Vec<f32>, // (embedding_dim, )
+    pub channel_mix_lerp_r: Vec<f32>, // (embedding_dim, )
+    pub channel_mix_key: T,           // (hidden_dim, embedding_dim)
+    pub channel_mix_value: T,         // (embedding_dim, hidden_dim)
+    pub channel_mix_receptance: T,    // (embedding_dim, embedding_dim)
+}
+
+/// the recurrent state of a layer, which keeps the same size on any length of the context.
+#[derive(Debug, Clone)]
+pub struct RwkvState {
+    // the normed input of the last token on the time mix and the channel mix
+    pub att_shift: Vec<f32>, // (embedding_dim, )
+    pub ffn_shift: Vec<f32>, // (embedding_dim, )
+    pub wkv: Vec<f32>,       // (n_heads, head_size, head_size)
+}
+
+impl RwkvState {
+    pub fn new(conf: &Llama2Config) -> Self {
+        Self {
+            att_shift: vec![0.0; conf.embedding_dim],
+            ffn_shift: vec![0.0; conf.embedding_dim],
+            wkv: vec![0.0; conf.embedding_dim * conf.head_size()],
+        }
+    }
+}
+
+impl<'a> RwkvWeights<CpuTensor<'a>> {
+    pub fn load(
+        loader: &CpuTensorLoader<'a>,
+        names: &TensorNameMap,
+        conf: &Llama2Config,
+    ) -> Result<Self> {
+        let load_vec = |tensor: ModelTensor, layer: usize| -> Result<Vec<f32>> {
+            let tensor = loader.load_as(&names.name(tensor, layer), GGMLType::F32)?;
+            let mut buf = vec![0.0; tensor.shape().iter().product()];
+            tensor.export(&mut buf)?;
+            Ok(buf)
+        };
+        let load = |tensor: ModelTensor, layer: usize| loader.load(&names.name(tensor, layer));
+
+        let mut layers = vec![];
+        for layer in 0..conf.n_layers {
+            // the lerps of w, k, v, r, g may be fused in one tensor in this order
+            let time_mix_lerp = if loader.contains(&names.name(TimeMixLerpFused, layer)) {
+                load_vec(TimeMixLerpFused, layer)?
+                    .chunks(conf.embedding_dim)
+                    .map(|c| c.to_vec())
+                    .collect()
+            } else {
+                [
+                    TimeMixLerpW,
+                    TimeMixLerpK,
+                    TimeMixLerpV,
+                    TimeMixLerpR,
+                    TimeMixLerpG,
+                ]
+                .iter()
+                .map(|tensor| load_vec(*tensor, layer))
+                .collect::<Result<Vec<_>>>()?
+            };
+            layers.push(RwkvLayerWeights {
+                att_norm: load_vec(AttnNorm, layer)?,
+                att_norm_bias: load_vec(AttnNormBias, layer)?,
+                ffn_norm: load_vec(AttnNorm2, layer)?,
+                ffn_norm_bias: load_vec(AttnNorm2Bias, layer)?,
+                time_mix_lerp_x: load_vec(TimeMixLerpX, layer)?,
+                time_mix_lerp,
+                time_mix_w1: load(TimeMixW1, layer)?,
+                time_mix_w2: loader.load_experts(&names.name(TimeMixW2, layer))?,
+                time_mix_decay: load_vec(TimeMixDecay, layer)?,
+                time_mix_decay_w1: load(TimeMixDecayW1, layer)?,
+                time_mix_decay_w2: load(TimeMixDecayW2, layer)?,
+                time_mix_first: load_vec(TimeMixFirst, layer)?,
+                time_mix_receptance: load(TimeMixReceptance, layer)?,
+                time_mix_key: load(TimeMixKey, layer)?,
+                time_mix_value: load(TimeMixValue, layer)?,
+                time_mix_gate: load(TimeMixGate, layer)?,
+                time_mix_output: load(TimeMixOutput, layer)?,
+                time_mix_ln: load_vec(TimeMixLn, layer)?,
+                time_mix_ln_bias: load_vec(TimeMixLnBias, layer)?,
+                channel_mix_lerp_k: load_vec(ChannelMixLerpK, layer)?,
+                channel_mix_lerp_r: load_vec(ChannelMixLerpR, layer)?,
+                channel_mix_key: load(ChannelMixKey, layer)?,
+                channel_mix_value: load(ChannelMixValue, layer)?,
+                channel_mix_receptance: load(ChannelMixReceptance, layer)?,
+            });
+        }
+        Ok(Self {
+            token_embd_norm: load_vec(TokenEmbdNorm, 0)?,
+            token_embd_norm_bias: load_vec(TokenEmbdNormBias, 0)?,
+            layers,
+        })
+    }
+}
+
+impl<T: Tensor> RwkvWeights<T> {
+    /// convert the matrices onto another device, like wgpu.
+    pub fn convert<U: Tensor>(&self, f: impl Fn(&T) -> Result<U>) -> Result<RwkvWeights<U>> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|w| {
+                Ok(RwkvLayerWeights {
+                    att_norm: w.att_norm.clone(),
+                    att_norm_bias: w.att_norm_bias.clone(),
+                    ffn_norm: w.ffn_norm.clone(),
+                    ffn_norm_bias: w.ffn_norm_bias.clone(),
+                    time_mix_lerp_x: w.time_mix_lerp_x.clone(),
+                    time_mix_lerp: w.time_mix_lerp.clone(),
+                    time_mix_w1: f(&w.time_mix_w1)?,
+                    time_mix_w2: w.time_mix_w2.iter().map(&f).collect::<Result<Vec<_>>>()?,
+                    time_mix_decay: w.time_mix_decay.clone(),
+                    time_mix_decay_w1: f(&w.time_mix_decay_w1)?,
+                    time_mix_decay_w2: f(&w.time_mix_decay_w2)?,
+                    time_mix_first: w.time_mix_first.clone(),
+                    time_mix_receptance: f(&w.time_mix_receptance)?,
+                    time_mix_key: f(&w.time_mix_key)?,
+                    time_mix_value: f(&w.time_mix_value)?,
+                    time_mix_gate: f(&w.time_mix_gate)?,
+                    time_mix_output: f(&w.time_mix_output)?,
+                    time_mix_ln: w.time_mix_ln.clone(),
+                    time_mix_ln_bias: w.time_mix_ln_bias.clone(),
+                    channel_mix_lerp_k: w.channel_mix_lerp_k.clone(),
+                    channel_mix_lerp_r: w.channel_mix_lerp_r.clone(),
+                    channel_mix_key: f(&w.channel_mix_key)?,
+                    channel_mix_value: f(&w.channel_mix_value)?,
+                    channel_mix_receptance: f(&w.channel_mix_receptance)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RwkvWeights {
+            token_embd_norm: self.token_embd_norm.clone(),
+            token_embd_norm_bias: self.token_embd_norm_bias.clone(),
+            layers,
+        })
+    }
+
+    /// the layer norm on the token embedding before the first layer.
+    pub fn forward_embedding_norm(
+        &self,
+        x: T,
+        conf: &Llama2Config,
+        device: T::Device,
+    ) -> Result<T> {
+        let x = export(&x)?;
+        let x = layer_norm(
+            &x,
+            &self.token_embd_norm,
+            &self.token_embd_norm_bias,
+            conf.norm_eps,
+        );
+        T::from_vec(x, &[conf.embedding_dim], device)
+    }
+
+    /// forward a layer on the token, and move the state to the next token.
+    pub fn forward_layer(
+        &self,
+        x: T,
+        l: usize,
+        state: &mut RwkvState,
+        conf: &Llama2Config,
+        device: T::Device,
+    ) -> Result<T> {
+        let w = &self.layers[l];
+        let mut x = export(&x)?;
+
+        let xx = layer_norm(&x, &w.att_norm, &w.att_norm_bias, conf.norm_eps);
+        let att = Self::forward_time_mix(w, &xx, state, conf, &device)?;
+        x.iter_mut().zip(att).for_each(|(x, a)| *x += a);
+
+        let xx = layer_norm(&x, &w.ffn_norm, &w.ffn_norm_bias, conf.norm_eps);
+        let ffn = Self::forward_channel_mix(w, &xx, state, &device)?;
+        x.iter_mut().zip(ffn).for_each(|(x, f)| *x += f);
+
+        // the outputs of the time mix and the channel mix are rescaled in the conversion to
+        // avoid the overflow on fp16, which is taken back here
+        let rescale = conf.rescale_every_n_layers;
+        if rescale > 0 && (l + 1) % rescale == 0 {
+            x.iter_mut().for_each(|x| *x /= 2.0);
+        }
+        T::from_vec(x, &[conf.embedding_dim], device)
+    }
+
+    fn forward_time_mix(
+        w: &RwkvLayerWeights<T>,
+        xx: &[f32],
+        state: &mut RwkvState,
+        conf: &Llama2Config,
+        device: &T::Device,
+    ) -> Result<Vec<f32>> {
+        let head_size = conf.head_size();
+        let sx = token_shift(&mut state.att_shift, xx);
+
+        // the lerps of w, k, v, r, g are adjusted by the input through the low rank
+        let xxx = lerp(xx, &sx, &w.time_mix_lerp_x);
+        let mut xxx = matmul(&w.time_mix_w1, xxx, device)?;
+        xxx.iter_mut().for_each(|v| *v = v.tanh());
+        let lora_dim = xxx.len() / w.time_mix_w2.len();
+        let mut xs = vec![];
+        for (i, chunk) in xxx.chunks(lora_dim).enumerate() {
+            let mut mu = matmul(&w.time_mix_w2[i], chunk.to_vec(), device)?;
+            mu.iter_mut()
+                .zip(&w.time_mix_lerp[i])
+                .for_each(|(m, lerp)| *m += lerp);
+            xs.push(lerp(xx, &sx, &mu));
+        }
+        let [xw, xk, xv, xr, xg]: [Vec<f32>; 5] = xs.try_into().unwrap();
+
+        let r = matmul(&w.time_mix_receptance, xr, device)?;
+        let k = matmul(&w.time_mix_key, xk, device)?;
+        let v = matmul(&w.time_mix_value, xv, device)?;
+        let g = matmul(&w.time_mix_gate, xg, device)?;
+
+        // the decay of each channel is also adjusted by the input
+        let mut decay = matmul(&w.time_mix_decay_w1, xw, device)?;
+        decay.iter_mut().for_each(|v| *v = v.tanh());
+        let mut decay = matmul(&w.time_mix_decay_w2, decay, device)?;
+        decay
+            .iter_mut()
+            .zip(&w.time_mix_decay)
+            .for_each(|(d, base)| *d = (-(base + *d).exp()).exp());
+
+        let mut out = wkv6(
+            &mut state.wkv,
+            &r,
+            &k,
+            &v,
+            &decay,
+            &w.time_mix_first,
+            head_size,
+        );
+        for (h, head) in out.chunks_mut(head_size).enumerate() {
+            let range = h * head_size..(h + 1) * head_size;
+            let normed = layer_norm(
+                head,
+                &w.time_mix_ln[range.clone()],
+                &w.time_mix_ln_bias[range],
+                TIME_MIX_GROUP_NORM_EPS,
+            );
+            head.copy_from_slice(&normed);
+        }
+        out.iter_mut().zip(g).for_each(|(o, g)| *o *= silu(g));
+        matmul(&w.time_mix_output, out, device)
+    }
+
+    fn forward_channel_mix(
+        w: &RwkvLayerWeights<T>,
+        xx: &[f32],
+        state: &mut RwkvState,
+        device: &T::Device,
+    ) -> Result<Vec<f32>> {
+        let sx = token_shift(&mut state.ffn_shift, xx);
+        let xk = lerp(xx, &sx, &w.channel_mix_lerp_k);
+        let xr = lerp(xx, &sx, &w.channel_mix_lerp_r);
+
+        let mut k = matmul(&w.channel_mix_key, xk, device)?;
+        k.iter_mut().for_each(|v| *v = v.max(0.0).powi(2));
+        let kv = matmul(&w.channel_mix_value, k, device)?;
+        let r = matmul(&w.channel_mix_receptance, xr, device)?;
+        Ok(r.iter()
+            .zip(kv)
+            .map(|(r, kv)| kv / (1.0 + (-r).exp()))
+            .collect())
+    }
+}
+
+/// the wkv of rwkv v6 on a token over the state of (n_heads, head_size, head_size). each head
+/// takes `out = r @ (diag(u) @ k^T @ v + s)`, and the state moves on by
+/// `s = k^T @ v + diag(w) @ s`.
+pub fn wkv6(
+    state: &mut [f32],
+    r: &[f32],
+    k: &[f32],
+    v: &[f32],
+    w: &[f32],
+    u: &[f32],
+    head_size: usize,
+) -> Vec<f32> {
+    let mut out = vec![0.0; r.len()];
+    for h in 0..r.len() / head_size {
+        let base = h * head_size;
+        let s = &mut state[base * head_size..(base + head_size) * head_size];
+        for i in 0..head_size {
+            let (r, k, w, u) = (r[base + i], k[base + i], w[base + i], u[base + i]);
+            for j in 0..head_size {
+                let kv = k * v[base + j];
+                let s = &mut s[i * head_size + j];
+                out[base + j] += r * (u * kv + *s);
+                *s = kv + w * *s;
+            }
+        }
+    }
+    out
+}
+
+/// returns the difference of the input of the last token to the current one, and keeps the
+/// current one for the next token.
+fn token_shift(prev: &mut [f32], x: &[f32]) -> Vec<f32> {
+    let sx = prev.iter().zip(x).map(|(p, x)| p - x).collect();
+    prev.copy_from_slice(x);
+    sx
+}
+
+fn lerp(x: &[f32], sx: &[f32], mu: &[f32]) -> Vec<f32> {
+    x.iter()
+        .zip(sx)
+        .zip(mu)
+        .map(|((x, sx), mu)| x + sx * mu)
+        .collect()
+}
+
+fn layer_norm(x: &[f32], weight: &[f32], bias: &[f32], eps: f32) -> Vec<f32> {
+    let n = x.len() as f32;
+    let mean = x.iter().sum::<f32>() / n;
+    let var = x.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / n;
+    let scale = 1.0 / (var + eps).sqrt();
+    x.iter()
+        .zip(weight)
+        .zip(bias)
+        .map(|((x, w), b)| (x - mean) * scale * w + b)
+        .collect()
+}
+
+fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+fn matmul<T: Tensor>(w: &T, x: Vec<f32>, device: &T::Device) -> Result<Vec<f32>> {
+    let len = x.len();
+    let x = T::from_vec(x, &[len], device.clone())?;
+    export(&w.matmul_vec(&x)?)
+}
+
+fn export<T: Tensor>(t: &T) -> Result<Vec<f32>> {
+    let mut buf = vec![0.0; t.strider().len()];
+    t.export(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::backends::wgpu::WgpuTensorDevice;
+    use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataArray;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFWriter;
+
+    use super::*;
+    use crate::llama2::Llama2Runner;
+    use crate::model::CpuLlama2Model;
+    use crate::model::WgpuLlama2Model;
+    use crate::sampler::Llama2Sampler;
+
+    const TOKENS: [&str; 16] = [
+        "<s>", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", " ",
+    ];
+
+    /// write a tiny rwkv6 model of 2 layers and 2 heads with the pseudo random weights in the memory,
+    /// the dimensions are in the GGUF order.
+    fn write_rwkv_model() -> Result<GGUFFileLoader> {
+        let (embed, head_size, ffn, lora, vocab) = (32, 16, 64, 4, TOKENS.len());
+        let mut w = GGUFWriter::new();
+        w.add_metadata("general.architecture", GGUFMetadataValue::String("rwkv6"));
+        w.add_metadata("rwkv6.context_length", GGUFMetadataValue::U32(32));
+        w.add_metadata(
+            "rwkv6.embedding_length",
+            GGUFMetadataValue::U32(embed as u32),
+        );
+        w.add_metadata("rwkv6.block_count", GGUFMetadataValue::U32(2));
+        w.add_metadata(
+            "rwkv6.feed_forward_length",
+            GGUFMetadataValue::U32(ffn as u32),
+        );
+        w.add_metadata("rwkv6.attention.head_count", GGUFMetadataValue::U32(0));
+        w.add_metadata(
+            "rwkv6.attention.layer_norm_epsilon",
+            GGUFMetadataValue::F32(1e-5),
+        );
+        w.add_metadata(
+            "rwkv6.wkv.head_size",
+            GGUFMetadataValue::U32(head_size as u32),
+        );
+        w.add_metadata("rwkv6.rescale_every_n_layers", GGUFMetadataValue::U32(2));
+        w.add_metadata("tokenizer.ggml.model", GGUFMetadataValue::String("rwkv"));
+        w.add_metadata(
+            "tokenizer.ggml.tokens",
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(TOKENS.to_vec())),
+        );
+
+        let mut seed = 0;
+        let mut add = |name: &str, dims: &[usize], offset: f32| -> Result<()> {
+            let buf = (0..dims.iter().product::<usize>())
+                .flat_map(|_| {
+                    seed += 1;
+                    let v = offset + (seed as f32 * 12.9898).sin() * 0.5;
+                    v.to_le_bytes()
+                })
+                .collect::<Vec<u8>>();
+            w.add_tensor(name, dims, GGMLType::F32, buf)
+        };
+        add("token_embd.weight", &[embed, vocab], 0.0)?;
+        add("token_embd_norm.weight", &[embed], 1.0)?;
+        add("token_embd_norm.bias", &[embed], 0.0)?;
+        for l in 0..2 {
+            let name = |name: &str| format!("blk.{}.{}", l, name);
+            for norm in ["attn_norm", "attn_norm_2", "time_mix_ln"] {
+                add(&name(&format!("{}.weight", norm)), &[embed], 1.0)?;
+                add(&name(&format!("{}.bias", norm)), &[embed], 0.0)?;
+            }
+            add(&name("time_mix_lerp_x.weight"), &[embed, 1, 1], 0.5)?;
+            add(&name("time_mix_lerp_fused.weight"), &[embed, 1, 1, 5], 0.5)?;
+            add(&name("time_mix_w1.weight"), &[embed, lora * 5], 0.0)?;
+            add(&name("time_mix_w2.weight"), &[lora, embed, 5], 0.0)?;
+            add(&name("time_mix_decay.weight"), &[embed, 1, 1], -1.0)?;
+            add(&name("time_mix_decay_w1.weight"), &[embed, lora], 0.0)?;
+            add(&name("time_mix_decay_w2.weight"), &[lora, embed], 0.0)?;
+            add(
+                &name("time_mix_first.weight"),
+                &[head_size, embed / head_size],
+                0.0,
+            )?;
+            for m in ["receptance", "key", "value", "gate", "output"] {
+                add(
+                    &name(&format!("time_mix_{}.weight", m)),
+                    &[embed, embed],
+                    0.0,
+                )?;
+            }
+            add(&name("channel_mix_lerp_k.weight"), &[embed, 1, 1], 0.5)?;
+            add(&name("channel_mix_lerp_r.weight"), &[embed, 1, 1], 0.5)?;
+            add(&name("channel_mix_key.weight"), &[embed, ffn], 0.0)?;
+            add(&name("channel_mix_value.weight"), &[ffn, embed], 0.0)?;
+            add(&name("channel_mix_receptance.weight"), &[embed, embed], 0.0)?;
+        }
+        add("output_norm.weight", &[embed], 1.0)?;
+        add("output_norm.bias", &[embed], 0.0)?;
+        add("output.weight", &[embed, vocab], 0.0)?;
+
+        GGUFFileLoader::from_writer(&w)
+    }
+
+    #[test]
+    fn test_generate_rwkv() -> Result<()> {
+        let gl = write_rwkv_model()?;
+        let gf = gl.open()?;
+        let device_cpu = CpuTensorDevice::new();
+        let model_cpu = CpuLlama2Model::load(&gf, device_cpu)?;
+        assert_eq!(model_cpu.conf.n_heads, 2);
+        assert_eq!(model_cpu.conf.n_kv_cache_layers(), 0);
+
+        // the activations of all the matmuls are exported to the host through the staging buffer
+        let device_wgpu =
+            WgpuTensorDevice::new(WgpuTensorDeviceOptions::new().with_staging_buf_bytes(1024));
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        // the same token takes different logits on the recurrent state of the tokens before
+        let mut runner_cpu = Llama2Runner::try_from(&model_cpu)?;
+        let mut runner_wgpu = Llama2Runner::try_from(&model_wgpu)?;
+        let mut logits = vec![];
+        for (pos, token) in [1, 2, 1].into_iter().enumerate() {
+            let logits_cpu = runner_cpu.forward(token, pos)?.to_vec();
+            let logits_wgpu = runner_wgpu.forward(token, pos)?.to_vec();
+            for (a, b) in logits_cpu.iter().zip(&logits_wgpu) {
+                assert_relative_eq!(a, b, epsilon = 1e-4);
+            }
+            logits.push(logits_cpu);
+        }
+        assert_ne!(logits[0], logits[2]);
+
+        let mut sampler = Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0);
+        let mut runner_cpu = Llama2Runner::try_from(&model_cpu)?;
+        let mut runner_wgpu = Llama2Runner::try_from(&model_wgpu)?;
+        let output_cpu = runner_cpu
+            .generate("abc", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        let output_wgpu = runner_wgpu
+            .generate("abc", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert!(!output_cpu.is_empty());
+        assert_eq!(output_cpu, output_wgpu);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wkv6() {
+        // 2 heads of size 2 on 3 tokens
+        let head_size = 2;
+        let tokens: Vec<[Vec<f32>; 4]> = (0..3)
+            .map(|t| {
+                let f = |c: usize| -> Vec<f32> {
+                    (0..4)
+                        .map(|i| ((t * 7 + c * 3 + i) % 5) as f32 / 5.0 - 0.3)
+                        .collect()
+                };
+                [
+                    f(0),
+                    f(1),
+                    f(2),
+                    f(3).iter().map(|w| 0.5 + w / 2.0).collect(),
+                ]
+            })
+            .collect();
+        let u = vec![0.3, -0.2, 0.5, 0.1];
+
+        let mut state = vec![0.0; 8];
+        for (t, [r, k, v, w]) in tokens.iter().enumerate() {
+            let out = wkv6(&mut state, r, k, v, w, &u, head_size);
+
+            // out_j = sum_i r_i * (u_i * k_i * v_j + sum_{t' < t} (prod_{t' < s < t} w_i) * k_i * v_j)
+            for h in 0..2 {
+                for j in 0..head_size {
+                    let mut expected = 0.0;
+                    for i in 0..head_size {
+                        let (c, o) = (h * head_size + i, h * head_size + j);
+                        let mut sum = u[c] * k[c] * v[o];
+                        for past in 0..t {
+                            let [_, k, v, _] = &tokens[past];
+                            let decay: f32 = (past + 1..t).map(|s| tokens[s][3][c]).product();
+                            sum += decay * k[c] * v[o];
+                        }
+                        expected += r[c] * sum;
+                    }
+                    assert_relative_eq!(out[h * head_size + j], expected, epsilon = 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_token_shift() {
+        let mut prev = vec![0.0; 2];
+        assert_eq!(token_shift(&mut prev, &[1.0, 2.0]), vec![-1.0, -2.0]);
+        assert_eq!(token_shift(&mut prev, &[3.0, 1.0]), vec![-2.0, 1.0]);
+        assert_eq!(prev, vec![3.0, 1.0]);
+    }
+}
@@ -1,47 +1,326 @@
+use std::collections::HashMap;
+
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::tokenizer::BpeTokenizer;
 use rand::Rng;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+use crate::grammar::Grammar;
+use crate::json_schema::json_schema_to_grammar;
+use crate::logits_processor::DryProcessor;
+use crate::logits_processor::GrammarProcessor;
+pub use crate::logits_processor::Llama2SamplerStage;
+use crate::logits_processor::LogitBiasProcessor;
+use crate::logits_processor::LogitsProcessor;
+use crate::logits_processor::PenaltiesProcessor;
+use crate::logits_processor::TemperatureProcessor;
 
+/// the sampler runs the logits through a chain of the logits processors, then picks the
+/// argmax on the zero temperature, or samples from the softmax of the processed logits.
+///
+/// the chain is the processors in the order they are added by `with_logit_bias`,
+/// `with_grammar`, `with_penalties` and `with_processor`, followed by the temperature and
+/// the truncation stages (top-p, min-p, or the stages set by `with_stages`).
 pub struct Llama2Sampler {
-    prob_index: Vec<(f32, usize)>,
+    vocab_size: usize,
     temperature: f32,
     topp: f32,
+    minp: f32,
+    stages: Vec<Llama2SamplerStage>,
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    n_logprobs: Option<usize>,
+    logprobs: Option<Llama2SamplerLogprobs>,
+    rng: ChaCha12Rng, // the same as StdRng, but the state can be saved
+}
+
+/// the log probabilities on a sampling step, computed from the raw logits of the model
+/// before any processor or the temperature is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2SamplerLogprobs {
+    /// the sampled token and its log probability.
+    pub token: usize,
+    pub logprob: f32,
+    /// the most likely tokens with their log probabilities, in descending order.
+    pub top: Vec<(usize, f32)>,
+}
+
+impl Llama2SamplerLogprobs {
+    fn new(logits: &[f32], token: usize, top_n: usize) -> Self {
+        let mut logprobs = logits.to_vec();
+        log_softmax(&mut logprobs);
+
+        let mut top = logprobs.iter().copied().enumerate().collect::<Vec<_>>();
+        let cmp = |a: &(usize, f32), b: &(usize, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+        let top_n = top_n.min(top.len());
+        if top_n > 0 && top_n < top.len() {
+            top.select_nth_unstable_by(top_n - 1, cmp);
+        }
+        top.truncate(top_n);
+        top.sort_by(cmp);
+
+        Self {
+            token,
+            logprob: logprobs[token],
+            top,
+        }
+    }
+}
+
+/// the state of the random number generator of the sampler, to resume the sampling later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Llama2SamplerRng {
+    pub seed: [u8; 32],
+    pub stream: u64,
+    pub word_pos: u128,
+}
+
+/// the penalties are applied on the logits of the tokens appeared in the last `last_n`
+/// tokens of the history, to make the model less likely to repeat itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Llama2SamplerPenalties {
+    /// the number of the recent tokens to penalize, 0 disables the penalties.
+    pub last_n: usize,
+    /// divide the positive logits and multiply the negative logits, 1.0 means disabled.
+    pub repeat: f32,
+    /// subtract `frequency * count` from the logits, 0.0 means disabled.
+    pub frequency: f32,
+    /// subtract `presence` from the logits once if the token appeared, 0.0 means disabled.
+    pub presence: f32,
+}
+
+impl Default for Llama2SamplerPenalties {
+    fn default() -> Self {
+        Self {
+            last_n: 64,
+            repeat: 1.0,
+            frequency: 0.0,
+            presence: 0.0,
+        }
+    }
+}
+
+impl Llama2SamplerPenalties {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.last_n > 0 && (self.repeat != 1.0 || self.frequency != 0.0 || self.presence != 0.0)
+    }
+}
+
+/// the DRY (don't repeat yourself) penalty, it penalizes the tokens which would extend a
+/// sequence already appeared in the context, the penalty grows exponentially with the
+/// length of the repeated sequence: `multiplier * base ^ (len - allowed_length)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Llama2SamplerDry {
+    /// the strength of the penalty, 0.0 disables it.
+    pub multiplier: f32,
+    pub base: f32,
+    /// the repeated sequences not longer than it are not penalized.
+    pub allowed_length: usize,
+    /// the number of the recent tokens to search the repetitions, 0 disables it.
+    pub last_n: usize,
+    /// the matching of the repeated sequences stops at the tokens containing any of them,
+    /// so the repetitions of the boilerplates like the names of the speakers in a chat won't
+    /// be penalized across the lines.
+    pub sequence_breakers: Vec<String>,
+}
+
+impl Default for Llama2SamplerDry {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.0,
+            base: 1.75,
+            allowed_length: 2,
+            last_n: 1024,
+            sequence_breakers: ["\n", ":", "\"", "*"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Llama2SamplerDry {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.multiplier > 0.0 && self.last_n > 0
+    }
 }
 
 impl Llama2Sampler {
     pub fn new(vocab_size: usize, temperature: f32, topp: f32) -> Self {
         Self {
-            prob_index: vec![(0.0, 0); vocab_size],
+            vocab_size,
             temperature,
             topp,
+            minp: 0.0,
+            stages: vec![],
+            processors: vec![],
+            n_logprobs: None,
+            logprobs: None,
+            rng: ChaCha12Rng::from_entropy(),
+        }
+    }
+
+    /// append a processor to the chain, it's applied before the temperature.
+    pub fn with_processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    /// truncate the candidates with the stages in order after applying the temperature,
+    /// it replaces the top-p and min-p sampling if set.
+    pub fn with_stages(mut self, stages: Vec<Llama2SamplerStage>) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    /// constrain the sampled text to the grammar, see `GrammarProcessor`.
+    pub fn with_grammar(self, grammar: Grammar, tokenizer: &BpeTokenizer) -> Self {
+        self.with_processor(GrammarProcessor::new(grammar, tokenizer))
+    }
+
+    /// add the bias to the logits of the tokens before sampling, a bias of `f32::NEG_INFINITY`
+    /// bans the token.
+    pub fn with_logit_bias(self, logit_bias: HashMap<usize, f32>) -> Self {
+        self.with_processor(LogitBiasProcessor::new(logit_bias))
+    }
+
+    /// constrain the sampled text to a JSON value of the schema, see `json_schema` for the
+    /// supported keywords.
+    pub fn with_json_schema(
+        self,
+        schema: &serde_json::Value,
+        tokenizer: &BpeTokenizer,
+    ) -> Result<Self> {
+        let grammar = Grammar::parse(&json_schema_to_grammar(schema)?)?;
+        Ok(self.with_grammar(grammar, tokenizer))
+    }
+
+    /// seed the random number generator to make the sampling reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
+        self
+    }
+
+    /// the state of the random number generator, which is saved in the sessions.
+    pub fn rng(&self) -> Llama2SamplerRng {
+        Llama2SamplerRng {
+            seed: self.rng.get_seed(),
+            stream: self.rng.get_stream(),
+            word_pos: self.rng.get_word_pos(),
+        }
+    }
+
+    pub fn set_rng(&mut self, rng: Llama2SamplerRng) {
+        self.rng = ChaCha12Rng::from_seed(rng.seed);
+        self.rng.set_stream(rng.stream);
+        self.rng.set_word_pos(rng.word_pos);
+    }
+
+    pub fn with_penalties(self, penalties: Llama2SamplerPenalties) -> Self {
+        if !penalties.is_enabled() {
+            return self;
+        }
+        self.with_processor(PenaltiesProcessor::new(penalties))
+    }
+
+    /// penalize the tokens extending the repeated sequences, see `Llama2SamplerDry`.
+    pub fn with_dry(self, dry: Llama2SamplerDry, tokenizer: &BpeTokenizer) -> Self {
+        if !dry.is_enabled() {
+            return self;
+        }
+        self.with_processor(DryProcessor::new(dry, tokenizer))
+    }
+
+    /// enable min-p sampling instead of top-p, the tokens with a probability lower than
+    /// `minp * max_prob` are discarded. it works better than top-p on high temperatures,
+    /// since the cutoff is scaled with the confidence of the top token.
+    pub fn with_minp(mut self, minp: f32) -> Self {
+        self.minp = minp;
+        self
+    }
+
+    /// record the log probabilities of the sampled token and the `top_n` most likely
+    /// tokens on each step, they can be taken by `take_logprobs` after sampling.
+    pub fn with_logprobs(mut self, top_n: usize) -> Self {
+        self.n_logprobs = Some(top_n);
+        self
+    }
+
+    /// take the log probabilities of the last sampled token, None if `with_logprobs` is
+    /// not enabled.
+    pub fn take_logprobs(&mut self) -> Option<Llama2SamplerLogprobs> {
+        self.logprobs.take()
+    }
+
+    /// accept the sampled token, like advancing the grammar state and recording the
+    /// token into the history of the penalties.
+    pub fn accept(&mut self, token: usize) {
+        for processor in self.processors.iter_mut() {
+            processor.accept(token);
         }
     }
 
+    /// reset the processors on starting a new generation after the prompt.
+    pub fn reset(&mut self, prompt: &[usize]) {
+        for processor in self.processors.iter_mut() {
+            processor.reset(prompt);
+        }
+    }
+
+    /// process the logits with the chain and pick the next token, the caller should call
+    /// `accept` with the picked token if it's used.
     pub fn sample(&mut self, logits: &mut [f32]) -> Result<usize> {
+        if logits.len() != self.vocab_size {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "expected {} logits, but got {}",
+                    self.vocab_size,
+                    logits.len()
+                ),
+                cause: None,
+            });
+        }
+        let span = tracing::trace_span!("sample", token = tracing::field::Empty).entered();
+        let raw_logits = self.n_logprobs.map(|_| logits.to_vec());
+        let token = self.sample_processed(logits)?;
+        span.record("token", token);
+        if let (Some(top_n), Some(raw_logits)) = (self.n_logprobs, raw_logits) {
+            self.logprobs = Some(Llama2SamplerLogprobs::new(&raw_logits, token, top_n));
+        }
+        Ok(token)
+    }
+
+    fn sample_processed(&mut self, logits: &mut [f32]) -> Result<usize> {
+        for processor in self.processors.iter_mut() {
+            processor.process(logits)?;
+        }
+
         if self.temperature == 0.0 {
             return Self::sample_argmax(logits);
         }
-
-        // apply the temperature to the logits. the lower the temperature,
-        // the more deterministic the sampling.
-        for logit in logits.iter_mut() {
-            *logit /= self.temperature;
+        TemperatureProcessor::new(self.temperature).process(logits)?;
+        for mut stage in self.truncation_stages() {
+            stage.process(logits)?;
         }
+
         // apply softmax to the logits to get the probabilities for next token
         softmax(logits);
 
         // flip a (float) coin (this is our source of entropy for sampling)
-        let mut rng = rand::thread_rng();
-        let coin: f32 = rng.gen_range(0.0..1.0);
+        let coin: f32 = self.rng.gen_range(0.0..1.0);
+        Ok(Self::sample_multi(logits, coin))
+    }
 
-        // we sample from this distribution to get the next token
-        if self.topp <= 0_f32 || self.topp >= 1.0_f32 {
-            // simply sample from the predicted probability distribution
-            Self::sample_multi(logits, coin);
+    fn truncation_stages(&self) -> Vec<Llama2SamplerStage> {
+        if !self.stages.is_empty() {
+            self.stages.clone()
+        } else if self.minp > 0_f32 && self.minp < 1.0_f32 {
+            vec![Llama2SamplerStage::MinP(self.minp)]
+        } else if self.topp > 0_f32 && self.topp < 1.0_f32 {
+            vec![Llama2SamplerStage::TopP(self.topp)]
+        } else {
+            vec![]
         }
-
-        Self::sample_topp(logits, self.topp, &mut self.prob_index, coin)
     }
 
     pub fn sample_multi(probs: &[f32], coin: f32) -> usize {
@@ -57,50 +336,6 @@ impl Llama2Sampler {
         probs.len() - 1 // in case of rounding errors
     }
 
-    pub fn sample_topp(
-        probs: &[f32],
-        topp: f32,
-        prob_index: &mut [(f32, usize)],
-        coin: f32,
-    ) -> Result<usize> {
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability topp. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-        // coin is a random number in [0, 1), usually from random_f32()
-
-        let cutoff = (1.0_f32 - topp) / (probs.len() - 1) as f32;
-        let mut n0 = 0;
-        for (i, prob) in probs.iter().enumerate() {
-            if *prob >= cutoff {
-                prob_index[n0] = (probs[i], i);
-                n0 += 1;
-            }
-        }
-        prob_index[..n0].sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-
-        // truncate the list where cumulative probability exceeds topp
-        let mut cumulative_prob = 0_f32;
-        let mut last_idx = n0 - 1; // in case of rounding errors consider all elements
-        for (i, prob) in prob_index[0..n0].iter().enumerate() {
-            cumulative_prob += prob.0;
-            if cumulative_prob > topp {
-                last_idx = i;
-                break; // we've exceeded topp by including last_idx
-            }
-        }
-
-        // sample from the truncated list
-        let r = coin * cumulative_prob;
-        let mut cdf = 0_f32;
-        for prob in prob_index[0..=last_idx].iter() {
-            cdf += prob.0;
-            if cdf > r {
-                return Ok(prob.1);
-            }
-        }
-        Ok(prob_index[last_idx].1) // in case of rounding errors
-    }
-
     pub fn sample_argmax(probs: &[f32]) -> Result<usize> {
         probs
             .iter()
@@ -126,3 +361,192 @@ pub fn softmax(a: &mut [f32]) {
         *a /= sum;
     }
 }
+
+pub fn log_softmax(a: &mut [f32]) {
+    let max = a.iter().fold(f32::NAN, |a, b| a.max(*b));
+    let log_sum = a.iter().map(|a| (*a - max).exp()).sum::<f32>().ln();
+    for a in a.iter_mut() {
+        *a -= max + log_sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_minp() -> Result<()> {
+        let probs = [0.05_f32, 0.5, 0.1, 0.3, 0.05];
+        let sample_set = |sampler: &mut Llama2Sampler| {
+            let mut picks = (0..64)
+                .map(|_| sampler.sample(&mut probs.iter().map(|p| p.ln()).collect::<Vec<_>>()))
+                .collect::<Result<Vec<_>>>()?;
+            picks.sort();
+            picks.dedup();
+            Ok::<_, crabml::error::Error>(picks)
+        };
+
+        // only the tokens 1 and 3 are kept with the cutoff 0.5 * 0.5
+        let mut sampler = Llama2Sampler::new(probs.len(), 1.0, 0.0)
+            .with_stages(vec![Llama2SamplerStage::MinP(0.5)])
+            .with_seed(42);
+        assert_eq!(sample_set(&mut sampler)?, vec![1, 3]);
+
+        // all the tokens are kept with a tiny min-p
+        let mut sampler = Llama2Sampler::new(probs.len(), 1.0, 0.0)
+            .with_stages(vec![Llama2SamplerStage::MinP(0.01)])
+            .with_seed(42);
+        assert_eq!(sample_set(&mut sampler)?, vec![0, 1, 2, 3, 4]);
+
+        // min-p takes the place of top-p
+        let mut sampler = Llama2Sampler::new(probs.len(), 1.0, 0.9)
+            .with_minp(0.99)
+            .with_seed(42);
+        assert_eq!(sample_set(&mut sampler)?, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed() -> Result<()> {
+        let logits = (0..32).map(|i| (i % 5) as f32).collect::<Vec<_>>();
+        let sample_n = |sampler: &mut Llama2Sampler| {
+            (0..16)
+                .map(|_| sampler.sample(&mut logits.clone()))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let mut s1 = Llama2Sampler::new(32, 1.0, 0.9).with_seed(42);
+        let mut s2 = Llama2Sampler::new(32, 1.0, 0.9).with_seed(42);
+        let mut s3 = Llama2Sampler::new(32, 1.0, 0.9).with_seed(7);
+        let picks = sample_n(&mut s1)?;
+        assert_eq!(picks, sample_n(&mut s2)?);
+        assert_ne!(picks, sample_n(&mut s3)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_logit_bias() -> Result<()> {
+        let logit_bias = HashMap::from([(1, f32::NEG_INFINITY), (3, 2.5), (100, 1.0)]);
+        let mut sampler = Llama2Sampler::new(4, 0.0, 0.0).with_logit_bias(logit_bias);
+        let mut logits = vec![1.0, 4.0, 2.0, 1.0];
+        assert_eq!(sampler.sample(&mut logits)?, 3);
+        assert_eq!(logits, vec![1.0, f32::NEG_INFINITY, 2.0, 3.5]);
+
+        // the banned token is never sampled
+        let logit_bias = HashMap::from([(1, f32::NEG_INFINITY)]);
+        let mut sampler = Llama2Sampler::new(4, 1.0, 0.0)
+            .with_logit_bias(logit_bias)
+            .with_seed(42);
+        for _ in 0..32 {
+            assert_ne!(sampler.sample(&mut [1.0, 4.0, 2.0, 1.0])?, 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_grammar() -> Result<()> {
+        let vocab = ["<unk>", "<s>", "</s>", "yes", "no", ",", "x", "▁no"];
+        let tokenizer = BpeTokenizer::new(
+            vocab.iter().map(|s| s.to_string()).collect(),
+            vec![0.0; vocab.len()],
+            1,
+            2,
+        );
+        let grammar = Grammar::parse(r#"root ::= ("yes" | "no") ("," ("yes" | "no"))*"#)?;
+        let mut sampler =
+            Llama2Sampler::new(vocab.len(), 0.0, 0.0).with_grammar(grammar, &tokenizer);
+
+        // the preferred x and eos are masked out at first
+        let tokens = [
+            [0.0, 0.0, 5.0, 1.0, 2.0, 3.0, 10.0, 4.0],
+            [0.0, 0.0, 1.0, 1.0, 2.0, 3.0, 10.0, 4.0],
+            [0.0, 0.0, 5.0, 1.0, 2.0, 3.0, 10.0, 4.0],
+            [0.0, 0.0, 5.0, 1.0, 2.0, 3.0, 10.0, 4.0],
+        ]
+        .iter()
+        .map(|logits| {
+            let token = sampler.sample(&mut logits.clone())?;
+            sampler.accept(token);
+            Ok(token)
+        })
+        .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens, vec![4, 5, 4, 2]);
+
+        let mut logits = [f32::NEG_INFINITY; 8];
+        logits[6] = 1.0;
+        assert!(sampler.sample(&mut logits).is_err());
+
+        sampler.reset(&[]);
+        assert_eq!(
+            sampler.sample(&mut [0.0, 0.0, 5.0, 3.0, 2.0, 3.0, 1.0, 4.0])?,
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_penalties() -> Result<()> {
+        let mut sampler = Llama2Sampler::new(4, 0.0, 0.0).with_penalties(Llama2SamplerPenalties {
+            last_n: 3,
+            repeat: 2.0,
+            frequency: 0.5,
+            presence: 0.25,
+        });
+        sampler.reset(&[0, 1]);
+        sampler.accept(1);
+
+        // the repeated token is no longer the argmax
+        let mut logits = vec![1.0, 4.0, 2.0, -1.0];
+        assert_eq!(sampler.sample(&mut logits)?, 2);
+
+        sampler.reset(&[]);
+        let mut logits = vec![1.0, 4.0, 2.0, -1.0];
+        assert_eq!(sampler.sample(&mut logits)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_processor() -> Result<()> {
+        struct BanEven;
+        impl LogitsProcessor for BanEven {
+            fn process(&mut self, logits: &mut [f32]) -> Result<()> {
+                for logit in logits.iter_mut().step_by(2) {
+                    *logit = f32::NEG_INFINITY;
+                }
+                Ok(())
+            }
+        }
+
+        // the processors run in the order they are added
+        let mut sampler = Llama2Sampler::new(4, 0.0, 0.0)
+            .with_processor(BanEven)
+            .with_logit_bias(HashMap::from([(2, 10.0), (3, -10.0)]));
+        assert_eq!(sampler.sample(&mut [1.0, 2.0, 4.0, 3.0])?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_logprobs() -> Result<()> {
+        let probs = [0.1_f32, 0.4, 0.2, 0.3];
+        let logits = probs.iter().map(|p| p.ln() + 1.0).collect::<Vec<_>>();
+
+        // the logprobs are taken on the raw logits, not the biased ones
+        let mut sampler = Llama2Sampler::new(4, 0.0, 0.0)
+            .with_logit_bias(HashMap::from([(0, 10.0)]))
+            .with_logprobs(2);
+        assert_eq!(sampler.sample(&mut logits.clone())?, 0);
+        let logprobs = sampler.take_logprobs().unwrap();
+        assert_eq!(logprobs.token, 0);
+        assert!((logprobs.logprob - 0.1_f32.ln()).abs() < 1e-5);
+        assert_eq!(logprobs.top.len(), 2);
+        assert_eq!((logprobs.top[0].0, logprobs.top[1].0), (1, 3));
+        assert!((logprobs.top[0].1 - 0.4_f32.ln()).abs() < 1e-5);
+        assert!((logprobs.top[1].1 - 0.3_f32.ln()).abs() < 1e-5);
+        assert_eq!(sampler.take_logprobs(), None);
+
+        let mut sampler = Llama2Sampler::new(4, 0.0, 0.0);
+        sampler.sample(&mut logits.clone())?;
+        assert_eq!(sampler.take_logprobs(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFWriter;
+use crabml::gguf::KEY_GENERAL_ARCHITECTURE;
+
+use crate::rwkv::RwkvState;
+use crate::sampler::Llama2SamplerRng;
+
+const KEY_SESSION_TOKENS: &str = "session.tokens";
+const KEY_SESSION_KV_DIM: &str = "session.kv_dim";
+const KEY_SESSION_RNG_SEED: &str = "session.rng.seed";
+const KEY_SESSION_RNG_STREAM: &str = "session.rng.stream";
+const KEY_SESSION_RNG_WORD_POS: &str = "session.rng.word_pos";
+
+/// the state of a generation saved on the disk, so a long prompt is forwarded once and reused
+/// across the runs, like the session file of llama.cpp. it's taken by `Llama2Runner::session`
+/// and restored by `Llama2Runner::restore_session`.
+///
+/// the session is saved as a GGUF file, the tokens and the rng state are kept in the metadata,
+/// and the cached rows of each layer are kept in the f32 tensors.
+#[derive(Debug, Clone)]
+pub struct Llama2Session {
+    /// the tokens forwarded on the kv cache.
+    pub tokens: Vec<usize>,
+    /// the state of the rng of the sampler.
+    pub rng: Llama2SamplerRng,
+    pub(crate) kv_dim: usize,
+    pub(crate) keys: Vec<Vec<f32>>,   // (layer, n_rows * kv_dim)
+    pub(crate) values: Vec<Vec<f32>>, // (layer, n_rows * kv_dim)
+    pub(crate) rwkv_state: Vec<RwkvState>,
+}
+
+impl Llama2Session {
+    pub fn save(&self, path: &str) -> Result<()> {
+        let tokens = self.tokens.iter().map(|t| *t as u32).collect::<Vec<_>>();
+        let word_pos = [self.rng.word_pos as u64, (self.rng.word_pos >> 64) as u64];
+        let mut w = GGUFWriter::new();
+        w.add_metadata(
+            KEY_GENERAL_ARCHITECTURE,
+            GGUFMetadataValue::String("session"),
+        );
+        w.add_metadata(
+            KEY_SESSION_TOKENS,
+            GGUFMetadataValue::Array(GGUFMetadataArray::U32Array(&tokens)),
+        );
+        w.add_metadata(
+            KEY_SESSION_KV_DIM,
+            GGUFMetadataValue::U32(self.kv_dim as u32),
+        );
+        w.add_metadata(
+            KEY_SESSION_RNG_SEED,
+            GGUFMetadataValue::Array(GGUFMetadataArray::U8Array(&self.rng.seed)),
+        );
+        w.add_metadata(
+            KEY_SESSION_RNG_STREAM,
+            GGUFMetadataValue::U64(self.rng.stream),
+        );
+        w.add_metadata(
+            KEY_SESSION_RNG_WORD_POS,
+            GGUFMetadataValue::Array(GGUFMetadataArray::U64Array(&word_pos)),
+        );
+
+        for (l, (keys, values)) in self.keys.iter().zip(self.values.iter()).enumerate() {
+            let dims = [self.kv_dim, keys.len() / self.kv_dim.max(1)];
+            w.add_tensor(
+                &format!("cache_k.{}", l),
+                &dims,
+                GGMLType::F32,
+                f32_bytes(keys),
+            )?;
+            w.add_tensor(
+                &format!("cache_v.{}", l),
+                &dims,
+                GGMLType::F32,
+                f32_bytes(values),
+            )?;
+        }
+        for (l, state) in self.rwkv_state.iter().enumerate() {
+            for (name, buf) in [
+                ("rwkv_att_shift", &state.att_shift),
+                ("rwkv_ffn_shift", &state.ffn_shift),
+                ("rwkv_wkv", &state.wkv),
+            ] {
+                let name = format!("{}.{}", name, l);
+                w.add_tensor(&name, &[buf.len()], GGMLType::F32, f32_bytes(buf))?;
+            }
+        }
+        w.write_to_file(path)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let loader = GGUFFileLoader::new(path)?;
+        let gf = loader.open()?;
+        let metadata = gf.metadata();
+        let not_session = || Error {
+            kind: ErrorKind::FormatError,
+            message: format!("the file {} is not a session", path),
+            cause: None,
+        };
+        let tokens = metadata
+            .get_u32_array(KEY_SESSION_TOKENS)
+            .ok_or_else(not_session)?;
+        let kv_dim = metadata
+            .get_u32(KEY_SESSION_KV_DIM)
+            .ok_or_else(not_session)?;
+        let seed = metadata
+            .get_u8_array(KEY_SESSION_RNG_SEED)
+            .and_then(|seed| seed.try_into().ok())
+            .ok_or_else(not_session)?;
+        let stream = metadata
+            .get_u64(KEY_SESSION_RNG_STREAM)
+            .ok_or_else(not_session)?;
+        let word_pos = match metadata.get_u64_array(KEY_SESSION_RNG_WORD_POS) {
+            Some([lo, hi]) => *lo as u128 | (*hi as u128) << 64,
+            _ => return Err(not_session()),
+        };
+
+        let tensor = |name: String| -> Option<Vec<f32>> {
+            let info = gf.get_tensor_info(&name)?;
+            match info.typ() {
+                GGMLType::F32 => Some(f32_from_bytes(info.data())),
+                _ => None,
+            }
+        };
+        let mut keys = vec![];
+        let mut values = vec![];
+        while let Some(k) = tensor(format!("cache_k.{}", keys.len())) {
+            let v = tensor(format!("cache_v.{}", keys.len())).ok_or_else(not_session)?;
+            keys.push(k);
+            values.push(v);
+        }
+        let mut rwkv_state = vec![];
+        while let Some(att_shift) = tensor(format!("rwkv_att_shift.{}", rwkv_state.len())) {
+            let l = rwkv_state.len();
+            rwkv_state.push(RwkvState {
+                att_shift,
+                ffn_shift: tensor(format!("rwkv_ffn_shift.{}", l)).ok_or_else(not_session)?,
+                wkv: tensor(format!("rwkv_wkv.{}", l)).ok_or_else(not_session)?,
+            });
+        }
+
+        Ok(Self {
+            tokens: tokens.iter().map(|t| *t as usize).collect(),
+            rng: Llama2SamplerRng {
+                seed,
+                stream,
+                word_pos,
+            },
+            kv_dim: kv_dim as usize,
+            keys,
+            values,
+            rwkv_state,
+        })
+    }
+}
+
+fn f32_bytes(buf: &[f32]) -> Vec<u8> {
+    buf.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub(crate) fn f32_from_bytes(buf: &[u8]) -> Vec<f32> {
+    buf.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
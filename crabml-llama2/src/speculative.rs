@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use crabml::time::Instant;
+use crabml::tokenizer::BpeSpecialMode;
+use crabml::tokenizer::BpeStreamDecoder;
+
+use crate::llama2::Llama2GeneratedToken;
+use crate::llama2::Llama2Runner;
+use crate::llama2::Llama2TokenLogprobs;
+use crate::sampler::Llama2Sampler;
+
+/// how the draft tokens are proposed.
+enum Llama2Drafter<'a, T: Tensor> {
+    /// the most likely tokens of a small model sharing the vocab.
+    Model(&'a mut Llama2Runner<T>),
+    /// the tokens following the latest match of the last n-gram in the context, which needs
+    /// no second model, like the prompt lookup decoding.
+    PromptLookup { max_ngram: usize },
+}
+
+/// generate with the tokens proposed by a drafter, the target model verifies all the draft
+/// tokens in one batched pass, and keeps the ones matching its own samples.
+///
+/// the target samples every position with its sampler as usual, a draft token is accepted
+/// only if it's the same as the sampled one, so the output is exactly the same as `generate`
+/// with the same sampler, only faster if the draft model guesses well, like on the greedy or
+/// the low temperature generation.
+pub struct Llama2SpeculativeGenerator<'a, T: Tensor> {
+    runner: &'a mut Llama2Runner<T>,
+    drafter: Llama2Drafter<'a, T>,
+    sampler: &'a mut Llama2Sampler,
+    n_draft: usize,
+    steps: usize,
+    prompt_len: usize,
+    tokens: Vec<usize>, // the prompt and the generated tokens
+    decoder: BpeStreamDecoder,
+    ready: VecDeque<Llama2GeneratedToken>,
+    n_drafted: usize,
+    n_accepted: usize,
+    stopped: bool,
+}
+
+impl<'a, T: Tensor> Llama2SpeculativeGenerator<'a, T> {
+    fn new(
+        runner: &'a mut Llama2Runner<T>,
+        mut drafter: Llama2Drafter<'a, T>,
+        sampler: &'a mut Llama2Sampler,
+        prompt: &str,
+        steps: usize,
+        n_draft: usize,
+    ) -> Result<Self> {
+        if let Llama2Drafter::Model(draft) = &drafter {
+            if runner.conf.vocab_size != draft.conf.vocab_size {
+                return Err((
+                    ErrorKind::BadInput,
+                    "the draft model does not share the vocab with the target model",
+                )
+                    .into());
+            }
+            if !draft.rwkv_state.is_empty() {
+                return Err((
+                    ErrorKind::NotImplemented,
+                    "the speculative decoding on the recurrent state is not supported yet",
+                )
+                    .into());
+            }
+        }
+        if !runner.rwkv_state.is_empty() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the speculative decoding on the recurrent state is not supported yet",
+            )
+                .into());
+        }
+
+        let tokenizer = &runner.tokenizer;
+        let tokens = tokenizer.encode_special(
+            prompt,
+            tokenizer.add_bos_token(),
+            tokenizer.add_eos_token(),
+        )?;
+        if tokens.is_empty() {
+            return Err((
+                ErrorKind::BadInput,
+                "something is wrong, expected at least 1 prompt token",
+            )
+                .into());
+        }
+        sampler.reset(&tokens);
+
+        // the prompt except the last token is forwarded on the models
+        let prompt_len = tokens.len();
+        let mut runners = vec![&mut *runner];
+        if let Llama2Drafter::Model(draft) = &mut drafter {
+            runners.push(draft);
+        }
+        for runner in runners {
+            runner.truncate(0)?;
+            let batch_size = runner.batch_size;
+            for (i, batch) in tokens[..prompt_len - 1].chunks(batch_size).enumerate() {
+                runner.forward_batch(batch, i * batch_size)?;
+            }
+        }
+
+        Ok(Self {
+            runner,
+            drafter,
+            sampler,
+            n_draft,
+            steps,
+            prompt_len,
+            tokens,
+            decoder: BpeStreamDecoder::new(BpeSpecialMode::Skip),
+            ready: VecDeque::new(),
+            n_drafted: 0,
+            n_accepted: 0,
+            stopped: false,
+        })
+    }
+
+    /// the ratio of the draft tokens accepted by the target model.
+    pub fn acceptance_rate(&self) -> f32 {
+        self.n_accepted as f32 / self.n_drafted.max(1) as f32
+    }
+
+    /// generate the next token, returns None on the end of the generation.
+    pub fn next_token(&mut self) -> Result<Option<Llama2GeneratedToken>> {
+        if self.ready.is_empty() && !self.stopped {
+            self.forward_next()?;
+        }
+        Ok(self.ready.pop_front())
+    }
+
+    /// draft the tokens and verify them on the target model, the accepted tokens and the one
+    /// sampled after them are kept in the ready tokens.
+    fn forward_next(&mut self) -> Result<()> {
+        let start_time = Instant::now();
+        let pos = self.tokens.len() - 1;
+        let seq_len = match &self.drafter {
+            Llama2Drafter::Model(draft) => self.runner.conf.seq_len.min(draft.conf.seq_len),
+            Llama2Drafter::PromptLookup { .. } => self.runner.conf.seq_len,
+        };
+        let end = seq_len.min(self.steps + self.prompt_len);
+        if pos >= end {
+            self.stopped = true;
+            return Ok(());
+        }
+
+        // the draft tokens are taken at most to the end of the generation
+        let n_draft = self.n_draft.min(end - pos - 1);
+        let drafted = self.draft_tokens(n_draft)?;
+        self.n_drafted += drafted.len();
+
+        // verify the draft tokens in one pass, the logits of the last token are taken as well
+        let mut batch = vec![self.tokens[pos]];
+        batch.extend_from_slice(&drafted);
+        let logits = self.runner.forward_batch_logits(&batch, pos)?;
+        for (i, mut logits) in logits.into_iter().enumerate() {
+            let token = self.sampler.sample(&mut logits)?;
+            self.sampler.accept(token);
+
+            // data-dependent terminating condition: the BOS token delimits sequences
+            let tokenizer = &self.runner.tokenizer;
+            if token == tokenizer.bos_token() || token == tokenizer.eos_token() {
+                self.stopped = true;
+                break;
+            }
+
+            let prev_token = *self.tokens.last().unwrap();
+            self.tokens.push(token);
+            let logprobs = self
+                .sampler
+                .take_logprobs()
+                .map(|logprobs| {
+                    Llama2TokenLogprobs::decode(
+                        tokenizer,
+                        prev_token,
+                        logprobs,
+                        BpeSpecialMode::Skip,
+                    )
+                })
+                .transpose()?;
+            self.ready.push_back(Llama2GeneratedToken {
+                token,
+                text: self.decoder.decode(tokenizer, prev_token, token)?,
+                pos: self.tokens.len() - 1,
+                elapsed: start_time.elapsed(),
+                logprobs,
+            });
+            if drafted.get(i) != Some(&token) {
+                break;
+            }
+            self.n_accepted += 1;
+        }
+
+        // drop the rows of the rejected draft tokens
+        let n_forwarded = self.tokens.len() - 1;
+        self.runner.truncate(n_forwarded)?;
+        if let Llama2Drafter::Model(draft) = &mut self.drafter {
+            draft.truncate(n_forwarded)?;
+        }
+        Ok(())
+    }
+
+    fn draft_tokens(&mut self, n: usize) -> Result<Vec<usize>> {
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        let draft = match &mut self.drafter {
+            Llama2Drafter::Model(draft) => draft,
+            Llama2Drafter::PromptLookup { max_ngram } => {
+                return Ok(lookup_ngram(&self.tokens, *max_ngram, n));
+            }
+        };
+
+        // forward the draft model on the tokens it has not seen, and take the most likely
+        // tokens one by one
+        let n_seen = draft.tokens().len();
+        let mut logits = draft
+            .forward_batch(&self.tokens[n_seen..], n_seen)?
+            .to_vec();
+        let mut drafted = vec![];
+        loop {
+            let token = Llama2Sampler::sample_argmax(&logits)?;
+            drafted.push(token);
+            if drafted.len() == n {
+                return Ok(drafted);
+            }
+            let pos = self.tokens.len() + drafted.len() - 1;
+            logits = draft.forward(token, pos)?.to_vec();
+        }
+    }
+}
+
+/// the at most `n` tokens following the latest earlier match of the last n-gram of the
+/// tokens, the longer n-grams up to `max_ngram` are tried first.
+fn lookup_ngram(tokens: &[usize], max_ngram: usize, n: usize) -> Vec<usize> {
+    for ngram in (1..=max_ngram.min(tokens.len().saturating_sub(1))).rev() {
+        let suffix = &tokens[tokens.len() - ngram..];
+        let found = (0..tokens.len() - ngram)
+            .rev()
+            .find(|start| &tokens[*start..*start + ngram] == suffix);
+        if let Some(start) = found {
+            let from = start + ngram;
+            return tokens[from..(from + n).min(tokens.len())].to_vec();
+        }
+    }
+    vec![]
+}
+
+impl<'a, T: Tensor> Iterator for Llama2SpeculativeGenerator<'a, T> {
+    type Item = Result<Llama2GeneratedToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().transpose()
+    }
+}
+
+impl<'a, T: Tensor> Llama2Runner<T> {
+    /// generate like `generate` with the tokens proposed by the draft model, at most
+    /// `n_draft` tokens are drafted on each step. the draft model is expected to share the
+    /// vocab with this one, like a small model of the same family.
+    pub fn generate_speculative(
+        &'a mut self,
+        draft: &'a mut Llama2Runner<T>,
+        prompt: &str,
+        steps: usize,
+        n_draft: usize,
+        sampler: &'a mut Llama2Sampler,
+    ) -> Result<Llama2SpeculativeGenerator<'a, T>> {
+        let drafter = Llama2Drafter::Model(draft);
+        Llama2SpeculativeGenerator::new(self, drafter, sampler, prompt, steps, n_draft)
+    }
+
+    /// generate like `generate` with the tokens drafted from the matches of the last
+    /// n-gram in the context, which speeds up the outputs repeating the prompt, like the
+    /// code editing or the question answering on a document.
+    pub fn generate_prompt_lookup(
+        &'a mut self,
+        prompt: &str,
+        steps: usize,
+        n_draft: usize,
+        max_ngram: usize,
+        sampler: &'a mut Llama2Sampler,
+    ) -> Result<Llama2SpeculativeGenerator<'a, T>> {
+        let drafter = Llama2Drafter::PromptLookup { max_ngram };
+        Llama2SpeculativeGenerator::new(self, drafter, sampler, prompt, steps, n_draft)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_generate_speculative() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let gl_draft = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf_draft = gl_draft.open()?;
+        let lm_draft = CpuLlama2Model::load(&gf_draft, CpuTensorDevice::new())?;
+
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let output = runner.generate("Lily is a cat", 30, &mut sampler)?;
+        let expected = output.collect::<Result<Vec<String>>>()?.join("");
+
+        // the output is the same with the one without the draft model
+        let mut draft = Llama2Runner::try_from(&lm_draft)?;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut output =
+            runner.generate_speculative(&mut draft, "Lily is a cat", 30, 4, &mut sampler)?;
+        let tokens = output.by_ref().collect::<Result<Vec<_>>>()?;
+        let s = tokens.iter().map(|t| t.text.as_str()).collect::<String>();
+        assert_eq!(s, expected);
+        assert_eq!(tokens.last().unwrap().pos, 36);
+        assert!(output.acceptance_rate() > 0.5);
+
+        // the draft model is expected to share the vocab
+        let gl_small = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf_small = gl_small.open()?;
+        let lm_small = CpuLlama2Model::load(&gf_small, CpuTensorDevice::new())?;
+        let mut draft = Llama2Runner::try_from(&lm_small)?;
+        let result = runner.generate_speculative(&mut draft, "Lily is a cat", 30, 4, &mut sampler);
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_prompt_lookup() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let prompt = "Lily is a cat who likes to play with yarn. She has many colors of yarn in \
+            her box. Lily is a cat";
+
+        let mut runner = Llama2Runner::try_from(&lm)?;
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let output = runner.generate(prompt, 20, &mut sampler)?;
+        let expected = output.collect::<Result<Vec<String>>>()?.join("");
+
+        // the output repeating the prompt like " who likes to" is drafted from the prompt
+        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+        let mut output = runner.generate_prompt_lookup(prompt, 20, 4, 3, &mut sampler)?;
+        let tokens = output.by_ref().collect::<Result<Vec<_>>>()?;
+        let s = tokens.iter().map(|t| t.text.as_str()).collect::<String>();
+        assert_eq!(s, expected);
+        assert!(output.acceptance_rate() > 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_ngram() {
+        let tokens = [1, 2, 3, 4, 5, 2, 3, 9, 2, 3];
+        // the latest match of the longest n-gram is taken
+        assert_eq!(lookup_ngram(&tokens, 2, 3), vec![9, 2, 3]);
+        assert_eq!(lookup_ngram(&tokens, 2, 1), vec![9]);
+        assert_eq!(lookup_ngram(&[1, 2, 3, 1, 2, 4, 2], 2, 2), vec![4, 2]);
+        assert_eq!(lookup_ngram(&[1, 2, 3], 2, 2), Vec::<usize>::new());
+        assert_eq!(lookup_ngram(&[1], 2, 2), Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,90 @@
+//! the async generation api, enabled by the `async` feature.
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use futures_core::Stream;
+
+use crate::llama2::Llama2GeneratedToken;
+use crate::llama2::Llama2Runner;
+use crate::llama2::Llama2RunnerOutputGenerator;
+use crate::sampler::Llama2Sampler;
+
+/// a stream of the generated tokens.
+///
+/// the runner is not `Send` since the weights are shared by `Rc`, so it can not be moved
+/// into a blocking task. instead each token is generated inside `block_in_place`, which
+/// hands off the other tasks of the worker thread to the other workers while the model is
+/// running. it panics on the current thread runtime, like `block_in_place` does.
+pub struct Llama2TokenStream<'a, T: Tensor> {
+    generator: Llama2RunnerOutputGenerator<'a, T>,
+}
+
+impl<'a, T: Tensor> Llama2TokenStream<'a, T> {
+    /// wrap a generator into a stream, like the one with the stop sequences.
+    pub fn new(generator: Llama2RunnerOutputGenerator<'a, T>) -> Self {
+        Self { generator }
+    }
+}
+
+impl<'a, T: Tensor> Stream for Llama2TokenStream<'a, T> {
+    type Item = Result<Llama2GeneratedToken>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let generator = &mut self.get_mut().generator;
+        let token = tokio::task::block_in_place(|| generator.next_token());
+        Poll::Ready(token.transpose())
+    }
+}
+
+impl<'a, T: Tensor> Llama2Runner<T> {
+    pub fn generate_stream(
+        &'a mut self,
+        prompt: &str,
+        steps: usize,
+        sampler: &'a mut Llama2Sampler,
+    ) -> Result<Llama2TokenStream<'a, T>> {
+        let generator = self.generate(prompt, steps, sampler)?;
+        Ok(Llama2TokenStream::new(generator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_generate_stream() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+        let text = rt.block_on(async {
+            let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0);
+            let mut runner = Llama2Runner::try_from(&lm)?;
+            let mut stream = runner.generate_stream("Lily is a cat", 30, &mut sampler)?;
+
+            let mut text = String::new();
+            while let Some(token) =
+                std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+            {
+                text.push_str(&token?.text);
+            }
+            Ok::<_, crabml::error::Error>(text)
+        })?;
+
+        assert_eq!(
+            text,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+}